@@ -0,0 +1,116 @@
+//! Per-key access control lists.
+//!
+//! The ACL file is a JSON document mapping bearer tokens to principals, and key names to the
+//! principals allowed to use each key:
+//!
+//! ```json
+//! {
+//!   "tokens": { "3f9c...": "billing", "a17e...": "ops" },
+//!   "keys": {
+//!     "payments": { "generate": ["ops"], "decrypt": ["billing"], "sign": ["billing", "ops"] },
+//!     "releases": { "generate": ["ops"], "sign": ["*"] }
+//!   }
+//! }
+//! ```
+//!
+//! The `*` principal stands for every authenticated caller. Keys missing from the file cannot be
+//! generated or used; their public half, if it exists, stays readable.
+use serde::Deserialize;
+use std::{collections::HashMap, path::Path};
+
+/// The operations guarded by the ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Generate,
+    Decrypt,
+    Sign,
+}
+
+/// The principals allowed to perform each operation with a key.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyAcl {
+    #[serde(default)]
+    generate: Vec<String>,
+    #[serde(default)]
+    decrypt: Vec<String>,
+    #[serde(default)]
+    sign: Vec<String>,
+}
+
+/// The access control lists of the service.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Acl {
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+    #[serde(default)]
+    keys: HashMap<String, KeyAcl>,
+}
+
+impl Acl {
+    /// Loads the ACL file at `path`.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+
+    /// Returns the principal of the bearer `token`, if the token is known.
+    pub fn authenticate(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token).map(String::as_str)
+    }
+
+    /// Returns whether `principal` may perform `permission` with the key `key`.
+    pub fn allows(
+        &self,
+        principal: &str,
+        key: &str,
+        permission: Permission,
+    ) -> bool {
+        let Some(acl) = self.keys.get(key) else {
+            return false;
+        };
+        let principals = match permission {
+            Permission::Generate => &acl.generate,
+            Permission::Decrypt => &acl.decrypt,
+            Permission::Sign => &acl.sign,
+        };
+        principals
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == principal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl_checks_permissions_per_key() {
+        let acl: Acl = serde_json::from_str(
+            r#"{
+                "tokens": { "t1": "billing", "t2": "ops" },
+                "keys": {
+                    "payments": { "generate": ["ops"], "decrypt": ["billing"] },
+                    "releases": { "sign": ["*"] }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(acl.authenticate("t1"), Some("billing"));
+        assert_eq!(acl.authenticate("billing"), None);
+        assert!(acl.allows("billing", "payments", Permission::Decrypt));
+        assert!(!acl.allows("ops", "payments", Permission::Decrypt));
+        assert!(!acl.allows("billing", "payments", Permission::Generate));
+        assert!(!acl.allows("billing", "payments", Permission::Sign));
+        assert!(acl.allows("anyone", "releases", Permission::Sign));
+        assert!(!acl.allows("ops", "unknown", Permission::Generate));
+    }
+
+    #[test]
+    fn test_acl_rejects_unknown_fields() {
+        let result = serde_json::from_str::<Acl>(r#"{"keys":{"k":{"encrypt":[]}}}"#);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! The key store and the operations of the service.
+use crate::acl::{Acl, Permission};
+use crate::proto::{
+    DecryptRequest, DecryptResponse, GenerateKeyRequest, GetPublicKeyRequest,
+    PublicKey, SignRequest, SignResponse,
+};
+use e2ee::server::{E2ee, KeySize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tonic::{Request, Status};
+
+/// Keys held by the service, persisted as `<name>.private.pem` and `<name>.public.pem` files in a
+/// directory, and the ACL guarding them.
+#[derive(Debug)]
+pub struct Kms {
+    dir: PathBuf,
+    keys: RwLock<HashMap<String, Arc<E2ee>>>,
+    acl: Acl,
+}
+
+impl Kms {
+    /// Opens the key store in `dir`, loading every key pair it contains.
+    pub fn open(dir: impl Into<PathBuf>, acl: Acl) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        let mut keys = HashMap::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let Some(name) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".private.pem"))
+            else {
+                continue;
+            };
+            let (private_key_path, public_key_path) = key_paths(&dir, name);
+            let e2ee = E2ee::new_from_pem(
+                std::fs::read_to_string(private_key_path)?,
+                std::fs::read_to_string(public_key_path)?,
+            )?;
+            keys.insert(name.to_string(), Arc::new(e2ee));
+        }
+        Ok(Self {
+            dir,
+            keys: RwLock::new(keys),
+            acl,
+        })
+    }
+
+    /// Generates and persists a new key pair. Existing keys are never replaced.
+    pub fn generate_key(
+        &self,
+        request: Request<GenerateKeyRequest>,
+    ) -> Result<PublicKey, Status> {
+        let principal = self.authorize(&request, Permission::Generate)?;
+        let request = request.into_inner();
+        let key_size = match request.bits {
+            0 | 2048 => KeySize::Bit2048,
+            3072 => KeySize::Bit3072,
+            4096 => KeySize::Bit4096,
+            bits => {
+                return Err(Status::invalid_argument(format!(
+                    "unsupported key size {bits}"
+                )))
+            }
+        };
+        if self.key(&request.name).is_ok() {
+            return Err(Status::already_exists(format!(
+                "key {} already exists",
+                request.name
+            )));
+        }
+
+        let e2ee = E2ee::new(key_size)
+            .map_err(|_| Status::internal("failed to generate key"))?;
+        let mut keys = self.keys.write().unwrap_or_else(|err| err.into_inner());
+        if keys.contains_key(&request.name) {
+            return Err(Status::already_exists(format!(
+                "key {} already exists",
+                request.name
+            )));
+        }
+        let (private_key_path, public_key_path) =
+            key_paths(&self.dir, &request.name);
+        e2ee.save_keys_to_files(
+            &private_key_path.to_string_lossy(),
+            &public_key_path.to_string_lossy(),
+        )
+        .map_err(|_| Status::internal("failed to store key"))?;
+        eprintln!("{principal} generated key {}", request.name);
+        let public_key = public_key(&request.name, &e2ee)?;
+        keys.insert(request.name, Arc::new(e2ee));
+        Ok(public_key)
+    }
+
+    /// Returns a public key. No authentication is required.
+    pub fn get_public_key(
+        &self,
+        request: Request<GetPublicKeyRequest>,
+    ) -> Result<PublicKey, Status> {
+        let name = request.into_inner().name;
+        public_key(&name, &*self.key(&name)?)
+    }
+
+    /// Decrypts an envelope.
+    pub fn decrypt(
+        &self,
+        request: Request<DecryptRequest>,
+    ) -> Result<DecryptResponse, Status> {
+        self.authorize(&request, Permission::Decrypt)?;
+        let request = request.into_inner();
+        let plaintext = self
+            .key(&request.name)?
+            .decrypt_envelope(&request.envelope)
+            .map_err(|_| Status::invalid_argument("failed to decrypt"))?;
+        Ok(DecryptResponse { plaintext })
+    }
+
+    /// Signs a message.
+    pub fn sign(
+        &self,
+        request: Request<SignRequest>,
+    ) -> Result<SignResponse, Status> {
+        let principal = self.authorize(&request, Permission::Sign)?;
+        let request = request.into_inner();
+        let signature = self
+            .key(&request.name)?
+            .sign_with_context(&request.message, &principal)
+            .map_err(|_| Status::internal("failed to sign"))?;
+        Ok(SignResponse { signature })
+    }
+
+    fn key(&self, name: &str) -> Result<Arc<E2ee>, Status> {
+        self.keys
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("key {name} not found")))
+    }
+
+    /// Authenticates the caller of `request` and checks that it may perform `permission` with
+    /// the key named in the request, returning its principal.
+    fn authorize<T: Named>(
+        &self,
+        request: &Request<T>,
+        permission: Permission,
+    ) -> Result<String, Status> {
+        let principal = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.acl.authenticate(token))
+            .ok_or_else(|| Status::unauthenticated("missing or unknown token"))?;
+        let name = request.get_ref().name();
+        if !is_valid_name(name) {
+            return Err(Status::invalid_argument("invalid key name"));
+        }
+        if !self.acl.allows(principal, name, permission) {
+            return Err(Status::permission_denied(format!(
+                "{principal} may not use key {name} for this operation"
+            )));
+        }
+        Ok(principal.to_string())
+    }
+}
+
+/// A request naming the key it operates on.
+trait Named {
+    fn name(&self) -> &str;
+}
+
+macro_rules! impl_named {
+    ($($request:ty),*) => {
+        $(impl Named for $request {
+            fn name(&self) -> &str {
+                &self.name
+            }
+        })*
+    };
+}
+
+impl_named!(GenerateKeyRequest, DecryptRequest, SignRequest);
+
+/// Returns whether `name` can be used as a key name, and thus in a file name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+fn key_paths(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("{name}.private.pem")),
+        dir.join(format!("{name}.public.pem")),
+    )
+}
+
+fn public_key(name: &str, e2ee: &E2ee) -> Result<PublicKey, Status> {
+    let key_id = e2ee::core::key_id(e2ee.get_public_key())
+        .map_err(|_| Status::internal("failed to encode key"))?;
+    Ok(PublicKey {
+        name: name.to_string(),
+        public_key_pem: e2ee.get_public_key_pem().to_string(),
+        key_id,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use e2ee::client::PublicE2ee;
+    use tonic::Code;
+
+    const ACL: &str = r#"{
+        "tokens": { "t-billing": "billing", "t-ops": "ops" },
+        "keys": {
+            "payments": { "generate": ["ops"], "decrypt": ["billing"], "sign": ["ops"] }
+        }
+    }"#;
+
+    fn request<T>(message: T, token: Option<&str>) -> Request<T> {
+        let mut request = Request::new(message);
+        if let Some(token) = token {
+            request
+                .metadata_mut()
+                .insert("authorization", format!("Bearer {token}").parse().unwrap());
+        }
+        request
+    }
+
+    fn open(dir: &Path) -> Kms {
+        Kms::open(dir, serde_json::from_str(ACL).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_kms_enforces_acl_and_persists_keys() {
+        let dir =
+            std::env::temp_dir().join(format!("e2ee-kms-{}", std::process::id()));
+        let kms = open(&dir);
+        let generate = |token| {
+            let message = GenerateKeyRequest {
+                name: "payments".into(),
+                bits: 2048,
+            };
+            kms.generate_key(request(message, token))
+        };
+        assert_eq!(generate(None).unwrap_err().code(), Code::Unauthenticated);
+        assert_eq!(
+            generate(Some("t-billing")).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+        let public_key = generate(Some("t-ops")).unwrap();
+        assert_eq!(
+            generate(Some("t-ops")).unwrap_err().code(),
+            Code::AlreadyExists
+        );
+
+        // Reopening the store loads the persisted key.
+        let kms = open(&dir);
+        let message = GetPublicKeyRequest {
+            name: "payments".into(),
+        };
+        assert_eq!(
+            kms.get_public_key(request(message, None)).unwrap(),
+            public_key
+        );
+
+        let client = PublicE2ee::new(public_key.public_key_pem).unwrap();
+        let decrypt = |token| {
+            let message = DecryptRequest {
+                name: "payments".into(),
+                envelope: client.encrypt_envelope(b"Hello, KMS!").unwrap(),
+            };
+            kms.decrypt(request(message, token))
+        };
+        assert_eq!(
+            decrypt(Some("t-billing")).unwrap().plaintext,
+            b"Hello, KMS!"
+        );
+        assert_eq!(
+            decrypt(Some("t-ops")).unwrap_err().code(),
+            Code::PermissionDenied
+        );
+
+        let message = SignRequest {
+            name: "payments".into(),
+            message: b"release".to_vec(),
+        };
+        let signature = kms.sign(request(message, Some("t-ops"))).unwrap().signature;
+        client.verify(b"release", &signature).unwrap();
+
+        std::fs::remove_dir_all(&dir).expect("Failed to delete key store");
+    }
+
+    #[test]
+    fn test_kms_rejects_invalid_names() {
+        assert!(is_valid_name("payments-2024_q1"));
+        assert!(!is_valid_name("../payments"));
+        assert!(!is_valid_name(""));
+    }
+}
@@ -0,0 +1,58 @@
+// `Status` is the error type of tonic handlers; boxing it would only move the conversion around.
+#![allow(clippy::result_large_err)]
+
+mod acl;
+mod kms;
+mod proto;
+
+use acl::Acl;
+use anyhow::{Context, Result};
+use clap::Parser;
+use kms::Kms;
+use proto::KmsServer;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
+
+/// Minimal self-hosted key management service
+///
+/// The service keeps RSA key pairs in a directory and serves the `e2ee.kms.v1.Kms` gRPC API
+/// (see `proto/kms.proto`): GenerateKey, GetPublicKey, Decrypt (e2ee envelopes) and Sign
+/// (RSASSA-PKCS1-v1_5 with SHA-256). Callers authenticate with a bearer token, and each key has
+/// its own access control list.
+#[derive(Parser)]
+#[command(
+    name = "E2E encryption KMS",
+    version = env!("E2EE_CLI_VERSION"),
+    about = "gRPC key management service generating, decrypting and signing with RSA keys"
+)]
+struct Cli {
+    #[arg(
+        short,
+        long,
+        default_value = "127.0.0.1:50051",
+        help = "Address to listen on"
+    )]
+    listen: SocketAddr,
+    #[arg(long, default_value = "keys", help = "Directory holding the key pairs")]
+    key_dir: PathBuf,
+    #[arg(long, default_value = "acl.json", help = "Path to the ACL file")]
+    acl_file_path: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    let acl = Acl::load(&cli.acl_file_path).context("Failed to load ACL file")?;
+    let kms = Kms::open(&cli.key_dir, acl).context("Failed to open key store")?;
+
+    println!("Listening on {}", cli.listen);
+    tonic::transport::Server::builder()
+        .add_service(KmsServer::new(Arc::new(kms)))
+        .serve_with_shutdown(cli.listen, async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .context("Server error")?;
+
+    Ok(())
+}
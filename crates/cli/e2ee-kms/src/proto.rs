@@ -0,0 +1,215 @@
+//! The messages and the server of the `e2ee.kms.v1.Kms` gRPC service, matching `proto/kms.proto`.
+//!
+//! The messages are declared with `prost` derives rather than generated by `tonic-build`, so that
+//! building the service does not require `protoc`.
+use crate::kms::Kms;
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tonic::{
+    body::BoxBody,
+    codec::ProstCodec,
+    server::{Grpc, NamedService, UnaryService},
+    Status,
+};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GenerateKeyRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(uint32, tag = "2")]
+    pub bits: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GetPublicKeyRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct PublicKey {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub public_key_pem: String,
+    #[prost(string, tag = "3")]
+    pub key_id: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DecryptRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub envelope: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DecryptResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub plaintext: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SignRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bytes = "vec", tag = "2")]
+    pub message: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SignResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub signature: Vec<u8>,
+}
+
+type BoxFuture<T, E> = Pin<Box<dyn Future<Output = Result<T, E>> + Send>>;
+
+/// A handler of one RPC of the service.
+type Handler<Req, Resp> = fn(&Kms, tonic::Request<Req>) -> Result<Resp, Status>;
+
+/// The gRPC server of the service.
+#[derive(Debug, Clone)]
+pub struct KmsServer {
+    kms: Arc<Kms>,
+}
+
+impl KmsServer {
+    pub fn new(kms: Arc<Kms>) -> Self {
+        Self { kms }
+    }
+}
+
+impl NamedService for KmsServer {
+    const NAME: &'static str = "e2ee.kms.v1.Kms";
+}
+
+impl<B> tower_service::Service<http::Request<B>> for KmsServer
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Infallible>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let kms = Arc::clone(&self.kms);
+        match request.uri().path() {
+            "/e2ee.kms.v1.Kms/GenerateKey" => unary(kms, request, Kms::generate_key),
+            "/e2ee.kms.v1.Kms/GetPublicKey" => {
+                unary(kms, request, Kms::get_public_key)
+            }
+            "/e2ee.kms.v1.Kms/Decrypt" => unary(kms, request, Kms::decrypt),
+            "/e2ee.kms.v1.Kms/Sign" => unary(kms, request, Kms::sign),
+            _ => Box::pin(async { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+/// Serves a unary RPC with `handler`. Handlers run on the blocking thread pool, as key generation
+/// and private key operations are CPU-bound.
+fn unary<B, Req, Resp>(
+    kms: Arc<Kms>,
+    request: http::Request<B>,
+    handler: Handler<Req, Resp>,
+) -> BoxFuture<http::Response<BoxBody>, Infallible>
+where
+    B: http_body::Body + Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+{
+    Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+        Ok(grpc.unary(Rpc { kms, handler }, request).await)
+    })
+}
+
+struct Rpc<Req, Resp> {
+    kms: Arc<Kms>,
+    handler: Handler<Req, Resp>,
+}
+
+impl<Req, Resp> UnaryService<Req> for Rpc<Req, Resp>
+where
+    Req: Send + 'static,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type Future = BoxFuture<tonic::Response<Resp>, Status>;
+
+    fn call(&mut self, request: tonic::Request<Req>) -> Self::Future {
+        let kms = Arc::clone(&self.kms);
+        let handler = self.handler;
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || handler(&kms, request))
+                .await
+                .map_err(|_| Status::internal("handler failed"))?
+                .map(tonic::Response::new)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::{client::Grpc as GrpcClient, Code};
+
+    #[tokio::test]
+    async fn test_server_routes_rpcs() {
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-kms-proto-{}", std::process::id()));
+        let kms = Kms::open(&dir, Default::default()).unwrap();
+        let mut client = GrpcClient::new(KmsServer::new(Arc::new(kms)));
+        client.ready().await.unwrap();
+        let status = client
+            .unary::<_, PublicKey, _>(
+                tonic::Request::new(GetPublicKeyRequest {
+                    name: "missing".into(),
+                }),
+                "/e2ee.kms.v1.Kms/GetPublicKey".parse().unwrap(),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+
+        client.ready().await.unwrap();
+        let status = client
+            .unary::<_, SignResponse, _>(
+                tonic::Request::new(SignRequest {
+                    name: "payments".into(),
+                    message: b"release".to_vec(),
+                }),
+                "/e2ee.kms.v1.Kms/Sign".parse().unwrap(),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        client.ready().await.unwrap();
+        let status = client
+            .unary::<_, SignResponse, _>(
+                tonic::Request::new(SignRequest::default()),
+                "/e2ee.kms.v1.Kms/Encrypt".parse().unwrap(),
+                ProstCodec::default(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unimplemented);
+
+        std::fs::remove_dir_all(&dir).expect("Failed to delete key store");
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{Context, Result};
+use axum::{
+    body::Bytes,
+    extract::{DefaultBodyLimit, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Router,
+};
+use clap::Parser;
+use e2ee::{envelope, keystore, server::E2ee};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// HTTP daemon exposing envelope encryption to other services
+///
+/// The daemon holds a server key pair and serves a small REST API, so that services written in
+/// any language can produce and open the same envelopes as the `e2ee` library:
+///
+/// - `POST /encrypt` encrypts the request body into an envelope for the server key;
+/// - `POST /decrypt` decrypts an envelope with the server key;
+/// - `GET /keys/public` returns the PEM-encoded server public key;
+/// - `GET /healthz` returns `ok`;
+/// - `GET /readyz` runs `E2ee::health_check` and returns its report, with status 503 if a check
+///   failed.
+///
+/// With `--keys-dir`, the daemon first checks the key files of a directory (see
+/// `keystore::scan_dir`) and refuses to start if a key is weak, expired or readable by other
+/// users.
+///
+/// The API has no authentication: only expose it on a trusted internal network.
+#[derive(Parser)]
+#[command(
+    name = "E2E encryption daemon",
+    version = env!("E2EE_CLI_VERSION"),
+    about = "HTTP daemon encrypting and decrypting envelopes with a server key pair"
+)]
+struct Cli {
+    #[arg(
+        short,
+        long,
+        default_value = "127.0.0.1:8080",
+        help = "Address to listen on"
+    )]
+    listen: SocketAddr,
+    #[arg(
+        long,
+        default_value = "private.pem",
+        help = "Path to private key pem file"
+    )]
+    private_key_file_path: PathBuf,
+    #[arg(
+        long,
+        default_value = "public.pem",
+        help = "Path to public key pem file"
+    )]
+    public_key_file_path: PathBuf,
+    #[arg(
+        long,
+        help = "Directory of key files to validate at startup. The daemon refuses to start if any of them violates the key policy"
+    )]
+    keys_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        default_value_t = DEFAULT_BODY_LIMIT,
+        help = "Largest request body, in bytes"
+    )]
+    body_limit: usize,
+}
+
+/// The default largest request body: 16 MiB.
+const DEFAULT_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// The response header carrying the ID of the server key.
+const KEY_ID_HEADER: &str = "e2ee-key-id";
+
+/// Builds the router of the API, backed by `e2ee`.
+fn app(e2ee: Arc<E2ee>, body_limit: usize) -> Router {
+    Router::new()
+        .route("/encrypt", post(encrypt))
+        .route("/decrypt", post(decrypt))
+        .route("/keys/public", get(public_key))
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(ready))
+        .layer(DefaultBodyLimit::max(body_limit))
+        .with_state(e2ee)
+}
+
+async fn encrypt(State(e2ee): State<Arc<E2ee>>, payload: Bytes) -> Response {
+    match e2ee.encrypt_envelope(&payload) {
+        Ok(encrypted) => {
+            ([(header::CONTENT_TYPE, envelope::CONTENT_TYPE)], encrypted)
+                .into_response()
+        }
+        Err(_) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "failed to encrypt").into_response()
+        }
+    }
+}
+
+async fn decrypt(State(e2ee): State<Arc<E2ee>>, encrypted: Bytes) -> Response {
+    match e2ee.decrypt_envelope(&encrypted) {
+        Ok(payload) => (
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            payload,
+        )
+            .into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "failed to decrypt").into_response(),
+    }
+}
+
+async fn public_key(State(e2ee): State<Arc<E2ee>>) -> Response {
+    match e2ee::core::key_id(e2ee.get_public_key()) {
+        Ok(key_id) => (
+            [
+                (header::CONTENT_TYPE.as_str(), "application/x-pem-file"),
+                (KEY_ID_HEADER, key_id.as_str()),
+            ],
+            e2ee.get_public_key_pem().to_string(),
+        )
+            .into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// Checks the key files of `dir` against the key policy, printing the violations to stderr.
+fn validate_keys(dir: &Path) -> Result<()> {
+    let keys = keystore::scan_dir(dir).with_context(|| {
+        format!("Failed to read keys directory {}", dir.display())
+    })?;
+    let mut invalid = 0;
+    for key in keys.iter().filter(|key| !key.is_valid()) {
+        for violation in &key.violations {
+            eprintln!("{}: {}", key.path.display(), violation);
+        }
+        invalid += 1;
+    }
+    if invalid > 0 {
+        anyhow::bail!("{} key files violate the key policy", invalid);
+    }
+    Ok(())
+}
+
+async fn ready(State(e2ee): State<Arc<E2ee>>) -> Response {
+    let report = e2ee.health_check();
+    let status = if report.is_healthy() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, report.to_string()).into_response()
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if let Some(keys_dir) = &cli.keys_dir {
+        validate_keys(keys_dir)?;
+    }
+
+    let private_key_pem = std::fs::read_to_string(&cli.private_key_file_path)
+        .context("Failed to read private key file")?;
+    let public_key_pem = std::fs::read_to_string(&cli.public_key_file_path)
+        .context("Failed to read public key file")?;
+    let e2ee = E2ee::new_from_pem(private_key_pem, public_key_pem)
+        .context("Failed to create SDK")?;
+
+    let listener = tokio::net::TcpListener::bind(cli.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", cli.listen))?;
+    println!("Listening on {}", listener.local_addr()?);
+    axum::serve(listener, app(Arc::new(e2ee), cli.body_limit))
+        .with_graceful_shutdown(async {
+            tokio::signal::ctrl_c().await.ok();
+        })
+        .await
+        .context("Server error")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use e2ee::server::KeySize;
+    use http_body_util::BodyExt;
+    use tower::ServiceExt;
+
+    async fn call(app: Router, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body.to_vec())
+    }
+
+    fn post(uri: &str, body: impl Into<Body>) -> Request<Body> {
+        Request::post(uri).body(body.into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_encrypt_then_decrypt() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let app = app(Arc::clone(&e2ee), DEFAULT_BODY_LIMIT);
+
+        let response = app
+            .clone()
+            .oneshot(post("/encrypt", "Hello, daemon!"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            envelope::CONTENT_TYPE
+        );
+        let encrypted = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            e2ee.decrypt_envelope(&encrypted).unwrap(),
+            b"Hello, daemon!"
+        );
+
+        let (status, decrypted) = call(app, post("/decrypt", encrypted)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(decrypted, b"Hello, daemon!");
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_rejects_invalid_envelopes() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let other = E2ee::new(KeySize::Bit2048).unwrap();
+        let encrypted = other.encrypt_envelope(b"Hello, daemon!").unwrap();
+        let (status, _) =
+            call(app(Arc::clone(&e2ee), 1024), post("/decrypt", encrypted)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) =
+            call(app(e2ee, 16), post("/encrypt", "x".repeat(17))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_rejects_malformed_bodies() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let app = app(Arc::clone(&e2ee), DEFAULT_BODY_LIMIT);
+        let encrypted = e2ee.encrypt_envelope(b"Hello, daemon!").unwrap();
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+
+        for body in [
+            Vec::new(),
+            b"not an envelope".to_vec(),
+            encrypted[..encrypted.len() / 2].to_vec(),
+            tampered,
+        ] {
+            assert_eq!(
+                call(app.clone(), post("/decrypt", body)).await,
+                (StatusCode::BAD_REQUEST, b"failed to decrypt".to_vec())
+            );
+        }
+        let request = Request::get("/decrypt").body(Body::empty()).unwrap();
+        assert_eq!(call(app, request).await.0, StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[test]
+    fn test_validate_keys_rejects_invalid_key_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-serve-test-keys-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        std::fs::write(dir.join("public.pem"), e2ee.get_public_key_pem()).unwrap();
+        assert!(validate_keys(&dir).is_ok());
+
+        std::fs::write(dir.join("broken.key"), "not a key").unwrap();
+        assert!(validate_keys(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(validate_keys(&dir).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_public_key_and_health() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let app = app(Arc::clone(&e2ee), DEFAULT_BODY_LIMIT);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/keys/public").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()[KEY_ID_HEADER],
+            e2ee::core::key_id(e2ee.get_public_key()).unwrap()
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, e2ee.get_public_key_pem().as_bytes());
+
+        let request = Request::get("/healthz").body(Body::empty()).unwrap();
+        assert_eq!(
+            call(app.clone(), request).await,
+            (StatusCode::OK, b"ok".to_vec())
+        );
+
+        let request = Request::get("/readyz").body(Body::empty()).unwrap();
+        let (status, report) = call(app, request).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(String::from_utf8(report)
+            .unwrap()
+            .contains("round_trip: ok\n"));
+    }
+}
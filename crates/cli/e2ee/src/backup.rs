@@ -0,0 +1,68 @@
+//! The `backup` and `restore` commands, moving the keys of a keystore, pins and settings to a
+//! new machine in an encrypted backup.
+use crate::{
+    keys::{passphrase, BACKUP_PASSPHRASE_VAR, KEYSTORE_PASSPHRASE_VAR},
+    Output,
+};
+use anyhow::{Context, Result};
+use e2ee::{backup::Backup, keystore::Keystore, password::PasswordKdf};
+use std::path::Path;
+
+/// Backs up the keys of the keystore at `keystore`, `pins` and `settings` to `output`.
+pub fn backup(
+    keystore: &Path,
+    output: &Path,
+    pins: &[(String, String)],
+    settings: &[(String, String)],
+    out: Output,
+) -> Result<()> {
+    let keystore =
+        Keystore::open(keystore, passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes())
+            .context("Failed to open keystore")?;
+    let mut backup =
+        Backup::from_keystore(&keystore).context("Failed to read keystore")?;
+    backup.pins.extend(pins.iter().cloned());
+    backup.settings.extend(settings.iter().cloned());
+    let archive = backup
+        .export_backup(
+            passphrase(BACKUP_PASSPHRASE_VAR)?.as_bytes(),
+            &PasswordKdf::default(),
+        )
+        .context("Failed to export backup")?;
+    std::fs::write(output, archive).context("Failed to write backup file")?;
+    out.status(format_args!(
+        "Backed up {} keys to: {}",
+        backup.keys.len(),
+        output.display()
+    ));
+    Ok(())
+}
+
+/// Restores the backup at `input` into a new keystore at `keystore`, and prints its pins and
+/// settings.
+pub fn restore(input: &Path, keystore: &Path, out: Output) -> Result<()> {
+    let archive = std::fs::read(input).context("Failed to read backup file")?;
+    let backup = Backup::import_backup(
+        &archive,
+        passphrase(BACKUP_PASSPHRASE_VAR)?.as_bytes(),
+    )
+    .context("Failed to import backup")?;
+    backup
+        .restore_keystore(
+            keystore,
+            passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes(),
+            &PasswordKdf::default(),
+        )
+        .context("Failed to restore keystore")?;
+    for name in backup.keys.keys() {
+        out.result("Key: ", name);
+    }
+    for (peer, pin) in &backup.pins {
+        out.result("Pin: ", format_args!("{}={}", peer, pin));
+    }
+    for (key, value) in &backup.settings {
+        out.result("Setting: ", format_args!("{}={}", key, value));
+    }
+    out.status(format_args!("Keystore is saved to: {}", keystore.display()));
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! The `env` command, encrypting and decrypting the values of environment (.env) files.
+use crate::{
+    keys::{private_key, recipient_keys},
+    Output,
+};
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use e2ee::dotenv;
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub enum EnvCommands {
+    /// Encrypt the values that are not encrypted yet
+    Encrypt {
+        #[arg(default_value = ".env", help = "Environment file to encrypt")]
+        file: PathBuf,
+        #[arg(
+            long = "recipient",
+            default_value = "public.pem",
+            help = "Public key pem file or fingerprint of a keystore key to encrypt the values for. Repeatable"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            short,
+            long,
+            help = "File to write the encrypted environment file to instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+
+    /// Decrypt the encrypted values
+    Decrypt {
+        #[arg(default_value = ".env", help = "Environment file to decrypt")]
+        file: PathBuf,
+        #[arg(
+            long,
+            default_value = "private.pem",
+            help = "Private key pem file or keystore key name the values are encrypted for"
+        )]
+        key: String,
+        #[arg(
+            short,
+            long,
+            help = "File to write the decrypted environment file to instead of stdout"
+        )]
+        output: Option<PathBuf>,
+    },
+}
+
+/// Runs an `env` subcommand, resolving keys with the keystore at `keystore`.
+pub fn run_env_command(
+    keystore: &Path,
+    command: &EnvCommands,
+    out: Output,
+) -> Result<()> {
+    let (file, output, contents) = match command {
+        EnvCommands::Encrypt {
+            file,
+            recipients,
+            output,
+        } => {
+            let public_keys: Vec<_> = recipient_keys(recipients, keystore)?
+                .iter()
+                .map(|recipient| recipient.get_public_key().clone())
+                .collect();
+            let contents = read_env_file(file)?;
+            let encrypted = dotenv::encrypt(&contents, &public_keys)
+                .context("Failed to encrypt environment file")?;
+            (file, output, encrypted)
+        }
+        EnvCommands::Decrypt { file, key, output } => {
+            let e2ee_server = private_key(key, keystore)?;
+            let contents = read_env_file(file)?;
+            let decrypted =
+                dotenv::decrypt(&contents, e2ee_server.get_private_key())
+                    .context("Failed to decrypt environment file")?;
+            (file, output, decrypted)
+        }
+    };
+    match output {
+        Some(path) => {
+            std::fs::write(path, contents)
+                .context("Failed to write environment file")?;
+            out.status(format_args!(
+                "{} is saved to: {}",
+                file.display(),
+                path.display()
+            ));
+        }
+        None => print!("{}", contents),
+    }
+    Ok(())
+}
+
+/// Reads the environment file at `path`.
+pub fn read_env_file(path: &Path) -> Result<String> {
+    std::fs::read_to_string(path).context("Failed to read environment file")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::{temp_dir, write_key_files};
+
+    #[test]
+    fn test_env_files_round_trip() {
+        let dir = temp_dir("env");
+        write_key_files(&dir);
+        let contents =
+            "# Database\nDATABASE_URL=postgres://localhost/app\nAPI_KEY=s3cr3t\n";
+        std::fs::write(dir.join(".env"), contents).unwrap();
+        let (out, keystore) = (Output { quiet: true }, dir.join("keystore.e2ee"));
+
+        let encrypt = EnvCommands::Encrypt {
+            file: dir.join(".env"),
+            recipients: vec![dir.join("public.pem").to_str().unwrap().to_string()],
+            output: Some(dir.join(".env.encrypted")),
+        };
+        run_env_command(&keystore, &encrypt, out).unwrap();
+        let encrypted = read_env_file(&dir.join(".env.encrypted")).unwrap();
+        assert!(encrypted.starts_with("# Database\nDATABASE_URL=ENC["));
+        assert!(!encrypted.contains("s3cr3t"));
+
+        let decrypt = EnvCommands::Decrypt {
+            file: dir.join(".env.encrypted"),
+            key: dir.join("private.pem").to_str().unwrap().to_string(),
+            output: Some(dir.join(".env.decrypted")),
+        };
+        run_env_command(&keystore, &decrypt, out).unwrap();
+        assert_eq!(
+            read_env_file(&dir.join(".env.decrypted")).unwrap(),
+            contents
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
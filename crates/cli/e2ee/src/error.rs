@@ -0,0 +1,230 @@
+//! Errors of the CLI, and the exit codes scripts can branch on.
+//!
+//! | Code | Failure                                                                  |
+//! |------|--------------------------------------------------------------------------|
+//! | 0    | Success                                                                  |
+//! | 1    | Any other failure                                                        |
+//! | 2    | Invalid arguments                                                        |
+//! | 3    | Bad key: unusable key file, wrong passphrase or no matching key          |
+//! | 4    | Bad ciphertext: malformed or tampered ciphertext, or invalid signature   |
+//! | 5    | Policy violation: expired data, key policy, or too many failed tries     |
+//! | 6    | I/O error                                                                |
+use e2ee::{
+    backup::BackupError, client::PublicE2eeError, dotenv::DotenvError,
+    envelope::EnvelopeError, interop::KeyImportError, keystore::KeystoreError,
+    secrets::SecretError, server::E2eeError, signing::SigningError,
+};
+use std::process::ExitCode;
+use thiserror::Error;
+
+/// An error raised by the CLI itself rather than by the SDK.
+#[derive(Error, Debug)]
+pub enum CliError {
+    #[error("No public key file or keystore key matches {0}")]
+    UnknownRecipient(String),
+
+    #[error("Several keystore keys match {0}")]
+    AmbiguousRecipient(String),
+
+    #[error("No keystore key is a recipient of the envelope")]
+    NoMatchingKey,
+
+    #[error("Malformed ciphertext")]
+    MalformedCiphertext,
+
+    #[error("The key file holds no private key")]
+    NoPrivateKey,
+
+    #[error("Refusing to write secrets to {0}, which is not on a tmpfs")]
+    PersistentSecretsDir(String),
+
+    #[error("{0} key files violate the key policy")]
+    PolicyViolations(usize),
+}
+
+/// The category of a failure, and its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Other = 1,
+    BadKey = 3,
+    BadCiphertext = 4,
+    Policy = 5,
+    Io = 6,
+}
+
+impl Failure {
+    /// Returns the category of `error`, from the first error of its chain with a known
+    /// category.
+    pub fn of(error: &anyhow::Error) -> Self {
+        error.chain().find_map(classify).unwrap_or(Failure::Other)
+    }
+}
+
+impl From<Failure> for ExitCode {
+    fn from(failure: Failure) -> Self {
+        ExitCode::from(failure as u8)
+    }
+}
+
+/// Returns the category of `error`, or `None` if it wraps an error to look at instead.
+fn classify(error: &(dyn std::error::Error + 'static)) -> Option<Failure> {
+    if let Some(error) = error.downcast_ref::<CliError>() {
+        return Some(match error {
+            CliError::UnknownRecipient(_)
+            | CliError::AmbiguousRecipient(_)
+            | CliError::NoMatchingKey
+            | CliError::NoPrivateKey => Failure::BadKey,
+            CliError::MalformedCiphertext => Failure::BadCiphertext,
+            CliError::PersistentSecretsDir(_) | CliError::PolicyViolations(_) => {
+                Failure::Policy
+            }
+        });
+    }
+    if error.is::<std::io::Error>() {
+        return Some(Failure::Io);
+    }
+    if let Some(error) = error.downcast_ref::<E2eeError>() {
+        return match error {
+            E2eeError::Envelope(_) => None,
+            E2eeError::Rsa(_)
+            | E2eeError::Pkcs8(_)
+            | E2eeError::Spki(_)
+            | E2eeError::Keygen(_)
+            | E2eeError::InvalidRecipientKey(_)
+            | E2eeError::InvalidPublicKey(_) => Some(Failure::BadKey),
+            E2eeError::Encoding(_)
+            | E2eeError::Decoding(_)
+            | E2eeError::Mac(_)
+            | E2eeError::DecryptionFailed => Some(Failure::BadCiphertext),
+            E2eeError::LockedOut { .. } | E2eeError::Replayed => {
+                Some(Failure::Policy)
+            }
+            E2eeError::FileWriteError(_)
+            | E2eeError::AuditLog(_)
+            | E2eeError::SecureMemory(_)
+            | E2eeError::GuardStore(_) => Some(Failure::Io),
+            E2eeError::BufferTooSmall { .. } | E2eeError::InvalidExpiry => {
+                Some(Failure::Other)
+            }
+        };
+    }
+    if let Some(error) = error.downcast_ref::<PublicE2eeError>() {
+        return match error {
+            PublicE2eeError::Envelope(_) => None,
+            PublicE2eeError::Rsa(_)
+            | PublicE2eeError::Pkcs8(_)
+            | PublicE2eeError::Spki(_)
+            | PublicE2eeError::InvalidPublicKey(_) => Some(Failure::BadKey),
+            PublicE2eeError::Encoding(_)
+            | PublicE2eeError::Decoding(_)
+            | PublicE2eeError::Mac(_)
+            | PublicE2eeError::InvalidSignature => Some(Failure::BadCiphertext),
+            PublicE2eeError::InvalidExpiry => Some(Failure::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<EnvelopeError>() {
+        return Some(match error {
+            EnvelopeError::InvalidClientKey(_)
+            | EnvelopeError::Spki(_)
+            | EnvelopeError::NotARecipient => Failure::BadKey,
+            EnvelopeError::Rsa(_)
+            | EnvelopeError::Malformed
+            | EnvelopeError::UnsupportedVersion(_)
+            | EnvelopeError::Authentication
+            | EnvelopeError::InvalidJson(_) => Failure::BadCiphertext,
+            EnvelopeError::Expired { .. } => Failure::Policy,
+            EnvelopeError::TooManyEscrowKeys | EnvelopeError::RecipientCount(_) => {
+                Failure::Other
+            }
+        });
+    }
+    if let Some(error) = error.downcast_ref::<KeystoreError>() {
+        return match error {
+            KeystoreError::E2ee(_) | KeystoreError::PublicE2ee(_) => None,
+            KeystoreError::Io(_) => Some(Failure::Io),
+            KeystoreError::Pkcs8(_)
+            | KeystoreError::Rsa(_)
+            | KeystoreError::WrongPassphrase
+            | KeystoreError::Malformed
+            | KeystoreError::UnsupportedVersion(_)
+            | KeystoreError::NotFound(_) => Some(Failure::BadKey),
+            KeystoreError::Password(_)
+            | KeystoreError::DuplicateName(_)
+            | KeystoreError::InvalidName => Some(Failure::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<BackupError>() {
+        return match error {
+            BackupError::Keystore(_) => None,
+            BackupError::Pkcs8(_) | BackupError::WrongPassphrase => {
+                Some(Failure::BadKey)
+            }
+            BackupError::Malformed | BackupError::UnsupportedVersion(_) => {
+                Some(Failure::BadCiphertext)
+            }
+            BackupError::Password(_) | BackupError::InvalidName => {
+                Some(Failure::Other)
+            }
+        };
+    }
+    if let Some(error) = error.downcast_ref::<DotenvError>() {
+        return match error {
+            DotenvError::Envelope { .. } => None,
+            DotenvError::InvalidValue(_) | DotenvError::NameMismatch { .. } => {
+                Some(Failure::BadCiphertext)
+            }
+            DotenvError::Malformed(_) => Some(Failure::Other),
+        };
+    }
+    if error.is::<KeyImportError>() {
+        return Some(Failure::BadKey);
+    }
+    if let Some(error) = error.downcast_ref::<SigningError>() {
+        return match error {
+            SigningError::Sign(_) => None,
+            SigningError::Io(_) => Some(Failure::Io),
+            SigningError::InvalidSignature
+            | SigningError::MalformedManifest(_)
+            | SigningError::Modified(_)
+            | SigningError::Missing(_)
+            | SigningError::Unlisted(_) => Some(Failure::BadCiphertext),
+            SigningError::UnsupportedPath(_) => Some(Failure::Other),
+        };
+    }
+    if let Some(error) = error.downcast_ref::<SecretError>() {
+        return Some(match error {
+            SecretError::Store(_) => Failure::Io,
+            SecretError::Malformed
+            | SecretError::UnsupportedVersion(_)
+            | SecretError::Invalid => Failure::BadCiphertext,
+            SecretError::Expired { .. } => Failure::Policy,
+            SecretError::NotFound | SecretError::InvalidExpiry => Failure::Other,
+        });
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_failure_of_error_chain() {
+        let error = Err::<(), _>(E2eeError::Envelope(EnvelopeError::Authentication))
+            .context("Failed to decrypt envelope")
+            .unwrap_err();
+        assert_eq!(Failure::of(&error), Failure::BadCiphertext);
+
+        let error = Err::<(), _>(KeystoreError::WrongPassphrase)
+            .context("Failed to open keystore")
+            .unwrap_err();
+        assert_eq!(Failure::of(&error), Failure::BadKey);
+
+        let error = std::fs::read("/nonexistent/e2ee-cli")
+            .context("Failed to read file")
+            .unwrap_err();
+        assert_eq!(Failure::of(&error), Failure::Io);
+        assert_eq!(Failure::of(&anyhow::anyhow!("Oops")), Failure::Other);
+    }
+}
@@ -0,0 +1,114 @@
+//! The `filter` command, encrypting or decrypting newline-delimited records from stdin to stdout.
+use crate::{
+    error::CliError,
+    keys::{keystore_keys, open_envelope, recipient_keys, seal_for},
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use e2ee::{client::PublicE2ee, interop::Encoding, server::E2ee};
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+/// What `filter` does to each record.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FilterMode {
+    /// Encrypt each line into a base64 envelope
+    Encrypt,
+    /// Decrypt each base64 envelope back into a line
+    Decrypt,
+}
+
+/// Encrypts or decrypts each line of stdin to stdout.
+pub fn run_filter(
+    mode: FilterMode,
+    recipients: &[String],
+    keystore_path: &Path,
+) -> Result<()> {
+    let (input, output) = (io::stdin().lock(), io::stdout().lock());
+    match mode {
+        FilterMode::Encrypt => {
+            encrypt_lines(&recipient_keys(recipients, keystore_path)?, input, output)
+        }
+        FilterMode::Decrypt => {
+            decrypt_lines(&keystore_keys(keystore_path)?, input, output)
+        }
+    }
+}
+
+/// Encrypts each line of `input` into a base64 envelope for `recipients`, written to `output`
+/// as soon as it is encrypted, so that the filter can follow a growing log.
+fn encrypt_lines(
+    recipients: &[PublicE2ee],
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    let encoding = Encoding::default();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read stdin")?;
+        let envelope = seal_for(recipients, line.as_bytes())
+            .with_context(|| format!("Failed to encrypt line {}", number + 1))?;
+        writeln!(output, "{}", encoding.encode(&envelope))
+            .context("Failed to write stdout")?;
+        output.flush().context("Failed to write stdout")?;
+    }
+    Ok(())
+}
+
+/// Decrypts each base64 envelope of `input` with the matching key of `keys`, and writes it to
+/// `output` as soon as it is decrypted.
+fn decrypt_lines(
+    keys: &[(String, E2ee)],
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    let encoding = Encoding::default();
+    for (number, line) in input.lines().enumerate() {
+        let line = line.context("Failed to read stdin")?;
+        let decrypted = encoding
+            .decode(line.trim())
+            .map_err(|_| CliError::MalformedCiphertext.into())
+            .and_then(|envelope| open_envelope(keys, &envelope))
+            .with_context(|| format!("Failed to decrypt line {}", number + 1))?;
+        writeln!(output, "{}", String::from_utf8_lossy(&decrypted))
+            .context("Failed to write stdout")?;
+        output.flush().context("Failed to write stdout")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Failure;
+    use e2ee::{core, server::KeySize};
+
+    #[test]
+    fn test_filter_round_trips_lines() {
+        let e2ee_server = E2ee::new(KeySize::Bit1024).unwrap();
+        let recipient =
+            PublicE2ee::new(e2ee_server.get_public_key_pem().to_string()).unwrap();
+        let input = "first record\n\nthird record with ünïcode\n";
+
+        let mut encrypted = Vec::new();
+        encrypt_lines(&[recipient], input.as_bytes(), &mut encrypted).unwrap();
+        let encrypted = String::from_utf8(encrypted).unwrap();
+        assert_eq!(encrypted.lines().count(), 3);
+        assert!(!encrypted.contains("record"));
+
+        let keys = [(
+            core::key_id(e2ee_server.get_public_key()).unwrap(),
+            e2ee_server,
+        )];
+        let mut decrypted = Vec::new();
+        decrypt_lines(&keys, encrypted.as_bytes(), &mut decrypted).unwrap();
+        assert_eq!(String::from_utf8(decrypted).unwrap(), input);
+
+        let malformed = format!("{encrypted}not base64!\n");
+        let error =
+            decrypt_lines(&keys, malformed.as_bytes(), io::sink()).unwrap_err();
+        assert_eq!(error.to_string(), "Failed to decrypt line 4");
+        assert_eq!(Failure::of(&error), Failure::BadCiphertext);
+    }
+}
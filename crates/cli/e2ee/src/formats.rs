@@ -0,0 +1,288 @@
+//! The `import-key` and `export-key` commands, converting keys between the formats of this tool
+//! and those of other tools.
+use crate::{
+    error::CliError,
+    keys::{passphrase, recipient_keys, KEYSTORE_PASSPHRASE_VAR},
+    Output,
+};
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use e2ee::{
+    client::PublicE2ee,
+    io as key_io,
+    key_import::{self, KeyFormat},
+    keystore::Keystore,
+    uri,
+};
+use qrcode::{
+    render::{svg, unicode},
+    QrCode,
+};
+use std::{
+    io::{self, IsTerminal, Write},
+    path::Path,
+};
+
+/// The format of a key file for `import-key`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ImportFormat {
+    /// Detect the format from the content of the file
+    Auto,
+    /// PKCS#8 private key, in PEM or DER
+    Pkcs8,
+    /// SPKI public key, in PEM or DER
+    Spki,
+    /// PKCS#1 private or public key, in PEM or DER
+    Pkcs1,
+    /// JSON Web Key
+    Jwk,
+    /// OpenSSH public key line or unencrypted private key
+    Openssh,
+    /// X.509 certificate, in PEM or DER
+    X509,
+    /// OpenPGP public key, armored or binary
+    Pgp,
+}
+
+impl ImportFormat {
+    fn key_format(self) -> Option<KeyFormat> {
+        match self {
+            ImportFormat::Auto => None,
+            ImportFormat::Pkcs8 => Some(KeyFormat::Pkcs8),
+            ImportFormat::Spki => Some(KeyFormat::Spki),
+            ImportFormat::Pkcs1 => Some(KeyFormat::Pkcs1),
+            ImportFormat::Jwk => Some(KeyFormat::Jwk),
+            ImportFormat::Openssh => Some(KeyFormat::OpenSsh),
+            ImportFormat::X509 => Some(KeyFormat::X509),
+            ImportFormat::Pgp => Some(KeyFormat::Pgp),
+        }
+    }
+}
+
+/// The format of a public key for `export-key`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// SPKI PEM, the format of this tool
+    Pem,
+    /// SPKI DER, the body of the PEM
+    Der,
+    /// JSON Web Key for RSA-OAEP-256, for `crypto.subtle.importKey`
+    Jwk,
+    /// OpenSSH `ssh-rsa` line, for `authorized_keys`
+    Openssh,
+    /// `e2ee:pk:` URI, for links and mobile apps
+    Uri,
+    /// QR code of the `e2ee:pk:` URI
+    Qr,
+}
+
+/// Imports the key in `file`, written in `format`, into pem files, or the private key into the
+/// keystore at `keystore` under `name`. Keys without a destination are printed.
+pub fn import_key(
+    file: &Path,
+    format: ImportFormat,
+    public_key_file_path: Option<&Path>,
+    private_key_file_path: Option<&Path>,
+    name: Option<&str>,
+    keystore: &Path,
+    out: Output,
+) -> Result<()> {
+    let data = std::fs::read(file).context("Failed to read key file")?;
+    let key = match format.key_format() {
+        Some(format) => key_import::import_key_as(&data, format),
+        None => key_import::import_key(&data),
+    }
+    .context("Failed to import key")?;
+    if key.private_key().is_none()
+        && (name.is_some() || private_key_file_path.is_some())
+    {
+        return Err(CliError::NoPrivateKey.into());
+    }
+    out.status(format_args!("Format: {}", key.format()));
+    out.result("Fingerprint: ", key.fingerprint()?);
+    for warning in key.warnings() {
+        out.warning(warning);
+    }
+
+    let public_key_pem = key_io::encode_public_key_pem(key.public_key())?;
+    match public_key_file_path {
+        Some(path) => {
+            key_io::write_pem_file(path, &public_key_pem)
+                .context("Failed to save public key file")?;
+            out.status(format_args!(
+                "Public Key Pem is saved to: {}",
+                path.display()
+            ));
+        }
+        None => out.result("Public Key Pem:\n", &public_key_pem),
+    }
+
+    let Some(private_key) = key.private_key() else {
+        return Ok(());
+    };
+    if let Some(name) = name {
+        let mut keystore = Keystore::open(
+            keystore,
+            passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes(),
+        )
+        .context("Failed to open keystore")?;
+        keystore
+            .insert(name, private_key)
+            .context("Failed to add key")?;
+        out.status(format_args!("Key {} is added to the keystore", name));
+        return Ok(());
+    }
+    let private_key_pem = key_io::encode_private_key_pem(private_key)?;
+    match private_key_file_path {
+        Some(path) => {
+            key_io::write_pem_file(path, &private_key_pem)
+                .context("Failed to save private key file")?;
+            out.status(format_args!(
+                "Private Key Pem is saved to: {}",
+                path.display()
+            ));
+        }
+        None => out.result("Private Key Pem:\n", &*private_key_pem),
+    }
+    Ok(())
+}
+
+/// Exports `key`, a public key pem file or the fingerprint of a key in `keystore`, in `format`
+/// to `output` or stdout.
+pub fn run_export_key(
+    key: &str,
+    keystore: &Path,
+    format: ExportFormat,
+    comment: &str,
+    output: Option<&Path>,
+    out: Output,
+) -> Result<()> {
+    let key = recipient_keys(&[key.to_string()], keystore)?.remove(0);
+    let exported = export_key(&key, format, comment, output.is_some())?;
+    match output {
+        Some(path) => {
+            std::fs::write(path, exported).context("Failed to write key file")?;
+            out.status(format_args!("Key is saved to: {}", path.display()));
+        }
+        None if matches!(format, ExportFormat::Der)
+            && io::stdout().is_terminal() =>
+        {
+            anyhow::bail!("Refusing to write DER to a terminal, use --output");
+        }
+        None => io::stdout()
+            .write_all(&exported)
+            .context("Failed to write key")?,
+    }
+    Ok(())
+}
+
+/// Encodes the public key of `key` in `format`. QR codes are rendered as text for a terminal, or
+/// as an SVG image when written to a file.
+fn export_key(
+    key: &PublicE2ee,
+    format: ExportFormat,
+    comment: &str,
+    to_file: bool,
+) -> Result<Vec<u8>> {
+    let public_key = key.get_public_key();
+    let text = match format {
+        ExportFormat::Pem => key.get_public_key_pem().to_owned(),
+        ExportFormat::Der => return Ok(key_io::encode_public_key_der(public_key)?),
+        ExportFormat::Jwk => key_io::encode_public_key_jwk(public_key) + "\n",
+        ExportFormat::Openssh => {
+            key_io::encode_public_key_openssh(public_key, comment) + "\n"
+        }
+        ExportFormat::Uri => uri::encode_public_key(public_key)? + "\n",
+        ExportFormat::Qr => {
+            let code = QrCode::new(uri::encode_public_key(public_key)?)
+                .context("Failed to encode QR code")?;
+            if to_file {
+                code.render::<svg::Color>().build()
+            } else {
+                // Light modules on a dark terminal background, as most terminals are.
+                code.render::<unicode::Dense1x2>()
+                    .dark_color(unicode::Dense1x2::Light)
+                    .light_color(unicode::Dense1x2::Dark)
+                    .build()
+                    + "\n"
+            }
+        }
+    };
+    Ok(text.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::{temp_dir, write_key_files};
+
+    #[test]
+    fn test_exported_keys_import_back() {
+        let dir = temp_dir("formats");
+        let e2ee_server = write_key_files(&dir);
+        let key =
+            PublicE2ee::new(e2ee_server.get_public_key_pem().to_string()).unwrap();
+        for (format, import_format) in [
+            (ExportFormat::Pem, ImportFormat::Spki),
+            (ExportFormat::Der, ImportFormat::Spki),
+            (ExportFormat::Jwk, ImportFormat::Jwk),
+            (ExportFormat::Openssh, ImportFormat::Openssh),
+        ] {
+            let exported =
+                export_key(&key, format, "alice@example.com", true).unwrap();
+            let imported = key_import::import_key_as(
+                &exported,
+                import_format.key_format().unwrap(),
+            )
+            .unwrap();
+            assert_eq!(imported.public_key(), e2ee_server.get_public_key());
+            let detected = key_import::import_key(&exported).unwrap();
+            assert_eq!(detected.public_key(), e2ee_server.get_public_key());
+        }
+
+        let exported = export_key(&key, ExportFormat::Uri, "", false).unwrap();
+        assert_eq!(
+            uri::decode(String::from_utf8(exported).unwrap().trim()).unwrap(),
+            uri::Uri::PublicKey(e2ee_server.get_public_key().clone())
+        );
+        let svg = export_key(&key, ExportFormat::Qr, "", true).unwrap();
+        assert!(String::from_utf8(svg).unwrap().contains("<svg"));
+
+        // A private key is converted into pem files.
+        let out = Output { quiet: true };
+        let keystore = dir.join("keystore.e2ee");
+        import_key(
+            &dir.join("private.pem"),
+            ImportFormat::Auto,
+            Some(&dir.join("imported-public.pem")),
+            Some(&dir.join("imported-private.pem")),
+            None,
+            &keystore,
+            out,
+        )
+        .unwrap();
+        let imported = e2ee::server::E2ee::new_from_pem(
+            std::fs::read_to_string(dir.join("imported-private.pem")).unwrap(),
+            std::fs::read_to_string(dir.join("imported-public.pem")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(imported, e2ee_server);
+
+        // A public key holds no private key to import.
+        let error = import_key(
+            &dir.join("public.pem"),
+            ImportFormat::Spki,
+            None,
+            Some(&dir.join("none.pem")),
+            None,
+            &keystore,
+            out,
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::NoPrivateKey)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
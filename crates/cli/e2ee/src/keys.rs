@@ -0,0 +1,240 @@
+//! Loading of the keys named on the command line, from pem files or a keystore, and the
+//! envelopes sealed and opened with them.
+use crate::error::CliError;
+use anyhow::{Context, Result};
+use e2ee::{
+    client::PublicE2ee, core, envelope, envelope::Envelope, io as key_io,
+    keystore::Keystore, server::E2ee,
+};
+use std::path::Path;
+
+/// The environment variable holding the passphrase of the keystore.
+pub const KEYSTORE_PASSPHRASE_VAR: &str = "E2EE_KEYSTORE_PASSPHRASE";
+
+/// The environment variable holding the new passphrase of the keystore, for
+/// `keystore change-passphrase`.
+pub const NEW_KEYSTORE_PASSPHRASE_VAR: &str = "E2EE_NEW_KEYSTORE_PASSPHRASE";
+
+/// The environment variable holding the passphrase of the backup.
+pub const BACKUP_PASSPHRASE_VAR: &str = "E2EE_BACKUP_PASSPHRASE";
+
+/// Loads `recipients`, each a path to a public key pem file or the fingerprint (or key ID) of a
+/// key in the keystore at `keystore_path`.
+pub fn recipient_keys(
+    recipients: &[String],
+    keystore_path: &Path,
+) -> Result<Vec<PublicE2ee>> {
+    let mut keystore = None;
+    let mut keys = Vec::new();
+    for recipient in recipients {
+        let e2ee_client = if Path::new(recipient).is_file() {
+            let public_key_pem = std::fs::read_to_string(recipient)
+                .context("Failed to read public key file")?;
+            PublicE2ee::new(public_key_pem)?
+        } else {
+            if keystore.is_none() {
+                keystore = Some(
+                    Keystore::open(
+                        keystore_path,
+                        passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes(),
+                    )
+                    .context("Failed to open keystore")?,
+                );
+            }
+            find_keystore_key(keystore.as_ref().unwrap(), recipient)?
+        };
+        keys.push(e2ee_client);
+    }
+    Ok(keys)
+}
+
+/// Encrypts `plaintext` into an envelope for `recipients`.
+pub fn seal_for(recipients: &[PublicE2ee], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let public_keys: Vec<_> = recipients
+        .iter()
+        .map(|recipient| recipient.get_public_key().clone())
+        .collect();
+    envelope::seal_for_recipients(&public_keys, plaintext)
+        .context("Failed to encrypt envelope")
+}
+
+/// Returns the key of `keystore` whose fingerprint starts with `fingerprint`.
+fn find_keystore_key(keystore: &Keystore, fingerprint: &str) -> Result<PublicE2ee> {
+    let fingerprint = fingerprint.to_ascii_lowercase();
+    let mut matches = Vec::new();
+    for name in keystore.names() {
+        let e2ee_client =
+            keystore.public_e2ee(name).context("Failed to load key")?;
+        if core::fingerprint(e2ee_client.get_public_key())?.starts_with(&fingerprint)
+        {
+            matches.push(e2ee_client);
+        }
+    }
+    match matches.len() {
+        1 => Ok(matches.remove(0)),
+        0 => Err(CliError::UnknownRecipient(fingerprint).into()),
+        _ => Err(CliError::AmbiguousRecipient(fingerprint).into()),
+    }
+}
+
+/// Loads `key`, a path to a private key pem file or the name of a key in the keystore at
+/// `keystore_path`.
+pub fn private_key(key: &str, keystore_path: &Path) -> Result<E2ee> {
+    if Path::new(key).is_file() {
+        let private_key_pem = std::fs::read_to_string(key)
+            .context("Failed to read private key file")?;
+        let private_key = key_io::decode_private_key_pem(&private_key_pem)
+            .context("Failed to decode private key")?;
+        return E2ee::from_private_key(private_key).context("Failed to create SDK");
+    }
+    Keystore::open(
+        keystore_path,
+        passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes(),
+    )
+    .context("Failed to open keystore")?
+    .e2ee(key)
+    .context("Failed to load key")
+}
+
+/// Loads the keys of the keystore at `keystore_path`, with their key IDs.
+pub fn keystore_keys(keystore_path: &Path) -> Result<Vec<(String, E2ee)>> {
+    let keystore = Keystore::open(
+        keystore_path,
+        passphrase(KEYSTORE_PASSPHRASE_VAR)?.as_bytes(),
+    )
+    .context("Failed to open keystore")?;
+    keystore
+        .names()
+        .into_iter()
+        .map(|name| {
+            let e2ee_server = keystore.e2ee(name).context("Failed to load key")?;
+            Ok((core::key_id(e2ee_server.get_public_key())?, e2ee_server))
+        })
+        .collect()
+}
+
+/// Decrypts `envelope` with the key of `keys` whose ID matches one of its recipients.
+pub fn open_envelope(keys: &[(String, E2ee)], envelope: &[u8]) -> Result<Vec<u8>> {
+    let key_ids: Vec<_> = Envelope::parse(envelope)
+        .context("Failed to parse envelope")?
+        .recipients()
+        .iter()
+        .filter_map(|recipient| recipient.key_id())
+        .collect();
+    let (_, e2ee_server) = keys
+        .iter()
+        .find(|(key_id, _)| key_ids.contains(key_id))
+        .ok_or(CliError::NoMatchingKey)?;
+    e2ee_server
+        .decrypt_envelope(envelope)
+        .context("Failed to decrypt envelope")
+}
+
+/// Reads a passphrase from the environment variable `var`.
+pub fn passphrase(var: &str) -> Result<String> {
+    std::env::var(var)
+        .with_context(|| format!("Failed to read passphrase from {var}"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use e2ee::{
+        password::{PasswordKdf, PasswordParams},
+        server::KeySize,
+    };
+    use std::path::PathBuf;
+
+    /// The passphrase of the keystores of the tests, set in the environment of every test that
+    /// opens one.
+    pub const PASSPHRASE: &str = "correct horse battery staple";
+
+    /// Returns an empty directory for the test `name`.
+    pub fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-cli-test-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes the key pair of a new key to `private.pem` and `public.pem` in `dir`.
+    pub fn write_key_files(dir: &Path) -> E2ee {
+        let e2ee_server = E2ee::new(KeySize::Bit1024).unwrap();
+        e2ee_server
+            .save_keys_to_files(
+                dir.join("private.pem").to_str().unwrap(),
+                dir.join("public.pem").to_str().unwrap(),
+            )
+            .unwrap();
+        e2ee_server
+    }
+
+    /// Creates a keystore at `path` with cheap key derivation, holding the keys `names`.
+    pub fn create_keystore(path: &Path, names: &[&str]) -> Keystore {
+        std::env::set_var(KEYSTORE_PASSPHRASE_VAR, PASSPHRASE);
+        let kdf = PasswordKdf::new(PasswordParams::new(64, 1, 1).unwrap());
+        let mut keystore =
+            Keystore::create(path, PASSPHRASE.as_bytes(), &kdf).unwrap();
+        for name in names {
+            keystore.generate(name, KeySize::Bit1024).unwrap();
+        }
+        keystore
+    }
+
+    #[test]
+    fn test_envelopes_open_with_the_key_of_a_recipient() {
+        let dir = temp_dir("keys");
+        let e2ee_server = write_key_files(&dir);
+        let keystore_path = dir.join("keystore.e2ee");
+        let keystore = create_keystore(&keystore_path, &["alice", "bob"]);
+        let bob = keystore.e2ee("bob").unwrap();
+
+        // A pem file and the fingerprint prefix of a keystore key.
+        let recipients = recipient_keys(
+            &[
+                dir.join("public.pem").to_str().unwrap().to_string(),
+                bob.get_fingerprint().unwrap()[..16].to_uppercase(),
+            ],
+            &keystore_path,
+        )
+        .unwrap();
+        let envelope = seal_for(&recipients, b"Hello recipients!").unwrap();
+
+        let keys = keystore_keys(&keystore_path).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(
+            open_envelope(&keys, &envelope).unwrap(),
+            b"Hello recipients!"
+        );
+        let from_file =
+            private_key(dir.join("private.pem").to_str().unwrap(), &keystore_path)
+                .unwrap();
+        assert_eq!(from_file.get_public_key(), e2ee_server.get_public_key());
+        let key_id = core::key_id(from_file.get_public_key()).unwrap();
+        assert_eq!(
+            open_envelope(&[(key_id, from_file)], &envelope).unwrap(),
+            b"Hello recipients!"
+        );
+
+        let alice = keystore_keys(&keystore_path)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, e2ee_server)| {
+                e2ee_server.get_public_key() != bob.get_public_key()
+            })
+            .collect::<Vec<_>>();
+        let error = open_envelope(&alice, &envelope).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::NoMatchingKey)
+        ));
+        let error =
+            recipient_keys(&["00ff00ff".to_string()], &keystore_path).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::UnknownRecipient(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
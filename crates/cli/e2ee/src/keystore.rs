@@ -0,0 +1,189 @@
+//! The `keystore` command, managing named private keys in a keystore file.
+use crate::{
+    keys::{passphrase, KEYSTORE_PASSPHRASE_VAR, NEW_KEYSTORE_PASSPHRASE_VAR},
+    Output,
+};
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use e2ee::{
+    keystore::Keystore,
+    password::PasswordKdf,
+    server::{E2ee, KeySize},
+};
+use std::path::{Path, PathBuf};
+
+#[derive(Subcommand)]
+pub enum KeystoreCommands {
+    /// Create an empty keystore
+    Init,
+
+    /// List the names of the stored keys
+    List,
+
+    /// Generate a new key, or import a key pair from pem files, under a name
+    Add {
+        #[arg(help = "Name of the key")]
+        name: String,
+        #[arg(
+            short = 's',
+            long = "size",
+            default_value = "bit2048",
+            help = "Key size of a generated key"
+        )]
+        key_size: KeySize,
+        #[arg(
+            long,
+            requires = "public_key_file_path",
+            help = "Path to the private key pem file to import instead of generating a key"
+        )]
+        private_key_file_path: Option<PathBuf>,
+        #[arg(
+            long,
+            requires = "private_key_file_path",
+            help = "Path to the public key pem file to import"
+        )]
+        public_key_file_path: Option<PathBuf>,
+    },
+
+    /// Print the public key pem of a stored key, and its private key pem with `--private`
+    Export {
+        #[arg(help = "Name of the key")]
+        name: String,
+        #[arg(long, help = "Also print the private key pem")]
+        private: bool,
+    },
+
+    /// Remove a stored key
+    Remove {
+        #[arg(help = "Name of the key")]
+        name: String,
+    },
+
+    /// Protect the keystore with the passphrase in E2EE_NEW_KEYSTORE_PASSPHRASE
+    ChangePassphrase,
+}
+
+/// Runs a `keystore` subcommand on the keystore at `path`.
+pub fn run_keystore_command(
+    path: &Path,
+    command: &KeystoreCommands,
+    out: Output,
+) -> Result<()> {
+    let keystore_passphrase = passphrase(KEYSTORE_PASSPHRASE_VAR)?;
+    let open = || {
+        Keystore::open(path, keystore_passphrase.as_bytes())
+            .context("Failed to open keystore")
+    };
+    match command {
+        KeystoreCommands::Init => {
+            Keystore::create(
+                path,
+                keystore_passphrase.as_bytes(),
+                &PasswordKdf::default(),
+            )
+            .context("Failed to create keystore")?;
+            out.status(format_args!("Keystore is saved to: {}", path.display()));
+        }
+        KeystoreCommands::List => {
+            for name in open()?.names() {
+                println!("{}", name);
+            }
+        }
+        KeystoreCommands::Add {
+            name,
+            key_size,
+            private_key_file_path,
+            public_key_file_path,
+        } => {
+            let mut keystore = open()?;
+            if let (Some(private_key_file_path), Some(public_key_file_path)) =
+                (private_key_file_path, public_key_file_path)
+            {
+                let private_key_pem = std::fs::read_to_string(private_key_file_path)
+                    .context("Failed to read private key file")?;
+                let public_key_pem = std::fs::read_to_string(public_key_file_path)
+                    .context("Failed to read public key file")?;
+                let e2ee_server =
+                    E2ee::new_from_pem(private_key_pem, public_key_pem)
+                        .context("Failed to create SDK")?;
+                keystore
+                    .insert(name, e2ee_server.get_private_key())
+                    .context("Failed to add key")?;
+            } else {
+                keystore
+                    .generate(name, *key_size)
+                    .context("Failed to generate key")?;
+            }
+            let e2ee_server = keystore.e2ee(name).context("Failed to load key")?;
+            out.result(
+                &format!("Key {} is added with fingerprint: ", name),
+                e2ee_server.get_fingerprint()?,
+            );
+        }
+        KeystoreCommands::Export { name, private } => {
+            let e2ee_server = open()?.e2ee(name).context("Failed to load key")?;
+            out.result("Public Key Pem:\n", e2ee_server.get_public_key_pem());
+            if *private {
+                let private_key_pem = e2ee_server
+                    .get_private_key_pem()
+                    .context("Failed to encode private key")?;
+                out.result("Private Key Pem:\n", &*private_key_pem);
+            }
+        }
+        KeystoreCommands::Remove { name } => {
+            open()?.remove(name).context("Failed to remove key")?;
+            out.status(format_args!("Key {} is removed", name));
+        }
+        KeystoreCommands::ChangePassphrase => {
+            let new_passphrase = passphrase(NEW_KEYSTORE_PASSPHRASE_VAR)?;
+            open()?
+                .change_passphrase(
+                    new_passphrase.as_bytes(),
+                    &PasswordKdf::default(),
+                )
+                .context("Failed to change passphrase")?;
+            out.status("Passphrase is changed");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::tests::{
+        create_keystore, temp_dir, write_key_files, PASSPHRASE,
+    };
+
+    #[test]
+    fn test_keystore_command_adds_and_removes_keys() {
+        let dir = temp_dir("keystore");
+        let e2ee_server = write_key_files(&dir);
+        let path = dir.join("keystore.e2ee");
+        create_keystore(&path, &["generated"]);
+        let out = Output { quiet: true };
+
+        let add = KeystoreCommands::Add {
+            name: "imported".to_string(),
+            key_size: KeySize::Bit1024,
+            private_key_file_path: Some(dir.join("private.pem")),
+            public_key_file_path: Some(dir.join("public.pem")),
+        };
+        run_keystore_command(&path, &add, out).unwrap();
+        let keystore = Keystore::open(&path, PASSPHRASE.as_bytes()).unwrap();
+        assert_eq!(keystore.names(), ["generated", "imported"]);
+        assert_eq!(keystore.e2ee("imported").unwrap(), e2ee_server);
+        // Names are unique.
+        assert!(run_keystore_command(&path, &add, out).is_err());
+
+        let remove = KeystoreCommands::Remove {
+            name: "generated".to_string(),
+        };
+        run_keystore_command(&path, &remove, out).unwrap();
+        let keystore = Keystore::open(&path, PASSPHRASE.as_bytes()).unwrap();
+        assert_eq!(keystore.names(), ["imported"]);
+        // `init` never overwrites a keystore.
+        assert!(run_keystore_command(&path, &KeystoreCommands::Init, out).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
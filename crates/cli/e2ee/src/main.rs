@@ -1,10 +1,37 @@
+mod backup;
+mod env;
+mod error;
+mod filter;
+mod formats;
+mod keys;
+mod keystore;
+mod reencrypt;
+mod run;
+
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Parser, Subcommand};
 use e2ee::{
     client::PublicE2ee,
+    interop::Encoding,
+    secrets::{self, DirectorySecretStore},
     server::{E2ee, KeySize},
+    signing,
+};
+use env::EnvCommands;
+use error::{CliError, Failure};
+use filter::FilterMode;
+use formats::{ExportFormat, ImportFormat};
+use keys::{keystore_keys, open_envelope, private_key, recipient_keys, seal_for};
+use keystore::KeystoreCommands;
+use std::{
+    fmt::Display,
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, UNIX_EPOCH},
 };
-use std::path::PathBuf;
+use tracing::Level;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 /// Command Line Interface for End-to-End Encryption
 ///
@@ -17,10 +44,62 @@ use std::path::PathBuf;
     about = "CLI tool to encrypt and decrypt messages using RSA encryption"
 )]
 struct Cli {
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Print results without labels, and nothing else. Failures are reported by exit code only"
+    )]
+    quiet: bool,
+    #[arg(
+        short,
+        long,
+        action = ArgAction::Count,
+        global = true,
+        help = "Log to stderr where encryption and decryption fail (-v), and the OAEP hashes, encoding and envelope format attempted (-vv). Key material and plaintext are never logged"
+    )]
+    verbose: u8,
+    #[arg(
+        long,
+        global = true,
+        help = "Log everything, with the duration of each operation"
+    )]
+    trace: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Where the results and status messages of a command are printed.
+#[derive(Clone, Copy)]
+struct Output {
+    quiet: bool,
+}
+
+impl Output {
+    /// Prints a result, after `label` unless in quiet mode.
+    fn result(&self, label: &str, value: impl Display) {
+        if self.quiet {
+            println!("{}", value);
+        } else {
+            println!("{}{}", label, value);
+        }
+    }
+
+    /// Prints a status message, unless in quiet mode.
+    fn status(&self, message: impl Display) {
+        if !self.quiet {
+            println!("{}", message);
+        }
+    }
+
+    /// Prints a warning to stderr, unless in quiet mode.
+    fn warning(&self, message: impl Display) {
+        if !self.quiet {
+            eprintln!("Warning: {}", message);
+        }
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Generate a new pair of RSA keys and save them to files
@@ -55,10 +134,45 @@ enum Commands {
             help = "Path to public key pem file"
         )]
         public_key_file_path: PathBuf,
+        #[arg(
+            long = "recipient",
+            help = "Public key pem file or fingerprint of a keystore key to encrypt an envelope for. Repeatable"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving recipient fingerprints"
+        )]
+        keystore: PathBuf,
         #[arg(short, long, help = "Message to encrypt. Example: \"Hello, world!\"")]
         message: String,
     },
 
+    /// Encrypt a file into an envelope for one or more recipients
+    EncryptFile {
+        #[arg(
+            long = "recipient",
+            required = true,
+            help = "Public key pem file or fingerprint of a keystore key to encrypt for. Repeatable"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving recipient fingerprints"
+        )]
+        keystore: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Path to the envelope file. Defaults to the file path with .e2ee appended"
+        )]
+        output: Option<PathBuf>,
+        #[arg(help = "File to encrypt")]
+        path: PathBuf,
+    },
+
     /// Decrypt a ciphertext using a private RSA key
     Decrypt {
         #[arg(
@@ -74,14 +188,357 @@ enum Commands {
             help = "Path to public key pem file"
         )]
         public_key_file_path: PathBuf,
+        #[arg(
+            long,
+            help = "Path to a keystore whose key matching a recipient decrypts an envelope, instead of the pem files"
+        )]
+        keystore: Option<PathBuf>,
         #[arg(short, long, help = "Ciphertext to decrypt. Example: \"Zm9vYmFy\"")]
         ciphertext: String,
     },
+
+    /// Decrypt an envelope file with the keystore key matching one of its recipients
+    DecryptFile {
+        #[arg(long, default_value = "keystore.e2ee", help = "Path to the keystore")]
+        keystore: PathBuf,
+        #[arg(
+            short,
+            long,
+            help = "Path to the decrypted file. Defaults to the file path without .e2ee"
+        )]
+        output: Option<PathBuf>,
+        #[arg(help = "Envelope file to decrypt")]
+        path: PathBuf,
+    },
+
+    /// Sign a file into a detached .sig file, or a directory into a signed SHA256SUMS manifest
+    Sign {
+        #[arg(
+            long,
+            default_value = "private.pem",
+            help = "Path to private key pem file"
+        )]
+        private_key_file_path: PathBuf,
+        #[arg(
+            long,
+            default_value = "public.pem",
+            help = "Path to public key pem file"
+        )]
+        public_key_file_path: PathBuf,
+        #[arg(help = "File or directory to sign")]
+        path: PathBuf,
+    },
+
+    /// Verify the detached signature of a file, or the signed SHA256SUMS manifest of a directory
+    Verify {
+        #[arg(
+            long,
+            default_value = "public.pem",
+            help = "Path to public key pem file"
+        )]
+        public_key_file_path: PathBuf,
+        #[arg(
+            long,
+            help = "Path to the signature of a file. Defaults to the file path with .sig appended"
+        )]
+        signature: Option<PathBuf>,
+        #[arg(help = "File or directory to verify")]
+        path: PathBuf,
+    },
+
+    /// Encrypt a one-time secret into a store and print the link revealing it
+    Share {
+        #[arg(
+            long,
+            default_value = "secrets",
+            help = "Directory storing the encrypted secrets"
+        )]
+        store: PathBuf,
+        #[arg(
+            long,
+            default_value_t = 86400,
+            help = "Seconds after which the secret expires"
+        )]
+        ttl: u64,
+        #[arg(
+            long,
+            help = "Base URL of the link. Example: \"https://secrets.example.com/s\""
+        )]
+        base_url: Option<String>,
+        #[arg(short, long, help = "Secret to share. Example: \"hunter2\"")]
+        message: String,
+    },
+
+    /// Reveal a one-time secret shared with `share`, removing it from the store
+    Reveal {
+        #[arg(
+            long,
+            default_value = "secrets",
+            help = "Directory storing the encrypted secrets"
+        )]
+        store: PathBuf,
+        #[arg(help = "Link printed by `share`")]
+        link: String,
+    },
+
+    /// Export the keys of a keystore, pins and settings into an encrypted backup
+    Backup {
+        #[arg(long, help = "Path to the keystore to back up")]
+        keystore: PathBuf,
+        #[arg(
+            short,
+            long,
+            default_value = "backup.e2ee",
+            help = "Path to the backup file"
+        )]
+        output: PathBuf,
+        #[arg(
+            long = "pin",
+            value_parser = parse_entry,
+            help = "Pinned key of a peer to back up. Example: \"alice=3f9a0c...\""
+        )]
+        pins: Vec<(String, String)>,
+        #[arg(
+            long = "setting",
+            value_parser = parse_entry,
+            help = "Setting to back up. Example: \"theme=dark\""
+        )]
+        settings: Vec<(String, String)>,
+    },
+
+    /// Restore a backup made with `backup` into a new keystore
+    Restore {
+        #[arg(
+            short,
+            long,
+            default_value = "backup.e2ee",
+            help = "Path to the backup file"
+        )]
+        input: PathBuf,
+        #[arg(long, help = "Path to the keystore to create")]
+        keystore: PathBuf,
+    },
+
+    /// Encrypt or decrypt newline-delimited records from stdin to stdout, one envelope per line
+    Filter {
+        #[arg(long, help = "Whether to encrypt or decrypt the records")]
+        mode: FilterMode,
+        #[arg(
+            long = "recipient",
+            required_if_eq("mode", "encrypt"),
+            help = "Public key pem file or fingerprint of a keystore key to encrypt for. Repeatable"
+        )]
+        recipients: Vec<String>,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving recipient fingerprints, or decrypting the records"
+        )]
+        keystore: PathBuf,
+    },
+
+    /// Rewrap the envelope files under the given paths from an old key to a new key, leaving their
+    /// payloads untouched
+    Reencrypt {
+        #[arg(
+            long,
+            help = "Private key pem file or keystore key name the envelopes are encrypted for"
+        )]
+        old_key: String,
+        #[arg(
+            long,
+            help = "Public key pem file or fingerprint of a keystore key to rewrap the envelopes for"
+        )]
+        new_key: String,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving key names and fingerprints"
+        )]
+        keystore: PathBuf,
+        #[arg(long, help = "Report the envelopes to rewrap without writing them")]
+        dry_run: bool,
+        #[arg(required = true, help = "Envelope files, or directories to walk")]
+        paths: Vec<PathBuf>,
+    },
+
+    /// Import a key written by another tool (PKCS#1, JWK, OpenSSH, OpenPGP...) and convert it into
+    /// PKCS#8/SPKI PEM files or a keystore entry
+    ImportKey {
+        #[arg(help = "Key file to import")]
+        file: PathBuf,
+        #[arg(long, default_value = "auto", help = "Format of the key file")]
+        format: ImportFormat,
+        #[arg(
+            long = "public-key-file-path",
+            help = "Path to save the public key pem file to, instead of printing it"
+        )]
+        public_key_file_path: Option<PathBuf>,
+        #[arg(
+            long = "private-key-file-path",
+            help = "Path to save the private key pem file to, instead of printing it"
+        )]
+        private_key_file_path: Option<PathBuf>,
+        #[arg(
+            long,
+            conflicts_with = "private_key_file_path",
+            help = "Add the private key to the keystore under this name, instead of printing it"
+        )]
+        name: Option<String>,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore file"
+        )]
+        keystore: PathBuf,
+    },
+
+    /// Export a public key as PEM, DER, JWK, OpenSSH, an `e2ee:pk:` URI or a QR code of the URI,
+    /// for web, infrastructure and mobile consumers
+    ExportKey {
+        #[arg(
+            short,
+            long,
+            default_value = "public.pem",
+            help = "Public key pem file or fingerprint of a keystore key to export"
+        )]
+        key: String,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving key fingerprints"
+        )]
+        keystore: PathBuf,
+        #[arg(
+            short,
+            long,
+            default_value = "pem",
+            help = "Format to export the key in"
+        )]
+        format: ExportFormat,
+        #[arg(
+            long,
+            default_value = "",
+            help = "Comment ending the OpenSSH key line"
+        )]
+        comment: String,
+        #[arg(
+            short,
+            long,
+            help = "File to write the key to instead of stdout. QR codes are written as SVG images"
+        )]
+        output: Option<PathBuf>,
+    },
+
+    /// List the key files of a directory with their type, size, fingerprint and expiry, and fail
+    /// if any of them violates the key policy
+    Inspect {
+        #[arg(
+            default_value = ".",
+            help = "Directory holding the key files (.pem, .der, .key, .pub, .crt, .cer)"
+        )]
+        dir: PathBuf,
+    },
+
+    /// Encrypt or decrypt the values of an environment (.env) file into `ENC[...]` markers,
+    /// keeping variable names and comments readable
+    Env {
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving key names and fingerprints"
+        )]
+        keystore: PathBuf,
+        #[command(subcommand)]
+        command: EnvCommands,
+    },
+
+    /// Decrypt the secrets of encrypted environment files and run a command with them in its
+    /// environment, or in files on a tmpfs, without writing them to persistent storage
+    Run {
+        #[arg(
+            long = "env-file",
+            required = true,
+            help = "Environment file encrypted with `env encrypt`. Repeatable, later files override earlier ones"
+        )]
+        env_files: Vec<PathBuf>,
+        #[arg(
+            long,
+            default_value = "private.pem",
+            help = "Private key pem file or keystore key name the secrets are encrypted for"
+        )]
+        key: String,
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore resolving key names"
+        )]
+        keystore: PathBuf,
+        #[arg(
+            long,
+            help = "Directory on a tmpfs to write each secret to, in a file named after its variable, instead of the environment. <NAME>_FILE variables hold the paths. Linux only, where tmpfs mounts are detected"
+        )]
+        secrets_dir: Option<PathBuf>,
+        #[arg(last = true, required = true, help = "Command to run, after --")]
+        command: Vec<String>,
+    },
+
+    /// Manage named private keys in a keystore file encrypted under a passphrase
+    Keystore {
+        #[arg(
+            long,
+            default_value = "keystore.e2ee",
+            help = "Path to the keystore file"
+        )]
+        keystore: PathBuf,
+        #[command(subcommand)]
+        command: KeystoreCommands,
+    },
+}
+
+/// Parses a `KEY=VALUE` argument.
+fn parse_entry(entry: &str) -> Result<(String, String)> {
+    let (key, value) = entry.split_once('=').context("Expected KEY=VALUE")?;
+    Ok((key.to_string(), value.to_string()))
 }
 
-fn main() -> Result<()> {
+fn main() -> ExitCode {
     let cli = Cli::parse();
+    init_tracing(&cli);
+    match run(&cli, Output { quiet: cli.quiet }) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            if !cli.quiet {
+                eprintln!("Error: {:?}", error);
+            }
+            Failure::of(&error).into()
+        }
+    }
+}
 
+/// Logs the spans and events of the SDK to stderr, at the level selected by `-v` and `--trace`.
+fn init_tracing(cli: &Cli) {
+    let level = match (cli.trace, cli.verbose) {
+        (true, _) => Level::TRACE,
+        (false, 0) => return,
+        (false, 1) => Level::WARN,
+        (false, _) => Level::DEBUG,
+    };
+    let span_events = if cli.trace {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_span_events(span_events)
+        .with_ansi(io::stderr().is_terminal())
+        .with_writer(io::stderr)
+        .init();
+}
+
+/// Runs the command of `cli`, printing to `out`.
+fn run(cli: &Cli, out: Output) -> Result<()> {
     match &cli.command {
         Commands::GenerateKeys {
             key_size,
@@ -90,26 +547,42 @@ fn main() -> Result<()> {
         } => {
             let e2ee_server =
                 E2ee::new(*key_size).context("Failed to create SDK")?;
-            println!("Public Key Pem:\n{}", e2ee_server.get_public_key_pem());
-            println!("Private Key Pem:\n{}", e2ee_server.get_private_key_pem());
+            out.result("Public Key Pem:\n", e2ee_server.get_public_key_pem());
+            let private_key_pem = e2ee_server
+                .get_private_key_pem()
+                .context("Failed to encode private key")?;
+            out.result("Private Key Pem:\n", &*private_key_pem);
             e2ee_server
                 .save_keys_to_files(
                     private_key_file_path.to_str().unwrap(),
                     public_key_file_path.to_str().unwrap(),
                 )
                 .context("Failed to save keys to files")?;
-            println!(
+            out.status(format_args!(
                 "Public Key Pem is saved to: {}",
                 public_key_file_path.display()
-            );
-            println!(
+            ));
+            out.status(format_args!(
                 "Private Key Pem is saved to: {}",
                 private_key_file_path.display()
-            );
+            ));
+        }
+        Commands::Encrypt {
+            recipients,
+            keystore,
+            message,
+            ..
+        } if !recipients.is_empty() => {
+            let envelope = seal_for(
+                &recipient_keys(recipients, keystore)?,
+                message.as_bytes(),
+            )?;
+            out.result("Encrypted message: ", Encoding::default().encode(&envelope));
         }
         Commands::Encrypt {
             public_key_file_path,
             message,
+            ..
         } => {
             let public_key_pem = std::fs::read_to_string(public_key_file_path)
                 .context("Failed to read public key file")?;
@@ -117,12 +590,42 @@ fn main() -> Result<()> {
             let encrypted = e2ee_client
                 .encrypt(message)
                 .context("Failed to encrypt message")?;
-            println!("Encrypted message: {}", encrypted);
+            out.result("Encrypted message: ", encrypted);
+        }
+        Commands::EncryptFile {
+            recipients,
+            keystore,
+            output,
+            path,
+        } => {
+            let plaintext = std::fs::read(path).context("Failed to read file")?;
+            let envelope =
+                seal_for(&recipient_keys(recipients, keystore)?, &plaintext)?;
+            let output = output.clone().unwrap_or_else(|| {
+                let mut output = path.clone().into_os_string();
+                output.push(".e2ee");
+                output.into()
+            });
+            std::fs::write(&output, envelope)
+                .context("Failed to write envelope file")?;
+            out.status(format_args!("Envelope is saved to: {}", output.display()));
+        }
+        Commands::Decrypt {
+            keystore: Some(keystore),
+            ciphertext,
+            ..
+        } => {
+            let envelope = Encoding::default()
+                .decode(ciphertext)
+                .map_err(|_| CliError::MalformedCiphertext)?;
+            let decrypted = open_envelope(&keystore_keys(keystore)?, &envelope)?;
+            out.result("Decrypted message: ", String::from_utf8_lossy(&decrypted));
         }
         Commands::Decrypt {
             private_key_file_path,
             public_key_file_path,
             ciphertext,
+            keystore: None,
         } => {
             let private_key_pem = std::fs::read_to_string(private_key_file_path)
                 .context("Failed to read private key file")?;
@@ -133,9 +636,414 @@ fn main() -> Result<()> {
             let decrypted = e2ee_server
                 .decrypt(ciphertext)
                 .context("Failed to decrypt message")?;
-            println!("Decrypted message: {}", decrypted);
+            out.result("Decrypted message: ", decrypted);
+        }
+        Commands::DecryptFile {
+            keystore,
+            output,
+            path,
+        } => {
+            let envelope =
+                std::fs::read(path).context("Failed to read envelope file")?;
+            let decrypted = open_envelope(&keystore_keys(keystore)?, &envelope)?;
+            let output = match output {
+                Some(output) => output.clone(),
+                None if path
+                    .extension()
+                    .is_some_and(|extension| extension == "e2ee") =>
+                {
+                    path.with_extension("")
+                }
+                None => anyhow::bail!(
+                    "Missing --output for a file without .e2ee extension"
+                ),
+            };
+            std::fs::write(&output, decrypted)
+                .context("Failed to write decrypted file")?;
+            out.status(format_args!(
+                "Decrypted file is saved to: {}",
+                output.display()
+            ));
+        }
+        Commands::Sign {
+            private_key_file_path,
+            public_key_file_path,
+            path,
+        } => {
+            let private_key_pem = std::fs::read_to_string(private_key_file_path)
+                .context("Failed to read private key file")?;
+            let public_key_pem = std::fs::read_to_string(public_key_file_path)
+                .context("Failed to read public key file")?;
+            let e2ee_server = E2ee::new_from_pem(private_key_pem, public_key_pem)
+                .context("Failed to create SDK")?;
+            if path.is_dir() {
+                let manifest = signing::sign_manifest(&e2ee_server, path)
+                    .context("Failed to sign directory")?;
+                out.status(format_args!(
+                    "Manifest is saved to: {}",
+                    manifest.display()
+                ));
+            } else {
+                let signature = signing::sign_file(&e2ee_server, path)
+                    .context("Failed to sign file")?;
+                out.status(format_args!(
+                    "Signature is saved to: {}",
+                    signature.display()
+                ));
+            }
+        }
+        Commands::Verify {
+            public_key_file_path,
+            signature,
+            path,
+        } => {
+            let public_key_pem = std::fs::read_to_string(public_key_file_path)
+                .context("Failed to read public key file")?;
+            let e2ee_client = PublicE2ee::new(public_key_pem)?;
+            if path.is_dir() {
+                let files = signing::verify_manifest(&e2ee_client, path)
+                    .context("Failed to verify directory")?;
+                out.status(format_args!("Verified {} files", files));
+            } else {
+                let signature = signature
+                    .clone()
+                    .unwrap_or_else(|| signing::signature_path(path));
+                signing::verify_file(&e2ee_client, path, signature)
+                    .context("Failed to verify file")?;
+                out.status("Signature is valid");
+            }
+        }
+        Commands::Share {
+            store,
+            ttl,
+            base_url,
+            message,
+        } => {
+            let store = DirectorySecretStore::new(store)
+                .context("Failed to open secret store")?;
+            let shared = secrets::share(
+                &store,
+                message.as_bytes(),
+                Duration::from_secs(*ttl),
+            )
+            .context("Failed to share secret")?;
+            match base_url {
+                Some(base_url) => out.result("Link: ", shared.link(base_url)),
+                None => out
+                    .result("Link: ", format_args!("{}#{}", shared.id, shared.key)),
+            }
+        }
+        Commands::Reveal { store, link } => {
+            let store = DirectorySecretStore::new(store)
+                .context("Failed to open secret store")?;
+            let (id, key) = secrets::parse_link(link)?;
+            let secret = secrets::reveal(&store, id, key)
+                .context("Failed to reveal secret")?;
+            out.result("Secret: ", String::from_utf8_lossy(&secret));
+        }
+        Commands::Backup {
+            keystore,
+            output,
+            pins,
+            settings,
+        } => {
+            backup::backup(keystore, output, pins, settings, out)?;
+        }
+        Commands::Restore { input, keystore } => {
+            backup::restore(input, keystore, out)?;
+        }
+        Commands::Filter {
+            mode,
+            recipients,
+            keystore,
+        } => {
+            filter::run_filter(*mode, recipients, keystore)?;
+        }
+        Commands::Reencrypt {
+            old_key,
+            new_key,
+            keystore,
+            dry_run,
+            paths,
+        } => {
+            let old_key = private_key(old_key, keystore)?.detailed_errors();
+            let new_key =
+                recipient_keys(std::slice::from_ref(new_key), keystore)?.remove(0);
+            reencrypt::reencrypt(&old_key, &new_key, paths, *dry_run, out)?;
+        }
+        Commands::ImportKey {
+            file,
+            format,
+            public_key_file_path,
+            private_key_file_path,
+            name,
+            keystore,
+        } => {
+            formats::import_key(
+                file,
+                *format,
+                public_key_file_path.as_deref(),
+                private_key_file_path.as_deref(),
+                name.as_deref(),
+                keystore,
+                out,
+            )?;
+        }
+        Commands::ExportKey {
+            key,
+            keystore,
+            format,
+            comment,
+            output,
+        } => {
+            formats::run_export_key(
+                key,
+                keystore,
+                *format,
+                comment,
+                output.as_deref(),
+                out,
+            )?;
+        }
+        Commands::Inspect { dir } => {
+            let keys =
+                e2ee::keystore::scan_dir(dir).context("Failed to read directory")?;
+            let mut violations = 0;
+            for key in &keys {
+                let kind = if key.private { "private" } else { "public" };
+                match key.format {
+                    Some(format) => out.status(format_args!(
+                        "{}: {} {} key, {} bits",
+                        key.path.display(),
+                        format,
+                        kind,
+                        key.bits
+                    )),
+                    None => out.status(format_args!("{}:", key.path.display())),
+                }
+                if let Some(fingerprint) = &key.fingerprint {
+                    out.status(format_args!("  Fingerprint: {}", fingerprint));
+                }
+                if let Some(expires_at) = key.expires_at {
+                    let seconds = expires_at
+                        .duration_since(UNIX_EPOCH)
+                        .map(|duration| duration.as_secs())
+                        .unwrap_or_default();
+                    out.status(format_args!(
+                        "  Expires at: {} (Unix time)",
+                        seconds
+                    ));
+                }
+                for violation in &key.violations {
+                    out.status(format_args!("  Violation: {}", violation));
+                }
+                violations += usize::from(!key.is_valid());
+            }
+            if violations > 0 {
+                return Err(CliError::PolicyViolations(violations).into());
+            }
+            out.status(format_args!(
+                "{} key files comply with the policy",
+                keys.len()
+            ));
+        }
+        Commands::Env { keystore, command } => {
+            env::run_env_command(keystore, command, out)?;
+        }
+        Commands::Run {
+            env_files,
+            key,
+            keystore,
+            secrets_dir,
+            command,
+        } => {
+            run::run_with_env_files(
+                env_files,
+                key,
+                keystore,
+                secrets_dir.as_deref(),
+                command,
+            )?;
+        }
+        Commands::Keystore { keystore, command } => {
+            keystore::run_keystore_command(keystore, command, out)?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{error::ErrorKind, CommandFactory};
+    use keys::tests::temp_dir;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("e2ee-cli").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_cli_parses_arguments() {
+        Cli::command().debug_assert();
+
+        let cli = parse(&[
+            "encrypt",
+            "-m",
+            "Hi",
+            "--recipient",
+            "a",
+            "--recipient",
+            "b",
+        ])
+        .unwrap();
+        assert!(matches!(
+            &cli.command,
+            Commands::Encrypt { recipients, message, .. }
+                if recipients == &["a", "b"] && message == "Hi"
+        ));
+        // Global flags follow the subcommand too.
+        let cli = parse(&["decrypt-file", "notes.e2ee", "-q", "-vv"]).unwrap();
+        assert!(cli.quiet);
+        assert_eq!(cli.verbose, 2);
+
+        let cli = parse(&[
+            "run",
+            "--env-file",
+            "a.env",
+            "--env-file",
+            "b.env",
+            "--",
+            "app",
+            "--port",
+            "80",
+        ])
+        .unwrap();
+        assert!(matches!(
+            &cli.command,
+            Commands::Run { env_files, command, secrets_dir: None, .. }
+                if env_files.len() == 2 && command == &["app", "--port", "80"]
+        ));
+        let cli =
+            parse(&["backup", "--keystore", "k", "--pin", "alice=3f=9a"]).unwrap();
+        assert!(matches!(
+            &cli.command,
+            Commands::Backup { pins, .. }
+                if pins == &[("alice".to_string(), "3f=9a".to_string())]
+        ));
+
+        for (args, kind) in [
+            (
+                &["run", "--env-file", "a.env"][..],
+                ErrorKind::MissingRequiredArgument,
+            ),
+            (
+                &["filter", "--mode", "encrypt"],
+                ErrorKind::MissingRequiredArgument,
+            ),
+            (
+                &["encrypt-file", "notes.txt"],
+                ErrorKind::MissingRequiredArgument,
+            ),
+            (
+                &["backup", "--keystore", "k", "--pin", "alice"],
+                ErrorKind::ValueValidation,
+            ),
+            (&["export-key", "--format", "pgp"], ErrorKind::InvalidValue),
+            (
+                &[
+                    "import-key",
+                    "key.pem",
+                    "--name",
+                    "a",
+                    "--private-key-file-path",
+                    "p",
+                ],
+                ErrorKind::ArgumentConflict,
+            ),
+        ] {
+            assert_eq!(
+                parse(args).err().map(|error| error.kind()),
+                Some(kind),
+                "{args:?}"
+            );
+        }
+        assert!(parse(&["filter", "--mode", "decrypt"]).is_ok());
+    }
+
+    #[test]
+    fn test_commands_round_trip_files() {
+        let dir = temp_dir("main");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let run_args =
+            |args: &[&str]| run(&parse(args).unwrap(), Output { quiet: true });
+        run_args(&[
+            "generate-keys",
+            "--size",
+            "bit1024",
+            "--public-key-file-path",
+            &path("public.pem"),
+            "--private-key-file-path",
+            &path("private.pem"),
+        ])
+        .unwrap();
+        let e2ee_server = E2ee::new_from_pem(
+            std::fs::read_to_string(path("private.pem")).unwrap(),
+            std::fs::read_to_string(path("public.pem")).unwrap(),
+        )
+        .unwrap();
+
+        std::fs::write(path("notes.txt"), "Hello files!").unwrap();
+        run_args(&[
+            "encrypt-file",
+            "--recipient",
+            &path("public.pem"),
+            &path("notes.txt"),
+        ])
+        .unwrap();
+        let envelope = std::fs::read(path("notes.txt.e2ee")).unwrap();
+        assert_eq!(
+            e2ee_server.decrypt_envelope(&envelope).unwrap(),
+            b"Hello files!"
+        );
+
+        let keys = [(
+            e2ee::core::key_id(e2ee_server.get_public_key()).unwrap(),
+            e2ee_server,
+        )];
+        assert_eq!(open_envelope(&keys, &envelope).unwrap(), b"Hello files!");
+
+        let sign = [
+            "sign",
+            "--private-key-file-path",
+            &path("private.pem"),
+            "--public-key-file-path",
+            &path("public.pem"),
+            &path("notes.txt"),
+        ];
+        run_args(&sign).unwrap();
+        let verify = [
+            "verify",
+            "--public-key-file-path",
+            &path("public.pem"),
+            &path("notes.txt"),
+        ];
+        run_args(&verify).unwrap();
+        std::fs::write(path("notes.txt"), "Hello tampered files!").unwrap();
+        let error = run_args(&verify).unwrap_err();
+        assert_eq!(Failure::of(&error), Failure::BadCiphertext);
+
+        // A recipient that is not a file is looked up in a keystore, which does not exist.
+        let keystore = path("keystore.e2ee");
+        assert!(run_args(&[
+            "encrypt-file",
+            "--keystore",
+            &keystore,
+            "--recipient",
+            &path("missing.pem"),
+            &path("notes.txt"),
+        ])
+        .is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,191 @@
+//! The `reencrypt` command, rewrapping envelope files from an old key to a new key.
+use crate::Output;
+use anyhow::{Context, Result};
+use e2ee::{
+    client::PublicE2ee,
+    core,
+    envelope::{json, Envelope},
+    server::E2ee,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Rewraps the envelope files under `paths` that are encrypted for `old_key` for `new_key`, and
+/// reports a summary. Other files are skipped, so that the command can be run again after a
+/// failure.
+pub fn reencrypt(
+    old_key: &E2ee,
+    new_key: &PublicE2ee,
+    paths: &[PathBuf],
+    dry_run: bool,
+    out: Output,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        list_files(path, &mut files)
+            .with_context(|| format!("Failed to list {}", path.display()))?;
+    }
+    let key_id = core::key_id(old_key.get_public_key())?;
+    let (mut rewrapped, mut skipped) = (0, 0);
+    let mut failures = Vec::new();
+    for file in &files {
+        match reencrypt_file(old_key, &key_id, new_key, file, dry_run) {
+            Ok(true) => {
+                rewrapped += 1;
+                out.result(
+                    if dry_run {
+                        "Would rewrap: "
+                    } else {
+                        "Rewrapped: "
+                    },
+                    file.display(),
+                );
+            }
+            Ok(false) => skipped += 1,
+            Err(error) => {
+                if !out.quiet {
+                    eprintln!("Failed to rewrap {}: {:#}", file.display(), error);
+                }
+                failures.push(error);
+            }
+        }
+    }
+    out.status(format_args!(
+        "{} {} envelopes, skipped {} other files, {} failed",
+        if dry_run { "Would rewrap" } else { "Rewrapped" },
+        rewrapped,
+        skipped,
+        failures.len()
+    ));
+    let failed = failures.len();
+    match failures.into_iter().next() {
+        Some(error) => {
+            Err(error.context(format!("Failed to rewrap {} files", failed)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Rewraps the envelope at `path` for `new_key`, through a temporary file. Returns `false` if
+/// the file is not an envelope encrypted for `old_key`, whose key ID is `key_id`.
+fn reencrypt_file(
+    old_key: &E2ee,
+    key_id: &str,
+    new_key: &PublicE2ee,
+    path: &Path,
+    dry_run: bool,
+) -> Result<bool> {
+    let envelope = std::fs::read(path).context("Failed to read file")?;
+    if !is_recipient(&envelope, key_id) {
+        return Ok(false);
+    }
+    let rewrapped = old_key
+        .rewrap(&envelope, new_key.get_public_key())
+        .context("Failed to rewrap envelope")?;
+    if !dry_run {
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, rewrapped).context("Failed to write envelope")?;
+        std::fs::rename(&temporary, path).context("Failed to write envelope")?;
+    }
+    Ok(true)
+}
+
+/// Returns whether `envelope` is a binary or JSON envelope with `key_id` among its recipients, or
+/// an envelope of version 1, which does not name its recipient.
+fn is_recipient(envelope: &[u8], key_id: &str) -> bool {
+    let decoded;
+    let envelope = if json::is_json(envelope) {
+        match std::str::from_utf8(envelope).map(json::from_json) {
+            Ok(Ok(binary)) => {
+                decoded = binary;
+                &decoded
+            }
+            _ => return false,
+        }
+    } else {
+        envelope
+    };
+    let Ok(envelope) = Envelope::parse(envelope) else {
+        return false;
+    };
+    let key_ids: Vec<_> = envelope
+        .recipients()
+        .iter()
+        .filter_map(|recipient| recipient.key_id())
+        .collect();
+    key_ids.is_empty() || key_ids.iter().any(|id| id == key_id)
+}
+
+/// Adds `path` to `files` if it is a file, or the files under it, sorted, if it is a directory.
+/// Symbolic links are not followed.
+fn list_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let file_type = std::fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            list_files(&entry, files)?;
+        }
+    } else if file_type.is_file() {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{seal_for, tests::temp_dir};
+    use e2ee::server::KeySize;
+
+    #[test]
+    fn test_reencrypt_rewraps_the_envelopes_of_the_old_key() {
+        let dir = temp_dir("reencrypt");
+        let old_key = E2ee::new(KeySize::Bit1024).unwrap();
+        let new_key = E2ee::new(KeySize::Bit1024).unwrap();
+        let other = E2ee::new(KeySize::Bit1024).unwrap();
+        let public = |e2ee_server: &E2ee| {
+            PublicE2ee::new(e2ee_server.get_public_key_pem().to_string()).unwrap()
+        };
+        let key_id = core::key_id(old_key.get_public_key()).unwrap();
+
+        std::fs::create_dir(dir.join("nested")).unwrap();
+        let envelope = seal_for(&[public(&old_key)], b"Hello rewrap!").unwrap();
+        assert!(is_recipient(&envelope, &key_id));
+        std::fs::write(dir.join("nested/a.e2ee"), &envelope).unwrap();
+        let for_other = seal_for(&[public(&other)], b"Not mine").unwrap();
+        assert!(!is_recipient(&for_other, &key_id));
+        std::fs::write(dir.join("b.e2ee"), &for_other).unwrap();
+        std::fs::write(dir.join("notes.txt"), "plain text").unwrap();
+        assert!(!is_recipient(b"plain text", &key_id));
+
+        let mut files = Vec::new();
+        list_files(&dir, &mut files).unwrap();
+        assert_eq!(
+            files,
+            [
+                dir.join("b.e2ee"),
+                dir.join("nested/a.e2ee"),
+                dir.join("notes.txt")
+            ]
+        );
+
+        let (out, paths) = (Output { quiet: true }, [dir.clone()]);
+        reencrypt(&old_key, &public(&new_key), &paths, true, out).unwrap();
+        assert_eq!(std::fs::read(dir.join("nested/a.e2ee")).unwrap(), envelope);
+        reencrypt(&old_key, &public(&new_key), &paths, false, out).unwrap();
+        let rewrapped = std::fs::read(dir.join("nested/a.e2ee")).unwrap();
+        assert_eq!(
+            new_key.decrypt_envelope(&rewrapped).unwrap(),
+            b"Hello rewrap!"
+        );
+        assert!(old_key.decrypt_envelope(&rewrapped).is_err());
+        assert_eq!(std::fs::read(dir.join("b.e2ee")).unwrap(), for_other);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,306 @@
+//! The `run` command, running a command with the secrets of encrypted environment files.
+//!
+//! The secrets are passed in the environment of the command, or with `--secrets-dir` in files
+//! of a directory on a tmpfs, named after their variables, whose paths are passed in
+//! `<NAME>_FILE` variables. Memory-backed file systems are only detected on Linux, from
+//! `/proc/self/mounts`: on other systems, `--secrets-dir` is refused.
+use crate::{env::read_env_file, error::CliError, keys::private_key};
+use anyhow::{Context, Result};
+use e2ee::{dotenv, server::E2ee};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+use zeroize::Zeroizing;
+
+/// A secret variable, with its name and value.
+type Secret = (String, Zeroizing<String>);
+
+/// Decrypts `env_files` with `key`, a private key pem file or the name of a key in `keystore`,
+/// and runs `command` with their secrets, then exits with its exit code.
+pub fn run_with_env_files(
+    env_files: &[PathBuf],
+    key: &str,
+    keystore: &Path,
+    secrets_dir: Option<&Path>,
+    command: &[String],
+) -> Result<()> {
+    let secrets = decrypt_env_files(env_files, &private_key(key, keystore)?)?;
+    run_with_secrets(command, secrets, secrets_dir)
+}
+
+/// Returns the variables of `env_files` decrypted with `e2ee_server`, in order, so that the
+/// variables of later files override those of earlier ones.
+fn decrypt_env_files(
+    env_files: &[PathBuf],
+    e2ee_server: &E2ee,
+) -> Result<Vec<Secret>> {
+    let mut secrets = Vec::new();
+    for file in env_files {
+        let contents = Zeroizing::new(
+            dotenv::decrypt(&read_env_file(file)?, e2ee_server.get_private_key())
+                .context("Failed to decrypt environment file")?,
+        );
+        secrets.extend(
+            dotenv::parse(&contents).context("Failed to parse environment file")?,
+        );
+    }
+    Ok(secrets)
+}
+
+/// Runs `command` with `secrets` in its environment or, with `secrets_dir`, in files of that
+/// directory, and exits with its exit code.
+fn run_with_secrets(
+    command: &[String],
+    secrets: Vec<Secret>,
+    secrets_dir: Option<&Path>,
+) -> Result<()> {
+    let mut child = Command::new(&command[0]);
+    child.args(&command[1..]);
+    let Some(secrets_dir) = secrets_dir else {
+        for (name, value) in &secrets {
+            child.env(name, value.as_str());
+        }
+        drop(secrets);
+        return exec(child);
+    };
+    let status = run_with_secret_files(child, &secrets, secrets_dir);
+    drop(secrets);
+    std::process::exit(status?.code().unwrap_or(1));
+}
+
+/// Writes each of `secrets` to a file of `secrets_dir` named after its variable, runs `child`
+/// with the `<NAME>_FILE` variables holding their paths, and removes the files when it exits.
+/// The directory must be on a tmpfs, so that the secrets never reach persistent storage.
+fn run_with_secret_files(
+    mut child: Command,
+    secrets: &[Secret],
+    secrets_dir: &Path,
+) -> Result<ExitStatus> {
+    if !is_memory_backed(secrets_dir)
+        .with_context(|| format!("Failed to inspect {}", secrets_dir.display()))?
+    {
+        return Err(CliError::PersistentSecretsDir(
+            secrets_dir.display().to_string(),
+        )
+        .into());
+    }
+
+    let mut paths = Vec::new();
+    let mut write_secrets = || -> Result<()> {
+        for (name, value) in secrets {
+            anyhow::ensure!(
+                !name.starts_with('.'),
+                "Invalid secret file name {}",
+                name
+            );
+            let path = secrets_dir.join(name);
+            write_secret_file(&path, value)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            child.env(format!("{}_FILE", name), &path);
+            paths.push(path);
+        }
+        Ok(())
+    };
+    let status = write_secrets().and_then(|()| {
+        child.status().with_context(|| {
+            format!("Failed to run {}", child.get_program().to_string_lossy())
+        })
+    });
+    for path in &paths {
+        let _ = std::fs::remove_file(path);
+    }
+    status
+}
+
+/// Replaces the current process with `command`, so that it receives the signals of the
+/// container or service manager directly.
+#[cfg(unix)]
+fn exec(mut command: Command) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    let program = command.get_program().to_string_lossy().into_owned();
+    Err(command.exec()).with_context(|| format!("Failed to run {}", program))
+}
+
+/// Runs `command` and exits with its exit code.
+#[cfg(not(unix))]
+fn exec(mut command: Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {}", program))?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+/// Creates the file `path`, readable by the current user only, and writes `value` to it. The file
+/// must not exist yet.
+fn write_secret_file(path: &Path, value: &str) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(value.as_bytes())
+}
+
+/// Returns whether `path` is on a memory-backed file system (tmpfs or ramfs), according to
+/// `/proc/self/mounts`.
+#[cfg(target_os = "linux")]
+fn is_memory_backed(path: &Path) -> io::Result<bool> {
+    let path = path.canonicalize()?;
+    let mounts = std::fs::read_to_string("/proc/self/mounts")?;
+    Ok(matches!(
+        file_system(&path, &mounts),
+        Some("tmpfs" | "ramfs")
+    ))
+}
+
+/// Fails: memory-backed file systems are only detected on Linux, so no directory is known to
+/// keep the secrets off persistent storage.
+#[cfg(not(target_os = "linux"))]
+fn is_memory_backed(_path: &Path) -> io::Result<bool> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "tmpfs mounts are only detected on Linux",
+    ))
+}
+
+/// Returns the file system of the longest mount point of `mounts`, in the format of
+/// `/proc/self/mounts`, containing the canonical `path`.
+#[cfg(target_os = "linux")]
+fn file_system<'a>(path: &Path, mounts: &'a str) -> Option<&'a str> {
+    mounts
+        .lines()
+        .filter_map(|mount| {
+            let mut fields = mount.split_whitespace().skip(1);
+            Some((Path::new(fields.next()?), fields.next()?))
+        })
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.as_os_str().len())
+        .map(|(_, file_system)| file_system)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::keys::tests::temp_dir;
+    use e2ee::server::KeySize;
+
+    fn secret(name: &str, value: &str) -> Secret {
+        (name.to_string(), Zeroizing::new(value.to_string()))
+    }
+
+    #[test]
+    fn test_file_system_of_the_longest_mount_point() {
+        let mounts = "overlay / overlay rw 0 0\n\
+                      proc /proc proc rw 0 0\n\
+                      tmpfs /run tmpfs rw 0 0\n\
+                      /dev/sda1 /run/media ext4 rw 0 0\n";
+        assert_eq!(file_system(Path::new("/home/app"), mounts), Some("overlay"));
+        assert_eq!(
+            file_system(Path::new("/run/secrets"), mounts),
+            Some("tmpfs")
+        );
+        assert_eq!(
+            file_system(Path::new("/run/media/usb"), mounts),
+            Some("ext4")
+        );
+        // Mount points match whole path components only.
+        assert_eq!(file_system(Path::new("/runner"), mounts), Some("overlay"));
+        assert_eq!(file_system(Path::new("/home"), ""), None);
+    }
+
+    #[test]
+    fn test_secret_files_are_removed_after_the_command_exits() {
+        // Nothing is written outside of a memory-backed directory.
+        let error = run_with_secret_files(
+            Command::new("true"),
+            &[secret("API_KEY", "s3cr3t")],
+            Path::new("/proc"),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CliError>(),
+            Some(CliError::PersistentSecretsDir(_))
+        ));
+
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() || !is_memory_backed(shm).unwrap() {
+            eprintln!("Skipping test: /dev/shm is not a tmpfs");
+            return;
+        }
+        let secrets_dir =
+            shm.join(format!("e2ee-cli-test-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&secrets_dir);
+        std::fs::create_dir(&secrets_dir).unwrap();
+        let dir = temp_dir("run");
+        let report = dir.join("report");
+
+        // The command reads the secrets from the files named by the `_FILE` variables.
+        let mut child = Command::new("sh");
+        child
+            .arg("-c")
+            .arg(r#"cat "$API_KEY_FILE" "$DB_PASSWORD_FILE" > "$1"; stat -c %a "$API_KEY_FILE" >> "$1"; exit 3"#)
+            .arg("sh")
+            .arg(&report);
+        let secrets = [
+            secret("API_KEY", "s3cr3t"),
+            secret("DB_PASSWORD", "hunter2"),
+        ];
+        let status = run_with_secret_files(child, &secrets, &secrets_dir).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(
+            std::fs::read_to_string(&report).unwrap(),
+            "s3cr3thunter2600\n"
+        );
+        assert_eq!(std::fs::read_dir(&secrets_dir).unwrap().count(), 0);
+
+        // A file in the way fails the command before it runs, and the files written before are
+        // removed.
+        std::fs::write(secrets_dir.join("DB_PASSWORD"), "").unwrap();
+        std::fs::remove_file(&report).unwrap();
+        let mut child = Command::new("sh");
+        child.arg("-c").arg(r#"touch "$1""#).arg("sh").arg(&report);
+        assert!(run_with_secret_files(child, &secrets, &secrets_dir).is_err());
+        assert!(!report.exists());
+        assert!(!secrets_dir.join("API_KEY").exists());
+        assert!(run_with_secret_files(
+            Command::new("true"),
+            &[secret(".hidden", "")],
+            &secrets_dir
+        )
+        .is_err());
+
+        std::fs::remove_dir_all(&secrets_dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_later_env_files_override_earlier_ones() {
+        let dir = temp_dir("run-env");
+        let e2ee_server = E2ee::new(KeySize::Bit1024).unwrap();
+        let recipients = [e2ee_server.get_public_key().clone()];
+        for (name, contents) in [
+            ("base.env", "API_KEY=base\nDEBUG=0\n"),
+            ("local.env", "API_KEY=local\n"),
+        ] {
+            let encrypted = dotenv::encrypt(contents, &recipients).unwrap();
+            std::fs::write(dir.join(name), encrypted).unwrap();
+        }
+        let secrets = decrypt_env_files(
+            &[dir.join("base.env"), dir.join("local.env")],
+            &e2ee_server,
+        )
+        .unwrap();
+        let secrets: Vec<_> = secrets
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        // `Command::env` keeps the last value of a variable.
+        assert_eq!(
+            secrets,
+            [("API_KEY", "base"), ("DEBUG", "0"), ("API_KEY", "local")]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
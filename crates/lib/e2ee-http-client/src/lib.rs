@@ -0,0 +1,291 @@
+//! # E2EE HTTP client
+//!
+//! A [`reqwest_middleware`] middleware that encrypts HTTP bodies with the `e2ee` library, so that
+//! they stay confidential across proxies and load balancers that terminate TLS.
+//!
+//! [`EncryptionMiddleware`] encrypts the body of every outgoing request into an envelope (see
+//! `e2ee::envelope`) for the server's public key, and sends it with the
+//! `application/vnd.e2ee.envelope` content type. With [`EncryptionMiddleware::with_response_key`],
+//! it also sends the client's public key in the `e2ee-client-key` header and decrypts responses
+//! carrying the envelope content type, so that handlers see plain responses.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use e2ee::client::PublicE2ee;
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee_http_client::EncryptionMiddleware;
+//! use reqwest_middleware::ClientBuilder;
+//!
+//! # async fn run(server_public_key_pem: String) -> Result<(), Box<dyn std::error::Error>> {
+//! let server = PublicE2ee::new(server_public_key_pem)?;
+//! let client = ClientBuilder::new(reqwest::Client::new())
+//!     .with(EncryptionMiddleware::new(server).with_response_key(E2ee::new(KeySize::Bit2048)?))
+//!     .build();
+//!
+//! let response = client
+//!     .post("https://api.example.com/messages")
+//!     .body(r#"{"text":"Hello, world!"}"#)
+//!     .send()
+//!     .await?;
+//! println!("{}", response.text().await?);
+//! # Ok(())
+//! # }
+//! ```
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use e2ee::client::PublicE2ee;
+use e2ee::envelope::{self, CLIENT_KEY_HEADER, ORIGINAL_CONTENT_TYPE_HEADER};
+use e2ee::server::E2ee;
+use http::{header, Extensions, HeaderValue};
+use reqwest::{Request, Response, ResponseBuilderExt};
+use reqwest_middleware::{Middleware, Next, Result};
+use std::sync::Arc;
+
+/// A middleware encrypting request bodies for a server, and decrypting its encrypted responses.
+#[derive(Debug, Clone)]
+pub struct EncryptionMiddleware {
+    server: Arc<PublicE2ee>,
+    response_key: Option<ResponseKey>,
+}
+
+#[derive(Debug, Clone)]
+struct ResponseKey {
+    e2ee: Arc<E2ee>,
+    header: HeaderValue,
+}
+
+impl EncryptionMiddleware {
+    /// Creates a middleware encrypting request bodies for the server holding the private key of
+    /// `server`.
+    pub fn new(server: PublicE2ee) -> Self {
+        Self {
+            server: Arc::new(server),
+            response_key: None,
+        }
+    }
+
+    /// Asks the server to encrypt responses for `client`, and decrypts them with it.
+    ///
+    /// The public key of `client` is sent in the `e2ee-client-key` header of every request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the public key of `client` cannot be DER-encoded, which does not happen for keys
+    /// created by `E2ee`.
+    pub fn with_response_key(mut self, client: E2ee) -> Self {
+        let header = envelope::encode_client_key(client.get_public_key())
+            .ok()
+            .and_then(|value| HeaderValue::from_str(&value).ok())
+            .expect("an RSA public key can be encoded as a header");
+        self.response_key = Some(ResponseKey {
+            e2ee: Arc::new(client),
+            header,
+        });
+        self
+    }
+
+    fn encrypt_request(&self, request: &mut Request) -> anyhow::Result<()> {
+        if let Some(key) = &self.response_key {
+            request
+                .headers_mut()
+                .insert(CLIENT_KEY_HEADER, key.header.clone());
+        }
+        let Some(body) = request.body_mut() else {
+            return Ok(());
+        };
+        let payload = body.as_bytes().ok_or_else(|| {
+            anyhow!("streaming request bodies cannot be encrypted")
+        })?;
+        let encrypted = self
+            .server
+            .encrypt_envelope(payload)
+            .context("failed to encrypt the request body")?;
+        *body = encrypted.into();
+
+        let headers = request.headers_mut();
+        if let Some(content_type) = headers.remove(header::CONTENT_TYPE) {
+            headers.insert(ORIGINAL_CONTENT_TYPE_HEADER, content_type);
+        }
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(envelope::CONTENT_TYPE),
+        );
+        headers.remove(header::CONTENT_LENGTH);
+        Ok(())
+    }
+
+    async fn decrypt_response(
+        &self,
+        response: Response,
+    ) -> anyhow::Result<Response> {
+        let Some(key) = &self.response_key else {
+            return Ok(response);
+        };
+        if !is_envelope(response.headers()) {
+            return Ok(response);
+        }
+
+        let status = response.status();
+        let version = response.version();
+        let url = response.url().clone();
+        let mut headers = response.headers().clone();
+        let encrypted = response
+            .bytes()
+            .await
+            .context("failed to read the response body")?;
+        let payload = key
+            .e2ee
+            .decrypt_envelope(&encrypted)
+            .context("failed to decrypt the response body")?;
+
+        headers.remove(header::CONTENT_TYPE);
+        headers.remove(header::CONTENT_LENGTH);
+        if let Some(content_type) = headers.remove(ORIGINAL_CONTENT_TYPE_HEADER) {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        let mut builder = http::Response::builder()
+            .status(status)
+            .version(version)
+            .url(url);
+        if let Some(builder_headers) = builder.headers_mut() {
+            *builder_headers = headers;
+        }
+        Ok(builder.body(payload)?.into())
+    }
+}
+
+#[async_trait]
+impl Middleware for EncryptionMiddleware {
+    async fn handle(
+        &self,
+        mut request: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        self.encrypt_request(&mut request)?;
+        let response = next.run(request, extensions).await?;
+        Ok(self.decrypt_response(response).await?)
+    }
+}
+
+/// Returns whether `headers` declare an envelope body.
+fn is_envelope(headers: &http::HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| {
+            media_type
+                .trim()
+                .eq_ignore_ascii_case(envelope::CONTENT_TYPE)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Bytes, http::HeaderMap, response::IntoResponse, routing::post, Router,
+    };
+    use e2ee::server::KeySize;
+    use http::HeaderName;
+    use reqwest_middleware::ClientBuilder;
+    use std::net::SocketAddr;
+
+    /// Starts a server that decrypts request bodies with `server` and echoes them back, encrypted
+    /// for the client key of the request if there is one.
+    async fn spawn_echo_server(server: E2ee) -> SocketAddr {
+        let server = Arc::new(server);
+        let app = Router::new().route(
+            "/echo",
+            post(move |headers: HeaderMap, body: Bytes| async move {
+                assert!(is_envelope(&headers));
+                let payload = server.decrypt_envelope(&body).unwrap();
+                let content_type = headers[ORIGINAL_CONTENT_TYPE_HEADER].clone();
+                match headers.get(CLIENT_KEY_HEADER) {
+                    Some(client_key) => {
+                        let client_key = envelope::decode_client_key(
+                            client_key.to_str().unwrap(),
+                        )
+                        .unwrap();
+                        let encrypted =
+                            envelope::seal(&client_key, &payload).unwrap();
+                        (
+                            [
+                                (
+                                    header::CONTENT_TYPE,
+                                    HeaderValue::from_static(envelope::CONTENT_TYPE),
+                                ),
+                                (
+                                    HeaderName::from_static(
+                                        ORIGINAL_CONTENT_TYPE_HEADER,
+                                    ),
+                                    content_type,
+                                ),
+                            ],
+                            encrypted,
+                        )
+                            .into_response()
+                    }
+                    None => ([(header::CONTENT_TYPE, content_type)], payload)
+                        .into_response(),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    fn public_e2ee(e2ee: &E2ee) -> PublicE2ee {
+        PublicE2ee::from_public_key(e2ee.get_public_key().clone()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_request_and_response_are_encrypted() {
+        let server = E2ee::new(KeySize::Bit2048).unwrap();
+        let middleware = EncryptionMiddleware::new(public_e2ee(&server))
+            .with_response_key(E2ee::new(KeySize::Bit2048).unwrap());
+        let addr = spawn_echo_server(server).await;
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let body = "x".repeat(10_000);
+        let response = client
+            .post(format!("http://{addr}/echo"))
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(body.clone())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.url().path(), "/echo");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(response.text().await.unwrap(), body);
+    }
+
+    #[tokio::test]
+    async fn test_plain_responses_pass_through() {
+        let server = E2ee::new(KeySize::Bit2048).unwrap();
+        let middleware = EncryptionMiddleware::new(public_e2ee(&server));
+        let addr = spawn_echo_server(server).await;
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let response = client
+            .post(format!("http://{addr}/echo"))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(r#"{"text":"Hello, world!"}"#)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            response.text().await.unwrap(),
+            r#"{"text":"Hello, world!"}"#
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! An end-to-end encrypted chat client, talking to a peer through the `chat_server` relay.
+//!
+//! Each participant owns an RSA identity key pair and knows the public key of the other. One of
+//! them starts the handshake with `--initiate`; once the session is established, lines typed on
+//! stdin are encrypted with the Double Ratchet and sent to the peer.
+//!
+//! ```sh
+//! cargo run -p e2ee --example chat_server --features session
+//! cargo run -p e2ee --example chat_client --features session -- \
+//!     ws://127.0.0.1:9001 bob.private.pem bob.public.pem alice.public.pem
+//! cargo run -p e2ee --example chat_client --features session -- --initiate \
+//!     ws://127.0.0.1:9001 alice.private.pem alice.public.pem bob.public.pem
+//! ```
+use clap::Parser;
+use e2ee::client::PublicE2ee;
+use e2ee::server::E2ee;
+use e2ee::session::{Handshake, HandshakeMessage, Session};
+use futures_util::{SinkExt, Stream, StreamExt};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+#[derive(Parser)]
+struct Cli {
+    #[arg(long, help = "Start the handshake, instead of waiting for the peer")]
+    initiate: bool,
+    #[arg(help = "URL of the chat server")]
+    url: String,
+    #[arg(help = "Path to the private key of this identity")]
+    private_key_file_path: PathBuf,
+    #[arg(help = "Path to the public key of this identity")]
+    public_key_file_path: PathBuf,
+    #[arg(help = "Path to the public key of the peer")]
+    peer_public_key_file_path: PathBuf,
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let identity = E2ee::new_from_pem(
+        std::fs::read_to_string(&cli.private_key_file_path)
+            .expect("Failed to read private key file"),
+        std::fs::read_to_string(&cli.public_key_file_path)
+            .expect("Failed to read public key file"),
+    )
+    .expect("Failed to load identity keys");
+    let peer = PublicE2ee::new(
+        std::fs::read_to_string(&cli.peer_public_key_file_path)
+            .expect("Failed to read peer public key file"),
+    )
+    .expect("Failed to load peer public key");
+
+    let (websocket, _) = tokio_tungstenite::connect_async(&cli.url)
+        .await
+        .expect("Failed to connect to chat server");
+    let (mut sink, mut stream) = websocket.split();
+
+    // Establish the session: a signed ephemeral key in each direction.
+    let mut session: Session = if cli.initiate {
+        let (handshake, hello) =
+            Handshake::initiate(&identity).expect("Failed to start handshake");
+        sink.send(Message::Binary(hello.to_bytes()))
+            .await
+            .expect("Failed to send handshake");
+        let reply = receive(&mut stream)
+            .await
+            .expect("Connection closed during handshake");
+        let reply =
+            HandshakeMessage::from_bytes(&reply).expect("Malformed handshake reply");
+        handshake
+            .complete(peer.get_public_key(), &reply)
+            .expect("Failed to complete handshake")
+    } else {
+        let hello = receive(&mut stream)
+            .await
+            .expect("Connection closed during handshake");
+        let hello =
+            HandshakeMessage::from_bytes(&hello).expect("Malformed handshake");
+        let (session, reply) =
+            Handshake::respond(&identity, peer.get_public_key(), &hello)
+                .expect("Failed to accept handshake");
+        sink.send(Message::Binary(reply.to_bytes()))
+            .await
+            .expect("Failed to send handshake reply");
+        session
+    };
+    println!("Session established, type messages and press Enter");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let Ok(Some(line)) = line else { break };
+                let message = session.encrypt(line.as_bytes()).expect("Failed to encrypt");
+                sink.send(Message::Binary(message))
+                    .await
+                    .expect("Failed to send message");
+            }
+            message = receive(&mut stream) => {
+                let Some(message) = message else { break };
+                match session.decrypt(&message) {
+                    Ok(plaintext) => println!("peer: {}", String::from_utf8_lossy(&plaintext)),
+                    Err(err) => eprintln!("Dropped message: {err}"),
+                }
+            }
+        }
+    }
+}
+
+/// Returns the next binary frame, or `None` once the connection is closed.
+async fn receive(
+    stream: &mut (impl Stream<Item = Result<Message, WsError>> + Unpin),
+) -> Option<Vec<u8>> {
+    loop {
+        match stream.next().await? {
+            Ok(Message::Binary(bytes)) => return Some(bytes),
+            Ok(_) => continue,
+            Err(_) => return None,
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! A WebSocket relay for the `chat_client` example.
+//!
+//! The relay forwards every binary frame to the other connected clients. It only ever sees
+//! handshake messages and ciphertexts: the conversation is encrypted end to end.
+//!
+//! ```sh
+//! cargo run -p e2ee --example chat_server --features session -- 127.0.0.1:9001
+//! ```
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_tungstenite::tungstenite::Message;
+
+type Clients = Arc<Mutex<HashMap<SocketAddr, mpsc::UnboundedSender<Message>>>>;
+
+#[tokio::main]
+async fn main() {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "127.0.0.1:9001".to_string());
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Failed to bind address");
+    println!("Relaying on ws://{address}");
+
+    let clients = Clients::default();
+    while let Ok((stream, peer)) = listener.accept().await {
+        tokio::spawn(relay(stream, peer, Arc::clone(&clients)));
+    }
+}
+
+async fn relay(stream: tokio::net::TcpStream, peer: SocketAddr, clients: Clients) {
+    let Ok(websocket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    println!("{peer} connected");
+    let (mut sink, mut stream) = websocket.split();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    clients.lock().unwrap().insert(peer, sender);
+
+    let forward = tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    while let Some(Ok(message)) = stream.next().await {
+        if !message.is_binary() {
+            continue;
+        }
+        for (address, client) in clients.lock().unwrap().iter() {
+            if *address != peer {
+                client.send(message.clone()).ok();
+            }
+        }
+    }
+
+    clients.lock().unwrap().remove(&peer);
+    forward.abort();
+    println!("{peer} disconnected");
+}
@@ -36,5 +36,10 @@ fn main() {
 
     // Output the encrypted message
     println!("Public pem:\n{}\n", e2ee.get_public_key_pem());
-    println!("Private pem:\n{}", e2ee.get_private_key_pem());
+    println!(
+        "Private pem:\n{}",
+        &*e2ee
+            .get_private_key_pem()
+            .expect("Failed to encode private key")
+    );
 }
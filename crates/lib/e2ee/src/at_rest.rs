@@ -0,0 +1,257 @@
+//! Encryption of database fields by the server that owns them.
+//!
+//! RSA is slow and only encrypts short messages, so encrypting every field of a table with
+//! `E2ee::encrypt` does not scale. Instead, the server generates a [`DataKey`] once, stores it
+//! wrapped under its RSA public key (e.g. in a configuration table), and encrypts fields with
+//! AES-256-GCM under the data key. [`DataKey::seal`] authenticates the table and column
+//! identifiers of each field, so that a ciphertext copied into another column fails to open.
+//!
+//! To rotate the RSA key pair, [`rewrap`] the data key under the new public key: the fields
+//! themselves are not re-encrypted.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::at_rest::{self, DataKey};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let wrapped = DataKey::generate()
+//!     .wrap(e2ee.get_public_key())
+//!     .expect("Failed to wrap data key");
+//!
+//! let data_key = DataKey::unwrap(&e2ee, &wrapped).expect("Failed to unwrap data key");
+//! let sealed = data_key
+//!     .seal("users", "email", b"kha@example.com")
+//!     .expect("Failed to seal field");
+//! assert_eq!(
+//!     data_key.open("users", "email", &sealed).unwrap(),
+//!     b"kha@example.com"
+//! );
+//! assert!(data_key.open("users", "name", &sealed).is_err());
+//!
+//! // Rotate the RSA key pair.
+//! let rotated = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let wrapped = at_rest::rewrap(&e2ee, rotated.get_public_key(), &wrapped)
+//!     .expect("Failed to rewrap data key");
+//! let data_key = DataKey::unwrap(&rotated, &wrapped).expect("Failed to unwrap data key");
+//! assert_eq!(
+//!     data_key.open("users", "email", &sealed).unwrap(),
+//!     b"kha@example.com"
+//! );
+//! ```
+use crate::core;
+use crate::server::E2ee;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    RsaPublicKey,
+};
+use std::fmt;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the format of wrapped keys and sealed fields.
+pub const VERSION: u8 = 1;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+pub type AtRestResult<T> = std::result::Result<T, AtRestError>;
+
+/// An error returned when wrapping a data key or sealing a field.
+#[derive(Error, Debug)]
+pub enum AtRestError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::Error),
+
+    #[error("Malformed wrapped key or sealed field")]
+    Malformed,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid data key, or the field was tampered with or moved")]
+    Invalid,
+}
+
+/// An AES-256 key encrypting the fields of a database, stored wrapped under an RSA public key.
+#[derive(Clone)]
+pub struct DataKey {
+    key: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataKey").finish_non_exhaustive()
+    }
+}
+
+impl DataKey {
+    /// Generates a random data key.
+    pub fn generate() -> Self {
+        let mut key = Zeroizing::new([0; KEY_LEN]);
+        OsRng.fill_bytes(key.as_mut());
+        Self { key }
+    }
+
+    /// Encrypts the data key under `public_key`, to be stored next to the data.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if RSA encryption fails.
+    pub fn wrap(&self, public_key: &RsaPublicKey) -> AtRestResult<Vec<u8>> {
+        let mut wrapped = vec![VERSION];
+        wrapped.extend(core::encrypt(public_key, self.key.as_ref())?);
+        Ok(wrapped)
+    }
+
+    /// Decrypts a data key wrapped with [`DataKey::wrap`] under the public key of `e2ee`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key was wrapped under another public key or was
+    /// tampered with.
+    pub fn unwrap(e2ee: &E2ee, wrapped: &[u8]) -> AtRestResult<Self> {
+        let (&version, encrypted) =
+            wrapped.split_first().ok_or(AtRestError::Malformed)?;
+        if version != VERSION {
+            return Err(AtRestError::UnsupportedVersion(version));
+        }
+        let decrypted =
+            Zeroizing::new(core::decrypt(e2ee.get_private_key(), encrypted)?);
+        let key = <[u8; KEY_LEN]>::try_from(decrypted.as_slice())
+            .map_err(|_| AtRestError::Malformed)?;
+        Ok(Self {
+            key: Zeroizing::new(key),
+        })
+    }
+
+    /// Encrypts the value of `column` in `table`. The identifiers are authenticated but not
+    /// stored: the same ones must be given to [`DataKey::open`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    pub fn seal(
+        &self,
+        table: &str,
+        column: &str,
+        plaintext: &[u8],
+    ) -> AtRestResult<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let encrypted = Aes256Gcm::new(self.key.as_ref().into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &aad(table, column),
+                },
+            )
+            .map_err(|_| AtRestError::Invalid)?;
+
+        let mut sealed = vec![VERSION];
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(encrypted);
+        Ok(sealed)
+    }
+
+    /// Decrypts the value of `column` in `table` sealed with [`DataKey::seal`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `AtRestError::Invalid` if the field was sealed under another data
+    /// key or for another column, or was tampered with.
+    pub fn open(
+        &self,
+        table: &str,
+        column: &str,
+        sealed: &[u8],
+    ) -> AtRestResult<Vec<u8>> {
+        let (&version, rest) = sealed.split_first().ok_or(AtRestError::Malformed)?;
+        if version != VERSION {
+            return Err(AtRestError::UnsupportedVersion(version));
+        }
+        if rest.len() < NONCE_LEN {
+            return Err(AtRestError::Malformed);
+        }
+        let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+        Aes256Gcm::new(self.key.as_ref().into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: encrypted,
+                    aad: &aad(table, column),
+                },
+            )
+            .map_err(|_| AtRestError::Invalid)
+    }
+}
+
+/// Re-wraps a data key wrapped under the public key of `old` under `new_public_key`, when rotating
+/// the RSA key pair. The fields sealed under the data key remain readable as they are.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be unwrapped with `old` or wrapped again.
+pub fn rewrap(
+    old: &E2ee,
+    new_public_key: &RsaPublicKey,
+    wrapped: &[u8],
+) -> AtRestResult<Vec<u8>> {
+    DataKey::unwrap(old, wrapped)?.wrap(new_public_key)
+}
+
+/// Binds a field to its table and column, length-prefixed so that `("ab", "c")` and `("a", "bc")`
+/// differ.
+fn aad(table: &str, column: &str) -> Vec<u8> {
+    let mut aad = vec![VERSION];
+    for identifier in [table, column] {
+        aad.extend_from_slice(&(identifier.len() as u64).to_be_bytes());
+        aad.extend_from_slice(identifier.as_bytes());
+    }
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_fields_are_bound_to_their_column() {
+        let data_key = DataKey::generate();
+        let sealed = data_key.seal("ab", "c", b"value").unwrap();
+        assert_eq!(data_key.open("ab", "c", &sealed).unwrap(), b"value");
+        assert!(matches!(
+            data_key.open("a", "bc", &sealed),
+            Err(AtRestError::Invalid)
+        ));
+        assert!(matches!(
+            DataKey::generate().open("ab", "c", &sealed),
+            Err(AtRestError::Invalid)
+        ));
+        assert!(matches!(
+            data_key.open("ab", "c", &[VERSION, 0]),
+            Err(AtRestError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_rewrap_rotates_the_wrapping_key() {
+        let old = E2ee::new(KeySize::Bit1024).unwrap();
+        let new = E2ee::new(KeySize::Bit1024).unwrap();
+        let data_key = DataKey::generate();
+        let sealed = data_key.seal("users", "email", b"value").unwrap();
+
+        let wrapped = data_key.wrap(old.get_public_key()).unwrap();
+        let rewrapped = rewrap(&old, new.get_public_key(), &wrapped).unwrap();
+        assert!(DataKey::unwrap(&old, &rewrapped).is_err());
+        let data_key = DataKey::unwrap(&new, &rewrapped).unwrap();
+        assert_eq!(data_key.open("users", "email", &sealed).unwrap(), b"value");
+    }
+}
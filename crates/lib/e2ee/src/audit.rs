@@ -0,0 +1,293 @@
+//! Audit logging of private key usage.
+//!
+//! Attach an [`AuditLogger`] to an `E2ee` instance with `E2ee::with_audit_logger` to record every
+//! decryption and signature, together with the fingerprint of the key used, an optional
+//! caller-supplied context (e.g. a user or request ID) and a timestamp. Plaintext and ciphertext
+//! are never part of an event.
+//!
+//! With the `io` feature, [`JsonlAuditLogger`] appends events to a file as JSON lines.
+use std::{fmt, sync::Arc, time::SystemTime};
+
+/// The private key operations recorded by an [`AuditLogger`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum AuditOperation {
+    /// Decryption of a ciphertext with the private key.
+    Decrypt,
+    /// Signature of a message with the private key.
+    Sign,
+}
+
+impl AuditOperation {
+    /// Returns a short, lowercase name of the operation.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOperation::Decrypt => "decrypt",
+            AuditOperation::Sign => "sign",
+        }
+    }
+}
+
+/// A single use of a private key.
+#[derive(Debug, Clone)]
+pub struct AuditEvent<'a> {
+    /// The operation performed with the key.
+    pub operation: AuditOperation,
+    /// The SHA-256 fingerprint of the key's public half (see `core::fingerprint`).
+    pub key_fingerprint: &'a str,
+    /// The context supplied by the caller, if any.
+    pub context: Option<&'a str>,
+    /// When the operation completed.
+    pub timestamp: SystemTime,
+    /// Whether the operation succeeded.
+    pub success: bool,
+}
+
+/// A receiver for [`AuditEvent`]s.
+///
+/// Logging is fail-closed: if `log` returns an error, the audited operation fails with
+/// `E2eeError::AuditLog` and its result (e.g. the decrypted plaintext) is not returned.
+pub trait AuditLogger: Send + Sync {
+    /// Records `event`.
+    ///
+    /// # Errors
+    ///
+    /// Implementations return an error if the event could not be recorded.
+    fn log(&self, event: &AuditEvent<'_>) -> std::io::Result<()>;
+}
+
+/// A shared handle to the audit logger attached to an `E2ee` instance, if any.
+#[derive(Clone, Default)]
+pub(crate) struct SharedAuditLogger(Option<Arc<dyn AuditLogger>>);
+
+impl SharedAuditLogger {
+    pub(crate) fn new(logger: Arc<dyn AuditLogger>) -> Self {
+        Self(Some(logger))
+    }
+
+    pub(crate) fn get(&self) -> Option<&dyn AuditLogger> {
+        self.0.as_deref()
+    }
+}
+
+impl fmt::Debug for SharedAuditLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("Some(AuditLogger)"),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+#[cfg(feature = "io")]
+pub use jsonl::JsonlAuditLogger;
+
+#[cfg(feature = "io")]
+mod jsonl {
+    use super::{AuditEvent, AuditLogger};
+    use std::{
+        fs::{File, OpenOptions},
+        io::Write,
+        path::Path,
+        sync::Mutex,
+        time::UNIX_EPOCH,
+    };
+
+    /// An [`AuditLogger`] that appends one JSON object per event to a file.
+    ///
+    /// Each line has the form
+    /// `{"timestamp_ms":1726489200000,"operation":"decrypt","key_fingerprint":"539c…","context":"user-42","success":true}`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::audit::JsonlAuditLogger;
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::sync::Arc;
+    ///
+    /// let log_path = concat!(env!("CARGO_MANIFEST_DIR"), "/files/doc_audit.jsonl");
+    /// let logger = JsonlAuditLogger::open(log_path).expect("Failed to open audit log");
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_audit_logger(Arc::new(logger));
+    ///
+    /// let encrypted = e2ee.encrypt("Secret message").expect("Failed to encrypt message");
+    /// e2ee.decrypt_with_context(&encrypted, "user-42")
+    ///     .expect("Failed to decrypt message");
+    ///
+    /// // Clean up file
+    /// std::fs::remove_file(log_path).expect("Failed to delete audit log");
+    /// ```
+    #[derive(Debug)]
+    pub struct JsonlAuditLogger {
+        file: Mutex<File>,
+    }
+
+    impl JsonlAuditLogger {
+        /// Opens `path` for appending, creating the file if needed.
+        ///
+        /// # Errors
+        ///
+        /// This function returns an error if the file cannot be opened.
+        pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Ok(Self {
+                file: Mutex::new(file),
+            })
+        }
+    }
+
+    impl AuditLogger for JsonlAuditLogger {
+        fn log(&self, event: &AuditEvent<'_>) -> std::io::Result<()> {
+            let timestamp_ms = event
+                .timestamp
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis())
+                .unwrap_or_default();
+            let mut line = serde_json::json!({
+                "timestamp_ms": timestamp_ms,
+                "operation": event.operation.as_str(),
+                "key_fingerprint": event.key_fingerprint,
+                "context": event.context,
+                "success": event.success,
+            })
+            .to_string();
+            line.push('\n');
+
+            let mut file = self
+                .file
+                .lock()
+                .map_err(|_| std::io::Error::other("audit log lock poisoned"))?;
+            file.write_all(line.as_bytes())?;
+            file.flush()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{E2ee, E2eeError, KeySize};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MemoryAuditLogger(Mutex<Vec<(String, Option<String>, bool)>>);
+
+    impl AuditLogger for MemoryAuditLogger {
+        fn log(&self, event: &AuditEvent<'_>) -> std::io::Result<()> {
+            self.0.lock().unwrap().push((
+                event.key_fingerprint.to_string(),
+                event.context.map(str::to_string),
+                event.success,
+            ));
+            Ok(())
+        }
+    }
+
+    struct FailingAuditLogger;
+
+    impl AuditLogger for FailingAuditLogger {
+        fn log(&self, _event: &AuditEvent<'_>) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+    }
+
+    #[test]
+    fn test_audit_logger_records_decryptions() {
+        let logger = Arc::new(MemoryAuditLogger::default());
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_audit_logger(logger.clone());
+        let fingerprint = e2ee.get_fingerprint().unwrap();
+
+        let encrypted = e2ee.encrypt("Hello audit!").unwrap();
+        e2ee.decrypt_with_context(&encrypted, "user-42").unwrap();
+        assert!(e2ee.decrypt("invalid_base64_string").is_err());
+
+        assert_eq!(
+            *logger.0.lock().unwrap(),
+            vec![
+                (fingerprint.clone(), Some("user-42".to_string()), true),
+                (fingerprint, None, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit_logger_records_signatures() {
+        #[derive(Default)]
+        struct OperationLogger(Mutex<Vec<(AuditOperation, Option<String>)>>);
+
+        impl AuditLogger for OperationLogger {
+            fn log(&self, event: &AuditEvent<'_>) -> std::io::Result<()> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((event.operation, event.context.map(str::to_string)));
+                Ok(())
+            }
+        }
+
+        let logger = Arc::new(OperationLogger::default());
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_audit_logger(logger.clone());
+        e2ee.sign_with_context(b"Hello audit!", "release").unwrap();
+        let encrypted = e2ee.encrypt("Hello audit!").unwrap();
+        e2ee.decrypt(&encrypted).unwrap();
+
+        assert_eq!(
+            *logger.0.lock().unwrap(),
+            vec![
+                (AuditOperation::Sign, Some("release".to_string())),
+                (AuditOperation::Decrypt, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit_logger_failure_withholds_plaintext() {
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_audit_logger(Arc::new(FailingAuditLogger));
+
+        let encrypted = e2ee.encrypt("Hello audit!").unwrap();
+        let result = e2ee.decrypt(&encrypted);
+        assert!(matches!(result, Err(E2eeError::AuditLog(_))));
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_jsonl_audit_logger_writes_lines() {
+        const LOG_PATH: &str =
+            concat!(env!("CARGO_MANIFEST_DIR"), "/files/test_audit.jsonl");
+        let logger = JsonlAuditLogger::open(LOG_PATH).unwrap();
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_audit_logger(Arc::new(logger));
+
+        let message = "Top secret";
+        let encrypted = e2ee.encrypt(message).unwrap();
+        e2ee.decrypt_with_context(&encrypted, "request-1").unwrap();
+        e2ee.decrypt(&encrypted).unwrap();
+
+        let contents = std::fs::read_to_string(LOG_PATH).unwrap();
+        std::fs::remove_file(LOG_PATH).expect("Failed to delete audit log");
+
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["operation"], "decrypt");
+        assert_eq!(lines[0]["context"], "request-1");
+        assert_eq!(lines[0]["success"], true);
+        assert_eq!(
+            lines[0]["key_fingerprint"],
+            e2ee.get_fingerprint().unwrap().as_str()
+        );
+        assert!(lines[1]["context"].is_null());
+        assert!(!contents.contains(message));
+        assert!(!contents.contains(&encrypted));
+    }
+}
@@ -0,0 +1,413 @@
+//! Encrypted backups of a full identity, to migrate to a new machine.
+//!
+//! This module is enabled by the `keystore` feature. A [`Backup`] gathers everything an app needs
+//! to carry on elsewhere: the private keys of a `Keystore`, saved sessions (see
+//! `session::Session::serialize`), the key pins of peers, and settings. [`Backup::export_backup`]
+//! encrypts it into a single archive under a passphrase, with a key derived with Argon2id (see the
+//! `password` module), and [`Backup::import_backup`] decrypts it.
+//!
+//! The archive has the layout of a keystore file:
+//!
+//! ```text
+//! version (1 byte) | password header (29 bytes) | nonce (12 bytes) | ciphertext
+//! ```
+//!
+//! where the version and the header are authenticated as associated data, and the plaintext holds
+//! the keys, the sessions, the pins and the settings, in this order, each as:
+//!
+//! ```text
+//! entry count (4 bytes) | (name length (2 bytes) | name | value length (4 bytes) | value)...
+//! ```
+//!
+//! with integers in big endian. Keys are PKCS#8 DER, and pins and settings UTF-8.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::backup::Backup;
+//! use e2ee::keystore::Keystore;
+//! use e2ee::password::PasswordKdf;
+//! use e2ee::server::KeySize;
+//!
+//! let dir = std::env::temp_dir();
+//! let old_path = dir.join(format!("doc-backup-old-{}", std::process::id()));
+//! let new_path = dir.join(format!("doc-backup-new-{}", std::process::id()));
+//! let mut keystore = Keystore::create(&old_path, b"passphrase", &PasswordKdf::default())
+//!     .expect("Failed to create keystore");
+//! keystore.generate("identity", KeySize::Bit2048).expect("Failed to generate key");
+//!
+//! let mut backup = Backup::from_keystore(&keystore).expect("Failed to read keystore");
+//! backup.pins.insert("alice".to_string(), "3f9a0c...".to_string());
+//! backup.settings.insert("theme".to_string(), "dark".to_string());
+//! let archive = backup
+//!     .export_backup(b"backup passphrase", &PasswordKdf::default())
+//!     .expect("Failed to export backup");
+//!
+//! // On the new machine.
+//! let backup = Backup::import_backup(&archive, b"backup passphrase").expect("Failed to import");
+//! let keystore = backup
+//!     .restore_keystore(&new_path, b"passphrase", &PasswordKdf::default())
+//!     .expect("Failed to restore keystore");
+//! assert_eq!(keystore.names(), vec!["identity"]);
+//! assert_eq!(backup.settings["theme"], "dark");
+//! # std::fs::remove_file(&old_path).unwrap();
+//! # std::fs::remove_file(&new_path).unwrap();
+//! ```
+use crate::keystore::{Keystore, KeystoreError};
+use crate::password::{PasswordError, PasswordHeader, PasswordKdf, HEADER_LEN};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use rsa::{
+    pkcs8::{self, DecodePrivateKey, EncodePrivateKey},
+    rand_core::{OsRng, RngCore},
+    RsaPrivateKey,
+};
+use std::{collections::BTreeMap, fmt, path::Path};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the backup archive layout.
+pub const VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+pub type BackupResult<T> = std::result::Result<T, BackupError>;
+
+/// An error returned when exporting, importing or restoring a backup.
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error("Keystore error: {0}")]
+    Keystore(#[from] KeystoreError),
+
+    #[error("Password error: {0}")]
+    Password(#[from] PasswordError),
+
+    #[error("PKCS#8 error: {0}")]
+    Pkcs8(#[from] pkcs8::Error),
+
+    #[error("Wrong passphrase, or the backup was tampered with")]
+    WrongPassphrase,
+
+    #[error("Malformed backup")]
+    Malformed,
+
+    #[error("Unsupported backup version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Entry names must be 1 to 65535 bytes long")]
+    InvalidName,
+}
+
+/// The state of an identity: private keys, saved sessions, pins and settings, by name.
+#[derive(Clone, Default)]
+pub struct Backup {
+    /// The PKCS#8 DER encodings of the private keys, e.g. by keystore name.
+    pub keys: BTreeMap<String, Zeroizing<Vec<u8>>>,
+    /// Saved sessions, e.g. the output of `session::Session::serialize`, by peer.
+    pub sessions: BTreeMap<String, Vec<u8>>,
+    /// The pinned key IDs or fingerprints of peers, by peer.
+    pub pins: BTreeMap<String, String>,
+    /// The settings of the app.
+    pub settings: BTreeMap<String, String>,
+}
+
+impl fmt::Debug for Backup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backup")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("sessions", &self.sessions.keys().collect::<Vec<_>>())
+            .field("pins", &self.pins)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+impl Backup {
+    /// Creates an empty backup.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a backup of the keys of `keystore`, under their names.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a stored key is malformed.
+    pub fn from_keystore(keystore: &Keystore) -> BackupResult<Self> {
+        let mut backup = Self::new();
+        for name in keystore.names() {
+            backup.insert_key(name, &keystore.private_key(name)?)?;
+        }
+        Ok(backup)
+    }
+
+    /// Adds `private_key` to the backup under `name`, replacing any key of that name.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be encoded.
+    pub fn insert_key(
+        &mut self,
+        name: &str,
+        private_key: &RsaPrivateKey,
+    ) -> BackupResult<()> {
+        let der = Zeroizing::new(private_key.to_pkcs8_der()?.as_bytes().to_vec());
+        self.keys.insert(name.to_string(), der);
+        Ok(())
+    }
+
+    /// Returns the private key backed up under `name`, if any.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key is malformed.
+    pub fn private_key(&self, name: &str) -> BackupResult<Option<RsaPrivateKey>> {
+        self.keys
+            .get(name)
+            .map(|der| RsaPrivateKey::from_pkcs8_der(der))
+            .transpose()
+            .map_err(BackupError::from)
+    }
+
+    /// Creates a keystore at `path` holding the backed up keys, protected by `passphrase`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `path` already exists, if a key is malformed or if the
+    /// keystore cannot be written.
+    pub fn restore_keystore(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> BackupResult<Keystore> {
+        let mut keystore = Keystore::create(path, passphrase, kdf)?;
+        for (name, der) in &self.keys {
+            keystore.insert(name, &RsaPrivateKey::from_pkcs8_der(der)?)?;
+        }
+        Ok(keystore)
+    }
+
+    /// Encrypts the backup into an archive under `passphrase`, with a key derived by `kdf`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `BackupError::InvalidName` if a name is empty or longer than 65535
+    /// bytes, or an error if the key derivation fails.
+    pub fn export_backup(
+        &self,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> BackupResult<Vec<u8>> {
+        let mut plaintext = Zeroizing::new(Vec::new());
+        encode_section(&mut plaintext, self.keys.iter().map(|(k, v)| (k, &v[..])))?;
+        encode_section(
+            &mut plaintext,
+            self.sessions.iter().map(|(k, v)| (k, &v[..])),
+        )?;
+        encode_section(
+            &mut plaintext,
+            self.pins.iter().map(|(k, v)| (k, v.as_bytes())),
+        )?;
+        encode_section(
+            &mut plaintext,
+            self.settings.iter().map(|(k, v)| (k, v.as_bytes())),
+        )?;
+
+        let derived = kdf.derive(passphrase)?;
+        let mut archive = vec![VERSION];
+        archive.extend_from_slice(&derived.header.to_bytes());
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = Aes256Gcm::new(derived.key.as_ref().into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &archive,
+                },
+            )
+            .map_err(|_| BackupError::Malformed)?;
+        archive.extend_from_slice(&nonce);
+        archive.extend_from_slice(&ciphertext);
+        Ok(archive)
+    }
+
+    /// Decrypts an archive produced by [`Backup::export_backup`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `BackupError::WrongPassphrase` if `passphrase` is wrong or the
+    /// archive was tampered with, or an error if it is malformed.
+    pub fn import_backup(archive: &[u8], passphrase: &[u8]) -> BackupResult<Self> {
+        let (&version, rest) =
+            archive.split_first().ok_or(BackupError::Malformed)?;
+        if version != VERSION {
+            return Err(BackupError::UnsupportedVersion(version));
+        }
+        if rest.len() < HEADER_LEN + NONCE_LEN {
+            return Err(BackupError::Malformed);
+        }
+        let (header, rest) = rest.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let key = PasswordHeader::from_bytes(header)?.derive_key(passphrase)?;
+        let plaintext = Zeroizing::new(
+            Aes256Gcm::new(key.as_ref().into())
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &archive[..1 + HEADER_LEN],
+                    },
+                )
+                .map_err(|_| BackupError::WrongPassphrase)?,
+        );
+
+        let mut rest = &plaintext[..];
+        let keys = decode_section(&mut rest)?
+            .into_iter()
+            .map(|(name, value)| (name, Zeroizing::new(value)))
+            .collect();
+        let sessions = decode_section(&mut rest)?;
+        let pins = decode_text_section(&mut rest)?;
+        let settings = decode_text_section(&mut rest)?;
+        if !rest.is_empty() {
+            return Err(BackupError::Malformed);
+        }
+        Ok(Self {
+            keys,
+            sessions,
+            pins,
+            settings,
+        })
+    }
+}
+
+/// Appends the entry count, then each length-prefixed name and value, to `plaintext`.
+fn encode_section<'a>(
+    plaintext: &mut Vec<u8>,
+    entries: impl ExactSizeIterator<Item = (&'a String, &'a [u8])>,
+) -> BackupResult<()> {
+    plaintext.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for (name, value) in entries {
+        let name_len =
+            u16::try_from(name.len()).map_err(|_| BackupError::InvalidName)?;
+        if name_len == 0 {
+            return Err(BackupError::InvalidName);
+        }
+        let value_len =
+            u32::try_from(value.len()).map_err(|_| BackupError::Malformed)?;
+        plaintext.extend_from_slice(&name_len.to_be_bytes());
+        plaintext.extend_from_slice(name.as_bytes());
+        plaintext.extend_from_slice(&value_len.to_be_bytes());
+        plaintext.extend_from_slice(value);
+    }
+    Ok(())
+}
+
+/// Decodes a section encoded by [`encode_section`] at the start of `rest`, and advances `rest`
+/// past it.
+fn decode_section(rest: &mut &[u8]) -> BackupResult<BTreeMap<String, Vec<u8>>> {
+    let mut take = |len: usize| -> BackupResult<&[u8]> {
+        if rest.len() < len {
+            return Err(BackupError::Malformed);
+        }
+        let (field, tail) = rest.split_at(len);
+        *rest = tail;
+        Ok(field)
+    };
+    let count = u32::from_be_bytes(take(4)?.try_into().expect("4 bytes"));
+    let mut entries = BTreeMap::new();
+    for _ in 0..count {
+        let len = u16::from_be_bytes(take(2)?.try_into().expect("2 bytes"));
+        let name = std::str::from_utf8(take(len.into())?)
+            .map_err(|_| BackupError::Malformed)?
+            .to_string();
+        let len = u32::from_be_bytes(take(4)?.try_into().expect("4 bytes"));
+        if entries.insert(name, take(len as usize)?.to_vec()).is_some() {
+            return Err(BackupError::Malformed);
+        }
+    }
+    Ok(entries)
+}
+
+/// Decodes a section of UTF-8 values.
+fn decode_text_section(rest: &mut &[u8]) -> BackupResult<BTreeMap<String, String>> {
+    decode_section(rest)?
+        .into_iter()
+        .map(|(name, value)| {
+            String::from_utf8(value)
+                .map(|value| (name, value))
+                .map_err(|_| BackupError::Malformed)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+    use crate::password::PasswordParams;
+
+    fn fast_kdf() -> PasswordKdf {
+        PasswordKdf::new(PasswordParams::new(64, 1, 1).unwrap())
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let mut backup = Backup::new();
+        backup.insert_key("identity", &private_key).unwrap();
+        backup.sessions.insert("bob".to_string(), vec![1, 2, 3]);
+        backup.pins.insert("bob".to_string(), "3f9a0c".to_string());
+        backup
+            .settings
+            .insert("theme".to_string(), "dark".to_string());
+        assert!(!format!("{backup:?}").contains("PRIVATE"));
+
+        let archive = backup.export_backup(b"secret", &fast_kdf()).unwrap();
+        let imported = Backup::import_backup(&archive, b"secret").unwrap();
+        assert_eq!(imported.private_key("identity").unwrap(), Some(private_key));
+        assert_eq!(imported.sessions, backup.sessions);
+        assert_eq!(imported.pins, backup.pins);
+        assert_eq!(imported.settings, backup.settings);
+
+        assert!(matches!(
+            Backup::import_backup(&archive, b"wrong"),
+            Err(BackupError::WrongPassphrase)
+        ));
+        let mut tampered = archive.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Backup::import_backup(&tampered, b"secret"),
+            Err(BackupError::WrongPassphrase)
+        ));
+    }
+
+    #[test]
+    fn test_restore_keystore() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("e2ee-backup-{}-restore", std::process::id()));
+        let mut backup = Backup::new();
+        backup
+            .insert_key("identity", &core::generate_private_key(1024).unwrap())
+            .unwrap();
+        let keystore = backup
+            .restore_keystore(&path, b"secret", &fast_kdf())
+            .unwrap();
+        assert_eq!(keystore.names(), vec!["identity"]);
+        assert_eq!(
+            Backup::from_keystore(&Keystore::open(&path, b"secret").unwrap())
+                .unwrap()
+                .keys,
+            backup.keys
+        );
+        // An existing keystore is never overwritten.
+        assert!(backup
+            .restore_keystore(&path, b"secret", &fast_kdf())
+            .is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+//! Caching of RSA decryption results.
+//!
+//! This module is enabled by the `cache` feature. Services that decrypt the same RSA ciphertexts
+//! over and over, typically the wrapped data keys of envelopes sealed with a
+//! `envelope::DataKeyCache`, can attach a [`DecryptionCache`] to an `E2ee` instance with
+//! `E2ee::with_decryption_cache`. Successful RSA decryptions are then kept, keyed by the SHA-256
+//! hash of the ciphertext and its OAEP parameters, and served from memory until they expire or
+//! are evicted as least recently used.
+//!
+//! Cached results are plaintexts (data keys or short messages) held in memory: size the cache
+//! and its time to live accordingly. Cache hits are still reported to the audit logger, and to
+//! the metrics sink as decryptions; [`MetricsSink::cache_lookup`] additionally reports each hit
+//! or miss.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::cache::DecryptionCache;
+//! use e2ee::server::{E2ee, KeySize};
+//! use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+//!
+//! let cache = Arc::new(DecryptionCache::new(
+//!     NonZeroUsize::new(10_000).unwrap(),
+//!     Duration::from_secs(300),
+//! ));
+//! let e2ee = E2ee::new(KeySize::Bit2048)
+//!     .expect("Failed to create E2ee instance")
+//!     .with_decryption_cache(cache.clone());
+//!
+//! let encrypted = e2ee.encrypt("Hello, cache!").expect("Failed to encrypt message");
+//! for _ in 0..3 {
+//!     assert_eq!(e2ee.decrypt(&encrypted).expect("Failed to decrypt"), "Hello, cache!");
+//! }
+//! let stats = cache.stats();
+//! assert_eq!((stats.hits, stats.misses), (2, 1));
+//! ```
+use crate::core::OaepParams;
+#[cfg(doc)]
+use crate::metrics::MetricsSink;
+use crate::metrics::SharedMetrics;
+use rsa::sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+type CacheKey = [u8; 32];
+
+/// A bounded LRU cache of RSA decryption results, with a time to live.
+pub struct DecryptionCache {
+    capacity: NonZeroUsize,
+    ttl: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<CacheKey, Entry>,
+    /// The keys of the entries, by the tick of their last use.
+    recency: BTreeMap<u64, CacheKey>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    plaintext: Vec<u8>,
+    inserted: Instant,
+    last_used: u64,
+}
+
+/// Counters of a [`DecryptionCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that required an RSA decryption.
+    pub misses: u64,
+    /// Entries currently cached.
+    pub len: usize,
+}
+
+impl CacheStats {
+    /// Returns the share of lookups answered from the cache, between 0 and 1.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+impl fmt::Debug for DecryptionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptionCache")
+            .field("capacity", &self.capacity)
+            .field("ttl", &self.ttl)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl DecryptionCache {
+    /// Creates a cache holding up to `capacity` results for `ttl` each.
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Returns the hit and miss counters, and the number of cached entries.
+    pub fn stats(&self) -> CacheStats {
+        let state = self.lock();
+        CacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            len: state.entries.len(),
+        }
+    }
+
+    /// Removes every cached result, e.g. after a key was compromised. The counters are kept.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    /// Returns the cached decryption of `ciphertext`, or decrypts it with `decrypt` and caches the
+    /// result if it succeeds.
+    pub(crate) fn get_or_try_insert<E>(
+        &self,
+        oaep: OaepParams,
+        ciphertext: &[u8],
+        metrics: &SharedMetrics,
+        decrypt: impl FnOnce() -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<u8>, E> {
+        let key = cache_key(oaep, ciphertext);
+        let cached = self.lock().get(&key, self.ttl);
+        metrics.cache_lookup(cached.is_some());
+        if let Some(plaintext) = cached {
+            return Ok(plaintext);
+        }
+        // The lock is not held while decrypting, so that concurrent misses run in parallel.
+        let plaintext = decrypt()?;
+        self.lock().insert(key, plaintext.clone(), self.capacity);
+        Ok(plaintext)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl State {
+    fn get(&mut self, key: &CacheKey, ttl: Duration) -> Option<Vec<u8>> {
+        let Some(entry) = self.entries.get(key) else {
+            self.misses += 1;
+            return None;
+        };
+        if entry.inserted.elapsed() >= ttl {
+            self.remove(key);
+            self.misses += 1;
+            return None;
+        }
+        self.hits += 1;
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key).expect("the entry exists");
+        self.recency.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.recency.insert(tick, *key);
+        Some(entry.plaintext.clone())
+    }
+
+    fn insert(&mut self, key: CacheKey, plaintext: Vec<u8>, capacity: NonZeroUsize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity.get() {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, key);
+        self.entries.insert(
+            key,
+            Entry {
+                plaintext,
+                inserted: Instant::now(),
+                last_used: self.tick,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+        }
+    }
+}
+
+fn cache_key(oaep: OaepParams, ciphertext: &[u8]) -> CacheKey {
+    Sha256::new()
+        .chain_update(oaep.hash.as_str())
+        .chain_update([0])
+        .chain_update(oaep.mgf1_hash.as_str())
+        .chain_update([0])
+        .chain_update(ciphertext)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(cache: &DecryptionCache, ciphertext: &[u8]) -> (Vec<u8>, bool) {
+        let mut decrypted = false;
+        let plaintext = cache
+            .get_or_try_insert::<()>(
+                OaepParams::SHA256,
+                ciphertext,
+                &SharedMetrics::default(),
+                || {
+                    decrypted = true;
+                    Ok(ciphertext.to_ascii_uppercase())
+                },
+            )
+            .unwrap();
+        (plaintext, !decrypted)
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let cache =
+            DecryptionCache::new(NonZeroUsize::new(2).unwrap(), Duration::MAX);
+        assert_eq!(lookup(&cache, b"a"), (b"A".to_vec(), false));
+        assert_eq!(lookup(&cache, b"b"), (b"B".to_vec(), false));
+        assert_eq!(lookup(&cache, b"a"), (b"A".to_vec(), true));
+        // "b" is the least recently used entry.
+        assert!(!lookup(&cache, b"c").1);
+        assert!(lookup(&cache, b"a").1);
+        assert!(!lookup(&cache, b"b").1);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                len: 2
+            }
+        );
+        assert!((cache.stats().hit_rate() - 1.0 / 3.0).abs() < f64::EPSILON);
+        cache.clear();
+        assert_eq!(cache.stats().len, 0);
+    }
+
+    #[test]
+    fn test_cache_expires_entries_and_skips_failures() {
+        let cache =
+            DecryptionCache::new(NonZeroUsize::new(8).unwrap(), Duration::ZERO);
+        assert!(!lookup(&cache, b"a").1);
+        assert!(!lookup(&cache, b"a").1);
+
+        let cache =
+            DecryptionCache::new(NonZeroUsize::new(8).unwrap(), Duration::MAX);
+        let result = cache.get_or_try_insert(
+            OaepParams::SHA256,
+            b"a",
+            &SharedMetrics::default(),
+            || Err("invalid padding"),
+        );
+        assert!(result.is_err());
+        assert_eq!(cache.stats().len, 0);
+        assert!(!lookup(&cache, b"a").1);
+    }
+
+    #[test]
+    fn test_e2ee_caches_wrapped_data_keys() {
+        use crate::envelope::DataKeyCache;
+        use crate::metrics::{MetricsSink, Operation};
+        use crate::server::{E2ee, KeySize};
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        };
+
+        #[derive(Default)]
+        struct HitCounter(AtomicU64);
+
+        impl MetricsSink for HitCounter {
+            fn increment(&self, _operation: Operation, _success: bool) {}
+
+            fn observe_duration(&self, _operation: Operation, _duration: Duration) {}
+
+            fn cache_lookup(&self, hit: bool) {
+                if hit {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let metrics = Arc::new(HitCounter::default());
+        let cache = Arc::new(DecryptionCache::new(
+            NonZeroUsize::new(16).unwrap(),
+            Duration::MAX,
+        ));
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_metrics(metrics.clone())
+            .with_decryption_cache(cache.clone());
+        let data_keys = DataKeyCache::new(e2ee.get_public_key().clone());
+        for payload in [&b"first"[..], b"second", b"third"] {
+            let envelope = data_keys.seal(payload).unwrap();
+            assert_eq!(e2ee.decrypt_envelope(&envelope).unwrap(), payload);
+        }
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                len: 1
+            }
+        );
+        assert_eq!(metrics.0.load(Ordering::Relaxed), 2);
+        assert!(e2ee.decryption_cache().is_some());
+    }
+}
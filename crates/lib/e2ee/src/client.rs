@@ -1,10 +1,24 @@
-use base64::{engine::general_purpose, Engine};
-use error::PublicE2eeResult;
-use rsa::{
-    pkcs8::DecodePublicKey, rand_core::OsRng, sha2::Sha256, Oaep, RsaPublicKey,
+use crate::core;
+use crate::envelope::{self, Aead, EnvelopeFormat};
+use crate::interop::InteropConfig;
+#[cfg(feature = "io")]
+use crate::io;
+use crate::mac::Mac;
+use crate::metrics::{MetricsSink, Operation, SharedMetrics};
+use crate::rng::SharedRng;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use rsa::{rand_core::CryptoRngCore, traits::PublicKeyParts, RsaPublicKey};
+use std::{
+    borrow::Borrow,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 mod error;
+mod key_pair;
+pub use error::{PublicE2eeError, PublicE2eeResult};
+pub use key_pair::ClientKeyPair;
 
 /// A struct representing the End-to-End Encryption (E2EE) system on the client side.
 ///
@@ -15,10 +29,17 @@ mod error;
 /// The client-side E2EE system requires only the public key to function correctly. The public key is used
 /// for encrypting messages before they are sent to the server.
 ///
+/// `PublicE2ee` is `Send + Sync`, and draws randomness from the operating system on each call
+/// unless a generator is injected with `with_rng`: a single instance can be shared between
+/// threads, e.g. in an `Arc`.
+///
+/// Two instances are equal when they encrypt for the same public key, regardless of their
+/// configuration.
+///
 /// The `PublicE2ee` struct includes the following fields:
 ///
 /// - `public_key`: The RSA public key used for encrypting messages.
-/// - `public_key_pem`: The PEM-encoded public key as a string.
+/// - `public_key_pem`: The PEM-encoded public key as a string (requires the `io` feature).
 ///
 /// # Examples
 ///
@@ -38,12 +59,41 @@ mod error;
 /// # Errors
 ///
 /// The struct's methods may return errors if encryption operations fail.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PublicE2ee {
     public_key: RsaPublicKey,
+    #[cfg(feature = "io")]
     public_key_pem: String,
+    metrics: SharedMetrics,
+    config: InteropConfig,
+    escrow_keys: Vec<RsaPublicKey>,
+    aead: Aead,
+    envelope_format: EnvelopeFormat,
+    rng: SharedRng,
+    envelope_mac: Option<Mac>,
+}
+
+impl PartialEq for PublicE2ee {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+impl Eq for PublicE2ee {}
+
+// Consistent with `PartialEq`, which only compares the public keys.
+impl Borrow<RsaPublicKey> for PublicE2ee {
+    fn borrow(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
 }
 
+// `PublicE2ee` is shared across threads, see its documentation.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<PublicE2ee>();
+};
+
 impl PublicE2ee {
     /// Creates a new `PublicE2ee` instance from a PEM-encoded public key.
     ///
@@ -69,6 +119,9 @@ impl PublicE2ee {
     /// - If the `public_key_pem` is not a valid PEM-encoded RSA public key.
     /// - If the `public_key_pem` string cannot be parsed or decoded correctly. This includes cases where the
     ///   provided key is not in the expected format (e.g., it is malformed or encrypted).
+    /// - If the decoded key fails validation (`PublicE2eeError::InvalidPublicKey`): the public exponent is
+    ///   even or smaller than 65537, the modulus is shorter than 1024 bits, or the modulus is obviously
+    ///   malformed (even or divisible by a small prime).
     ///
     /// # Examples
     ///
@@ -88,14 +141,140 @@ impl PublicE2ee {
     /// This method is safe to use as long as the provided `public_key_pem` is a valid PEM-encoded RSA public key.
     /// Ensure that the public key string is properly formatted and originates from a trusted source. Passing an
     /// invalid or corrupted PEM string will result in an error.
+    #[cfg(feature = "io")]
     pub fn new(public_key_pem: String) -> PublicE2eeResult<Self> {
-        let public_key = RsaPublicKey::from_public_key_pem(&public_key_pem)?;
+        let (n, e) = io::decode_public_key_components(&public_key_pem)?;
+        core::validate_public_key(&n, &e)
+            .map_err(PublicE2eeError::InvalidPublicKey)?;
+        let public_key = RsaPublicKey::new(n, e)?;
+        Ok(Self {
+            public_key,
+            public_key_pem,
+            metrics: SharedMetrics::default(),
+            config: InteropConfig::default(),
+            escrow_keys: Vec::new(),
+            aead: Aead::default(),
+            envelope_format: EnvelopeFormat::default(),
+            rng: SharedRng::default(),
+            envelope_mac: None,
+        })
+    }
+
+    /// Creates a new `PublicE2ee` instance from an existing RSA public key.
+    ///
+    /// The key goes through the same validation as keys imported with `PublicE2ee::new`.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key` - The RSA public key used for encryption.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::{client::PublicE2ee, core};
+    /// use rsa::RsaPublicKey;
+    ///
+    /// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let e2ee_client = PublicE2ee::from_public_key(RsaPublicKey::from(&private_key))
+    ///     .expect("Failed to create PublicE2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PublicE2eeError::InvalidPublicKey` if the key fails validation, or an
+    /// error if the key cannot be PEM-encoded (with the `io` feature).
+    pub fn from_public_key(public_key: RsaPublicKey) -> PublicE2eeResult<Self> {
+        core::validate_public_key(public_key.n(), public_key.e())
+            .map_err(PublicE2eeError::InvalidPublicKey)?;
+        #[cfg(feature = "io")]
+        let public_key_pem = io::encode_public_key_pem(&public_key)?;
         Ok(Self {
             public_key,
+            #[cfg(feature = "io")]
             public_key_pem,
+            metrics: SharedMetrics::default(),
+            config: InteropConfig::default(),
+            escrow_keys: Vec::new(),
+            aead: Aead::default(),
+            envelope_format: EnvelopeFormat::default(),
+            rng: SharedRng::default(),
+            envelope_mac: None,
         })
     }
 
+    /// Attaches a metrics sink that receives counters and durations for every encryption
+    /// performed by this instance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::client::PublicE2ee;
+    /// use e2ee::metrics::NoopMetrics;
+    /// use std::sync::Arc;
+    ///
+    /// const PUBLIC_KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/public.pem");
+    /// let public_key_pem = std::fs::read_to_string(PUBLIC_KEY_PATH).expect("Failed to read public key file");
+    /// let e2ee_client = PublicE2ee::new(public_key_pem)
+    ///     .expect("Failed to create PublicE2ee instance")
+    ///     .with_metrics(Arc::new(NoopMetrics));
+    /// ```
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = SharedMetrics::new(sink);
+        self
+    }
+
+    /// Sets the OAEP parameters and ciphertext encoding used by `encrypt`.
+    ///
+    /// They must match the configuration of the decrypting side.
+    pub fn with_interop_config(mut self, config: InteropConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds an escrow key, for which the data key of every envelope produced by
+    /// `encrypt_envelope` is also wrapped, so that the organization holding the escrow private key
+    /// can recover the payloads if the recipient's key is lost (see `envelope::seal_with_escrow`).
+    pub fn with_escrow_key(mut self, escrow_key: RsaPublicKey) -> Self {
+        self.escrow_keys.push(escrow_key);
+        self
+    }
+
+    /// Sets the cipher encrypting the payloads of `encrypt_envelope` and `encrypt_with_expiry`,
+    /// AES-256-GCM by default. ChaCha20-Poly1305 is faster on devices without AES instructions
+    /// (see `envelope::seal_with_aead`).
+    pub fn with_aead(mut self, aead: Aead) -> Self {
+        self.aead = aead;
+        self
+    }
+
+    /// Draws the OAEP seeds and envelope data keys and nonces from `rng` instead of `OsRng` (see
+    /// the `rng` module). Clones of this instance share it.
+    pub fn with_rng(mut self, rng: impl CryptoRngCore + Send + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Sets the encoding of the envelopes produced by `encrypt_envelope` and `encrypt_with_expiry`,
+    /// binary by default (see `envelope::json`).
+    pub fn with_envelope_format(mut self, format: EnvelopeFormat) -> Self {
+        self.envelope_format = format;
+        self
+    }
+
+    /// Authenticates the ciphertexts of `encrypt` with `mac` (see `mac::Mac::seal`), to be
+    /// decrypted by an `E2ee` instance configured with the same key through
+    /// `E2ee::with_envelope_mac`.
+    pub fn with_envelope_mac(mut self, mac: Mac) -> Self {
+        self.envelope_mac = Some(mac);
+        self
+    }
+
+    /// Switches to `InteropConfig::WEBCRYPTO`, producing ciphertexts that can be decrypted in
+    /// browsers with `crypto.subtle.decrypt({ name: "RSA-OAEP" }, key, data)` after `atob`.
+    pub fn webcrypto_compatible(self) -> Self {
+        self.with_interop_config(InteropConfig::WEBCRYPTO)
+    }
+
     /// Encrypts a message using the public key.
     ///
     /// This function takes a plaintext message and encrypts it using the RSA public key
@@ -137,23 +316,156 @@ impl PublicE2ee {
     /// Ensure that the `PublicE2ee` instance is correctly initialized with a valid public key before
     /// calling this method. Passing an invalid or improperly initialized instance may lead to errors.
     pub fn encrypt(&self, message: &str) -> PublicE2eeResult<String> {
-        let mut rng = OsRng;
-        let padding = Oaep::new::<Sha256>();
-        let encrypted_data =
-            self.public_key
-                .encrypt(&mut rng, padding, message.as_bytes())?;
-        Ok(general_purpose::STANDARD_NO_PAD.encode(encrypted_data))
+        self.encrypt_raw(message.as_bytes())
+    }
+
+    /// Wraps a symmetric key (e.g. the key of an external AEAD pipeline) with RSA-OAEP, to be
+    /// unwrapped with `E2ee::unwrap_key`. The wrapped key is encoded like the output of `encrypt`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key is too long for the RSA key or encryption fails.
+    pub fn wrap_key(&self, key: &[u8]) -> PublicE2eeResult<String> {
+        self.encrypt_raw(key)
+    }
+
+    /// Encrypts `plaintext` for the public key, and encodes it.
+    fn encrypt_raw(&self, plaintext: &[u8]) -> PublicE2eeResult<String> {
+        self.metrics.measure(Operation::Encrypt, || {
+            let encrypted_data = core::encrypt_with_rng(
+                &mut self.rng.clone(),
+                &self.public_key,
+                self.config.oaep,
+                plaintext,
+            )?;
+            let encrypted_data = match &self.envelope_mac {
+                Some(mac) => mac.seal(b"", &encrypted_data)?,
+                None => encrypted_data,
+            };
+            Ok(self.config.encoding.encode(&encrypted_data))
+        })
+    }
+
+    /// Encrypts `message` like `encrypt`, but returns the raw RSA-OAEP ciphertext without base64
+    /// encoding, to be decrypted with `E2ee::decrypt_bytes_ref`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    #[cfg(feature = "bytes")]
+    pub fn encrypt_bytes_ref(
+        &self,
+        message: impl AsRef<[u8]>,
+    ) -> PublicE2eeResult<Bytes> {
+        self.metrics.measure(Operation::Encrypt, || {
+            let encrypted_data = core::encrypt_with_rng(
+                &mut self.rng.clone(),
+                &self.public_key,
+                self.config.oaep,
+                message.as_ref(),
+            )?;
+            Ok(Bytes::from(encrypted_data))
+        })
+    }
+
+    /// Encrypts `payload` of any size into an envelope (see the `envelope` module), to be
+    /// decrypted with `E2ee::decrypt_envelope`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    pub fn encrypt_envelope(&self, payload: &[u8]) -> PublicE2eeResult<Vec<u8>> {
+        self.metrics.measure(Operation::Encrypt, || {
+            Ok(self.envelope_format.encode(envelope::seal_with_options(
+                &mut self.rng.clone(),
+                &self.public_key,
+                &self.escrow_keys,
+                None,
+                self.aead,
+                payload,
+            )?)?)
+        })
+    }
+
+    /// Encrypts `payload` of any size into an envelope that `E2ee::decrypt_envelope` rejects once
+    /// `ttl` has elapsed (see `envelope::seal_with_expiry`).
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PublicE2eeError::InvalidExpiry` if `ttl` reaches beyond the range
+    /// of `SystemTime`, or an error if encryption fails.
+    pub fn encrypt_with_expiry(
+        &self,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> PublicE2eeResult<Vec<u8>> {
+        let expires_at = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or(PublicE2eeError::InvalidExpiry)?;
+        self.metrics.measure(Operation::Encrypt, || {
+            Ok(self.envelope_format.encode(envelope::seal_with_options(
+                &mut self.rng.clone(),
+                &self.public_key,
+                &self.escrow_keys,
+                Some(expires_at),
+                self.aead,
+                payload,
+            )?)?)
+        })
+    }
+
+    /// Verifies an RSASSA-PKCS1-v1_5 (SHA-256) `signature` of `message`, as produced by
+    /// `E2ee::sign`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PublicE2eeError::InvalidSignature` if the signature does not match.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> PublicE2eeResult<()> {
+        self.metrics.measure(Operation::Verify, || {
+            core::verify(&self.public_key, message, signature)
+                .map_err(|_| PublicE2eeError::InvalidSignature)
+        })
+    }
+
+    /// Verifies a `signature` of a message given its SHA-256 `digest`, like `verify` verifies the
+    /// signature of the message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PublicE2eeError::InvalidSignature` if the signature does not match.
+    pub fn verify_digest(
+        &self,
+        digest: &[u8; 32],
+        signature: &[u8],
+    ) -> PublicE2eeResult<()> {
+        self.metrics.measure(Operation::Verify, || {
+            core::verify_digest(&self.public_key, digest, signature)
+                .map_err(|_| PublicE2eeError::InvalidSignature)
+        })
+    }
+
+    /// Retrieves the public key in its original `RsaPublicKey` format.
+    pub fn get_public_key(&self) -> &RsaPublicKey {
+        &self.public_key
     }
 
     /// Retrieves the PEM-encoded public key.
+    #[cfg(feature = "io")]
     pub fn get_public_key_pem(&self) -> &str {
         &self.public_key_pem
     }
 }
 
 #[cfg(test)]
+#[cfg(feature = "io")]
 mod tests {
-    use super::PublicE2ee;
+    use super::{PublicE2ee, PublicE2eeError};
+    use rsa::{
+        pkcs8::{EncodePublicKey, LineEnding},
+        rand_core::OsRng,
+        traits::PublicKeyParts,
+        BigUint, RsaPrivateKey, RsaPublicKey,
+    };
     use std::fs;
 
     const PUBLIC_KEY_PATH: &str =
@@ -199,6 +511,22 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_public_e2ee_clone_and_eq() {
+        let public_key_pem = fs::read_to_string(PUBLIC_KEY_PATH)
+            .expect("Failed to read public key file");
+        let e2ee_client = PublicE2ee::new(public_key_pem).unwrap();
+
+        // Clones compare equal, even with another configuration.
+        let clone = e2ee_client.clone().webcrypto_compatible();
+        assert_eq!(e2ee_client, clone);
+
+        let other_key = RsaPrivateKey::new(&mut OsRng, 1024)
+            .unwrap()
+            .to_public_key();
+        assert_ne!(e2ee_client, PublicE2ee::from_public_key(other_key).unwrap());
+    }
+
     #[test]
     fn test_public_e2ee_get_public_key_pem() {
         // Read the public key from a file.
@@ -216,4 +544,61 @@ mod tests {
             "Retrieved public key PEM does not match the original"
         );
     }
+
+    /// Encodes a public key built from raw (possibly unsafe) parameters as PEM.
+    fn foreign_public_key_pem(n: BigUint, e: BigUint) -> String {
+        RsaPublicKey::new_unchecked(n, e)
+            .to_public_key_pem(LineEnding::default())
+            .expect("Failed to encode public key")
+    }
+
+    fn bundled_public_key() -> RsaPublicKey {
+        use rsa::pkcs8::DecodePublicKey;
+        let public_key_pem = fs::read_to_string(PUBLIC_KEY_PATH)
+            .expect("Failed to read public key file");
+        RsaPublicKey::from_public_key_pem(&public_key_pem)
+            .expect("Failed to decode public key")
+    }
+
+    #[test]
+    fn test_public_e2ee_rejects_small_exponent() {
+        let public_key = bundled_public_key();
+        let pem =
+            foreign_public_key_pem(public_key.n().clone(), BigUint::from(3u32));
+
+        let result = PublicE2ee::new(pem);
+        assert!(matches!(result, Err(PublicE2eeError::InvalidPublicKey(_))));
+    }
+
+    #[test]
+    fn test_public_e2ee_rejects_even_exponent() {
+        let public_key = bundled_public_key();
+        let pem =
+            foreign_public_key_pem(public_key.n().clone(), BigUint::from(65538u32));
+
+        let result = PublicE2ee::new(pem);
+        assert!(matches!(result, Err(PublicE2eeError::InvalidPublicKey(_))));
+    }
+
+    #[test]
+    fn test_public_e2ee_rejects_short_modulus() {
+        let private_key = RsaPrivateKey::new(&mut OsRng, 512)
+            .expect("Failed to generate private key");
+        let pem =
+            foreign_public_key_pem(private_key.n().clone(), private_key.e().clone());
+
+        let result = PublicE2ee::new(pem);
+        assert!(matches!(result, Err(PublicE2eeError::InvalidPublicKey(_))));
+    }
+
+    #[test]
+    fn test_public_e2ee_rejects_malformed_modulus() {
+        let public_key = bundled_public_key();
+        // Multiplying by 3 keeps the size plausible but makes the modulus trivially factorable.
+        let n = public_key.n() * BigUint::from(3u32);
+        let pem = foreign_public_key_pem(n, public_key.e().clone());
+
+        let result = PublicE2ee::new(pem);
+        assert!(matches!(result, Err(PublicE2eeError::InvalidPublicKey(_))));
+    }
 }
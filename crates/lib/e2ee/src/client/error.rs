@@ -17,4 +17,19 @@ pub enum PublicE2eeError {
 
     #[error("Decoding error: {0}")]
     Decoding(#[from] base64::DecodeError),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] crate::envelope::EnvelopeError),
+
+    #[error("MAC error: {0}")]
+    Mac(#[from] crate::mac::MacError),
+
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("The time to live is too long")]
+    InvalidExpiry,
 }
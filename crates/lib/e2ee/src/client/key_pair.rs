@@ -0,0 +1,126 @@
+use super::{PublicE2ee, PublicE2eeResult};
+use crate::server::{E2ee, E2eeResult, KeySize};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+
+/// A key pair generated by the client itself, so that the server can answer with messages only
+/// the client decrypts.
+///
+/// `PublicE2ee` only encrypts for the key of the server. For two-way end-to-end encryption, the
+/// client generates a `ClientKeyPair`, sends its public key to the server along with its first
+/// message, and decrypts the responses the server encrypts for that key with a `PublicE2ee` of
+/// its own. The private key never leaves the client.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::client::{ClientKeyPair, PublicE2ee};
+/// use e2ee::server::KeySize;
+///
+/// let key_pair = ClientKeyPair::generate(KeySize::Bit2048).expect("Failed to generate key pair");
+///
+/// // On the server, with the public key sent by the client.
+/// let client = PublicE2ee::new(key_pair.public_key_pem().to_string())
+///     .expect("Failed to load client key");
+/// let response = client.encrypt("Hello, client!").expect("Failed to encrypt response");
+///
+/// // Back on the client.
+/// assert_eq!(key_pair.decrypt(&response).unwrap(), "Hello, client!");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientKeyPair {
+    e2ee: E2ee,
+}
+
+impl ClientKeyPair {
+    /// Generates a key pair of `key_size`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if key generation fails.
+    pub fn generate(key_size: KeySize) -> E2eeResult<Self> {
+        Ok(Self {
+            e2ee: E2ee::new(key_size)?,
+        })
+    }
+
+    /// Creates a key pair from a private key generated or stored elsewhere, e.g. in a
+    /// `Keystore`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key is invalid.
+    pub fn from_private_key(private_key: RsaPrivateKey) -> E2eeResult<Self> {
+        Ok(Self {
+            e2ee: E2ee::from_private_key(private_key)?,
+        })
+    }
+
+    /// Returns the public key, to send to the server.
+    pub fn public_key(&self) -> &RsaPublicKey {
+        self.e2ee.get_public_key()
+    }
+
+    /// Returns the PEM-encoded public key, to send to the server.
+    #[cfg(feature = "io")]
+    pub fn public_key_pem(&self) -> &str {
+        self.e2ee.get_public_key_pem()
+    }
+
+    /// Returns a `PublicE2ee` encrypting for this key pair, e.g. for a test server in the same
+    /// process.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PublicE2eeError::InvalidPublicKey` if the key pair was created from
+    /// a private key too weak to encrypt for.
+    pub fn to_public_e2ee(&self) -> PublicE2eeResult<PublicE2ee> {
+        PublicE2ee::from_public_key(self.public_key().clone())
+    }
+
+    /// Decrypts a message encrypted for this key pair with `PublicE2ee::encrypt`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message was not encrypted for this key pair or was
+    /// tampered with.
+    pub fn decrypt(&self, ciphertext: &str) -> E2eeResult<String> {
+        self.e2ee.decrypt(ciphertext)
+    }
+
+    /// Decrypts an envelope sealed for this key pair with `PublicE2ee::encrypt_envelope`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the envelope was not sealed for this key pair or was
+    /// tampered with.
+    pub fn decrypt_envelope(&self, envelope: &[u8]) -> E2eeResult<Vec<u8>> {
+        self.e2ee.decrypt_envelope(envelope)
+    }
+
+    /// Returns the underlying `E2ee` instance, e.g. to sign messages with the key pair.
+    pub fn as_e2ee(&self) -> &E2ee {
+        &self.e2ee
+    }
+}
+
+impl From<ClientKeyPair> for E2ee {
+    fn from(key_pair: ClientKeyPair) -> Self {
+        key_pair.e2ee
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_key_pair_decrypts_responses() {
+        let key_pair = ClientKeyPair::generate(KeySize::Bit1024).unwrap();
+        let server_side = key_pair.to_public_e2ee().unwrap();
+        let envelope = server_side.encrypt_envelope(b"response").unwrap();
+        assert_eq!(key_pair.decrypt_envelope(&envelope).unwrap(), b"response");
+
+        let other = ClientKeyPair::generate(KeySize::Bit1024).unwrap();
+        assert!(other.decrypt_envelope(&envelope).is_err());
+    }
+}
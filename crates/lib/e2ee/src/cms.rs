@@ -0,0 +1,151 @@
+//! The CMS structures of RFC 5652 shared by the `timestamp` and `email` modules.
+//!
+//! Sets are kept encoded, see [`set_elements`] and [`set_of`]. Only DER is supported: messages
+//! using the indefinite lengths of BER are rejected.
+use der::{
+    asn1::{ObjectIdentifier, OctetString},
+    Any, Decode, Encode, Reader, Sequence, SliceReader, Tag, Tagged,
+};
+use rsa::pkcs8::spki::AlgorithmIdentifierOwned;
+
+pub(crate) const ID_DATA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.1");
+pub(crate) const ID_SIGNED_DATA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.2");
+pub(crate) const ID_ENVELOPED_DATA: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.7.3");
+pub(crate) const RSA_ENCRYPTION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct ContentInfo {
+    pub(crate) content_type: ObjectIdentifier,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT")]
+    pub(crate) content: Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct SignedData {
+    pub(crate) version: u8,
+    pub(crate) digest_algorithms: Any,
+    pub(crate) encap_content_info: EncapsulatedContentInfo,
+    #[asn1(
+        context_specific = "0",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) certificates: Option<Vec<Any>>,
+    #[asn1(
+        context_specific = "1",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) crls: Option<Vec<Any>>,
+    pub(crate) signer_infos: Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct EncapsulatedContentInfo {
+    pub(crate) econtent_type: ObjectIdentifier,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT", optional = "true")]
+    pub(crate) econtent: Option<OctetString>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct SignerInfo {
+    pub(crate) version: u8,
+    pub(crate) sid: Any,
+    pub(crate) digest_algorithm: AlgorithmIdentifierOwned,
+    #[asn1(
+        context_specific = "0",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) signed_attrs: Option<Vec<Any>>,
+    pub(crate) signature_algorithm: AlgorithmIdentifierOwned,
+    pub(crate) signature: OctetString,
+    #[asn1(
+        context_specific = "1",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) unsigned_attrs: Option<Vec<Any>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct Attribute {
+    pub(crate) oid: ObjectIdentifier,
+    pub(crate) values: Any,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct EnvelopedData {
+    pub(crate) version: u8,
+    #[asn1(
+        context_specific = "0",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) originator_info: Option<Vec<Any>>,
+    pub(crate) recipient_infos: Any,
+    pub(crate) encrypted_content_info: EncryptedContentInfo,
+    #[asn1(
+        context_specific = "1",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    pub(crate) unprotected_attrs: Option<Vec<Any>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct EncryptedContentInfo {
+    pub(crate) content_type: ObjectIdentifier,
+    pub(crate) content_encryption_algorithm: AlgorithmIdentifierOwned,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", optional = "true")]
+    pub(crate) encrypted_content: Option<OctetString>,
+}
+
+/// The `ktri` choice of a `RecipientInfo`, the only one that is not tagged.
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct KeyTransRecipientInfo {
+    pub(crate) version: u8,
+    pub(crate) rid: Any,
+    pub(crate) key_encryption_algorithm: AlgorithmIdentifierOwned,
+    pub(crate) encrypted_key: OctetString,
+}
+
+/// Identifies a certificate by its issuer and serial number, both kept encoded so that they are
+/// compared byte for byte.
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+pub(crate) struct IssuerAndSerialNumber {
+    pub(crate) issuer: Any,
+    pub(crate) serial_number: Any,
+}
+
+/// Returns the elements of a `SET OF`, in their encoded order. Unlike `der::asn1::SetOfVec`, this
+/// tolerates the unsorted and duplicate elements some implementations send.
+pub(crate) fn set_elements(set: &Any) -> der::Result<Vec<Any>> {
+    set.tag().assert_eq(Tag::Set)?;
+    let mut reader = SliceReader::new(set.value())?;
+    let mut elements = Vec::new();
+    while !reader.is_finished() {
+        elements.push(Any::decode(&mut reader)?);
+    }
+    Ok(elements)
+}
+
+/// Encodes `elements` as a `SET OF`, sorted by their encodings as required by DER.
+pub(crate) fn set_of(elements: &[Any]) -> der::Result<Any> {
+    let mut encoded = elements
+        .iter()
+        .map(Encode::to_der)
+        .collect::<der::Result<Vec<_>>>()?;
+    encoded.sort();
+    Any::new(Tag::Set, encoded.concat())
+}
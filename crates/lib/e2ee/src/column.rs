@@ -0,0 +1,309 @@
+//! Field-level database encryption for `sqlx`.
+//!
+//! This module is enabled by the `sqlx` feature. [`Encrypted<T>`] holds the encrypted value of a
+//! column: it implements `sqlx::Type`, `Encode` and `Decode` for every database storing `Vec<u8>`
+//! (e.g. a `BLOB` or `bytea` column), so that it can be bound to queries and read from rows like
+//! any other value.
+//!
+//! Values are encrypted and decrypted by the [`Column`] they are stored in, obtained from
+//! [`ColumnKeys::column`] with the names of its table and column. The names are bound to the value
+//! as the RSA-OAEP label of its data key, so that a value copied into another column or table, by
+//! someone with write access to the database, fails to decrypt. Values are serialized to JSON and
+//! encrypted in the payload format of the `mq` module: the ID of the key, then an envelope. To
+//! rotate keys, call [`ColumnKeys::rotate`] with the new key pair; rows written before remain
+//! readable as long as the old key is kept, and are re-encrypted for the new key the next time
+//! they are written.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::column::{ColumnKeys, Encrypted};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let keys = ColumnKeys::new(e2ee).expect("Failed to create column keys");
+//! let emails = keys.column("users", "email");
+//!
+//! // sqlx::query("INSERT INTO users (email) VALUES (?)")
+//! //     .bind(emails.seal(&"kha@example.com")?)
+//! //     .execute(&pool)
+//! //     .await?;
+//! // let email: Encrypted<String> = sqlx::query_scalar("SELECT email FROM users")
+//! //     .fetch_one(&pool)
+//! //     .await?;
+//! let email: Encrypted<String> = emails
+//!     .seal(&"kha@example.com".to_string())
+//!     .expect("Failed to encrypt");
+//! assert_eq!(emails.open(&email).expect("Failed to decrypt"), "kha@example.com");
+//! assert!(keys.column("users", "name").open(&email).is_err());
+//! ```
+use crate::mq::{DecryptingDeserializer, EncryptingSerializer, Json, MqError};
+use crate::server::E2ee;
+use serde::{de::DeserializeOwned, Serialize};
+use sqlx::{encode::IsNull, error::BoxDynError, Database, Decode, Encode, Type};
+use std::{fmt, marker::PhantomData, sync::RwLock};
+
+/// The keys used to encrypt column values, and to decrypt the values encrypted before rotations.
+#[derive(Debug)]
+pub struct ColumnKeys {
+    serializer: EncryptingSerializer<Json>,
+    deserializer: RwLock<DecryptingDeserializer<Json>>,
+}
+
+impl ColumnKeys {
+    /// Creates keys encrypting and decrypting values with `e2ee`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the ID of the key cannot be computed.
+    pub fn new(e2ee: E2ee) -> Result<Self, MqError> {
+        let serializer =
+            EncryptingSerializer::new(e2ee.get_public_key().clone(), Json)?;
+        let mut deserializer = DecryptingDeserializer::new(Json);
+        deserializer.add_key(e2ee)?;
+        Ok(Self {
+            serializer,
+            deserializer: RwLock::new(deserializer),
+        })
+    }
+
+    /// Returns the column `column` of the table `table`, which encrypts and decrypts its values
+    /// with these keys.
+    pub fn column(&self, table: &str, column: &str) -> Column<'_> {
+        Column {
+            keys: self,
+            // The length of the table name keeps the label unambiguous, whatever the names hold.
+            label: format!("e2ee-column:{}:{table}.{column}", table.len()),
+        }
+    }
+
+    /// Encrypts the values written from now on with `e2ee`, and returns its key ID. Previous keys
+    /// are kept to decrypt the values written before.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the ID of the key cannot be computed.
+    pub fn rotate(&self, e2ee: E2ee) -> Result<String, MqError> {
+        let public_key = e2ee.get_public_key().clone();
+        let key_id = self
+            .deserializer
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .add_key(e2ee)?;
+        self.serializer.rotate(public_key)?;
+        Ok(key_id)
+    }
+
+    /// Removes the key with ID `key_id`, once every value encrypted for it has been rewritten.
+    pub fn remove_key(&self, key_id: &str) -> Option<E2ee> {
+        self.deserializer
+            .write()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove_key(key_id)
+    }
+
+    /// Returns the ID of the key values are currently encrypted for.
+    pub fn key_id(&self) -> String {
+        self.serializer.key_id()
+    }
+
+    /// Returns the ID of the key a stored value was encrypted for.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MqError::Malformed` if `stored` does not start with a key ID.
+    pub fn key_id_of<'a>(&self, stored: &'a [u8]) -> Result<&'a str, MqError> {
+        self.deserializer
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .key_id_of(stored)
+    }
+}
+
+/// A column of a table, encrypting the values stored in it with [`ColumnKeys`], and decrypting
+/// only the values encrypted for it.
+#[derive(Debug)]
+pub struct Column<'a> {
+    keys: &'a ColumnKeys,
+    label: String,
+}
+
+impl Column<'_> {
+    /// Encrypts `value` to be stored in this column.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `value` cannot be serialized or encrypted.
+    pub fn seal<T: Serialize>(&self, value: &T) -> Result<Encrypted<T>, MqError> {
+        let stored = self
+            .keys
+            .serializer
+            .serialize_with_label(value, Some(&self.label))?;
+        Ok(Encrypted::from_bytes(stored))
+    }
+
+    /// Decrypts a value read from this column.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key of the value is unknown, if the value was not
+    /// encrypted for this column, or if it cannot be decrypted or deserialized.
+    pub fn open<T: DeserializeOwned>(
+        &self,
+        value: &Encrypted<T>,
+    ) -> Result<T, MqError> {
+        self.keys
+            .deserializer
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .deserialize_with_label(&value.stored, Some(&self.label))
+    }
+}
+
+/// A column value of type `T`, as encrypted by a [`Column`] and stored in the database.
+///
+/// The `Debug` implementation does not print the encrypted value.
+pub struct Encrypted<T> {
+    stored: Vec<u8>,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T> Encrypted<T> {
+    /// Wraps a value encrypted by a [`Column`], e.g. read from the database without `sqlx`.
+    pub fn from_bytes(stored: Vec<u8>) -> Self {
+        Self {
+            stored,
+            value: PhantomData,
+        }
+    }
+
+    /// Returns the encrypted value, as stored in the database.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.stored
+    }
+
+    /// Returns the encrypted value, as stored in the database.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.stored
+    }
+}
+
+impl<T> Clone for Encrypted<T> {
+    fn clone(&self) -> Self {
+        Self::from_bytes(self.stored.clone())
+    }
+}
+
+impl<T> fmt::Debug for Encrypted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encrypted(..)")
+    }
+}
+
+impl<T, DB> Type<DB> for Encrypted<T>
+where
+    DB: Database,
+    Vec<u8>: Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <Vec<u8> as Type<DB>>::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        <Vec<u8> as Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'q, T, DB> Encode<'q, DB> for Encrypted<T>
+where
+    DB: Database,
+    Vec<u8>: Encode<'q, DB>,
+{
+    fn encode_by_ref(
+        &self,
+        buf: &mut <DB as Database>::ArgumentBuffer,
+    ) -> Result<IsNull, BoxDynError> {
+        self.stored.encode_by_ref(buf)
+    }
+}
+
+impl<'r, T, DB> Decode<'r, DB> for Encrypted<T>
+where
+    DB: Database,
+    Vec<u8>: Decode<'r, DB>,
+{
+    fn decode(value: <DB as Database>::ValueRef<'r>) -> Result<Self, BoxDynError> {
+        Ok(Self::from_bytes(<Vec<u8> as Decode<DB>>::decode(value)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+    use sqlx::{Connection, SqliteConnection};
+
+    #[tokio::test]
+    async fn test_columns_are_encrypted_at_rest_across_rotations() {
+        let keys = ColumnKeys::new(E2ee::new(KeySize::Bit1024).unwrap()).unwrap();
+        let old_id = keys.key_id();
+        let (emails, tags) =
+            (keys.column("users", "email"), keys.column("users", "tags"));
+
+        let mut db = SqliteConnection::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE users (id INTEGER, email BLOB, tags BLOB)")
+            .execute(&mut db)
+            .await
+            .unwrap();
+        let insert = "INSERT INTO users VALUES (?, ?, ?)";
+        sqlx::query(insert)
+            .bind(1)
+            .bind(emails.seal(&"kha@example.com").unwrap())
+            .bind(tags.seal(&["admin"]).unwrap())
+            .execute(&mut db)
+            .await
+            .unwrap();
+
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT email FROM users")
+            .fetch_one(&mut db)
+            .await
+            .unwrap();
+        assert!(!stored.windows(7).any(|window| window == b"example"));
+        assert_eq!(keys.key_id_of(&stored).unwrap(), old_id);
+
+        let new_id = keys.rotate(E2ee::new(KeySize::Bit1024).unwrap()).unwrap();
+        sqlx::query(insert)
+            .bind(2)
+            .bind(emails.seal(&"nguyen@example.com").unwrap())
+            .bind(tags.seal(&Vec::<String>::new()).unwrap())
+            .execute(&mut db)
+            .await
+            .unwrap();
+        let rows: Vec<(Encrypted<String>, Encrypted<Vec<String>>)> =
+            sqlx::query_as("SELECT email, tags FROM users ORDER BY id")
+                .fetch_all(&mut db)
+                .await
+                .unwrap();
+        assert_eq!(emails.open(&rows[0].0).unwrap(), "kha@example.com");
+        assert_eq!(tags.open(&rows[0].1).unwrap(), ["admin"]);
+        assert_eq!(emails.open(&rows[1].0).unwrap(), "nguyen@example.com");
+        assert!(tags.open(&rows[1].1).unwrap().is_empty());
+        assert_eq!(keys.key_id_of(rows[1].0.as_bytes()).unwrap(), new_id);
+
+        // A value copied into another column or table does not decrypt.
+        let copied = Encrypted::<String>::from_bytes(rows[1].0.as_bytes().to_vec());
+        assert!(tags
+            .open(&Encrypted::<Vec<String>>::from_bytes(
+                copied.clone().into_bytes()
+            ))
+            .is_err());
+        assert!(keys.column("admins", "email").open(&copied).is_err());
+        assert!(keys.column("users.email", "").open(&copied).is_err());
+        assert_eq!(emails.open(&copied).unwrap(), "nguyen@example.com");
+
+        keys.remove_key(&old_id);
+        assert!(emails.open(&rows[0].0).is_err());
+        assert_ne!(old_id, new_id);
+        assert_eq!(format!("{:?}", rows[1].0), "Encrypted(..)");
+    }
+}
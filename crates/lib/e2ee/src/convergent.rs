@@ -0,0 +1,273 @@
+//! Deterministic convergent encryption of chunks, for deduplicating backups (enabled by the
+//! `convergent` feature).
+//!
+//! Every other mode of this crate encrypts the same plaintext into different ciphertexts, which
+//! defeats the deduplication of backup tools. A [`Convergent`] instance instead derives the key of
+//! each chunk from its content, so that identical chunks encrypted by different users produce
+//! identical sealed chunks with the same [`ChunkId`], and are stored once. The chunk key must be
+//! kept by whoever may read the chunk, e.g. in a per-user file manifest encrypted with
+//! `E2ee::wrap_key` or an envelope.
+//!
+//! # Confidentiality tradeoffs
+//!
+//! Convergent encryption is weaker than the other modes, by design:
+//!
+//! - **Confirmation of a file**: anyone who knows the convergence secret and guesses a chunk can
+//!   encrypt it and check whether the store holds the same chunk ID. Chunks with little entropy
+//!   (a form letter with a salary, a configuration file with a short password) can be recovered
+//!   by brute force.
+//! - **Equality is visible**: the store learns which users hold identical chunks, and when the
+//!   same content is stored again.
+//!
+//! The convergence secret limits these attacks to those who hold it: share it only between the
+//! users that must deduplicate together (e.g. one secret per organization), never publish it, and
+//! do not use this module for data an attacker could guess, such as small structured records. The
+//! chunk key, not the secret, is what grants access to a chunk.
+//!
+//! A sealed chunk is `version | AES-256-GCM ciphertext and tag`, under the chunk key and a zero
+//! nonce: each key only ever encrypts the content it was derived from. [`Convergent::open`]
+//! checks that the content matches the key, so a chunk forged by a key holder is rejected.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::convergent::Convergent;
+//!
+//! let convergent = Convergent::new(b"organization convergence secret");
+//! let alice = convergent.seal(b"a chunk of a shared file").expect("Failed to seal chunk");
+//! let bob = convergent.seal(b"a chunk of a shared file").expect("Failed to seal chunk");
+//! assert_eq!(alice.id, bob.id);
+//! assert_eq!(alice.sealed, bob.sealed);
+//!
+//! let chunk = convergent
+//!     .open(&alice.key, &bob.sealed)
+//!     .expect("Failed to open chunk");
+//! assert_eq!(chunk, b"a chunk of a shared file");
+//! ```
+use crate::core;
+use crate::kdf::Kdf;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rsa::sha2::{Digest, Sha256};
+use std::fmt;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the format of sealed chunks.
+pub const VERSION: u8 = 1;
+
+/// The length of a chunk key, in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// The salt of the key derivation, binding keys to this format.
+const SALT: &[u8] = b"e2ee convergent v1";
+
+/// The label of the derivation of chunk keys.
+const KEY_LABEL: &str = "chunk key";
+
+pub type ConvergentResult<T> = std::result::Result<T, ConvergentError>;
+
+/// An error returned when sealing or opening a chunk.
+#[derive(Error, Debug)]
+pub enum ConvergentError {
+    #[error("Malformed sealed chunk")]
+    Malformed,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid chunk key, or the chunk was tampered with")]
+    Invalid,
+
+    #[error(
+        "The chunk was not derived from its content with this convergence secret"
+    )]
+    NotConvergent,
+
+    #[error("Key derivation error: {0}")]
+    Kdf(#[from] crate::kdf::KdfError),
+}
+
+/// The SHA-256 hash of a sealed chunk, under which it is stored and deduplicated.
+///
+/// The store can check that a chunk matches its ID without any key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkId(pub [u8; 32]);
+
+impl ChunkId {
+    /// Returns the ID of `sealed`.
+    pub fn of(sealed: &[u8]) -> Self {
+        Self(Sha256::digest(sealed).into())
+    }
+}
+
+impl fmt::Display for ChunkId {
+    /// Writes the ID as lowercase hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&core::hex(&self.0))
+    }
+}
+
+/// The key of a chunk, derived from its content.
+#[derive(Clone)]
+pub struct ChunkKey(Zeroizing<[u8; KEY_LEN]>);
+
+impl fmt::Debug for ChunkKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChunkKey").finish_non_exhaustive()
+    }
+}
+
+impl ChunkKey {
+    /// Restores a key stored with [`ChunkKey::as_bytes`].
+    pub fn from_bytes(key: [u8; KEY_LEN]) -> Self {
+        Self(Zeroizing::new(key))
+    }
+
+    /// Returns the key, to be stored in a manifest encrypted for the readers of the chunk.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+/// A chunk sealed by [`Convergent::seal`].
+#[derive(Debug, Clone)]
+pub struct SealedChunk {
+    /// The ID to store the chunk under.
+    pub id: ChunkId,
+    /// The key opening the chunk.
+    pub key: ChunkKey,
+    /// The sealed chunk.
+    pub sealed: Vec<u8>,
+}
+
+/// Seals chunks under keys derived from their content and a convergence secret.
+#[derive(Clone)]
+pub struct Convergent {
+    kdf: Kdf,
+}
+
+impl fmt::Debug for Convergent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Convergent").finish_non_exhaustive()
+    }
+}
+
+impl Convergent {
+    /// Creates an instance deduplicating chunks with the users sharing `convergence_secret`.
+    ///
+    /// An empty secret deduplicates with anyone, and lets anyone confirm the content of chunks:
+    /// see the tradeoffs of the module documentation.
+    pub fn new(convergence_secret: impl AsRef<[u8]>) -> Self {
+        Self {
+            kdf: Kdf::extract(Some(SALT), convergence_secret.as_ref()),
+        }
+    }
+
+    /// Derives the key of `chunk`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be derived.
+    pub fn chunk_key(&self, chunk: &[u8]) -> ConvergentResult<ChunkKey> {
+        Ok(ChunkKey(
+            self.kdf.derive_key(KEY_LABEL, &Sha256::digest(chunk))?,
+        ))
+    }
+
+    /// Seals `chunk` under the key derived from its content. Sealing the same chunk with the same
+    /// convergence secret always returns the same sealed chunk and ID.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be derived or encryption fails.
+    pub fn seal(&self, chunk: &[u8]) -> ConvergentResult<SealedChunk> {
+        let key = self.chunk_key(chunk)?;
+        let ciphertext = cipher(&key)
+            .encrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: chunk,
+                    aad: &[VERSION],
+                },
+            )
+            .map_err(|_| ConvergentError::Invalid)?;
+        let mut sealed = Vec::with_capacity(1 + ciphertext.len());
+        sealed.push(VERSION);
+        sealed.extend(ciphertext);
+        Ok(SealedChunk {
+            id: ChunkId::of(&sealed),
+            key,
+            sealed,
+        })
+    }
+
+    /// Opens a chunk sealed with [`Convergent::seal`], and checks that its content matches `key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ConvergentError::Invalid` if `key` is not the key of the chunk or
+    /// the chunk was tampered with, and `ConvergentError::NotConvergent` if the content does not
+    /// derive `key` with the convergence secret of this instance.
+    pub fn open(&self, key: &ChunkKey, sealed: &[u8]) -> ConvergentResult<Vec<u8>> {
+        let (&version, ciphertext) =
+            sealed.split_first().ok_or(ConvergentError::Malformed)?;
+        if version != VERSION {
+            return Err(ConvergentError::UnsupportedVersion(version));
+        }
+        let chunk = cipher(key)
+            .decrypt(
+                &Nonce::default(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[VERSION],
+                },
+            )
+            .map_err(|_| ConvergentError::Invalid)?;
+        // The caller holds both the key and the content: the comparison reveals nothing.
+        if self.chunk_key(&chunk)?.as_bytes() != key.as_bytes() {
+            return Err(ConvergentError::NotConvergent);
+        }
+        Ok(chunk)
+    }
+}
+
+fn cipher(key: &ChunkKey) -> Aes256Gcm {
+    Aes256Gcm::new(key.as_bytes().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_is_deterministic_per_secret() {
+        let convergent = Convergent::new(b"secret");
+        let first = convergent.seal(b"chunk").unwrap();
+        let second = convergent.seal(b"chunk").unwrap();
+        assert_eq!(first.id, second.id);
+        assert_eq!(first.sealed, second.sealed);
+        assert_eq!(first.id, ChunkId::of(&first.sealed));
+        assert_ne!(convergent.seal(b"other chunk").unwrap().id, first.id);
+
+        let other = Convergent::new(b"other secret");
+        assert_ne!(other.seal(b"chunk").unwrap().id, first.id);
+        assert!(matches!(
+            other.open(&first.key, &first.sealed),
+            Err(ConvergentError::NotConvergent)
+        ));
+
+        let mut tampered = first.sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            convergent.open(&first.key, &tampered),
+            Err(ConvergentError::Invalid)
+        ));
+        assert_eq!(
+            convergent.open(&first.key, &first.sealed).unwrap(),
+            b"chunk"
+        );
+    }
+}
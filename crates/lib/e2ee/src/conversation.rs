@@ -0,0 +1,149 @@
+//! Two-way conversations between a key pair and the public key of a peer.
+//!
+//! `E2ee` decrypts for its own key and `PublicE2ee` encrypts for someone else's, so a two-way
+//! exchange juggles both, and nothing checks who a message came from. A [`Conversation`] holds
+//! "my key pair and their public key": [`Conversation::send`] signs a message with my key and
+//! encrypts it for theirs, and [`Conversation::receive`] decrypts a message with my key and
+//! accepts it only if they signed it. Messages are sealed-sender envelopes (see the
+//! `sealed_sender` module), so relays only learn the key ID of the recipient.
+//!
+//! A client typically holds a `client::ClientKeyPair` and the public key of the server, and the
+//! server its `E2ee` instance and the public key sent by the client.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::client::ClientKeyPair;
+//! use e2ee::conversation::Conversation;
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let server = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let client = ClientKeyPair::generate(KeySize::Bit2048).expect("Failed to generate key pair");
+//!
+//! let client_side = Conversation::new(client.clone(), server.get_public_key().clone())
+//!     .expect("Invalid server key");
+//! let server_side = Conversation::new(server, client.public_key().clone())
+//!     .expect("Invalid client key");
+//!
+//! let request = client_side.send(b"ping").expect("Failed to send");
+//! assert_eq!(server_side.receive(&request).unwrap(), b"ping");
+//! let response = server_side.send(b"pong").expect("Failed to send");
+//! assert_eq!(client_side.receive(&response).unwrap(), b"pong");
+//! ```
+use crate::core;
+use crate::sealed_sender::{self, SealedSenderError};
+use crate::server::E2ee;
+use rsa::{traits::PublicKeyParts, RsaPublicKey};
+use thiserror::Error;
+
+pub type ConversationResult<T> = std::result::Result<T, ConversationError>;
+
+/// An error returned when starting a conversation or exchanging messages in it.
+#[derive(Error, Debug)]
+pub enum ConversationError {
+    #[error("Sealed-sender error: {0}")]
+    SealedSender(#[from] SealedSenderError),
+
+    #[error("Invalid peer key: {0}")]
+    InvalidPeerKey(String),
+
+    #[error("The message was not sent by the peer of the conversation")]
+    UnexpectedSender,
+}
+
+/// A conversation between my key pair and the public key of a peer.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    me: E2ee,
+    peer: RsaPublicKey,
+}
+
+impl Conversation {
+    /// Starts a conversation between `me` and the holder of `peer`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ConversationError::InvalidPeerKey` if `peer` is too weak to encrypt
+    /// for (see `core::validate_public_key`).
+    pub fn new(me: impl Into<E2ee>, peer: RsaPublicKey) -> ConversationResult<Self> {
+        core::validate_public_key(peer.n(), peer.e())
+            .map_err(ConversationError::InvalidPeerKey)?;
+        Ok(Self {
+            me: me.into(),
+            peer,
+        })
+    }
+
+    /// Returns my key pair.
+    pub fn me(&self) -> &E2ee {
+        &self.me
+    }
+
+    /// Returns the public key of the peer.
+    pub fn peer(&self) -> &RsaPublicKey {
+        &self.peer
+    }
+
+    /// Signs `plaintext` with my key and encrypts it into an envelope for the peer.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing or encryption fails.
+    pub fn send(&self, plaintext: &[u8]) -> ConversationResult<Vec<u8>> {
+        Ok(sealed_sender::seal(&self.me, &self.peer, plaintext)?)
+    }
+
+    /// Decrypts an envelope sent by the peer with [`Conversation::send`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ConversationError::UnexpectedSender` if the envelope was signed by
+    /// another key than the peer's, or an error if it cannot be decrypted or its signature is
+    /// invalid.
+    pub fn receive(&self, envelope: &[u8]) -> ConversationResult<Vec<u8>> {
+        let opened = sealed_sender::open(&self.me, envelope)?;
+        if opened.sender != self.peer {
+            return Err(ConversationError::UnexpectedSender);
+        }
+        Ok(opened.message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_conversation_roundtrip() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let alice_side =
+            Conversation::new(alice.clone(), bob.get_public_key().clone()).unwrap();
+        let bob_side =
+            Conversation::new(bob, alice.get_public_key().clone()).unwrap();
+
+        let envelope = alice_side.send(b"Hello, Bob!").unwrap();
+        assert_eq!(bob_side.receive(&envelope).unwrap(), b"Hello, Bob!");
+        // A message sent to the peer cannot be received by its own sender.
+        assert!(alice_side.receive(&envelope).is_err());
+    }
+
+    #[test]
+    fn test_conversation_rejects_other_senders() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let mallory = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob_side =
+            Conversation::new(bob.clone(), alice.get_public_key().clone()).unwrap();
+
+        let forged = Conversation::new(mallory, bob.get_public_key().clone())
+            .unwrap()
+            .send(b"Hello, Bob!")
+            .unwrap();
+        assert!(matches!(
+            bob_side.receive(&forged),
+            Err(ConversationError::UnexpectedSender)
+        ));
+    }
+}
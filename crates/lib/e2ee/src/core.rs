@@ -0,0 +1,657 @@
+//! Pure RSA primitives shared by the client and server sides.
+//!
+//! This module only deals with in-memory keys and bytes: key generation, RSA-OAEP encryption and
+//! decryption, RSASSA-PKCS1-v1_5 signatures, and validation of foreign public keys. It never touches the file system or PEM
+//! encoding, which live in the `io` module.
+use rsa::{
+    pkcs8::{spki, EncodePublicKey},
+    rand_core::{CryptoRngCore, OsRng},
+    sha2::{Digest, Sha256, Sha384, Sha512},
+    BigUint, Oaep, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
+use sha1::Sha1;
+
+/// The smallest modulus size (in bits) accepted for imported public keys.
+const MIN_MODULUS_BITS: usize = 1024;
+
+/// The smallest public exponent accepted for imported public keys (F4).
+const MIN_PUBLIC_EXPONENT: u32 = 65537;
+
+/// Small primes used to detect obviously malformed moduli.
+const SMALL_PRIMES: [u32; 25] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73,
+    79, 83, 89, 97,
+];
+
+/// A hash function usable in RSA-OAEP, either for the label or for the MGF1 mask generation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum OaepHash {
+    /// SHA-1, still the default of many platforms (e.g. the MGF1 hash of Java's
+    /// `OAEPWithSHA-256AndMGF1Padding`).
+    Sha1,
+    /// SHA-256, the default of this crate.
+    #[default]
+    Sha256,
+    /// SHA-384.
+    Sha384,
+    /// SHA-512.
+    Sha512,
+}
+
+impl OaepHash {
+    /// Returns the name of the hash as used by WebCrypto and JOSE (e.g. `"SHA-256"`).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OaepHash::Sha1 => "SHA-1",
+            OaepHash::Sha256 => "SHA-256",
+            OaepHash::Sha384 => "SHA-384",
+            OaepHash::Sha512 => "SHA-512",
+        }
+    }
+
+    /// Returns the digest size in bytes.
+    pub fn output_size(&self) -> usize {
+        match self {
+            OaepHash::Sha1 => 20,
+            OaepHash::Sha256 => 32,
+            OaepHash::Sha384 => 48,
+            OaepHash::Sha512 => 64,
+        }
+    }
+
+    fn digest(&self) -> Box<dyn rsa::sha2::digest::DynDigest + Send + Sync> {
+        match self {
+            OaepHash::Sha1 => Box::new(Sha1::new()),
+            OaepHash::Sha256 => Box::new(Sha256::new()),
+            OaepHash::Sha384 => Box::new(Sha384::new()),
+            OaepHash::Sha512 => Box::new(Sha512::new()),
+        }
+    }
+}
+
+/// The parameters of RSA-OAEP padding: the label hash and the MGF1 hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct OaepParams {
+    /// The hash applied to the (empty) label.
+    pub hash: OaepHash,
+    /// The hash used by the MGF1 mask generation function.
+    pub mgf1_hash: OaepHash,
+}
+
+impl OaepParams {
+    /// SHA-256 for both the label and MGF1, the parameters used by `encrypt` and `decrypt`.
+    pub const SHA256: Self = Self::new(OaepHash::Sha256);
+
+    /// Creates parameters using `hash` for both the label and MGF1.
+    pub const fn new(hash: OaepHash) -> Self {
+        Self {
+            hash,
+            mgf1_hash: hash,
+        }
+    }
+
+    /// Returns the longest message, in bytes, that can be encrypted under a key of `key_bytes`
+    /// bytes with these parameters.
+    pub fn max_message_len(&self, key_bytes: usize) -> usize {
+        key_bytes.saturating_sub(2 * self.hash.output_size() + 2)
+    }
+
+    fn padding(&self) -> Oaep {
+        Oaep {
+            digest: self.hash.digest(),
+            mgf_digest: self.mgf1_hash.digest(),
+            label: None,
+        }
+    }
+
+    fn padding_with_label(&self, label: &str) -> Oaep {
+        Oaep {
+            label: Some(label.to_string()),
+            ..self.padding()
+        }
+    }
+}
+
+/// Generates a new RSA private key with a modulus of `bits` bits.
+///
+/// # Errors
+///
+/// This function returns an error if key generation fails.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", err(level = "warn"))
+)]
+pub fn generate_private_key(bits: usize) -> rsa::Result<RsaPrivateKey> {
+    generate_private_key_with_rng(&mut OsRng, bits)
+}
+
+/// Generates a new RSA private key with a modulus of `bits` bits, drawing its primes from `rng`
+/// (see the `rng` module).
+///
+/// # Errors
+///
+/// This function returns an error if key generation fails.
+pub fn generate_private_key_with_rng<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    bits: usize,
+) -> rsa::Result<RsaPrivateKey> {
+    RsaPrivateKey::new(rng, bits)
+}
+
+/// Encrypts `message` with RSA-OAEP (SHA-256) under `public_key`.
+///
+/// # Errors
+///
+/// This function returns an error if the message is too long for the key or if encryption fails.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key_bits = rsa::traits::PublicKeyParts::size(public_key) * 8),
+        err(level = "warn")
+    )
+)]
+pub fn encrypt(public_key: &RsaPublicKey, message: &[u8]) -> rsa::Result<Vec<u8>> {
+    encrypt_with(public_key, OaepParams::SHA256, message)
+}
+
+/// Encrypts `message` with RSA-OAEP under `public_key`, using the given OAEP `params`.
+///
+/// # Errors
+///
+/// This function returns an error if the message is too long for the key or if encryption fails.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key_bits = rsa::traits::PublicKeyParts::size(public_key) * 8),
+        err(level = "warn")
+    )
+)]
+pub fn encrypt_with(
+    public_key: &RsaPublicKey,
+    params: OaepParams,
+    message: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    encrypt_with_rng(&mut OsRng, public_key, params, message)
+}
+
+/// Encrypts `message` like [`encrypt_with`], drawing the OAEP seed from `rng` (see the `rng`
+/// module).
+///
+/// # Errors
+///
+/// This function returns an error if the message is too long for the key or if encryption fails.
+pub fn encrypt_with_rng<R: CryptoRngCore + ?Sized>(
+    mut rng: &mut R,
+    public_key: &RsaPublicKey,
+    params: OaepParams,
+    message: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    public_key.encrypt(&mut rng, params.padding(), message)
+}
+
+/// Decrypts an RSA-OAEP (SHA-256) `ciphertext` with `private_key`.
+///
+/// # Errors
+///
+/// This function returns an error if decryption fails.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key_bits = rsa::traits::PublicKeyParts::size(private_key) * 8),
+        err(level = "warn")
+    )
+)]
+pub fn decrypt(
+    private_key: &RsaPrivateKey,
+    ciphertext: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    decrypt_with(private_key, OaepParams::SHA256, ciphertext)
+}
+
+/// Decrypts an RSA-OAEP `ciphertext` with `private_key`, using the given OAEP `params`.
+///
+/// # Errors
+///
+/// This function returns an error if decryption fails.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key_bits = rsa::traits::PublicKeyParts::size(private_key) * 8),
+        err(level = "warn")
+    )
+)]
+pub fn decrypt_with(
+    private_key: &RsaPrivateKey,
+    params: OaepParams,
+    ciphertext: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    private_key.decrypt(params.padding(), ciphertext)
+}
+
+/// Decrypts an RSA-OAEP `ciphertext` like [`decrypt_with`], blinding the private key operation
+/// with a random factor so that its timing does not depend on the ciphertext.
+///
+/// # Errors
+///
+/// This function returns an error if decryption fails.
+pub fn decrypt_blinded_with(
+    private_key: &RsaPrivateKey,
+    params: OaepParams,
+    ciphertext: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    decrypt_blinded_with_rng(&mut OsRng, private_key, params, ciphertext)
+}
+
+/// Decrypts an RSA-OAEP `ciphertext` like [`decrypt_blinded_with`], drawing the blinding factor
+/// from `rng`.
+///
+/// # Errors
+///
+/// This function returns an error if decryption fails.
+pub fn decrypt_blinded_with_rng<R: CryptoRngCore + ?Sized>(
+    mut rng: &mut R,
+    private_key: &RsaPrivateKey,
+    params: OaepParams,
+    ciphertext: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    private_key.decrypt_blinded(&mut rng, params.padding(), ciphertext)
+}
+
+/// Encrypts `message` like [`encrypt_with_rng`], with `label` as the RSA-OAEP label.
+///
+/// The ciphertext only decrypts with the same label (see [`decrypt_with_label`]), which binds it
+/// to its context, e.g. the device that sent it, without taking room from the message.
+///
+/// # Errors
+///
+/// This function returns an error if the message is too long for the key or if encryption fails.
+pub fn encrypt_with_label<R: CryptoRngCore + ?Sized>(
+    mut rng: &mut R,
+    public_key: &RsaPublicKey,
+    params: OaepParams,
+    label: &str,
+    message: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    public_key.encrypt(&mut rng, params.padding_with_label(label), message)
+}
+
+/// Decrypts an RSA-OAEP `ciphertext` produced by [`encrypt_with_label`] with the same `label`.
+///
+/// # Errors
+///
+/// This function returns an error if decryption fails, including when `label` differs.
+pub fn decrypt_with_label(
+    private_key: &RsaPrivateKey,
+    params: OaepParams,
+    label: &str,
+    ciphertext: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    private_key.decrypt(params.padding_with_label(label), ciphertext)
+}
+
+/// Removes the RSA-OAEP padding from `encoded`, the big-endian result of a raw RSA decryption
+/// under a key of `key_bytes` bytes, as specified in RFC 8017 section 7.1.2. It serves private key
+/// operations not done by `rsa`, such as combined threshold decryptions.
+///
+/// Every check runs whatever the outcome of the previous ones, and they all fail with the same
+/// error.
+pub(crate) fn oaep_decode(
+    params: OaepParams,
+    key_bytes: usize,
+    encoded: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    let hash_len = params.hash.output_size();
+    if encoded.len() > key_bytes || key_bytes < 2 * hash_len + 2 {
+        return Err(rsa::Error::Decryption);
+    }
+    let mut padded = vec![0; key_bytes];
+    padded[key_bytes - encoded.len()..].copy_from_slice(encoded);
+    let mut invalid = padded[0];
+    let (seed, block) = padded[1..].split_at_mut(hash_len);
+    mgf1_xor(seed, params.mgf1_hash, block);
+    mgf1_xor(block, params.mgf1_hash, seed);
+
+    let label_hash = params.hash.digest().finalize();
+    for (byte, expected) in block.iter().zip(label_hash.iter()) {
+        invalid |= byte ^ expected;
+    }
+    // The label hash is followed by zeros, then by 0x01 and the message.
+    let mut separator = None;
+    for (index, &byte) in block.iter().enumerate().skip(hash_len) {
+        if separator.is_none() {
+            invalid |= u8::from(byte > 1);
+            separator = (byte == 1).then_some(index);
+        }
+    }
+    match separator {
+        Some(separator) if invalid == 0 => Ok(block[separator + 1..].to_vec()),
+        _ => Err(rsa::Error::Decryption),
+    }
+}
+
+/// XORs `output` with the MGF1 mask of `seed`.
+fn mgf1_xor(output: &mut [u8], hash: OaepHash, seed: &[u8]) {
+    let mut digest = hash.digest();
+    for (counter, chunk) in (0u32..).zip(output.chunks_mut(hash.output_size())) {
+        digest.update(seed);
+        digest.update(&counter.to_be_bytes());
+        for (byte, mask) in chunk.iter_mut().zip(digest.finalize_reset().iter()) {
+            *byte ^= mask;
+        }
+    }
+}
+
+/// Signs `message` with RSASSA-PKCS1-v1_5 (SHA-256) under `private_key`, the scheme of
+/// `openssl dgst -sha256 -sign` and of JWS `RS256`.
+///
+/// # Errors
+///
+/// This function returns an error if the key is too small for a SHA-256 signature.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(key_bits = rsa::traits::PublicKeyParts::size(private_key) * 8),
+        err(level = "warn")
+    )
+)]
+pub fn sign(private_key: &RsaPrivateKey, message: &[u8]) -> rsa::Result<Vec<u8>> {
+    sign_digest(private_key, &Sha256::digest(message).into())
+}
+
+/// Signs `message` like [`sign`], blinding the private key operation with a random factor.
+///
+/// # Errors
+///
+/// This function returns an error if the key is too small for a SHA-256 signature.
+pub fn sign_blinded(
+    private_key: &RsaPrivateKey,
+    message: &[u8],
+) -> rsa::Result<Vec<u8>> {
+    sign_digest_blinded(private_key, &Sha256::digest(message).into())
+}
+
+/// Signs the SHA-256 `digest` of a message like [`sign`] signs the message, for messages hashed
+/// incrementally (e.g. large files).
+///
+/// # Errors
+///
+/// This function returns an error if the key is too small for a SHA-256 signature.
+pub fn sign_digest(
+    private_key: &RsaPrivateKey,
+    digest: &[u8; 32],
+) -> rsa::Result<Vec<u8>> {
+    private_key.sign(Pkcs1v15Sign::new::<Sha256>(), digest)
+}
+
+/// Signs the SHA-256 `digest` of a message like [`sign_blinded`] signs the message.
+///
+/// # Errors
+///
+/// This function returns an error if the key is too small for a SHA-256 signature.
+pub fn sign_digest_blinded(
+    private_key: &RsaPrivateKey,
+    digest: &[u8; 32],
+) -> rsa::Result<Vec<u8>> {
+    sign_digest_blinded_with_rng(&mut OsRng, private_key, digest)
+}
+
+/// Signs the SHA-256 `digest` of a message like [`sign_digest_blinded`], drawing the blinding
+/// factor from `rng`.
+///
+/// # Errors
+///
+/// This function returns an error if the key is too small for a SHA-256 signature.
+pub fn sign_digest_blinded_with_rng<R: CryptoRngCore + ?Sized>(
+    mut rng: &mut R,
+    private_key: &RsaPrivateKey,
+    digest: &[u8; 32],
+) -> rsa::Result<Vec<u8>> {
+    private_key.sign_with_rng(&mut rng, Pkcs1v15Sign::new::<Sha256>(), digest)
+}
+
+/// Verifies an RSASSA-PKCS1-v1_5 (SHA-256) `signature` of `message` under `public_key`.
+///
+/// # Errors
+///
+/// This function returns `rsa::Error::Verification` if the signature does not match.
+pub fn verify(
+    public_key: &RsaPublicKey,
+    message: &[u8],
+    signature: &[u8],
+) -> rsa::Result<()> {
+    verify_digest(public_key, &Sha256::digest(message).into(), signature)
+}
+
+/// Verifies an RSASSA-PKCS1-v1_5 (SHA-256) `signature` of a message with the SHA-256 `digest`.
+///
+/// # Errors
+///
+/// This function returns `rsa::Error::Verification` if the signature does not match.
+pub fn verify_digest(
+    public_key: &RsaPublicKey,
+    digest: &[u8; 32],
+    signature: &[u8],
+) -> rsa::Result<()> {
+    public_key.verify(Pkcs1v15Sign::new::<Sha256>(), digest, signature)
+}
+
+/// Computes the fingerprint of a public key: the lowercase hex SHA-256 digest of its SPKI DER
+/// encoding (the same value as `openssl pkey -pubin -outform DER | sha256sum`).
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn fingerprint(public_key: &RsaPublicKey) -> spki::Result<String> {
+    let der = public_key.to_public_key_der()?;
+    Ok(hex(&Sha256::digest(der.as_bytes())))
+}
+
+/// Encodes `bytes` as lowercase hex.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        hex.push(char::from(DIGITS[usize::from(byte >> 4)]));
+        hex.push(char::from(DIGITS[usize::from(byte & 0x0f)]));
+    }
+    hex
+}
+
+/// Computes the short ID of a public key: the first 16 hex digits of its [`fingerprint`], used to
+/// tag ciphertexts with the key they were encrypted for.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn key_id(public_key: &RsaPublicKey) -> spki::Result<String> {
+    let mut fingerprint = fingerprint(public_key)?;
+    fingerprint.truncate(KEY_ID_LEN);
+    Ok(fingerprint)
+}
+
+/// The length of the key IDs returned by [`key_id`], in hex digits.
+pub const KEY_ID_LEN: usize = 16;
+
+/// Validates the parameters of a foreign RSA public key.
+///
+/// The exponent must be odd and at least 65537, the modulus must be at least 1024 bits long, larger
+/// than the exponent and must not be divisible by any small prime. A descriptive reason is returned
+/// when one of these checks fails.
+pub(crate) fn validate_public_key(n: &BigUint, e: &BigUint) -> Result<(), String> {
+    let zero = BigUint::default();
+
+    if e < &BigUint::from(MIN_PUBLIC_EXPONENT) {
+        return Err(format!(
+            "public exponent {} is smaller than {}",
+            e, MIN_PUBLIC_EXPONENT
+        ));
+    }
+    if e % BigUint::from(2u32) == zero {
+        return Err(format!("public exponent {} is even", e));
+    }
+    if n.bits() < MIN_MODULUS_BITS {
+        return Err(format!(
+            "modulus is {} bits, at least {} bits are required",
+            n.bits(),
+            MIN_MODULUS_BITS
+        ));
+    }
+    if n <= e {
+        return Err("modulus is not larger than the public exponent".into());
+    }
+    if let Some(prime) = SMALL_PRIMES
+        .iter()
+        .find(|&&prime| n % BigUint::from(prime) == zero)
+    {
+        return Err(format!("modulus is divisible by {}", prime));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::traits::PublicKeyParts;
+
+    #[test]
+    fn test_core_encrypt_decrypt_roundtrip() {
+        let private_key = generate_private_key(2048).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        let message = b"Hello core!";
+        let encrypted = encrypt(&public_key, message).unwrap();
+        let decrypted = decrypt(&private_key, &encrypted).unwrap();
+        assert_eq!(message.as_slice(), decrypted.as_slice());
+    }
+
+    #[test]
+    fn test_oaep_params_must_match() {
+        let private_key = generate_private_key(1024).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        let params = OaepParams {
+            hash: OaepHash::Sha256,
+            mgf1_hash: OaepHash::Sha1,
+        };
+        let message = b"Hello params!";
+
+        let encrypted = encrypt_with(&public_key, params, message).unwrap();
+        assert_eq!(
+            decrypt_with(&private_key, params, &encrypted).unwrap(),
+            message
+        );
+        assert!(decrypt(&private_key, &encrypted).is_err());
+        assert_eq!(OaepParams::SHA256.max_message_len(128), 62);
+
+        let labelled =
+            encrypt_with_label(&mut OsRng, &public_key, params, "a", message)
+                .unwrap();
+        assert_eq!(
+            decrypt_with_label(&private_key, params, "a", &labelled).unwrap(),
+            message
+        );
+        assert!(decrypt_with_label(&private_key, params, "b", &labelled).is_err());
+        assert!(decrypt_with(&private_key, params, &labelled).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let private_key = generate_private_key(1024).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        let signature = sign(&private_key, b"Hello signature!").unwrap();
+        assert_eq!(signature.len(), public_key.size());
+        assert_eq!(signature, sign(&private_key, b"Hello signature!").unwrap());
+        verify(&public_key, b"Hello signature!", &signature).unwrap();
+        assert!(matches!(
+            verify(&public_key, b"Hello signature?", &signature),
+            Err(rsa::Error::Verification)
+        ));
+    }
+
+    #[test]
+    fn test_fingerprint_is_stable_hex() {
+        let private_key = generate_private_key(1024).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+        let fingerprint = fingerprint(&public_key).unwrap();
+        assert_eq!(fingerprint.len(), 64);
+        assert!(fingerprint.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(fingerprint, super::fingerprint(&public_key).unwrap());
+        assert_eq!(hex(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_fingerprint_matches_openssl() {
+        let public_key_pem = std::fs::read_to_string(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/files/public.pem"
+        ))
+        .expect("Failed to read public key file");
+        let (n, e) =
+            crate::io::decode_public_key_components(&public_key_pem).unwrap();
+        let public_key = RsaPublicKey::new(n, e).unwrap();
+        // openssl pkey -pubin -in files/public.pem -outform DER | sha256sum
+        assert_eq!(
+            fingerprint(&public_key).unwrap(),
+            "539c7a2d954932ee0c2e34da78542bf1c591d4fbaa3f9e49e8164c52da69dcd0"
+        );
+    }
+
+    #[test]
+    fn test_validate_public_key_accepts_generated_key() {
+        let private_key = generate_private_key(2048).unwrap();
+        assert!(validate_public_key(private_key.n(), private_key.e()).is_ok());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_never_logs_plaintext() {
+        use std::sync::{Arc, Mutex};
+        use tracing_subscriber::fmt::format::FmtSpan;
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let message = "Do not log this plaintext";
+        tracing::subscriber::with_default(subscriber, || {
+            let private_key = generate_private_key(1024).unwrap();
+            let public_key = RsaPublicKey::from(&private_key);
+            let encrypted = encrypt(&public_key, message.as_bytes()).unwrap();
+            decrypt(&private_key, &encrypted).unwrap();
+            assert!(decrypt(&private_key, b"not a ciphertext").is_err());
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("decrypt"));
+        assert!(output.contains("key_bits=1024"));
+        assert!(!output.contains(message));
+    }
+}
@@ -0,0 +1,381 @@
+//! Deniable encryption: one ciphertext, two plaintexts.
+//!
+//! This module is enabled by the `experimental` feature.
+//!
+//! # Warning
+//!
+//! This mode is experimental: its format may change without notice, and it has not been reviewed.
+//! Deniability is only as good as the story told with it. It does not hold against an adversary
+//! who knows that this mode exists and that you use it, since the hidden slot is always there;
+//! it only helps if every ciphertext you produce, including ordinary ones, comes from
+//! [`seal_one`], so that the mere presence of a second slot proves nothing. The length of the
+//! ciphertext reveals the length of the longer plaintext (rounded up to 256 bytes), so choose a
+//! decoy of similar length. Nothing here protects against malware, logs or backups that kept the
+//! real plaintext, or against rubber-hose cryptanalysis of the second key.
+//!
+//! # Design
+//!
+//! A ciphertext produced by [`seal`] holds two slots in random order. Each slot is an RSA-OAEP
+//! (SHA-256) wrapped AES-256-GCM data key, a nonce and the encryption of one plaintext, padded to a
+//! common length. The decoy key opens the decoy slot, and the hidden key the other one; neither
+//! private key reveals whether the other slot holds a message. [`seal_one`] fills the second slot
+//! with random bytes of the same length, which cannot be distinguished from a slot sealed for a
+//! key the adversary does not have. Its binary layout is:
+//!
+//! ```text
+//! version (1 byte) | padded length (4 bytes, big endian) | slot | slot
+//! slot: wrapped key length (2 bytes, big endian) | wrapped key | nonce (12 bytes) | AES-256-GCM
+//!       ciphertext and tag
+//! ```
+//!
+//! Both keys must have the same modulus size, so that the slots have the same length.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::deniable;
+//!
+//! let decoy_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let hidden_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let sealed = deniable::seal(
+//!     &decoy_key.to_public_key(),
+//!     b"Grocery list: eggs, milk",
+//!     &hidden_key.to_public_key(),
+//!     b"Meet at the north gate",
+//! )
+//! .expect("Failed to seal");
+//! assert_eq!(deniable::open(&decoy_key, &sealed).unwrap(), b"Grocery list: eggs, milk");
+//! assert_eq!(deniable::open(&hidden_key, &sealed).unwrap(), b"Meet at the north gate");
+//! ```
+use crate::core::{self, OaepParams};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use thiserror::Error;
+
+/// The version of the deniable ciphertext format.
+pub const VERSION: u8 = 1;
+
+/// The plaintexts are padded to a multiple of this length.
+const PADDING_BLOCK: usize = 256;
+
+/// The length of the plaintext length prefix inside each slot.
+const LENGTH_PREFIX_LEN: usize = 4;
+
+const DATA_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// An error returned when sealing or opening a deniable ciphertext.
+#[derive(Error, Debug)]
+pub enum DeniableError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("Malformed deniable ciphertext")]
+    Malformed,
+
+    #[error("Unsupported deniable ciphertext version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The decoy and hidden keys have different sizes")]
+    KeySizeMismatch,
+
+    #[error("The plaintext is too long")]
+    TooLong,
+
+    #[error("No slot of the ciphertext can be decrypted with this key")]
+    Decryption,
+}
+
+pub type DeniableResult<T> = std::result::Result<T, DeniableError>;
+
+/// Encrypts `decoy` for `decoy_key` and `hidden` for `hidden_key` into a single ciphertext.
+///
+/// # Errors
+///
+/// This function returns `DeniableError::KeySizeMismatch` if the keys have different modulus
+/// sizes, `DeniableError::TooLong` if a plaintext is 4 GiB or longer, or `DeniableError::Rsa` if
+/// a data key cannot be encrypted.
+pub fn seal(
+    decoy_key: &RsaPublicKey,
+    decoy: &[u8],
+    hidden_key: &RsaPublicKey,
+    hidden: &[u8],
+) -> DeniableResult<Vec<u8>> {
+    if decoy_key.size() != hidden_key.size() {
+        return Err(DeniableError::KeySizeMismatch);
+    }
+    seal_slots([Some((decoy_key, decoy)), Some((hidden_key, hidden))])
+}
+
+/// Encrypts `plaintext` for `public_key` in the same format as [`seal`], with random bytes in place
+/// of the hidden slot.
+///
+/// # Errors
+///
+/// This function returns `DeniableError::TooLong` if the plaintext is 4 GiB or longer, or
+/// `DeniableError::Rsa` if the data key cannot be encrypted.
+pub fn seal_one(
+    public_key: &RsaPublicKey,
+    plaintext: &[u8],
+) -> DeniableResult<Vec<u8>> {
+    seal_slots([Some((public_key, plaintext)), None])
+}
+
+/// Decrypts the slot of `ciphertext` sealed for `private_key`, whether it is the decoy or the
+/// hidden one.
+///
+/// Both slots are always tried, so that the time taken does not reveal which one was opened.
+///
+/// # Errors
+///
+/// This function returns `DeniableError::Decryption` if no slot was sealed for `private_key` or
+/// if the ciphertext was tampered with, or `DeniableError::Malformed` or
+/// `DeniableError::UnsupportedVersion` if it cannot be parsed.
+pub fn open(
+    private_key: &RsaPrivateKey,
+    ciphertext: &[u8],
+) -> DeniableResult<Vec<u8>> {
+    let (header, slots) = parse(ciphertext, private_key.size())?;
+    let mut opened = None;
+    for slot in slots {
+        if let Some(plaintext) = open_slot(private_key, header, slot) {
+            opened.get_or_insert(plaintext);
+        }
+    }
+    opened.ok_or(DeniableError::Decryption)
+}
+
+/// Seals the slots in random order, each one for a key or, if `None`, filled with random bytes.
+fn seal_slots(
+    mut slots: [Option<(&RsaPublicKey, &[u8])>; 2],
+) -> DeniableResult<Vec<u8>> {
+    let longest = slots
+        .iter()
+        .flatten()
+        .map(|(_, plaintext)| plaintext.len())
+        .max()
+        .unwrap_or_default();
+    let padded_len = (longest + LENGTH_PREFIX_LEN)
+        .div_ceil(PADDING_BLOCK)
+        .saturating_mul(PADDING_BLOCK);
+    let mut header = vec![VERSION];
+    header.extend_from_slice(
+        &u32::try_from(padded_len)
+            .map_err(|_| DeniableError::TooLong)?
+            .to_be_bytes(),
+    );
+    if OsRng.next_u32() & 1 == 1 {
+        slots.swap(0, 1);
+    }
+    let key_len = slots
+        .iter()
+        .flatten()
+        .map(|(key, _)| key.size())
+        .next()
+        .expect("at least one slot is sealed");
+
+    let mut ciphertext = header.clone();
+    for slot in slots {
+        let (wrapped_key, nonce, encrypted) = match slot {
+            Some((public_key, plaintext)) => {
+                seal_slot(public_key, &header, plaintext, padded_len)?
+            }
+            None => {
+                let mut chaff =
+                    vec![0u8; key_len + NONCE_LEN + padded_len + TAG_LEN];
+                OsRng.fill_bytes(&mut chaff);
+                let rest = chaff.split_off(key_len);
+                let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+                (chaff, nonce.to_vec(), encrypted.to_vec())
+            }
+        };
+        ciphertext.extend_from_slice(&(wrapped_key.len() as u16).to_be_bytes());
+        ciphertext.extend_from_slice(&wrapped_key);
+        ciphertext.extend_from_slice(&nonce);
+        ciphertext.extend_from_slice(&encrypted);
+    }
+    Ok(ciphertext)
+}
+
+/// Seals `plaintext`, prefixed with its length and padded to `padded_len`, for `public_key`, and
+/// returns the wrapped key, nonce and ciphertext of the slot.
+fn seal_slot(
+    public_key: &RsaPublicKey,
+    header: &[u8],
+    plaintext: &[u8],
+    padded_len: usize,
+) -> DeniableResult<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+    let mut data_key = [0u8; DATA_KEY_LEN];
+    OsRng.fill_bytes(&mut data_key);
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let wrapped_key = core::encrypt_with(public_key, OaepParams::SHA256, &data_key)?;
+
+    let mut padded = (plaintext.len() as u32).to_be_bytes().to_vec();
+    padded.extend_from_slice(plaintext);
+    padded.resize(padded_len, 0);
+    let encrypted = Aes256Gcm::new(&data_key.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &padded,
+                aad: &associated_data(header, &wrapped_key),
+            },
+        )
+        .map_err(|_| DeniableError::Malformed)?;
+    Ok((wrapped_key, nonce.to_vec(), encrypted))
+}
+
+/// A slot of a parsed ciphertext.
+struct Slot<'a> {
+    wrapped_key: &'a [u8],
+    nonce: &'a [u8],
+    encrypted: &'a [u8],
+}
+
+/// Splits `ciphertext` into its header and slots, whose wrapped keys must be `key_len` bytes long.
+fn parse<'a>(
+    ciphertext: &'a [u8],
+    key_len: usize,
+) -> DeniableResult<(&'a [u8], [Slot<'a>; 2])> {
+    let (&version, _) = ciphertext.split_first().ok_or(DeniableError::Malformed)?;
+    if version != VERSION {
+        return Err(DeniableError::UnsupportedVersion(version));
+    }
+    let header = ciphertext
+        .get(..1 + LENGTH_PREFIX_LEN)
+        .ok_or(DeniableError::Malformed)?;
+    let padded_len =
+        u32::from_be_bytes(header[1..].try_into().expect("the length is 4 bytes"))
+            as usize;
+    let slot_len = 2 + key_len + NONCE_LEN + padded_len + TAG_LEN;
+    let body = &ciphertext[header.len()..];
+    if body.len() != 2 * slot_len {
+        return Err(DeniableError::Malformed);
+    }
+    let slot = |bytes: &'a [u8]| -> DeniableResult<Slot<'a>> {
+        let wrapped_key_len = u16::from_be_bytes([bytes[0], bytes[1]]) as usize;
+        if wrapped_key_len != key_len {
+            return Err(DeniableError::Malformed);
+        }
+        let (wrapped_key, rest) = bytes[2..].split_at(key_len);
+        let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+        Ok(Slot {
+            wrapped_key,
+            nonce,
+            encrypted,
+        })
+    };
+    let (first, second) = body.split_at(slot_len);
+    Ok((header, [slot(first)?, slot(second)?]))
+}
+
+fn open_slot(
+    private_key: &RsaPrivateKey,
+    header: &[u8],
+    slot: Slot<'_>,
+) -> Option<Vec<u8>> {
+    let data_key =
+        core::decrypt_with(private_key, OaepParams::SHA256, slot.wrapped_key)
+            .ok()?;
+    let padded = Aes256Gcm::new_from_slice(&data_key)
+        .ok()?
+        .decrypt(
+            Nonce::from_slice(slot.nonce),
+            Payload {
+                msg: slot.encrypted,
+                aad: &associated_data(header, slot.wrapped_key),
+            },
+        )
+        .ok()?;
+    let (len, rest) = padded.split_first_chunk::<LENGTH_PREFIX_LEN>()?;
+    rest.get(..u32::from_be_bytes(*len) as usize)
+        .map(<[u8]>::to_vec)
+}
+
+/// Binds each slot to the header and to its own wrapped key.
+fn associated_data(header: &[u8], wrapped_key: &[u8]) -> Vec<u8> {
+    [header, wrapped_key].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_each_key_opens_its_own_slot() {
+        let decoy_key = core::generate_private_key(1024).unwrap();
+        let hidden_key = core::generate_private_key(1024).unwrap();
+        let hidden = vec![0x42; 300];
+        let sealed = seal(
+            &decoy_key.to_public_key(),
+            b"decoy",
+            &hidden_key.to_public_key(),
+            &hidden,
+        )
+        .unwrap();
+        assert_eq!(open(&decoy_key, &sealed).unwrap(), b"decoy");
+        assert_eq!(open(&hidden_key, &sealed).unwrap(), hidden);
+        assert_eq!(sealed.len(), 5 + 2 * (2 + 128 + NONCE_LEN + 512 + TAG_LEN));
+
+        // A ciphertext with a single message has the same shape.
+        let single = seal_one(&decoy_key.to_public_key(), b"decoy").unwrap();
+        assert_eq!(open(&decoy_key, &single).unwrap(), b"decoy");
+        assert_eq!(single.len(), 5 + 2 * (2 + 128 + NONCE_LEN + 256 + TAG_LEN));
+        assert!(matches!(
+            open(&hidden_key, &single),
+            Err(DeniableError::Decryption)
+        ));
+    }
+
+    #[test]
+    fn test_open_rejects_tampering_and_mismatched_keys() {
+        let decoy_key = core::generate_private_key(1024).unwrap();
+        let hidden_key = core::generate_private_key(1024).unwrap();
+        let sealed = seal(
+            &decoy_key.to_public_key(),
+            b"decoy",
+            &hidden_key.to_public_key(),
+            b"hidden",
+        )
+        .unwrap();
+        for index in [4, sealed.len() / 2, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            let decoy = open(&decoy_key, &tampered).ok();
+            let hidden = open(&hidden_key, &tampered).ok();
+            // Tampering can only break the slot it touches, or both through the header.
+            assert!(decoy.is_none() || hidden.is_none());
+        }
+        assert!(matches!(
+            open(&decoy_key, &sealed[..sealed.len() - 1]),
+            Err(DeniableError::Malformed)
+        ));
+        let mut wrong_version = sealed.clone();
+        wrong_version[0] = 2;
+        assert!(matches!(
+            open(&decoy_key, &wrong_version),
+            Err(DeniableError::UnsupportedVersion(2))
+        ));
+
+        let larger_key = core::generate_private_key(1536).unwrap();
+        assert!(matches!(
+            seal(
+                &decoy_key.to_public_key(),
+                b"decoy",
+                &larger_key.to_public_key(),
+                b"hidden"
+            ),
+            Err(DeniableError::KeySizeMismatch)
+        ));
+    }
+}
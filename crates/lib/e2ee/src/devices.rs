@@ -0,0 +1,471 @@
+//! Identities owning several device keys.
+//!
+//! A user with a phone and a laptop keeps a private key on each device, and a long-term identity
+//! key that vouches for them. The identity key signs a [`DeviceList`] naming every device key;
+//! peers fetch the [`SignedDeviceList`], verify it against the identity key they already trust,
+//! and [`seal_for_devices`] encrypts each message into a single envelope that every device opens
+//! (see `envelope::seal_for_recipients`).
+//!
+//! Every change to the list increments its sequence number. [`SignedDeviceList::verify_update`]
+//! rejects lists older than the one a peer already knows, so that a removed (e.g. stolen) device
+//! cannot be brought back by replaying an old list, and [`DeviceList::added_since`] reports the
+//! new devices, e.g. to warn the user.
+//!
+//! The signed list is a JSON object, to be published next to the identity key:
+//!
+//! ```json
+//! {
+//!   "document": "{\"devices\":[{\"id\":\"phone\",\"key\":\"MIIB...\"}],\"identity\":\"3f9a...\",\"sequence\":1}",
+//!   "signature": "kX2v..."
+//! }
+//! ```
+//!
+//! Device keys are base64-encoded SPKI DER, and the identity is the key ID of the identity key
+//! (see `core::key_id`).
+//!
+//! This module is enabled by the default `io` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::devices::{self, DeviceList, SignedDeviceList};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let identity = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let phone = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let laptop = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//!
+//! let mut list = DeviceList::new(identity.get_public_key()).expect("Failed to create list");
+//! list.add_device("phone", phone.get_public_key().clone()).expect("Invalid device");
+//! list.add_device("laptop", laptop.get_public_key().clone()).expect("Invalid device");
+//! let published = list.sign(&identity).expect("Failed to sign list").to_json();
+//!
+//! // A peer verifies the list against the identity key, and encrypts for every device.
+//! let list = SignedDeviceList::from_json(&published)
+//!     .and_then(|signed| signed.verify(identity.get_public_key()))
+//!     .expect("Invalid device list");
+//! let envelope = devices::seal_for_devices(&list, b"Hello, all my devices!")
+//!     .expect("Failed to encrypt");
+//! assert_eq!(phone.decrypt_envelope(&envelope).unwrap(), b"Hello, all my devices!");
+//! assert_eq!(laptop.decrypt_envelope(&envelope).unwrap(), b"Hello, all my devices!");
+//! ```
+use crate::core;
+use crate::envelope::{self, EnvelopeError};
+use crate::server::{E2ee, E2eeError};
+use base64::{engine::general_purpose, Engine};
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde_json::{json, Value};
+use thiserror::Error;
+
+/// Separates the signatures of device lists from other signatures of the identity key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee-device-list-v1\0";
+
+pub type DevicesResult<T> = std::result::Result<T, DevicesError>;
+
+/// An error returned when managing, verifying or encrypting for device lists.
+#[derive(Error, Debug)]
+pub enum DevicesError {
+    #[error("Signing failed: {0}")]
+    Sign(#[from] E2eeError),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Malformed device list: {0}")]
+    Malformed(&'static str),
+
+    #[error("Invalid key for device {id:?}: {reason}")]
+    InvalidDeviceKey { id: String, reason: String },
+
+    #[error("Device {0:?} is already in the list")]
+    DuplicateDevice(String),
+
+    #[error("The device list belongs to identity {actual}, expected {expected}")]
+    WrongIdentity { expected: String, actual: String },
+
+    #[error("Invalid device list signature")]
+    InvalidSignature,
+
+    #[error("Device list {sequence} is not newer than the known list {known}")]
+    Rollback { known: u64, sequence: u64 },
+}
+
+/// A device key of an identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    /// The name of the device, unique within its list.
+    pub id: String,
+    /// The public key of the device.
+    pub public_key: RsaPublicKey,
+}
+
+/// The devices of an identity, with the sequence number of the list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceList {
+    identity: String,
+    sequence: u64,
+    devices: Vec<Device>,
+}
+
+impl DeviceList {
+    /// Creates an empty list for the identity key `identity`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be DER-encoded.
+    pub fn new(identity: &RsaPublicKey) -> DevicesResult<Self> {
+        Ok(Self {
+            identity: core::key_id(identity)?,
+            sequence: 0,
+            devices: Vec::new(),
+        })
+    }
+
+    /// Returns the key ID of the identity key owning the devices.
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Returns the sequence number of the list, incremented on every change.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the devices of the identity.
+    pub fn devices(&self) -> &[Device] {
+        &self.devices
+    }
+
+    /// Returns the public keys of the devices.
+    pub fn public_keys(&self) -> Vec<RsaPublicKey> {
+        self.devices
+            .iter()
+            .map(|device| device.public_key.clone())
+            .collect()
+    }
+
+    /// Adds the device `id` with `public_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DevicesError::DuplicateDevice` if a device is already named `id`,
+    /// or `DevicesError::InvalidDeviceKey` if the key is weak or malformed.
+    pub fn add_device(
+        &mut self,
+        id: impl Into<String>,
+        public_key: RsaPublicKey,
+    ) -> DevicesResult<()> {
+        let id = id.into();
+        if self.devices.iter().any(|device| device.id == id) {
+            return Err(DevicesError::DuplicateDevice(id));
+        }
+        core::validate_public_key(public_key.n(), public_key.e()).map_err(
+            |reason| DevicesError::InvalidDeviceKey {
+                id: id.clone(),
+                reason,
+            },
+        )?;
+        self.devices.push(Device { id, public_key });
+        self.sequence += 1;
+        Ok(())
+    }
+
+    /// Removes the device `id`, returning it if it was in the list.
+    pub fn remove_device(&mut self, id: &str) -> Option<Device> {
+        let index = self.devices.iter().position(|device| device.id == id)?;
+        self.sequence += 1;
+        Some(self.devices.remove(index))
+    }
+
+    /// Returns the devices of this list whose key is not in `known`, an earlier list.
+    pub fn added_since(&self, known: &DeviceList) -> Vec<&Device> {
+        self.devices
+            .iter()
+            .filter(|device| {
+                !known
+                    .devices
+                    .iter()
+                    .any(|known| known.public_key == device.public_key)
+            })
+            .collect()
+    }
+
+    /// Signs the list with the identity key of `identity`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DevicesError::WrongIdentity` if `identity` does not own the list, or
+    /// an error if signing fails.
+    pub fn sign(&self, identity: &E2ee) -> DevicesResult<SignedDeviceList> {
+        check_identity(&self.identity, identity.get_public_key())?;
+        let devices = self
+            .devices
+            .iter()
+            .map(|device| {
+                let der = device.public_key.to_public_key_der()?;
+                Ok(json!({
+                    "id": device.id,
+                    "key": general_purpose::STANDARD.encode(der.as_bytes()),
+                }))
+            })
+            .collect::<DevicesResult<Vec<_>>>()?;
+        let document = json!({
+            "identity": self.identity,
+            "sequence": self.sequence,
+            "devices": devices,
+        })
+        .to_string();
+        let signature =
+            identity.sign(&[SIGNATURE_CONTEXT, document.as_bytes()].concat())?;
+        Ok(SignedDeviceList {
+            document,
+            signature,
+        })
+    }
+}
+
+/// A device list signed by its identity key, as published.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedDeviceList {
+    document: String,
+    signature: Vec<u8>,
+}
+
+impl SignedDeviceList {
+    /// Encodes the signed list as a JSON object with `document` and `signature` fields.
+    pub fn to_json(&self) -> String {
+        json!({
+            "document": self.document,
+            "signature": general_purpose::STANDARD.encode(&self.signature),
+        })
+        .to_string()
+    }
+
+    /// Decodes a signed list encoded with [`SignedDeviceList::to_json`], without verifying it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the JSON is malformed.
+    pub fn from_json(json: &str) -> DevicesResult<Self> {
+        let value: Value = serde_json::from_str(json)?;
+        let document = value
+            .get("document")
+            .and_then(Value::as_str)
+            .ok_or(DevicesError::Malformed("missing document"))?;
+        let signature = value
+            .get("signature")
+            .and_then(Value::as_str)
+            .and_then(|signature| general_purpose::STANDARD.decode(signature).ok())
+            .ok_or(DevicesError::Malformed("missing or invalid signature"))?;
+        Ok(Self {
+            document: document.to_string(),
+            signature,
+        })
+    }
+
+    /// Verifies the signature of the list with the identity key `identity` and returns the list.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DevicesError::InvalidSignature` if the list was not signed by
+    /// `identity`, `DevicesError::WrongIdentity` if it belongs to another identity, or an error if
+    /// the list or one of its keys is malformed.
+    pub fn verify(&self, identity: &RsaPublicKey) -> DevicesResult<DeviceList> {
+        core::verify(
+            identity,
+            &[SIGNATURE_CONTEXT, self.document.as_bytes()].concat(),
+            &self.signature,
+        )
+        .map_err(|_| DevicesError::InvalidSignature)?;
+
+        let document: Value = serde_json::from_str(&self.document)?;
+        let owner = document
+            .get("identity")
+            .and_then(Value::as_str)
+            .ok_or(DevicesError::Malformed("missing identity"))?;
+        check_identity(owner, identity)?;
+        let sequence = document
+            .get("sequence")
+            .and_then(Value::as_u64)
+            .ok_or(DevicesError::Malformed("missing sequence"))?;
+        let entries = document
+            .get("devices")
+            .and_then(Value::as_array)
+            .ok_or(DevicesError::Malformed("missing devices"))?;
+
+        let mut list = DeviceList {
+            identity: owner.to_string(),
+            sequence: 0,
+            devices: Vec::with_capacity(entries.len()),
+        };
+        for entry in entries {
+            let id = entry
+                .get("id")
+                .and_then(Value::as_str)
+                .ok_or(DevicesError::Malformed("missing device id"))?;
+            let der = entry
+                .get("key")
+                .and_then(Value::as_str)
+                .and_then(|key| general_purpose::STANDARD.decode(key).ok())
+                .ok_or(DevicesError::Malformed("missing or invalid device key"))?;
+            let public_key = RsaPublicKey::from_public_key_der(&der)?;
+            list.add_device(id, public_key)?;
+        }
+        list.sequence = sequence;
+        Ok(list)
+    }
+
+    /// Verifies the list like [`SignedDeviceList::verify`], and checks that it is newer than
+    /// `known`, the last list verified for the same identity.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DevicesError::Rollback` if the list is not newer than `known`, or the
+    /// errors of [`SignedDeviceList::verify`].
+    pub fn verify_update(
+        &self,
+        identity: &RsaPublicKey,
+        known: &DeviceList,
+    ) -> DevicesResult<DeviceList> {
+        let list = self.verify(identity)?;
+        if list.sequence <= known.sequence {
+            return Err(DevicesError::Rollback {
+                known: known.sequence,
+                sequence: list.sequence,
+            });
+        }
+        Ok(list)
+    }
+}
+
+/// Encrypts `plaintext` into a single envelope that every device of `list` opens with
+/// `E2ee::decrypt_envelope`.
+///
+/// # Errors
+///
+/// This function returns `DevicesError::Envelope` if the list has no devices or more than 256, or
+/// if encryption fails.
+pub fn seal_for_devices(
+    list: &DeviceList,
+    plaintext: &[u8],
+) -> DevicesResult<Vec<u8>> {
+    Ok(envelope::seal_for_recipients(
+        &list.public_keys(),
+        plaintext,
+    )?)
+}
+
+fn check_identity(expected: &str, identity: &RsaPublicKey) -> DevicesResult<()> {
+    let actual = core::key_id(identity)?;
+    if actual != expected {
+        return Err(DevicesError::WrongIdentity {
+            expected: expected.to_string(),
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_device_list_roundtrip_and_fan_out() {
+        let identity = E2ee::new(KeySize::Bit1024).unwrap();
+        let phone = E2ee::new(KeySize::Bit1024).unwrap();
+        let laptop = E2ee::new(KeySize::Bit1024).unwrap();
+
+        let mut list = DeviceList::new(identity.get_public_key()).unwrap();
+        list.add_device("phone", phone.get_public_key().clone())
+            .unwrap();
+        assert!(matches!(
+            list.add_device("phone", laptop.get_public_key().clone()),
+            Err(DevicesError::DuplicateDevice(_))
+        ));
+        let known =
+            SignedDeviceList::from_json(&list.sign(&identity).unwrap().to_json())
+                .unwrap()
+                .verify(identity.get_public_key())
+                .unwrap();
+        assert_eq!(known, list);
+
+        list.add_device("laptop", laptop.get_public_key().clone())
+            .unwrap();
+        let signed = list.sign(&identity).unwrap();
+        let update = signed
+            .verify_update(identity.get_public_key(), &known)
+            .unwrap();
+        assert_eq!(update.sequence(), 2);
+        assert_eq!(
+            update
+                .added_since(&known)
+                .iter()
+                .map(|device| device.id.as_str())
+                .collect::<Vec<_>>(),
+            ["laptop"]
+        );
+
+        let envelope = seal_for_devices(&update, b"fan-out").unwrap();
+        for device in [&phone, &laptop] {
+            assert_eq!(device.decrypt_envelope(&envelope).unwrap(), b"fan-out");
+        }
+        assert!(matches!(
+            identity.decrypt_envelope(&envelope),
+            Err(E2eeError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_device_list_rejects_forgeries_and_rollbacks() {
+        let identity = E2ee::new(KeySize::Bit1024).unwrap();
+        let mallory = E2ee::new(KeySize::Bit1024).unwrap();
+        let phone = E2ee::new(KeySize::Bit1024).unwrap();
+
+        let mut list = DeviceList::new(identity.get_public_key()).unwrap();
+        list.add_device("phone", phone.get_public_key().clone())
+            .unwrap();
+        let old = list.sign(&identity).unwrap();
+        list.remove_device("phone").unwrap();
+        let current = list.sign(&identity).unwrap();
+        let known = current.verify(identity.get_public_key()).unwrap();
+        assert!(matches!(
+            old.verify_update(identity.get_public_key(), &known),
+            Err(DevicesError::Rollback {
+                known: 2,
+                sequence: 1
+            })
+        ));
+
+        assert!(matches!(
+            list.sign(&mallory),
+            Err(DevicesError::WrongIdentity { .. })
+        ));
+        let mut forged = current.clone();
+        forged.document =
+            forged.document.replace("\"sequence\":2", "\"sequence\":9");
+        assert!(matches!(
+            forged.verify(identity.get_public_key()),
+            Err(DevicesError::InvalidSignature)
+        ));
+        assert!(matches!(
+            current.verify(mallory.get_public_key()),
+            Err(DevicesError::InvalidSignature)
+        ));
+        assert!(matches!(
+            seal_for_devices(&known, b""),
+            Err(DevicesError::Envelope(EnvelopeError::RecipientCount(0)))
+        ));
+    }
+}
@@ -0,0 +1,440 @@
+//! Public key discovery through `.well-known` URLs.
+//!
+//! This module is enabled by the `discovery` feature. Federated deployments publish the public key
+//! of each identity, an address such as `alice@example.com`, at
+//!
+//! ```text
+//! https://example.com/.well-known/e2ee/<hash>.pem
+//! ```
+//!
+//! where `<hash>` is the lowercase hex SHA-256 digest of the lowercase identity, so that the URL
+//! does not list the addresses of the domain. [`well_known_url`] builds this URL, [`publish`]
+//! writes the document of a key under the web root of the domain, and [`KeyDiscovery::fetch`]
+//! downloads and checks it.
+//!
+//! The document holds the SPKI PEM public key followed by an `E2EE IDENTITY SIGNATURE` PEM block:
+//! a signature of the identity and the key by the key itself. It binds the key to the identity it
+//! was published for, so that a document copied to the URL of another identity is rejected. The
+//! authenticity of the domain itself rests on TLS: [`HttpsKeySource`] only fetches `https` URLs,
+//! checks certificates against the Mozilla roots bundled by `webpki-roots` and does not follow
+//! redirects.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use e2ee::discovery::{self, HttpsKeySource, KeyDiscovery};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! // On example.com, served as static files by the web server.
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! discovery::publish(&alice, "alice@example.com", "/var/www/example.com")
+//!     .expect("Failed to publish key");
+//!
+//! // On any other domain.
+//! let discovery = KeyDiscovery::new(HttpsKeySource::new());
+//! let public_key = discovery
+//!     .fetch("alice@example.com")
+//!     .expect("Failed to discover key");
+//! assert_eq!(&public_key, alice.get_public_key());
+//! ```
+use crate::core;
+use crate::server::{E2ee, E2eeError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey, LineEnding},
+    sha2::{Digest, Sha256},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+
+/// The directory of the published keys, relative to the web root of a domain.
+pub const WELL_KNOWN_DIR: &str = ".well-known/e2ee";
+
+/// The PEM label of the self-signature following the public key in published documents.
+pub const SIGNATURE_LABEL: &str = "E2EE IDENTITY SIGNATURE";
+
+/// Separates the self-signatures of published keys from other signatures of the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee well-known key v1\0";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_DOCUMENT_LEN: u64 = 64 << 10;
+
+pub type DiscoveryResult<T> = std::result::Result<T, DiscoveryError>;
+
+/// An error returned when publishing or discovering a public key.
+#[derive(Error, Debug)]
+pub enum DiscoveryError {
+    #[error("E2ee error: {0}")]
+    E2ee(#[from] E2eeError),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Transport error: {0}")]
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Invalid identity: {0}")]
+    InvalidIdentity(String),
+
+    #[error("Key discovery requires https, got {0}")]
+    InsecureUrl(String),
+
+    #[error("Malformed key document: {0}")]
+    Malformed(&'static str),
+
+    #[error("Invalid public key: {0}")]
+    InvalidKey(String),
+
+    #[error("The self-signature does not match the key and identity")]
+    InvalidSignature,
+}
+
+/// Fetches the documents of published keys.
+///
+/// It is implemented by [`HttpsKeySource`], and by closures for other transports.
+pub trait KeySource: Send + Sync {
+    /// Fetches the document at `url`.
+    fn fetch(&self, url: &str) -> DiscoveryResult<String>;
+}
+
+impl<F> KeySource for F
+where
+    F: Fn(&str) -> DiscoveryResult<String> + Send + Sync,
+{
+    fn fetch(&self, url: &str) -> DiscoveryResult<String> {
+        self(url)
+    }
+}
+
+/// Fetches documents over HTTPS, checking certificates against the Mozilla root certificates.
+#[derive(Debug, Clone)]
+pub struct HttpsKeySource {
+    agent: ureq::Agent,
+}
+
+impl Default for HttpsKeySource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HttpsKeySource {
+    /// Creates a source with a timeout of 30 seconds.
+    pub fn new() -> Self {
+        Self {
+            agent: agent(DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// Sets the timeout of each request, from connection to the end of the response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.agent = agent(timeout);
+        self
+    }
+}
+
+fn agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout(timeout)
+        .https_only(true)
+        .redirects(0)
+        .build()
+}
+
+impl KeySource for HttpsKeySource {
+    fn fetch(&self, url: &str) -> DiscoveryResult<String> {
+        if !url.starts_with("https://") {
+            return Err(DiscoveryError::InsecureUrl(url.to_string()));
+        }
+        let response = self
+            .agent
+            .get(url)
+            .set("Accept", "application/x-pem-file")
+            .call()
+            .map_err(|err| DiscoveryError::Transport(Box::new(err)))?;
+        let mut document = String::new();
+        response
+            .into_reader()
+            .take(MAX_DOCUMENT_LEN)
+            .read_to_string(&mut document)?;
+        Ok(document)
+    }
+}
+
+/// Discovers the public keys of identities from a [`KeySource`].
+pub struct KeyDiscovery {
+    source: Box<dyn KeySource>,
+}
+
+impl fmt::Debug for KeyDiscovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyDiscovery").finish_non_exhaustive()
+    }
+}
+
+impl KeyDiscovery {
+    /// Creates a discovery fetching documents from `source`.
+    pub fn new(source: impl KeySource + 'static) -> Self {
+        Self {
+            source: Box::new(source),
+        }
+    }
+
+    /// Fetches the public key published for `identity` at its [`well_known_url`], and checks its
+    /// self-signature.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DiscoveryError::InvalidIdentity` if `identity` is not an address,
+    /// an error if the document cannot be fetched, or an error from [`parse_document`].
+    pub fn fetch(&self, identity: &str) -> DiscoveryResult<RsaPublicKey> {
+        let document = self.source.fetch(&well_known_url(identity)?)?;
+        parse_document(identity, &document)
+    }
+}
+
+/// Returns the path of the document of `identity`, relative to the web root of its domain.
+///
+/// # Errors
+///
+/// This function returns `DiscoveryError::InvalidIdentity` if `identity` is not an address.
+pub fn well_known_path(identity: &str) -> DiscoveryResult<String> {
+    let (identity, _) = normalize(identity)?;
+    let hash = core::hex(&Sha256::digest(identity.as_bytes()));
+    Ok(format!("{WELL_KNOWN_DIR}/{hash}.pem"))
+}
+
+/// Returns the URL of the document of `identity`, on the domain of the address.
+///
+/// # Errors
+///
+/// This function returns `DiscoveryError::InvalidIdentity` if `identity` is not an address.
+pub fn well_known_url(identity: &str) -> DiscoveryResult<String> {
+    let (_, domain) = normalize(identity)?;
+    Ok(format!("https://{domain}/{}", well_known_path(identity)?))
+}
+
+/// Builds the document publishing the public key of `e2ee` for `identity`: the PEM public key and
+/// its self-signature.
+///
+/// # Errors
+///
+/// This function returns `DiscoveryError::InvalidIdentity` if `identity` is not an address, or an
+/// error if the key cannot be encoded or signing fails.
+pub fn document(e2ee: &E2ee, identity: &str) -> DiscoveryResult<String> {
+    let (identity, _) = normalize(identity)?;
+    let public_key = e2ee.get_public_key();
+    let signature = e2ee.sign(&signed_data(&identity, public_key)?)?;
+
+    let mut document = public_key.to_public_key_pem(LineEnding::LF)?;
+    document.push_str(&format!("-----BEGIN {SIGNATURE_LABEL}-----\n"));
+    let encoded = STANDARD.encode(signature);
+    for start in (0..encoded.len()).step_by(64) {
+        document.push_str(&encoded[start..encoded.len().min(start + 64)]);
+        document.push('\n');
+    }
+    document.push_str(&format!("-----END {SIGNATURE_LABEL}-----\n"));
+    Ok(document)
+}
+
+/// Writes the [`document`] of `identity` to its [`well_known_path`] under `web_root`, creating
+/// the directories as needed, and returns the path of the file.
+///
+/// # Errors
+///
+/// This function returns an error if the document cannot be built or written.
+pub fn publish(
+    e2ee: &E2ee,
+    identity: &str,
+    web_root: impl AsRef<Path>,
+) -> DiscoveryResult<PathBuf> {
+    let path = web_root.as_ref().join(well_known_path(identity)?);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, document(e2ee, identity)?)?;
+    Ok(path)
+}
+
+/// Parses the document published for `identity`, validates its public key and checks its
+/// self-signature.
+///
+/// # Errors
+///
+/// This function returns `DiscoveryError::Malformed` if the document does not hold a public key
+/// and a signature, `DiscoveryError::InvalidKey` if the key is too weak (see
+/// `core::validate_public_key`), or `DiscoveryError::InvalidSignature` if the signature does not
+/// match the key and `identity`.
+pub fn parse_document(
+    identity: &str,
+    document: &str,
+) -> DiscoveryResult<RsaPublicKey> {
+    let (identity, _) = normalize(identity)?;
+    let key_pem = pem_block(document, "PUBLIC KEY")
+        .ok_or(DiscoveryError::Malformed("missing public key"))?;
+    let signature = pem_block(document, SIGNATURE_LABEL)
+        .ok_or(DiscoveryError::Malformed("missing self-signature"))?;
+    let signature = STANDARD
+        .decode(pem_body(signature))
+        .map_err(|_| DiscoveryError::Malformed("invalid self-signature encoding"))?;
+
+    let public_key = RsaPublicKey::from_public_key_pem(key_pem)?;
+    core::validate_public_key(public_key.n(), public_key.e())
+        .map_err(DiscoveryError::InvalidKey)?;
+    core::verify(
+        &public_key,
+        &signed_data(&identity, &public_key)?,
+        &signature,
+    )
+    .map_err(|_| DiscoveryError::InvalidSignature)?;
+    Ok(public_key)
+}
+
+/// Lowercases `identity` and splits the domain out of it.
+fn normalize(identity: &str) -> DiscoveryResult<(String, String)> {
+    let identity = identity.trim().to_lowercase();
+    let invalid = || DiscoveryError::InvalidIdentity(identity.clone());
+    let (local, domain) = identity.rsplit_once('@').ok_or_else(invalid)?;
+    let valid_domain = domain.split('.').count() > 1
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if local.is_empty() || !valid_domain {
+        return Err(invalid());
+    }
+    let domain = domain.to_string();
+    Ok((identity, domain))
+}
+
+/// Returns the data signed by a published key: the context, the identity and the key.
+fn signed_data(
+    identity: &str,
+    public_key: &RsaPublicKey,
+) -> DiscoveryResult<Vec<u8>> {
+    let der = public_key.to_public_key_der()?;
+    Ok([
+        SIGNATURE_CONTEXT,
+        identity.as_bytes(),
+        b"\0",
+        der.as_bytes(),
+    ]
+    .concat())
+}
+
+/// Returns the PEM block of `document` labeled `label`, from its `BEGIN` to its `END` line.
+fn pem_block<'a>(document: &'a str, label: &str) -> Option<&'a str> {
+    let begin = format!("-----BEGIN {label}-----");
+    let end = format!("-----END {label}-----");
+    let start = document.find(&begin)?;
+    let len = document[start..].find(&end)? + end.len();
+    Some(&document[start..start + len])
+}
+
+/// Returns the base64 content of a PEM block, without its `BEGIN` and `END` lines.
+fn pem_body(block: &str) -> String {
+    block
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .map(str::trim)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_well_known_url() {
+        let url = well_known_url("Alice@Example.com").unwrap();
+        let hash = core::hex(&Sha256::digest(b"alice@example.com"));
+        assert_eq!(
+            url,
+            format!("https://example.com/.well-known/e2ee/{hash}.pem")
+        );
+        for identity in ["alice", "@example.com", "alice@localhost", "a@b/c.com"] {
+            assert!(matches!(
+                well_known_url(identity),
+                Err(DiscoveryError::InvalidIdentity(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_published_keys_are_discovered() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let web_root = std::env::temp_dir()
+            .join(format!("e2ee-discovery-{}", std::process::id()));
+        let path = publish(&alice, "alice@example.com", &web_root).unwrap();
+        assert_eq!(
+            path,
+            web_root.join(well_known_path("alice@example.com").unwrap())
+        );
+
+        let root = web_root.clone();
+        let discovery = KeyDiscovery::new(move |url: &str| {
+            let path = url
+                .strip_prefix("https://example.com/")
+                .ok_or(DiscoveryError::InsecureUrl(url.to_string()))?;
+            Ok(fs::read_to_string(root.join(path))?)
+        });
+        assert_eq!(
+            &discovery.fetch("ALICE@example.com").unwrap(),
+            alice.get_public_key()
+        );
+        assert!(matches!(
+            discovery.fetch("bob@example.com"),
+            Err(DiscoveryError::Io(_))
+        ));
+        fs::remove_dir_all(web_root).unwrap();
+    }
+
+    #[test]
+    fn test_documents_are_bound_to_their_identity() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let eve = E2ee::new(KeySize::Bit1024).unwrap();
+        let document = document(&alice, "alice@example.com").unwrap();
+        assert!(parse_document("alice@example.com", &document).is_ok());
+        assert!(matches!(
+            parse_document("bob@example.com", &document),
+            Err(DiscoveryError::InvalidSignature)
+        ));
+
+        // Eve swaps her key into Alice's document.
+        let eve_pem = eve
+            .get_public_key()
+            .to_public_key_pem(LineEnding::LF)
+            .unwrap();
+        let alice_pem = pem_block(&document, "PUBLIC KEY").unwrap();
+        let forged = document.replace(alice_pem, eve_pem.trim_end());
+        assert!(matches!(
+            parse_document("alice@example.com", &forged),
+            Err(DiscoveryError::InvalidSignature)
+        ));
+
+        let unsigned = alice_pem.to_string();
+        assert!(matches!(
+            parse_document("alice@example.com", &unsigned),
+            Err(DiscoveryError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_https_is_required() {
+        assert!(matches!(
+            HttpsKeySource::new().fetch("http://example.com/key.pem"),
+            Err(DiscoveryError::InsecureUrl(_))
+        ));
+    }
+}
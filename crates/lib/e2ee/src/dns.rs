@@ -0,0 +1,324 @@
+//! Public key fingerprints published in DNS.
+//!
+//! This module is enabled by the `dns` feature. A domain publishes the fingerprints of its public
+//! keys (see `core::fingerprint`) in TXT records, and peers check the keys they received through
+//! another channel, e.g. the `discovery` module, against them. An attacker
+//! then has to compromise both the web server and the DNS zone of the domain to substitute a key.
+//!
+//! The records of the key of a domain are at `_e2ee.<domain>`, and those of an identity such as
+//! `alice@example.com` at `<hash>._e2ee.example.com`, where `<hash>` is the first 32 hex digits of
+//! the SHA-256 digest of the lowercase identity. [`record`] returns their content:
+//!
+//! ```text
+//! v=e2ee1; fp=<fingerprint>
+//! ```
+//!
+//! Several records may be published, e.g. while rotating keys: a key is accepted if any of them
+//! matches. The fingerprint is the SHA-256 digest of the SPKI DER encoding, the same value as in a
+//! DANE `TLSA 3 1 1` record. DNS answers are only as trustworthy as the resolver: sign the zone with
+//! DNSSEC and query a validating resolver.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use e2ee::dns::{self, DnsVerifier, SystemResolver};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! // Published in the zone of example.com.
+//! println!(
+//!     "{} IN TXT \"{}\"",
+//!     dns::record_name("alice@example.com").unwrap(),
+//!     dns::record(alice.get_public_key()).unwrap()
+//! );
+//!
+//! let verifier = DnsVerifier::new(SystemResolver::new().expect("Failed to read resolv.conf"));
+//! verifier
+//!     .verify("alice@example.com", alice.get_public_key())
+//!     .expect("The key does not match DNS");
+//! ```
+use crate::core;
+use rsa::{
+    pkcs8::spki,
+    sha2::{Digest, Sha256},
+    RsaPublicKey,
+};
+use std::{fmt, io};
+use thiserror::Error;
+
+/// The label under which the records of a domain are published.
+pub const RECORD_LABEL: &str = "_e2ee";
+
+/// The version tag of the records.
+pub const RECORD_VERSION: &str = "e2ee1";
+
+/// The number of hex digits of the identity hash in record names, keeping them short enough for a
+/// DNS label.
+const IDENTITY_HASH_LEN: usize = 32;
+
+pub type DnsResult<T> = std::result::Result<T, DnsError>;
+
+/// An error returned when looking up or checking key fingerprints in DNS.
+#[derive(Error, Debug)]
+pub enum DnsError {
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("DNS lookup failed: {0}")]
+    Lookup(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Invalid name: {0}")]
+    InvalidName(String),
+
+    #[error("No e2ee record at {0}")]
+    NoRecord(String),
+
+    #[error("The key fingerprint {fingerprint} is not published at {name}")]
+    FingerprintMismatch { name: String, fingerprint: String },
+}
+
+/// Looks up the TXT records of a name.
+///
+/// It is implemented by [`SystemResolver`], and by closures for other resolvers.
+pub trait TxtResolver: Send + Sync {
+    /// Returns the TXT records of `name`, each with its strings concatenated, or no records if the
+    /// name does not exist.
+    fn lookup_txt(&self, name: &str) -> DnsResult<Vec<String>>;
+}
+
+impl<F> TxtResolver for F
+where
+    F: Fn(&str) -> DnsResult<Vec<String>> + Send + Sync,
+{
+    fn lookup_txt(&self, name: &str) -> DnsResult<Vec<String>> {
+        self(name)
+    }
+}
+
+/// Resolves names with the resolvers configured in `/etc/resolv.conf`, or the system settings
+/// on Windows.
+pub struct SystemResolver {
+    resolver: hickory_resolver::Resolver,
+}
+
+impl fmt::Debug for SystemResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SystemResolver").finish_non_exhaustive()
+    }
+}
+
+impl SystemResolver {
+    /// Creates a resolver from the system configuration.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the system configuration cannot be read.
+    pub fn new() -> DnsResult<Self> {
+        Ok(Self {
+            resolver: hickory_resolver::Resolver::from_system_conf()?,
+        })
+    }
+}
+
+impl TxtResolver for SystemResolver {
+    fn lookup_txt(&self, name: &str) -> DnsResult<Vec<String>> {
+        // A trailing dot keeps the name from being completed with the search domains.
+        match self.resolver.txt_lookup(format!("{name}.")) {
+            Ok(lookup) => Ok(lookup.iter().map(|txt| txt.to_string()).collect()),
+            Err(err) => match err.kind() {
+                hickory_resolver::error::ResolveErrorKind::NoRecordsFound {
+                    ..
+                } => Ok(Vec::new()),
+                _ => Err(DnsError::Lookup(Box::new(err))),
+            },
+        }
+    }
+}
+
+/// Checks public keys against the fingerprints published in DNS, through a [`TxtResolver`].
+pub struct DnsVerifier {
+    resolver: Box<dyn TxtResolver>,
+}
+
+impl fmt::Debug for DnsVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DnsVerifier").finish_non_exhaustive()
+    }
+}
+
+impl DnsVerifier {
+    /// Creates a verifier looking records up with `resolver`.
+    pub fn new(resolver: impl TxtResolver + 'static) -> Self {
+        Self {
+            resolver: Box::new(resolver),
+        }
+    }
+
+    /// Returns the fingerprints published for `name`, a domain or an identity.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DnsError::InvalidName` if `name` is not a domain or an address, or
+    /// an error if the lookup fails. Records without the `e2ee1` version tag are ignored.
+    pub fn fingerprints(&self, name: &str) -> DnsResult<Vec<String>> {
+        Ok(self
+            .resolver
+            .lookup_txt(&record_name(name)?)?
+            .iter()
+            .filter_map(|txt| parse_record(txt))
+            .collect())
+    }
+
+    /// Checks that the fingerprint of `public_key` is published for `name`, a domain or an
+    /// identity.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `DnsError::NoRecord` if no fingerprint is published for `name`,
+    /// `DnsError::FingerprintMismatch` if none matches the key, or an error if the lookup fails.
+    pub fn verify(&self, name: &str, public_key: &RsaPublicKey) -> DnsResult<()> {
+        let fingerprints = self.fingerprints(name)?;
+        if fingerprints.is_empty() {
+            return Err(DnsError::NoRecord(record_name(name)?));
+        }
+        let fingerprint = core::fingerprint(public_key)?;
+        if !fingerprints.contains(&fingerprint) {
+            return Err(DnsError::FingerprintMismatch {
+                name: record_name(name)?,
+                fingerprint,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the records of `name`: `_e2ee.<domain>` for a domain, or
+/// `<hash>._e2ee.<domain>` for an identity `local@domain`.
+///
+/// # Errors
+///
+/// This function returns `DnsError::InvalidName` if `name` is not a domain or an address.
+pub fn record_name(name: &str) -> DnsResult<String> {
+    let name = name.trim().trim_end_matches('.').to_lowercase();
+    let invalid = || DnsError::InvalidName(name.clone());
+    let (local, domain) = match name.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() => (Some(local), domain),
+        Some(_) => return Err(invalid()),
+        None => (None, name.as_str()),
+    };
+    let valid_domain = domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if !valid_domain {
+        return Err(invalid());
+    }
+    Ok(match local {
+        Some(_) => {
+            let mut hash = core::hex(&Sha256::digest(name.as_bytes()));
+            hash.truncate(IDENTITY_HASH_LEN);
+            format!("{hash}.{RECORD_LABEL}.{domain}")
+        }
+        None => format!("{RECORD_LABEL}.{domain}"),
+    })
+}
+
+/// Returns the content of the TXT record publishing `public_key`.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn record(public_key: &RsaPublicKey) -> DnsResult<String> {
+    Ok(format!(
+        "v={RECORD_VERSION}; fp={}",
+        core::fingerprint(public_key)?
+    ))
+}
+
+/// Returns the lowercase fingerprint of a TXT record, or `None` if it is not an `e2ee1` record.
+pub fn parse_record(txt: &str) -> Option<String> {
+    let mut version = None;
+    let mut fingerprint = None;
+    for field in txt.split(';') {
+        match field.trim().split_once('=') {
+            Some(("v", value)) => version = Some(value.trim()),
+            Some(("fp", value)) => fingerprint = Some(value.trim().to_lowercase()),
+            _ => {}
+        }
+    }
+    let fingerprint = fingerprint
+        .filter(|fp| fp.len() == 64 && fp.chars().all(|c| c.is_ascii_hexdigit()))?;
+    (version == Some(RECORD_VERSION)).then_some(fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{E2ee, KeySize};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_record_names() {
+        assert_eq!(record_name("Example.com.").unwrap(), "_e2ee.example.com");
+        let name = record_name("Alice@example.com").unwrap();
+        let (hash, domain) = name.split_once('.').unwrap();
+        assert_eq!(hash.len(), IDENTITY_HASH_LEN);
+        assert_eq!(domain, "_e2ee.example.com");
+        assert_eq!(name, record_name("alice@EXAMPLE.com").unwrap());
+        for name in ["localhost", "@example.com", "a b.com", "example..com"] {
+            assert!(matches!(record_name(name), Err(DnsError::InvalidName(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let fp = "ab".repeat(32);
+        assert_eq!(parse_record(&format!("v=e2ee1; fp={fp}")), Some(fp.clone()));
+        assert_eq!(
+            parse_record(&format!("fp={}; v=e2ee1", fp.to_uppercase())),
+            Some(fp.clone())
+        );
+        assert_eq!(parse_record(&format!("v=e2ee2; fp={fp}")), None);
+        assert_eq!(parse_record("v=e2ee1; fp=abcd"), None);
+        assert_eq!(parse_record("v=spf1 -all"), None);
+    }
+
+    #[test]
+    fn test_keys_are_checked_against_dns() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let old = E2ee::new(KeySize::Bit1024).unwrap();
+        let eve = E2ee::new(KeySize::Bit1024).unwrap();
+        let zone = HashMap::from([(
+            record_name("alice@example.com").unwrap(),
+            vec![
+                "v=spf1 -all".to_string(),
+                record(old.get_public_key()).unwrap(),
+                record(alice.get_public_key()).unwrap(),
+            ],
+        )]);
+        let verifier = DnsVerifier::new(move |name: &str| {
+            Ok(zone.get(name).cloned().unwrap_or_default())
+        });
+
+        assert_eq!(verifier.fingerprints("alice@example.com").unwrap().len(), 2);
+        verifier
+            .verify("alice@example.com", alice.get_public_key())
+            .unwrap();
+        verifier
+            .verify("alice@example.com", old.get_public_key())
+            .unwrap();
+        assert!(matches!(
+            verifier.verify("alice@example.com", eve.get_public_key()),
+            Err(DnsError::FingerprintMismatch { .. })
+        ));
+        assert!(matches!(
+            verifier.verify("bob@example.com", alice.get_public_key()),
+            Err(DnsError::NoRecord(_))
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+//! Encryption of the values of environment files.
+//!
+//! [`encrypt`] replaces the value of every variable of a `.env` file with an `ENC[...]` marker
+//! holding an envelope (see the `envelope` module) for one or more recipients, and [`decrypt`]
+//! restores them. Comments, blank lines and variable names stay readable, so that encrypted
+//! environment files can be committed, reviewed and diffed:
+//!
+//! ```text
+//! # Database
+//! DATABASE_URL=ENC[AQAB...]
+//! export API_TOKEN=ENC[AQAB...]
+//! ```
+//!
+//! Values already in an `ENC[...]` marker are left untouched by [`encrypt`], so that only the
+//! variables added or changed since the last encryption show up in diffs. Each envelope is bound
+//! to the name of its variable: moving an encrypted value to another variable makes [`decrypt`]
+//! fail. Empty values are not encrypted.
+//!
+//! Values are encrypted as written, quotes and trailing comments included, one variable per line.
+//! [`parse`] reads the variables of a decrypted file, removing quotes and comments, to pass them
+//! to a process.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::{core, dotenv};
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let public_key = private_key.to_public_key();
+//!
+//! let contents = "# Database\nDATABASE_URL=postgres://app:hunter2@db/app\n";
+//! let encrypted = dotenv::encrypt(contents, &[public_key]).expect("Failed to encrypt");
+//! assert!(encrypted.starts_with("# Database\nDATABASE_URL=ENC["));
+//!
+//! let decrypted = dotenv::decrypt(&encrypted, &private_key).expect("Failed to decrypt");
+//! assert_eq!(decrypted, contents);
+//! ```
+use crate::{
+    envelope::{self, EnvelopeError},
+    interop::Encoding,
+};
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+pub type DotenvResult<T> = std::result::Result<T, DotenvError>;
+
+/// An error returned when encrypting or decrypting an environment file.
+#[derive(Error, Debug)]
+pub enum DotenvError {
+    #[error("Line {line}: {source}")]
+    Envelope {
+        line: usize,
+        #[source]
+        source: EnvelopeError,
+    },
+
+    #[error("Line {0} is not a variable assignment")]
+    Malformed(usize),
+
+    #[error("Line {0} has an invalid ENC[...] value")]
+    InvalidValue(usize),
+
+    #[error(
+        "The value of {name} on line {line} was encrypted for another variable"
+    )]
+    NameMismatch { line: usize, name: String },
+}
+
+/// The start of an encrypted value.
+pub const MARKER_PREFIX: &str = "ENC[";
+
+/// The end of an encrypted value.
+pub const MARKER_SUFFIX: &str = "]";
+
+/// Encrypts the values of the environment file `contents` for `recipients`, leaving names,
+/// comments, blank lines and already encrypted values untouched.
+///
+/// # Errors
+///
+/// This function returns an error if a line is neither a comment nor a variable assignment, or if
+/// a value cannot be encrypted for `recipients`.
+pub fn encrypt(contents: &str, recipients: &[RsaPublicKey]) -> DotenvResult<String> {
+    map_values(contents, |line, name, value| {
+        if value.is_empty() || is_encrypted(value) {
+            return Ok(None);
+        }
+        let envelope = envelope::seal_for_recipients(
+            recipients,
+            bound_value(name, value).as_bytes(),
+        )
+        .map_err(|source| DotenvError::Envelope { line, source })?;
+        Ok(Some(format!(
+            "{MARKER_PREFIX}{}{MARKER_SUFFIX}",
+            Encoding::default().encode(&envelope)
+        )))
+    })
+}
+
+/// Decrypts the `ENC[...]` values of the environment file `contents` with `private_key`, leaving
+/// the other lines untouched.
+///
+/// # Errors
+///
+/// This function returns an error if a line is neither a comment nor a variable assignment, if an
+/// encrypted value is malformed or cannot be opened with `private_key`, or if it was encrypted for
+/// another variable.
+pub fn decrypt(contents: &str, private_key: &RsaPrivateKey) -> DotenvResult<String> {
+    map_values(contents, |line, name, value| {
+        let Some(encoded) = value
+            .strip_prefix(MARKER_PREFIX)
+            .and_then(|value| value.strip_suffix(MARKER_SUFFIX))
+        else {
+            return Ok(None);
+        };
+        let envelope = Encoding::default()
+            .decode(encoded)
+            .map_err(|_| DotenvError::InvalidValue(line))?;
+        let plaintext = envelope::open(private_key, &envelope)
+            .map_err(|source| DotenvError::Envelope { line, source })?;
+        let plaintext = String::from_utf8(plaintext)
+            .map_err(|_| DotenvError::InvalidValue(line))?;
+        plaintext
+            .strip_prefix(&bound_value(name, ""))
+            .map(|value| Some(value.to_owned()))
+            .ok_or_else(|| DotenvError::NameMismatch {
+                line,
+                name: name.to_owned(),
+            })
+    })
+}
+
+/// Parses the variables of the decrypted environment file `contents` into their names and
+/// unquoted values, to pass them to a process.
+///
+/// # Errors
+///
+/// This function returns an error if a line is neither a comment nor a variable assignment, if a
+/// quoted value is not terminated, or if a value is still encrypted.
+pub fn parse(contents: &str) -> DotenvResult<Vec<(String, Zeroizing<String>)>> {
+    let mut variables = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        if let Some((_, name, value)) = split_assignment(index + 1, line)? {
+            if is_encrypted(value) {
+                return Err(DotenvError::InvalidValue(index + 1));
+            }
+            variables.push((name.to_owned(), unquote(index + 1, value)?));
+        }
+    }
+    Ok(variables)
+}
+
+/// Returns whether `value` is an `ENC[...]` marker.
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(MARKER_PREFIX) && value.ends_with(MARKER_SUFFIX)
+}
+
+/// Returns the plaintext sealed for `value`, bound to the variable `name`.
+fn bound_value(name: &str, value: &str) -> String {
+    format!("{name}={value}")
+}
+
+/// Rewrites the file by passing the line number, name and value of every variable to `f`, which
+/// returns the new value, or `None` to keep the line as is.
+fn map_values<F>(contents: &str, mut f: F) -> DotenvResult<String>
+where
+    F: FnMut(usize, &str, &str) -> DotenvResult<Option<String>>,
+{
+    let mut output = String::with_capacity(contents.len());
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        let content = line.trim_end_matches(['\r', '\n']);
+        let Some((assignment, name, value)) = split_assignment(index + 1, content)?
+        else {
+            output.push_str(line);
+            continue;
+        };
+        match f(index + 1, name, value)? {
+            Some(value) => {
+                output.push_str(assignment);
+                output.push('=');
+                output.push_str(&value);
+                output.push_str(&line[content.len()..]);
+            }
+            None => output.push_str(line),
+        }
+    }
+    Ok(output)
+}
+
+/// Splits `content`, the text of line `line`, into the text before `=`, the variable name and the
+/// trimmed value, or returns `None` for comments and blank lines.
+fn split_assignment(
+    line: usize,
+    content: &str,
+) -> DotenvResult<Option<(&str, &str, &str)>> {
+    let trimmed = content.trim_start();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Ok(None);
+    }
+    let (assignment, value) = content
+        .split_once('=')
+        .ok_or(DotenvError::Malformed(line))?;
+    let name = assignment.trim();
+    let name = name.strip_prefix("export ").unwrap_or(name).trim_start();
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'))
+    {
+        return Err(DotenvError::Malformed(line));
+    }
+    Ok(Some((assignment, name, value.trim())))
+}
+
+/// Unquotes a value: single-quoted values are taken literally, double-quoted values may hold
+/// `\n`, `\r`, `\t`, `\"` and `\\` escapes, and unquoted values end at a ` #` comment.
+fn unquote(line: usize, value: &str) -> DotenvResult<Zeroizing<String>> {
+    let mut unquoted = Zeroizing::new(String::with_capacity(value.len()));
+    if let Some(quoted) = value.strip_prefix('\'') {
+        let (quoted, _) = quoted
+            .split_once('\'')
+            .ok_or(DotenvError::Malformed(line))?;
+        unquoted.push_str(quoted);
+    } else if let Some(quoted) = value.strip_prefix('"') {
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next().ok_or(DotenvError::Malformed(line))? {
+                '"' => break,
+                '\\' => unquoted.push(match chars.next() {
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('t') => '\t',
+                    Some(c @ ('"' | '\\')) => c,
+                    _ => return Err(DotenvError::Malformed(line)),
+                }),
+                c => unquoted.push(c),
+            }
+        }
+    } else {
+        let end = value.find(" #").unwrap_or(value.len());
+        unquoted.push_str(value[..end].trim_end());
+    }
+    Ok(unquoted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+
+    #[test]
+    fn test_encrypt_values_only() {
+        let private_key = core::generate_private_key(2048).unwrap();
+        let recipients = [private_key.to_public_key()];
+        let contents = "# Comment\r\n\nexport API_TOKEN=\"s3cr=t\" # rotated\nEMPTY=\nPORT=8080";
+
+        let encrypted = encrypt(contents, &recipients).unwrap();
+        let lines: Vec<_> = encrypted.split('\n').collect();
+        assert_eq!(lines[..2], ["# Comment\r", ""]);
+        assert!(lines[2].starts_with("export API_TOKEN=ENC["));
+        assert!(!encrypted.contains("s3cr=t"));
+        assert_eq!(lines[3], "EMPTY=");
+        assert!(lines[4].starts_with("PORT=ENC[") && lines[4].ends_with(']'));
+        assert_eq!(decrypt(&encrypted, &private_key).unwrap(), contents);
+
+        // Encrypted values are kept, so that only new values change.
+        let added = format!("{encrypted}\nDEBUG=1\n");
+        let reencrypted = encrypt(&added, &recipients).unwrap();
+        assert!(reencrypted.starts_with(&encrypted));
+        assert!(decrypt(&reencrypted, &private_key)
+            .unwrap()
+            .ends_with("PORT=8080\nDEBUG=1\n"));
+    }
+
+    #[test]
+    fn test_decrypt_rejects_moved_values() {
+        let private_key = core::generate_private_key(2048).unwrap();
+        let encrypted =
+            encrypt("PASSWORD=hunter2\n", &[private_key.to_public_key()]).unwrap();
+        let moved = encrypted.replace("PASSWORD=", "USERNAME=");
+        assert!(matches!(
+            decrypt(&moved, &private_key),
+            Err(DotenvError::NameMismatch { line: 1, .. })
+        ));
+        assert!(matches!(
+            decrypt("TOKEN=ENC[not base64!]\n", &private_key),
+            Err(DotenvError::InvalidValue(1))
+        ));
+        assert!(matches!(
+            encrypt("not an assignment\n", &[]),
+            Err(DotenvError::Malformed(1))
+        ));
+    }
+
+    #[test]
+    fn test_parse_unquotes_values() {
+        let contents = "# Comment\nA=plain # note\nexport B='sin\"gle'\nC=\"li\\ne\\\"s\" # x\nD=\n";
+        let variables = parse(contents).unwrap();
+        let variables: Vec<_> = variables
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        assert_eq!(
+            variables,
+            [
+                ("A", "plain"),
+                ("B", "sin\"gle"),
+                ("C", "li\ne\"s"),
+                ("D", "")
+            ]
+        );
+        assert!(matches!(
+            parse("A=\"unterminated\n"),
+            Err(DotenvError::Malformed(1))
+        ));
+        assert!(matches!(
+            parse("A=ENC[AAAA]"),
+            Err(DotenvError::InvalidValue(1))
+        ));
+    }
+}
@@ -0,0 +1,720 @@
+//! S/MIME encryption of emails.
+//!
+//! This module is enabled by the `email` feature. [`encrypt`] takes an RFC 5322 message and
+//! replaces its body with an `application/pkcs7-mime` entity
+//! ([RFC 8551](https://www.rfc-editor.org/rfc/rfc8551)), a CMS `EnvelopedData` structure that only
+//! the holders of the recipient [`Certificate`]s can open, so that mail clients such as Outlook or
+//! Thunderbird decrypt it natively. [`decrypt`] opens inbound encrypted emails.
+//!
+//! The header fields describing the content (`Content-*` and `MIME-Version`) are encrypted with the
+//! body, while the other fields (`From`, `To`, `Subject`, ...) stay in the clear, as mail servers
+//! need them to route the message: the subject is not protected.
+//!
+//! Outgoing emails encrypt the content with AES-256-CBC, and its key with RSAES-OAEP using SHA-256
+//! for every recipient. Inbound emails may also use AES-128-CBC or AES-192-CBC and the other OAEP
+//! hashes, but the PKCS #1 v1.5 key transport of older clients is rejected, being open to padding
+//! oracle attacks. The CMS structures must be DER-encoded (see the `timestamp` module, which
+//! shares them).
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::email::{self, Certificate};
+//! use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
+//!
+//! let certificate = Certificate::from_pem(include_str!("../files/smime/certificate.pem"))
+//!     .expect("Invalid certificate");
+//! let message = b"From: bob@example.com\r\n\
+//!     To: alice@example.com\r\n\
+//!     Subject: Quarterly results\r\n\
+//!     Content-Type: text/plain; charset=utf-8\r\n\
+//!     \r\n\
+//!     The numbers are in.\r\n";
+//! let encrypted = email::encrypt(message, std::slice::from_ref(&certificate)).expect("Failed to encrypt");
+//! // Decrypt with: openssl cms -decrypt -in email.eml -recip certificate.pem -inkey private.pem
+//!
+//! let private_key = RsaPrivateKey::from_pkcs8_pem(include_str!("../files/smime/private.pem"))
+//!     .expect("Invalid private key");
+//! let decrypted = email::decrypt(&encrypted, &certificate, &private_key).expect("Failed to decrypt");
+//! assert_eq!(decrypted, message);
+//! ```
+use crate::cms::{
+    self, ContentInfo, EncryptedContentInfo, EnvelopedData, IssuerAndSerialNumber,
+    KeyTransRecipientInfo, ID_DATA, ID_ENVELOPED_DATA, RSA_ENCRYPTION,
+};
+use crate::core::{self, OaepHash, OaepParams};
+use aes::{Aes128, Aes192, Aes256};
+use base64::{engine::general_purpose, Engine};
+use cbc::cipher::{
+    block_padding::Pkcs7, BlockCipher, BlockDecrypt, BlockDecryptMut,
+    BlockEncryptMut, KeyInit, KeyIvInit,
+};
+use der::{
+    asn1::{BitString, ObjectIdentifier, OctetString},
+    Any, Decode, Encode, Sequence, Tag, Tagged,
+};
+use rsa::{
+    pkcs8::{spki::AlgorithmIdentifierOwned, DecodePublicKey},
+    rand_core::{OsRng, RngCore},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The media type of S/MIME encrypted entities.
+pub const CONTENT_TYPE: &str = "application/pkcs7-mime";
+
+/// The length of the lines of the base64-encoded entity.
+const LINE_LEN: usize = 76;
+
+const ID_RSAES_OAEP: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.7");
+const ID_MGF1: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.8");
+const ID_P_SPECIFIED: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.9");
+const ID_AES128_CBC: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.2");
+const ID_AES192_CBC: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.22");
+const ID_AES256_CBC: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.1.42");
+
+/// The OAEP hashes, with the object identifiers of their digests.
+const HASHES: [(OaepHash, ObjectIdentifier); 4] = [
+    (
+        OaepHash::Sha1,
+        ObjectIdentifier::new_unwrap("1.3.14.3.2.26"),
+    ),
+    (
+        OaepHash::Sha256,
+        ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1"),
+    ),
+    (
+        OaepHash::Sha384,
+        ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.2"),
+    ),
+    (
+        OaepHash::Sha512,
+        ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.3"),
+    ),
+];
+
+pub type EmailResult<T> = std::result::Result<T, EmailError>;
+
+/// An error returned when encrypting or decrypting emails.
+#[derive(Error, Debug)]
+pub enum EmailError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("Malformed CMS structure: {0}")]
+    Asn1(#[from] der::Error),
+
+    #[error("Invalid certificate: {0}")]
+    InvalidCertificate(String),
+
+    #[error("Malformed email: {0}")]
+    Malformed(&'static str),
+
+    #[error("The email is not encrypted with S/MIME")]
+    NotEncrypted,
+
+    #[error("At least one recipient is required")]
+    NoRecipients,
+
+    #[error("The email is not encrypted for the certificate")]
+    NotARecipient,
+
+    #[error("Unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Failed to decrypt the email")]
+    Decryption,
+}
+
+/// The X.509 certificate of a recipient, holding an RSA public key. Only the fields needed to
+/// address the recipient are read: the certificate is not verified, which is left to the caller
+/// (e.g. against a corporate directory).
+#[derive(Debug, Clone)]
+pub struct Certificate {
+    der: Vec<u8>,
+    issuer_and_serial_number: IssuerAndSerialNumber,
+    public_key: RsaPublicKey,
+}
+
+impl Certificate {
+    /// Parses a DER-encoded certificate.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `EmailError::InvalidCertificate` if the certificate is malformed or
+    /// its key is not a valid RSA public key.
+    pub fn from_der(der: &[u8]) -> EmailResult<Self> {
+        let invalid = |err: &dyn std::fmt::Display| {
+            EmailError::InvalidCertificate(err.to_string())
+        };
+        let certificate =
+            X509Certificate::from_der(der).map_err(|err| invalid(&err))?;
+        let tbs = certificate.tbs_certificate;
+        let spki = tbs.subject_public_key_info.to_der()?;
+        let public_key =
+            RsaPublicKey::from_public_key_der(&spki).map_err(|err| invalid(&err))?;
+        core::validate_public_key(public_key.n(), public_key.e())
+            .map_err(|reason| invalid(&reason))?;
+        Ok(Self {
+            der: der.to_vec(),
+            issuer_and_serial_number: IssuerAndSerialNumber {
+                issuer: tbs.issuer,
+                serial_number: tbs.serial_number,
+            },
+            public_key,
+        })
+    }
+
+    /// Parses a PEM-encoded certificate (`-----BEGIN CERTIFICATE-----`).
+    ///
+    /// # Errors
+    ///
+    /// This function returns `EmailError::InvalidCertificate` if the PEM encoding or the
+    /// certificate is invalid.
+    pub fn from_pem(pem: &str) -> EmailResult<Self> {
+        let (label, der) = der::pem::decode_vec(pem.trim().as_bytes())
+            .map_err(|err| EmailError::InvalidCertificate(err.to_string()))?;
+        if label != "CERTIFICATE" {
+            return Err(EmailError::InvalidCertificate(format!(
+                "unexpected PEM label {label}"
+            )));
+        }
+        Self::from_der(&der)
+    }
+
+    /// Returns the DER encoding of the certificate.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Returns the public key of the recipient.
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+}
+
+/// Encrypts the RFC 5322 `message` for `recipients`, and returns the encrypted email. Line endings
+/// are normalized to CRLF.
+///
+/// # Errors
+///
+/// This function returns `EmailError::NoRecipients` if `recipients` is empty,
+/// `EmailError::Malformed` if the header of the message is invalid, or an error if encryption
+/// fails.
+pub fn encrypt(message: &[u8], recipients: &[Certificate]) -> EmailResult<Vec<u8>> {
+    if recipients.is_empty() {
+        return Err(EmailError::NoRecipients);
+    }
+    let (fields, body) = split_message(message)?;
+    let (content_fields, outer_fields): (Vec<_>, Vec<_>) = fields
+        .into_iter()
+        .partition(|field| is_content_field(field));
+    let mut entity = Vec::new();
+    for field in content_fields {
+        entity.extend_from_slice(&to_crlf(field));
+    }
+    entity.extend_from_slice(b"\r\n");
+    entity.extend_from_slice(&to_crlf(body));
+
+    let encoded = general_purpose::STANDARD.encode(envelop(&entity, recipients)?);
+    let mut email = Vec::new();
+    for field in outer_fields {
+        email.extend_from_slice(&to_crlf(field));
+    }
+    email.extend_from_slice(
+        format!(
+            "MIME-Version: 1.0\r\n\
+             Content-Type: {CONTENT_TYPE}; smime-type=enveloped-data; name=\"smime.p7m\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             Content-Disposition: attachment; filename=\"smime.p7m\"\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    for line in encoded.as_bytes().chunks(LINE_LEN) {
+        email.extend_from_slice(line);
+        email.extend_from_slice(b"\r\n");
+    }
+    Ok(email)
+}
+
+/// Decrypts an S/MIME encrypted `email` addressed to `certificate`, whose private key is
+/// `private_key`, and returns the original message: the header fields in the clear followed by the
+/// decrypted entity.
+///
+/// # Errors
+///
+/// This function returns `EmailError::NotEncrypted` if the email is not an S/MIME encrypted email,
+/// `EmailError::NotARecipient` if it is not encrypted for `certificate`,
+/// `EmailError::UnsupportedAlgorithm` if it uses an unsupported algorithm, or
+/// `EmailError::Decryption` if the key does not match or the content was tampered with.
+pub fn decrypt(
+    email: &[u8],
+    certificate: &Certificate,
+    private_key: &RsaPrivateKey,
+) -> EmailResult<Vec<u8>> {
+    let (fields, body) = split_message(email)?;
+    let media_type = header_value(&fields, "content-type")
+        .map(|value| {
+            let media_type = value.split(';').next().unwrap_or_default();
+            media_type.trim().to_ascii_lowercase()
+        })
+        .ok_or(EmailError::NotEncrypted)?;
+    if media_type != CONTENT_TYPE && media_type != "application/x-pkcs7-mime" {
+        return Err(EmailError::NotEncrypted);
+    }
+    if !header_value(&fields, "content-transfer-encoding")
+        .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("base64"))
+    {
+        return Err(EmailError::Malformed("the entity is not encoded in base64"));
+    }
+    let encoded: Vec<u8> = body
+        .iter()
+        .copied()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .collect();
+    let der = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| EmailError::Malformed("invalid base64 in the entity"))?;
+    let entity = open(&der, certificate, private_key)?;
+
+    let mut message = Vec::new();
+    for field in fields.into_iter().filter(|field| !is_content_field(field)) {
+        message.extend_from_slice(&to_crlf(field));
+    }
+    message.extend_from_slice(&entity);
+    Ok(message)
+}
+
+/// Encrypts `content` into a DER-encoded CMS `ContentInfo` holding an `EnvelopedData` structure.
+fn envelop(content: &[u8], recipients: &[Certificate]) -> EmailResult<Vec<u8>> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    let mut iv = [0u8; 16];
+    OsRng.fill_bytes(key.as_mut());
+    OsRng.fill_bytes(&mut iv);
+    let ciphertext = cbc::Encryptor::<Aes256>::new(key.as_ref().into(), &iv.into())
+        .encrypt_padded_vec_mut::<Pkcs7>(content);
+
+    let mut recipient_infos = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let encrypted_key =
+            core::encrypt_with(&recipient.public_key, OaepParams::SHA256, &key[..])?;
+        recipient_infos.push(Any::encode_from(&KeyTransRecipientInfo {
+            version: 0,
+            rid: Any::encode_from(&recipient.issuer_and_serial_number)?,
+            key_encryption_algorithm: oaep_algorithm(OaepParams::SHA256)?,
+            encrypted_key: OctetString::new(encrypted_key)?,
+        })?);
+    }
+    let enveloped_data = EnvelopedData {
+        version: 0,
+        originator_info: None,
+        recipient_infos: cms::set_of(&recipient_infos)?,
+        encrypted_content_info: EncryptedContentInfo {
+            content_type: ID_DATA,
+            content_encryption_algorithm: AlgorithmIdentifierOwned {
+                oid: ID_AES256_CBC,
+                parameters: Some(Any::encode_from(&OctetString::new(iv)?)?),
+            },
+            encrypted_content: Some(OctetString::new(ciphertext)?),
+        },
+        unprotected_attrs: None,
+    };
+    Ok(ContentInfo {
+        content_type: ID_ENVELOPED_DATA,
+        content: Any::encode_from(&enveloped_data)?,
+    }
+    .to_der()?)
+}
+
+/// Decrypts the content of a DER-encoded CMS `ContentInfo` holding an `EnvelopedData` structure.
+fn open(
+    der: &[u8],
+    certificate: &Certificate,
+    private_key: &RsaPrivateKey,
+) -> EmailResult<Vec<u8>> {
+    let content_info = ContentInfo::from_der(der)?;
+    if content_info.content_type != ID_ENVELOPED_DATA {
+        return Err(EmailError::NotEncrypted);
+    }
+    let enveloped_data: EnvelopedData = content_info.content.decode_as()?;
+    let mut recipient_info = None;
+    for info in cms::set_elements(&enveloped_data.recipient_infos)? {
+        // The other choices of `RecipientInfo` are tagged, and do not use RSA key transport.
+        if info.tag() != Tag::Sequence {
+            continue;
+        }
+        let info: KeyTransRecipientInfo = info.decode_as()?;
+        if info.rid.tag() == Tag::Sequence
+            && info.rid.decode_as::<IssuerAndSerialNumber>()?
+                == certificate.issuer_and_serial_number
+        {
+            recipient_info = Some(info);
+            break;
+        }
+    }
+    let recipient_info = recipient_info.ok_or(EmailError::NotARecipient)?;
+    let params = oaep_params(&recipient_info.key_encryption_algorithm)?;
+    let key = Zeroizing::new(
+        core::decrypt_blinded_with(
+            private_key,
+            params,
+            recipient_info.encrypted_key.as_bytes(),
+        )
+        .map_err(|_| EmailError::Decryption)?,
+    );
+
+    let encrypted = &enveloped_data.encrypted_content_info;
+    let algorithm = &encrypted.content_encryption_algorithm;
+    let iv: OctetString = algorithm
+        .parameters
+        .as_ref()
+        .ok_or(EmailError::Malformed("the content encryption has no IV"))?
+        .decode_as()?;
+    let ciphertext = encrypted
+        .encrypted_content
+        .as_ref()
+        .ok_or(EmailError::Malformed("the content is detached"))?
+        .as_bytes();
+    let plaintext = match algorithm.oid {
+        ID_AES128_CBC => decrypt_cbc::<Aes128>(&key, iv.as_bytes(), ciphertext),
+        ID_AES192_CBC => decrypt_cbc::<Aes192>(&key, iv.as_bytes(), ciphertext),
+        ID_AES256_CBC => decrypt_cbc::<Aes256>(&key, iv.as_bytes(), ciphertext),
+        oid => return Err(EmailError::UnsupportedAlgorithm(oid.to_string())),
+    };
+    plaintext.ok_or(EmailError::Decryption)
+}
+
+fn decrypt_cbc<C>(key: &[u8], iv: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>
+where
+    C: BlockCipher + BlockDecrypt + KeyInit,
+{
+    cbc::Decryptor::<C>::new_from_slices(key, iv)
+        .ok()?
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .ok()
+}
+
+/// Returns the RSAES-OAEP algorithm identifier of `params` (RFC 4055), omitting the SHA-1 defaults.
+fn oaep_algorithm(params: OaepParams) -> EmailResult<AlgorithmIdentifierOwned> {
+    let hash_func =
+        (params.hash != OaepHash::Sha1).then(|| hash_algorithm(params.hash));
+    let mask_gen_func = match params.mgf1_hash {
+        OaepHash::Sha1 => None,
+        hash => Some(AlgorithmIdentifierOwned {
+            oid: ID_MGF1,
+            parameters: Some(Any::encode_from(&hash_algorithm(hash))?),
+        }),
+    };
+    Ok(AlgorithmIdentifierOwned {
+        oid: ID_RSAES_OAEP,
+        parameters: Some(Any::encode_from(&RsaesOaepParams {
+            hash_func,
+            mask_gen_func,
+            p_source_func: None,
+        })?),
+    })
+}
+
+/// Returns the OAEP parameters of a key transport algorithm identifier.
+fn oaep_params(algorithm: &AlgorithmIdentifierOwned) -> EmailResult<OaepParams> {
+    if algorithm.oid == RSA_ENCRYPTION {
+        return Err(EmailError::UnsupportedAlgorithm(
+            "PKCS #1 v1.5 key transport".to_string(),
+        ));
+    }
+    if algorithm.oid != ID_RSAES_OAEP {
+        return Err(EmailError::UnsupportedAlgorithm(algorithm.oid.to_string()));
+    }
+    let params: RsaesOaepParams = match &algorithm.parameters {
+        Some(parameters) => parameters.decode_as()?,
+        None => RsaesOaepParams {
+            hash_func: None,
+            mask_gen_func: None,
+            p_source_func: None,
+        },
+    };
+    let hash = params
+        .hash_func
+        .map_or(Ok(OaepHash::Sha1), |hash| oaep_hash(&hash.oid))?;
+    let mgf1_hash = match params.mask_gen_func {
+        None => OaepHash::Sha1,
+        Some(mask_gen_func) if mask_gen_func.oid == ID_MGF1 => {
+            let hash: AlgorithmIdentifierOwned = mask_gen_func
+                .parameters
+                .ok_or(EmailError::Malformed("MGF1 has no hash"))?
+                .decode_as()?;
+            oaep_hash(&hash.oid)?
+        }
+        Some(mask_gen_func) => {
+            return Err(EmailError::UnsupportedAlgorithm(
+                mask_gen_func.oid.to_string(),
+            ))
+        }
+    };
+    if let Some(p_source_func) = params.p_source_func {
+        let label: Option<OctetString> = p_source_func
+            .parameters
+            .map(|label| label.decode_as())
+            .transpose()?;
+        if p_source_func.oid != ID_P_SPECIFIED
+            || label.is_some_and(|label| !label.as_bytes().is_empty())
+        {
+            return Err(EmailError::UnsupportedAlgorithm(
+                "OAEP with a label".to_string(),
+            ));
+        }
+    }
+    Ok(OaepParams { hash, mgf1_hash })
+}
+
+fn hash_algorithm(hash: OaepHash) -> AlgorithmIdentifierOwned {
+    let (_, oid) = HASHES
+        .iter()
+        .find(|(candidate, _)| *candidate == hash)
+        .expect("every OAEP hash has an object identifier");
+    AlgorithmIdentifierOwned {
+        oid: *oid,
+        parameters: Some(Any::null()),
+    }
+}
+
+fn oaep_hash(oid: &ObjectIdentifier) -> EmailResult<OaepHash> {
+    HASHES
+        .iter()
+        .find(|(_, candidate)| candidate == oid)
+        .map(|(hash, _)| *hash)
+        .ok_or_else(|| EmailError::UnsupportedAlgorithm(oid.to_string()))
+}
+
+/// Splits a message into its header fields, each with its continuation lines and line endings,
+/// and its body.
+fn split_message(message: &[u8]) -> EmailResult<(Vec<&[u8]>, &[u8])> {
+    let mut fields: Vec<&[u8]> = Vec::new();
+    let mut field_start = 0;
+    let mut offset = 0;
+    while offset < message.len() {
+        let line_end = message[offset..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(message.len(), |position| offset + position + 1);
+        let line = &message[offset..line_end];
+        if line == b"\n" || line == b"\r\n" {
+            if offset > field_start {
+                fields.push(&message[field_start..offset]);
+            }
+            return Ok((fields, &message[line_end..]));
+        }
+        if line[0] == b' ' || line[0] == b'\t' {
+            if offset == 0 {
+                return Err(EmailError::Malformed(
+                    "the header starts with a continuation",
+                ));
+            }
+        } else {
+            if !line.contains(&b':') {
+                return Err(EmailError::Malformed("invalid header field"));
+            }
+            if offset > field_start {
+                fields.push(&message[field_start..offset]);
+            }
+            field_start = offset;
+        }
+        offset = line_end;
+    }
+    if offset > field_start {
+        fields.push(&message[field_start..offset]);
+    }
+    Ok((fields, &[]))
+}
+
+/// Returns the name of a header field.
+fn field_name(field: &[u8]) -> &[u8] {
+    let end = field
+        .iter()
+        .position(|&byte| byte == b':')
+        .unwrap_or(field.len());
+    field[..end].trim_ascii()
+}
+
+/// Returns whether a header field describes the content, and is encrypted with it.
+fn is_content_field(field: &[u8]) -> bool {
+    let name = field_name(field).to_ascii_lowercase();
+    name.starts_with(b"content-") || name == b"mime-version"
+}
+
+/// Returns the unfolded value of the first header field named `name`, case-insensitively.
+fn header_value(fields: &[&[u8]], name: &str) -> Option<String> {
+    let field = fields
+        .iter()
+        .find(|field| field_name(field).eq_ignore_ascii_case(name.as_bytes()))?;
+    let colon = field.iter().position(|&byte| byte == b':')?;
+    let value: Vec<u8> = field[colon + 1..]
+        .iter()
+        .copied()
+        .filter(|&byte| byte != b'\r' && byte != b'\n')
+        .collect();
+    Some(String::from_utf8_lossy(&value).into_owned())
+}
+
+/// Normalizes bare LF line endings to CRLF.
+fn to_crlf(bytes: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(bytes.len());
+    let mut previous = 0;
+    for &byte in bytes {
+        if byte == b'\n' && previous != b'\r' {
+            normalized.push(b'\r');
+        }
+        normalized.push(byte);
+        previous = byte;
+    }
+    normalized
+}
+
+// The structures of RFC 5280 and RFC 4055. Only the fields addressing a recipient are decoded.
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct X509Certificate {
+    tbs_certificate: TbsCertificate,
+    signature_algorithm: AlgorithmIdentifierOwned,
+    signature: BitString,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct TbsCertificate {
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT", optional = "true")]
+    version: Option<u8>,
+    serial_number: Any,
+    signature: AlgorithmIdentifierOwned,
+    issuer: Any,
+    validity: Any,
+    subject: Any,
+    subject_public_key_info: Any,
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", optional = "true")]
+    issuer_unique_id: Option<BitString>,
+    #[asn1(context_specific = "2", tag_mode = "IMPLICIT", optional = "true")]
+    subject_unique_id: Option<BitString>,
+    #[asn1(context_specific = "3", tag_mode = "EXPLICIT", optional = "true")]
+    extensions: Option<Any>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct RsaesOaepParams {
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT", optional = "true")]
+    hash_func: Option<AlgorithmIdentifierOwned>,
+    #[asn1(context_specific = "1", tag_mode = "EXPLICIT", optional = "true")]
+    mask_gen_func: Option<AlgorithmIdentifierOwned>,
+    #[asn1(context_specific = "2", tag_mode = "EXPLICIT", optional = "true")]
+    p_source_func: Option<AlgorithmIdentifierOwned>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs8::DecodePrivateKey;
+
+    const CERTIFICATE: &str = include_str!("../files/smime/certificate.pem");
+    const PRIVATE_KEY: &str = include_str!("../files/smime/private.pem");
+    /// Encrypted with `openssl cms -encrypt -aes128 -keyopt rsa_padding_mode:oaep`, i.e. with
+    /// AES-128-CBC and the SHA-1 defaults of RSAES-OAEP.
+    const OPENSSL_EMAIL: &[u8] = include_bytes!("../files/smime/openssl.eml");
+
+    fn recipient() -> (Certificate, RsaPrivateKey) {
+        (
+            Certificate::from_pem(CERTIFICATE).unwrap(),
+            RsaPrivateKey::from_pkcs8_pem(PRIVATE_KEY).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_roundtrip_moves_content_fields_into_the_entity() {
+        let (certificate, private_key) = recipient();
+        let message = b"From: bob@example.com\n\
+            Subject: Hello,\n  folded\n\
+            MIME-Version: 1.0\n\
+            Content-Type: text/plain\n\
+            \n\
+            Line 1\nLine 2\n";
+        let encrypted =
+            encrypt(message, std::slice::from_ref(&certificate)).unwrap();
+        let text = String::from_utf8(encrypted.clone()).unwrap();
+        assert!(text.starts_with(
+            "From: bob@example.com\r\nSubject: Hello,\r\n  folded\r\n"
+        ));
+        assert!(text.contains("Content-Type: application/pkcs7-mime;"));
+        assert!(!text.contains("text/plain"));
+        let (_, body) = text.split_once("\r\n\r\n").unwrap();
+        assert!(body.lines().all(|line| line.len() <= LINE_LEN));
+
+        assert_eq!(
+            decrypt(&encrypted, &certificate, &private_key).unwrap(),
+            to_crlf(message)
+        );
+        assert!(matches!(
+            decrypt(&to_crlf(message), &certificate, &private_key),
+            Err(EmailError::NotEncrypted)
+        ));
+        assert!(matches!(
+            encrypt(message, &[]),
+            Err(EmailError::NoRecipients)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_or_misaddressed_emails_are_rejected() {
+        let (certificate, private_key) = recipient();
+        let encrypted = encrypt(
+            b"Subject: Hi\r\n\r\nBody",
+            std::slice::from_ref(&certificate),
+        )
+        .unwrap();
+        let (fields, body) = split_message(&encrypted).unwrap();
+        let mut der = general_purpose::STANDARD
+            .decode(
+                body.iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap();
+        let last = der.len() - 1;
+        der[last] ^= 1;
+        let mut tampered: Vec<u8> = fields.concat();
+        tampered.extend_from_slice(b"\r\n");
+        tampered
+            .extend_from_slice(general_purpose::STANDARD.encode(&der).as_bytes());
+        assert!(matches!(
+            decrypt(&tampered, &certificate, &private_key),
+            Err(EmailError::Decryption)
+        ));
+
+        let other_key = core::generate_private_key(1024).unwrap();
+        let mut other = certificate.clone();
+        other.issuer_and_serial_number.serial_number =
+            Any::encode_from(&2u8).unwrap();
+        assert!(matches!(
+            decrypt(&encrypted, &other, &other_key),
+            Err(EmailError::NotARecipient)
+        ));
+        assert!(matches!(
+            Certificate::from_pem(PRIVATE_KEY),
+            Err(EmailError::InvalidCertificate(_))
+        ));
+    }
+
+    #[test]
+    fn test_decrypts_openssl_emails() {
+        let (certificate, private_key) = recipient();
+        let decrypted = decrypt(OPENSSL_EMAIL, &certificate, &private_key).unwrap();
+        let decrypted = String::from_utf8(decrypted).unwrap();
+        assert!(decrypted.starts_with("To: alice@example.com\r\n"));
+        assert!(decrypted.ends_with("Encrypted by OpenSSL.\r\n"));
+    }
+}
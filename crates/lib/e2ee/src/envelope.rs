@@ -0,0 +1,1833 @@
+//! Hybrid encryption of payloads of any size.
+//!
+//! RSA-OAEP alone can only encrypt a few hundred bytes. An envelope encrypts the payload with a
+//! random AES-256-GCM data key, and the data key with RSA-OAEP (SHA-256) under the recipient's
+//! public key. Its binary layout is:
+//!
+//! ```text
+//! version (1 byte) | key ID (8 bytes) | wrapped key length (2 bytes, big endian) | wrapped key |
+//! nonce (12 bytes) | AES-256-GCM ciphertext and tag
+//! ```
+//!
+//! The header (version, key ID, length and wrapped key) is authenticated as associated data.
+//!
+//! The key ID is the first 8 bytes of the SHA-256 fingerprint of the recipient's public key, the
+//! binary form of `core::key_id`. [`Envelope::key_id`] returns it without any private key, so that
+//! a fleet holding several key generations can route each envelope to the right key. Envelopes of
+//! version 1, which have no key ID, can still be opened.
+//!
+//! # Key escrow
+//!
+//! Organizations that must be able to recover data when a user's key is lost can configure escrow
+//! keys (recovery agents) with [`seal_with_escrow`], `DataKeyCache::with_escrow_key`,
+//! `E2ee::with_escrow_key` or `PublicE2ee::with_escrow_key`. The data key is then also wrapped
+//! for each escrow key, in an envelope of version 3 whose header continues with:
+//!
+//! ```text
+//! escrow key count (1 byte) | (key ID (8 bytes) | wrapped key length (2 bytes) | wrapped key)...
+//! ```
+//!
+//! [`Envelope::recipients`] lists the recipient and the escrow keys of an envelope, so that escrow
+//! is never silent, and [`open`] with an escrow private key decrypts the payload.
+//!
+//! # Expiry
+//!
+//! Short-lived secrets, such as the payloads of one-time links, can be sealed with an expiry time
+//! with [`seal_with_expiry`] or `E2ee::encrypt_with_expiry`, in an envelope of version 4: the
+//! header of version 3 (with a possibly empty list of escrow keys) followed by the expiry time, in
+//! seconds since the Unix epoch (8 bytes, big endian). The expiry is authenticated with the rest of
+//! the header, and [`open`] rejects envelopes past their expiry with `EnvelopeError::Expired`,
+//! unless told otherwise with [`open_at`]. Keep in mind that anyone who decrypted the payload before
+//! it expired may have kept it, and that the check relies on the clock of the decrypting machine.
+//!
+//! # Ciphers
+//!
+//! The payload is encrypted with AES-256-GCM by default. ChaCha20-Poly1305 is several times faster
+//! on CPUs without AES instructions, such as many ARM devices running the client side: seal with
+//! [`seal_with_aead`], `DataKeyCache::with_aead`, `E2ee::with_aead` or `PublicE2ee::with_aead` to
+//! use it. The cipher is marked by the [`CHACHA20_POLY1305_FLAG`] bit of the version byte, which is
+//! authenticated with the rest of the header, and [`open`] supports both. Versions of this crate
+//! that predate the flag reject such envelopes as an unsupported version.
+//!
+//! # Multiple recipients
+//!
+//! [`seal_for_recipients`] wraps the data key for several keys, e.g. the devices of a user (see
+//! the `devices` module), in an envelope of version 5 with the header of version 3, where every
+//! key is a recipient. Each of them opens the envelope with [`open`].
+//!
+//! `E2ee::encrypt_envelope`, `E2ee::decrypt_envelope` and `PublicE2ee::encrypt_envelope` wrap
+//! [`seal`] and [`open`] with metrics and audit logging. High-throughput producers can seal with a
+//! [`DataKeyCache`] instead, which reuses each wrapped data key for many envelopes.
+//!
+//! # Rewrapping
+//!
+//! When a key pair is rotated, [`rewrap`] and `E2ee::rewrap` move envelopes to the new public key
+//! without decrypting their payload: the data key is unwrapped with the old private key and
+//! wrapped again for the new public key, and only the authentication tag of the payload is
+//! recomputed, since the header it authenticates changed. The tag is checked first, so a tampered
+//! envelope is not rewrapped into a valid one. The other recipients and the expiry are kept.
+//!
+//! # JSON
+//!
+//! With the `io` feature, the [`json`] module converts envelopes to and from a JSON object with
+//! JWE-like fields (`v`, `alg`, `kid`, `iv`, `ek`, `ct`, `tag`), for APIs that want structured
+//! fields rather than one opaque blob. [`open`] accepts both encodings, and
+//! [`EnvelopeFormat::Json`] makes `E2ee::encrypt_envelope` and `PublicE2ee::encrypt_envelope`
+//! produce JSON.
+//!
+//! # HTTP transport
+//!
+//! Envelopes sent over HTTP use the [`CONTENT_TYPE`] media type, and carry the media type of the
+//! payload in the [`ORIGINAL_CONTENT_TYPE_HEADER`] header. A client that wants an encrypted
+//! response sends its public key in the [`CLIENT_KEY_HEADER`] header (see [`encode_client_key`]).
+use crate::core::{self, OaepParams};
+use aes_gcm::{
+    aead::{self, Aead as _, KeyInit, Payload},
+    aes::{
+        cipher::{BlockEncrypt, KeyIvInit, StreamCipher},
+        Aes256,
+    },
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+use chacha20::ChaCha20;
+use chacha20poly1305::ChaCha20Poly1305;
+use ghash::{universal_hash::UniversalHash, GHash};
+use poly1305::Poly1305;
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey},
+    rand_core::{CryptoRngCore, OsRng, RngCore},
+    sha2::{Digest, Sha256},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use std::{
+    fmt, iter,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+#[cfg(feature = "io")]
+pub mod json;
+
+/// The media type of envelopes sent over HTTP.
+pub const CONTENT_TYPE: &str = "application/vnd.e2ee.envelope";
+
+/// The header carrying the media type of the payload of an envelope sent over HTTP.
+pub const ORIGINAL_CONTENT_TYPE_HEADER: &str = "e2ee-content-type";
+
+/// The header carrying the base64-encoded SPKI DER public key that responses should be encrypted
+/// to.
+pub const CLIENT_KEY_HEADER: &str = "e2ee-client-key";
+
+/// The version of the envelope format produced by [`seal`].
+pub const VERSION: u8 = 2;
+
+/// The version of the envelope format without key ID, still accepted by [`open`].
+pub const VERSION_WITHOUT_KEY_ID: u8 = 1;
+
+/// The version of the envelope format with escrow keys, produced when escrow keys are configured.
+pub const VERSION_WITH_ESCROW: u8 = 3;
+
+/// The version of the envelope format with escrow keys and an expiry time, produced when an expiry
+/// is set.
+pub const VERSION_WITH_EXPIRY: u8 = 4;
+
+/// The version of the envelope format with several recipients, produced by
+/// [`seal_for_recipients`].
+pub const VERSION_MULTI_RECIPIENT: u8 = 5;
+
+/// The bit of the version byte set when the payload is encrypted with ChaCha20-Poly1305 rather
+/// than AES-256-GCM, in envelopes and streams.
+pub const CHACHA20_POLY1305_FLAG: u8 = 0x80;
+
+/// The length of the key ID of an envelope, in bytes.
+pub const KEY_ID_LEN: usize = 8;
+
+const DATA_KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const EXPIRY_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// An error returned when sealing or opening an envelope.
+#[derive(Error, Debug)]
+pub enum EnvelopeError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("Malformed envelope")]
+    Malformed,
+
+    #[error("Unsupported envelope version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The envelope payload failed authentication")]
+    Authentication,
+
+    #[error("Invalid client key: {0}")]
+    InvalidClientKey(String),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("The key is not a recipient of the envelope")]
+    NotARecipient,
+
+    #[error("Too many escrow keys")]
+    TooManyEscrowKeys,
+
+    #[error("Envelopes are sealed for 1 to 256 recipients, not {0}")]
+    RecipientCount(usize),
+
+    #[error("Envelope expired at {expires_at:?}")]
+    Expired { expires_at: SystemTime },
+
+    #[error("Invalid JSON envelope: {0}")]
+    InvalidJson(String),
+}
+
+/// The encoding of the envelopes produced by `E2ee::encrypt_envelope` and
+/// `PublicE2ee::encrypt_envelope`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EnvelopeFormat {
+    /// The binary layout described in the module documentation.
+    #[default]
+    Binary,
+    /// The JSON object of the [`json`] module.
+    #[cfg(feature = "io")]
+    Json,
+}
+
+impl EnvelopeFormat {
+    /// Encodes a binary envelope in this format.
+    pub(crate) fn encode(
+        &self,
+        envelope: Vec<u8>,
+    ) -> Result<Vec<u8>, EnvelopeError> {
+        match self {
+            EnvelopeFormat::Binary => Ok(envelope),
+            #[cfg(feature = "io")]
+            EnvelopeFormat::Json => Ok(json::to_json(&envelope)?.into_bytes()),
+        }
+    }
+}
+
+/// The authenticated cipher encrypting the payload of an envelope or a stream with the data key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Aead {
+    /// AES-256-GCM, the fastest on CPUs with AES instructions (AES-NI, ARMv8 Crypto Extensions).
+    #[default]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, the fastest in software, e.g. on ARM devices without AES instructions.
+    ChaCha20Poly1305,
+}
+
+impl Aead {
+    /// Returns the name of the cipher (e.g. `"AES-256-GCM"`).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Aead::Aes256Gcm => "AES-256-GCM",
+            Aead::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+
+    /// Splits a version byte into the version and the cipher it marks.
+    pub(crate) fn split_version(byte: u8) -> (u8, Self) {
+        let aead = if byte & CHACHA20_POLY1305_FLAG == 0 {
+            Aead::Aes256Gcm
+        } else {
+            Aead::ChaCha20Poly1305
+        };
+        (byte & !CHACHA20_POLY1305_FLAG, aead)
+    }
+
+    /// Returns `version` with the bit marking this cipher.
+    pub(crate) fn mark_version(&self, version: u8) -> u8 {
+        match self {
+            Aead::Aes256Gcm => version,
+            Aead::ChaCha20Poly1305 => version | CHACHA20_POLY1305_FLAG,
+        }
+    }
+
+    /// Creates an instance of the cipher with a key of 32 bytes.
+    pub(crate) fn cipher(&self, key: &[u8]) -> Option<AeadCipher> {
+        Some(match self {
+            Aead::Aes256Gcm => {
+                AeadCipher::Aes256Gcm(Box::new(Aes256Gcm::new_from_slice(key).ok()?))
+            }
+            Aead::ChaCha20Poly1305 => AeadCipher::ChaCha20Poly1305(
+                ChaCha20Poly1305::new_from_slice(key).ok()?,
+            ),
+        })
+    }
+}
+
+/// An instance of an [`Aead`] cipher with its key.
+pub(crate) enum AeadCipher {
+    // The expanded AES key schedule is large, and ciphers are created once per envelope or stream.
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl AeadCipher {
+    pub(crate) fn encrypt(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        payload: Payload<'_, '_>,
+    ) -> aead::Result<Vec<u8>> {
+        match self {
+            AeadCipher::Aes256Gcm(cipher) => {
+                cipher.encrypt(Nonce::from_slice(nonce), payload)
+            }
+            AeadCipher::ChaCha20Poly1305(cipher) => {
+                cipher.encrypt(Nonce::from_slice(nonce), payload)
+            }
+        }
+    }
+
+    pub(crate) fn decrypt(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        payload: Payload<'_, '_>,
+    ) -> aead::Result<Vec<u8>> {
+        match self {
+            AeadCipher::Aes256Gcm(cipher) => {
+                cipher.decrypt(Nonce::from_slice(nonce), payload)
+            }
+            AeadCipher::ChaCha20Poly1305(cipher) => {
+                cipher.decrypt(Nonce::from_slice(nonce), payload)
+            }
+        }
+    }
+}
+
+/// A parsed envelope, borrowing its parts from the encoded bytes.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, Envelope};
+///
+/// let public_key = core::generate_private_key(2048)
+///     .expect("Failed to generate key")
+///     .to_public_key();
+/// let sealed = envelope::seal(&public_key, b"Hello, envelope!").expect("Failed to seal");
+/// let key_id = Envelope::parse(&sealed).expect("Malformed envelope").key_id();
+/// assert_eq!(key_id, Some(core::key_id(&public_key).unwrap()));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    version: u8,
+    aead: Aead,
+    key_id: Option<&'a [u8]>,
+    header: &'a [u8],
+    wrapped_key: &'a [u8],
+    escrow: &'a [u8],
+    expires_at: Option<SystemTime>,
+    nonce: &'a [u8],
+    ciphertext: &'a [u8],
+}
+
+impl<'a> Envelope<'a> {
+    /// Parses an envelope produced by [`seal`] or a [`DataKeyCache`], without decrypting it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `EnvelopeError::UnsupportedVersion` if the version is unknown, or
+    /// `EnvelopeError::Malformed` if the envelope is truncated.
+    pub fn parse(envelope: &'a [u8]) -> Result<Self, EnvelopeError> {
+        let (&version, _) =
+            envelope.split_first().ok_or(EnvelopeError::Malformed)?;
+        let (version, aead) = match Aead::split_version(version) {
+            // Envelopes of version 1 predate the flag.
+            (VERSION_WITHOUT_KEY_ID, Aead::ChaCha20Poly1305) => {
+                return Err(EnvelopeError::UnsupportedVersion(version))
+            }
+            split => split,
+        };
+        let key_id_len = match version {
+            VERSION
+            | VERSION_WITH_ESCROW
+            | VERSION_WITH_EXPIRY
+            | VERSION_MULTI_RECIPIENT => KEY_ID_LEN,
+            VERSION_WITHOUT_KEY_ID => 0,
+            _ => {
+                return Err(EnvelopeError::UnsupportedVersion(
+                    aead.mark_version(version),
+                ))
+            }
+        };
+        let (key_id, wrapped_key, mut header_len) =
+            parse_wrapped_key(envelope, 1, key_id_len)?;
+        let escrow_start = header_len;
+        if version >= VERSION_WITH_ESCROW {
+            let count = *envelope.get(header_len).ok_or(EnvelopeError::Malformed)?;
+            header_len += 1;
+            for _ in 0..count {
+                header_len = parse_wrapped_key(envelope, header_len, KEY_ID_LEN)?.2;
+            }
+        }
+        let escrow_end = header_len;
+        let mut expires_at = None;
+        if version == VERSION_WITH_EXPIRY {
+            let seconds = envelope
+                .get(header_len..header_len + EXPIRY_LEN)
+                .ok_or(EnvelopeError::Malformed)?;
+            let seconds = u64::from_be_bytes(seconds.try_into().expect("8 bytes"));
+            expires_at = Some(
+                UNIX_EPOCH
+                    .checked_add(Duration::from_secs(seconds))
+                    .ok_or(EnvelopeError::Malformed)?,
+            );
+            header_len += EXPIRY_LEN;
+        }
+        if envelope.len() < header_len + NONCE_LEN {
+            return Err(EnvelopeError::Malformed);
+        }
+        let (header, rest) = envelope.split_at(header_len);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        Ok(Self {
+            version,
+            aead,
+            key_id: (key_id_len > 0).then_some(key_id),
+            header,
+            wrapped_key,
+            escrow: &header[escrow_start..escrow_end],
+            expires_at,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    /// Returns the version of the envelope format, without the [`CHACHA20_POLY1305_FLAG`] bit.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the cipher encrypting the payload.
+    pub fn aead(&self) -> Aead {
+        self.aead
+    }
+
+    /// Returns the ID of the recipient's key, in the hex form of `core::key_id`, or `None` for an
+    /// envelope of version 1.
+    pub fn key_id(&self) -> Option<String> {
+        self.key_id.map(core::hex)
+    }
+
+    /// Returns the data key encrypted with RSA-OAEP for the recipient.
+    pub fn wrapped_key(&self) -> &'a [u8] {
+        self.wrapped_key
+    }
+
+    /// Returns the nonce of the payload.
+    pub fn nonce(&self) -> &'a [u8] {
+        self.nonce
+    }
+
+    /// Returns the encrypted payload, followed by its authentication tag.
+    pub fn ciphertext(&self) -> &'a [u8] {
+        self.ciphertext
+    }
+
+    /// Returns the time after which the envelope is rejected by [`open`], if it has one. It is only
+    /// authenticated once the envelope is opened.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at
+    }
+
+    /// Returns the keys the data key is wrapped for: the recipient first, then the escrow keys, or
+    /// every recipient of an envelope of version 5.
+    pub fn recipients(&self) -> Vec<Recipient<'a>> {
+        let role = if self.version == VERSION_MULTI_RECIPIENT {
+            RecipientRole::Recipient
+        } else {
+            RecipientRole::Escrow
+        };
+        let mut recipients = vec![Recipient {
+            role: RecipientRole::Recipient,
+            key_id: self.key_id,
+            wrapped_key: self.wrapped_key,
+        }];
+        // The escrow keys were validated by `parse`.
+        let mut offset = 1;
+        while let Ok((key_id, wrapped_key, end)) =
+            parse_wrapped_key(self.escrow, offset, KEY_ID_LEN)
+        {
+            recipients.push(Recipient {
+                role,
+                key_id: Some(key_id),
+                wrapped_key,
+            });
+            offset = end;
+        }
+        recipients
+    }
+
+    /// Returns the data key wrapped for `public_key`, selected by key ID among the recipients of an
+    /// envelope of version 3 or above. Envelopes of versions 1 and 2 have a single wrapped key, which is
+    /// returned as is.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `EnvelopeError::NotARecipient` if the envelope has escrow keys and
+    /// none of its recipients is `public_key`, or an error if the key cannot be DER-encoded.
+    pub fn wrapped_key_for(
+        &self,
+        public_key: &RsaPublicKey,
+    ) -> Result<&'a [u8], EnvelopeError> {
+        if self.version < VERSION_WITH_ESCROW {
+            return Ok(self.wrapped_key);
+        }
+        let key_id = key_id(public_key)?;
+        self.recipients()
+            .into_iter()
+            .find(|recipient| recipient.key_id == Some(&key_id[..]))
+            .map(|recipient| recipient.wrapped_key)
+            .ok_or(EnvelopeError::NotARecipient)
+    }
+}
+
+/// The role of a key an envelope is sealed for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecipientRole {
+    /// The key the envelope was sealed for.
+    Recipient,
+    /// An escrow key, able to recover the payload if the recipient's key is lost.
+    Escrow,
+}
+
+/// A key an envelope is sealed for, as listed by [`Envelope::recipients`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Recipient<'a> {
+    role: RecipientRole,
+    key_id: Option<&'a [u8]>,
+    wrapped_key: &'a [u8],
+}
+
+impl<'a> Recipient<'a> {
+    /// Returns whether this is the recipient or an escrow key.
+    pub fn role(&self) -> RecipientRole {
+        self.role
+    }
+
+    /// Returns the ID of the key, in the hex form of `core::key_id`, or `None` for an envelope of
+    /// version 1.
+    pub fn key_id(&self) -> Option<String> {
+        self.key_id.map(core::hex)
+    }
+
+    /// Returns the data key encrypted with RSA-OAEP for this key.
+    pub fn wrapped_key(&self) -> &'a [u8] {
+        self.wrapped_key
+    }
+    /// Returns the binary key ID, as written into the envelope.
+    #[cfg_attr(not(feature = "proto"), allow(dead_code))]
+    pub(crate) fn raw_key_id(&self) -> Option<&'a [u8]> {
+        self.key_id
+    }
+}
+
+/// Parses a key ID of `key_id_len` bytes and a length-prefixed wrapped key at `offset`, and
+/// returns them with the offset of their end.
+fn parse_wrapped_key(
+    bytes: &[u8],
+    offset: usize,
+    key_id_len: usize,
+) -> Result<(&[u8], &[u8], usize), EnvelopeError> {
+    let length_offset = offset + key_id_len;
+    let wrapped_key_len = bytes
+        .get(length_offset..length_offset + 2)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        .ok_or(EnvelopeError::Malformed)?;
+    let end = length_offset + 2 + wrapped_key_len;
+    let wrapped_key = bytes
+        .get(length_offset + 2..end)
+        .ok_or(EnvelopeError::Malformed)?;
+    Ok((&bytes[offset..length_offset], wrapped_key, end))
+}
+
+/// Encodes the fields of an envelope decoded from another encoding (e.g. JSON) into the binary
+/// layout, and checks that the result parses. `recipients` starts with the recipient, whose key
+/// ID is `None` in envelopes of version 1, followed by the escrow keys or other recipients.
+#[cfg_attr(not(any(feature = "io", feature = "proto")), allow(dead_code))]
+pub(crate) fn encode_fields(
+    version: u8,
+    aead: Aead,
+    recipients: &[(Option<&[u8]>, &[u8])],
+    expires_at: Option<u64>,
+    nonce: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let ((key_id, wrapped_key), others) =
+        recipients.split_first().ok_or(EnvelopeError::Malformed)?;
+    if key_id.is_some() == (version == VERSION_WITHOUT_KEY_ID)
+        || (version < VERSION_WITH_ESCROW && !others.is_empty())
+        || expires_at.is_some() != (version == VERSION_WITH_EXPIRY)
+        || nonce.len() != NONCE_LEN
+    {
+        return Err(EnvelopeError::Malformed);
+    }
+    let mut envelope = vec![aead.mark_version(version)];
+    push_wrapped_key(&mut envelope, *key_id, wrapped_key)?;
+    if version >= VERSION_WITH_ESCROW {
+        envelope.push(
+            u8::try_from(others.len())
+                .map_err(|_| EnvelopeError::TooManyEscrowKeys)?,
+        );
+        for (key_id, wrapped_key) in others {
+            let key_id = key_id.ok_or(EnvelopeError::Malformed)?;
+            push_wrapped_key(&mut envelope, Some(key_id), wrapped_key)?;
+        }
+    }
+    if let Some(seconds) = expires_at {
+        envelope.extend_from_slice(&seconds.to_be_bytes());
+    }
+    envelope.extend_from_slice(nonce);
+    envelope.extend_from_slice(ciphertext);
+    Envelope::parse(&envelope)?;
+    Ok(envelope)
+}
+
+/// Appends a key ID, if any, and a length-prefixed wrapped key to `envelope`.
+fn push_wrapped_key(
+    envelope: &mut Vec<u8>,
+    key_id: Option<&[u8]>,
+    wrapped_key: &[u8],
+) -> Result<(), EnvelopeError> {
+    if let Some(key_id) = key_id {
+        if key_id.len() != KEY_ID_LEN {
+            return Err(EnvelopeError::Malformed);
+        }
+        envelope.extend_from_slice(key_id);
+    }
+    let len =
+        u16::try_from(wrapped_key.len()).map_err(|_| EnvelopeError::Malformed)?;
+    envelope.extend_from_slice(&len.to_be_bytes());
+    envelope.extend_from_slice(wrapped_key);
+    Ok(())
+}
+
+/// Returns the binary key ID of `public_key` written into envelopes.
+pub(crate) fn key_id(
+    public_key: &RsaPublicKey,
+) -> Result<[u8; KEY_ID_LEN], EnvelopeError> {
+    let der = public_key.to_public_key_der()?;
+    let digest = Sha256::digest(der.as_bytes());
+    Ok(digest[..KEY_ID_LEN]
+        .try_into()
+        .expect("the digest is long enough"))
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key`.
+///
+/// # Errors
+///
+/// This function returns an error if the data key cannot be encrypted with `public_key`, or if
+/// `public_key` cannot be DER-encoded to compute its ID.
+pub fn seal(
+    public_key: &RsaPublicKey,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with_escrow(public_key, &[], plaintext)
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key` like [`seal`], drawing the data key, the
+/// OAEP seed and the nonce from `rng` (see the `rng` module).
+///
+/// # Errors
+///
+/// This function returns the errors of [`seal`].
+pub fn seal_with_rng<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    public_key: &RsaPublicKey,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with_options(rng, public_key, &[], None, Aead::default(), plaintext)
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key` like [`seal`], with `aead` rather than
+/// AES-256-GCM.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, Aead, Envelope};
+///
+/// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let sealed = envelope::seal_with_aead(
+///     &private_key.to_public_key(),
+///     Aead::ChaCha20Poly1305,
+///     b"Hello, ARM!",
+/// )
+/// .expect("Failed to seal");
+/// assert_eq!(Envelope::parse(&sealed).unwrap().aead(), Aead::ChaCha20Poly1305);
+/// assert_eq!(envelope::open(&private_key, &sealed).unwrap(), b"Hello, ARM!");
+/// ```
+///
+/// # Errors
+///
+/// This function returns the errors of [`seal`].
+pub fn seal_with_aead(
+    public_key: &RsaPublicKey,
+    aead: Aead,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with_options(&mut OsRng, public_key, &[], None, aead, plaintext)
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key`, with the data key also wrapped for each
+/// of `escrow_keys`. Without escrow keys, this is [`seal`].
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, Envelope, RecipientRole};
+///
+/// let user_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let escrow_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let sealed = envelope::seal_with_escrow(
+///     &user_key.to_public_key(),
+///     &[escrow_key.to_public_key()],
+///     b"Hello, recovery!",
+/// )
+/// .expect("Failed to seal");
+/// let roles: Vec<_> = Envelope::parse(&sealed)
+///     .expect("Malformed envelope")
+///     .recipients()
+///     .iter()
+///     .map(|recipient| recipient.role())
+///     .collect();
+/// assert_eq!(roles, [RecipientRole::Recipient, RecipientRole::Escrow]);
+/// // The user's key is lost: the escrow key recovers the payload.
+/// assert_eq!(envelope::open(&escrow_key, &sealed).unwrap(), b"Hello, recovery!");
+/// ```
+///
+/// # Errors
+///
+/// This function returns `EnvelopeError::TooManyEscrowKeys` if there are more than 255 escrow
+/// keys, or the errors of [`seal`] for any of the keys.
+pub fn seal_with_escrow(
+    public_key: &RsaPublicKey,
+    escrow_keys: &[RsaPublicKey],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with_options(
+        &mut OsRng,
+        public_key,
+        escrow_keys,
+        None,
+        Aead::default(),
+        plaintext,
+    )
+}
+
+/// Encrypts `plaintext` into an envelope that any of `public_keys` opens. With a single key, this
+/// is [`seal`].
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, Envelope};
+///
+/// let phone = core::generate_private_key(2048).expect("Failed to generate key");
+/// let laptop = core::generate_private_key(2048).expect("Failed to generate key");
+/// let sealed = envelope::seal_for_recipients(
+///     &[phone.to_public_key(), laptop.to_public_key()],
+///     b"Hello, devices!",
+/// )
+/// .expect("Failed to seal");
+/// assert_eq!(Envelope::parse(&sealed).unwrap().recipients().len(), 2);
+/// assert_eq!(envelope::open(&phone, &sealed).unwrap(), b"Hello, devices!");
+/// assert_eq!(envelope::open(&laptop, &sealed).unwrap(), b"Hello, devices!");
+/// ```
+///
+/// # Errors
+///
+/// This function returns `EnvelopeError::RecipientCount` if there are no keys or more than 256,
+/// or the errors of [`seal`] for any of the keys.
+pub fn seal_for_recipients(
+    public_keys: &[RsaPublicKey],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let (first, others) = match public_keys.split_first() {
+        Some((first, others)) if others.len() <= usize::from(u8::MAX) => {
+            (first, others)
+        }
+        _ => return Err(EnvelopeError::RecipientCount(public_keys.len())),
+    };
+    let mut data_key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+    OsRng.fill_bytes(data_key.as_mut());
+    let mut header =
+        wrap_data_key(&mut OsRng, &data_key, first, others, None, None)?;
+    if !others.is_empty() {
+        // The header of version 3, where the other keys are recipients rather than escrow keys.
+        header[0] = VERSION_MULTI_RECIPIENT;
+    }
+    seal_with_data_key(&mut OsRng, &header, &data_key, plaintext)
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key` that [`open`] rejects after
+/// `expires_at`, rounded down to the second.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, EnvelopeError};
+/// use std::time::{Duration, SystemTime};
+///
+/// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let expires_at = SystemTime::now() + Duration::from_secs(600);
+/// let sealed = envelope::seal_with_expiry(&private_key.to_public_key(), expires_at, b"otp")
+///     .expect("Failed to seal");
+/// assert_eq!(envelope::open(&private_key, &sealed).unwrap(), b"otp");
+///
+/// let later = expires_at + Duration::from_secs(1);
+/// assert!(matches!(
+///     envelope::open_at(&private_key, &sealed, Some(later)),
+///     Err(EnvelopeError::Expired { .. })
+/// ));
+/// ```
+///
+/// # Errors
+///
+/// This function returns the errors of [`seal`].
+pub fn seal_with_expiry(
+    public_key: &RsaPublicKey,
+    expires_at: SystemTime,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    seal_with_options(
+        &mut OsRng,
+        public_key,
+        &[],
+        Some(expires_at),
+        Aead::default(),
+        plaintext,
+    )
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key` and `escrow_keys`, with an optional
+/// expiry time, with `aead`, drawing its random values from `rng`.
+pub(crate) fn seal_with_options<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    public_key: &RsaPublicKey,
+    escrow_keys: &[RsaPublicKey],
+    expires_at: Option<SystemTime>,
+    aead: Aead,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let mut data_key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+    rng.fill_bytes(data_key.as_mut());
+    let mut header =
+        wrap_data_key(rng, &data_key, public_key, escrow_keys, expires_at, None)?;
+    header[0] = aead.mark_version(header[0]);
+    seal_with_data_key(rng, &header, &data_key, plaintext)
+}
+
+/// Encrypts `plaintext` into an envelope for `public_key` like [`seal`], wrapping the data key with
+/// `label` as the RSA-OAEP label. The envelope only opens with the same label (see
+/// [`open_with_label`]), which binds it to a context such as the database column it is stored in.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope;
+///
+/// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let sealed = envelope::seal_with_label(&private_key.to_public_key(), "users.email", b"a@b.c")
+///     .expect("Failed to seal");
+/// assert_eq!(
+///     envelope::open_with_label(&private_key, "users.email", &sealed).unwrap(),
+///     b"a@b.c"
+/// );
+/// assert!(envelope::open_with_label(&private_key, "users.name", &sealed).is_err());
+/// ```
+///
+/// # Errors
+///
+/// This function returns the errors of [`seal`].
+pub fn seal_with_label(
+    public_key: &RsaPublicKey,
+    label: &str,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let mut data_key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+    OsRng.fill_bytes(data_key.as_mut());
+    let mut header =
+        wrap_data_key(&mut OsRng, &data_key, public_key, &[], None, Some(label))?;
+    header[0] = Aead::default().mark_version(header[0]);
+    seal_with_data_key(&mut OsRng, &header, &data_key, plaintext)
+}
+
+/// Wraps `data_key` for `public_key` and `escrow_keys`, with `label` as the RSA-OAEP label if set,
+/// and returns the header of the envelopes sealed with it.
+fn wrap_data_key<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    data_key: &[u8; DATA_KEY_LEN],
+    public_key: &RsaPublicKey,
+    escrow_keys: &[RsaPublicKey],
+    expires_at: Option<SystemTime>,
+    label: Option<&str>,
+) -> Result<Vec<u8>, EnvelopeError> {
+    let escrow_count = u8::try_from(escrow_keys.len())
+        .map_err(|_| EnvelopeError::TooManyEscrowKeys)?;
+    let version = match (escrow_count, expires_at) {
+        (_, Some(_)) => VERSION_WITH_EXPIRY,
+        (0, None) => VERSION,
+        (_, None) => VERSION_WITH_ESCROW,
+    };
+    let mut header = vec![version];
+    for (index, key) in iter::once(public_key).chain(escrow_keys).enumerate() {
+        let wrapped_key = match label {
+            Some(label) => core::encrypt_with_label(
+                rng,
+                key,
+                OaepParams::SHA256,
+                label,
+                data_key,
+            )?,
+            None => core::encrypt_with_rng(rng, key, OaepParams::SHA256, data_key)?,
+        };
+        header.extend_from_slice(&key_id(key)?);
+        header.extend_from_slice(&(wrapped_key.len() as u16).to_be_bytes());
+        header.extend_from_slice(&wrapped_key);
+        if index == 0 && version >= VERSION_WITH_ESCROW {
+            header.push(escrow_count);
+        }
+    }
+    if let Some(expires_at) = expires_at {
+        let seconds = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        header.extend_from_slice(&seconds.to_be_bytes());
+    }
+    Ok(header)
+}
+
+/// Encrypts `plaintext` with `data_key`, already wrapped into `header`, under a fresh random
+/// nonce, with the cipher marked in the version byte of `header`.
+fn seal_with_data_key<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    header: &[u8],
+    data_key: &[u8; DATA_KEY_LEN],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let mut envelope = header.to_vec();
+    let (_, aead) = Aead::split_version(header[0]);
+    let ciphertext = aead
+        .cipher(data_key)
+        .expect("the data key has the length of the key of every cipher")
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &envelope,
+            },
+        )
+        .map_err(|_| EnvelopeError::Authentication)?;
+    envelope.extend_from_slice(&nonce);
+    envelope.extend_from_slice(&ciphertext);
+    Ok(envelope)
+}
+
+/// Decrypts an envelope produced by [`seal`] with `private_key`, which may be the recipient's key
+/// or one of its escrow keys. With the `io` feature, the envelope may also be in the JSON encoding
+/// of the [`json`] module.
+///
+/// # Errors
+///
+/// This function returns an error if the envelope is malformed, if it has expired, if
+/// `private_key` is not one of its recipients, if the data key cannot be decrypted with
+/// `private_key`, or if the payload was tampered with.
+pub fn open(
+    private_key: &RsaPrivateKey,
+    envelope: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    open_at(private_key, envelope, Some(SystemTime::now()))
+}
+
+/// Decrypts an envelope like [`open`], checking its expiry against `now`, or not at all if `now` is
+/// `None` (e.g. to recover expired envelopes from an archive).
+///
+/// # Errors
+///
+/// This function returns the errors of [`open`].
+pub fn open_at(
+    private_key: &RsaPrivateKey,
+    envelope: &[u8],
+    now: Option<SystemTime>,
+) -> Result<Vec<u8>, EnvelopeError> {
+    open_with(envelope, &private_key.to_public_key(), now, |wrapped_key| {
+        Ok(core::decrypt_with(
+            private_key,
+            OaepParams::SHA256,
+            wrapped_key,
+        )?)
+    })
+}
+
+/// Decrypts an envelope produced by [`seal_with_label`] with `private_key` and the same `label`.
+///
+/// # Errors
+///
+/// This function returns the errors of [`open`], and fails to decrypt the data key if `label`
+/// differs from the label of the envelope.
+pub fn open_with_label(
+    private_key: &RsaPrivateKey,
+    label: &str,
+    envelope: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    open_with(
+        envelope,
+        &private_key.to_public_key(),
+        Some(SystemTime::now()),
+        |wrapped_key| {
+            Ok(core::decrypt_with_label(
+                private_key,
+                OaepParams::SHA256,
+                label,
+                wrapped_key,
+            )?)
+        },
+    )
+}
+
+/// Decrypts an envelope, unwrapping the data key wrapped for `public_key` with `unwrap_key`, after
+/// checking its expiry against `now` if set.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, err(level = "warn"))
+)]
+pub(crate) fn open_with(
+    envelope: &[u8],
+    public_key: &RsaPublicKey,
+    now: Option<SystemTime>,
+    unwrap_key: impl FnOnce(&[u8]) -> Result<Vec<u8>, EnvelopeError>,
+) -> Result<Vec<u8>, EnvelopeError> {
+    #[cfg(feature = "io")]
+    let decoded;
+    #[cfg(feature = "io")]
+    let envelope = if json::is_json(envelope) {
+        let text = std::str::from_utf8(envelope)
+            .map_err(|err| EnvelopeError::InvalidJson(err.to_string()))?;
+        decoded = json::from_json(text)?;
+        &decoded
+    } else {
+        envelope
+    };
+    let envelope = Envelope::parse(envelope)?;
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        version = envelope.version,
+        aead = envelope.aead.as_str(),
+        recipients = envelope.recipients().len(),
+        expires = envelope.expires_at.is_some(),
+        "Parsed envelope"
+    );
+    // Checking before decrypting saves an RSA operation: a forged expiry fails authentication
+    // anyway.
+    if let (Some(now), Some(expires_at)) = (now, envelope.expires_at) {
+        if now >= expires_at {
+            return Err(EnvelopeError::Expired { expires_at });
+        }
+    }
+    let data_key =
+        Zeroizing::new(unwrap_key(envelope.wrapped_key_for(public_key)?)?);
+    if data_key.len() != DATA_KEY_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+    let nonce = envelope.nonce.try_into().expect("12 bytes");
+    envelope
+        .aead
+        .cipher(&data_key)
+        .ok_or(EnvelopeError::Malformed)?
+        .decrypt(
+            nonce,
+            Payload {
+                msg: envelope.ciphertext,
+                aad: envelope.header,
+            },
+        )
+        .map_err(|_| EnvelopeError::Authentication)
+}
+
+/// Rewraps an envelope sealed for the public key of `private_key` for `new_public_key`, without
+/// decrypting its payload (see the module documentation). An envelope in the JSON encoding of the
+/// [`json`] module is rewrapped into JSON.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope;
+///
+/// let old_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let new_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let sealed = envelope::seal(&old_key.to_public_key(), b"Hello, envelope!")
+///     .expect("Failed to seal");
+///
+/// let rewrapped = envelope::rewrap(&old_key, &new_key.to_public_key(), &sealed)
+///     .expect("Failed to rewrap");
+/// assert_eq!(envelope::open(&new_key, &rewrapped).unwrap(), b"Hello, envelope!");
+/// assert!(envelope::open(&old_key, &rewrapped).is_err());
+/// ```
+///
+/// # Errors
+///
+/// This function returns an error if the envelope is malformed, if `private_key` is not one of its
+/// recipients, if the data key cannot be decrypted with `private_key` or wrapped for
+/// `new_public_key`, or if the payload was tampered with.
+pub fn rewrap(
+    private_key: &RsaPrivateKey,
+    new_public_key: &RsaPublicKey,
+    envelope: &[u8],
+) -> Result<Vec<u8>, EnvelopeError> {
+    rewrap_with(
+        &mut OsRng,
+        envelope,
+        &private_key.to_public_key(),
+        new_public_key,
+        |wrapped_key| {
+            Ok(core::decrypt_with(
+                private_key,
+                OaepParams::SHA256,
+                wrapped_key,
+            )?)
+        },
+    )
+}
+
+/// Rewraps an envelope for `new_public_key`, unwrapping the data key wrapped for `public_key` with
+/// `unwrap_key`.
+pub(crate) fn rewrap_with<R: CryptoRngCore + ?Sized>(
+    rng: &mut R,
+    envelope: &[u8],
+    public_key: &RsaPublicKey,
+    new_public_key: &RsaPublicKey,
+    unwrap_key: impl FnOnce(&[u8]) -> Result<Vec<u8>, EnvelopeError>,
+) -> Result<Vec<u8>, EnvelopeError> {
+    #[cfg(feature = "io")]
+    if json::is_json(envelope) {
+        let text = std::str::from_utf8(envelope)
+            .map_err(|err| EnvelopeError::InvalidJson(err.to_string()))?;
+        let rewrapped = rewrap_with(
+            rng,
+            &json::from_json(text)?,
+            public_key,
+            new_public_key,
+            unwrap_key,
+        )?;
+        return Ok(json::to_json(&rewrapped)?.into_bytes());
+    }
+    let parsed = Envelope::parse(envelope)?;
+    let data_key = Zeroizing::new(unwrap_key(parsed.wrapped_key_for(public_key)?)?);
+    if data_key.len() != DATA_KEY_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+    let nonce: &[u8; NONCE_LEN] = parsed.nonce.try_into().expect("12 bytes");
+    let tag_start = parsed
+        .ciphertext
+        .len()
+        .checked_sub(TAG_LEN)
+        .ok_or(EnvelopeError::Malformed)?;
+    let (ciphertext, tag) = parsed.ciphertext.split_at(tag_start);
+    let expected =
+        payload_tag(parsed.aead, &data_key, nonce, parsed.header, ciphertext);
+    // Constant-time comparison, as for the tags checked by the ciphers themselves.
+    if expected
+        .iter()
+        .zip(tag)
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        != 0
+    {
+        return Err(EnvelopeError::Authentication);
+    }
+
+    // Replace the key the data key was unwrapped with, which is the first recipient of envelopes
+    // of versions 1 and 2, and keep the others.
+    let mut recipients = parsed
+        .recipients()
+        .iter()
+        .map(|recipient| (recipient.raw_key_id(), recipient.wrapped_key()))
+        .collect::<Vec<_>>();
+    let index = if parsed.version < VERSION_WITH_ESCROW {
+        0
+    } else {
+        let key_id = key_id(public_key)?;
+        recipients
+            .iter()
+            .position(|(id, _)| *id == Some(&key_id[..]))
+            .ok_or(EnvelopeError::NotARecipient)?
+    };
+    let new_key_id = key_id(new_public_key)?;
+    let new_wrapped_key =
+        core::encrypt_with_rng(rng, new_public_key, OaepParams::SHA256, &data_key)?;
+    recipients[index] = (Some(&new_key_id), &new_wrapped_key);
+    // Envelopes of version 1 have no key ID, and are upgraded to version 2.
+    let version = parsed.version.max(VERSION);
+    let expires_at = parsed.expires_at.map(|expires_at| {
+        expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs())
+    });
+    let mut rewrapped = encode_fields(
+        version,
+        parsed.aead,
+        &recipients,
+        expires_at,
+        nonce,
+        parsed.ciphertext,
+    )?;
+    let header_len = Envelope::parse(&rewrapped)?.header.len();
+    let tag = payload_tag(
+        parsed.aead,
+        &data_key,
+        nonce,
+        &rewrapped[..header_len],
+        ciphertext,
+    );
+    let tag_start = rewrapped.len() - TAG_LEN;
+    rewrapped[tag_start..].copy_from_slice(&tag);
+    Ok(rewrapped)
+}
+
+/// Computes the authentication tag of `ciphertext` (without its tag) and `aad` under `data_key`
+/// and `nonce`, as AES-256-GCM or ChaCha20-Poly1305 would, without decrypting `ciphertext`.
+fn payload_tag(
+    aead: Aead,
+    data_key: &[u8],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> [u8; TAG_LEN] {
+    let mut lengths = [0u8; TAG_LEN];
+    let tag = match aead {
+        Aead::Aes256Gcm => {
+            // GHASH is keyed with the encryption of the zero block, and its output masked with
+            // the encryption of the initial counter block: the nonce followed by 1.
+            let cipher = Aes256::new_from_slice(data_key).expect("32-byte key");
+            let mut hash_key = Default::default();
+            cipher.encrypt_block(&mut hash_key);
+            let mut mask = ghash::Block::default();
+            mask[..NONCE_LEN].copy_from_slice(nonce);
+            mask[TAG_LEN - 1] = 1;
+            cipher.encrypt_block(&mut mask);
+
+            let mut ghash = GHash::new(&hash_key);
+            ghash.update_padded(aad);
+            ghash.update_padded(ciphertext);
+            lengths[..8].copy_from_slice(&(aad.len() as u64 * 8).to_be_bytes());
+            lengths[8..]
+                .copy_from_slice(&(ciphertext.len() as u64 * 8).to_be_bytes());
+            ghash.update(&[lengths.into()]);
+            let mut tag = ghash.finalize();
+            tag.iter_mut()
+                .zip(mask)
+                .for_each(|(byte, mask)| *byte ^= mask);
+            tag
+        }
+        Aead::ChaCha20Poly1305 => {
+            // The Poly1305 key is the first 32 bytes of the keystream.
+            let mut mac_key = Zeroizing::new([0u8; poly1305::KEY_SIZE]);
+            ChaCha20::new(data_key.into(), nonce.into())
+                .apply_keystream(mac_key.as_mut());
+            let mut mac = Poly1305::new(mac_key.as_ref().into());
+            mac.update_padded(aad);
+            mac.update_padded(ciphertext);
+            lengths[..8].copy_from_slice(&(aad.len() as u64).to_le_bytes());
+            lengths[8..].copy_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+            mac.update(&[lengths.into()]);
+            mac.finalize()
+        }
+    };
+    tag.into()
+}
+
+/// The default number of envelopes sealed with one data key by a [`DataKeyCache`].
+pub const DEFAULT_MAX_USES: u64 = 1 << 20;
+
+/// The default lifetime of a data key cached by a [`DataKeyCache`].
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// The largest number of envelopes sealed with one data key. Nonces are random, so a data key must
+/// not encrypt more than 2^32 messages.
+pub const MAX_USES_LIMIT: u64 = 1 << 32;
+
+/// Seals envelopes reusing a data key for many payloads, to save RSA operations.
+///
+/// [`seal`] wraps a fresh data key with RSA-OAEP for every payload. A `DataKeyCache` wraps a data
+/// key once and reuses it, with a fresh nonce, until it has sealed `max_uses` envelopes or is
+/// older than `max_age`. The envelopes are in the usual format, and are opened with [`open`]; the
+/// envelopes sealed with the same data key share the same wrapped key.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::core;
+/// use e2ee::envelope::{self, DataKeyCache};
+/// use std::time::Duration;
+///
+/// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+/// let cache = DataKeyCache::new(private_key.to_public_key())
+///     .with_max_uses(10_000)
+///     .with_max_age(Duration::from_secs(60));
+/// let envelope = cache.seal(b"Hello, envelope!").expect("Failed to seal");
+/// assert_eq!(envelope::open(&private_key, &envelope).unwrap(), b"Hello, envelope!");
+/// ```
+pub struct DataKeyCache {
+    public_key: RsaPublicKey,
+    escrow_keys: Vec<RsaPublicKey>,
+    max_uses: u64,
+    max_age: Duration,
+    aead: Aead,
+    current: Mutex<Option<CachedDataKey>>,
+}
+
+struct CachedDataKey {
+    data_key: Zeroizing<[u8; DATA_KEY_LEN]>,
+    header: Vec<u8>,
+    created: Instant,
+    uses: u64,
+}
+
+impl fmt::Debug for DataKeyCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DataKeyCache")
+            .field("escrow_keys", &self.escrow_keys.len())
+            .field("max_uses", &self.max_uses)
+            .field("max_age", &self.max_age)
+            .field("aead", &self.aead)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DataKeyCache {
+    /// Creates a cache sealing envelopes for `public_key`, with [`DEFAULT_MAX_USES`] and
+    /// [`DEFAULT_MAX_AGE`].
+    pub fn new(public_key: RsaPublicKey) -> Self {
+        Self {
+            public_key,
+            escrow_keys: Vec::new(),
+            max_uses: DEFAULT_MAX_USES,
+            max_age: DEFAULT_MAX_AGE,
+            aead: Aead::default(),
+            current: Mutex::new(None),
+        }
+    }
+
+    /// Sets the number of envelopes sealed with one data key, at least 1 and at most
+    /// [`MAX_USES_LIMIT`].
+    pub fn with_max_uses(mut self, max_uses: u64) -> Self {
+        self.max_uses = max_uses.clamp(1, MAX_USES_LIMIT);
+        self
+    }
+
+    /// Sets the lifetime of a data key.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Adds an escrow key, for which every data key is also wrapped (see [`seal_with_escrow`]).
+    pub fn with_escrow_key(mut self, escrow_key: RsaPublicKey) -> Self {
+        self.escrow_keys.push(escrow_key);
+        self
+    }
+
+    /// Sets the cipher encrypting the payloads (see [`seal_with_aead`]).
+    pub fn with_aead(mut self, aead: Aead) -> Self {
+        self.aead = aead;
+        self
+    }
+
+    /// Encrypts `plaintext` into an envelope, with the cached data key if it can still be used, or
+    /// with a new one.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a new data key cannot be encrypted with the public key, or
+    /// if the key cannot be DER-encoded to compute its ID.
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+        let mut current = self.current.lock().unwrap_or_else(|err| err.into_inner());
+        let usable = current.as_ref().is_some_and(|cached| {
+            cached.uses < self.max_uses && cached.created.elapsed() < self.max_age
+        });
+        if !usable {
+            let mut data_key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+            OsRng.fill_bytes(data_key.as_mut());
+            let mut header = wrap_data_key(
+                &mut OsRng,
+                &data_key,
+                &self.public_key,
+                &self.escrow_keys,
+                None,
+                None,
+            )?;
+            header[0] = self.aead.mark_version(header[0]);
+            *current = Some(CachedDataKey {
+                data_key,
+                header,
+                created: Instant::now(),
+                uses: 0,
+            });
+        }
+        let cached = current.as_mut().expect("a data key was just cached");
+        cached.uses += 1;
+        seal_with_data_key(&mut OsRng, &cached.header, &cached.data_key, plaintext)
+    }
+
+    /// Discards the cached data key, so that the next envelope is sealed with a new one.
+    pub fn rotate(&self) {
+        *self.current.lock().unwrap_or_else(|err| err.into_inner()) = None;
+    }
+}
+
+/// Encodes `public_key` as the value of the [`CLIENT_KEY_HEADER`] header: its SPKI DER encoding
+/// in standard base64 without padding.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn encode_client_key(
+    public_key: &RsaPublicKey,
+) -> Result<String, EnvelopeError> {
+    let der = public_key
+        .to_public_key_der()
+        .map_err(|err| EnvelopeError::InvalidClientKey(err.to_string()))?;
+    Ok(general_purpose::STANDARD_NO_PAD.encode(der.as_bytes()))
+}
+
+/// Decodes and validates the value of a [`CLIENT_KEY_HEADER`] header.
+///
+/// # Errors
+///
+/// This function returns `EnvelopeError::InvalidClientKey` if the value is not a base64-encoded
+/// SPKI DER RSA public key, or if the key fails the validation applied to `PublicE2ee` keys.
+pub fn decode_client_key(value: &str) -> Result<RsaPublicKey, EnvelopeError> {
+    let invalid = |reason: String| EnvelopeError::InvalidClientKey(reason);
+    let der = general_purpose::STANDARD_NO_PAD
+        .decode(value.trim().trim_end_matches('='))
+        .map_err(|err| invalid(err.to_string()))?;
+    let public_key = RsaPublicKey::from_public_key_der(&der)
+        .map_err(|err| invalid(err.to_string()))?;
+    core::validate_public_key(public_key.n(), public_key.e()).map_err(invalid)?;
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        for plaintext in [&b""[..], b"Hello, envelope!", &[0x42; 100_000]] {
+            let envelope = seal(&public_key, plaintext).unwrap();
+            assert_eq!(envelope[0], VERSION);
+            assert_eq!(open(&private_key, &envelope).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn test_envelopes_carry_key_id() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let envelope = seal(&public_key, b"Hello, envelope!").unwrap();
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION);
+        assert_eq!(parsed.key_id(), Some(core::key_id(&public_key).unwrap()));
+        assert_eq!(parsed.wrapped_key().len(), 128);
+        let cached = DataKeyCache::new(public_key.clone())
+            .seal(b"cached")
+            .unwrap();
+        assert_eq!(Envelope::parse(&cached).unwrap().key_id(), parsed.key_id());
+
+        // The key ID is authenticated.
+        let mut tampered = envelope.clone();
+        tampered[1] ^= 1;
+        assert!(matches!(
+            open(&private_key, &tampered),
+            Err(EnvelopeError::Authentication)
+        ));
+
+        // Envelopes of version 1 have no key ID.
+        let mut legacy = vec![VERSION_WITHOUT_KEY_ID];
+        legacy.extend_from_slice(&envelope[1 + KEY_ID_LEN..3 + KEY_ID_LEN + 128]);
+        let data_key = core::decrypt_with(
+            &private_key,
+            OaepParams::SHA256,
+            parsed.wrapped_key(),
+        )
+        .unwrap();
+        let nonce = [7u8; NONCE_LEN];
+        let ciphertext = Aes256Gcm::new_from_slice(&data_key)
+            .unwrap()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: b"legacy",
+                    aad: &legacy,
+                },
+            )
+            .unwrap();
+        legacy.extend_from_slice(&nonce);
+        legacy.extend_from_slice(&ciphertext);
+        assert_eq!(Envelope::parse(&legacy).unwrap().key_id(), None);
+        assert_eq!(open(&private_key, &legacy).unwrap(), b"legacy");
+    }
+
+    #[test]
+    fn test_escrow_keys_can_open_envelopes() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let escrow_keys = [
+            core::generate_private_key(1024).unwrap(),
+            core::generate_private_key(1024).unwrap(),
+        ];
+        let escrow_public_keys = escrow_keys.clone().map(|key| key.to_public_key());
+        let envelope = seal_with_escrow(
+            &private_key.to_public_key(),
+            &escrow_public_keys,
+            b"recoverable",
+        )
+        .unwrap();
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION_WITH_ESCROW);
+        let recipients = parsed.recipients();
+        assert_eq!(
+            recipients
+                .iter()
+                .map(|recipient| (recipient.role(), recipient.key_id().unwrap()))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    RecipientRole::Recipient,
+                    core::key_id(&private_key.to_public_key()).unwrap()
+                ),
+                (
+                    RecipientRole::Escrow,
+                    core::key_id(&escrow_public_keys[0]).unwrap()
+                ),
+                (
+                    RecipientRole::Escrow,
+                    core::key_id(&escrow_public_keys[1]).unwrap()
+                ),
+            ]
+        );
+        assert_eq!(recipients[0].wrapped_key(), parsed.wrapped_key());
+        for key in [&private_key, &escrow_keys[0], &escrow_keys[1]] {
+            assert_eq!(open(key, &envelope).unwrap(), b"recoverable");
+        }
+        let stranger = core::generate_private_key(1024).unwrap();
+        assert!(matches!(
+            open(&stranger, &envelope),
+            Err(EnvelopeError::NotARecipient)
+        ));
+
+        // The escrow keys are authenticated, and cannot be stripped.
+        let mut stripped = vec![VERSION];
+        stripped.extend_from_slice(&envelope[1..3 + KEY_ID_LEN + 128]);
+        stripped.extend_from_slice(parsed.nonce);
+        stripped.extend_from_slice(parsed.ciphertext);
+        assert!(matches!(
+            open(&private_key, &stripped),
+            Err(EnvelopeError::Authentication)
+        ));
+        assert!(matches!(
+            Envelope::parse(&envelope[..parsed.header.len() - 1]),
+            Err(EnvelopeError::Malformed)
+        ));
+
+        let cache = DataKeyCache::new(private_key.to_public_key())
+            .with_escrow_key(escrow_public_keys[1].clone());
+        let cached = cache.seal(b"cached").unwrap();
+        assert_eq!(Envelope::parse(&cached).unwrap().recipients().len(), 2);
+        assert_eq!(open(&escrow_keys[1], &cached).unwrap(), b"cached");
+        assert_eq!(
+            Envelope::parse(&seal(&stranger.to_public_key(), b"").unwrap())
+                .unwrap()
+                .recipients()[0]
+                .role(),
+            RecipientRole::Recipient
+        );
+    }
+
+    #[test]
+    fn test_envelopes_for_several_recipients() {
+        let private_keys = [
+            core::generate_private_key(1024).unwrap(),
+            core::generate_private_key(1024).unwrap(),
+        ];
+        let public_keys = private_keys.clone().map(|key| key.to_public_key());
+        let envelope = seal_for_recipients(&public_keys, b"fan-out").unwrap();
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION_MULTI_RECIPIENT);
+        assert_eq!(
+            parsed
+                .recipients()
+                .iter()
+                .map(|recipient| (recipient.role(), recipient.key_id().unwrap()))
+                .collect::<Vec<_>>(),
+            public_keys
+                .iter()
+                .map(|key| (RecipientRole::Recipient, core::key_id(key).unwrap()))
+                .collect::<Vec<_>>()
+        );
+        for key in &private_keys {
+            assert_eq!(open(key, &envelope).unwrap(), b"fan-out");
+        }
+        let stranger = core::generate_private_key(1024).unwrap();
+        assert!(matches!(
+            open(&stranger, &envelope),
+            Err(EnvelopeError::NotARecipient)
+        ));
+
+        let single = seal_for_recipients(&public_keys[..1], b"single").unwrap();
+        assert_eq!(Envelope::parse(&single).unwrap().version(), VERSION);
+        assert!(matches!(
+            seal_for_recipients(&[], b""),
+            Err(EnvelopeError::RecipientCount(0))
+        ));
+    }
+
+    #[test]
+    fn test_envelopes_expire() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let escrow_key = core::generate_private_key(1024).unwrap();
+        let expires_at = UNIX_EPOCH + Duration::from_secs(2_000_000_000);
+        let envelope = seal_with_options(
+            &mut OsRng,
+            &private_key.to_public_key(),
+            &[escrow_key.to_public_key()],
+            Some(expires_at + Duration::from_millis(500)),
+            Aead::default(),
+            b"short-lived",
+        )
+        .unwrap();
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION_WITH_EXPIRY);
+        assert_eq!(parsed.expires_at(), Some(expires_at));
+        assert_eq!(parsed.recipients().len(), 2);
+
+        let before = expires_at - Duration::from_secs(1);
+        for key in [&private_key, &escrow_key] {
+            assert_eq!(
+                open_at(key, &envelope, Some(before)).unwrap(),
+                b"short-lived"
+            );
+            assert!(matches!(
+                open_at(key, &envelope, Some(expires_at)),
+                Err(EnvelopeError::Expired { expires_at: at }) if at == expires_at
+            ));
+        }
+        assert_eq!(
+            open_at(&private_key, &envelope, None).unwrap(),
+            b"short-lived"
+        );
+
+        // The expiry is authenticated.
+        let mut extended = envelope.clone();
+        extended[parsed.header.len() - 2] ^= 1;
+        assert!(matches!(
+            open_at(&private_key, &extended, None),
+            Err(EnvelopeError::Authentication)
+        ));
+
+        let expired = seal_with_expiry(
+            &private_key.to_public_key(),
+            SystemTime::now(),
+            b"gone",
+        )
+        .unwrap();
+        assert_eq!(Envelope::parse(&expired).unwrap().recipients().len(), 1);
+        assert!(matches!(
+            open(&private_key, &expired),
+            Err(EnvelopeError::Expired { .. })
+        ));
+
+        // An expiry beyond the range of `SystemTime` is malformed, not a panic.
+        let mut unbounded = envelope.clone();
+        let expiry = parsed.header.len() - EXPIRY_LEN..parsed.header.len();
+        unbounded[expiry].fill(0xff);
+        assert!(matches!(
+            Envelope::parse(&unbounded),
+            Err(EnvelopeError::Malformed)
+        ));
+        assert_eq!(
+            Envelope::parse(&seal(&private_key.to_public_key(), b"").unwrap())
+                .unwrap()
+                .expires_at(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_envelopes() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let envelope =
+            seal_with_aead(&public_key, Aead::ChaCha20Poly1305, b"Hello, ARM!")
+                .unwrap();
+        assert_eq!(envelope[0], VERSION | CHACHA20_POLY1305_FLAG);
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION);
+        assert_eq!(parsed.aead(), Aead::ChaCha20Poly1305);
+        assert_eq!(open(&private_key, &envelope).unwrap(), b"Hello, ARM!");
+
+        // The flag is authenticated: the payload cannot be opened with the other cipher.
+        let mut downgraded = envelope.clone();
+        downgraded[0] = VERSION;
+        assert!(matches!(
+            open(&private_key, &downgraded),
+            Err(EnvelopeError::Authentication)
+        ));
+
+        let cache = DataKeyCache::new(public_key.clone())
+            .with_escrow_key(public_key)
+            .with_aead(Aead::ChaCha20Poly1305);
+        let envelope = cache.seal(b"cached").unwrap();
+        let parsed = Envelope::parse(&envelope).unwrap();
+        assert_eq!(parsed.version(), VERSION_WITH_ESCROW);
+        assert_eq!(parsed.aead(), Aead::ChaCha20Poly1305);
+        assert_eq!(open(&private_key, &envelope).unwrap(), b"cached");
+    }
+
+    #[test]
+    fn test_open_rejects_tampering() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let envelope =
+            seal(&private_key.to_public_key(), b"Hello, envelope!").unwrap();
+
+        let mut tampered = envelope.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&private_key, &tampered),
+            Err(EnvelopeError::Authentication)
+        ));
+
+        let mut wrong_version = envelope.clone();
+        wrong_version[0] = 6;
+        assert!(matches!(
+            open(&private_key, &wrong_version),
+            Err(EnvelopeError::UnsupportedVersion(6))
+        ));
+        wrong_version[0] = VERSION_WITHOUT_KEY_ID | CHACHA20_POLY1305_FLAG;
+        assert!(matches!(
+            open(&private_key, &wrong_version),
+            Err(EnvelopeError::UnsupportedVersion(0x81))
+        ));
+
+        assert!(matches!(
+            open(&private_key, &envelope[..100]),
+            Err(EnvelopeError::Malformed)
+        ));
+        assert!(matches!(
+            open(&private_key, &[]),
+            Err(EnvelopeError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_rewrap_keeps_payload_and_other_recipients() {
+        let old_key = core::generate_private_key(1024).unwrap();
+        let new_key = core::generate_private_key(1024).unwrap();
+        let escrow_key = core::generate_private_key(1024).unwrap();
+        let expires_at = UNIX_EPOCH + Duration::from_secs(4_000_000_000);
+        for aead in [Aead::Aes256Gcm, Aead::ChaCha20Poly1305] {
+            let envelope = seal_with_options(
+                &mut OsRng,
+                &old_key.to_public_key(),
+                &[escrow_key.to_public_key()],
+                Some(expires_at),
+                aead,
+                b"archived",
+            )
+            .unwrap();
+            let rewrapped =
+                rewrap(&old_key, &new_key.to_public_key(), &envelope).unwrap();
+            let (before, after) = (
+                Envelope::parse(&envelope).unwrap(),
+                Envelope::parse(&rewrapped).unwrap(),
+            );
+            // Only the tag of the payload changed.
+            assert_eq!(after.nonce(), before.nonce());
+            let len = before.ciphertext().len() - TAG_LEN;
+            assert_eq!(after.ciphertext()[..len], before.ciphertext()[..len]);
+            assert_eq!(after.expires_at(), Some(expires_at));
+            assert_eq!(after.recipients()[1], before.recipients()[1]);
+
+            assert_eq!(open(&new_key, &rewrapped).unwrap(), b"archived");
+            assert_eq!(open(&escrow_key, &rewrapped).unwrap(), b"archived");
+            assert!(matches!(
+                open(&old_key, &rewrapped),
+                Err(EnvelopeError::NotARecipient)
+            ));
+
+            // A tampered payload is not rewrapped into a valid envelope.
+            let mut tampered = envelope.clone();
+            tampered[envelope.len() - TAG_LEN - 1] ^= 1;
+            assert!(matches!(
+                rewrap(&old_key, &new_key.to_public_key(), &tampered),
+                Err(EnvelopeError::Authentication)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_data_key_cache_reuses_and_renews_data_keys() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let cache = DataKeyCache::new(private_key.to_public_key()).with_max_uses(2);
+        let wrapped_key = |envelope: &[u8]| {
+            Envelope::parse(envelope).unwrap().wrapped_key.to_vec()
+        };
+
+        let first = cache.seal(b"first").unwrap();
+        let second = cache.seal(b"second").unwrap();
+        let third = cache.seal(b"third").unwrap();
+        assert_eq!(wrapped_key(&first), wrapped_key(&second));
+        assert_ne!(
+            Envelope::parse(&first).unwrap().nonce,
+            Envelope::parse(&second).unwrap().nonce
+        );
+        assert_ne!(wrapped_key(&second), wrapped_key(&third));
+        cache.rotate();
+        let fourth = cache.seal(b"fourth").unwrap();
+        assert_ne!(wrapped_key(&third), wrapped_key(&fourth));
+        for (envelope, plaintext) in [
+            (first, &b"first"[..]),
+            (second, b"second"),
+            (third, b"third"),
+            (fourth, b"fourth"),
+        ] {
+            assert_eq!(open(&private_key, &envelope).unwrap(), plaintext);
+        }
+
+        let cache = DataKeyCache::new(private_key.to_public_key())
+            .with_max_age(Duration::ZERO);
+        let first = cache.seal(b"first").unwrap();
+        let second = cache.seal(b"second").unwrap();
+        assert_ne!(wrapped_key(&first), wrapped_key(&second));
+    }
+
+    #[test]
+    fn test_client_key_header_roundtrip() {
+        let public_key = core::generate_private_key(1024).unwrap().to_public_key();
+        let value = encode_client_key(&public_key).unwrap();
+        assert_eq!(decode_client_key(&value).unwrap(), public_key);
+        assert!(matches!(
+            decode_client_key("not a key"),
+            Err(EnvelopeError::InvalidClientKey(_))
+        ));
+    }
+}
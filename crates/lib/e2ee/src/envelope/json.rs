@@ -0,0 +1,287 @@
+//! The JSON encoding of envelopes.
+//!
+//! This module is enabled by the `io` feature. Many APIs expect structured fields rather than one
+//! opaque blob: [`to_json`] converts an envelope into a JSON object with the fields of the
+//! flattened JWE serialization, and [`from_json`] converts it back, losslessly:
+//!
+//! ```text
+//! {
+//!   "v": 2,                       // the version of the binary format
+//!   "alg": "RSA-OAEP-256+A256GCM", // or "RSA-OAEP-256+C20P" for ChaCha20-Poly1305
+//!   "kid": "<hex key ID>",         // absent in envelopes of version 1
+//!   "ek": "<wrapped data key>",
+//!   "rcpt": [{"kid": "...", "ek": "..."}], // escrow keys or other recipients, version 3 and above
+//!   "exp": 1700000000,            // the expiry time, in seconds since the Unix epoch, version 4
+//!   "iv": "<nonce>",
+//!   "ct": "<ciphertext>",
+//!   "tag": "<authentication tag>"
+//! }
+//! ```
+//!
+//! Binary fields are encoded in unpadded base64url. The header of the binary envelope, which is
+//! authenticated as associated data, is rebuilt from the fields, so that tampering with any of them
+//! fails authentication. `envelope::open` and `E2ee::decrypt_envelope` accept both encodings, and
+//! `E2ee::with_envelope_format` and `PublicE2ee::with_envelope_format` produce JSON envelopes.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::envelope::{self, json};
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let sealed = envelope::seal(&private_key.to_public_key(), b"Hello, JSON!").expect("Failed to seal");
+//! let encoded = json::to_json(&sealed).expect("Failed to encode envelope");
+//! assert!(encoded.contains("\"alg\":\"RSA-OAEP-256+A256GCM\""));
+//!
+//! assert_eq!(json::from_json(&encoded).unwrap(), sealed);
+//! assert_eq!(envelope::open(&private_key, encoded.as_bytes()).unwrap(), b"Hello, JSON!");
+//! ```
+use super::{
+    encode_fields, Aead, Envelope, EnvelopeError, KEY_ID_LEN, NONCE_LEN,
+    VERSION_WITHOUT_KEY_ID, VERSION_WITH_ESCROW, VERSION_WITH_EXPIRY,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use serde_json::{json, Map, Value};
+use std::time::UNIX_EPOCH;
+
+/// The length of the authentication tag of both ciphers, in bytes.
+const TAG_LEN: usize = 16;
+
+/// The `alg` of envelopes encrypted with AES-256-GCM.
+pub const ALG_AES_256_GCM: &str = "RSA-OAEP-256+A256GCM";
+
+/// The `alg` of envelopes encrypted with ChaCha20-Poly1305.
+pub const ALG_CHACHA20_POLY1305: &str = "RSA-OAEP-256+C20P";
+
+/// Returns whether `envelope` is a JSON envelope rather than a binary one. The first byte of a
+/// binary envelope is its version, never `{`.
+pub fn is_json(envelope: &[u8]) -> bool {
+    envelope.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'{')
+}
+
+/// Converts a binary envelope into its JSON encoding.
+///
+/// # Errors
+///
+/// This function returns an error if `envelope` is malformed (see `Envelope::parse`).
+pub fn to_json(envelope: &[u8]) -> Result<String, EnvelopeError> {
+    let parsed = Envelope::parse(envelope)?;
+    if parsed.ciphertext.len() < TAG_LEN {
+        return Err(EnvelopeError::Malformed);
+    }
+    let (ciphertext, tag) = parsed
+        .ciphertext
+        .split_at(parsed.ciphertext.len() - TAG_LEN);
+    let mut object = Map::new();
+    object.insert("v".into(), parsed.version.into());
+    object.insert("alg".into(), alg(parsed.aead).into());
+    if let Some(key_id) = parsed.key_id() {
+        object.insert("kid".into(), key_id.into());
+    }
+    object.insert(
+        "ek".into(),
+        URL_SAFE_NO_PAD.encode(parsed.wrapped_key).into(),
+    );
+    if parsed.version >= VERSION_WITH_ESCROW {
+        let recipients = parsed.recipients()[1..]
+            .iter()
+            .map(|recipient| {
+                json!({
+                    "kid": recipient.key_id(),
+                    "ek": URL_SAFE_NO_PAD.encode(recipient.wrapped_key()),
+                })
+            })
+            .collect();
+        object.insert("rcpt".into(), Value::Array(recipients));
+    }
+    if let Some(expires_at) = parsed.expires_at {
+        let seconds = expires_at
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        object.insert("exp".into(), seconds.into());
+    }
+    object.insert("iv".into(), URL_SAFE_NO_PAD.encode(parsed.nonce).into());
+    object.insert("ct".into(), URL_SAFE_NO_PAD.encode(ciphertext).into());
+    object.insert("tag".into(), URL_SAFE_NO_PAD.encode(tag).into());
+    Ok(Value::Object(object).to_string())
+}
+
+/// Converts a JSON envelope produced by [`to_json`] back into its binary encoding.
+///
+/// # Errors
+///
+/// This function returns `EnvelopeError::InvalidJson` if the JSON is malformed or a field is
+/// missing or invalid, or an error if the rebuilt envelope is malformed.
+pub fn from_json(json: &str) -> Result<Vec<u8>, EnvelopeError> {
+    let value: Value = serde_json::from_str(json)
+        .map_err(|err| EnvelopeError::InvalidJson(err.to_string()))?;
+    let version = value
+        .get("v")
+        .and_then(Value::as_u64)
+        .and_then(|version| u8::try_from(version).ok())
+        .ok_or_else(|| invalid("missing or invalid v"))?;
+    let aead = match string_field(&value, "alg")? {
+        ALG_AES_256_GCM => Aead::Aes256Gcm,
+        ALG_CHACHA20_POLY1305 => Aead::ChaCha20Poly1305,
+        _ => return Err(invalid("unsupported alg")),
+    };
+
+    let mut recipients = vec![recipient(&value, version != VERSION_WITHOUT_KEY_ID)?];
+    if version >= VERSION_WITH_ESCROW {
+        for additional in value
+            .get("rcpt")
+            .and_then(Value::as_array)
+            .ok_or_else(|| invalid("missing or invalid rcpt"))?
+        {
+            recipients.push(recipient(additional, true)?);
+        }
+    }
+    let expires_at = match version {
+        VERSION_WITH_EXPIRY => Some(
+            value
+                .get("exp")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| invalid("missing or invalid exp"))?,
+        ),
+        _ => None,
+    };
+    let nonce = base64_field(&value, "iv")?;
+    if nonce.len() != NONCE_LEN {
+        return Err(invalid("invalid iv"));
+    }
+    let tag = base64_field(&value, "tag")?;
+    if tag.len() != TAG_LEN {
+        return Err(invalid("invalid tag"));
+    }
+    let ciphertext = [base64_field(&value, "ct")?, tag].concat();
+    let recipients: Vec<_> = recipients
+        .iter()
+        .map(|(key_id, wrapped_key)| (key_id.as_deref(), &wrapped_key[..]))
+        .collect();
+    encode_fields(version, aead, &recipients, expires_at, &nonce, &ciphertext)
+}
+
+fn alg(aead: Aead) -> &'static str {
+    match aead {
+        Aead::Aes256Gcm => ALG_AES_256_GCM,
+        Aead::ChaCha20Poly1305 => ALG_CHACHA20_POLY1305,
+    }
+}
+
+fn invalid(reason: &str) -> EnvelopeError {
+    EnvelopeError::InvalidJson(reason.to_string())
+}
+
+fn string_field<'a>(value: &'a Value, name: &str) -> Result<&'a str, EnvelopeError> {
+    value
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| invalid(&format!("missing or invalid {name}")))
+}
+
+fn base64_field(value: &Value, name: &str) -> Result<Vec<u8>, EnvelopeError> {
+    URL_SAFE_NO_PAD
+        .decode(string_field(value, name)?)
+        .map_err(|_| invalid(&format!("invalid base64url in {name}")))
+}
+
+/// Returns the key ID, if `with_key_id`, and the wrapped key of a recipient.
+fn recipient(
+    value: &Value,
+    with_key_id: bool,
+) -> Result<(Option<Vec<u8>>, Vec<u8>), EnvelopeError> {
+    let key_id = if with_key_id {
+        let key_id = string_field(value, "kid")?;
+        if key_id.len() != 2 * KEY_ID_LEN
+            || !key_id.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(invalid("invalid kid"));
+        }
+        Some(decode_hex(key_id))
+    } else {
+        None
+    };
+    Ok((key_id, base64_field(value, "ek")?))
+}
+
+/// Decodes a string of hex digits, already checked by the caller.
+fn decode_hex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("hex digits"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+    use crate::envelope::{self, seal_with_options};
+    use rsa::rand_core::OsRng;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_json_roundtrip_for_every_version() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let escrow_key = core::generate_private_key(1024).unwrap();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        for (escrow_keys, expires_at, aead) in [
+            (vec![], None, Aead::Aes256Gcm),
+            (
+                vec![escrow_key.to_public_key()],
+                None,
+                Aead::ChaCha20Poly1305,
+            ),
+            (vec![], Some(expires_at), Aead::Aes256Gcm),
+        ] {
+            let sealed = seal_with_options(
+                &mut OsRng,
+                &public_key,
+                &escrow_keys,
+                expires_at,
+                aead,
+                b"payload",
+            )
+            .unwrap();
+            let encoded = to_json(&sealed).unwrap();
+            assert!(is_json(encoded.as_bytes()));
+            assert!(!is_json(&sealed));
+            assert_eq!(from_json(&encoded).unwrap(), sealed);
+            assert_eq!(
+                envelope::open(&private_key, encoded.as_bytes()).unwrap(),
+                b"payload"
+            );
+        }
+    }
+
+    #[test]
+    fn test_tampered_json_is_rejected() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let sealed =
+            envelope::seal(&private_key.to_public_key(), b"payload").unwrap();
+        let mut value: Value =
+            serde_json::from_str(&to_json(&sealed).unwrap()).unwrap();
+
+        value["alg"] = ALG_CHACHA20_POLY1305.into();
+        assert!(matches!(
+            envelope::open(&private_key, value.to_string().as_bytes()),
+            Err(EnvelopeError::Authentication)
+        ));
+        value["alg"] = "none".into();
+        assert!(matches!(
+            from_json(&value.to_string()),
+            Err(EnvelopeError::InvalidJson(_))
+        ));
+        value["alg"] = ALG_AES_256_GCM.into();
+        value["kid"] = "zz".into();
+        assert!(matches!(
+            from_json(&value.to_string()),
+            Err(EnvelopeError::InvalidJson(_))
+        ));
+        value.as_object_mut().unwrap().remove("kid");
+        assert!(from_json(&value.to_string()).is_err());
+        assert!(from_json("not json").is_err());
+    }
+}
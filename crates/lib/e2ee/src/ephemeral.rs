@@ -0,0 +1,374 @@
+//! Forward-secret one-shot encryption with ephemeral X25519 keys.
+//!
+//! This module is enabled by the `session` feature. RSA keys cannot take part in a Diffie-Hellman
+//! exchange, so each recipient publishes an X25519 [`CompanionKey`], certified by a signature of
+//! its RSA identity key (a [`SignedCompanionKey`]). [`encrypt_ephemeral`] checks that certificate,
+//! generates a one-shot X25519 key pair, derives an AES-256-GCM key from the exchange with the
+//! companion key, and sends the ephemeral public key with the ciphertext. The ephemeral secret is
+//! dropped right away, so compromising the sender later reveals nothing about the messages it
+//! sent. Recipients that rotate their companion key and destroy the old one also protect the
+//! messages received before the rotation.
+//!
+//! A message is laid out as:
+//!
+//! ```text
+//! version (1 byte) | companion key ID (8 bytes) | ephemeral public key (32 bytes) |
+//! AES-256-GCM ciphertext and tag
+//! ```
+//!
+//! The companion key ID is the first 8 bytes of the SHA-256 digest of the companion public key, so
+//! that a recipient holding several companion keys can pick the right one; the header is
+//! authenticated as associated data.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::ephemeral::{self, CompanionKey, SignedCompanionKey};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let bob = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let companion = CompanionKey::generate();
+//! let published = companion.certify(&bob).expect("Failed to sign companion key").to_bytes();
+//!
+//! let signed = SignedCompanionKey::from_bytes(&published).expect("Malformed companion key");
+//! let message = ephemeral::encrypt_ephemeral(bob.get_public_key(), &signed, b"Hello, Bob!")
+//!     .expect("Failed to encrypt");
+//! let decrypted = ephemeral::decrypt_ephemeral(&companion, &message).expect("Failed to decrypt");
+//! assert_eq!(decrypted, b"Hello, Bob!");
+//! ```
+use crate::core;
+use crate::kdf::Kdf;
+use crate::server::{E2ee, E2eeError};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use rsa::{
+    rand_core::OsRng,
+    sha2::{Digest, Sha256},
+    RsaPublicKey,
+};
+use std::fmt;
+use thiserror::Error;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+/// The version of the formats of signed companion keys and messages.
+pub const VERSION: u8 = 1;
+
+/// The length of companion key IDs, in bytes.
+pub const KEY_ID_LEN: usize = 8;
+
+const CERTIFICATE_CONTEXT: &[u8] = b"e2ee-companion-key-v1";
+const KDF_INFO: &[u8] = b"e2ee-ephemeral-v1";
+const HEADER_LEN: usize = 1 + KEY_ID_LEN + 32;
+
+/// An error returned when certifying companion keys or exchanging messages.
+#[derive(Error, Debug)]
+pub enum EphemeralError {
+    #[error("Signature error: {0}")]
+    Signature(#[from] E2eeError),
+
+    #[error("The companion key is not signed by the recipient's identity")]
+    InvalidSignature,
+
+    #[error("Malformed companion key or message")]
+    Malformed,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The message was encrypted for companion key {0}")]
+    UnknownKey(String),
+
+    #[error("Weak X25519 key")]
+    WeakKey,
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+}
+
+/// The X25519 secret key of a recipient, published as a [`SignedCompanionKey`].
+pub struct CompanionKey {
+    secret: StaticSecret,
+}
+
+impl fmt::Debug for CompanionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompanionKey")
+            .field("key_id", &core::hex(&self.key_id()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl CompanionKey {
+    /// Generates a random companion key.
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Restores a companion key saved with [`CompanionKey::to_bytes`].
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self {
+            secret: StaticSecret::from(bytes),
+        }
+    }
+
+    /// Returns the secret key, to be stored as carefully as the RSA private key.
+    pub fn to_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// Returns the X25519 public key.
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Returns the ID of the key, embedded in the messages encrypted for it.
+    pub fn key_id(&self) -> [u8; KEY_ID_LEN] {
+        key_id(&self.public_key())
+    }
+
+    /// Signs the public key with the RSA key of `identity`, to be published.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing fails.
+    pub fn certify(
+        &self,
+        identity: &E2ee,
+    ) -> Result<SignedCompanionKey, EphemeralError> {
+        let public_key = self.public_key();
+        let signature =
+            identity.sign(&[CERTIFICATE_CONTEXT, &public_key].concat())?;
+        Ok(SignedCompanionKey {
+            public_key,
+            signature,
+        })
+    }
+}
+
+/// A companion public key signed by the RSA identity key of its owner.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedCompanionKey {
+    public_key: [u8; 32],
+    signature: Vec<u8>,
+}
+
+impl SignedCompanionKey {
+    /// Returns the X25519 public key.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public_key
+    }
+
+    /// Checks that the key was signed by `identity`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `EphemeralError::InvalidSignature` if it was not.
+    pub fn verify(&self, identity: &RsaPublicKey) -> Result<(), EphemeralError> {
+        core::verify(
+            identity,
+            &[CERTIFICATE_CONTEXT, &self.public_key].concat(),
+            &self.signature,
+        )
+        .map_err(|_| EphemeralError::InvalidSignature)
+    }
+
+    /// Encodes the key as `version | public key (32) | u16 BE signature length | signature`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(35 + self.signature.len());
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.public_key);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Decodes a key encoded with [`SignedCompanionKey::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key is malformed or of another version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EphemeralError> {
+        let (&version, rest) =
+            bytes.split_first().ok_or(EphemeralError::Malformed)?;
+        if version != VERSION {
+            return Err(EphemeralError::UnsupportedVersion(version));
+        }
+        if rest.len() < 34 {
+            return Err(EphemeralError::Malformed);
+        }
+        let (public_key, rest) = rest.split_at(32);
+        let (len, signature) = rest.split_at(2);
+        if usize::from(u16::from_be_bytes([len[0], len[1]])) != signature.len() {
+            return Err(EphemeralError::Malformed);
+        }
+        Ok(Self {
+            public_key: public_key.try_into().expect("32 bytes were split off"),
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+/// Encrypts `plaintext` for the owner of `recipient`, with a one-shot X25519 key pair and the
+/// companion key `companion`, after checking that `recipient` signed it.
+///
+/// # Errors
+///
+/// This function returns `EphemeralError::InvalidSignature` if `companion` was not signed by
+/// `recipient`, or an error if the key exchange or encryption fails.
+pub fn encrypt_ephemeral(
+    recipient: &RsaPublicKey,
+    companion: &SignedCompanionKey,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, EphemeralError> {
+    companion.verify(recipient)?;
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(companion.public_key));
+    if !shared.was_contributory() {
+        return Err(EphemeralError::WeakKey);
+    }
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.push(VERSION);
+    header.extend_from_slice(&key_id(&companion.public_key));
+    header.extend_from_slice(&ephemeral_public);
+    let (cipher, nonce) =
+        message_cipher(shared.as_bytes(), &ephemeral_public, &companion.public_key);
+    let ciphertext = cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &header,
+            },
+        )
+        .map_err(|_| EphemeralError::DecryptionFailed)?;
+    Ok([header, ciphertext].concat())
+}
+
+/// Decrypts a message produced by [`encrypt_ephemeral`] with the companion key it was encrypted
+/// for.
+///
+/// # Errors
+///
+/// This function returns `EphemeralError::UnknownKey` if the message was encrypted for another
+/// companion key, `EphemeralError::DecryptionFailed` if it was tampered with, or an error if it
+/// is malformed.
+pub fn decrypt_ephemeral(
+    companion: &CompanionKey,
+    message: &[u8],
+) -> Result<Vec<u8>, EphemeralError> {
+    if message.len() < HEADER_LEN {
+        return Err(EphemeralError::Malformed);
+    }
+    let (header, ciphertext) = message.split_at(HEADER_LEN);
+    if header[0] != VERSION {
+        return Err(EphemeralError::UnsupportedVersion(header[0]));
+    }
+    if header[1..1 + KEY_ID_LEN] != companion.key_id() {
+        return Err(EphemeralError::UnknownKey(core::hex(
+            &header[1..1 + KEY_ID_LEN],
+        )));
+    }
+    let ephemeral_public: [u8; 32] =
+        header[1 + KEY_ID_LEN..].try_into().expect("32 bytes");
+    let shared = companion
+        .secret
+        .diffie_hellman(&PublicKey::from(ephemeral_public));
+    if !shared.was_contributory() {
+        return Err(EphemeralError::WeakKey);
+    }
+
+    let (cipher, nonce) = message_cipher(
+        shared.as_bytes(),
+        &ephemeral_public,
+        &companion.public_key(),
+    );
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| EphemeralError::DecryptionFailed)
+}
+
+fn key_id(public_key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+    Sha256::digest(public_key)[..KEY_ID_LEN]
+        .try_into()
+        .expect("8 bytes")
+}
+
+/// Derives the AES-256-GCM key and nonce of a message from the shared secret of its key exchange.
+/// Each ephemeral key encrypts a single message, so the nonce may be derived too.
+fn message_cipher(
+    shared: &[u8; 32],
+    ephemeral_public: &[u8; 32],
+    companion_public: &[u8; 32],
+) -> (Aes256Gcm, [u8; 12]) {
+    let mut output = [0; 44];
+    Kdf::extract(
+        Some(&[*ephemeral_public, *companion_public].concat()),
+        shared,
+    )
+    .expand(KDF_INFO, &mut output)
+    .expect("44 bytes is a valid HKDF output length");
+    let cipher = Aes256Gcm::new_from_slice(&output[..32]).expect("32-byte key");
+    (cipher, output[32..].try_into().expect("12 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_ephemeral_roundtrip() {
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let companion = CompanionKey::generate();
+        let signed = SignedCompanionKey::from_bytes(
+            &companion.certify(&bob).unwrap().to_bytes(),
+        )
+        .unwrap();
+
+        let first =
+            encrypt_ephemeral(bob.get_public_key(), &signed, b"Hello").unwrap();
+        let second =
+            encrypt_ephemeral(bob.get_public_key(), &signed, b"Hello").unwrap();
+        // Every message has its own ephemeral key.
+        assert_ne!(first[..HEADER_LEN], second[..HEADER_LEN]);
+        assert_eq!(decrypt_ephemeral(&companion, &first).unwrap(), b"Hello");
+
+        let restored = CompanionKey::from_bytes(companion.to_bytes());
+        assert_eq!(decrypt_ephemeral(&restored, &second).unwrap(), b"Hello");
+
+        let mut tampered = first.clone();
+        tampered[HEADER_LEN - 1] ^= 1;
+        assert!(decrypt_ephemeral(&companion, &tampered).is_err());
+        assert!(matches!(
+            decrypt_ephemeral(&CompanionKey::generate(), &first),
+            Err(EphemeralError::UnknownKey(_))
+        ));
+        assert!(matches!(
+            decrypt_ephemeral(&companion, &first[..10]),
+            Err(EphemeralError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_companion_key_must_be_signed_by_recipient() {
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let mallory = E2ee::new(KeySize::Bit1024).unwrap();
+        let forged = CompanionKey::generate().certify(&mallory).unwrap();
+        assert!(matches!(
+            encrypt_ephemeral(bob.get_public_key(), &forged, b"Hello"),
+            Err(EphemeralError::InvalidSignature)
+        ));
+    }
+}
@@ -11,195 +11,1710 @@
 /// undefined behavior. Additionally, the caller must free any allocated memory using the appropriate functions
 /// provided in this module (e.g., `e2ee_server_free`, `e2ee_client_free`).
 ///
+/// # Thread safety
+///
+/// Every function is reentrant: none of them keeps global state (the seed set for tests below is
+/// per thread), and the callbacks they take may
+/// call back into this module. What can be shared between threads depends on the handle:
+///
+/// - `E2ee`, `PublicE2ee` and `CancellationToken` handles can be used from several threads at
+///   once, e.g. a single server decrypting on a thread pool.
+/// - `Keystore`, `Handshake` and `Session` handles and the contexts of `e2ee_stream_*` functions
+///   are mutated by the functions taking them: they can move between threads, but must not be used
+///   from several threads at once.
+/// - Strings and buffers returned by these functions belong to the caller, and can be freed from
+///   any thread.
+///
+/// With the `ffi-testing` feature, `e2ee_set_rng_seed_for_testing` makes the instances created by
+/// the calling thread draw from a seeded generator, so that host-language test suites can compare
+/// keys and ciphertexts with golden files. It must never be enabled in production builds.
+///
 /// # Functions
 ///
 /// - `e2ee_server_new`: Creates a new `E2ee` instance with a specified key size.
+/// - `e2ee_server_new_with_progress`: Creates a new `E2ee` instance, reporting the progress of
+///   the key generation and allowing to cancel it.
+/// - `e2ee_cancellation_token_new`, `e2ee_cancellation_token_cancel` and
+///   `e2ee_cancellation_token_free`: Manage the token cancelling a key generation.
 /// - `e2ee_server_new_from_pem`: Creates a new `E2ee` instance from provided PEM-encoded keys.
 /// - `e2ee_client_new_from_public_pem`: Creates a new `PublicE2ee` instance from a PEM-encoded public key.
 /// - `e2ee_server_encrypt`: Encrypts a message using the server's public key.
 /// - `e2ee_client_encrypt`: Encrypts a message using the client's public key.
 /// - `e2ee_server_decrypt`: Decrypts a message using the server's private key.
+/// - `e2ee_server_decrypt_into`: Decrypts a message into a buffer provided by the caller.
+/// - `e2ee_encrypt_file` and `e2ee_decrypt_file`: Encrypt and decrypt files as streams, reporting
+///   their progress.
+/// - `e2ee_encrypt_hybrid_bytes` and `e2ee_decrypt_hybrid_bytes`: Encrypt and decrypt binary
+///   payloads of any size into envelopes, freed with `e2ee_free_bytes`.
+/// - `e2ee_stream_encrypt_begin`, `e2ee_stream_encrypt_update` and `e2ee_stream_encrypt_finish`:
+///   Encrypt a stream fed piece by piece, in constant memory. `e2ee_stream_encrypt_free`
+///   abandons it.
+/// - `e2ee_stream_decrypt_begin`, `e2ee_stream_decrypt_update`, `e2ee_stream_decrypt_finish` and
+///   `e2ee_stream_decrypt_free`: Decrypt such a stream.
+/// - `e2ee_keystore_create`, `e2ee_keystore_open` and `e2ee_keystore_free`: Manage a `Keystore`
+///   of named private keys, when the `keystore` feature is enabled.
+/// - `e2ee_keystore_create_in_storage` and `e2ee_keystore_open_in_storage`: Keep a `Keystore`
+///   in a storage backend implemented by the host with `E2eeStorageBackend` callbacks, e.g. over
+///   SQLite or the Keychain.
+/// - `e2ee_keystore_list`, `e2ee_keystore_generate` and `e2ee_keystore_remove`: List, generate
+///   and remove stored keys.
+/// - `e2ee_keystore_server` and `e2ee_keystore_client`: Create instances bound to a stored key.
+/// - `e2ee_session_new_initiator`, `e2ee_session_complete` and `e2ee_session_new_responder`:
+///   Establish a `Session` with the handshake of the `session` module, when the `session` feature
+///   is enabled.
+/// - `e2ee_session_encrypt` and `e2ee_session_decrypt`: Exchange messages over a session.
+/// - `e2ee_session_serialize` and `e2ee_session_deserialize`: Save and resume a session.
+/// - `e2ee_handshake_free` and `e2ee_session_free`: Free pending handshakes and sessions.
+/// - `e2ee_set_rng_seed_for_testing` and `e2ee_clear_rng_seed_for_testing`: Seed the generator
+///   of the instances created by the calling thread, when the `ffi-testing` feature is enabled.
 /// - `e2ee_server_get_public_key_pem`: Retrieves the PEM-encoded public key from the server.
 /// - `e2ee_server_get_private_key_pem`: Retrieves the PEM-encoded private key from the server.
 /// - `e2ee_server_free`: Frees the memory associated with an `E2ee` instance.
 /// - `e2ee_client_free`: Frees the memory associated with a `PublicE2ee` instance.
 /// - `e2ee_server_free_string`: Frees memory associated with a C string.
 use crate::client::PublicE2ee;
-use crate::server::{E2ee, KeySize};
-use std::ffi::{CStr, CString};
+use crate::keygen::{CancellationToken, KeyGenerator};
+#[cfg(feature = "keystore")]
+use crate::keystore::Keystore;
+#[cfg(feature = "keystore")]
+use crate::password::PasswordKdf;
+use crate::server::{E2ee, E2eeError, KeySize};
+#[cfg(feature = "session")]
+use crate::session::{Handshake, HandshakeMessage, Session};
+#[cfg(feature = "keystore")]
+use crate::storage::{Batch, Operation, StorageBackend};
+use crate::stream;
+#[cfg(feature = "ffi-testing")]
+use rand_chacha::{
+    rand_core::{RngCore, SeedableRng},
+    ChaCha20Rng,
+};
+#[cfg(feature = "ffi-testing")]
+use std::cell::RefCell;
+use std::ffi::{c_void, CStr, CString};
+use std::io::Write;
 use std::os::raw::{c_char, c_int};
+use zeroize::Zeroize;
+
+/// A callback receiving the progress of a key generation: the number of primes found, the
+/// number of primes needed, the number of candidates tested so far, and the `user_data` pointer.
+pub type E2eeKeygenProgressCallback = extern "C" fn(c_int, c_int, u64, *mut c_void);
+
+/// A callback receiving the progress of a file operation: the number of bytes of the input file
+/// read so far, its length, and the `user_data` pointer.
+pub type E2eeProgressCallback = extern "C" fn(u64, u64, *mut c_void);
+
+#[cfg(feature = "ffi-testing")]
+thread_local! {
+    /// The generator seeded by `e2ee_set_rng_seed_for_testing` on this thread.
+    static TEST_RNG: RefCell<Option<ChaCha20Rng>> = const { RefCell::new(None) };
+}
+
+/// The generator given to instances when a test seed is set.
+#[cfg(feature = "ffi-testing")]
+type InstanceRng = ChaCha20Rng;
+#[cfg(not(feature = "ffi-testing"))]
+type InstanceRng = rsa::rand_core::OsRng;
+
+/// Returns the generator of a new instance, drawn from the one seeded on this thread by
+/// `e2ee_set_rng_seed_for_testing`, or `None` to keep the default one.
+fn instance_rng() -> Option<InstanceRng> {
+    #[cfg(feature = "ffi-testing")]
+    return TEST_RNG.with_borrow_mut(|rng| {
+        rng.as_mut().map(|rng| {
+            let mut seed = [0u8; 32];
+            rng.fill_bytes(&mut seed);
+            ChaCha20Rng::from_seed(seed)
+        })
+    });
+    #[cfg(not(feature = "ffi-testing"))]
+    None
+}
+
+fn with_instance_rng(e2ee: E2ee) -> E2ee {
+    match instance_rng() {
+        Some(rng) => e2ee.with_rng(rng),
+        None => e2ee,
+    }
+}
+
+fn with_instance_rng_public(e2ee: PublicE2ee) -> PublicE2ee {
+    match instance_rng() {
+        Some(rng) => e2ee.with_rng(rng),
+        None => e2ee,
+    }
+}
+
+fn key_size_from_c(key_size: c_int) -> Option<KeySize> {
+    match key_size {
+        1024 => Some(KeySize::Bit1024),
+        2048 => Some(KeySize::Bit2048),
+        3072 => Some(KeySize::Bit3072),
+        4096 => Some(KeySize::Bit4096),
+        _ => None,
+    }
+}
+
+/// Creates a new `E2ee` instance with the specified RSA key size.
+///
+/// # Arguments
+///
+/// * `key_size` - The RSA key size (1024, 2048, 3072, 4096).
+///
+/// # Returns
+///
+/// Returns a pointer to the newly created `E2ee` instance. Returns a null pointer if an invalid key size is specified or an error occurs during instantiation.
+///
+/// # Safety
+///
+/// This function is safe as long as the `key_size` parameter is a valid RSA key size.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub extern "C" fn e2ee_server_new(key_size: c_int) -> *mut E2ee {
+    let Some(key_size) = key_size_from_c(key_size) else {
+        return std::ptr::null_mut(); // Invalid key size
+    };
+    let e2ee = match instance_rng() {
+        Some(rng) => E2ee::new_with_rng(key_size, rng),
+        None => E2ee::new(key_size),
+    };
+    match e2ee {
+        Ok(sdk) => Box::into_raw(Box::new(sdk)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Creates a new `E2ee` instance with the specified RSA key size, reporting the progress of the
+/// key generation and aborting it when `cancellation_token` is cancelled.
+///
+/// The generation blocks the calling thread: call this function from a background thread, and
+/// cancel the token from another one (e.g. the UI thread).
+///
+/// # Arguments
+///
+/// * `key_size` - The RSA key size (1024, 2048, 3072, 4096).
+/// * `progress` - A callback receiving the progress, or null. It is called from the generating
+///   thread, each time a prime is found and every 64 candidates.
+/// * `user_data` - A pointer passed back to `progress`.
+/// * `cancellation_token` - A token created with `e2ee_cancellation_token_new`, or null.
+///
+/// # Returns
+///
+/// Returns a pointer to the newly created `E2ee` instance. Returns a null pointer if an invalid key size is specified,
+/// if the generation was cancelled or if an error occurs during instantiation.
+///
+/// # Safety
+///
+/// `cancellation_token` must be null or a valid token that is not freed before this function returns. `user_data`
+/// must remain valid until this function returns.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_server_new_with_progress(
+    key_size: c_int,
+    progress: Option<E2eeKeygenProgressCallback>,
+    user_data: *mut c_void,
+    cancellation_token: *const CancellationToken,
+) -> *mut E2ee {
+    /// The `user_data` pointer, owned by the caller.
+    struct UserData(*mut c_void);
+    // SAFETY: the pointer is only handed back to the caller's callback.
+    unsafe impl Send for UserData {}
+    unsafe impl Sync for UserData {}
+
+    let Some(key_size) = key_size_from_c(key_size) else {
+        return std::ptr::null_mut(); // Invalid key size
+    };
+    let mut generator = KeyGenerator::new(key_size as usize);
+    if let Some(rng) = instance_rng() {
+        generator = generator.with_rng(rng);
+    }
+    if let Some(progress) = progress {
+        let user_data = UserData(user_data);
+        generator = generator.with_progress(move |report| {
+            let user_data = &user_data;
+            progress(
+                report.primes_found as c_int,
+                report.primes_needed as c_int,
+                report.candidates_tested,
+                user_data.0,
+            )
+        });
+    }
+    if let Some(token) = unsafe { cancellation_token.as_ref() } {
+        generator = generator.with_cancellation(token.clone());
+    }
+    match generator.generate() {
+        Ok(private_key) => match E2ee::from_private_key(private_key) {
+            Ok(sdk) => Box::into_raw(Box::new(with_instance_rng(sdk))),
+            Err(_) => std::ptr::null_mut(),
+        },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Creates a token cancelling key generations, to pass to `e2ee_server_new_with_progress`.
+///
+/// # Returns
+///
+/// Returns a pointer to the token, to free with `e2ee_cancellation_token_free`.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub extern "C" fn e2ee_cancellation_token_new() -> *mut CancellationToken {
+    Box::into_raw(Box::new(CancellationToken::new()))
+}
+
+/// Cancels the key generations using `cancellation_token`. This function can be called from any
+/// thread.
+///
+/// # Safety
+///
+/// The `cancellation_token` pointer must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_cancellation_token_cancel(
+    cancellation_token: *const CancellationToken,
+) {
+    unsafe { &*cancellation_token }.cancel();
+}
+
+/// Frees a token created with `e2ee_cancellation_token_new`.
+///
+/// # Safety
+///
+/// The pointer must have been returned by `e2ee_cancellation_token_new`, must not be used by a running generation,
+/// and must only be freed once.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_cancellation_token_free(
+    cancellation_token: *mut CancellationToken,
+) {
+    if !cancellation_token.is_null() {
+        unsafe {
+            drop(Box::from_raw(cancellation_token));
+        }
+    }
+}
+
+/// Creates a new `E2ee` instance from PEM-encoded private and public keys.
+///
+/// # Arguments
+///
+/// * `private_key_pem` - A pointer to a C string containing the PEM-encoded private key.
+/// * `public_key_pem` - A pointer to a C string containing the PEM-encoded public key.
+///
+/// # Returns
+///
+/// Returns a pointer to the newly created `E2ee` instance. Returns a null pointer if an error occurs during instantiation.
+///
+/// # Safety
+///
+/// The provided pointers must be valid C strings. The function assumes they are non-null and contain valid UTF-8 data.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_server_new_from_pem(
+    private_key_pem: *const c_char,
+    public_key_pem: *const c_char,
+) -> *mut E2ee {
+    let (Ok(private_key), Ok(public_key)) = (
+        unsafe { CStr::from_ptr(private_key_pem) }.to_str(),
+        unsafe { CStr::from_ptr(public_key_pem) }.to_str(),
+    ) else {
+        return std::ptr::null_mut();
+    };
+
+    match E2ee::new_from_pem(private_key.to_string(), public_key.to_string()) {
+        Ok(e2ee) => Box::into_raw(Box::new(with_instance_rng(e2ee))),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Creates a new `PublicE2ee` instance from a PEM-encoded public key.
+///
+/// # Arguments
+///
+/// * `public_key` - A pointer to a C string containing the PEM-encoded public key.
+///
+/// # Returns
+///
+/// Returns a pointer to the newly created `PublicE2ee` instance. Returns a null pointer if an error occurs during instantiation.
+///
+/// # Safety
+///
+/// The `public_key` pointer must be a valid C string. The function assumes the provided pointer is non-null and contains valid UTF-8 data.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_client_new_from_public_pem(
+    public_key: *const c_char,
+) -> *mut PublicE2ee {
+    let Ok(public_key) = unsafe { CStr::from_ptr(public_key) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    match PublicE2ee::new(public_key.to_string()) {
+        Ok(e2ee) => Box::into_raw(Box::new(with_instance_rng_public(e2ee))),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Encrypts a message using the server's public key.
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+/// * `message` - A pointer to a C string containing the plaintext message.
+///
+/// # Returns
+///
+/// Returns a pointer to a C string containing the base64-encoded encrypted message. Returns a null pointer if encryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_server` and `message` pointers must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_server_encrypt(
+    e2ee_server: *mut E2ee,
+    message: *const c_char,
+) -> *mut c_char {
+    let e2ee_server = unsafe { &*e2ee_server };
+    let Ok(message) = unsafe { CStr::from_ptr(message) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    match e2ee_server.encrypt(message) {
+        Ok(encrypted) => {
+            CString::new(encrypted).map_or(std::ptr::null_mut(), CString::into_raw)
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Encrypts a message using the client's public key.
+///
+/// # Arguments
+///
+/// * `e2ee_client` - A pointer to a `PublicE2ee` instance.
+/// * `message` - A pointer to a C string containing the plaintext message.
+///
+/// # Returns
+///
+/// Returns a pointer to a C string containing the base64-encoded encrypted message. Returns a null pointer if encryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_client` and `message` pointers must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_client_encrypt(
+    e2ee_client: *mut PublicE2ee,
+    message: *const c_char,
+) -> *mut c_char {
+    let e2ee_client = unsafe { &*e2ee_client };
+    let Ok(message) = unsafe { CStr::from_ptr(message) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    match e2ee_client.encrypt(message) {
+        Ok(encrypted) => {
+            CString::new(encrypted).map_or(std::ptr::null_mut(), CString::into_raw)
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Decrypts a message using the server's private key.
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+/// * `ciphertext` - A pointer to a C string containing the base64-encoded encrypted message.
+///
+/// # Returns
+///
+/// Returns a pointer to a C string containing the decrypted plaintext message. Returns a null pointer if decryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_server` and `ciphertext` pointers must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_server_decrypt(
+    e2ee_server: *mut E2ee,
+    ciphertext: *const c_char,
+) -> *mut c_char {
+    let e2ee_server = unsafe { &*e2ee_server };
+    let Ok(ciphertext) = unsafe { CStr::from_ptr(ciphertext) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    match e2ee_server.decrypt(ciphertext) {
+        Ok(decrypted) => {
+            CString::new(decrypted).map_or(std::ptr::null_mut(), CString::into_raw)
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Decrypts a message using the server's private key into a buffer provided by the caller,
+/// without allocating the returned plaintext.
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+/// * `ciphertext` - A pointer to a C string containing the base64-encoded encrypted message.
+/// * `out` - A pointer to the buffer receiving the plaintext, which is not NUL-terminated. It may
+///   be null if `out_len` is 0, to query the length needed.
+/// * `out_len` - The length of `out`, in bytes. The size of the key in bytes is always enough.
+/// * `written` - A pointer receiving the length of the plaintext, or the length needed if `out`
+///   is too small.
+///
+/// # Returns
+///
+/// Returns 0 on success, -1 if decryption fails, or -2 if `out` is too small, in which case
+/// nothing is written to it.
+///
+/// # Safety
+///
+/// The `e2ee_server`, `ciphertext` and `written` pointers must be valid and non-null, and `out`
+/// must be valid for writes of `out_len` bytes, or null if `out_len` is 0.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_server_decrypt_into(
+    e2ee_server: *mut E2ee,
+    ciphertext: *const c_char,
+    out: *mut u8,
+    out_len: usize,
+    written: *mut usize,
+) -> c_int {
+    let e2ee_server = unsafe { &*e2ee_server };
+    let Ok(ciphertext) = unsafe { CStr::from_ptr(ciphertext) }.to_str() else {
+        return -1;
+    };
+    let out = unsafe { bytes_from_c_mut(out, out_len) };
+
+    match e2ee_server.decrypt_into(ciphertext, out) {
+        Ok(len) => {
+            unsafe { *written = len };
+            0
+        }
+        Err(E2eeError::BufferTooSmall { needed }) => {
+            unsafe { *written = needed };
+            -2
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Encrypts the file at `input` for the public key of `e2ee_client` into the file at `output`, as
+/// a stream (see `stream::encrypt_file`), so that files larger than memory can be encrypted.
+///
+/// The encryption blocks the calling thread: call this function from a background thread.
+///
+/// # Arguments
+///
+/// * `e2ee_client` - A pointer to a `PublicE2ee` instance.
+/// * `input` - A pointer to a C string containing the path of the file to encrypt.
+/// * `output` - A pointer to a C string containing the path of the encrypted file.
+/// * `progress` - A callback receiving the progress, or null. It is called from the calling
+///   thread after every read of the input file.
+/// * `user_data` - A pointer passed back to `progress`.
+///
+/// # Returns
+///
+/// Returns 0 on success, or -1 if a path is invalid, a file cannot be read or written, or
+/// encryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_client`, `input` and `output` pointers must be valid and non-null. `user_data` must
+/// remain valid until this function returns.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_encrypt_file(
+    e2ee_client: *mut PublicE2ee,
+    input: *const c_char,
+    output: *const c_char,
+    progress: Option<E2eeProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let e2ee_client = unsafe { &*e2ee_client };
+    let (Ok(input), Ok(output)) = (
+        unsafe { CStr::from_ptr(input) }.to_str(),
+        unsafe { CStr::from_ptr(output) }.to_str(),
+    ) else {
+        return -1;
+    };
+
+    match stream::encrypt_file_with_progress(
+        e2ee_client.get_public_key(),
+        input,
+        output,
+        |read, len| {
+            if let Some(progress) = progress {
+                progress(read, len, user_data);
+            }
+        },
+    ) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Decrypts the file at `input`, encrypted with `e2ee_encrypt_file`, with the private key of
+/// `e2ee_server` into the file at `output` (see `stream::decrypt_file`). The output file is only
+/// created once the whole input is authenticated.
+///
+/// The decryption blocks the calling thread: call this function from a background thread.
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+/// * `input` - A pointer to a C string containing the path of the encrypted file.
+/// * `output` - A pointer to a C string containing the path of the decrypted file.
+/// * `progress` - A callback receiving the progress, or null. It is called from the calling
+///   thread after every read of the input file.
+/// * `user_data` - A pointer passed back to `progress`.
+///
+/// # Returns
+///
+/// Returns 0 on success, or -1 if a path is invalid, a file cannot be read or written, or
+/// decryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_server`, `input` and `output` pointers must be valid and non-null. `user_data` must
+/// remain valid until this function returns.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_decrypt_file(
+    e2ee_server: *mut E2ee,
+    input: *const c_char,
+    output: *const c_char,
+    progress: Option<E2eeProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let e2ee_server = unsafe { &*e2ee_server };
+    let (Ok(input), Ok(output)) = (
+        unsafe { CStr::from_ptr(input) }.to_str(),
+        unsafe { CStr::from_ptr(output) }.to_str(),
+    ) else {
+        return -1;
+    };
+
+    match stream::decrypt_file_with_progress(
+        e2ee_server.get_private_key(),
+        input,
+        output,
+        |read, len| {
+            if let Some(progress) = progress {
+                progress(read, len, user_data);
+            }
+        },
+    ) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Encrypts a binary payload of any size for the public key of `e2ee_client` into an envelope
+/// (see `PublicE2ee::encrypt_envelope`).
+///
+/// # Arguments
+///
+/// * `e2ee_client` - A pointer to a `PublicE2ee` instance.
+/// * `data` - A pointer to the payload.
+/// * `len` - The length of the payload, in bytes.
+/// * `out_len` - A pointer receiving the length of the envelope.
+///
+/// # Returns
+///
+/// Returns a pointer to the envelope, to free with `e2ee_free_bytes`. Returns a null pointer if
+/// encryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_client` and `out_len` pointers must be valid and non-null, and `data` must be valid
+/// for reads of `len` bytes (it may be null if `len` is 0).
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_encrypt_hybrid_bytes(
+    e2ee_client: *mut PublicE2ee,
+    data: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let e2ee_client = unsafe { &*e2ee_client };
+    let data = unsafe { bytes_from_c(data, len) };
+
+    match e2ee_client.encrypt_envelope(data) {
+        Ok(envelope) => unsafe { bytes_into_c(envelope, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Decrypts an envelope returned by `e2ee_encrypt_hybrid_bytes` with the private key of
+/// `e2ee_server` (see `E2ee::decrypt_envelope`).
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+/// * `envelope` - A pointer to the envelope.
+/// * `len` - The length of the envelope, in bytes.
+/// * `out_len` - A pointer receiving the length of the payload.
+///
+/// # Returns
+///
+/// Returns a pointer to the payload, to free with `e2ee_free_bytes`. Returns a null pointer if
+/// decryption fails.
+///
+/// # Safety
+///
+/// The `e2ee_server` and `out_len` pointers must be valid and non-null, and `envelope` must be
+/// valid for reads of `len` bytes.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_decrypt_hybrid_bytes(
+    e2ee_server: *mut E2ee,
+    envelope: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let e2ee_server = unsafe { &*e2ee_server };
+    let envelope = unsafe { bytes_from_c(envelope, len) };
+
+    match e2ee_server.decrypt_envelope(envelope) {
+        Ok(payload) => unsafe { bytes_into_c(payload, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// The context of a stream encryption, from `e2ee_stream_encrypt_begin` to
+/// `e2ee_stream_encrypt_finish`.
+pub type E2eeStreamEncryptor = stream::StreamEncryptor<Vec<u8>>;
+
+/// Starts encrypting a stream for the public key of `e2ee_client` (see `StreamEncryptor`), fed
+/// piece by piece with `e2ee_stream_encrypt_update` so that data larger than the memory can be
+/// encrypted from the caller's own I/O loop.
+///
+/// # Arguments
+///
+/// * `e2ee_client` - A pointer to a `PublicE2ee` instance.
+///
+/// # Returns
+///
+/// Returns a pointer to the encryption context, to pass to `e2ee_stream_encrypt_finish` or to free
+/// with `e2ee_stream_encrypt_free`. Returns a null pointer if the data key cannot be wrapped.
+///
+/// # Safety
+///
+/// The `e2ee_client` pointer must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_encrypt_begin(
+    e2ee_client: *mut PublicE2ee,
+) -> *mut E2eeStreamEncryptor {
+    let e2ee_client = unsafe { &*e2ee_client };
+
+    match stream::StreamEncryptor::new(e2ee_client.get_public_key(), Vec::new()) {
+        Ok(encryptor) => Box::into_raw(Box::new(encryptor)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Encrypts the next piece of a stream.
+///
+/// # Arguments
+///
+/// * `encryptor` - A pointer to the context returned by `e2ee_stream_encrypt_begin`.
+/// * `data` - A pointer to the piece.
+/// * `len` - The length of the piece, in bytes.
+/// * `out_len` - A pointer receiving the length of the encrypted output.
+///
+/// # Returns
+///
+/// Returns a pointer to the encrypted output ready so far, possibly empty, to write out and free
+/// with `e2ee_free_bytes`. Returns a null pointer if encryption fails, after which the context
+/// must be freed with `e2ee_stream_encrypt_free`.
+///
+/// # Safety
+///
+/// The `encryptor` and `out_len` pointers must be valid and non-null, and `data` must be valid
+/// for reads of `len` bytes (it may be null if `len` is 0).
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_encrypt_update(
+    encryptor: *mut E2eeStreamEncryptor,
+    data: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let encryptor = unsafe { &mut *encryptor };
+    let data = unsafe { bytes_from_c(data, len) };
+
+    match encryptor.write_all(data) {
+        Ok(()) => unsafe {
+            bytes_into_c(std::mem::take(encryptor.get_mut()), out_len)
+        },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Encrypts the last chunk of a stream and frees its context. A stream that is not finished
+/// fails to decrypt.
+///
+/// # Arguments
+///
+/// * `encryptor` - A pointer to the context returned by `e2ee_stream_encrypt_begin`, freed by this
+///   function.
+/// * `out_len` - A pointer receiving the length of the encrypted output.
+///
+/// # Returns
+///
+/// Returns a pointer to the rest of the encrypted stream, to write out and free with
+/// `e2ee_free_bytes`. Returns a null pointer if encryption fails.
+///
+/// # Safety
+///
+/// The `encryptor` and `out_len` pointers must be valid and non-null, and `encryptor` must not be
+/// used after this call.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_encrypt_finish(
+    encryptor: *mut E2eeStreamEncryptor,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let encryptor = unsafe { Box::from_raw(encryptor) };
+
+    match encryptor.finish() {
+        Ok(encrypted) => unsafe { bytes_into_c(encrypted, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees the context of a stream encryption that is abandoned.
+///
+/// # Safety
+///
+/// `encryptor` must be null or a pointer returned by `e2ee_stream_encrypt_begin` that was neither
+/// finished nor freed.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_encrypt_free(
+    encryptor: *mut E2eeStreamEncryptor,
+) {
+    if !encryptor.is_null() {
+        unsafe {
+            drop(Box::from_raw(encryptor));
+        }
+    }
+}
+
+/// Starts decrypting a stream encrypted for the private key of `e2ee_server` (see
+/// `PushDecryptor`), fed piece by piece with `e2ee_stream_decrypt_update`.
+///
+/// # Arguments
+///
+/// * `e2ee_server` - A pointer to an `E2ee` instance.
+///
+/// # Returns
+///
+/// Returns a pointer to the decryption context, to pass to `e2ee_stream_decrypt_finish` or to free
+/// with `e2ee_stream_decrypt_free`.
+///
+/// # Safety
+///
+/// The `e2ee_server` pointer must be valid and non-null.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_decrypt_begin(
+    e2ee_server: *mut E2ee,
+) -> *mut stream::PushDecryptor {
+    let e2ee_server = unsafe { &*e2ee_server };
+    Box::into_raw(Box::new(stream::PushDecryptor::new(
+        e2ee_server.get_private_key(),
+    )))
+}
+
+/// Decrypts the next piece of a stream.
+///
+/// Each chunk returned is authenticated, but the stream as a whole is only once
+/// `e2ee_stream_decrypt_finish` succeeds: until then, it may have been truncated, and the output
+/// must be discarded if it fails.
+///
+/// # Arguments
+///
+/// * `decryptor` - A pointer to the context returned by `e2ee_stream_decrypt_begin`.
+/// * `data` - A pointer to the piece.
+/// * `len` - The length of the piece, in bytes.
+/// * `out_len` - A pointer receiving the length of the plaintext.
+///
+/// # Returns
+///
+/// Returns a pointer to the plaintext of the chunks completed by the piece, possibly empty, to
+/// free with `e2ee_free_bytes`. Returns a null pointer if decryption fails, after which the
+/// context must be freed with `e2ee_stream_decrypt_free`.
+///
+/// # Safety
+///
+/// The `decryptor` and `out_len` pointers must be valid and non-null, and `data` must be valid
+/// for reads of `len` bytes (it may be null if `len` is 0).
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_decrypt_update(
+    decryptor: *mut stream::PushDecryptor,
+    data: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let decryptor = unsafe { &mut *decryptor };
+    let data = unsafe { bytes_from_c(data, len) };
+
+    match decryptor.update(data) {
+        Ok(plaintext) => unsafe { bytes_into_c(plaintext, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Decrypts the last chunk of a stream and frees its context.
+///
+/// # Arguments
+///
+/// * `decryptor` - A pointer to the context returned by `e2ee_stream_decrypt_begin`, freed by this
+///   function.
+/// * `out_len` - A pointer receiving the length of the plaintext.
+///
+/// # Returns
+///
+/// Returns a pointer to the rest of the plaintext, to free with `e2ee_free_bytes`. Returns a null
+/// pointer if the stream is truncated or was tampered with.
+///
+/// # Safety
+///
+/// The `decryptor` and `out_len` pointers must be valid and non-null, and `decryptor` must not be
+/// used after this call.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_decrypt_finish(
+    decryptor: *mut stream::PushDecryptor,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let decryptor = unsafe { Box::from_raw(decryptor) };
+
+    match decryptor.finish() {
+        Ok(plaintext) => unsafe { bytes_into_c(plaintext, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees the context of a stream decryption that is abandoned.
+///
+/// # Safety
+///
+/// `decryptor` must be null or a pointer returned by `e2ee_stream_decrypt_begin` that was neither
+/// finished nor freed.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_stream_decrypt_free(
+    decryptor: *mut stream::PushDecryptor,
+) {
+    if !decryptor.is_null() {
+        unsafe {
+            drop(Box::from_raw(decryptor));
+        }
+    }
+}
+
+/// Starts a session handshake as `identity`, the initiator (see `Handshake::initiate`).
+///
+/// # Arguments
+///
+/// * `identity` - A pointer to the `E2ee` instance holding the identity key of the initiator.
+/// * `hello` - A pointer receiving the handshake message to send to the responder, to free with
+///   `e2ee_free_bytes`.
+/// * `hello_len` - A pointer receiving the length of the handshake message.
+///
+/// # Returns
+///
+/// Returns a pointer to the pending handshake, to pass to `e2ee_session_complete` with the reply
+/// of the responder, or to free with `e2ee_handshake_free`. Returns a null pointer if signing
+/// fails.
+///
+/// # Safety
+///
+/// The `identity`, `hello` and `hello_len` pointers must be valid and non-null.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_new_initiator(
+    identity: *mut E2ee,
+    hello: *mut *mut u8,
+    hello_len: *mut usize,
+) -> *mut Handshake {
+    let identity = unsafe { &*identity };
+
+    match Handshake::initiate(identity) {
+        Ok((handshake, message)) => {
+            unsafe { *hello = bytes_into_c(message.to_bytes(), hello_len) };
+            Box::into_raw(Box::new(handshake))
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Completes a handshake started with `e2ee_session_new_initiator` with the `reply` of the
+/// responder `peer` (see `Handshake::complete`). The handshake is consumed, whether it succeeds
+/// or not.
+///
+/// # Arguments
+///
+/// * `handshake` - A pointer returned by `e2ee_session_new_initiator`.
+/// * `peer` - A pointer to a `PublicE2ee` instance holding the identity key of the responder.
+/// * `reply` - A pointer to the handshake message of the responder.
+/// * `reply_len` - The length of the reply, in bytes.
+///
+/// # Returns
+///
+/// Returns a pointer to the session, to free with `e2ee_session_free`. Returns a null pointer if
+/// the reply is malformed or was not signed by `peer`.
+///
+/// # Safety
+///
+/// The `handshake` and `peer` pointers must be valid and non-null, `reply` must be valid for
+/// reads of `reply_len` bytes, and `handshake` must not be used afterwards.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_complete(
+    handshake: *mut Handshake,
+    peer: *mut PublicE2ee,
+    reply: *const u8,
+    reply_len: usize,
+) -> *mut Session {
+    let handshake = unsafe { Box::from_raw(handshake) };
+    let peer = unsafe { &*peer };
+    let reply = unsafe { bytes_from_c(reply, reply_len) };
+
+    match HandshakeMessage::from_bytes(reply)
+        .and_then(|reply| handshake.complete(peer.get_public_key(), &reply))
+    {
+        Ok(session) => Box::into_raw(Box::new(session)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Accepts the handshake message `hello` of the initiator `peer` as `identity`, the responder
+/// (see `Handshake::respond`).
+///
+/// # Arguments
+///
+/// * `identity` - A pointer to the `E2ee` instance holding the identity key of the responder.
+/// * `peer` - A pointer to a `PublicE2ee` instance holding the identity key of the initiator.
+/// * `hello` - A pointer to the handshake message of the initiator.
+/// * `hello_len` - The length of the handshake message, in bytes.
+/// * `reply` - A pointer receiving the reply to send back, to free with `e2ee_free_bytes`.
+/// * `reply_len` - A pointer receiving the length of the reply.
+///
+/// # Returns
+///
+/// Returns a pointer to the session, to free with `e2ee_session_free`. Returns a null pointer if
+/// the handshake message is malformed or was not signed by `peer`, or if signing fails.
+///
+/// # Safety
+///
+/// The `identity`, `peer`, `reply` and `reply_len` pointers must be valid and non-null, and
+/// `hello` must be valid for reads of `hello_len` bytes.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_new_responder(
+    identity: *mut E2ee,
+    peer: *mut PublicE2ee,
+    hello: *const u8,
+    hello_len: usize,
+    reply: *mut *mut u8,
+    reply_len: *mut usize,
+) -> *mut Session {
+    let identity = unsafe { &*identity };
+    let peer = unsafe { &*peer };
+    let hello = unsafe { bytes_from_c(hello, hello_len) };
+
+    match HandshakeMessage::from_bytes(hello).and_then(|hello| {
+        Handshake::respond(identity, peer.get_public_key(), &hello)
+    }) {
+        Ok((session, message)) => {
+            unsafe { *reply = bytes_into_c(message.to_bytes(), reply_len) };
+            Box::into_raw(Box::new(session))
+        }
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Encrypts a message with `session` (see `Session::encrypt`).
+///
+/// # Arguments
+///
+/// * `session` - A pointer to a session.
+/// * `plaintext` - A pointer to the message.
+/// * `len` - The length of the message, in bytes.
+/// * `out_len` - A pointer receiving the length of the encrypted message.
+///
+/// # Returns
+///
+/// Returns a pointer to the encrypted message, to free with `e2ee_free_bytes`. Returns a null
+/// pointer if encryption fails.
+///
+/// # Safety
+///
+/// The `session` and `out_len` pointers must be valid and non-null, and `plaintext` must be valid
+/// for reads of `len` bytes (it may be null if `len` is 0).
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_encrypt(
+    session: *mut Session,
+    plaintext: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let session = unsafe { &mut *session };
+    let plaintext = unsafe { bytes_from_c(plaintext, len) };
+
+    match session.encrypt(plaintext) {
+        Ok(message) => unsafe { bytes_into_c(message, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Decrypts a message with `session` (see `Session::decrypt`). A message that fails to decrypt
+/// leaves the session unchanged.
+///
+/// # Arguments
+///
+/// * `session` - A pointer to a session.
+/// * `message` - A pointer to the encrypted message.
+/// * `len` - The length of the encrypted message, in bytes.
+/// * `out_len` - A pointer receiving the length of the plaintext.
+///
+/// # Returns
+///
+/// Returns a pointer to the plaintext, to free with `e2ee_free_bytes`. Returns a null pointer if
+/// decryption fails.
+///
+/// # Safety
+///
+/// The `session` and `out_len` pointers must be valid and non-null, and `message` must be valid
+/// for reads of `len` bytes.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_decrypt(
+    session: *mut Session,
+    message: *const u8,
+    len: usize,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let session = unsafe { &mut *session };
+    let message = unsafe { bytes_from_c(message, len) };
+
+    match session.decrypt(message) {
+        Ok(plaintext) => unsafe { bytes_into_c(plaintext, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Saves `session`, encrypted under a 32-byte storage key (see `Session::serialize`). Save the
+/// session after every call to `e2ee_session_encrypt` or `e2ee_session_decrypt`.
+///
+/// # Arguments
+///
+/// * `session` - A pointer to a session.
+/// * `storage_key` - A pointer to the 32-byte storage key.
+/// * `out_len` - A pointer receiving the length of the saved state.
+///
+/// # Returns
+///
+/// Returns a pointer to the saved state, to free with `e2ee_free_bytes`. Returns a null pointer if
+/// encryption fails.
+///
+/// # Safety
+///
+/// The `session` and `out_len` pointers must be valid and non-null, and `storage_key` must be
+/// valid for reads of 32 bytes.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_serialize(
+    session: *mut Session,
+    storage_key: *const u8,
+    out_len: *mut usize,
+) -> *mut u8 {
+    let session = unsafe { &*session };
+    let storage_key = unsafe { &*(storage_key as *const [u8; 32]) };
+
+    match session.serialize(storage_key) {
+        Ok(saved) => unsafe { bytes_into_c(saved, out_len) },
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Resumes a session saved with `e2ee_session_serialize` under `storage_key` (see
+/// `Session::deserialize`).
+///
+/// # Arguments
+///
+/// * `storage_key` - A pointer to the 32-byte storage key.
+/// * `saved` - A pointer to the saved state.
+/// * `len` - The length of the saved state, in bytes.
+///
+/// # Returns
+///
+/// Returns a pointer to the session, to free with `e2ee_session_free`. Returns a null pointer if
+/// the state was not saved under `storage_key`, was tampered with or is malformed.
+///
+/// # Safety
+///
+/// `storage_key` must be valid for reads of 32 bytes, and `saved` for reads of `len` bytes.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_deserialize(
+    storage_key: *const u8,
+    saved: *const u8,
+    len: usize,
+) -> *mut Session {
+    let storage_key = unsafe { &*(storage_key as *const [u8; 32]) };
+    let saved = unsafe { bytes_from_c(saved, len) };
+
+    match Session::deserialize(storage_key, saved) {
+        Ok(session) => Box::into_raw(Box::new(session)),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Frees a pending handshake returned by `e2ee_session_new_initiator` that will not be completed.
+///
+/// # Safety
+///
+/// The pointer must be null or have been returned by `e2ee_session_new_initiator`, must not have
+/// been passed to `e2ee_session_complete`, and must only be freed once.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_handshake_free(handshake: *mut Handshake) {
+    if !handshake.is_null() {
+        unsafe {
+            drop(Box::from_raw(handshake));
+        }
+    }
+}
+
+/// Frees a session.
+///
+/// # Safety
+///
+/// The pointer must be null or have been returned by `e2ee_session_complete`,
+/// `e2ee_session_new_responder` or `e2ee_session_deserialize`, and must only be freed once.
+#[cfg(all(feature = "ffi", feature = "session"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_session_free(session: *mut Session) {
+    if !session.is_null() {
+        unsafe {
+            drop(Box::from_raw(session));
+        }
+    }
+}
 
-/// Creates a new `E2ee` instance with the specified RSA key size.
+/// Creates an empty keystore at `path`, protected by `passphrase` (see `Keystore::create`), with
+/// the default Argon2id parameters.
 ///
 /// # Arguments
 ///
-/// * `key_size` - The RSA key size (1024, 2048, 3072, 4096).
+/// * `path` - A pointer to a C string containing the path of the keystore file, which must not
+///   exist.
+/// * `passphrase` - A pointer to a C string containing the passphrase.
 ///
 /// # Returns
 ///
-/// Returns a pointer to the newly created `E2ee` instance. Returns a null pointer if an invalid key size is specified or an error occurs during instantiation.
+/// Returns a pointer to the open keystore, to free with `e2ee_keystore_free`. Returns a null
+/// pointer if the file exists or cannot be written.
 ///
 /// # Safety
 ///
-/// This function is safe as long as the `key_size` parameter is a valid RSA key size.
-#[cfg(feature = "ffi")]
+/// The `path` and `passphrase` pointers must be valid C strings.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub extern "C" fn e2ee_server_new(key_size: c_int) -> *mut E2ee {
-    let key_size = match key_size {
-        1024 => KeySize::Bit1024,
-        2048 => KeySize::Bit2048,
-        3072 => KeySize::Bit3072,
-        4096 => KeySize::Bit4096,
-        _ => return std::ptr::null_mut(), // Invalid key size
+pub unsafe extern "C" fn e2ee_keystore_create(
+    path: *const c_char,
+    passphrase: *const c_char,
+) -> *mut Keystore {
+    let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+        return std::ptr::null_mut();
     };
-    match E2ee::new(key_size) {
-        Ok(sdk) => Box::into_raw(Box::new(sdk)),
+    let passphrase = unsafe { CStr::from_ptr(passphrase) }.to_bytes();
+
+    match Keystore::create(path, passphrase, &PasswordKdf::default()) {
+        Ok(keystore) => Box::into_raw(Box::new(keystore)),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
-/// Creates a new `E2ee` instance from PEM-encoded private and public keys.
+/// Opens the keystore at `path` with `passphrase` (see `Keystore::open`).
 ///
 /// # Arguments
 ///
-/// * `private_key_pem` - A pointer to a C string containing the PEM-encoded private key.
-/// * `public_key_pem` - A pointer to a C string containing the PEM-encoded public key.
+/// * `path` - A pointer to a C string containing the path of the keystore file.
+/// * `passphrase` - A pointer to a C string containing the passphrase.
 ///
 /// # Returns
 ///
-/// Returns a pointer to the newly created `E2ee` instance. Returns a null pointer if an error occurs during instantiation.
+/// Returns a pointer to the open keystore, to free with `e2ee_keystore_free`. Returns a null
+/// pointer if the file cannot be read, if the passphrase is wrong or if the file was tampered
+/// with.
 ///
 /// # Safety
 ///
-/// The provided pointers must be valid C strings. The function assumes they are non-null and contain valid UTF-8 data.
-#[cfg(feature = "ffi")]
+/// The `path` and `passphrase` pointers must be valid C strings.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub unsafe extern "C" fn e2ee_server_new_from_pem(
-    private_key_pem: *const c_char,
-    public_key_pem: *const c_char,
-) -> *mut E2ee {
-    let private_key = unsafe { CStr::from_ptr(private_key_pem).to_str().unwrap() };
-    let public_key = unsafe { CStr::from_ptr(public_key_pem).to_str().unwrap() };
+pub unsafe extern "C" fn e2ee_keystore_open(
+    path: *const c_char,
+    passphrase: *const c_char,
+) -> *mut Keystore {
+    let Ok(path) = unsafe { CStr::from_ptr(path) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+    let passphrase = unsafe { CStr::from_ptr(passphrase) }.to_bytes();
 
-    match E2ee::new_from_pem(private_key.to_string(), public_key.to_string()) {
-        Ok(e2ee) => Box::into_raw(Box::new(e2ee)),
+    match Keystore::open(path, passphrase) {
+        Ok(keystore) => Box::into_raw(Box::new(keystore)),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
-/// Creates a new `PublicE2ee` instance from a PEM-encoded public key.
+/// A callback receiving a key listed by `E2eeStorageBackend::list`, and the `push_context`
+/// pointer.
+#[cfg(feature = "keystore")]
+pub type E2eeStorageListCallback = extern "C" fn(*const c_char, *mut c_void);
+
+/// A write of a batch applied by `E2eeStorageBackend::apply`.
+#[cfg(feature = "keystore")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct E2eeStorageOperation {
+    /// The key written, a C string.
+    pub key: *const c_char,
+    /// The value to store under `key`, or null to remove the value stored under it.
+    pub value: *const u8,
+    /// The length of `value`.
+    pub value_len: usize,
+}
+
+/// A storage backend implemented by the host (see `storage::StorageBackend`), e.g. over SQLite or
+/// the Keychain. Every callback receives `context` first, and returns 0 on success or -1 on
+/// error, unless stated otherwise.
+///
+/// The callbacks may be called from any thread that uses the handles built on the backend.
+#[cfg(feature = "keystore")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct E2eeStorageBackend {
+    /// The pointer passed to every callback.
+    pub context: *mut c_void,
+    /// Looks up the value stored under the key, a C string. On success, sets the pointer and the
+    /// length to a buffer allocated by the host, freed with `free_value` once copied. Returns 1
+    /// if no value is stored under the key.
+    pub get:
+        extern "C" fn(*mut c_void, *const c_char, *mut *mut u8, *mut usize) -> c_int,
+    /// Frees a buffer returned by `get`.
+    pub free_value: extern "C" fn(*mut c_void, *mut u8, usize),
+    /// Calls the callback with each key starting with the prefix, a C string, in sorted order,
+    /// along with the last pointer.
+    pub list: extern "C" fn(
+        *mut c_void,
+        *const c_char,
+        E2eeStorageListCallback,
+        *mut c_void,
+    ) -> c_int,
+    /// Applies the operations, in order, all of them or none.
+    pub apply:
+        extern "C" fn(*mut c_void, *const E2eeStorageOperation, usize) -> c_int,
+}
+
+/// A `StorageBackend` calling the callbacks of the host.
+#[cfg(feature = "keystore")]
+struct HostStorage(E2eeStorageBackend);
+
+// The host implements the callbacks for the threads it uses the handles from.
+#[cfg(feature = "keystore")]
+unsafe impl Send for HostStorage {}
+#[cfg(feature = "keystore")]
+unsafe impl Sync for HostStorage {}
+
+#[cfg(feature = "keystore")]
+fn host_error(operation: &str) -> std::io::Error {
+    std::io::Error::other(format!("The host storage failed to {operation}"))
+}
+
+#[cfg(feature = "keystore")]
+fn c_key(key: &str) -> std::io::Result<CString> {
+    CString::new(key)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(feature = "keystore")]
+impl StorageBackend for HostStorage {
+    fn get(&self, key: &str) -> std::io::Result<Option<Vec<u8>>> {
+        let key = c_key(key)?;
+        let mut value = std::ptr::null_mut();
+        let mut value_len = 0;
+        match (self.0.get)(self.0.context, key.as_ptr(), &mut value, &mut value_len)
+        {
+            0 if value.is_null() => Ok(Some(Vec::new())),
+            0 => {
+                let copied =
+                    unsafe { std::slice::from_raw_parts(value, value_len) }.to_vec();
+                (self.0.free_value)(self.0.context, value, value_len);
+                Ok(Some(copied))
+            }
+            1 => Ok(None),
+            _ => Err(host_error("read")),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        extern "C" fn push(key: *const c_char, keys: *mut c_void) {
+            let keys = unsafe { &mut *(keys as *mut Vec<String>) };
+            if let Ok(key) = unsafe { CStr::from_ptr(key) }.to_str() {
+                keys.push(key.to_string());
+            }
+        }
+
+        let prefix = c_key(prefix)?;
+        let mut keys = Vec::<String>::new();
+        let context = &mut keys as *mut Vec<String> as *mut c_void;
+        match (self.0.list)(self.0.context, prefix.as_ptr(), push, context) {
+            0 => Ok(keys),
+            _ => Err(host_error("list")),
+        }
+    }
+
+    fn apply(&self, batch: Batch) -> std::io::Result<()> {
+        let keys = batch
+            .operations()
+            .iter()
+            .map(|operation| c_key(operation.key()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let operations: Vec<_> = batch
+            .operations()
+            .iter()
+            .zip(&keys)
+            .map(|(operation, key)| match operation {
+                Operation::Put { value, .. } => E2eeStorageOperation {
+                    key: key.as_ptr(),
+                    // A non-null pointer, even for an empty value.
+                    value: value.as_ptr(),
+                    value_len: value.len(),
+                },
+                Operation::Delete { .. } => E2eeStorageOperation {
+                    key: key.as_ptr(),
+                    value: std::ptr::null(),
+                    value_len: 0,
+                },
+            })
+            .collect();
+        match (self.0.apply)(self.0.context, operations.as_ptr(), operations.len()) {
+            0 => Ok(()),
+            _ => Err(host_error("write")),
+        }
+    }
+}
+
+/// Creates an empty keystore under `key` in the storage backend of the host, protected by
+/// `passphrase` (see `Keystore::create_in`), with the default Argon2id parameters.
 ///
 /// # Arguments
 ///
-/// * `public_key` - A pointer to a C string containing the PEM-encoded public key.
+/// * `backend` - A pointer to the callbacks of the backend, which are copied.
+/// * `key` - A pointer to a C string containing the key of the keystore in the backend, under
+///   which no value must be stored.
+/// * `passphrase` - A pointer to a C string containing the passphrase.
 ///
 /// # Returns
 ///
-/// Returns a pointer to the newly created `PublicE2ee` instance. Returns a null pointer if an error occurs during instantiation.
+/// Returns a pointer to the open keystore, to free with `e2ee_keystore_free`. Returns a null
+/// pointer if a value is stored under `key` or the backend fails.
 ///
 /// # Safety
 ///
-/// The `public_key` pointer must be a valid C string. The function assumes the provided pointer is non-null and contains valid UTF-8 data.
-#[cfg(feature = "ffi")]
+/// The `backend` pointer must be valid, its callbacks must remain callable with its `context`
+/// until the keystore is freed, and the `key` and `passphrase` pointers must be valid C strings.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub unsafe extern "C" fn e2ee_client_new_from_public_pem(
-    public_key: *const c_char,
-) -> *mut PublicE2ee {
-    let public_key = unsafe { CStr::from_ptr(public_key).to_str().unwrap() };
+pub unsafe extern "C" fn e2ee_keystore_create_in_storage(
+    backend: *const E2eeStorageBackend,
+    key: *const c_char,
+    passphrase: *const c_char,
+) -> *mut Keystore {
+    let backend = std::sync::Arc::new(HostStorage(unsafe { *backend }));
+    let Ok(key) = unsafe { CStr::from_ptr(key) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+    let passphrase = unsafe { CStr::from_ptr(passphrase) }.to_bytes();
 
-    match PublicE2ee::new(public_key.to_string()) {
-        Ok(e2ee) => Box::into_raw(Box::new(e2ee)),
+    match Keystore::create_in(backend, key, passphrase, &PasswordKdf::default()) {
+        Ok(keystore) => Box::into_raw(Box::new(keystore)),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
-/// Encrypts a message using the server's public key.
+/// Opens the keystore stored under `key` in the storage backend of the host with `passphrase`
+/// (see `Keystore::open_in`).
 ///
 /// # Arguments
 ///
-/// * `e2ee_server` - A pointer to an `E2ee` instance.
-/// * `message` - A pointer to a C string containing the plaintext message.
+/// * `backend` - A pointer to the callbacks of the backend, which are copied.
+/// * `key` - A pointer to a C string containing the key of the keystore in the backend.
+/// * `passphrase` - A pointer to a C string containing the passphrase.
 ///
 /// # Returns
 ///
-/// Returns a pointer to a C string containing the base64-encoded encrypted message. Returns a null pointer if encryption fails.
+/// Returns a pointer to the open keystore, to free with `e2ee_keystore_free`. Returns a null
+/// pointer if no keystore is stored under `key`, if the backend fails, if the passphrase is wrong
+/// or if the keystore was tampered with.
 ///
 /// # Safety
 ///
-/// The `e2ee_server` and `message` pointers must be valid and non-null.
-#[cfg(feature = "ffi")]
+/// The same as `e2ee_keystore_create_in_storage`.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub unsafe extern "C" fn e2ee_server_encrypt(
-    e2ee_server: *mut E2ee,
-    message: *const c_char,
-) -> *mut c_char {
-    let e2ee_server = unsafe { &*e2ee_server };
-    let message = unsafe { CStr::from_ptr(message).to_str().unwrap() };
+pub unsafe extern "C" fn e2ee_keystore_open_in_storage(
+    backend: *const E2eeStorageBackend,
+    key: *const c_char,
+    passphrase: *const c_char,
+) -> *mut Keystore {
+    let backend = std::sync::Arc::new(HostStorage(unsafe { *backend }));
+    let Ok(key) = unsafe { CStr::from_ptr(key) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+    let passphrase = unsafe { CStr::from_ptr(passphrase) }.to_bytes();
 
-    match e2ee_server.encrypt(message) {
-        Ok(encrypted) => CString::new(encrypted).unwrap().into_raw(),
+    match Keystore::open_in(backend, key, passphrase) {
+        Ok(keystore) => Box::into_raw(Box::new(keystore)),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
-/// Encrypts a message using the client's public key.
+/// Lists the names of the keys of `keystore`.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `e2ee_client` - A pointer to a `PublicE2ee` instance.
-/// * `message` - A pointer to a C string containing the plaintext message.
+/// Returns a pointer to a C string containing the sorted names, one per line, to free with
+/// `e2ee_server_free_string`.
+///
+/// # Safety
+///
+/// The `keystore` pointer must be valid and non-null.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_keystore_list(keystore: *mut Keystore) -> *mut c_char {
+    let keystore = unsafe { &*keystore };
+    // Names hold no control characters, so neither NUL bytes nor line breaks.
+    CString::new(keystore.names().join("\n"))
+        .unwrap_or_default()
+        .into_raw()
+}
+
+/// Generates a key of `key_size` bits in `keystore` under `name`, and saves the keystore. The
+/// private key never crosses the FFI boundary.
 ///
 /// # Returns
 ///
-/// Returns a pointer to a C string containing the base64-encoded encrypted message. Returns a null pointer if encryption fails.
+/// Returns 0 on success, or -1 if the key size is invalid, the name is taken or invalid, or the
+/// keystore cannot be saved.
 ///
 /// # Safety
 ///
-/// The `e2ee_client` and `message` pointers must be valid and non-null.
-#[cfg(feature = "ffi")]
+/// The `keystore` pointer must be valid and non-null, and `name` must be a valid C string.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub unsafe extern "C" fn e2ee_client_encrypt(
-    e2ee_client: *mut PublicE2ee,
-    message: *const c_char,
-) -> *mut c_char {
-    let e2ee_client = unsafe { &*e2ee_client };
-    let message = unsafe { CStr::from_ptr(message).to_str().unwrap() };
+pub unsafe extern "C" fn e2ee_keystore_generate(
+    keystore: *mut Keystore,
+    name: *const c_char,
+    key_size: c_int,
+) -> c_int {
+    let keystore = unsafe { &mut *keystore };
+    let (Ok(name), Some(key_size)) = (
+        unsafe { CStr::from_ptr(name) }.to_str(),
+        key_size_from_c(key_size),
+    ) else {
+        return -1;
+    };
 
-    match e2ee_client.encrypt(message) {
-        Ok(encrypted) => CString::new(encrypted).unwrap().into_raw(),
-        Err(_) => std::ptr::null_mut(),
+    match keystore.generate(name, key_size) {
+        Ok(()) => 0,
+        Err(_) => -1,
     }
 }
 
-/// Decrypts a message using the server's private key.
+/// Removes the key stored under `name` from `keystore`, and saves the keystore.
 ///
-/// # Arguments
+/// # Returns
 ///
-/// * `e2ee_server` - A pointer to an `E2ee` instance.
-/// * `ciphertext` - A pointer to a C string containing the base64-encoded encrypted message.
+/// Returns 0 on success, or -1 if no key is stored under `name` or the keystore cannot be saved.
+///
+/// # Safety
+///
+/// The `keystore` pointer must be valid and non-null, and `name` must be a valid C string.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_keystore_remove(
+    keystore: *mut Keystore,
+    name: *const c_char,
+) -> c_int {
+    let keystore = unsafe { &mut *keystore };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return -1;
+    };
+
+    match keystore.remove(name) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
+
+/// Creates an `E2ee` instance bound to the key stored under `name` in `keystore`.
 ///
 /// # Returns
 ///
-/// Returns a pointer to a C string containing the decrypted plaintext message. Returns a null pointer if decryption fails.
+/// Returns a pointer to the instance, to free with `e2ee_server_free`. Returns a null pointer if
+/// no key is stored under `name`.
 ///
 /// # Safety
 ///
-/// The `e2ee_server` and `ciphertext` pointers must be valid and non-null.
-#[cfg(feature = "ffi")]
+/// The `keystore` pointer must be valid and non-null, and `name` must be a valid C string.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
 #[no_mangle]
-pub unsafe extern "C" fn e2ee_server_decrypt(
-    e2ee_server: *mut E2ee,
-    ciphertext: *const c_char,
-) -> *mut c_char {
-    let e2ee_server = unsafe { &*e2ee_server };
-    let ciphertext = unsafe { CStr::from_ptr(ciphertext).to_str().unwrap() };
+pub unsafe extern "C" fn e2ee_keystore_server(
+    keystore: *mut Keystore,
+    name: *const c_char,
+) -> *mut E2ee {
+    let keystore = unsafe { &*keystore };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return std::ptr::null_mut();
+    };
 
-    match e2ee_server.decrypt(ciphertext) {
-        Ok(decrypted) => CString::new(decrypted).unwrap().into_raw(),
+    match keystore.e2ee(name) {
+        Ok(e2ee) => Box::into_raw(Box::new(with_instance_rng(e2ee))),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Creates a `PublicE2ee` instance bound to the public half of the key stored under `name` in
+/// `keystore`.
+///
+/// # Returns
+///
+/// Returns a pointer to the instance, to free with `e2ee_client_free`. Returns a null pointer if
+/// no key is stored under `name`.
+///
+/// # Safety
+///
+/// The `keystore` pointer must be valid and non-null, and `name` must be a valid C string.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_keystore_client(
+    keystore: *mut Keystore,
+    name: *const c_char,
+) -> *mut PublicE2ee {
+    let keystore = unsafe { &*keystore };
+    let Ok(name) = unsafe { CStr::from_ptr(name) }.to_str() else {
+        return std::ptr::null_mut();
+    };
+
+    match keystore.public_e2ee(name) {
+        Ok(e2ee) => Box::into_raw(Box::new(with_instance_rng_public(e2ee))),
         Err(_) => std::ptr::null_mut(),
     }
 }
 
+/// Closes a keystore, erasing the key derived from its passphrase. Instances created with
+/// `e2ee_keystore_server` and `e2ee_keystore_client` remain valid.
+///
+/// # Safety
+///
+/// The pointer must be null or have been returned by `e2ee_keystore_create`,
+/// `e2ee_keystore_open`, `e2ee_keystore_create_in_storage` or `e2ee_keystore_open_in_storage`,
+/// and must only be freed once.
+#[cfg(all(feature = "ffi", feature = "keystore"))]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_keystore_free(keystore: *mut Keystore) {
+    if !keystore.is_null() {
+        unsafe {
+            drop(Box::from_raw(keystore));
+        }
+    }
+}
+
+/// Seeds the generator of the `E2ee` and `PublicE2ee` instances created by the calling thread
+/// from now on, so that their keys and ciphertexts are the same on every run of a test. Each
+/// instance draws its own generator from the seeded one, in the order they are created. Streams
+/// and sessions still draw from the operating system.
+///
+/// This function is only compiled with the `ffi-testing` feature, which must never be enabled in
+/// production builds.
+///
+/// # Arguments
+///
+/// * `seed` - The seed of the generator.
+#[cfg(feature = "ffi-testing")]
+#[no_mangle]
+pub extern "C" fn e2ee_set_rng_seed_for_testing(seed: u64) {
+    TEST_RNG.with_borrow_mut(|rng| *rng = Some(ChaCha20Rng::seed_from_u64(seed)));
+}
+
+/// Makes the instances created by the calling thread draw from the operating system again,
+/// undoing `e2ee_set_rng_seed_for_testing`.
+#[cfg(feature = "ffi-testing")]
+#[no_mangle]
+pub extern "C" fn e2ee_clear_rng_seed_for_testing() {
+    TEST_RNG.with_borrow_mut(|rng| *rng = None);
+}
+
+/// Frees a buffer returned by a function of this module, e.g. `e2ee_encrypt_hybrid_bytes` or
+/// `e2ee_session_decrypt`. The buffer is zeroed first, as it may hold a plaintext.
+///
+/// # Safety
+///
+/// `bytes` must be null or a pointer returned by one of these functions, with the length they
+/// returned, and must only be freed once.
+#[cfg(feature = "ffi")]
+#[no_mangle]
+pub unsafe extern "C" fn e2ee_free_bytes(bytes: *mut u8, len: usize) {
+    if !bytes.is_null() {
+        let mut bytes =
+            unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(bytes, len)) };
+        bytes.zeroize();
+    }
+}
+
+/// Borrows `len` bytes at `data`, which may be null if `len` is 0.
+#[cfg(feature = "ffi")]
+unsafe fn bytes_from_c<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    if len == 0 {
+        return &[];
+    }
+    unsafe { std::slice::from_raw_parts(data, len) }
+}
+
+/// Borrows `len` writable bytes at `data`, which may be null if `len` is 0.
+#[cfg(feature = "ffi")]
+unsafe fn bytes_from_c_mut<'a>(data: *mut u8, len: usize) -> &'a mut [u8] {
+    if len == 0 {
+        return &mut [];
+    }
+    unsafe { std::slice::from_raw_parts_mut(data, len) }
+}
+
+/// Hands `bytes` over to the caller, writing their length to `len`.
+#[cfg(feature = "ffi")]
+unsafe fn bytes_into_c(bytes: Vec<u8>, len: *mut usize) -> *mut u8 {
+    let bytes = bytes.into_boxed_slice();
+    unsafe { *len = bytes.len() };
+    Box::into_raw(bytes) as *mut u8
+}
+
 /// Retrieves the public key in PEM format from the given `E2ee` server object.
 ///
 /// # Safety
@@ -247,9 +1762,10 @@ pub unsafe extern "C" fn e2ee_server_get_private_key_pem(
     e2ee_server: *mut E2ee,
 ) -> *mut c_char {
     let e2ee_server = unsafe { &*e2ee_server };
-    CString::new(e2ee_server.get_private_key_pem())
-        .unwrap()
-        .into_raw()
+    match e2ee_server.get_private_key_pem() {
+        Ok(pem) => CString::new(pem.as_bytes()).unwrap().into_raw(),
+        Err(_) => std::ptr::null_mut(),
+    }
 }
 
 /// Frees the memory associated with an `E2ee` server object.
@@ -397,6 +1913,40 @@ mod tests {
         unsafe { e2ee_server_free(e2ee_server) };
     }
 
+    // Test the e2ee_server_new_with_progress function, with a callback cancelling the generation
+    #[test]
+    fn test_e2ee_server_new_with_progress() {
+        extern "C" fn count(
+            _found: c_int,
+            _needed: c_int,
+            _tested: u64,
+            calls: *mut c_void,
+        ) {
+            unsafe { *(calls as *mut u64) += 1 };
+        }
+
+        let mut calls = 0u64;
+        let e2ee_server = unsafe {
+            e2ee_server_new_with_progress(
+                1024,
+                Some(count),
+                &mut calls as *mut u64 as *mut c_void,
+                std::ptr::null(),
+            )
+        };
+        assert!(!e2ee_server.is_null());
+        assert!(calls >= 2);
+        unsafe { e2ee_server_free(e2ee_server) };
+
+        let token = e2ee_cancellation_token_new();
+        unsafe { e2ee_cancellation_token_cancel(token) };
+        let e2ee_server = unsafe {
+            e2ee_server_new_with_progress(4096, None, std::ptr::null_mut(), token)
+        };
+        assert!(e2ee_server.is_null());
+        unsafe { e2ee_cancellation_token_free(token) };
+    }
+
     // Test the e2ee_server_new_from_pem function
     #[test]
     fn test_e2ee_server_new_from_pem() {
@@ -477,6 +2027,490 @@ mod tests {
         unsafe { e2ee_server_free(e2ee_server) };
     }
 
+    // Test the e2ee_server_decrypt_into function
+    #[test]
+    fn test_e2ee_server_decrypt_into() {
+        let e2ee_server = e2ee_server_new(2048);
+        let message_c = to_c_string("Hello, world!");
+        let encrypted_message_c =
+            unsafe { e2ee_server_encrypt(e2ee_server, message_c) };
+
+        let mut buffer = [0u8; 256];
+        let mut written = 0;
+        // A null buffer of length 0 queries the length needed.
+        let status = unsafe {
+            e2ee_server_decrypt_into(
+                e2ee_server,
+                encrypted_message_c,
+                std::ptr::null_mut(),
+                0,
+                &mut written,
+            )
+        };
+        assert_eq!((status, written), (-2, 13));
+        let status = unsafe {
+            e2ee_server_decrypt_into(
+                e2ee_server,
+                encrypted_message_c,
+                buffer.as_mut_ptr(),
+                4,
+                &mut written,
+            )
+        };
+        assert_eq!((status, written), (-2, 13));
+        let status = unsafe {
+            e2ee_server_decrypt_into(
+                e2ee_server,
+                encrypted_message_c,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut written,
+            )
+        };
+        assert_eq!(status, 0);
+        assert_eq!(&buffer[..written], b"Hello, world!");
+
+        unsafe { e2ee_server_free_string(encrypted_message_c) };
+        unsafe { e2ee_server_free(e2ee_server) };
+    }
+
+    // Test the e2ee_encrypt_file and e2ee_decrypt_file functions, counting progress reports
+    #[test]
+    fn test_e2ee_encrypt_and_decrypt_file() {
+        extern "C" fn record(read: u64, len: u64, last: *mut c_void) {
+            unsafe { *(last as *mut (u64, u64)) = (read, len) };
+        }
+
+        let dir =
+            std::env::temp_dir().join(format!("e2ee-ffi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("plain"), vec![7u8; 100_000]).unwrap();
+        let path = |name: &str| to_c_string(dir.join(name).to_str().unwrap());
+
+        let e2ee_server = e2ee_server_new(2048);
+        let public_key_pem = unsafe { e2ee_server_get_public_key_pem(e2ee_server) };
+        let e2ee_client = unsafe { e2ee_client_new_from_public_pem(public_key_pem) };
+        let mut last = (0u64, 0u64);
+        let status = unsafe {
+            e2ee_encrypt_file(
+                e2ee_client,
+                path("plain"),
+                path("enc"),
+                Some(record),
+                &mut last as *mut (u64, u64) as *mut c_void,
+            )
+        };
+        assert_eq!((status, last), (0, (100_000, 100_000)));
+        let status = unsafe {
+            e2ee_decrypt_file(
+                e2ee_server,
+                path("enc"),
+                path("dec"),
+                None,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, 0);
+        assert_eq!(std::fs::read(dir.join("dec")).unwrap(), vec![7u8; 100_000]);
+        let status = unsafe {
+            e2ee_decrypt_file(
+                e2ee_server,
+                path("missing"),
+                path("dec"),
+                None,
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(status, -1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        unsafe { e2ee_server_free_string(public_key_pem) };
+        unsafe { e2ee_client_free(e2ee_client) };
+        unsafe { e2ee_server_free(e2ee_server) };
+    }
+
+    // Test the e2ee_encrypt_hybrid_bytes and e2ee_decrypt_hybrid_bytes functions
+    #[test]
+    fn test_e2ee_hybrid_bytes() {
+        let e2ee_server = e2ee_server_new(2048);
+        let public_key_pem = unsafe { e2ee_server_get_public_key_pem(e2ee_server) };
+        let e2ee_client = unsafe { e2ee_client_new_from_public_pem(public_key_pem) };
+        let payload = vec![0u8, 1, 2, 255];
+
+        let mut envelope_len = 0;
+        let envelope = unsafe {
+            e2ee_encrypt_hybrid_bytes(
+                e2ee_client,
+                payload.as_ptr(),
+                payload.len(),
+                &mut envelope_len,
+            )
+        };
+        assert!(!envelope.is_null());
+        let mut decrypted_len = 0;
+        let decrypted = unsafe {
+            e2ee_decrypt_hybrid_bytes(
+                e2ee_server,
+                envelope,
+                envelope_len,
+                &mut decrypted_len,
+            )
+        };
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(decrypted, decrypted_len) },
+            payload
+        );
+        let corrupted = unsafe {
+            e2ee_decrypt_hybrid_bytes(e2ee_server, envelope, 10, &mut decrypted_len)
+        };
+        assert!(corrupted.is_null());
+
+        unsafe { e2ee_free_bytes(decrypted, decrypted_len) };
+        unsafe { e2ee_free_bytes(envelope, envelope_len) };
+        unsafe { e2ee_server_free_string(public_key_pem) };
+        unsafe { e2ee_client_free(e2ee_client) };
+        unsafe { e2ee_server_free(e2ee_server) };
+    }
+
+    // Test the e2ee_set_rng_seed_for_testing and e2ee_clear_rng_seed_for_testing functions
+    #[cfg(feature = "ffi-testing")]
+    #[test]
+    fn test_e2ee_rng_seed_for_testing() {
+        let run = || {
+            let e2ee_server = e2ee_server_new(1024);
+            let public_key_pem =
+                unsafe { e2ee_server_get_public_key_pem(e2ee_server) };
+            let e2ee_client =
+                unsafe { e2ee_client_new_from_public_pem(public_key_pem) };
+            let message = to_c_string("Hello, golden files!");
+            let encrypted = unsafe { e2ee_client_encrypt(e2ee_client, message) };
+            let result = (from_c_string(public_key_pem), from_c_string(encrypted));
+            unsafe { e2ee_server_free_string(encrypted) };
+            unsafe { e2ee_server_free_string(message as *mut c_char) };
+            unsafe { e2ee_server_free_string(public_key_pem) };
+            unsafe { e2ee_client_free(e2ee_client) };
+            unsafe { e2ee_server_free(e2ee_server) };
+            result
+        };
+
+        e2ee_set_rng_seed_for_testing(42);
+        let golden = run();
+        e2ee_set_rng_seed_for_testing(42);
+        assert_eq!(run(), golden);
+        // The seed only applies to the thread that set it.
+        assert_ne!(std::thread::spawn(run).join().unwrap(), golden);
+        e2ee_clear_rng_seed_for_testing();
+        assert_ne!(run(), golden);
+    }
+
+    // Test the e2ee_stream_encrypt_* and e2ee_stream_decrypt_* functions
+    #[test]
+    fn test_e2ee_stream() {
+        let e2ee_server = e2ee_server_new(1024);
+        let public_key_pem = unsafe { e2ee_server_get_public_key_pem(e2ee_server) };
+        let e2ee_client = unsafe { e2ee_client_new_from_public_pem(public_key_pem) };
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let take = |bytes: *mut u8, len: usize| {
+            assert!(!bytes.is_null());
+            let taken = unsafe { std::slice::from_raw_parts(bytes, len) }.to_vec();
+            unsafe { e2ee_free_bytes(bytes, len) };
+            taken
+        };
+
+        let encryptor = unsafe { e2ee_stream_encrypt_begin(e2ee_client) };
+        let mut encrypted = Vec::new();
+        let mut len = 0;
+        for piece in data.chunks(10_000) {
+            let out = unsafe {
+                e2ee_stream_encrypt_update(
+                    encryptor,
+                    piece.as_ptr(),
+                    piece.len(),
+                    &mut len,
+                )
+            };
+            encrypted.extend(take(out, len));
+        }
+        let out = unsafe { e2ee_stream_encrypt_finish(encryptor, &mut len) };
+        encrypted.extend(take(out, len));
+
+        let decrypt = |encrypted: &[u8]| {
+            let decryptor = unsafe { e2ee_stream_decrypt_begin(e2ee_server) };
+            let mut decrypted = Vec::new();
+            let mut len = 0;
+            for piece in encrypted.chunks(4_000) {
+                let out = unsafe {
+                    e2ee_stream_decrypt_update(
+                        decryptor,
+                        piece.as_ptr(),
+                        piece.len(),
+                        &mut len,
+                    )
+                };
+                decrypted.extend(take(out, len));
+            }
+            let out = unsafe { e2ee_stream_decrypt_finish(decryptor, &mut len) };
+            (!out.is_null()).then(|| {
+                decrypted.extend(take(out, len));
+                decrypted
+            })
+        };
+        assert_eq!(decrypt(&encrypted).unwrap(), data);
+        assert!(decrypt(&encrypted[..encrypted.len() - 1]).is_none());
+
+        unsafe { e2ee_stream_decrypt_free(std::ptr::null_mut()) };
+        unsafe { e2ee_server_free_string(public_key_pem) };
+        unsafe { e2ee_client_free(e2ee_client) };
+        unsafe { e2ee_server_free(e2ee_server) };
+    }
+
+    // Test a handshake, a message and a saved session through the session functions
+    #[cfg(feature = "session")]
+    #[test]
+    fn test_e2ee_session() {
+        let alice = e2ee_server_new(1024);
+        let bob = e2ee_server_new(1024);
+        let alice_pem = unsafe { e2ee_server_get_public_key_pem(alice) };
+        let bob_pem = unsafe { e2ee_server_get_public_key_pem(bob) };
+        let alice_public = unsafe { e2ee_client_new_from_public_pem(alice_pem) };
+        let bob_public = unsafe { e2ee_client_new_from_public_pem(bob_pem) };
+
+        let (mut hello, mut hello_len) = (std::ptr::null_mut(), 0);
+        let handshake =
+            unsafe { e2ee_session_new_initiator(alice, &mut hello, &mut hello_len) };
+        assert!(!handshake.is_null());
+        let (mut reply, mut reply_len) = (std::ptr::null_mut(), 0);
+        let bob_session = unsafe {
+            e2ee_session_new_responder(
+                bob,
+                alice_public,
+                hello,
+                hello_len,
+                &mut reply,
+                &mut reply_len,
+            )
+        };
+        assert!(!bob_session.is_null());
+        let alice_session = unsafe {
+            e2ee_session_complete(handshake, bob_public, reply, reply_len)
+        };
+        assert!(!alice_session.is_null());
+
+        let storage_key = [9u8; 32];
+        let mut saved_len = 0;
+        let saved = unsafe {
+            e2ee_session_serialize(
+                alice_session,
+                storage_key.as_ptr(),
+                &mut saved_len,
+            )
+        };
+        unsafe { e2ee_session_free(alice_session) };
+        let alice_session = unsafe {
+            e2ee_session_deserialize(storage_key.as_ptr(), saved, saved_len)
+        };
+        assert!(!alice_session.is_null());
+        assert!(unsafe {
+            e2ee_session_deserialize([0u8; 32].as_ptr(), saved, saved_len)
+        }
+        .is_null());
+
+        let mut message_len = 0;
+        let message = unsafe {
+            e2ee_session_encrypt(alice_session, b"Hi".as_ptr(), 2, &mut message_len)
+        };
+        let mut plaintext_len = 0;
+        let plaintext = unsafe {
+            e2ee_session_decrypt(
+                bob_session,
+                message,
+                message_len,
+                &mut plaintext_len,
+            )
+        };
+        assert_eq!(
+            unsafe { std::slice::from_raw_parts(plaintext, plaintext_len) },
+            b"Hi"
+        );
+        // A replayed message is rejected.
+        assert!(unsafe {
+            e2ee_session_decrypt(
+                bob_session,
+                message,
+                message_len,
+                &mut plaintext_len,
+            )
+        }
+        .is_null());
+
+        unsafe {
+            e2ee_free_bytes(plaintext, plaintext_len);
+            e2ee_free_bytes(message, message_len);
+            e2ee_free_bytes(saved, saved_len);
+            e2ee_free_bytes(reply, reply_len);
+            e2ee_free_bytes(hello, hello_len);
+            e2ee_session_free(alice_session);
+            e2ee_session_free(bob_session);
+            e2ee_client_free(alice_public);
+            e2ee_client_free(bob_public);
+            e2ee_server_free_string(alice_pem);
+            e2ee_server_free_string(bob_pem);
+            e2ee_server_free(alice);
+            e2ee_server_free(bob);
+        }
+    }
+
+    // Test generating a key in a keystore and using it after reopening the keystore
+    #[cfg(feature = "keystore")]
+    #[test]
+    fn test_e2ee_keystore() {
+        let path = std::env::temp_dir()
+            .join(format!("e2ee-ffi-keystore-{}", std::process::id()));
+        let path_c = to_c_string(path.to_str().unwrap());
+        let passphrase_c = to_c_string("passphrase");
+        let name_c = to_c_string("device");
+
+        let keystore = unsafe { e2ee_keystore_create(path_c, passphrase_c) };
+        assert!(!keystore.is_null());
+        assert_eq!(unsafe { e2ee_keystore_generate(keystore, name_c, 1024) }, 0);
+        assert_eq!(
+            unsafe { e2ee_keystore_generate(keystore, name_c, 1024) },
+            -1
+        );
+        unsafe { e2ee_keystore_free(keystore) };
+        assert!(
+            unsafe { e2ee_keystore_open(path_c, to_c_string("wrong")) }.is_null()
+        );
+
+        let keystore = unsafe { e2ee_keystore_open(path_c, passphrase_c) };
+        let names = unsafe { e2ee_keystore_list(keystore) };
+        assert_eq!(from_c_string(names), "device");
+        let e2ee_server = unsafe { e2ee_keystore_server(keystore, name_c) };
+        let e2ee_client = unsafe { e2ee_keystore_client(keystore, name_c) };
+        let encrypted =
+            unsafe { e2ee_client_encrypt(e2ee_client, to_c_string("Hi")) };
+        let decrypted = unsafe { e2ee_server_decrypt(e2ee_server, encrypted) };
+        assert_eq!(from_c_string(decrypted), "Hi");
+        assert_eq!(unsafe { e2ee_keystore_remove(keystore, name_c) }, 0);
+        assert!(unsafe { e2ee_keystore_server(keystore, name_c) }.is_null());
+
+        unsafe {
+            e2ee_server_free_string(decrypted);
+            e2ee_server_free_string(encrypted);
+            e2ee_server_free_string(names);
+            e2ee_client_free(e2ee_client);
+            e2ee_server_free(e2ee_server);
+            e2ee_keystore_free(keystore);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // Test keeping a keystore in a storage backend implemented with callbacks
+    #[cfg(feature = "keystore")]
+    #[test]
+    fn test_e2ee_keystore_in_storage() {
+        use crate::storage::MemoryStorage;
+
+        fn storage<'a>(context: *mut c_void) -> &'a MemoryStorage {
+            unsafe { &*(context as *const MemoryStorage) }
+        }
+        extern "C" fn get(
+            context: *mut c_void,
+            key: *const c_char,
+            value: *mut *mut u8,
+            value_len: *mut usize,
+        ) -> c_int {
+            let key = unsafe { CStr::from_ptr(key) }.to_str().unwrap();
+            match storage(context).get(key).unwrap() {
+                Some(stored) => {
+                    let stored = Box::leak(stored.into_boxed_slice());
+                    unsafe {
+                        *value_len = stored.len();
+                        *value = stored.as_mut_ptr();
+                    }
+                    0
+                }
+                None => 1,
+            }
+        }
+        extern "C" fn free_value(_context: *mut c_void, value: *mut u8, len: usize) {
+            drop(unsafe {
+                Box::from_raw(std::ptr::slice_from_raw_parts_mut(value, len))
+            });
+        }
+        extern "C" fn list(
+            context: *mut c_void,
+            prefix: *const c_char,
+            push: E2eeStorageListCallback,
+            push_context: *mut c_void,
+        ) -> c_int {
+            let prefix = unsafe { CStr::from_ptr(prefix) }.to_str().unwrap();
+            for key in storage(context).list(prefix).unwrap() {
+                push(CString::new(key).unwrap().as_ptr(), push_context);
+            }
+            0
+        }
+        extern "C" fn apply(
+            context: *mut c_void,
+            operations: *const E2eeStorageOperation,
+            count: usize,
+        ) -> c_int {
+            let mut batch = Batch::new();
+            for operation in unsafe { std::slice::from_raw_parts(operations, count) }
+            {
+                let key = unsafe { CStr::from_ptr(operation.key) }.to_str().unwrap();
+                batch = if operation.value.is_null() {
+                    batch.delete(key)
+                } else {
+                    let value = unsafe {
+                        std::slice::from_raw_parts(
+                            operation.value,
+                            operation.value_len,
+                        )
+                    };
+                    batch.put(key, value.to_vec())
+                };
+            }
+            storage(context).apply(batch).map_or(-1, |_| 0)
+        }
+
+        let memory = MemoryStorage::new();
+        let backend = E2eeStorageBackend {
+            context: &memory as *const MemoryStorage as *mut c_void,
+            get,
+            free_value,
+            list,
+            apply,
+        };
+        let key_c = to_c_string("keystores/default");
+        let passphrase_c = to_c_string("secret");
+        let name_c = to_c_string("signing");
+
+        let keystore = unsafe {
+            e2ee_keystore_create_in_storage(&backend, key_c, passphrase_c)
+        };
+        assert!(!keystore.is_null());
+        assert_eq!(unsafe { e2ee_keystore_generate(keystore, name_c, 1024) }, 0);
+        unsafe { e2ee_keystore_free(keystore) };
+        assert!(unsafe {
+            e2ee_keystore_create_in_storage(&backend, key_c, passphrase_c)
+        }
+        .is_null());
+        assert_eq!(
+            HostStorage(backend).list("keystores/").unwrap(),
+            ["keystores/default"]
+        );
+
+        let keystore =
+            unsafe { e2ee_keystore_open_in_storage(&backend, key_c, passphrase_c) };
+        let names = unsafe { e2ee_keystore_list(keystore) };
+        assert_eq!(from_c_string(names), "signing");
+        unsafe {
+            e2ee_server_free_string(names);
+            e2ee_keystore_free(keystore);
+        }
+    }
+
     // Test the e2ee_server_get_public_key_pem function
     #[test]
     fn test_e2ee_server_get_public_key_pem() {
@@ -0,0 +1,286 @@
+//! End-to-end encryption of gRPC messages with [`tonic`].
+//!
+//! This module is enabled by the `tonic` feature. [`EnvelopeCodec`] is a tonic codec that encodes
+//! [`prost`] messages and wraps each of them in an envelope (see the `envelope` module), so that
+//! message payloads stay confidential across a proxy that terminates TLS. Each side holds its own
+//! `E2ee` instance to decrypt incoming messages, and the public key of its peer to encrypt outgoing
+//! ones ([`CodecKeys`]).
+//!
+//! Generated clients and servers create their codec with `Default::default()`. Install the keys
+//! once at startup with [`install_codec_keys`] and point `tonic-build` at the codec:
+//!
+//! ```text
+//! tonic_build::manual::Method::builder().codec_path("e2ee::grpc::EnvelopeCodec")
+//! ```
+//!
+//! To encrypt only some fields of a message, declare them as `bytes` and fill them with
+//! `PublicE2ee::encrypt_envelope` instead.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::grpc::{install_codec_keys, CodecKeys};
+//! use e2ee::server::{E2ee, KeySize};
+//! use std::sync::Arc;
+//!
+//! let own = Arc::new(E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance"));
+//! # let peer_public_key = own.get_public_key().clone();
+//! install_codec_keys(CodecKeys::new(own, peer_public_key)).expect("Keys already installed");
+//! ```
+use crate::envelope;
+use crate::server::E2ee;
+use prost::{bytes::Buf, bytes::BufMut, Message};
+use rsa::RsaPublicKey;
+use std::{
+    marker::PhantomData,
+    sync::{Arc, OnceLock},
+};
+use tonic::{
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder},
+    Status,
+};
+
+static INSTALLED_KEYS: OnceLock<Arc<CodecKeys>> = OnceLock::new();
+
+/// The keys used by an [`EnvelopeCodec`].
+#[derive(Debug)]
+pub struct CodecKeys {
+    own: Arc<E2ee>,
+    peer: RsaPublicKey,
+}
+
+impl CodecKeys {
+    /// Creates codec keys decrypting incoming messages with `own` and encrypting outgoing messages
+    /// for `peer`.
+    pub fn new(own: Arc<E2ee>, peer: RsaPublicKey) -> Self {
+        Self { own, peer }
+    }
+}
+
+/// Installs the keys used by codecs created with `EnvelopeCodec::default()`.
+///
+/// # Errors
+///
+/// This function returns the keys back if keys were already installed.
+pub fn install_codec_keys(keys: CodecKeys) -> Result<(), CodecKeys> {
+    let keys = Arc::new(keys);
+    INSTALLED_KEYS
+        .set(keys)
+        .map_err(|keys| Arc::into_inner(keys).expect("the keys were not shared"))
+}
+
+/// A tonic codec encrypting outgoing messages of type `T` and decrypting incoming messages of type
+/// `U`.
+///
+/// Messages that fail to decrypt are rejected with `Status::invalid_argument`, without detail. A
+/// codec without keys (created with `default()` before [`install_codec_keys`]) fails every
+/// message with `Status::failed_precondition`.
+#[derive(Debug)]
+pub struct EnvelopeCodec<T, U> {
+    keys: Option<Arc<CodecKeys>>,
+    _marker: PhantomData<(T, U)>,
+}
+
+impl<T, U> EnvelopeCodec<T, U> {
+    /// Creates a codec using `keys`.
+    pub fn new(keys: Arc<CodecKeys>) -> Self {
+        Self {
+            keys: Some(keys),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, U> Default for EnvelopeCodec<T, U> {
+    /// Creates a codec using the keys installed with [`install_codec_keys`].
+    fn default() -> Self {
+        Self {
+            keys: INSTALLED_KEYS.get().cloned(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T, U> Codec for EnvelopeCodec<T, U>
+where
+    T: Message + Send + 'static,
+    U: Message + Default + Send + 'static,
+{
+    type Encode = T;
+    type Decode = U;
+    type Encoder = EnvelopeEncoder<T>;
+    type Decoder = EnvelopeDecoder<U>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        EnvelopeEncoder {
+            keys: self.keys.clone(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        EnvelopeDecoder {
+            keys: self.keys.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+fn missing_keys() -> Status {
+    Status::failed_precondition("no e2ee codec keys installed")
+}
+
+/// The encoder of an [`EnvelopeCodec`].
+#[derive(Debug)]
+pub struct EnvelopeEncoder<T> {
+    keys: Option<Arc<CodecKeys>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Message> EnvelopeEncoder<T> {
+    /// Encodes `item` and encrypts it for the peer.
+    #[allow(clippy::result_large_err)] // `Status` is the error type required by tonic.
+    fn seal(&self, item: &T) -> Result<Vec<u8>, Status> {
+        let keys = self.keys.as_ref().ok_or_else(missing_keys)?;
+        envelope::seal(&keys.peer, &item.encode_to_vec())
+            .map_err(|_| Status::internal("failed to encrypt message"))
+    }
+}
+
+impl<T: Message> Encoder for EnvelopeEncoder<T> {
+    type Item = T;
+    type Error = Status;
+
+    fn encode(&mut self, item: T, dst: &mut EncodeBuf<'_>) -> Result<(), Status> {
+        dst.put_slice(&self.seal(&item)?);
+        Ok(())
+    }
+}
+
+/// The decoder of an [`EnvelopeCodec`].
+#[derive(Debug)]
+pub struct EnvelopeDecoder<U> {
+    keys: Option<Arc<CodecKeys>>,
+    _marker: PhantomData<U>,
+}
+
+impl<U: Message + Default> EnvelopeDecoder<U> {
+    /// Decrypts `envelope` with the own key and decodes the message.
+    #[allow(clippy::result_large_err)] // `Status` is the error type required by tonic.
+    fn open(&self, envelope: &[u8]) -> Result<U, Status> {
+        let keys = self.keys.as_ref().ok_or_else(missing_keys)?;
+        let payload = keys
+            .own
+            .decrypt_envelope(envelope)
+            .map_err(|_| Status::invalid_argument("failed to decrypt message"))?;
+        U::decode(payload.as_slice())
+            .map_err(|_| Status::invalid_argument("failed to decode message"))
+    }
+}
+
+impl<U: Message + Default> Decoder for EnvelopeDecoder<U> {
+    type Item = U;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<U>, Status> {
+        let envelope = src.copy_to_bytes(src.remaining());
+        self.open(&envelope).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+    use http_body_util::{BodyExt, Full};
+    use prost::bytes::Bytes;
+    use std::future::{ready, Ready};
+    use tonic::{server::UnaryService, Code, Request, Response};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Echo {
+        #[prost(string, tag = "1")]
+        text: String,
+    }
+
+    #[derive(Clone)]
+    struct EchoService;
+
+    impl UnaryService<Echo> for EchoService {
+        type Response = Echo;
+        type Future = Ready<Result<Response<Echo>, Status>>;
+
+        fn call(&mut self, request: Request<Echo>) -> Self::Future {
+            let mut echo = request.into_inner();
+            echo.text = echo.text.to_uppercase();
+            ready(Ok(Response::new(echo)))
+        }
+    }
+
+    /// Frames `message` as a gRPC message: an uncompressed flag and a big-endian length.
+    fn frame(message: &[u8]) -> Bytes {
+        let mut framed = vec![0];
+        framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+        framed.extend_from_slice(message);
+        framed.into()
+    }
+
+    fn keys() -> (Arc<CodecKeys>, Arc<CodecKeys>) {
+        let client = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let server = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let client_keys =
+            CodecKeys::new(client.clone(), server.get_public_key().clone());
+        let server_keys = CodecKeys::new(server, client.get_public_key().clone());
+        (Arc::new(client_keys), Arc::new(server_keys))
+    }
+
+    async fn call(
+        server_keys: Arc<CodecKeys>,
+        body: Bytes,
+    ) -> http::Response<tonic::body::BoxBody> {
+        let mut grpc =
+            tonic::server::Grpc::new(EnvelopeCodec::<Echo, Echo>::new(server_keys));
+        let request = http::Request::post("/echo.Echo/Echo")
+            .header("content-type", "application/grpc")
+            .body(Full::new(body))
+            .unwrap();
+        grpc.unary(EchoService, request).await
+    }
+
+    #[tokio::test]
+    async fn test_codec_roundtrip_through_server() {
+        let (client_keys, server_keys) = keys();
+        let mut client = EnvelopeCodec::<Echo, Echo>::new(client_keys);
+        let request = Echo {
+            text: "hello, grpc".into(),
+        };
+        let sealed = client.encoder().seal(&request).unwrap();
+        assert_ne!(sealed, request.encode_to_vec());
+
+        let response = call(server_keys, frame(&sealed)).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let reply = client.decoder().open(&body[5..]).unwrap();
+        assert_eq!(reply.text, "HELLO, GRPC");
+    }
+
+    #[tokio::test]
+    async fn test_codec_rejects_unencrypted_messages() {
+        let (_, server_keys) = keys();
+        let plain = Echo {
+            text: "hello, grpc".into(),
+        };
+        let response = call(server_keys, frame(&plain.encode_to_vec())).await;
+        let status = Status::from_header_map(response.headers()).unwrap();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_codec_without_keys_fails() {
+        let mut codec = EnvelopeCodec::<Echo, Echo> {
+            keys: None,
+            _marker: PhantomData,
+        };
+        let status = codec.encoder().seal(&Echo::default()).unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+}
@@ -0,0 +1,400 @@
+//! Identity bundles, the single artifact two apps exchange to pair.
+//!
+//! An [`IdentityBundle`] gathers what a peer needs to talk to a user: the key to encrypt for, the
+//! identity key that signs (see the `sealed_sender` and `devices` modules), the signed device list
+//! of the identity, if any, and an optional expiry. [`export_bundle`] signs the bundle with the
+//! identity key itself, and [`import_bundle`] checks that self-signature, the device list and the
+//! expiry.
+//!
+//! A self-signature proves that the bundle was not altered since its owner exported it, not who
+//! the owner is: compare [`IdentityBundle::fingerprint`] out of band (e.g. read aloud, or by
+//! scanning a QR code in person) before trusting an imported bundle.
+//!
+//! The exported bundle is a JSON object, in the layout of signed device lists:
+//!
+//! ```json
+//! {
+//!   "document": "{\"devices\":{...},\"encryption_key\":\"MIIB...\",\"expires_at\":1700000000,\"signing_key\":\"MIIB...\",\"version\":1}",
+//!   "signature": "kX2v..."
+//! }
+//! ```
+//!
+//! Keys are base64-encoded SPKI DER. [`IdentityBundle::to_uri`] wraps the exported bundle in an
+//! `e2ee:identity:` URI (see the `uri` module), to be shared as a link or shown as a QR code: with
+//! two 2048-bit keys and no devices, it is under 2 KB long, within the capacity of a QR code in
+//! byte mode. Larger bundles are split into several codes with `uri::split`.
+//!
+//! This module is enabled by the default `io` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::identity::IdentityBundle;
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let bundle = IdentityBundle::new(alice.get_public_key(), alice.get_public_key());
+//! let uri = bundle.to_uri(&alice).expect("Failed to export bundle");
+//!
+//! // Bob scans the QR code showing the URI, and compares the fingerprints with Alice.
+//! let imported = IdentityBundle::from_uri(&uri).expect("Invalid bundle");
+//! assert_eq!(imported.fingerprint().unwrap(), bundle.fingerprint().unwrap());
+//! assert_eq!(imported.encryption_key(), alice.get_public_key());
+//! ```
+use crate::core;
+use crate::devices::{DevicesError, SignedDeviceList};
+use crate::server::{E2ee, E2eeError};
+use crate::uri::{self, Uri};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// The version of the bundle document.
+pub const VERSION: u64 = 1;
+
+/// Separates the self-signatures of bundles from other signatures of the identity key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee identity bundle v1\0";
+
+pub type IdentityResult<T> = std::result::Result<T, IdentityError>;
+
+/// An error returned when exporting or importing an identity bundle.
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Signing failed: {0}")]
+    Sign(#[from] E2eeError),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Invalid device list: {0}")]
+    Devices(#[from] DevicesError),
+
+    #[error("Malformed identity bundle: {0}")]
+    Malformed(&'static str),
+
+    #[error("Unsupported identity bundle version {0}")]
+    UnsupportedVersion(u64),
+
+    #[error("Invalid {0} key: {1}")]
+    InvalidKey(&'static str, String),
+
+    #[error("The bundle must be exported with its signing key")]
+    WrongSigningKey,
+
+    #[error("Invalid bundle self-signature")]
+    InvalidSignature,
+
+    #[error("The identity bundle has expired")]
+    Expired,
+
+    #[error("Invalid identity bundle URI")]
+    InvalidUri,
+}
+
+/// The public keys and devices of an identity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdentityBundle {
+    encryption_key: RsaPublicKey,
+    signing_key: RsaPublicKey,
+    devices: Option<SignedDeviceList>,
+    expires_at: Option<u64>,
+}
+
+impl IdentityBundle {
+    /// Creates a bundle with the key peers encrypt for and the identity key that signs, which may
+    /// be the same key.
+    pub fn new(encryption_key: &RsaPublicKey, signing_key: &RsaPublicKey) -> Self {
+        Self {
+            encryption_key: encryption_key.clone(),
+            signing_key: signing_key.clone(),
+            devices: None,
+            expires_at: None,
+        }
+    }
+
+    /// Adds the device list of the identity, signed by the signing key (see
+    /// `DeviceList::sign`).
+    pub fn with_devices(mut self, devices: SignedDeviceList) -> Self {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Makes the bundle expire at `expires_at`, rounded down to the second.
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(
+            expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_secs()),
+        );
+        self
+    }
+
+    /// Returns the key peers encrypt for.
+    pub fn encryption_key(&self) -> &RsaPublicKey {
+        &self.encryption_key
+    }
+
+    /// Returns the identity key, which signs the bundle and the device list.
+    pub fn signing_key(&self) -> &RsaPublicKey {
+        &self.signing_key
+    }
+
+    /// Returns the signed device list of the identity, verified on import.
+    pub fn devices(&self) -> Option<&SignedDeviceList> {
+        self.devices.as_ref()
+    }
+
+    /// Returns the expiry of the bundle, if any.
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        self.expires_at.and_then(expiry_time)
+    }
+
+    /// Returns whether the bundle has expired at `now`.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at()
+            .is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// Returns the fingerprint of the identity, the key ID of its signing key (see
+    /// `core::key_id`), to be compared out of band.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be DER-encoded.
+    pub fn fingerprint(&self) -> IdentityResult<String> {
+        Ok(core::key_id(&self.signing_key)?)
+    }
+
+    /// Exports the bundle with [`export_bundle`] and wraps it in an `e2ee:identity:` URI.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`export_bundle`].
+    pub fn to_uri(&self, signer: &E2ee) -> IdentityResult<String> {
+        Ok(uri::encode_identity(&export_bundle(self, signer)?))
+    }
+
+    /// Imports a bundle from a URI returned by [`IdentityBundle::to_uri`], with [`import_bundle`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `IdentityError::InvalidUri` if `uri` is not an identity bundle URI,
+    /// or the errors of [`import_bundle`].
+    pub fn from_uri(uri: &str) -> IdentityResult<Self> {
+        match uri::decode(uri) {
+            Ok(Uri::Identity(exported)) => import_bundle(&exported),
+            _ => Err(IdentityError::InvalidUri),
+        }
+    }
+}
+
+/// Signs `bundle` with the identity key of `signer` and encodes it as JSON.
+///
+/// # Errors
+///
+/// This function returns `IdentityError::WrongSigningKey` if `signer` does not hold the signing
+/// key of the bundle, or an error if a key cannot be encoded or signing fails.
+pub fn export_bundle(
+    bundle: &IdentityBundle,
+    signer: &E2ee,
+) -> IdentityResult<String> {
+    if signer.get_public_key() != &bundle.signing_key {
+        return Err(IdentityError::WrongSigningKey);
+    }
+    let mut document = json!({
+        "version": VERSION,
+        "encryption_key": encode_key(&bundle.encryption_key)?,
+        "signing_key": encode_key(&bundle.signing_key)?,
+    });
+    if let Some(devices) = &bundle.devices {
+        document["devices"] = serde_json::from_str(&devices.to_json())?;
+    }
+    if let Some(expires_at) = bundle.expires_at {
+        document["expires_at"] = expires_at.into();
+    }
+    let document = document.to_string();
+    let signature =
+        signer.sign(&[SIGNATURE_CONTEXT, document.as_bytes()].concat())?;
+    Ok(json!({
+        "document": document,
+        "signature": STANDARD.encode(signature),
+    })
+    .to_string())
+}
+
+/// Decodes a bundle exported with [`export_bundle`], and checks its self-signature, its device
+/// list and its expiry.
+///
+/// # Errors
+///
+/// This function returns `IdentityError::InvalidSignature` if the bundle was not signed by its
+/// signing key, `IdentityError::Devices` if the device list was not signed by it,
+/// `IdentityError::Expired` if the bundle has expired, or an error if the bundle or one of its
+/// keys is malformed.
+pub fn import_bundle(exported: &str) -> IdentityResult<IdentityBundle> {
+    let value: Value = serde_json::from_str(exported)?;
+    let document = value
+        .get("document")
+        .and_then(Value::as_str)
+        .ok_or(IdentityError::Malformed("missing document"))?;
+    let signature = value
+        .get("signature")
+        .and_then(Value::as_str)
+        .and_then(|signature| STANDARD.decode(signature).ok())
+        .ok_or(IdentityError::Malformed("missing or invalid signature"))?;
+
+    let fields: Value = serde_json::from_str(document)?;
+    let version = fields
+        .get("version")
+        .and_then(Value::as_u64)
+        .ok_or(IdentityError::Malformed("missing version"))?;
+    if version != VERSION {
+        return Err(IdentityError::UnsupportedVersion(version));
+    }
+    let signing_key = decode_key(&fields, "signing_key", "signing")?;
+    core::verify(
+        &signing_key,
+        &[SIGNATURE_CONTEXT, document.as_bytes()].concat(),
+        &signature,
+    )
+    .map_err(|_| IdentityError::InvalidSignature)?;
+
+    let devices = match fields.get("devices") {
+        Some(devices) => {
+            let devices = SignedDeviceList::from_json(&devices.to_string())?;
+            devices.verify(&signing_key)?;
+            Some(devices)
+        }
+        None => None,
+    };
+    let expires_at = match fields.get("expires_at") {
+        Some(expires_at) => Some(
+            expires_at
+                .as_u64()
+                .filter(|&seconds| expiry_time(seconds).is_some())
+                .ok_or(IdentityError::Malformed("invalid expires_at"))?,
+        ),
+        None => None,
+    };
+    let bundle = IdentityBundle {
+        encryption_key: decode_key(&fields, "encryption_key", "encryption")?,
+        signing_key,
+        devices,
+        expires_at,
+    };
+    if bundle.is_expired_at(SystemTime::now()) {
+        return Err(IdentityError::Expired);
+    }
+    Ok(bundle)
+}
+
+/// Returns the time `seconds` after the Unix epoch, or `None` if `SystemTime` cannot hold it.
+fn expiry_time(seconds: u64) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+fn encode_key(public_key: &RsaPublicKey) -> IdentityResult<String> {
+    Ok(STANDARD.encode(public_key.to_public_key_der()?.as_bytes()))
+}
+
+/// Decodes and validates the key in the field `name` of `fields`.
+fn decode_key(
+    fields: &Value,
+    name: &str,
+    role: &'static str,
+) -> IdentityResult<RsaPublicKey> {
+    let der = fields
+        .get(name)
+        .and_then(Value::as_str)
+        .and_then(|key| STANDARD.decode(key).ok())
+        .ok_or(IdentityError::Malformed("missing or invalid key"))?;
+    let public_key = RsaPublicKey::from_public_key_der(&der)
+        .map_err(|err| IdentityError::InvalidKey(role, err.to_string()))?;
+    core::validate_public_key(public_key.n(), public_key.e())
+        .map_err(|reason| IdentityError::InvalidKey(role, reason))?;
+    Ok(public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::DeviceList;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_bundle_roundtrip_with_devices() {
+        let identity = E2ee::new(KeySize::Bit1024).unwrap();
+        let device = E2ee::new(KeySize::Bit1024).unwrap();
+        let mut list = DeviceList::new(identity.get_public_key()).unwrap();
+        list.add_device("phone", device.get_public_key().clone())
+            .unwrap();
+        let bundle =
+            IdentityBundle::new(device.get_public_key(), identity.get_public_key())
+                .with_devices(list.sign(&identity).unwrap())
+                .with_expiry(SystemTime::now() + Duration::from_secs(3600));
+
+        let imported = import_bundle(&export_bundle(&bundle, &identity).unwrap());
+        assert_eq!(imported.unwrap(), bundle);
+        let uri = bundle.to_uri(&identity).unwrap();
+        assert!(uri.starts_with(uri::IDENTITY_PREFIX));
+        assert_eq!(IdentityBundle::from_uri(&uri).unwrap(), bundle);
+        assert!(matches!(
+            export_bundle(&bundle, &device),
+            Err(IdentityError::WrongSigningKey)
+        ));
+        assert!(matches!(
+            IdentityBundle::from_uri("e2ee:pk:AAAA"),
+            Err(IdentityError::InvalidUri)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_and_expired_bundles_are_rejected() {
+        let identity = E2ee::new(KeySize::Bit1024).unwrap();
+        let other = E2ee::new(KeySize::Bit1024).unwrap();
+        let bundle = IdentityBundle::new(
+            identity.get_public_key(),
+            identity.get_public_key(),
+        );
+        let mut value: Value =
+            serde_json::from_str(&export_bundle(&bundle, &identity).unwrap())
+                .unwrap();
+        let document = value["document"].as_str().unwrap().to_string();
+        value["document"] = document
+            .replacen(
+                &encode_key(identity.get_public_key()).unwrap(),
+                &encode_key(other.get_public_key()).unwrap(),
+                1,
+            )
+            .into();
+        assert!(matches!(
+            import_bundle(&value.to_string()),
+            Err(IdentityError::InvalidSignature)
+        ));
+
+        let expired = bundle.clone().with_expiry(UNIX_EPOCH);
+        assert!(matches!(
+            import_bundle(&export_bundle(&expired, &identity).unwrap()),
+            Err(IdentityError::Expired)
+        ));
+
+        // A self-signed expiry beyond the range of `SystemTime` is malformed, not a panic.
+        let unbounded = IdentityBundle {
+            expires_at: Some(u64::MAX),
+            ..bundle
+        };
+        assert!(matches!(
+            import_bundle(&export_bundle(&unbounded, &identity).unwrap()),
+            Err(IdentityError::Malformed("invalid expires_at"))
+        ));
+    }
+}
@@ -0,0 +1,797 @@
+//! Incremental encrypted backups, with content-defined chunking.
+//!
+//! An [`IncrementalBackup`] splits files into chunks whose boundaries depend on their content
+//! (see [`Chunker`]), so that an insertion or a change only alters the chunks around it. Each
+//! chunk is identified by a keyed hash of its content, a [`ChunkId`], and encrypted into an
+//! envelope for the recipients (see `envelope::seal_for_recipients`). The files and the IDs of
+//! their chunks are listed in a [`Manifest`], itself encrypted into an envelope.
+//!
+//! A backup started from the manifest of the previous one only emits the chunks that the store
+//! does not hold yet: unchanged files, and the unchanged parts of changed files, are not uploaded
+//! again. The chunk IDs are keyed with a secret kept in the manifest, so the store cannot confirm
+//! the content of a chunk by hashing a guess.
+//!
+//! [`Manifest::patch_file`] applies the same deduplication to a single changed file, for sync
+//! tools: it re-encrypts only the chunks of the new content that the store does not hold, and
+//! returns them as a [`Patch`] along with the updated manifest and the chunks no longer needed.
+//!
+//! The manifest also records the SHA-256 digest of each chunk envelope, and
+//! [`Manifest::merkle_tree`] commits to all of them: once its root is signed (see
+//! `merkle::MerkleTree::sign`), a single chunk can be checked with a Merkle proof, and the chunks
+//! a store tampered with can be located from their digests alone, without downloading and
+//! decrypting the whole backup.
+//!
+//! The manifest is encoded as:
+//!
+//! ```text
+//! version (1 byte) | ID key (32 bytes) | file count (4 bytes)
+//!     | (path length (2 bytes) | path | size (8 bytes) | chunk count (4 bytes)
+//!         | (chunk ID (32 bytes) | envelope digest (32 bytes))...)...
+//! ```
+//!
+//! with integers in big endian and paths in UTF-8.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::incremental::{IncrementalBackup, Manifest};
+//! use std::collections::HashMap;
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let recipients = [private_key.to_public_key()];
+//! let mut store = HashMap::new();
+//!
+//! let report = vec![0x42; 100_000];
+//! let mut backup = IncrementalBackup::new(&recipients);
+//! for chunk in backup.add_file("report.pdf", &report).expect("Failed to add file") {
+//!     store.insert(chunk.id, chunk.envelope);
+//! }
+//! let (manifest, sealed_manifest) = backup.finish().expect("Failed to seal manifest");
+//!
+//! // The next backup only uploads new chunks: none for an unchanged file.
+//! let mut backup = IncrementalBackup::from_previous(&recipients, &manifest);
+//! assert!(backup.add_file("report.pdf", &report).unwrap().is_empty());
+//! let (_, sealed_manifest) = backup.finish().expect("Failed to seal manifest");
+//!
+//! let manifest = Manifest::open(&private_key, &sealed_manifest).expect("Failed to open manifest");
+//! let restored = manifest
+//!     .restore_file(&private_key, "report.pdf", |id| store.get(id).cloned())
+//!     .expect("Failed to restore file");
+//! assert_eq!(restored, report);
+//! ```
+use crate::core;
+use crate::envelope::{self, EnvelopeError};
+use crate::merkle::{self, MerkleTree};
+use hmac::{Hmac, Mac};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    sha2::Sha256,
+    RsaPrivateKey, RsaPublicKey,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the manifest format.
+pub const VERSION: u8 = 1;
+
+/// The length of the key of chunk IDs, in bytes.
+const ID_KEY_LEN: usize = 32;
+
+pub type IncrementalResult<T> = std::result::Result<T, IncrementalError>;
+
+/// An error returned when backing up or restoring files.
+#[derive(Error, Debug)]
+pub enum IncrementalError {
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Invalid chunk sizes: the average must be a power of two between the minimum and the maximum")]
+    InvalidChunkSizes,
+
+    #[error("Malformed manifest")]
+    Malformed,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Path {0:?} is too long or listed twice")]
+    InvalidPath(String),
+
+    #[error("No file {0:?} in the manifest")]
+    NotFound(String),
+
+    #[error("Chunk {0} is missing from the store")]
+    MissingChunk(ChunkId),
+
+    #[error("Chunk {0} does not match its ID or digest")]
+    CorruptChunk(ChunkId),
+}
+
+/// The keyed SHA-256 hash of the content of a chunk, under which it is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkId(pub [u8; 32]);
+
+impl fmt::Display for ChunkId {
+    /// Writes the ID as lowercase hex.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&core::hex(&self.0))
+    }
+}
+
+/// The random values of the gear hash, one per byte value, generated with SplitMix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6532_6565_2063_6463;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Splits data into chunks with FastCDC-style content-defined chunking.
+///
+/// A gear rolling hash is computed over the data, and a chunk ends where the hash matches a mask,
+/// so that boundaries move along with the content when bytes are inserted or removed. The mask is
+/// stricter before the average size than after it, which keeps the chunk sizes close to the
+/// average.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl Default for Chunker {
+    /// Chunks of 2 KiB to 64 KiB, 8 KiB on average.
+    fn default() -> Self {
+        Self {
+            min_size: 2 * 1024,
+            avg_size: 8 * 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
+impl Chunker {
+    /// Creates a chunker cutting chunks of `min_size` to `max_size` bytes, `avg_size` on average.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `IncrementalError::InvalidChunkSizes` unless `avg_size` is a power
+    /// of two and `0 < min_size <= avg_size <= max_size`.
+    pub fn new(
+        min_size: usize,
+        avg_size: usize,
+        max_size: usize,
+    ) -> IncrementalResult<Self> {
+        if min_size == 0
+            || !avg_size.is_power_of_two()
+            || avg_size < 4
+            || min_size > avg_size
+            || avg_size > max_size
+        {
+            return Err(IncrementalError::InvalidChunkSizes);
+        }
+        Ok(Self {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    /// Returns the chunks of `data`, in order.
+    pub fn chunks<'a>(&self, mut data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let chunker = *self;
+        std::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+            let (chunk, rest) = data.split_at(chunker.cut_point(data));
+            data = rest;
+            Some(chunk)
+        })
+    }
+
+    /// Returns the length of the first chunk of `data`.
+    fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let bits = self.avg_size.trailing_zeros();
+        // The low bits of the hash only depend on the last few bytes, the high bits on the last
+        // 64 bytes: the masks test the high bits.
+        let strict_mask = !(u64::MAX >> (bits + 1));
+        let loose_mask = !(u64::MAX >> (bits - 1));
+        let end = data.len().min(self.max_size);
+        let normal = end.min(self.avg_size);
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+            let mask = if i < normal { strict_mask } else { loose_mask };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// A file listed in a [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// The path of the file, as given to [`IncrementalBackup::add_file`].
+    pub path: String,
+    /// The size of the file, in bytes.
+    pub size: u64,
+    /// The IDs of the chunks of the file, in order.
+    pub chunks: Vec<ChunkId>,
+    /// The SHA-256 digests of the envelopes of the chunks, in order.
+    pub digests: Vec<[u8; 32]>,
+}
+
+/// The list of the files of a backup and of their chunks.
+#[derive(Clone)]
+pub struct Manifest {
+    id_key: Zeroizing<[u8; ID_KEY_LEN]>,
+    files: Vec<FileEntry>,
+}
+
+impl fmt::Debug for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Manifest")
+            .field("files", &self.files)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Manifest {
+    /// Returns the files of the backup, in the order they were added.
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// Returns the file at `path`.
+    pub fn file(&self, path: &str) -> Option<&FileEntry> {
+        self.files.iter().find(|file| file.path == path)
+    }
+
+    /// Returns the IDs of all the chunks the backup needs, e.g. to delete the other chunks of the
+    /// store once older manifests are discarded.
+    pub fn chunk_ids(&self) -> HashSet<ChunkId> {
+        self.files
+            .iter()
+            .flat_map(|file| file.chunks.iter().copied())
+            .collect()
+    }
+
+    /// Returns the Merkle tree over the envelope digests of the chunks of all the files, in order.
+    ///
+    /// Leaf `i` of the tree is the chunk returned by [`Manifest::chunk_at`] for `i`.
+    pub fn merkle_tree(&self) -> MerkleTree {
+        MerkleTree::new(
+            self.files
+                .iter()
+                .flat_map(|file| file.digests.iter().copied()),
+        )
+    }
+
+    /// Returns the file holding leaf `index` of [`Manifest::merkle_tree`], and the index of the
+    /// chunk in the file, e.g. to report the files hit by tampered chunks.
+    pub fn chunk_at(&self, mut index: usize) -> Option<(&FileEntry, usize)> {
+        for file in &self.files {
+            if index < file.chunks.len() {
+                return Some((file, index));
+            }
+            index -= file.chunks.len();
+        }
+        None
+    }
+
+    /// Re-encrypts the file at `path` with its new content `data`, or adds it if it is not in the
+    /// manifest, splitting it with the default [`Chunker`].
+    ///
+    /// Only the chunks of `data` that are not already in the backup are encrypted, so a small
+    /// edit of a large file uploads a few chunks. The other files are left as they are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::core;
+    /// use e2ee::incremental::IncrementalBackup;
+    ///
+    /// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let recipients = [private_key.to_public_key()];
+    /// let mut data: Vec<u8> = (0..1_000_000u64).map(|i| (i * i >> 11) as u8).collect();
+    ///
+    /// let mut backup = IncrementalBackup::new(&recipients);
+    /// backup.add_file("disk.img", &data).expect("Failed to add file");
+    /// let (manifest, _) = backup.finish().expect("Failed to seal manifest");
+    ///
+    /// data[500_000] ^= 1;
+    /// let patch = manifest
+    ///     .patch_file(&recipients, "disk.img", &data)
+    ///     .expect("Failed to patch file");
+    /// assert!(patch.upload_size() < 100_000);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a chunk or the manifest cannot be encrypted.
+    pub fn patch_file(
+        &self,
+        recipients: &[RsaPublicKey],
+        path: &str,
+        data: &[u8],
+    ) -> IncrementalResult<Patch> {
+        self.patch_file_with_chunker(recipients, path, data, Chunker::default())
+    }
+
+    /// Re-encrypts a file like [`Manifest::patch_file`], splitting it with `chunker`, which must be
+    /// the chunker of the previous backups for their chunks to be reused.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`Manifest::patch_file`].
+    pub fn patch_file_with_chunker(
+        &self,
+        recipients: &[RsaPublicKey],
+        path: &str,
+        data: &[u8],
+        chunker: Chunker,
+    ) -> IncrementalResult<Patch> {
+        let mut backup =
+            IncrementalBackup::from_previous(recipients, self).with_chunker(chunker);
+        let mut upload = None;
+        for file in &self.files {
+            if file.path == path {
+                upload = Some(backup.add_file(path, data)?);
+            } else {
+                backup.manifest.files.push(file.clone());
+            }
+        }
+        let upload = match upload {
+            Some(upload) => upload,
+            None => backup.add_file(path, data)?,
+        };
+        let (manifest, sealed_manifest) = backup.finish()?;
+        let mut obsolete: Vec<_> = self
+            .chunk_ids()
+            .difference(&manifest.chunk_ids())
+            .copied()
+            .collect();
+        obsolete.sort_unstable();
+        Ok(Patch {
+            manifest,
+            sealed_manifest,
+            upload,
+            obsolete,
+        })
+    }
+
+    /// Decrypts a manifest sealed by [`IncrementalBackup::finish`] with `private_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the envelope cannot be opened with `private_key`, or if
+    /// the manifest is malformed.
+    pub fn open(
+        private_key: &RsaPrivateKey,
+        sealed: &[u8],
+    ) -> IncrementalResult<Self> {
+        let encoded = Zeroizing::new(envelope::open(private_key, sealed)?);
+        Self::decode(&encoded)
+    }
+
+    /// Restores the file at `path`, fetching its encrypted chunks with `fetch_chunk`, which
+    /// returns `None` for chunks missing from the store.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the file is not in the manifest, if a chunk is missing or
+    /// cannot be opened with `private_key`, or if a chunk does not match its ID or envelope digest,
+    /// e.g. because the store swapped two chunks.
+    pub fn restore_file(
+        &self,
+        private_key: &RsaPrivateKey,
+        path: &str,
+        mut fetch_chunk: impl FnMut(&ChunkId) -> Option<Vec<u8>>,
+    ) -> IncrementalResult<Vec<u8>> {
+        let file = self
+            .file(path)
+            .ok_or_else(|| IncrementalError::NotFound(path.to_owned()))?;
+        let mut data = Vec::with_capacity(file.size.try_into().unwrap_or_default());
+        for (id, digest) in file.chunks.iter().zip(&file.digests) {
+            let envelope =
+                fetch_chunk(id).ok_or(IncrementalError::MissingChunk(*id))?;
+            if merkle::chunk_digest(&envelope) != *digest {
+                return Err(IncrementalError::CorruptChunk(*id));
+            }
+            let chunk = envelope::open(private_key, &envelope)?;
+            if chunk_id(&self.id_key, &chunk) != *id {
+                return Err(IncrementalError::CorruptChunk(*id));
+            }
+            data.extend(chunk);
+        }
+        if data.len() as u64 != file.size {
+            return Err(IncrementalError::Malformed);
+        }
+        Ok(data)
+    }
+
+    fn encode(&self) -> Zeroizing<Vec<u8>> {
+        let mut encoded = Zeroizing::new(vec![VERSION]);
+        encoded.extend_from_slice(self.id_key.as_ref());
+        encoded.extend((self.files.len() as u32).to_be_bytes());
+        for file in &self.files {
+            encoded.extend((file.path.len() as u16).to_be_bytes());
+            encoded.extend_from_slice(file.path.as_bytes());
+            encoded.extend(file.size.to_be_bytes());
+            encoded.extend((file.chunks.len() as u32).to_be_bytes());
+            for (id, digest) in file.chunks.iter().zip(&file.digests) {
+                encoded.extend_from_slice(&id.0);
+                encoded.extend_from_slice(digest);
+            }
+        }
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> IncrementalResult<Self> {
+        let mut reader = Reader(encoded);
+        let version = reader.take::<1>()?[0];
+        if version != VERSION {
+            return Err(IncrementalError::UnsupportedVersion(version));
+        }
+        let id_key = Zeroizing::new(reader.take::<ID_KEY_LEN>()?);
+        let count = u32::from_be_bytes(reader.take()?);
+        let mut files = Vec::new();
+        for _ in 0..count {
+            let path_len = u16::from_be_bytes(reader.take()?);
+            let path = std::str::from_utf8(reader.slice(path_len.into())?)
+                .map_err(|_| IncrementalError::Malformed)?
+                .to_owned();
+            let size = u64::from_be_bytes(reader.take()?);
+            let chunk_count = u32::from_be_bytes(reader.take()?);
+            let (mut chunks, mut digests) = (Vec::new(), Vec::new());
+            for _ in 0..chunk_count {
+                chunks.push(ChunkId(reader.take()?));
+                digests.push(reader.take()?);
+            }
+            files.push(FileEntry {
+                path,
+                size,
+                chunks,
+                digests,
+            });
+        }
+        if !reader.0.is_empty() {
+            return Err(IncrementalError::Malformed);
+        }
+        Ok(Self { id_key, files })
+    }
+}
+
+/// A chunk to upload, emitted by [`IncrementalBackup::add_file`].
+#[derive(Debug, Clone)]
+pub struct NewChunk {
+    /// The ID to store the chunk under.
+    pub id: ChunkId,
+    /// The chunk, encrypted into an envelope for the recipients of the backup.
+    pub envelope: Vec<u8>,
+}
+
+/// The changes to a backup made by [`Manifest::patch_file`].
+#[derive(Debug)]
+pub struct Patch {
+    /// The manifest listing the new content of the file.
+    pub manifest: Manifest,
+    /// The manifest, encrypted for the recipients.
+    pub sealed_manifest: Vec<u8>,
+    /// The chunks to upload.
+    pub upload: Vec<NewChunk>,
+    /// The chunks of the previous manifest that the new one no longer needs. They can be deleted
+    /// from the store unless other kept manifests still list them.
+    pub obsolete: Vec<ChunkId>,
+}
+
+impl Patch {
+    /// Returns the number of bytes to upload, manifest excluded.
+    pub fn upload_size(&self) -> usize {
+        self.upload.iter().map(|chunk| chunk.envelope.len()).sum()
+    }
+}
+
+/// A backup in progress, emitting the encrypted chunks that the store does not hold yet.
+#[derive(Debug)]
+pub struct IncrementalBackup<'a> {
+    recipients: &'a [RsaPublicKey],
+    chunker: Chunker,
+    /// The envelope digests of the chunks in the store.
+    known: HashMap<ChunkId, [u8; 32]>,
+    manifest: Manifest,
+}
+
+impl<'a> IncrementalBackup<'a> {
+    /// Starts a first backup for `recipients`, which all chunks are emitted for.
+    pub fn new(recipients: &'a [RsaPublicKey]) -> Self {
+        let mut id_key = Zeroizing::new([0; ID_KEY_LEN]);
+        OsRng.fill_bytes(id_key.as_mut());
+        Self {
+            recipients,
+            chunker: Chunker::default(),
+            known: HashMap::new(),
+            manifest: Manifest {
+                id_key,
+                files: Vec::new(),
+            },
+        }
+    }
+
+    /// Starts a backup following the one of `previous`, whose chunks are assumed to be in the
+    /// store already and are not emitted again.
+    pub fn from_previous(
+        recipients: &'a [RsaPublicKey],
+        previous: &Manifest,
+    ) -> Self {
+        Self {
+            recipients,
+            chunker: Chunker::default(),
+            known: previous
+                .files
+                .iter()
+                .flat_map(|file| {
+                    file.chunks.iter().copied().zip(file.digests.clone())
+                })
+                .collect(),
+            manifest: Manifest {
+                id_key: previous.id_key.clone(),
+                files: Vec::new(),
+            },
+        }
+    }
+
+    /// Sets the chunker splitting files. Chunks are only deduplicated with backups that used the
+    /// same chunker.
+    pub fn with_chunker(mut self, chunker: Chunker) -> Self {
+        self.chunker = chunker;
+        self
+    }
+
+    /// Adds the file `data` at `path` to the backup, and returns its chunks that the store does
+    /// not hold yet, encrypted for the recipients.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the path is longer than 65535 bytes or was already added,
+    /// or if a chunk cannot be encrypted.
+    pub fn add_file(
+        &mut self,
+        path: &str,
+        data: &[u8],
+    ) -> IncrementalResult<Vec<NewChunk>> {
+        if u16::try_from(path.len()).is_err() || self.manifest.file(path).is_some() {
+            return Err(IncrementalError::InvalidPath(path.to_owned()));
+        }
+        let (mut chunks, mut digests) = (Vec::new(), Vec::new());
+        let mut new_chunks = Vec::new();
+        for chunk in self.chunker.chunks(data) {
+            let id = chunk_id(&self.manifest.id_key, chunk);
+            let digest = match self.known.get(&id) {
+                Some(digest) => *digest,
+                None => {
+                    let envelope =
+                        envelope::seal_for_recipients(self.recipients, chunk)?;
+                    let digest = merkle::chunk_digest(&envelope);
+                    self.known.insert(id, digest);
+                    new_chunks.push(NewChunk { id, envelope });
+                    digest
+                }
+            };
+            chunks.push(id);
+            digests.push(digest);
+        }
+        self.manifest.files.push(FileEntry {
+            path: path.to_owned(),
+            size: data.len() as u64,
+            chunks,
+            digests,
+        });
+        Ok(new_chunks)
+    }
+
+    /// Finishes the backup, and returns its manifest along with the manifest encrypted for the
+    /// recipients, to be stored next to the chunks.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the manifest cannot be encrypted.
+    pub fn finish(self) -> IncrementalResult<(Manifest, Vec<u8>)> {
+        let sealed =
+            envelope::seal_for_recipients(self.recipients, &self.manifest.encode())?;
+        Ok((self.manifest, sealed))
+    }
+}
+
+/// Returns the ID of `chunk`, keyed with `id_key`.
+fn chunk_id(id_key: &[u8; ID_KEY_LEN], chunk: &[u8]) -> ChunkId {
+    let mut hmac = Hmac::<Sha256>::new_from_slice(id_key)
+        .expect("HMAC accepts keys of any size");
+    hmac.update(chunk);
+    ChunkId(hmac.finalize().into_bytes().into())
+}
+
+/// Reads the fields of an encoded manifest.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn slice(&mut self, len: usize) -> IncrementalResult<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(IncrementalError::Malformed);
+        }
+        let (slice, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(slice)
+    }
+
+    fn take<const N: usize>(&mut self) -> IncrementalResult<[u8; N]> {
+        Ok(self.slice(N)?.try_into().expect("slice has length N"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns `len` pseudorandom bytes.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_boundaries_follow_content() {
+        let chunker = Chunker::new(256, 1024, 4096).unwrap();
+        let data = noise(64 * 1024, 1);
+        let chunks: Vec<_> = chunker.chunks(&data).collect();
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.iter().all(|chunk| chunk.len() <= 4096));
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|chunk| chunk.len() >= 256));
+
+        // Inserting bytes at the start only changes the first chunks.
+        let mut shifted = b"inserted".to_vec();
+        shifted.extend(&data);
+        let shifted: HashSet<_> = chunker.chunks(&shifted).collect();
+        let unchanged = chunks
+            .iter()
+            .filter(|chunk| shifted.contains(*chunk))
+            .count();
+        assert!(unchanged >= chunks.len() - 2);
+        assert!(Chunker::new(256, 1000, 4096).is_err());
+    }
+
+    #[test]
+    fn test_incremental_backup_uploads_changed_chunks() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let recipients = [private_key.to_public_key()];
+        let mut store = std::collections::HashMap::new();
+        let mut data = noise(200 * 1024, 2);
+
+        let mut backup = IncrementalBackup::new(&recipients);
+        let first = backup.add_file("data.bin", &data).unwrap();
+        assert!(backup.add_file("data.bin", &data).is_err());
+        let (manifest, _) = backup.finish().unwrap();
+        store.extend(first.iter().map(|chunk| (chunk.id, chunk.envelope.clone())));
+
+        data[100_000] ^= 1;
+        let mut backup = IncrementalBackup::from_previous(&recipients, &manifest);
+        let second = backup.add_file("data.bin", &data).unwrap();
+        assert!(!second.is_empty() && second.len() <= 2);
+        store.extend(
+            second
+                .iter()
+                .map(|chunk| (chunk.id, chunk.envelope.clone())),
+        );
+        let (_, sealed) = backup.finish().unwrap();
+
+        let manifest = Manifest::open(&private_key, &sealed).unwrap();
+        assert_eq!(manifest.files()[0].size, data.len() as u64);
+        let restore = |store: &std::collections::HashMap<ChunkId, Vec<u8>>| {
+            manifest
+                .restore_file(&private_key, "data.bin", |id| store.get(id).cloned())
+        };
+        assert_eq!(restore(&store).unwrap(), data);
+
+        let tree = manifest.merkle_tree();
+        let stored = MerkleTree::new(
+            manifest.files()[0]
+                .chunks
+                .iter()
+                .map(|id| merkle::chunk_digest(&store[id])),
+        );
+        assert!(tree.diff(&stored).is_empty());
+
+        let ids: Vec<_> = manifest.files()[0].chunks.clone();
+        let swapped = store[&ids[1]].clone();
+        store.insert(ids[0], swapped);
+        assert!(matches!(
+            restore(&store),
+            Err(IncrementalError::CorruptChunk(_))
+        ));
+        let stored =
+            MerkleTree::new(ids.iter().map(|id| merkle::chunk_digest(&store[id])));
+        let tampered = tree.diff(&stored);
+        assert_eq!(tampered, [0]);
+        assert_eq!(manifest.chunk_at(tampered[0]).unwrap().0.path, "data.bin");
+        store.remove(&ids[0]);
+        assert!(matches!(
+            restore(&store),
+            Err(IncrementalError::MissingChunk(_))
+        ));
+    }
+
+    #[test]
+    fn test_patch_file_reencrypts_changed_chunks() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let recipients = [private_key.to_public_key()];
+        let mut store = std::collections::HashMap::new();
+        let config = noise(10_000, 3);
+        let mut data = noise(200 * 1024, 4);
+
+        let mut backup = IncrementalBackup::new(&recipients);
+        for (path, file) in [("config", &config), ("data.bin", &data)] {
+            let chunks = backup.add_file(path, file).unwrap();
+            store.extend(chunks.into_iter().map(|chunk| (chunk.id, chunk.envelope)));
+        }
+        let (manifest, _) = backup.finish().unwrap();
+
+        data.splice(150_000..150_000, *b"inserted");
+        let patch = manifest.patch_file(&recipients, "data.bin", &data).unwrap();
+        assert!(!patch.upload.is_empty() && patch.upload.len() <= 2);
+        assert_eq!(patch.obsolete.len(), patch.upload.len());
+        for id in &patch.obsolete {
+            store.remove(id);
+        }
+        store.extend(
+            patch
+                .upload
+                .iter()
+                .map(|chunk| (chunk.id, chunk.envelope.clone())),
+        );
+
+        let manifest = Manifest::open(&private_key, &patch.sealed_manifest).unwrap();
+        let restore = |path| {
+            manifest
+                .restore_file(&private_key, path, |id| store.get(id).cloned())
+                .unwrap()
+        };
+        assert_eq!(restore("data.bin"), data);
+        assert_eq!(restore("config"), config);
+        assert_eq!(manifest.files()[0].path, "config");
+
+        let patch = manifest
+            .patch_file(&recipients, "new", b"new file")
+            .unwrap();
+        assert_eq!(patch.upload.len(), 1);
+        assert!(patch.obsolete.is_empty());
+        assert_eq!(patch.manifest.files().len(), 3);
+    }
+}
@@ -0,0 +1,619 @@
+//! Interoperability with RSA-OAEP implementations on other platforms.
+//!
+//! An [`InteropConfig`] selects the OAEP parameters and the text encoding of ciphertexts used by
+//! `E2ee` and `PublicE2ee` (see `E2ee::with_interop_config`). The default matches earlier versions
+//! of this crate; presets such as [`InteropConfig::WEBCRYPTO`] match the defaults of other
+//! platforms.
+//!
+//! When decryption of a foreign ciphertext fails, `E2ee::diagnose_ciphertext` returns a
+//! [`Diagnosis`] explaining the likely encoding or padding mismatch.
+//!
+//! With the `io` feature, this module also imports keys in the formats produced by
+//! `crypto.subtle.exportKey`: SPKI and PKCS#8 DER, and JWK.
+use crate::core::{self, OaepHash, OaepParams};
+use base64::{engine::general_purpose, Engine};
+use rsa::{traits::PublicKeyParts, Pkcs1v15Encrypt, RsaPrivateKey};
+
+/// The text encoding of ciphertexts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Encoding {
+    /// Standard base64 without padding, the default of this crate.
+    #[default]
+    Base64NoPad,
+    /// Standard base64 with `=` padding, as produced by `btoa`, Java's `Base64.getEncoder()` or
+    /// .NET's `Convert.ToBase64String`.
+    Base64,
+    /// URL-safe base64 without padding, as used by JOSE.
+    Base64UrlNoPad,
+}
+
+impl Encoding {
+    /// Encodes `bytes` as text.
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Base64NoPad => general_purpose::STANDARD_NO_PAD.encode(bytes),
+            Encoding::Base64 => general_purpose::STANDARD.encode(bytes),
+            Encoding::Base64UrlNoPad => {
+                general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+            }
+        }
+    }
+
+    /// Decodes `text` into bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `text` is not valid in this encoding.
+    pub fn decode(&self, text: &str) -> Result<Vec<u8>, base64::DecodeError> {
+        match self {
+            Encoding::Base64NoPad => general_purpose::STANDARD_NO_PAD.decode(text),
+            Encoding::Base64 => general_purpose::STANDARD.decode(text),
+            Encoding::Base64UrlNoPad => {
+                general_purpose::URL_SAFE_NO_PAD.decode(text)
+            }
+        }
+    }
+}
+
+/// The OAEP parameters and ciphertext encoding shared with a peer implementation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct InteropConfig {
+    /// The OAEP label and MGF1 hashes.
+    pub oaep: OaepParams,
+    /// The text encoding of ciphertexts.
+    pub encoding: Encoding,
+}
+
+impl InteropConfig {
+    /// RSA-OAEP with SHA-256 and base64 without padding, the default of this crate.
+    pub const DEFAULT: Self = Self {
+        oaep: OaepParams::SHA256,
+        encoding: Encoding::Base64NoPad,
+    };
+
+    /// Matches WebCrypto's `{ name: "RSA-OAEP", hash: "SHA-256" }` (SHA-256 for both the label
+    /// and MGF1), with ciphertexts encoded as padded standard base64, as produced by `btoa`.
+    ///
+    /// On the browser side, import the public key exported by `E2ee::get_public_key_pem` with
+    /// `crypto.subtle.importKey("spki", der, { name: "RSA-OAEP", hash: "SHA-256" }, false,
+    /// ["encrypt"])`, where `der` is the base64-decoded body of the PEM.
+    pub const WEBCRYPTO: Self = Self {
+        oaep: OaepParams::SHA256,
+        encoding: Encoding::Base64,
+    };
+
+    /// Matches Java's `Cipher.getInstance("RSA/ECB/OAEPWithSHA-256AndMGF1Padding")` initialized
+    /// without parameters, with ciphertexts encoded by `Base64.getEncoder()`.
+    ///
+    /// Despite its name, this transformation uses SHA-256 for the label but **SHA-1** for MGF1 with
+    /// the default SunJCE provider and the Android Keystore. Ciphertexts produced this way fail to
+    /// decrypt with the default configuration of this crate. If the Java side uses Bouncy Castle
+    /// or passes an explicit `OAEPParameterSpec` with `MGF1ParameterSpec.SHA256`, use
+    /// [`InteropConfig::JAVA_MGF1_SHA256`] instead.
+    pub const JAVA: Self = Self {
+        oaep: OaepParams {
+            hash: OaepHash::Sha256,
+            mgf1_hash: OaepHash::Sha1,
+        },
+        encoding: Encoding::Base64,
+    };
+
+    /// Matches Java's `RSA/ECB/OAEPWithSHA-256AndMGF1Padding` with SHA-256 for MGF1, as used by
+    /// the Bouncy Castle provider or with an explicit parameter spec:
+    ///
+    /// ```java
+    /// cipher.init(Cipher.ENCRYPT_MODE, key, new OAEPParameterSpec(
+    ///     "SHA-256", "MGF1", MGF1ParameterSpec.SHA256, PSource.PSpecified.DEFAULT));
+    /// ```
+    pub const JAVA_MGF1_SHA256: Self = Self {
+        oaep: OaepParams::SHA256,
+        encoding: Encoding::Base64,
+    };
+
+    /// Matches .NET's `rsa.Encrypt(data, RSAEncryptionPadding.OaepSHA256)` (SHA-256 for both the
+    /// label and MGF1), with ciphertexts encoded by `Convert.ToBase64String`.
+    ///
+    /// Encode the plaintext with `Encoding.UTF8.GetBytes`: `E2ee::decrypt` expects UTF-8 text.
+    pub const DOTNET: Self = Self {
+        oaep: OaepParams::SHA256,
+        encoding: Encoding::Base64,
+    };
+
+    fn preset_name(&self) -> Option<&'static str> {
+        [
+            (Self::DEFAULT, "InteropConfig::DEFAULT"),
+            (
+                Self::WEBCRYPTO,
+                "InteropConfig::WEBCRYPTO / JAVA_MGF1_SHA256 / DOTNET",
+            ),
+            (Self::JAVA, "InteropConfig::JAVA"),
+        ]
+        .into_iter()
+        .find(|(preset, _)| preset == self)
+        .map(|(_, name)| name)
+    }
+}
+
+/// The result of `E2ee::diagnose_ciphertext`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnosis {
+    /// The configuration that decrypts the ciphertext, if one was found.
+    pub config: Option<InteropConfig>,
+    /// Human-readable observations about the ciphertext, in the order they were made.
+    pub findings: Vec<String>,
+}
+
+const ALL_HASHES: [OaepHash; 4] = [
+    OaepHash::Sha256,
+    OaepHash::Sha1,
+    OaepHash::Sha384,
+    OaepHash::Sha512,
+];
+
+/// Tries every supported encoding, OAEP hash combination and PKCS#1 v1.5 on `ciphertext`.
+pub(crate) fn diagnose(private_key: &RsaPrivateKey, ciphertext: &[u8]) -> Diagnosis {
+    let key_len = private_key.size();
+    let mut findings = Vec::new();
+
+    let mut candidates = Vec::new();
+    match std::str::from_utf8(ciphertext) {
+        Ok(text) if ciphertext.len() != key_len => {
+            let trimmed: String = text.split_whitespace().collect();
+            if trimmed.len() != text.len() {
+                findings.push(
+                    "The ciphertext contains whitespace or line breaks (e.g. from a MIME \
+                     base64 encoder); it must be removed before decryption."
+                        .to_string(),
+                );
+            }
+            for encoding in [
+                Encoding::Base64,
+                Encoding::Base64NoPad,
+                Encoding::Base64UrlNoPad,
+            ] {
+                if let Ok(bytes) = encoding.decode(&trimmed) {
+                    candidates.push((Some(encoding), bytes));
+                }
+            }
+            if candidates.is_empty() {
+                // Hex is ASCII: other text is not sliced, since its characters may span
+                // several bytes.
+                let hex = (trimmed.is_ascii() && trimmed.len().is_multiple_of(2))
+                    .then(|| {
+                        (0..trimmed.len())
+                            .step_by(2)
+                            .map(|i| u8::from_str_radix(&trimmed[i..i + 2], 16))
+                            .collect::<Result<Vec<u8>, _>>()
+                            .ok()
+                    })
+                    .flatten();
+                match hex {
+                    Some(bytes) => {
+                        findings.push(
+                            "The ciphertext is hex encoded; encode it as base64 instead."
+                                .to_string(),
+                        );
+                        candidates.push((None, bytes));
+                    }
+                    None => findings.push(
+                        "The ciphertext is text but neither valid base64 (standard or \
+                         URL-safe) nor hex."
+                            .to_string(),
+                    ),
+                }
+            }
+        }
+        _ => {
+            findings.push(
+                "The ciphertext is binary; encode it as base64 before calling \
+                 `E2ee::decrypt`."
+                    .to_string(),
+            );
+            candidates.push((None, ciphertext.to_vec()));
+        }
+    }
+
+    for (encoding, mut bytes) in candidates {
+        if bytes.len() == key_len + 1 && bytes[0] == 0 {
+            findings.push(format!(
+                "The ciphertext is {} bytes with a leading zero byte, instead of {key_len} \
+                 bytes; the sender likely serialized it with a signed big integer (e.g. Java's \
+                 `BigInteger.toByteArray`).",
+                bytes.len()
+            ));
+            bytes.remove(0);
+        } else if bytes.len() < key_len && bytes.len() + 4 >= key_len {
+            findings.push(format!(
+                "The ciphertext is {} bytes instead of {key_len}; the sender likely stripped \
+                 its leading zero bytes.",
+                bytes.len()
+            ));
+            bytes.splice(0..0, std::iter::repeat_n(0, key_len - bytes.len()));
+        } else if bytes.len() != key_len {
+            if encoding.is_some() {
+                findings.push(format!(
+                    "Decoding as {encoding:?} yields {} bytes, but ciphertexts for this \
+                     {}-bit key are {key_len} bytes.",
+                    bytes.len(),
+                    key_len * 8
+                ));
+            } else {
+                findings.push(format!(
+                    "The ciphertext is {} bytes, but ciphertexts for this {}-bit key are \
+                     {key_len} bytes; it may have been encrypted to another key.",
+                    bytes.len(),
+                    key_len * 8
+                ));
+            }
+            continue;
+        }
+
+        for hash in ALL_HASHES {
+            for mgf1_hash in ALL_HASHES {
+                let oaep = OaepParams { hash, mgf1_hash };
+                let Ok(plaintext) = core::decrypt_with(private_key, oaep, &bytes)
+                else {
+                    continue;
+                };
+                let config = InteropConfig {
+                    oaep,
+                    encoding: encoding.unwrap_or_default(),
+                };
+                findings.push(format!(
+                    "The ciphertext decrypts with RSA-OAEP ({} label hash, MGF1 with {}){}.",
+                    hash.as_str(),
+                    mgf1_hash.as_str(),
+                    encoding
+                        .map(|encoding| format!(", encoded as {encoding:?}"))
+                        .unwrap_or_default(),
+                ));
+                if let Some(name) = config.preset_name() {
+                    findings.push(format!("Use {name}."));
+                }
+                if let Some(finding) = plaintext_encoding_finding(&plaintext) {
+                    findings.push(finding);
+                }
+                return Diagnosis {
+                    config: Some(config),
+                    findings,
+                };
+            }
+        }
+
+        if private_key.decrypt(Pkcs1v15Encrypt, &bytes).is_ok() {
+            findings.push(
+                "The ciphertext uses PKCS#1 v1.5 padding (e.g. Java's \
+                 `RSA/ECB/PKCS1Padding`, .NET's `RSAEncryptionPadding.Pkcs1`), which this \
+                 crate does not accept; switch the sender to OAEP."
+                    .to_string(),
+            );
+            return Diagnosis {
+                config: None,
+                findings,
+            };
+        }
+    }
+
+    findings.push(
+        "No supported padding decrypts the ciphertext; it was likely encrypted to another key \
+         or corrupted in transit."
+            .to_string(),
+    );
+    Diagnosis {
+        config: None,
+        findings,
+    }
+}
+
+fn plaintext_encoding_finding(plaintext: &[u8]) -> Option<String> {
+    let utf16: Vec<u16> = plaintext
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    // UTF-16 text of mostly ASCII characters contains NUL bytes, which are valid UTF-8.
+    let is_utf16 = plaintext.len().is_multiple_of(2)
+        && plaintext.contains(&0)
+        && String::from_utf16(&utf16).is_ok();
+    if is_utf16 {
+        Some(
+            "The plaintext is UTF-16 rather than UTF-8; encode it with UTF-8 (e.g. .NET's \
+             `Encoding.UTF8` instead of `Encoding.Unicode`)."
+                .to_string(),
+        )
+    } else if std::str::from_utf8(plaintext).is_err() {
+        Some(
+            "The plaintext is not valid UTF-8; `E2ee::decrypt` only accepts UTF-8 text."
+                .to_string(),
+        )
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "io")]
+pub use import::*;
+
+#[cfg(feature = "io")]
+mod import {
+    use crate::{core, io};
+    use base64::{engine::general_purpose, Engine};
+    use rsa::{pkcs8::DecodePrivateKey, BigUint, RsaPrivateKey, RsaPublicKey};
+    use thiserror::Error;
+
+    /// An error returned when importing a key.
+    #[derive(Error, Debug)]
+    pub enum KeyImportError {
+        #[error("RSA error: {0}")]
+        Rsa(#[from] rsa::errors::Error),
+
+        #[error("PKCS#8 error: {0}")]
+        Pkcs8(#[from] rsa::pkcs8::Error),
+
+        #[error("SPKI error: {0}")]
+        Spki(#[from] rsa::pkcs8::spki::Error),
+
+        #[error("Invalid JWK: {0}")]
+        Jwk(String),
+
+        #[error("Invalid public key: {0}")]
+        InvalidPublicKey(String),
+
+        #[error("PKCS#1 error: {0}")]
+        Pkcs1(#[from] rsa::pkcs1::Error),
+
+        #[error("Invalid OpenSSH key: {0}")]
+        OpenSsh(String),
+
+        #[error("Invalid X.509 certificate: {0}")]
+        Certificate(String),
+
+        #[cfg(feature = "pgp")]
+        #[error("OpenPGP error: {0}")]
+        Pgp(#[from] crate::pgp::PgpError),
+
+        #[error("Unrecognized key format")]
+        UnknownFormat,
+    }
+
+    /// Imports a public key exported with `crypto.subtle.exportKey("spki", key)`.
+    ///
+    /// The key goes through the same validation as keys imported with `PublicE2ee::new`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `der` is not an RSA public key or fails validation.
+    pub fn public_key_from_spki_der(
+        der: &[u8],
+    ) -> Result<RsaPublicKey, KeyImportError> {
+        let (n, e) = io::decode_public_key_components_der(der)?;
+        public_key_from_components(n, e)
+    }
+
+    /// Imports a private key exported with `crypto.subtle.exportKey("pkcs8", key)`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `der` is not an RSA private key.
+    pub fn private_key_from_pkcs8_der(
+        der: &[u8],
+    ) -> Result<RsaPrivateKey, KeyImportError> {
+        Ok(RsaPrivateKey::from_pkcs8_der(der)?)
+    }
+
+    /// Imports a public key exported with `crypto.subtle.exportKey("jwk", key)`.
+    ///
+    /// Only the `kty`, `n` and `e` members are used. The key goes through the same validation as
+    /// keys imported with `PublicE2ee::new`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `jwk` is not an RSA JSON Web Key or fails validation.
+    pub fn public_key_from_jwk(jwk: &str) -> Result<RsaPublicKey, KeyImportError> {
+        let jwk = parse_jwk(jwk)?;
+        public_key_from_components(jwk_member(&jwk, "n")?, jwk_member(&jwk, "e")?)
+    }
+
+    /// Imports a private key exported with `crypto.subtle.exportKey("jwk", key)`.
+    ///
+    /// The key is rebuilt from the `n`, `e`, `d`, `p` and `q` members; the CRT members are
+    /// recomputed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `jwk` is not a private RSA JSON Web Key or is
+    /// inconsistent.
+    pub fn private_key_from_jwk(jwk: &str) -> Result<RsaPrivateKey, KeyImportError> {
+        let jwk = parse_jwk(jwk)?;
+        let mut private_key = RsaPrivateKey::from_components(
+            jwk_member(&jwk, "n")?,
+            jwk_member(&jwk, "e")?,
+            jwk_member(&jwk, "d")?,
+            vec![jwk_member(&jwk, "p")?, jwk_member(&jwk, "q")?],
+        )?;
+        private_key.validate()?;
+        private_key.precompute()?;
+        Ok(private_key)
+    }
+
+    pub(crate) fn public_key_from_components(
+        n: BigUint,
+        e: BigUint,
+    ) -> Result<RsaPublicKey, KeyImportError> {
+        core::validate_public_key(&n, &e)
+            .map_err(KeyImportError::InvalidPublicKey)?;
+        Ok(RsaPublicKey::new(n, e)?)
+    }
+
+    fn parse_jwk(jwk: &str) -> Result<serde_json::Value, KeyImportError> {
+        let jwk: serde_json::Value = serde_json::from_str(jwk)
+            .map_err(|err| KeyImportError::Jwk(err.to_string()))?;
+        match jwk["kty"].as_str() {
+            Some("RSA") => Ok(jwk),
+            Some(kty) => {
+                Err(KeyImportError::Jwk(format!("unsupported key type {kty}")))
+            }
+            None => Err(KeyImportError::Jwk("missing member kty".into())),
+        }
+    }
+
+    fn jwk_member(
+        jwk: &serde_json::Value,
+        name: &str,
+    ) -> Result<BigUint, KeyImportError> {
+        let value = jwk[name]
+            .as_str()
+            .ok_or_else(|| KeyImportError::Jwk(format!("missing member {name}")))?;
+        let bytes = general_purpose::URL_SAFE_NO_PAD
+            .decode(value)
+            .map_err(|err| KeyImportError::Jwk(format!("member {name}: {err}")))?;
+        Ok(BigUint::from_bytes_be(&bytes))
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "io")]
+mod tests {
+    use super::*;
+    use crate::client::PublicE2ee;
+    use crate::server::E2ee;
+    use crate::vectors;
+    use rsa::{
+        pkcs8::DecodePublicKey, traits::PrivateKeyParts, RsaPrivateKey, RsaPublicKey,
+    };
+
+    const PRIVATE_JWK: &str = include_str!("../files/vectors/private.jwk");
+    const PUBLIC_JWK: &str = include_str!("../files/vectors/public.jwk");
+
+    fn pem_body(pem: &str) -> Vec<u8> {
+        let body: String = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        general_purpose::STANDARD.decode(body).unwrap()
+    }
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let bytes = [0xfb, 0xff, 0x01, 0x02];
+        for encoding in [
+            Encoding::Base64NoPad,
+            Encoding::Base64,
+            Encoding::Base64UrlNoPad,
+        ] {
+            assert_eq!(encoding.decode(&encoding.encode(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Base64.encode(&bytes), "+/8BAg==");
+        assert_eq!(Encoding::Base64UrlNoPad.encode(&bytes), "-_8BAg");
+        assert!(Encoding::Base64NoPad.decode("+/8BAg==").is_err());
+    }
+
+    #[test]
+    fn test_import_webcrypto_keys() {
+        let public_key =
+            RsaPublicKey::from_public_key_pem(vectors::PUBLIC_KEY_PEM).unwrap();
+        let private_key =
+            crate::io::decode_private_key_pem(vectors::PRIVATE_KEY_PEM).unwrap();
+
+        assert_eq!(public_key_from_jwk(PUBLIC_JWK).unwrap(), public_key);
+        assert_eq!(
+            public_key_from_spki_der(&pem_body(vectors::PUBLIC_KEY_PEM)).unwrap(),
+            public_key
+        );
+        let imported: RsaPrivateKey = private_key_from_jwk(PRIVATE_JWK).unwrap();
+        assert_eq!(imported.d(), private_key.d());
+        assert_eq!(
+            private_key_from_pkcs8_der(&pem_body(vectors::PRIVATE_KEY_PEM)).unwrap(),
+            private_key
+        );
+
+        assert!(matches!(
+            public_key_from_jwk(r#"{"kty":"EC","crv":"P-256"}"#),
+            Err(KeyImportError::Jwk(_))
+        ));
+        assert!(matches!(
+            public_key_from_jwk(r#"{"kty":"RSA","n":"AQAB","e":"AQAB"}"#),
+            Err(KeyImportError::InvalidPublicKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_java_presets_select_mgf1_hash() {
+        let e2ee = vectors::vector_e2ee().unwrap();
+        let sunjce = vectors::interop_vectors()
+            .into_iter()
+            .find(|vector| vector.config == InteropConfig::JAVA)
+            .unwrap();
+
+        let wrong = e2ee
+            .decrypt_detailed(&sunjce.ciphertext, InteropConfig::JAVA_MGF1_SHA256);
+        assert!(wrong.is_err());
+        let right = e2ee.decrypt_detailed(&sunjce.ciphertext, InteropConfig::JAVA);
+        assert_eq!(right.unwrap(), sunjce.plaintext);
+
+        let java = vectors::vector_e2ee()
+            .unwrap()
+            .with_interop_config(InteropConfig::JAVA);
+        let encrypted = java.encrypt("Hello Android!").unwrap();
+        assert_eq!(java.decrypt(&encrypted).unwrap(), "Hello Android!");
+    }
+
+    #[test]
+    fn test_diagnose_ciphertext() {
+        use rsa::rand_core::OsRng;
+
+        let e2ee = vectors::vector_e2ee().unwrap();
+        let public_key = e2ee.get_public_key();
+
+        for vector in vectors::interop_vectors() {
+            let diagnosis = e2ee.diagnose_ciphertext(vector.ciphertext.as_bytes());
+            assert_eq!(diagnosis.config, Some(vector.config), "{}", vector.name);
+        }
+
+        let raw = crate::core::encrypt(public_key, b"raw").unwrap();
+        let diagnosis = e2ee.diagnose_ciphertext(&raw);
+        assert_eq!(diagnosis.config, Some(InteropConfig::DEFAULT));
+        assert!(diagnosis.findings[0].contains("binary"));
+
+        let pkcs1 = public_key
+            .encrypt(&mut OsRng, Pkcs1v15Encrypt, b"legacy")
+            .unwrap();
+        let diagnosis =
+            e2ee.diagnose_ciphertext(Encoding::Base64.encode(&pkcs1).as_bytes());
+        assert_eq!(diagnosis.config, None);
+        assert!(diagnosis.findings.last().unwrap().contains("PKCS#1 v1.5"));
+
+        let utf16: Vec<u8> =
+            "hi".encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let utf16 = crate::core::encrypt(public_key, &utf16).unwrap();
+        let diagnosis =
+            e2ee.diagnose_ciphertext(Encoding::Base64.encode(&utf16).as_bytes());
+        assert!(diagnosis.findings.last().unwrap().contains("UTF-16"));
+
+        let diagnosis = e2ee.diagnose_ciphertext(b"not a ciphertext");
+        assert_eq!(diagnosis.config, None);
+
+        // Multibyte characters are reported, not sliced through.
+        let diagnosis = e2ee.diagnose_ciphertext("aéb".as_bytes());
+        assert_eq!(diagnosis.config, None);
+        assert!(diagnosis
+            .findings
+            .iter()
+            .any(|finding| finding.contains("nor hex")));
+    }
+
+    #[test]
+    fn test_webcrypto_compatible_roundtrip() {
+        let e2ee =
+            E2ee::from_private_key(private_key_from_jwk(PRIVATE_JWK).unwrap())
+                .unwrap()
+                .webcrypto_compatible();
+        let client =
+            PublicE2ee::from_public_key(public_key_from_jwk(PUBLIC_JWK).unwrap())
+                .unwrap()
+                .webcrypto_compatible();
+
+        let encrypted = client.encrypt("Hello browser!").unwrap();
+        assert!(encrypted.ends_with('='));
+        assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello browser!");
+    }
+}
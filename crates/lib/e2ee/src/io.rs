@@ -0,0 +1,230 @@
+//! PEM encoding and file persistence for RSA keys.
+//!
+//! This module is enabled by the default `io` feature. Disabling it drops PEM support from the
+//! `rsa` dependency and leaves only the in-memory primitives of the `core` module.
+//!
+//! Public keys can also be encoded as DER, JWK and OpenSSH for other consumers; the
+//! `key_import` module reads these formats back.
+use base64::{engine::general_purpose, Engine};
+use rsa::{
+    pkcs1::{der::Decode, RsaPublicKey as Pkcs1PublicKey},
+    pkcs8::{
+        spki, DecodePrivateKey, Document, EncodePrivateKey, EncodePublicKey,
+        LineEnding, SubjectPublicKeyInfoRef,
+    },
+    traits::PublicKeyParts,
+    BigUint, RsaPrivateKey, RsaPublicKey,
+};
+use std::{fs::File, io::Write, path::Path};
+use zeroize::Zeroizing;
+
+/// Encodes a private key as a PKCS#8 PEM string, zeroed when dropped.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be encoded.
+pub fn encode_private_key_pem(
+    private_key: &RsaPrivateKey,
+) -> rsa::pkcs8::Result<Zeroizing<String>> {
+    private_key.to_pkcs8_pem(LineEnding::default())
+}
+
+/// Encodes a public key as an SPKI PEM string.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be encoded.
+pub fn encode_public_key_pem(public_key: &RsaPublicKey) -> spki::Result<String> {
+    public_key.to_public_key_pem(LineEnding::default())
+}
+
+/// Encodes a public key as SPKI DER, the body of its PEM encoding.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be encoded.
+pub fn encode_public_key_der(public_key: &RsaPublicKey) -> spki::Result<Vec<u8>> {
+    Ok(public_key.to_public_key_der()?.into_vec())
+}
+
+/// Encodes a public key as a JSON Web Key for RSA-OAEP with SHA-256, with the members of
+/// `crypto.subtle.exportKey("jwk", key)`, so that it can be passed to `crypto.subtle.importKey`.
+pub fn encode_public_key_jwk(public_key: &RsaPublicKey) -> String {
+    serde_json::json!({
+        "kty": "RSA",
+        "n": general_purpose::URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be()),
+        "e": general_purpose::URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be()),
+        "alg": "RSA-OAEP-256",
+        "ext": true,
+        "key_ops": ["encrypt"],
+    })
+    .to_string()
+}
+
+/// Encodes a public key as an OpenSSH `ssh-rsa` line, as found in `id_rsa.pub` and
+/// `authorized_keys`, ending with `comment` unless it is empty.
+pub fn encode_public_key_openssh(
+    public_key: &RsaPublicKey,
+    comment: &str,
+) -> String {
+    let mut blob = Vec::new();
+    for field in [
+        b"ssh-rsa".to_vec(),
+        ssh_mpint(public_key.e()),
+        ssh_mpint(public_key.n()),
+    ] {
+        blob.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        blob.extend_from_slice(&field);
+    }
+    let line = format!("ssh-rsa {}", general_purpose::STANDARD.encode(blob));
+    if comment.is_empty() {
+        line
+    } else {
+        format!("{} {}", line, comment)
+    }
+}
+
+/// Encodes a positive integer as the body of an SSH `mpint`, with a leading zero byte when its
+/// high bit is set.
+fn ssh_mpint(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    if bytes[0] & 0x80 != 0 {
+        [&[0], bytes.as_slice()].concat()
+    } else {
+        bytes
+    }
+}
+
+/// Decodes a PKCS#8 PEM-encoded private key.
+///
+/// # Errors
+///
+/// This function returns an error if the PEM string is not a valid RSA private key.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, err(level = "warn"))
+)]
+pub fn decode_private_key_pem(
+    private_key_pem: &str,
+) -> rsa::pkcs8::Result<RsaPrivateKey> {
+    RsaPrivateKey::from_pkcs8_pem(private_key_pem)
+}
+
+/// Decodes the raw modulus and public exponent of an SPKI PEM-encoded RSA public key.
+///
+/// Unlike `RsaPublicKey::from_public_key_pem`, no validation is performed on the parameters, so that
+/// callers can report unsafe keys with a descriptive error.
+///
+/// # Errors
+///
+/// This function returns an error if the PEM string is not an RSA public key.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, err(level = "warn"))
+)]
+pub fn decode_public_key_components(
+    public_key_pem: &str,
+) -> spki::Result<(BigUint, BigUint)> {
+    let (_, document) = Document::from_pem(public_key_pem)?;
+    decode_public_key_components_der(document.as_bytes())
+}
+
+/// Decodes the raw modulus and public exponent of an SPKI DER-encoded RSA public key.
+///
+/// Like `decode_public_key_components`, no validation is performed on the parameters.
+///
+/// # Errors
+///
+/// This function returns an error if the DER bytes are not an RSA public key.
+pub fn decode_public_key_components_der(
+    public_key_der: &[u8],
+) -> spki::Result<(BigUint, BigUint)> {
+    let spki_ref = SubjectPublicKeyInfoRef::try_from(public_key_der)?;
+    spki_ref
+        .algorithm
+        .assert_algorithm_oid(rsa::pkcs1::ALGORITHM_OID)?;
+    let raw_key = Pkcs1PublicKey::from_der(spki_ref.subject_public_key.raw_bytes())?;
+    Ok((
+        BigUint::from_bytes_be(raw_key.modulus.as_bytes()),
+        BigUint::from_bytes_be(raw_key.public_exponent.as_bytes()),
+    ))
+}
+
+/// Writes a PEM string to `path`, creating or truncating the file.
+///
+/// # Errors
+///
+/// This function returns an error if the file cannot be created or written.
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(
+        level = "debug",
+        skip_all,
+        fields(path = %path.as_ref().display()),
+        err(level = "warn")
+    )
+)]
+pub fn write_pem_file(path: impl AsRef<Path>, pem: &str) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(pem.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::traits::PublicKeyParts;
+
+    const PUBLIC_KEY_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/files/public.pem");
+    const PRIVATE_KEY_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/files/private.pem");
+
+    #[test]
+    fn test_decode_public_key_components_matches_private_key() {
+        let public_key_pem = std::fs::read_to_string(PUBLIC_KEY_PATH)
+            .expect("Failed to read public key file");
+        let private_key_pem = std::fs::read_to_string(PRIVATE_KEY_PATH)
+            .expect("Failed to read private key file");
+
+        let (n, e) = decode_public_key_components(&public_key_pem).unwrap();
+        let private_key = decode_private_key_pem(&private_key_pem).unwrap();
+        assert_eq!(&n, private_key.n());
+        assert_eq!(&e, private_key.e());
+    }
+
+    #[test]
+    fn test_encode_public_key_foreign_formats() {
+        let public_key = decode_private_key_pem(crate::vectors::PRIVATE_KEY_PEM)
+            .unwrap()
+            .to_public_key();
+
+        let openssh = include_str!("../files/vectors/public.openssh");
+        assert_eq!(
+            encode_public_key_openssh(&public_key, "alice@example.com"),
+            openssh.trim_end()
+        );
+        let jwk = encode_public_key_jwk(&public_key);
+        assert_eq!(
+            crate::interop::public_key_from_jwk(&jwk).unwrap(),
+            public_key
+        );
+        let der = encode_public_key_der(&public_key).unwrap();
+        assert_eq!(
+            decode_public_key_components_der(&der).unwrap().0,
+            *public_key.n()
+        );
+    }
+
+    #[test]
+    fn test_encode_public_key_pem_roundtrip() {
+        let private_key_pem = std::fs::read_to_string(PRIVATE_KEY_PATH)
+            .expect("Failed to read private key file");
+        let private_key = decode_private_key_pem(&private_key_pem).unwrap();
+        let public_key = RsaPublicKey::from(&private_key);
+
+        let pem = encode_public_key_pem(&public_key).unwrap();
+        let (n, e) = decode_public_key_components(&pem).unwrap();
+        assert_eq!(&n, public_key.n());
+        assert_eq!(&e, public_key.e());
+    }
+}
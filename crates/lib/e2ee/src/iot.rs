@@ -0,0 +1,386 @@
+//! Compact encryption of sensor payloads for MQTT and other IoT transports.
+//!
+//! Devices are provisioned with the public key of their server. [`DeviceEncryptor`] encrypts each
+//! small payload (a sensor reading, a status report) directly with RSA-OAEP (SHA-256), without the
+//! data key and nonce of an envelope, and wraps it in a compact CBOR message:
+//!
+//! ```text
+//! [1, "<device ID>", h'<RSA-OAEP ciphertext>']
+//! ```
+//!
+//! The device ID is the RSA-OAEP label of the ciphertext: a message relabelled with another
+//! device ID, e.g. by a broker or anyone else on the path, fails to decrypt. The payload must fit
+//! in a single RSA-OAEP block: 190 bytes with a 2048-bit key (see
+//! [`DeviceEncryptor::max_payload_len`]). Use `PublicE2ee::encrypt_envelope` for larger payloads.
+//!
+//! On the server, [`BatchDecryptor`] holds the key provisioned for each device ID, and decrypts
+//! messages one by one or in batches, as received from an MQTT subscription.
+//!
+//! The device ID does not prove who encrypted a message: anyone holding the public key can
+//! encrypt a new message claiming any device ID. Authenticate devices at the transport level
+//! (e.g. MQTT credentials bound to the topic) when it matters.
+//!
+//! The device side, [`DeviceEncryptor`] and the CBOR encoding, only uses `core` and `alloc`, and
+//! [`DeviceEncryptor::encrypt_with_rng`] takes the random number generator of the device, for
+//! targets without an operating system generator. The crate itself still requires `std`, which
+//! [`BatchDecryptor`] uses.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::iot::{BatchDecryptor, DeviceEncryptor};
+//! use e2ee::server::{E2ee, KeySize};
+//! use std::sync::Arc;
+//!
+//! let server = Arc::new(E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance"));
+//!
+//! // On the device.
+//! let device = DeviceEncryptor::new("sensor-17", server.get_public_key().clone());
+//! let message = device.encrypt(br#"{"t":21.5}"#).expect("Failed to encrypt reading");
+//!
+//! // On the server.
+//! let mut decryptor = BatchDecryptor::new();
+//! decryptor.add_device("sensor-17", Arc::clone(&server));
+//! let reading = decryptor.decrypt(&message).expect("Failed to decrypt reading");
+//! assert_eq!(reading.device_id, "sensor-17");
+//! assert_eq!(reading.payload, br#"{"t":21.5}"#);
+//! ```
+use crate::core::{self, OaepParams};
+use crate::server::E2ee;
+use alloc::{string::String, vec::Vec};
+use rsa::{
+    rand_core::{CryptoRngCore, OsRng},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use std::{collections::HashMap, sync::Arc};
+use thiserror::Error;
+
+/// The version of the CBOR message format produced by [`DeviceEncryptor`].
+pub const VERSION: u64 = 1;
+
+const MAJOR_UNSIGNED: u8 = 0;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+
+/// An error returned when encrypting or decrypting a device message.
+#[derive(Error, Debug)]
+pub enum IotError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("The payload takes {len} bytes, more than the {max} bytes the key can encrypt")]
+    PayloadTooLarge { len: usize, max: usize },
+
+    #[error("Malformed device message")]
+    Malformed,
+
+    #[error("Unsupported device message version {0}")]
+    UnsupportedVersion(u64),
+
+    #[error("Unknown device {0}")]
+    UnknownDevice(String),
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+}
+
+/// Encrypts payloads on a device for its server.
+#[derive(Debug, Clone)]
+pub struct DeviceEncryptor {
+    device_id: String,
+    server_key: RsaPublicKey,
+}
+
+impl DeviceEncryptor {
+    /// Creates an encryptor for the device `device_id`, provisioned with `server_key`.
+    pub fn new(device_id: impl Into<String>, server_key: RsaPublicKey) -> Self {
+        Self {
+            device_id: device_id.into(),
+            server_key,
+        }
+    }
+
+    /// Returns the largest payload, in bytes, that can be encrypted with the server key.
+    pub fn max_payload_len(&self) -> usize {
+        OaepParams::SHA256.max_message_len(self.server_key.size())
+    }
+
+    /// Encrypts `payload` into a CBOR device message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `IotError::PayloadTooLarge` if the payload does not fit in a single
+    /// RSA-OAEP block, or an error if encryption fails.
+    pub fn encrypt(&self, payload: &[u8]) -> Result<Vec<u8>, IotError> {
+        self.encrypt_with_rng(&mut OsRng, payload)
+    }
+
+    /// Encrypts `payload` like [`DeviceEncryptor::encrypt`], drawing the OAEP seed from `rng`,
+    /// e.g. the hardware generator of the device.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `IotError::PayloadTooLarge` if the payload does not fit in a single
+    /// RSA-OAEP block, or an error if encryption fails.
+    pub fn encrypt_with_rng<R: CryptoRngCore + ?Sized>(
+        &self,
+        rng: &mut R,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, IotError> {
+        let max = self.max_payload_len();
+        if payload.len() > max {
+            return Err(IotError::PayloadTooLarge {
+                len: payload.len(),
+                max,
+            });
+        }
+        let ciphertext = core::encrypt_with_label(
+            rng,
+            &self.server_key,
+            OaepParams::SHA256,
+            &self.device_id,
+            payload,
+        )?;
+        let mut message =
+            Vec::with_capacity(ciphertext.len() + self.device_id.len() + 8);
+        write_header(&mut message, MAJOR_ARRAY, 3);
+        write_header(&mut message, MAJOR_UNSIGNED, VERSION);
+        write_header(&mut message, MAJOR_TEXT, self.device_id.len() as u64);
+        message.extend_from_slice(self.device_id.as_bytes());
+        write_header(&mut message, MAJOR_BYTES, ciphertext.len() as u64);
+        message.extend_from_slice(&ciphertext);
+        Ok(message)
+    }
+}
+
+/// A decrypted device message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceMessage {
+    /// The ID of the device that sent the message.
+    pub device_id: String,
+    /// The decrypted payload.
+    pub payload: Vec<u8>,
+}
+
+/// Decrypts device messages with the key provisioned for each device.
+#[derive(Debug, Default)]
+pub struct BatchDecryptor {
+    devices: HashMap<String, Arc<E2ee>>,
+}
+
+impl BatchDecryptor {
+    /// Creates a decryptor without any device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the device `device_id`, provisioned with the public key of `e2ee`. Several devices
+    /// may share the same key.
+    pub fn add_device(&mut self, device_id: impl Into<String>, e2ee: Arc<E2ee>) {
+        self.devices.insert(device_id.into(), e2ee);
+    }
+
+    /// Unregisters the device `device_id`.
+    pub fn remove_device(&mut self, device_id: &str) -> Option<Arc<E2ee>> {
+        self.devices.remove(device_id)
+    }
+
+    /// Decrypts a CBOR device message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message is malformed, if its device is unknown, or if
+    /// it cannot be decrypted with the key and the ID of the device, e.g. because it was
+    /// relabelled with another device ID.
+    pub fn decrypt(&self, message: &[u8]) -> Result<DeviceMessage, IotError> {
+        let mut input = message;
+        if read_header(&mut input, MAJOR_ARRAY)? != 3 {
+            return Err(IotError::Malformed);
+        }
+        let version = read_header(&mut input, MAJOR_UNSIGNED)?;
+        if version != VERSION {
+            return Err(IotError::UnsupportedVersion(version));
+        }
+        let device_id = read_bytes(&mut input, MAJOR_TEXT)?;
+        let device_id = String::from_utf8(device_id.to_vec())
+            .map_err(|_| IotError::Malformed)?;
+        let ciphertext = read_bytes(&mut input, MAJOR_BYTES)?;
+        if !input.is_empty() {
+            return Err(IotError::Malformed);
+        }
+
+        let e2ee = self
+            .devices
+            .get(&device_id)
+            .ok_or_else(|| IotError::UnknownDevice(device_id.clone()))?;
+        let payload = core::decrypt_with_label(
+            e2ee.get_private_key(),
+            OaepParams::SHA256,
+            &device_id,
+            ciphertext,
+        )
+        .map_err(|_| IotError::DecryptionFailed)?;
+        Ok(DeviceMessage { device_id, payload })
+    }
+
+    /// Decrypts a batch of CBOR device messages, returning one result per message, in order.
+    pub fn decrypt_batch<'a>(
+        &self,
+        messages: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Vec<Result<DeviceMessage, IotError>> {
+        messages
+            .into_iter()
+            .map(|message| self.decrypt(message))
+            .collect()
+    }
+}
+
+/// Writes a CBOR data item header with the shortest encoding of `value`.
+fn write_header(out: &mut Vec<u8>, major: u8, value: u64) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+}
+
+/// Reads a CBOR data item header of the `expected` major type and returns its value.
+fn read_header(input: &mut &[u8], expected: u8) -> Result<u64, IotError> {
+    let (&initial, rest) = input.split_first().ok_or(IotError::Malformed)?;
+    if initial >> 5 != expected {
+        return Err(IotError::Malformed);
+    }
+    let (value, len) = match initial & 0x1f {
+        info @ 0..=23 => (info as u64, 0),
+        info @ 24..=27 => {
+            let len = 1 << (info - 24);
+            let bytes = rest.get(..len).ok_or(IotError::Malformed)?;
+            let value = bytes
+                .iter()
+                .fold(0u64, |value, &byte| value << 8 | byte as u64);
+            (value, len)
+        }
+        _ => return Err(IotError::Malformed),
+    };
+    *input = &rest[len..];
+    Ok(value)
+}
+
+/// Reads a CBOR byte or text string of the `expected` major type.
+fn read_bytes<'a>(input: &mut &'a [u8], expected: u8) -> Result<&'a [u8], IotError> {
+    let len = usize::try_from(read_header(input, expected)?)
+        .map_err(|_| IotError::Malformed)?;
+    let bytes = input.get(..len).ok_or(IotError::Malformed)?;
+    *input = &input[len..];
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_cbor_layout() {
+        let server = E2ee::new(KeySize::Bit1024).unwrap();
+        let device = DeviceEncryptor::new("d1", server.get_public_key().clone());
+        let message = device.encrypt(b"21.5").unwrap();
+        // [1, "d1", h'...'] with a 128-byte ciphertext.
+        assert_eq!(message[..7], [0x83, 0x01, 0x62, b'd', b'1', 0x58, 128]);
+        assert_eq!(message.len(), 7 + 128);
+    }
+
+    #[test]
+    fn test_batch_decryption_by_device() {
+        let shared = Arc::new(E2ee::new(KeySize::Bit1024).unwrap());
+        let dedicated = Arc::new(E2ee::new(KeySize::Bit1024).unwrap());
+        let mut decryptor = BatchDecryptor::new();
+        decryptor.add_device("a", Arc::clone(&shared));
+        decryptor.add_device("b", Arc::clone(&shared));
+        decryptor.add_device("c", Arc::clone(&dedicated));
+
+        let a = DeviceEncryptor::new("a", shared.get_public_key().clone());
+        let b = DeviceEncryptor::new("b", shared.get_public_key().clone());
+        let c = DeviceEncryptor::new("c", dedicated.get_public_key().clone());
+        let unknown = DeviceEncryptor::new("z", shared.get_public_key().clone());
+        let wrong_key = DeviceEncryptor::new("c", shared.get_public_key().clone());
+        let messages = [
+            a.encrypt(b"1").unwrap(),
+            b.encrypt(b"2").unwrap(),
+            c.encrypt(b"3").unwrap(),
+            unknown.encrypt(b"4").unwrap(),
+            wrong_key.encrypt(b"5").unwrap(),
+            b"\x83\x02".to_vec(),
+        ];
+
+        let results = decryptor.decrypt_batch(messages.iter().map(Vec::as_slice));
+        for (result, (device_id, payload)) in
+            results.iter().zip([("a", b"1"), ("b", b"2"), ("c", b"3")])
+        {
+            let message = result.as_ref().unwrap();
+            assert_eq!(message.device_id, device_id);
+            assert_eq!(message.payload, payload);
+        }
+        assert!(
+            matches!(&results[3], Err(IotError::UnknownDevice(id)) if id == "z")
+        );
+        assert!(matches!(results[4], Err(IotError::DecryptionFailed)));
+        assert!(matches!(results[5], Err(IotError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_relabelled_messages_fail_to_decrypt() {
+        let shared = Arc::new(E2ee::new(KeySize::Bit1024).unwrap());
+        let mut decryptor = BatchDecryptor::new();
+        decryptor.add_device("d1", Arc::clone(&shared));
+        decryptor.add_device("d2", Arc::clone(&shared));
+
+        let device = DeviceEncryptor::new("d1", shared.get_public_key().clone());
+        let mut message = device.encrypt(b"21.5").unwrap();
+        assert_eq!(decryptor.decrypt(&message).unwrap().device_id, "d1");
+        // [1, "d1", ...] relabelled as [1, "d2", ...].
+        assert_eq!(message[4], b'1');
+        message[4] = b'2';
+        assert!(matches!(
+            decryptor.decrypt(&message),
+            Err(IotError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let server = E2ee::new(KeySize::Bit1024).unwrap();
+        let device = DeviceEncryptor::new("d1", server.get_public_key().clone());
+        assert_eq!(device.max_payload_len(), 62);
+        assert!(matches!(
+            device.encrypt(&[0; 63]),
+            Err(IotError::PayloadTooLarge { len: 63, max: 62 })
+        ));
+    }
+
+    #[test]
+    fn test_read_header_rejects_truncation() {
+        let mut out = Vec::new();
+        write_header(&mut out, MAJOR_BYTES, 70_000);
+        assert_eq!(out, [0x5a, 0x00, 0x01, 0x11, 0x70]);
+        let mut truncated = &out[..3];
+        assert!(matches!(
+            read_header(&mut truncated, MAJOR_BYTES),
+            Err(IotError::Malformed)
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+//! HKDF-SHA256 key derivation (RFC 5869) with labeled contexts.
+//!
+//! A shared secret, e.g. the output of a key exchange or a random master key, must not be used
+//! directly as several keys. [`Kdf::extract`] turns it into a pseudorandom key, from which
+//! [`Kdf::derive`] expands independent keys, one per purpose. Each key is bound to a label naming
+//! its purpose (e.g. `"database encryption"`) and to an optional context (e.g. a tenant ID), so
+//! that keys derived for different purposes or contexts never collide. The HKDF `info` of a
+//! derivation is:
+//!
+//! ```text
+//! label | 0x00 | context
+//! ```
+//!
+//! Labels cannot contain NUL bytes, which makes the encoding unambiguous. [`Kdf::expand`] takes a
+//! raw `info` instead, for protocols that specify it, such as the `session` module.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::kdf::Kdf;
+//!
+//! let shared_secret = [0x42; 32];
+//! let kdf = Kdf::extract(Some(b"my application v1"), &shared_secret);
+//! let encryption_key = kdf.derive_key("encryption", b"").expect("Failed to derive key");
+//! let signing_key = kdf.derive_key("signing", b"").expect("Failed to derive key");
+//! assert_ne!(*encryption_key, *signing_key);
+//!
+//! let mut tenant_key = [0u8; 64];
+//! kdf.derive("encryption", b"tenant 42", &mut tenant_key)
+//!     .expect("Failed to derive key");
+//! ```
+use hkdf::Hkdf;
+use rsa::sha2::Sha256;
+use std::fmt;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The largest number of bytes derived by a single expansion: 255 times the SHA-256 output size.
+pub const MAX_OUTPUT_LEN: usize = 255 * 32;
+
+/// The length of a pseudorandom key, in bytes.
+pub const PRK_LEN: usize = 32;
+
+pub type KdfResult<T> = std::result::Result<T, KdfError>;
+
+/// An error returned when deriving keys.
+#[derive(Error, Debug)]
+pub enum KdfError {
+    #[error(
+        "Invalid output length {0}: at most {MAX_OUTPUT_LEN} bytes can be derived"
+    )]
+    InvalidLength(usize),
+
+    #[error("Invalid pseudorandom key: at least {PRK_LEN} bytes are required")]
+    InvalidPrk,
+
+    #[error("Invalid label {0:?}: labels cannot contain NUL bytes")]
+    InvalidLabel(String),
+}
+
+/// A pseudorandom key from which purpose-specific keys are derived.
+#[derive(Clone)]
+pub struct Kdf {
+    hkdf: Hkdf<Sha256>,
+}
+
+impl fmt::Debug for Kdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Kdf").finish_non_exhaustive()
+    }
+}
+
+impl Kdf {
+    /// Extracts a pseudorandom key from `input_key_material`, such as a shared secret, with an
+    /// optional `salt`. A salt that is not secret, e.g. a protocol name or public keys, still
+    /// strengthens the extraction.
+    pub fn extract(salt: Option<&[u8]>, input_key_material: &[u8]) -> Self {
+        Self {
+            hkdf: Hkdf::new(salt, input_key_material),
+        }
+    }
+
+    /// Uses `prk`, which must already be uniformly random (e.g. a key drawn from a CSPRNG), as the
+    /// pseudorandom key, skipping the extraction.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KdfError::InvalidPrk` if `prk` is shorter than [`PRK_LEN`] bytes.
+    pub fn from_prk(prk: &[u8]) -> KdfResult<Self> {
+        Ok(Self {
+            hkdf: Hkdf::from_prk(prk).map_err(|_| KdfError::InvalidPrk)?,
+        })
+    }
+
+    /// Fills `output` with key material derived for the raw HKDF `info`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KdfError::InvalidLength` if `output` is longer than
+    /// [`MAX_OUTPUT_LEN`] bytes.
+    pub fn expand(&self, info: &[u8], output: &mut [u8]) -> KdfResult<()> {
+        self.hkdf
+            .expand(info, output)
+            .map_err(|_| KdfError::InvalidLength(output.len()))
+    }
+
+    /// Fills `output` with key material derived for the purpose `label`, in `context`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KdfError::InvalidLabel` if `label` contains a NUL byte, or
+    /// `KdfError::InvalidLength` if `output` is longer than [`MAX_OUTPUT_LEN`] bytes.
+    pub fn derive(
+        &self,
+        label: &str,
+        context: &[u8],
+        output: &mut [u8],
+    ) -> KdfResult<()> {
+        if label.contains('\0') {
+            return Err(KdfError::InvalidLabel(label.to_string()));
+        }
+        self.hkdf
+            .expand_multi_info(&[label.as_bytes(), b"\0", context], output)
+            .map_err(|_| KdfError::InvalidLength(output.len()))
+    }
+
+    /// Derives a 32-byte key for the purpose `label`, in `context`, zeroed when dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KdfError::InvalidLabel` if `label` contains a NUL byte.
+    pub fn derive_key(
+        &self,
+        label: &str,
+        context: &[u8],
+    ) -> KdfResult<Zeroizing<[u8; 32]>> {
+        let mut key = Zeroizing::new([0; 32]);
+        self.derive(label, context, key.as_mut())?;
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// RFC 5869, test case 1.
+    #[test]
+    fn test_rfc5869_vector() {
+        let kdf = Kdf::extract(
+            Some(&decode_hex("000102030405060708090a0b0c")),
+            &[0x0b; 22],
+        );
+        let mut okm = [0; 42];
+        kdf.expand(&decode_hex("f0f1f2f3f4f5f6f7f8f9"), &mut okm)
+            .unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            decode_hex(
+                "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf\
+                 34007208d5b887185865"
+            )
+        );
+    }
+
+    #[test]
+    fn test_labels_and_contexts_separate_keys() {
+        let kdf = Kdf::extract(None, b"shared secret");
+        let key = kdf.derive_key("encryption", b"").unwrap();
+        assert_eq!(key, kdf.derive_key("encryption", b"").unwrap());
+        assert_ne!(key, kdf.derive_key("signing", b"").unwrap());
+        assert_ne!(key, kdf.derive_key("encryption", b"tenant").unwrap());
+        // The separator keeps the label and the context apart.
+        assert_ne!(
+            kdf.derive_key("ab", b"c").unwrap(),
+            kdf.derive_key("a", b"bc").unwrap()
+        );
+        let mut raw = [0; 32];
+        kdf.expand(b"encryption\0", &mut raw).unwrap();
+        assert_eq!(raw, *key);
+
+        assert!(matches!(
+            kdf.derive_key("a\0b", b""),
+            Err(KdfError::InvalidLabel(_))
+        ));
+        let mut too_long = vec![0; MAX_OUTPUT_LEN + 1];
+        assert!(matches!(
+            kdf.expand(b"", &mut too_long),
+            Err(KdfError::InvalidLength(_))
+        ));
+        assert!(matches!(Kdf::from_prk(&[0; 16]), Err(KdfError::InvalidPrk)));
+        Kdf::from_prk(&[0; PRK_LEN]).unwrap();
+    }
+}
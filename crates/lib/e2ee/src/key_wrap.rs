@@ -0,0 +1,128 @@
+//! Symmetric key wrapping, for using this crate as a key-encryption-key (KEK) layer.
+//!
+//! Applications with their own AEAD pipeline (e.g. a database or an object store encrypting with
+//! AES-GCM) only need their data keys protected. `PublicE2ee::wrap_key` and `E2ee::wrap_key` wrap
+//! a data key under an RSA key with RSA-OAEP, and `E2ee::unwrap_key` unwraps it.
+//!
+//! To wrap keys under another symmetric key, e.g. a key derived from a password or held by a
+//! KMS, [`wrap`] and [`unwrap`] implement AES Key Wrap (RFC 3394) with a 128, 192 or 256-bit
+//! KEK. AES-KW is deterministic and only protects keys: the wrapped key must be a multiple of
+//! 8 bytes, and at least 16 bytes long.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::key_wrap;
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let data_key = [0x42; 32];
+//!
+//! // Under an RSA key.
+//! let wrapped = e2ee.wrap_key(&data_key).expect("Failed to wrap key");
+//! assert_eq!(e2ee.unwrap_key(&wrapped).unwrap().as_slice(), data_key);
+//!
+//! // Under a symmetric key.
+//! let kek = [0x17; 32];
+//! let wrapped = key_wrap::wrap(&kek, &data_key).expect("Failed to wrap key");
+//! assert_eq!(key_wrap::unwrap(&kek, &wrapped).unwrap().as_slice(), data_key);
+//! ```
+use aes_kw::{KekAes128, KekAes192, KekAes256};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+pub type KeyWrapResult<T> = std::result::Result<T, KeyWrapError>;
+
+/// An error returned when wrapping or unwrapping a key with AES-KW.
+#[derive(Error, Debug)]
+pub enum KeyWrapError {
+    #[error("Invalid KEK length {0}, expected 16, 24 or 32 bytes")]
+    InvalidKekLength(usize),
+
+    #[error("Invalid key length {0}, expected a multiple of 8 bytes of at least 16 bytes")]
+    InvalidKeyLength(usize),
+
+    #[error(
+        "Integrity check failed: wrong KEK, or the wrapped key was tampered with"
+    )]
+    IntegrityCheckFailed,
+}
+
+/// Wraps `key` under `kek` with AES Key Wrap (RFC 3394). The wrapped key is 8 bytes longer.
+///
+/// # Errors
+///
+/// This function returns `KeyWrapError::InvalidKekLength` if `kek` is not an AES key, and
+/// `KeyWrapError::InvalidKeyLength` if `key` is not a multiple of 8 bytes of at least 16 bytes.
+pub fn wrap(kek: &[u8], key: &[u8]) -> KeyWrapResult<Vec<u8>> {
+    if key.len() < 16 || !key.len().is_multiple_of(8) {
+        return Err(KeyWrapError::InvalidKeyLength(key.len()));
+    }
+    let wrapped = match kek.len() {
+        16 => KekAes128::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+        24 => KekAes192::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+        32 => KekAes256::try_from(kek).and_then(|kek| kek.wrap_vec(key)),
+        len => return Err(KeyWrapError::InvalidKekLength(len)),
+    };
+    wrapped.map_err(|_| KeyWrapError::InvalidKeyLength(key.len()))
+}
+
+/// Unwraps a key wrapped with [`wrap`] under `kek`. The key is zeroized when dropped.
+///
+/// # Errors
+///
+/// This function returns `KeyWrapError::IntegrityCheckFailed` if `kek` is wrong or `wrapped` was
+/// tampered with, and `KeyWrapError::InvalidKekLength` if `kek` is not an AES key.
+pub fn unwrap(kek: &[u8], wrapped: &[u8]) -> KeyWrapResult<Zeroizing<Vec<u8>>> {
+    if wrapped.len() < 24 || !wrapped.len().is_multiple_of(8) {
+        return Err(KeyWrapError::IntegrityCheckFailed);
+    }
+    let key = match kek.len() {
+        16 => KekAes128::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+        24 => KekAes192::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+        32 => KekAes256::try_from(kek).and_then(|kek| kek.unwrap_vec(wrapped)),
+        len => return Err(KeyWrapError::InvalidKekLength(len)),
+    };
+    key.map(Zeroizing::new)
+        .map_err(|_| KeyWrapError::IntegrityCheckFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test vector 4.6 of RFC 3394: a 256-bit key wrapped under a 256-bit KEK.
+    #[test]
+    fn test_rfc_3394_vector() {
+        let kek: Vec<u8> = (0x00..=0x1f).collect();
+        let key = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb,
+            0xcc, 0xdd, 0xee, 0xff, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+            0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        ];
+        let expected = [
+            0x28, 0xc9, 0xf4, 0x04, 0xc4, 0xb8, 0x10, 0xf4, 0xcb, 0xcc, 0xb3, 0x5c,
+            0xfb, 0x87, 0xf8, 0x26, 0x3f, 0x57, 0x86, 0xe2, 0xd8, 0x0e, 0xd3, 0x26,
+            0xcb, 0xc7, 0xf0, 0xe7, 0x1a, 0x99, 0xf4, 0x3b, 0xfb, 0x98, 0x8b, 0x9b,
+            0x7a, 0x02, 0xdd, 0x21,
+        ];
+        let wrapped = wrap(&kek, &key).unwrap();
+        assert_eq!(wrapped, expected);
+        assert_eq!(unwrap(&kek, &wrapped).unwrap().as_slice(), key);
+
+        let mut tampered = wrapped.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            unwrap(&kek, &tampered),
+            Err(KeyWrapError::IntegrityCheckFailed)
+        ));
+        assert!(matches!(
+            wrap(&kek[..20], &key),
+            Err(KeyWrapError::InvalidKekLength(20))
+        ));
+        assert!(matches!(
+            wrap(&kek, &key[..12]),
+            Err(KeyWrapError::InvalidKeyLength(12))
+        ));
+    }
+}
@@ -0,0 +1,481 @@
+//! RSA key generation on multiple threads, with progress reports, cancellation and timeouts.
+//!
+//! `core::generate_private_key` searches for the two primes of a key one after the other, on the
+//! calling thread. For 4096-bit keys this takes seconds, with a long tail. [`KeyGenerator`] tests
+//! prime candidates on several threads at once, and builds the key from the first two primes
+//! found, which divides the latency of provisioning flows by roughly the number of threads.
+//!
+//! The search can also report its [`KeygenProgress`] to a callback, e.g. to animate a progress
+//! indicator, and be aborted from another thread with a [`CancellationToken`]. With a timeout, a
+//! search taking pathologically long is abandoned and started over with fresh candidates, up to
+//! a number of retries, so that provisioning pipelines never hang for minutes.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::keygen::KeyGenerator;
+//! use std::num::NonZeroUsize;
+//!
+//! let threads = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+//! let private_key = KeyGenerator::new(2048)
+//!     .with_threads(threads)
+//!     .generate()
+//!     .expect("Failed to generate key");
+//! ```
+//!
+//! Cancelling a generation:
+//!
+//! ```
+//! use e2ee::keygen::{CancellationToken, KeyGenerator, KeygenError};
+//!
+//! let token = CancellationToken::new();
+//! let generator = KeyGenerator::new(4096)
+//!     .with_cancellation(token.clone())
+//!     .with_progress(|progress| println!("{} primes found", progress.primes_found));
+//! token.cancel();
+//! assert!(matches!(generator.generate(), Err(KeygenError::Cancelled)));
+//! ```
+//!
+//! Bounding the duration of a generation:
+//!
+//! ```
+//! use e2ee::keygen::KeyGenerator;
+//! use std::time::Duration;
+//!
+//! let private_key = KeyGenerator::new(2048)
+//!     .with_timeout(Duration::from_secs(30))
+//!     .with_retries(2)
+//!     .generate()
+//!     .expect("Failed to generate key");
+//! ```
+use crate::rng::SharedRng;
+use num_bigint_dig::prime::probably_prime;
+use rsa::{
+    rand_core::{CryptoRngCore, RngCore},
+    traits::PublicKeyParts,
+    BigUint, RsaPrivateKey,
+};
+use std::{
+    fmt,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+
+/// The public exponent of generated keys, as in `RsaPrivateKey::new`.
+const PUBLIC_EXPONENT: u32 = 65537;
+
+/// The number of Miller-Rabin rounds run on each candidate, as in `RsaPrivateKey::new`.
+const MILLER_RABIN_ROUNDS: usize = 20;
+
+/// The smallest modulus size (in bits) this generator accepts.
+const MIN_BITS: usize = 64;
+
+/// The number of candidates tested between two progress reports.
+const PROGRESS_INTERVAL: u64 = 64;
+
+/// An error returned by [`KeyGenerator::generate`].
+#[derive(Error, Debug)]
+pub enum KeygenError {
+    #[error("Key generation was cancelled")]
+    Cancelled,
+
+    #[error("Key generation timed out after {attempts} attempts")]
+    TimedOut { attempts: u32 },
+
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+}
+
+/// A handle aborting a key generation from another thread.
+///
+/// Clones share the same state: cancelling any of them cancels every generation using one.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Creates a token that is not cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the generations using this token. They return `KeygenError::Cancelled` after
+    /// testing their current candidate.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the token was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// The progress of a key generation.
+///
+/// Whether a candidate is prime is random, so the number of candidates left cannot be known in
+/// advance; for a 2048-bit key, about a few hundred candidates are tested per prime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeygenProgress {
+    /// The number of primes found so far, out of `primes_needed`.
+    pub primes_found: usize,
+    /// The number of primes of the key.
+    pub primes_needed: usize,
+    /// The number of candidates tested so far, by all threads.
+    pub candidates_tested: u64,
+}
+
+impl KeygenProgress {
+    /// Returns the share of primes found, between 0 and 1.
+    pub fn fraction(&self) -> f64 {
+        self.primes_found as f64 / self.primes_needed as f64
+    }
+}
+
+type ProgressCallback = Arc<dyn Fn(KeygenProgress) + Send + Sync>;
+
+/// A configurable RSA key generator.
+#[derive(Clone)]
+pub struct KeyGenerator {
+    bits: usize,
+    threads: NonZeroUsize,
+    progress: Option<ProgressCallback>,
+    cancellation: Option<CancellationToken>,
+    timeout: Option<Duration>,
+    retries: u32,
+    rng: SharedRng,
+}
+
+impl fmt::Debug for KeyGenerator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyGenerator")
+            .field("bits", &self.bits)
+            .field("threads", &self.threads)
+            .field("progress", &self.progress.is_some())
+            .field("cancellation", &self.cancellation)
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("rng", &self.rng)
+            .finish()
+    }
+}
+
+impl KeyGenerator {
+    /// Creates a generator of keys with a modulus of `bits` bits, running on a single thread.
+    pub fn new(bits: usize) -> Self {
+        Self {
+            bits,
+            threads: NonZeroUsize::MIN,
+            progress: None,
+            cancellation: None,
+            timeout: None,
+            retries: 0,
+            rng: SharedRng::default(),
+        }
+    }
+
+    /// Sets the number of threads searching for primes.
+    pub fn with_threads(mut self, threads: NonZeroUsize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Sets a callback receiving the progress of the generation, each time a prime is found and
+    /// every 64 candidates. It is called from the searching threads, so it should return quickly.
+    pub fn with_progress(
+        mut self,
+        progress: impl Fn(KeygenProgress) + Send + Sync + 'static,
+    ) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    /// Draws the prime candidates from `rng` instead of `OsRng` (see the `rng` module). The
+    /// searching threads share it, locking it for each candidate.
+    pub fn with_rng(mut self, rng: impl CryptoRngCore + Send + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Sets a token aborting the generation when cancelled.
+    pub fn with_cancellation(mut self, cancellation: CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Sets the time after which an attempt is abandoned, and either retried or reported as
+    /// `KeygenError::TimedOut`. A timeout beyond the range of `Instant`, e.g. `Duration::MAX`,
+    /// never elapses.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the number of attempts started over after timing out, none by default. A generation
+    /// thus takes at most the timeout multiplied by one plus the number of retries.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Generates a private key with a public exponent of 65537.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeygenError::Cancelled` if the generation was cancelled,
+    /// `KeygenError::TimedOut` if every attempt timed out, or `KeygenError::Rsa` if the modulus
+    /// size is smaller than 64 bits or if the primes found do not make a valid key.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", err(level = "warn"))
+    )]
+    pub fn generate(&self) -> Result<RsaPrivateKey, KeygenError> {
+        if self.bits < MIN_BITS {
+            return Err(rsa::Error::InvalidArguments.into());
+        }
+        let mut attempts = 0;
+        let primes = loop {
+            attempts += 1;
+            let deadline = self
+                .timeout
+                .and_then(|timeout| Instant::now().checked_add(timeout));
+            if let Some(primes) = self.search(deadline) {
+                break primes;
+            }
+            if self.is_cancelled() {
+                return Err(KeygenError::Cancelled);
+            }
+            if attempts > self.retries {
+                return Err(KeygenError::TimedOut { attempts });
+            }
+        };
+        let private_key =
+            RsaPrivateKey::from_primes(primes, BigUint::from(PUBLIC_EXPONENT))?;
+        debug_assert_eq!(private_key.n().bits(), self.bits);
+        Ok(private_key)
+    }
+
+    /// Searches for the primes of a key, and returns them unless the search was cancelled or
+    /// passed `deadline`.
+    fn search(&self, deadline: Option<Instant>) -> Option<Vec<BigUint>> {
+        let search = Search {
+            primes: Mutex::default(),
+            done: AtomicBool::new(false),
+            candidates_tested: AtomicU64::new(0),
+            deadline,
+            generator: self,
+        };
+        // The primes have their top two bits set, so the modulus has exactly `bits` bits.
+        let sizes = [self.bits.div_ceil(2), self.bits / 2];
+        thread::scope(|scope| {
+            for _ in 0..self.threads.get() {
+                scope.spawn(|| search.run(sizes));
+            }
+        });
+        if self.is_cancelled() {
+            return None;
+        }
+        let primes = search
+            .primes
+            .into_inner()
+            .unwrap_or_else(|err| err.into_inner());
+        (primes.len() == 2).then_some(primes)
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+}
+
+/// The state shared by the threads of a generation.
+struct Search<'a> {
+    primes: Mutex<Vec<BigUint>>,
+    done: AtomicBool,
+    candidates_tested: AtomicU64,
+    deadline: Option<Instant>,
+    generator: &'a KeyGenerator,
+}
+
+impl Search<'_> {
+    /// Tests candidates until the primes of both sizes are found, by this thread or another, or
+    /// until the generation is cancelled or passes its deadline.
+    fn run(&self, sizes: [usize; 2]) {
+        let exponent = BigUint::from(PUBLIC_EXPONENT);
+        let mut rng = self.generator.rng.clone();
+        while !self.done.load(Ordering::Relaxed) {
+            if self.generator.is_cancelled()
+                || self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                self.done.store(true, Ordering::Relaxed);
+                return;
+            }
+            let tested = self.candidates_tested.fetch_add(1, Ordering::Relaxed) + 1;
+            let found = {
+                // Reports are made under the lock, so that `primes_found` never decreases.
+                let primes = self.lock();
+                if tested.is_multiple_of(PROGRESS_INTERVAL) {
+                    self.report(primes.len(), tested);
+                }
+                primes.len()
+            };
+            let bits = sizes[found.min(1)];
+            let candidate = random_candidate(&mut rng, bits);
+            // `p - 1` must be coprime with the public exponent, which is prime.
+            if !probably_prime(&candidate, MILLER_RABIN_ROUNDS)
+                || (&candidate - 1u32) % &exponent == BigUint::from(0u32)
+            {
+                continue;
+            }
+            let mut primes = self.lock();
+            // Another thread may have found a prime of this size in the meantime.
+            if primes.len() == 2
+                || candidate.bits() != sizes[primes.len()]
+                || primes.contains(&candidate)
+            {
+                continue;
+            }
+            primes.push(candidate);
+            if primes.len() == 2 {
+                self.done.store(true, Ordering::Relaxed);
+            }
+            self.report(
+                primes.len(),
+                self.candidates_tested.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    fn report(&self, primes_found: usize, candidates_tested: u64) {
+        if let Some(progress) = &self.generator.progress {
+            progress(KeygenProgress {
+                primes_found,
+                primes_needed: 2,
+                candidates_tested,
+            });
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<BigUint>> {
+        self.primes.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Returns a random odd number of `bits` bits, with its top two bits set.
+fn random_candidate(rng: &mut impl RngCore, bits: usize) -> BigUint {
+    let mut bytes = vec![0u8; bits.div_ceil(8)];
+    rng.fill_bytes(&mut bytes);
+    let excess = bytes.len() * 8 - bits;
+    bytes[0] &= 0xff >> excess;
+    BigUint::from_bytes_be(&bytes)
+        | (BigUint::from(3u32) << (bits - 2))
+        | BigUint::from(1u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+    use crate::rng::tests::CountingRng;
+    use rsa::traits::PrivateKeyParts;
+
+    #[test]
+    fn test_parallel_keys_are_valid() {
+        for (bits, threads) in [(1024, 4), (1025, 2), (512, 1)] {
+            let private_key = KeyGenerator::new(bits)
+                .with_threads(NonZeroUsize::new(threads).unwrap())
+                .generate()
+                .unwrap();
+            private_key.validate().unwrap();
+            assert_eq!(private_key.n().bits(), bits);
+            assert_eq!(private_key.primes().len(), 2);
+            assert_eq!(private_key.e(), &BigUint::from(PUBLIC_EXPONENT));
+            if bits >= 1024 {
+                let public_key = rsa::RsaPublicKey::from(&private_key);
+                let ciphertext = core::encrypt(&public_key, b"parallel").unwrap();
+                assert_eq!(
+                    core::decrypt(&private_key, &ciphertext).unwrap(),
+                    b"parallel"
+                );
+            }
+        }
+        assert!(matches!(
+            KeyGenerator::new(32).generate(),
+            Err(KeygenError::Rsa(_))
+        ));
+    }
+
+    #[test]
+    fn test_generation_reports_progress_and_can_be_cancelled() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = reports.clone();
+        KeyGenerator::new(1024)
+            .with_threads(NonZeroUsize::new(2).unwrap())
+            .with_progress(move |progress| sink.lock().unwrap().push(progress))
+            .generate()
+            .unwrap();
+        let reports = reports.lock().unwrap();
+        let found: Vec<usize> = reports
+            .iter()
+            .map(|progress| progress.primes_found)
+            .filter(|&found| found > 0)
+            .collect();
+        assert_eq!(found.last(), Some(&2));
+        assert!(found.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(reports.last().unwrap().fraction(), 1.0);
+
+        // Cancel from the progress callback, before any prime is found.
+        let token = CancellationToken::new();
+        let canceller = token.clone();
+        let result = KeyGenerator::new(4096)
+            .with_threads(NonZeroUsize::new(2).unwrap())
+            .with_cancellation(token.clone())
+            .with_progress(move |_| canceller.cancel())
+            .generate();
+        assert!(matches!(result, Err(KeygenError::Cancelled)));
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn test_generation_times_out_after_retries() {
+        let attempts = Arc::new(AtomicU64::new(0));
+        let counter = attempts.clone();
+        let result = KeyGenerator::new(4096)
+            .with_timeout(Duration::ZERO)
+            .with_retries(2)
+            .with_progress(move |_| {
+                counter.fetch_add(1, Ordering::Relaxed);
+            })
+            .generate();
+        assert!(matches!(result, Err(KeygenError::TimedOut { attempts: 3 })));
+        assert_eq!(attempts.load(Ordering::Relaxed), 0);
+
+        for timeout in [Duration::from_secs(60), Duration::MAX] {
+            let private_key = KeyGenerator::new(1024)
+                .with_timeout(timeout)
+                .generate()
+                .unwrap();
+            assert_eq!(private_key.n().bits(), 1024);
+        }
+    }
+
+    #[test]
+    fn test_generation_draws_from_the_injected_rng() {
+        let rng = CountingRng::default();
+        let private_key = KeyGenerator::new(1024)
+            .with_threads(NonZeroUsize::new(2).unwrap())
+            .with_rng(rng.clone())
+            .generate()
+            .unwrap();
+        private_key.validate().unwrap();
+        assert!(rng.drawn() >= 2 * 64);
+    }
+}
@@ -0,0 +1,742 @@
+//! An encrypted file of named private keys, protected by a passphrase.
+//!
+//! This module is enabled by the `keystore` feature. A [`Keystore`] keeps private keys under
+//! names chosen by the application, in a single file encrypted with AES-256-GCM under a key
+//! derived from a passphrase with Argon2id (see the `password` module). Apps hand out `E2ee` and
+//! `PublicE2ee` instances bound to stored keys rather than PEM strings, so that private keys never
+//! leave the keystore unencrypted.
+//!
+//! Every change is written to the file right away, through a temporary file renamed over it, so
+//! that a crash never leaves a half-written keystore. [`Keystore::create_in`] and
+//! [`Keystore::open_in`] keep the same encrypted data under a key of a `storage::StorageBackend`
+//! instead, e.g. the SQLite database or the Keychain of a mobile app. The layout of the file is:
+//!
+//! ```text
+//! version (1 byte) | password header (29 bytes) | nonce (12 bytes) | ciphertext
+//! ```
+//!
+//! where the version and the header are authenticated as associated data, and the plaintext is:
+//!
+//! ```text
+//! key count (2 bytes) | (name length (2 bytes) | name | key length (4 bytes) | PKCS#8 DER)...
+//! ```
+//!
+//! with integers in big endian.
+//!
+//! With the `io` feature, [`scan_dir`] lists the key files of a directory (PEM, DER and the other
+//! formats of the `key_import` module) with their policy violations, to validate the keys of a
+//! deployment, for example when a server starts.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::keystore::Keystore;
+//! use e2ee::password::PasswordKdf;
+//! use e2ee::server::KeySize;
+//!
+//! let path = std::env::temp_dir().join(format!("doc-keystore-{}", std::process::id()));
+//! let mut keystore = Keystore::create(&path, b"passphrase", &PasswordKdf::default())
+//!     .expect("Failed to create keystore");
+//! keystore.generate("signing", KeySize::Bit2048).expect("Failed to generate key");
+//!
+//! let keystore = Keystore::open(&path, b"passphrase").expect("Failed to open keystore");
+//! assert_eq!(keystore.names(), vec!["signing"]);
+//! let e2ee = keystore.e2ee("signing").expect("Failed to load key");
+//! let signature = e2ee.sign(b"Hello, keystore!").expect("Failed to sign");
+//! # std::fs::remove_file(&path).unwrap();
+//! ```
+use crate::client::{PublicE2ee, PublicE2eeError};
+use crate::core;
+#[cfg(feature = "io")]
+use crate::key_import::{self, KeyFormat};
+use crate::password::{PasswordError, PasswordHeader, PasswordKdf, HEADER_LEN};
+use crate::server::{E2ee, E2eeError, KeySize};
+use crate::storage::StorageBackend;
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+#[cfg(feature = "io")]
+use rsa::traits::PublicKeyParts;
+use rsa::{
+    pkcs8::{self, DecodePrivateKey, EncodePrivateKey},
+    rand_core::{OsRng, RngCore},
+    RsaPrivateKey,
+};
+#[cfg(feature = "io")]
+use std::time::SystemTime;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the keystore file layout.
+pub const VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+pub type KeystoreResult<T> = std::result::Result<T, KeystoreError>;
+
+/// An error returned when creating, opening or changing a keystore.
+#[derive(Error, Debug)]
+pub enum KeystoreError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Password error: {0}")]
+    Password(#[from] PasswordError),
+
+    #[error("PKCS#8 error: {0}")]
+    Pkcs8(#[from] pkcs8::Error),
+
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::Error),
+
+    #[error("E2ee error: {0}")]
+    E2ee(#[from] E2eeError),
+
+    #[error("PublicE2ee error: {0}")]
+    PublicE2ee(#[from] PublicE2eeError),
+
+    #[error("Wrong passphrase, or the keystore was tampered with")]
+    WrongPassphrase,
+
+    #[error("Malformed keystore")]
+    Malformed,
+
+    #[error("Unsupported keystore version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The keystore already holds a key named {0:?}")]
+    DuplicateName(String),
+
+    #[error("The keystore holds no key named {0:?}")]
+    NotFound(String),
+
+    #[error("Key names must be 1 to 255 bytes long, without control characters")]
+    InvalidName,
+}
+
+/// Where a keystore is saved.
+enum Location {
+    File(PathBuf),
+    Backend {
+        backend: Arc<dyn StorageBackend>,
+        key: String,
+    },
+}
+
+impl fmt::Debug for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => f.debug_tuple("File").field(path).finish(),
+            Self::Backend { key, .. } => f
+                .debug_struct("Backend")
+                .field("key", key)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+impl Location {
+    fn exists(&self) -> io::Result<bool> {
+        match self {
+            Self::File(path) => Ok(path.exists()),
+            Self::Backend { backend, key } => Ok(backend.get(key)?.is_some()),
+        }
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::File(path) => fs::read(path),
+            Self::Backend { backend, key } => backend
+                .get(key)?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    /// Writes `data`, through a temporary file for a file.
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let path = match self {
+            Self::File(path) => path,
+            Self::Backend { backend, key } => return backend.put(key, data),
+        };
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temporary = path.with_file_name(name);
+        let result = fs::File::create(&temporary).and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()?;
+            fs::rename(&temporary, path)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        result
+    }
+}
+
+/// An open keystore, holding the key derived from its passphrase.
+pub struct Keystore {
+    location: Location,
+    header: PasswordHeader,
+    key: Zeroizing<[u8; 32]>,
+    /// The PKCS#8 DER encodings of the private keys, by name.
+    entries: BTreeMap<String, Zeroizing<Vec<u8>>>,
+}
+
+impl fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keystore")
+            .field("location", &self.location)
+            .field("names", &self.names())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Keystore {
+    /// Creates an empty keystore at `path`, protected by `passphrase` with a key derived by `kdf`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::Io` if `path` already exists or cannot be written, or
+    /// an error if the key derivation fails.
+    pub fn create(
+        path: impl AsRef<Path>,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> KeystoreResult<Self> {
+        Self::create_at(Location::File(path.as_ref().to_path_buf()), passphrase, kdf)
+    }
+
+    /// Creates an empty keystore under `key` in `backend`, like [`Keystore::create`] creates a
+    /// file. By convention, `key` starts with `storage::KEYSTORES`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::keystore::Keystore;
+    /// use e2ee::password::PasswordKdf;
+    /// use e2ee::storage::{MemoryStorage, KEYSTORES};
+    /// use std::sync::Arc;
+    ///
+    /// let storage = Arc::new(MemoryStorage::new());
+    /// let key = format!("{KEYSTORES}default");
+    /// Keystore::create_in(storage.clone(), &key, b"passphrase", &PasswordKdf::default())
+    ///     .expect("Failed to create keystore");
+    /// let keystore = Keystore::open_in(storage, &key, b"passphrase").expect("Failed to open");
+    /// assert!(keystore.names().is_empty());
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::Io` if a value is already stored under `key` or the
+    /// backend fails, or an error if the key derivation fails.
+    pub fn create_in(
+        backend: Arc<dyn StorageBackend>,
+        key: &str,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> KeystoreResult<Self> {
+        let key = key.to_string();
+        Self::create_at(Location::Backend { backend, key }, passphrase, kdf)
+    }
+
+    fn create_at(
+        location: Location,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> KeystoreResult<Self> {
+        if location.exists()? {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists).into());
+        }
+        let derived = kdf.derive(passphrase)?;
+        let keystore = Self {
+            location,
+            header: derived.header,
+            key: derived.key,
+            entries: BTreeMap::new(),
+        };
+        keystore.save()?;
+        Ok(keystore)
+    }
+
+    /// Opens the keystore at `path` with `passphrase`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::WrongPassphrase` if `passphrase` is wrong or the file
+    /// was tampered with, or an error if the file cannot be read or is malformed.
+    pub fn open(path: impl AsRef<Path>, passphrase: &[u8]) -> KeystoreResult<Self> {
+        Self::open_at(Location::File(path.as_ref().to_path_buf()), passphrase)
+    }
+
+    /// Opens the keystore stored under `key` in `backend` with `passphrase`, like
+    /// [`Keystore::open`] opens a file.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`Keystore::open`], and `KeystoreError::Io` of kind
+    /// `NotFound` if no value is stored under `key`.
+    pub fn open_in(
+        backend: Arc<dyn StorageBackend>,
+        key: &str,
+        passphrase: &[u8],
+    ) -> KeystoreResult<Self> {
+        let key = key.to_string();
+        Self::open_at(Location::Backend { backend, key }, passphrase)
+    }
+
+    fn open_at(location: Location, passphrase: &[u8]) -> KeystoreResult<Self> {
+        let data = location.read()?;
+        let (&version, rest) = data.split_first().ok_or(KeystoreError::Malformed)?;
+        if version != VERSION {
+            return Err(KeystoreError::UnsupportedVersion(version));
+        }
+        if rest.len() < HEADER_LEN + NONCE_LEN {
+            return Err(KeystoreError::Malformed);
+        }
+        let (header, rest) = rest.split_at(HEADER_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let header = PasswordHeader::from_bytes(header)?;
+        let key = header.derive_key(passphrase)?;
+        let plaintext = Zeroizing::new(
+            Aes256Gcm::new(key.as_ref().into())
+                .decrypt(
+                    Nonce::from_slice(nonce),
+                    Payload {
+                        msg: ciphertext,
+                        aad: &data[..1 + HEADER_LEN],
+                    },
+                )
+                .map_err(|_| KeystoreError::WrongPassphrase)?,
+        );
+        Ok(Self {
+            location,
+            header,
+            key,
+            entries: decode_entries(&plaintext)?,
+        })
+    }
+
+    /// Returns the path of the keystore file, or `None` for a keystore kept in a storage backend.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.location {
+            Location::File(path) => Some(path),
+            Location::Backend { .. } => None,
+        }
+    }
+
+    /// Returns the names of the stored keys, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.entries.keys().map(String::as_str).collect()
+    }
+
+    /// Returns whether a key is stored under `name`.
+    pub fn contains(&self, name: &str) -> bool {
+        self.entries.contains_key(name)
+    }
+
+    /// Stores `private_key` under `name`, and saves the keystore.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::DuplicateName` if a key is already stored under
+    /// `name`, `KeystoreError::InvalidName` if `name` is empty, longer than 255 bytes or holds
+    /// control characters, or an error if the keystore cannot be saved.
+    pub fn insert(
+        &mut self,
+        name: &str,
+        private_key: &RsaPrivateKey,
+    ) -> KeystoreResult<()> {
+        if name.is_empty()
+            || name.len() > usize::from(u8::MAX)
+            || name.chars().any(char::is_control)
+        {
+            return Err(KeystoreError::InvalidName);
+        }
+        if self.contains(name) {
+            return Err(KeystoreError::DuplicateName(name.to_string()));
+        }
+        let der = Zeroizing::new(private_key.to_pkcs8_der()?.as_bytes().to_vec());
+        self.entries.insert(name.to_string(), der);
+        self.save().inspect_err(|_| {
+            self.entries.remove(name);
+        })
+    }
+
+    /// Generates a key of `key_size` and stores it under `name`, so that it never leaves the
+    /// keystore unencrypted, and saves the keystore.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`Keystore::insert`], or an error if the generation
+    /// fails.
+    pub fn generate(&mut self, name: &str, key_size: KeySize) -> KeystoreResult<()> {
+        if self.contains(name) {
+            return Err(KeystoreError::DuplicateName(name.to_string()));
+        }
+        let private_key = core::generate_private_key(key_size as usize)?;
+        self.insert(name, &private_key)
+    }
+
+    /// Removes the key stored under `name`, and saves the keystore.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::NotFound` if no key is stored under `name`, or an
+    /// error if the keystore cannot be saved.
+    pub fn remove(&mut self, name: &str) -> KeystoreResult<()> {
+        let der = self
+            .entries
+            .remove(name)
+            .ok_or_else(|| KeystoreError::NotFound(name.to_string()))?;
+        self.save().inspect_err(|_| {
+            self.entries.insert(name.to_string(), der);
+        })
+    }
+
+    /// Returns the private key stored under `name`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `KeystoreError::NotFound` if no key is stored under `name`, or an
+    /// error if the key is malformed.
+    pub fn private_key(&self, name: &str) -> KeystoreResult<RsaPrivateKey> {
+        let der = self
+            .entries
+            .get(name)
+            .ok_or_else(|| KeystoreError::NotFound(name.to_string()))?;
+        Ok(RsaPrivateKey::from_pkcs8_der(der)?)
+    }
+
+    /// Returns an `E2ee` instance bound to the key stored under `name`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`Keystore::private_key`], or an error if the instance
+    /// cannot be created.
+    pub fn e2ee(&self, name: &str) -> KeystoreResult<E2ee> {
+        Ok(E2ee::from_private_key(self.private_key(name)?)?)
+    }
+
+    /// Returns a `PublicE2ee` instance bound to the public half of the key stored under `name`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`Keystore::private_key`], or an error if the instance
+    /// cannot be created.
+    pub fn public_e2ee(&self, name: &str) -> KeystoreResult<PublicE2ee> {
+        Ok(PublicE2ee::from_public_key(
+            self.private_key(name)?.to_public_key(),
+        )?)
+    }
+
+    /// Protects the keystore with `passphrase` from now on, with a key derived by `kdf`, and saves
+    /// it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key derivation fails or the keystore cannot be saved.
+    pub fn change_passphrase(
+        &mut self,
+        passphrase: &[u8],
+        kdf: &PasswordKdf,
+    ) -> KeystoreResult<()> {
+        let derived = kdf.derive(passphrase)?;
+        let previous = (
+            std::mem::replace(&mut self.header, derived.header),
+            std::mem::replace(&mut self.key, derived.key),
+        );
+        self.save().inspect_err(|_| {
+            (self.header, self.key) = previous;
+        })
+    }
+
+    /// Encrypts the keystore and writes it to its file or backend.
+    fn save(&self) -> KeystoreResult<()> {
+        let mut plaintext = Zeroizing::new(Vec::new());
+        plaintext.extend_from_slice(&(self.entries.len() as u16).to_be_bytes());
+        for (name, der) in &self.entries {
+            plaintext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            plaintext.extend_from_slice(name.as_bytes());
+            plaintext.extend_from_slice(&(der.len() as u32).to_be_bytes());
+            plaintext.extend_from_slice(der);
+        }
+
+        let mut data = vec![VERSION];
+        data.extend_from_slice(&self.header.to_bytes());
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = Aes256Gcm::new(self.key.as_ref().into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &data,
+                },
+            )
+            .map_err(|_| KeystoreError::Malformed)?;
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(self.location.write(&data)?)
+    }
+}
+
+/// The extensions of the files read by [`scan_dir`].
+#[cfg(feature = "io")]
+pub const KEY_FILE_EXTENSIONS: [&str; 6] =
+    ["pem", "der", "key", "pub", "crt", "cer"];
+
+/// A key file found by [`scan_dir`].
+#[cfg(feature = "io")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The format of the file, or `None` if it holds no key.
+    pub format: Option<KeyFormat>,
+    /// Whether the file holds a private key.
+    pub private: bool,
+    /// The size of the modulus in bits, or 0 if the file holds no valid key.
+    pub bits: usize,
+    /// The fingerprint of the public key (see `core::fingerprint`).
+    pub fingerprint: Option<String>,
+    /// The end of validity of the certificate holding the key, if any.
+    pub expires_at: Option<SystemTime>,
+    /// The reasons the key violates the policy of this crate, or could not be read.
+    pub violations: Vec<String>,
+}
+
+#[cfg(feature = "io")]
+impl KeyInfo {
+    /// Returns whether the file holds a key that complies with the policy.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// Reads the key files of the directory `path`, whose extension is one of
+/// [`KEY_FILE_EXTENSIONS`], and reports their type, size, fingerprint and expiry, sorted by path.
+///
+/// Keys are imported with `key_import::import_key`, so any of its formats is accepted. A file
+/// violates the policy if it holds no valid key, if the key is not recommended (see
+/// `ImportedKey::warnings`), or, on Unix, if it holds a private key and is accessible to other
+/// users than its owner. Applications can refuse to start on violations.
+///
+/// # Errors
+///
+/// This function returns an error if the directory cannot be read. Files that cannot be read are
+/// reported as violations.
+#[cfg(feature = "io")]
+pub fn scan_dir(path: impl AsRef<Path>) -> KeystoreResult<Vec<KeyInfo>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let is_key_file = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                KEY_FILE_EXTENSIONS
+                    .contains(&extension.to_ascii_lowercase().as_str())
+            });
+        if is_key_file && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths.into_iter().map(inspect_key_file).collect())
+}
+
+/// Reads the key file `path` for [`scan_dir`].
+#[cfg(feature = "io")]
+fn inspect_key_file(path: PathBuf) -> KeyInfo {
+    let mut info = KeyInfo {
+        path,
+        format: None,
+        private: false,
+        bits: 0,
+        fingerprint: None,
+        expires_at: None,
+        violations: Vec::new(),
+    };
+    let key = match fs::read(&info.path) {
+        Ok(data) => key_import::import_key(&Zeroizing::new(data)),
+        Err(err) => {
+            info.violations.push(format!("cannot be read: {}", err));
+            return info;
+        }
+    };
+    let key = match key {
+        Ok(key) => key,
+        Err(err) => {
+            info.violations.push(format!("holds no valid key: {}", err));
+            return info;
+        }
+    };
+    info.format = Some(key.format());
+    info.private = key.private_key().is_some();
+    info.bits = key.public_key().n().bits();
+    info.fingerprint = key.fingerprint().ok();
+    info.expires_at = key.expires_at();
+    info.violations = key.warnings();
+    #[cfg(unix)]
+    if info.private {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = fs::metadata(&info.path) {
+            let mode = metadata.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                info.violations.push(format!(
+                    "private key file is accessible to other users (mode {:o})",
+                    mode
+                ));
+            }
+        }
+    }
+    info
+}
+
+/// Decodes the entries of a decrypted keystore.
+fn decode_entries(
+    plaintext: &[u8],
+) -> KeystoreResult<BTreeMap<String, Zeroizing<Vec<u8>>>> {
+    let mut rest = plaintext;
+    let mut take = |len: usize| -> KeystoreResult<&[u8]> {
+        if rest.len() < len {
+            return Err(KeystoreError::Malformed);
+        }
+        let (field, tail) = rest.split_at(len);
+        rest = tail;
+        Ok(field)
+    };
+    let count = u16::from_be_bytes(take(2)?.try_into().expect("2 bytes"));
+    let mut entries = BTreeMap::new();
+    for _ in 0..count {
+        let len = u16::from_be_bytes(take(2)?.try_into().expect("2 bytes"));
+        let name = std::str::from_utf8(take(len.into())?)
+            .map_err(|_| KeystoreError::Malformed)?
+            .to_string();
+        let len = u32::from_be_bytes(take(4)?.try_into().expect("4 bytes"));
+        let der = Zeroizing::new(take(len as usize)?.to_vec());
+        if entries.insert(name, der).is_some() {
+            return Err(KeystoreError::Malformed);
+        }
+    }
+    if !rest.is_empty() {
+        return Err(KeystoreError::Malformed);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::PasswordParams;
+
+    fn fast_kdf() -> PasswordKdf {
+        PasswordKdf::new(PasswordParams::new(64, 1, 1).unwrap())
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("e2ee-keystore-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn test_keys_survive_reopening() {
+        let path = temp_path("reopen");
+        let mut keystore = Keystore::create(&path, b"secret", &fast_kdf()).unwrap();
+        let private_key = core::generate_private_key(1024).unwrap();
+        keystore.insert("imported", &private_key).unwrap();
+        keystore.generate("generated", KeySize::Bit1024).unwrap();
+        assert!(matches!(
+            keystore.insert("imported", &private_key),
+            Err(KeystoreError::DuplicateName(_))
+        ));
+        assert!(Keystore::create(&path, b"secret", &fast_kdf()).is_err());
+
+        let mut keystore = Keystore::open(&path, b"secret").unwrap();
+        assert_eq!(keystore.names(), vec!["generated", "imported"]);
+        assert_eq!(keystore.private_key("imported").unwrap(), private_key);
+        assert_eq!(
+            keystore.public_e2ee("imported").unwrap().get_public_key(),
+            &private_key.to_public_key()
+        );
+        keystore.remove("generated").unwrap();
+        assert!(matches!(
+            keystore.e2ee("generated"),
+            Err(KeystoreError::NotFound(_))
+        ));
+        assert_eq!(
+            Keystore::open(&path, b"secret").unwrap().names(),
+            vec!["imported"]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_passphrase_and_tampering_are_detected() {
+        let path = temp_path("tamper");
+        let mut keystore = Keystore::create(&path, b"old", &fast_kdf()).unwrap();
+        keystore.change_passphrase(b"new", &fast_kdf()).unwrap();
+        assert!(matches!(
+            Keystore::open(&path, b"old"),
+            Err(KeystoreError::WrongPassphrase)
+        ));
+        assert!(Keystore::open(&path, b"new").is_ok());
+
+        let mut data = fs::read(&path).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&path, &data).unwrap();
+        assert!(matches!(
+            Keystore::open(&path, b"new"),
+            Err(KeystoreError::WrongPassphrase)
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn test_scan_dir_reports_violations() {
+        let dir = temp_path("scan");
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("public.pem"),
+            include_str!("../files/vectors/public.pem"),
+        )
+        .unwrap();
+        fs::write(dir.join("broken.key"), "not a key").unwrap();
+        fs::write(dir.join("notes.txt"), "ignored").unwrap();
+        let private_path = dir.join("private.pem");
+        fs::write(&private_path, include_str!("../files/vectors/private.pem"))
+            .unwrap();
+
+        let keys = scan_dir(&dir).unwrap();
+        let names: Vec<_> = keys
+            .iter()
+            .map(|key| key.path.file_name().unwrap())
+            .collect();
+        assert_eq!(names, ["broken.key", "private.pem", "public.pem"]);
+        assert!(!keys[0].is_valid() && keys[0].format.is_none());
+        assert!(keys[1].private && keys[1].bits == 2048);
+        assert!(!keys[2].private && keys[2].is_valid());
+        assert_eq!(keys[1].fingerprint, keys[2].fingerprint);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o644))
+                .unwrap();
+            assert!(!scan_dir(&dir).unwrap()[1].is_valid());
+            fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))
+                .unwrap();
+            assert!(scan_dir(&dir).unwrap()[1].is_valid());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -5,8 +5,104 @@
 //!
 //! ## Modules
 //!
-//! - `client`: Contains the client-side encryption logic that uses only the public key for encryption.
+//! - `audit`: Contains the `AuditLogger` hook that records every use of the private key.
+//! - `column` (optional): Contains `Encrypted<T>`, an `sqlx` column type holding values encrypted
+//!   at rest, bound to their table and column, with key IDs for rotation.
+//! - `convergent` (optional): Contains `Convergent`, deterministic encryption of backup chunks
+//!   under keys derived from their content, so that identical chunks deduplicate.
+//! - `core`: Contains the pure RSA primitives (key generation, encryption, decryption, signatures) without any I/O.
+//! - `deniable` (optional, experimental): Contains `seal` and `open`, encrypting a decoy and a
+//!   hidden plaintext into a single ciphertext opened by different keys.
+//! - `email` (optional): Contains `encrypt` and `decrypt`, S/MIME encryption of RFC 5322 emails for
+//!   recipient certificates.
+//! - `ephemeral` (optional): Contains `encrypt_ephemeral`, encrypting one-shot messages with
+//!   ephemeral X25519 keys against a companion key certified by the recipient's RSA key.
+//! - `discovery` (optional): Contains `KeyDiscovery`, publishing and fetching self-signed public
+//!   keys at `.well-known` URLs of the domain of an identity.
+//! - `dns` (optional): Contains `DnsVerifier`, checking public keys against the fingerprints
+//!   published in TXT records of the domain of an identity.
+//! - `dotenv`: Contains `encrypt` and `decrypt`, encrypting the values of `.env` files into
+//!   `ENC[...]` markers while keeping variable names readable.
+//! - `devices` (default): Contains `DeviceList`, the device keys of an identity in a signed,
+//!   versioned list, and `seal_for_devices`, encrypting a message for all of them at once.
+//! - `identity` (default): Contains `IdentityBundle`, the self-signed keys, devices and expiry of
+//!   an identity, exchanged as a single JSON document or URI to pair two apps.
+//! - `envelope`: Contains the hybrid RSA-OAEP / AES-256-GCM envelope format for payloads of any size.
+//! - `grpc` (optional): Contains `EnvelopeCodec`, a `tonic` codec encrypting gRPC messages.
+//! - `incremental`: Contains `IncrementalBackup`, splitting files with content-defined chunking
+//!   into encrypted chunks and an encrypted manifest, so that later backups upload changed chunks
+//!   only.
+//! - `interop`: Contains the `InteropConfig` presets matching other RSA-OAEP implementations, and
+//!   helpers to import keys exported by WebCrypto.
+//! - `iot`: Contains `DeviceEncryptor` and `BatchDecryptor`, encrypting small sensor payloads into
+//!   compact CBOR messages for MQTT and decrypting them by device ID.
+//! - `io` (default): Contains PEM encoding and file persistence for keys.
+//! - `kdf`: Contains `Kdf`, HKDF-SHA256 key derivation with labeled contexts for deriving
+//!   purpose-specific keys from a shared secret.
+//! - `key_import` (default): Contains `import_key`, detecting and importing RSA keys in the
+//!   PKCS#1, JWK, OpenSSH, X.509 and OpenPGP formats of other tools.
+//! - `keygen`: Contains `KeyGenerator`, generating RSA keys on multiple threads with progress
+//!   reports, cancellation and timeouts.
+//! - `mac`: Contains `Mac`, HMAC-SHA256 and authenticated envelopes binding a header to a plain
+//!   RSA-OAEP ciphertext with a tag or a signature.
+//! - `merkle`: Contains `MerkleTree`, committing to the hashes of encrypted chunks with a signed
+//!   root, to check single chunks and locate tampered ones without downloading a whole archive.
+//! - `message`: Contains `Message`, chat messages with a text, metadata and attachments encrypted
+//!   as streams, authenticated by a single signature over their canonical encoding.
+//! - `cache` (optional): Contains `DecryptionCache`, a bounded LRU cache of RSA decryption results.
+//! - `client`: Contains the client-side encryption logic that uses only the public key for encryption,
+//!   and `ClientKeyPair`, generated by the client to decrypt the responses of the server.
+//! - `conversation`: Contains `Conversation`, holding my key pair and the public key of a peer to
+//!   send signed envelopes to it and receive only those it signed.
+//! - `session` (optional): Contains `Handshake` and `Session`, establishing forward-secret sessions
+//!   between identity keys and encrypting messages with the Double Ratchet.
+//! - `signing` (default): Contains detached signatures of files and signed `SHA256SUMS` manifests
+//!   of directory trees.
+//! - `timestamp` (optional): Contains `Timestamper`, requesting RFC 3161 time-stamp tokens for
+//!   signatures from a time-stamping authority, and their verification.
+//! - `threshold`: Contains `split` and `KeyShare`, sharing a private key so that any t of n
+//!   shareholders decrypt together without reconstructing it.
+//! - `uri`: Contains `e2ee:` URIs for public keys, messages and identity bundles, and their
+//!   splitting into parts that fit QR codes.
+//! - `sealed_sender`: Contains `seal` and `open`, signed messages whose sender is encrypted with
+//!   the message, so that only the recipient learns and authenticates it.
+//! - `at_rest`: Contains `DataKey`, encrypting database fields under a data key wrapped with the
+//!   RSA key of the server, bound to their table and column, and re-wrapped on key rotation.
+//! - `secrets`: Contains `share` and `reveal`, one-time secrets whose ciphertext is taken out of a
+//!   pluggable store when revealed, and whose key travels in the fragment of a link.
+//! - `storage`: Contains the `StorageBackend` trait, a pluggable key-value store with atomic
+//!   batches for keystores, sessions, pins and replay state, and its memory and file backends.
+//! - `stream`: Contains `StreamEncryptor` and `StreamDecryptor`, encrypting streams and large files
+//!   in chunks with the STREAM construction, which detects reordered and truncated chunks.
 //! - `server`: Contains the server-side encryption and decryption logic that requires both private and public keys.
+//! - `mq` (optional): Contains serializers encrypting message queue payloads, with key IDs for rotation.
+//! - `key_wrap`: Contains `wrap` and `unwrap`, AES Key Wrap of symmetric keys, for using the
+//!   crate as a key-encryption-key layer along with `wrap_key` and `unwrap_key`.
+//! - `keystore` (optional): Contains `Keystore`, a passphrase-protected file of named private keys,
+//!   and `scan_dir`, validating a directory of key files against the key policy.
+//! - `backup` (optional): Contains `Backup`, an encrypted archive of the keys, sessions, pins
+//!   and settings of an identity, to migrate to a new machine.
+//! - `password` (optional): Contains `PasswordKdf`, deriving keys from passwords with Argon2id,
+//!   with calibrated parameters stored next to the protected data.
+//! - `pgp` (optional): Contains OpenPGP public key import and message encryption for GnuPG recipients.
+//! - `proxy_reencryption` (optional, experimental): Contains `encrypt` and `re_encrypt`, letting a
+//!   proxy turn ciphertexts for one key pair into ciphertexts for another without decrypting them.
+//! - `proto` (optional): Contains the protobuf `Envelope` and `KeyBundle` messages of
+//!   `proto/e2ee.proto`, for carrying envelopes and public keys in typed gRPC fields.
+//! - `metrics`: Contains the `MetricsSink` hook used to report operation counters and durations.
+//! - `rng`: Contains the injection of a custom random number generator into key generation and
+//!   encryption.
+//! - `token` (default): Contains compact, URL-safe encrypted tokens carrying claims, an expiry and a key ID.
+//! - `traits`: Contains the `Encryptor` and `Decryptor` traits implemented by `E2ee` and `PublicE2ee`.
+//! - `test_utils` (optional): Contains proptest strategies, round-trip assertions and `MockE2ee` for downstream tests.
+//! - `vectors` (default): Contains known-answer test vectors for checking other implementations.
+//! - `secure_mem` (optional): Contains page-locked storage for private key material.
+//! - `wasm` (optional): Contains WebAssembly bindings for browsers, with `TransformStream`s that
+//!   encrypt and decrypt `ReadableStream`s chunk by chunk.
+//! - `watch` (optional): Contains `WatchedKeys`, reloading the key pair of a long-running server
+//!   when its key files change.
+//! - `wasm_storage` (optional): Contains `KeyStorage`, persisting key pairs in IndexedDB, wrapped
+//!   with a non-extractable WebCrypto key or under a password.
 //! - `ffi` (optional): Provides a foreign function interface (FFI) for integrating the encryption system with other platforms.
 //!
 //! ## Usage Examples
@@ -16,6 +112,7 @@
 //! To create an `E2ee` instance on the server side, both the private and public keys are required.
 //!
 //! ```rust
+//! # #[cfg(feature = "io")] {
 //! use e2ee::server::E2ee;
 //!
 //! const PRIVATE_KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/private.pem");
@@ -27,12 +124,170 @@
 //!
 //! // Initialize the `E2ee` instance with both private and public keys.
 //! let e2ee_server = E2ee::new_from_pem(private_key_pem, public_key_pem).expect("Failed to create E2ee server instance");
+//! # }
 //! ```
 //!
 //! ## Features
 //!
+//! - **`io`** (enabled by default): Enable PEM encoding/decoding and saving keys to files. Disable it
+//!   with `default-features = false` when only the in-memory crypto is needed.
 //! - **`ffi`**: Enable the `ffi` feature to include the foreign function interface for cross-platform support.
+//! - **`ffi-testing`**: Enable `ffi::e2ee_set_rng_seed_for_testing`, seeding the generator of the
+//!   instances created over FFI for deterministic golden tests. Never enable it in production.
+//! - **`tracing`**: Emit [`tracing`](https://docs.rs/tracing) spans and events for key generation, encryption,
+//!   decryption and key file operations, with the OAEP hashes, encoding and envelope format
+//!   attempted, and where decryption failed. Plaintext and key material are never recorded.
+//! - **`test-utils`**: Enable the `test_utils` module with [`proptest`](https://docs.rs/proptest)
+//!   strategies and `assert_roundtrip`, to property-test wrappers built on this crate.
+//! - **`secure-mem`**: Enable `secure_mem::LockedString`, page-locked storage for secrets that is
+//!   excluded from swap and core dumps, in which `E2ee::get_private_key_pem` returns the
+//!   PEM-encoded private key, and `secure_mem::lock_all_memory` and
+//!   `secure_mem::disable_core_dumps` to protect the rest of the process. The `RsaPrivateKey` held
+//!   by `E2ee` and used by every decryption is **not** page-locked: its numbers live in ordinary
+//!   heap buffers owned by the `rsa` crate, which may be swapped out or dumped unless
+//!   `secure_mem::lock_all_memory` and `secure_mem::disable_core_dumps` are called at startup,
+//!   before any key is loaded.
+//! - **`axum`**: Enable `server::layer::EnvelopeLayer`, a [`tower`](https://docs.rs/tower) layer
+//!   that decrypts envelope request bodies and encrypts responses for `axum` services.
+//! - **`tonic`**: Enable the `grpc` module with a [`tonic`](https://docs.rs/tonic) codec that
+//!   encrypts every gRPC message into an envelope.
+//! - **`mq`**: Enable the `mq` module with `EncryptingSerializer` and `DecryptingDeserializer`, to
+//!   encrypt Kafka or AMQP message payloads serialized with any serde format.
+//! - **`pgp`**: Enable the `pgp` module to import OpenPGP public keys and encrypt data into OpenPGP
+//!   messages that recipients can decrypt with GnuPG.
+//! - **`cache`**: Enable the `cache` module and `E2ee::with_decryption_cache`, caching the results
+//!   of RSA decryptions (e.g. wrapped data keys) with a time to live for read-heavy services.
+//! - **`convergent`**: Enable the `convergent` module, encrypting backup chunks under keys derived
+//!   from their content so that identical chunks deduplicate. Read its confidentiality tradeoffs
+//!   before enabling it.
+//! - **`sqlx`**: Enable the `column` module, with an [`sqlx`](https://docs.rs/sqlx) column type
+//!   for values encrypted and decrypted by the keys of their column.
+//! - **`sqlite`**: Enable `storage::sqlite`, a storage backend keeping values sealed under a
+//!   storage key in an SQLite database, with transactions and concurrent access.
+//! - **`redis`**: Enable `server::guard::redis`, keeping the lockouts and replay windows of
+//!   `DecryptGuard` in Redis, shared by the instances of a horizontally scaled service.
+//! - **`watch`**: Enable the `watch` module, reloading the key pair of a server from its key
+//!   files when they change, without restarting it.
+//! - **`session`**: Enable the `session` module, with X25519 handshakes signed by the RSA identity
+//!   keys and Double Ratchet message encryption for chat-like conversations, and the `ephemeral`
+//!   module with forward-secret one-shot messages.
+//! - **`timestamp`**: Enable the `timestamp` module, to have signatures timestamped by an RFC 3161
+//!   time-stamping authority over HTTP and prove that they predate a key compromise or expiry.
+//! - **`discovery`**: Enable the `discovery` module, to publish public keys at `.well-known` URLs
+//!   and fetch those of other domains over HTTPS.
+//! - **`dns`**: Enable the `dns` module, to check public keys against fingerprints published in
+//!   DNS as a second channel besides the one that delivered the keys.
+//! - **`mmap`**: Enable `stream::decrypt_file_mmap`, decrypting memory-mapped files chunk by chunk
+//!   for multi-gigabyte archives on hosts with little memory.
+//! - **`bytes`**: Enable `E2ee::encrypt_bytes_ref`, `E2ee::decrypt_bytes_ref` and
+//!   `PublicE2ee::encrypt_bytes_ref`, exchanging raw ciphertexts as [`bytes::Bytes`] without base64
+//!   round trips, e.g. in proxies forwarding binary payloads.
+//! - **`password`**: Enable the `password` module, deriving keys from passwords and passphrases
+//!   with Argon2id and parameters calibrated for the host.
+//! - **`email`**: Enable the `email` module, encrypting emails with S/MIME (`application/pkcs7-mime`)
+//!   for X.509 recipient certificates.
+//! - **`proto`**: Enable the `proto` module, with [`prost`](https://docs.rs/prost) types for the
+//!   envelope and key bundle messages of `proto/e2ee.proto`.
+//! - **`keystore`**: Enable the `keystore` and `backup` modules, keeping named private keys in a
+//!   file encrypted under a passphrase and backing up identities (implies `password`).
+//! - **`wasm`**: Enable the `wasm` module, exporting key pairs and stream encryption to JavaScript
+//!   with [`wasm-bindgen`](https://docs.rs/wasm-bindgen) for `wasm32-unknown-unknown` builds.
+//! - **`wasm-storage`**: Enable the `wasm_storage` module, persisting the key pairs of browser
+//!   apps in IndexedDB (implies `wasm` and `password`).
+//! - **`experimental`**: Enable the `deniable` module, whose dual-message ciphertexts open to a
+//!   decoy or a hidden plaintext depending on the key, and the `proxy_reencryption` module. Their
+//!   formats are unstable and unreviewed: read the warnings of the modules before relying on them.
+extern crate alloc;
+
+pub mod at_rest;
+pub mod audit;
+#[cfg(feature = "keystore")]
+pub mod backup;
+#[cfg(feature = "cache")]
+pub mod cache;
 pub mod client;
+#[cfg(any(feature = "timestamp", feature = "email"))]
+// Each user of the module needs only some of its structures.
+#[cfg_attr(not(all(feature = "timestamp", feature = "email")), allow(dead_code))]
+mod cms;
+#[cfg(feature = "sqlx")]
+pub mod column;
+#[cfg(feature = "convergent")]
+pub mod convergent;
+pub mod conversation;
+pub mod core;
+#[cfg(feature = "experimental")]
+pub mod deniable;
+#[cfg(feature = "io")]
+pub mod devices;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+#[cfg(feature = "dns")]
+pub mod dns;
+pub mod dotenv;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod envelope;
+#[cfg(feature = "session")]
+pub mod ephemeral;
 #[cfg(feature = "ffi")]
 pub mod ffi;
+#[cfg(feature = "tonic")]
+pub mod grpc;
+#[cfg(feature = "io")]
+pub mod identity;
+pub mod incremental;
+pub mod interop;
+#[cfg(feature = "io")]
+pub mod io;
+pub mod iot;
+pub mod kdf;
+#[cfg(feature = "io")]
+pub mod key_import;
+pub mod key_wrap;
+pub mod keygen;
+#[cfg(feature = "keystore")]
+pub mod keystore;
+pub mod mac;
+pub mod merkle;
+pub mod message;
+pub mod metrics;
+#[cfg(feature = "mq")]
+pub mod mq;
+#[cfg(feature = "password")]
+pub mod password;
+#[cfg(feature = "pgp")]
+pub mod pgp;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(feature = "experimental")]
+pub mod proxy_reencryption;
+pub mod rng;
+pub mod sealed_sender;
+pub mod secrets;
+#[cfg(feature = "secure-mem")]
+pub mod secure_mem;
 pub mod server;
+#[cfg(feature = "session")]
+pub mod session;
+#[cfg(feature = "io")]
+pub mod signing;
+pub mod storage;
+pub mod stream;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+pub mod threshold;
+#[cfg(feature = "timestamp")]
+pub mod timestamp;
+#[cfg(feature = "io")]
+pub mod token;
+pub mod traits;
+pub mod uri;
+#[cfg(feature = "io")]
+pub mod vectors;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "wasm-storage")]
+pub mod wasm_storage;
+#[cfg(feature = "watch")]
+pub mod watch;
@@ -0,0 +1,316 @@
+//! HMAC-SHA256 and authenticated envelopes for plain RSA-OAEP ciphertexts.
+//!
+//! RSA-OAEP ciphertexts carry no integrity protection beyond their padding: metadata sent with
+//! them (a key ID, a content type, a routing header) can be swapped in transit, and a tampered
+//! ciphertext is only noticed after a private key operation. This module binds a header and a
+//! ciphertext together, so that tampering with either is detected before decryption:
+//!
+//! - [`Mac::seal`] and [`Mac::open`] append and check an HMAC-SHA256 tag, with a key shared by the
+//!   sender and the recipient (e.g. derived with the `kdf` module);
+//! - [`sign_envelope`] and [`verify_envelope`] append and check an RSA signature of the sender,
+//!   when no key is shared.
+//!
+//! The binary layout of an authenticated envelope is:
+//!
+//! ```text
+//! version (1 byte) | header length (2 bytes, big endian) | header | ciphertext | tag or signature
+//! ```
+//!
+//! The tag or signature covers everything before it, prefixed with a context separating it from
+//! other uses of the key. `E2ee::with_envelope_mac` and `PublicE2ee::with_envelope_mac` apply
+//! [`Mac::seal`] to every ciphertext of `encrypt`, with an empty header.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::mac::{self, Mac};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let ciphertext = core::encrypt(e2ee.get_public_key(), b"Hello, world!").unwrap();
+//!
+//! let mac = Mac::new(b"a key shared with the recipient");
+//! let sealed = mac.seal(b"kid=2024-01", &ciphertext).expect("Failed to seal");
+//! let opened = mac.open(&sealed).expect("The envelope was tampered with");
+//! assert_eq!(opened.header, b"kid=2024-01");
+//! assert_eq!(opened.ciphertext, ciphertext);
+//!
+//! let signed = mac::sign_envelope(&e2ee, b"kid=2024-01", &ciphertext).expect("Failed to sign");
+//! mac::verify_envelope(e2ee.get_public_key(), &signed).expect("The envelope was tampered with");
+//! ```
+use crate::core;
+use crate::server::{E2ee, E2eeError};
+use hmac::{Hmac, Mac as _};
+use rsa::{sha2::Sha256, traits::PublicKeyParts, RsaPublicKey};
+use std::fmt;
+use thiserror::Error;
+
+/// The version of the layout of authenticated envelopes.
+pub const VERSION: u8 = 1;
+
+/// The length of an HMAC-SHA256 tag, in bytes.
+pub const TAG_LEN: usize = 32;
+
+/// Separates the tags of authenticated envelopes from other tags of the same key.
+const MAC_CONTEXT: &[u8] = b"e2ee authenticated envelope v1\0";
+
+/// Separates the signatures of authenticated envelopes from other signatures of the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee signed envelope v1\0";
+
+pub type MacResult<T> = std::result::Result<T, MacError>;
+
+/// An error returned when authenticating envelopes.
+#[derive(Error, Debug)]
+pub enum MacError {
+    #[error("Signing failed: {0}")]
+    Signing(Box<E2eeError>),
+
+    #[error("Header of {0} bytes is too long, at most 65535 bytes are allowed")]
+    HeaderTooLong(usize),
+
+    #[error("Malformed authenticated envelope")]
+    Malformed,
+
+    #[error("Unsupported authenticated envelope version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid authentication tag")]
+    InvalidTag,
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+}
+
+/// The header and the ciphertext of an authenticated envelope whose tag or signature was checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Authenticated<'a> {
+    pub header: &'a [u8],
+    pub ciphertext: &'a [u8],
+}
+
+/// An HMAC-SHA256 key.
+#[derive(Clone)]
+pub struct Mac {
+    hmac: Hmac<Sha256>,
+}
+
+impl fmt::Debug for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mac").finish_non_exhaustive()
+    }
+}
+
+impl Mac {
+    /// Creates a MAC keyed with `key`, which should be at least 32 random bytes.
+    pub fn new(key: &[u8]) -> Self {
+        Self {
+            hmac: Hmac::new_from_slice(key).expect("HMAC accepts keys of any size"),
+        }
+    }
+
+    /// Returns the HMAC-SHA256 tag of `data`.
+    pub fn tag(&self, data: &[u8]) -> [u8; TAG_LEN] {
+        self.tag_parts(&[data])
+    }
+
+    /// Checks the `tag` of `data` in constant time.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MacError::InvalidTag` if the tag does not match.
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> MacResult<()> {
+        self.verify_parts(&[data], tag)
+    }
+
+    /// Authenticates `header` and `ciphertext` into an envelope.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MacError::HeaderTooLong` if `header` is longer than 65535 bytes.
+    pub fn seal(&self, header: &[u8], ciphertext: &[u8]) -> MacResult<Vec<u8>> {
+        let mut sealed = encode(header, ciphertext)?;
+        let tag = self.tag_parts(&[MAC_CONTEXT, &sealed]);
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// Checks the tag of an envelope produced by [`Mac::seal`] and returns its header and
+    /// ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MacError::InvalidTag` if the envelope was tampered with or sealed
+    /// with another key, or an error if it is malformed.
+    pub fn open<'a>(&self, sealed: &'a [u8]) -> MacResult<Authenticated<'a>> {
+        let split = sealed
+            .len()
+            .checked_sub(TAG_LEN)
+            .ok_or(MacError::Malformed)?;
+        let (data, tag) = sealed.split_at(split);
+        self.verify_parts(&[MAC_CONTEXT, data], tag)?;
+        decode(data)
+    }
+
+    fn tag_parts(&self, parts: &[&[u8]]) -> [u8; TAG_LEN] {
+        let mut hmac = self.hmac.clone();
+        for part in parts {
+            hmac.update(part);
+        }
+        hmac.finalize().into_bytes().into()
+    }
+
+    fn verify_parts(&self, parts: &[&[u8]], tag: &[u8]) -> MacResult<()> {
+        let mut hmac = self.hmac.clone();
+        for part in parts {
+            hmac.update(part);
+        }
+        hmac.verify_slice(tag).map_err(|_| MacError::InvalidTag)
+    }
+}
+
+/// Authenticates `header` and `ciphertext` into an envelope signed with the key of `signer`.
+///
+/// # Errors
+///
+/// This function returns `MacError::HeaderTooLong` if `header` is longer than 65535 bytes, or an
+/// error if signing fails.
+pub fn sign_envelope(
+    signer: &E2ee,
+    header: &[u8],
+    ciphertext: &[u8],
+) -> MacResult<Vec<u8>> {
+    let mut sealed = encode(header, ciphertext)?;
+    let signature = signer
+        .sign(&[SIGNATURE_CONTEXT, &sealed].concat())
+        .map_err(|err| MacError::Signing(Box::new(err)))?;
+    sealed.extend_from_slice(&signature);
+    Ok(sealed)
+}
+
+/// Checks the signature of an envelope produced by [`sign_envelope`] under the key of its sender,
+/// and returns its header and ciphertext.
+///
+/// # Errors
+///
+/// This function returns `MacError::InvalidSignature` if the envelope was tampered with or signed
+/// with another key, or an error if it is malformed.
+pub fn verify_envelope<'a>(
+    public_key: &RsaPublicKey,
+    sealed: &'a [u8],
+) -> MacResult<Authenticated<'a>> {
+    let split = sealed
+        .len()
+        .checked_sub(public_key.size())
+        .ok_or(MacError::Malformed)?;
+    let (data, signature) = sealed.split_at(split);
+    core::verify(public_key, &[SIGNATURE_CONTEXT, data].concat(), signature)
+        .map_err(|_| MacError::InvalidSignature)?;
+    decode(data)
+}
+
+fn encode(header: &[u8], ciphertext: &[u8]) -> MacResult<Vec<u8>> {
+    let len = u16::try_from(header.len())
+        .map_err(|_| MacError::HeaderTooLong(header.len()))?;
+    let mut encoded =
+        Vec::with_capacity(3 + header.len() + ciphertext.len() + TAG_LEN);
+    encoded.push(VERSION);
+    encoded.extend_from_slice(&len.to_be_bytes());
+    encoded.extend_from_slice(header);
+    encoded.extend_from_slice(ciphertext);
+    Ok(encoded)
+}
+
+fn decode(data: &[u8]) -> MacResult<Authenticated<'_>> {
+    let (&version, rest) = data.split_first().ok_or(MacError::Malformed)?;
+    if version != VERSION {
+        return Err(MacError::UnsupportedVersion(version));
+    }
+    if rest.len() < 2 {
+        return Err(MacError::Malformed);
+    }
+    let (len, rest) = rest.split_at(2);
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    if rest.len() < len {
+        return Err(MacError::Malformed);
+    }
+    let (header, ciphertext) = rest.split_at(len);
+    Ok(Authenticated { header, ciphertext })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    /// RFC 4231, test case 2.
+    #[test]
+    fn test_rfc4231_vector() {
+        let tag = Mac::new(b"Jefe").tag(b"what do ya want for nothing?");
+        assert_eq!(
+            core::hex(&tag),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let mac = Mac::new(b"Jefe");
+        mac.verify(b"what do ya want for nothing?", &tag).unwrap();
+        assert!(matches!(
+            mac.verify(b"what do ya want for something?", &tag),
+            Err(MacError::InvalidTag)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_envelopes_are_rejected() {
+        let mac = Mac::new(&[7; 32]);
+        let sealed = mac.seal(b"header", b"ciphertext").unwrap();
+        assert_eq!(
+            mac.open(&sealed).unwrap(),
+            Authenticated {
+                header: b"header",
+                ciphertext: b"ciphertext"
+            }
+        );
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(mac.open(&tampered).is_err(), "byte {i}");
+        }
+        assert!(matches!(
+            Mac::new(&[8; 32]).open(&sealed),
+            Err(MacError::InvalidTag)
+        ));
+        assert!(matches!(mac.open(&sealed[..10]), Err(MacError::Malformed)));
+        let forged = [&[2, 0, 0][..], &mac.tag(b"")].concat();
+        assert!(matches!(mac.open(&forged), Err(MacError::InvalidTag)));
+        // The tag of an envelope is not the plain tag of its content.
+        assert_ne!(
+            &sealed[sealed.len() - TAG_LEN..],
+            mac.tag(&sealed[..sealed.len() - TAG_LEN])
+        );
+        assert!(matches!(
+            mac.seal(&vec![0; 65536], b""),
+            Err(MacError::HeaderTooLong(65536))
+        ));
+    }
+
+    #[test]
+    fn test_signed_envelopes() {
+        let signer = E2ee::new(KeySize::Bit1024).unwrap();
+        let other = E2ee::new(KeySize::Bit1024).unwrap();
+        let signed = sign_envelope(&signer, b"", b"ciphertext").unwrap();
+        let opened = verify_envelope(signer.get_public_key(), &signed).unwrap();
+        assert_eq!(opened.header, b"");
+        assert_eq!(opened.ciphertext, b"ciphertext");
+
+        let mut tampered = signed.clone();
+        tampered[3] ^= 1;
+        assert!(matches!(
+            verify_envelope(signer.get_public_key(), &tampered),
+            Err(MacError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify_envelope(other.get_public_key(), &signed),
+            Err(MacError::InvalidSignature)
+        ));
+    }
+}
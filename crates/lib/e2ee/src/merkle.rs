@@ -0,0 +1,406 @@
+//! Merkle trees over the hashes of encrypted chunks, with signed roots.
+//!
+//! A [`MerkleTree`] commits to an ordered list of chunks, e.g. the envelopes of a backup (see
+//! `incremental::Manifest::merkle_tree`) or the chunks of an encrypted stream, with a single
+//! 32-byte root. Once the root is signed into a [`SignedRoot`], anyone holding the public key can:
+//!
+//! - check a single downloaded chunk with a [`MerkleProof`] of `log2(n)` hashes, without
+//!   downloading the rest of the archive;
+//! - locate tampered chunks with [`MerkleTree::diff`], which compares the tree built from the
+//!   chunks found in a store with the signed one and only descends into the subtrees that differ.
+//!
+//! Leaves are the SHA-256 digests of the chunks. Leaf and inner nodes are hashed with distinct
+//! prefixes, so that an inner node cannot be passed off as a leaf:
+//!
+//! ```text
+//! leaf node  = SHA-256(0x00 | SHA-256(chunk))
+//! inner node = SHA-256(0x01 | left | right)
+//! ```
+//!
+//! The last node of a level with an odd number of nodes is promoted to the next level unchanged.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::merkle::MerkleTree;
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let chunks = [b"chunk 0".as_slice(), b"chunk 1", b"chunk 2"];
+//! let tree = MerkleTree::from_chunks(chunks);
+//! let signed_root = tree.sign(&private_key).expect("Failed to sign root");
+//!
+//! // A client checks chunk 2 against the signed root alone.
+//! signed_root
+//!     .verify(&private_key.to_public_key())
+//!     .expect("Invalid signature");
+//! let proof = tree.proof(2).expect("No such chunk");
+//! assert!(proof.verify(&signed_root.root, b"chunk 2"));
+//! assert!(!proof.verify(&signed_root.root, b"tampered"));
+//! ```
+use crate::core;
+use rsa::{
+    sha2::{Digest, Sha256},
+    RsaPrivateKey, RsaPublicKey,
+};
+use thiserror::Error;
+
+/// The version of the encoding of signed roots.
+pub const VERSION: u8 = 1;
+
+/// The prefix of the signed message, binding signatures to Merkle roots.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee merkle root v1";
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+pub type MerkleResult<T> = std::result::Result<T, MerkleError>;
+
+/// An error returned when signing or verifying a Merkle root.
+#[derive(Error, Debug)]
+pub enum MerkleError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::Error),
+
+    #[error("Malformed signed root")]
+    Malformed,
+
+    #[error("Unsupported version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid signature of the Merkle root")]
+    InvalidSignature,
+}
+
+/// Returns the SHA-256 digest of `chunk`, the leaf of a [`MerkleTree`].
+pub fn chunk_digest(chunk: &[u8]) -> [u8; 32] {
+    Sha256::digest(chunk).into()
+}
+
+fn leaf_node(digest: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([LEAF_PREFIX])
+        .chain_update(digest)
+        .finalize()
+        .into()
+}
+
+fn inner_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    Sha256::new()
+        .chain_update([NODE_PREFIX])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// A Merkle tree over the digests of an ordered list of chunks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleTree {
+    /// The nodes of each level, from the leaf nodes to the root.
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    /// Builds the tree over `digests`, the SHA-256 digests of the chunks (see [`chunk_digest`]).
+    pub fn new(digests: impl IntoIterator<Item = [u8; 32]>) -> Self {
+        let mut levels: Vec<Vec<_>> =
+            vec![digests.into_iter().map(|d| leaf_node(&d)).collect()];
+        while let Some(level) = levels.last().filter(|level| level.len() > 1) {
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => inner_node(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks of two nodes"),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// Builds the tree over `chunks`.
+    pub fn from_chunks<T: AsRef<[u8]>>(chunks: impl IntoIterator<Item = T>) -> Self {
+        Self::new(chunks.into_iter().map(|chunk| chunk_digest(chunk.as_ref())))
+    }
+
+    /// Returns the number of chunks.
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Returns whether the tree has no chunks.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the root of the tree, or the SHA-256 digest of nothing for an empty tree.
+    pub fn root(&self) -> [u8; 32] {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => chunk_digest(&[]),
+        }
+    }
+
+    /// Returns the proof that chunk `index` is in the tree, or `None` if there is no such chunk.
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(position ^ 1) {
+                siblings.push(*sibling);
+            }
+            position /= 2;
+        }
+        Some(MerkleProof {
+            index,
+            leaf_count: self.len(),
+            siblings,
+        })
+    }
+
+    /// Returns the indices of the chunks that differ between this tree and `other`, e.g. the
+    /// trusted tree of a signed root and the tree of the chunks found in a store.
+    ///
+    /// Only the subtrees whose roots differ are visited, so a few tampered chunks are located in
+    /// `O(k log n)` comparisons. Trees of different sizes are compared chunk by chunk, and the
+    /// chunks present in only one of them are reported as different.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<usize> {
+        let mut differences = Vec::new();
+        if self.len() != other.len() {
+            let (short, long) = if self.len() < other.len() {
+                (self, other)
+            } else {
+                (other, self)
+            };
+            differences
+                .extend((0..long.len()).filter(|&i| {
+                    short.levels[0].get(i) != Some(&long.levels[0][i])
+                }));
+        } else if !self.is_empty() {
+            self.diff_node(other, self.levels.len() - 1, 0, &mut differences);
+        }
+        differences
+    }
+
+    fn diff_node(
+        &self,
+        other: &MerkleTree,
+        level: usize,
+        position: usize,
+        differences: &mut Vec<usize>,
+    ) {
+        if self.levels[level][position] == other.levels[level][position] {
+            return;
+        }
+        if level == 0 {
+            differences.push(position);
+            return;
+        }
+        for child in [2 * position, 2 * position + 1] {
+            if child < self.levels[level - 1].len() {
+                self.diff_node(other, level - 1, child, differences);
+            }
+        }
+    }
+
+    /// Signs the root and the number of chunks with `private_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing fails.
+    pub fn sign(&self, private_key: &RsaPrivateKey) -> MerkleResult<SignedRoot> {
+        let root = self.root();
+        let leaf_count = self.len() as u64;
+        let signature =
+            core::sign_blinded(private_key, &signed_message(&root, leaf_count))?;
+        Ok(SignedRoot {
+            root,
+            leaf_count,
+            signature,
+        })
+    }
+}
+
+/// The sibling hashes linking a chunk to the root of a [`MerkleTree`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    /// The index of the chunk.
+    pub index: usize,
+    /// The number of chunks of the tree.
+    pub leaf_count: usize,
+    /// The sibling of the chunk's node at each level, skipping the levels where it has none.
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleProof {
+    /// Returns whether `chunk` is chunk `index` of the tree with `root`.
+    pub fn verify(&self, root: &[u8; 32], chunk: &[u8]) -> bool {
+        self.verify_digest(root, &chunk_digest(chunk))
+    }
+
+    /// Returns whether the chunk with SHA-256 `digest` is chunk `index` of the tree with `root`.
+    pub fn verify_digest(&self, root: &[u8; 32], digest: &[u8; 32]) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+        let mut node = leaf_node(digest);
+        let mut siblings = self.siblings.iter();
+        let (mut position, mut width) = (self.index, self.leaf_count);
+        while width > 1 {
+            if position ^ 1 < width {
+                let Some(sibling) = siblings.next() else {
+                    return false;
+                };
+                node = if position % 2 == 0 {
+                    inner_node(&node, sibling)
+                } else {
+                    inner_node(sibling, &node)
+                };
+            }
+            position /= 2;
+            width = width.div_ceil(2);
+        }
+        siblings.next().is_none() && node == *root
+    }
+}
+
+/// A Merkle root signed by [`MerkleTree::sign`].
+///
+/// It is encoded as `version (1 byte) | chunk count (8 bytes) | root (32 bytes) | signature`,
+/// with integers in big endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedRoot {
+    /// The root of the tree.
+    pub root: [u8; 32],
+    /// The number of chunks of the tree.
+    pub leaf_count: u64,
+    /// The RSA PKCS#1 v1.5 signature of the root and the number of chunks.
+    pub signature: Vec<u8>,
+}
+
+impl SignedRoot {
+    /// Verifies the signature with `public_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MerkleError::InvalidSignature` if the signature is invalid.
+    pub fn verify(&self, public_key: &RsaPublicKey) -> MerkleResult<()> {
+        core::verify(
+            public_key,
+            &signed_message(&self.root, self.leaf_count),
+            &self.signature,
+        )
+        .map_err(|_| MerkleError::InvalidSignature)
+    }
+
+    /// Encodes the signed root, to be stored next to the chunks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut encoded = vec![VERSION];
+        encoded.extend(self.leaf_count.to_be_bytes());
+        encoded.extend(self.root);
+        encoded.extend(&self.signature);
+        encoded
+    }
+
+    /// Decodes a signed root encoded with [`SignedRoot::to_bytes`], without verifying it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `encoded` is truncated or of an unknown version.
+    pub fn from_bytes(encoded: &[u8]) -> MerkleResult<Self> {
+        let (&version, rest) =
+            encoded.split_first().ok_or(MerkleError::Malformed)?;
+        if version != VERSION {
+            return Err(MerkleError::UnsupportedVersion(version));
+        }
+        if rest.len() <= 40 {
+            return Err(MerkleError::Malformed);
+        }
+        let (leaf_count, rest) = rest.split_at(8);
+        let (root, signature) = rest.split_at(32);
+        Ok(Self {
+            root: root.try_into().expect("32 bytes"),
+            leaf_count: u64::from_be_bytes(leaf_count.try_into().expect("8 bytes")),
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+fn signed_message(root: &[u8; 32], leaf_count: u64) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend(leaf_count.to_be_bytes());
+    message.extend(root);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| format!("chunk {i}").into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn test_proofs_of_every_chunk() {
+        for count in [1, 2, 3, 7, 8, 13] {
+            let chunks = chunks(count);
+            let tree = MerkleTree::from_chunks(&chunks);
+            let root = tree.root();
+            for (index, chunk) in chunks.iter().enumerate() {
+                let proof = tree.proof(index).unwrap();
+                assert!(proof.verify(&root, chunk), "{index} of {count}");
+                assert!(!proof.verify(&root, b"other"));
+                let moved = MerkleProof {
+                    index: (index + 1) % count,
+                    ..proof.clone()
+                };
+                assert!(count == 1 || !moved.verify(&root, chunk));
+            }
+            assert!(tree.proof(count).is_none());
+        }
+        assert_ne!(
+            MerkleTree::from_chunks(chunks(3)).root(),
+            MerkleTree::from_chunks(chunks(4)).root()
+        );
+    }
+
+    #[test]
+    fn test_diff_locates_tampered_chunks() {
+        let chunks = chunks(13);
+        let tree = MerkleTree::from_chunks(&chunks);
+        let mut tampered = chunks.clone();
+        tampered[2] = b"tampered".to_vec();
+        tampered[12] = b"tampered".to_vec();
+        assert_eq!(tree.diff(&MerkleTree::from_chunks(&tampered)), [2, 12]);
+        assert_eq!(tree.diff(&tree), Vec::<usize>::new());
+        assert_eq!(tree.diff(&MerkleTree::from_chunks(&chunks[..11])), [11, 12]);
+    }
+
+    #[test]
+    fn test_signed_root_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let signed = MerkleTree::from_chunks(chunks(5))
+            .sign(&private_key)
+            .unwrap();
+        let decoded = SignedRoot::from_bytes(&signed.to_bytes()).unwrap();
+        assert_eq!(decoded, signed);
+        decoded.verify(&private_key.to_public_key()).unwrap();
+
+        let forged = SignedRoot {
+            leaf_count: 4,
+            ..decoded
+        };
+        assert!(matches!(
+            forged.verify(&private_key.to_public_key()),
+            Err(MerkleError::InvalidSignature)
+        ));
+    }
+}
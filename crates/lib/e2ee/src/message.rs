@@ -0,0 +1,607 @@
+//! Chat messages with text, attachments and metadata.
+//!
+//! A [`Message`] gathers what a chat application sends at once: a text, attachments and
+//! metadata (e.g. a conversation ID or a reply-to reference). Attachments are encrypted for the
+//! recipient on their own with [`Message::attach`], as streams (see the `stream` module), so that
+//! large files are never held in memory and can be uploaded separately, e.g. to a blob store. The
+//! message keeps the name, the content type, the size and the SHA-256 digest of the encrypted
+//! stream of each attachment.
+//!
+//! [`Message::seal`] signs the canonical encoding of the message, which covers the text, the
+//! metadata and the digest of every attachment, and encrypts it with the signature into an
+//! envelope for the recipient. One signature thus authenticates the whole message:
+//! [`decrypt_attachment`] checks that an attachment is the one the sender attached. The canonical
+//! encoding, returned by [`Message::to_bytes`], is:
+//!
+//! ```text
+//! version (1 byte) | text length (4 bytes) | text | metadata count (2 bytes) |
+//! (key length (2 bytes) | key | value length (4 bytes) | value)... | attachment count (2 bytes) |
+//! (name length (2 bytes) | name | content type length (2 bytes) | content type |
+//!  size (8 bytes) | digest (32 bytes))...
+//! ```
+//!
+//! with integers in big endian and metadata sorted by key. The plaintext of the envelope is:
+//!
+//! ```text
+//! signature length (2 bytes, big endian) | signature | canonical encoding
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::message::{self, Message};
+//! use e2ee::server::{E2ee, KeySize};
+//!
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let bob = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//!
+//! let mut message = Message::new("Here are the photos").with_metadata("conversation", "42");
+//! let photo = b"...JPEG data...";
+//! let encrypted_photo = message
+//!     .attach(bob.get_public_key(), "beach.jpg", "image/jpeg", &photo[..], Vec::new())
+//!     .expect("Failed to encrypt attachment");
+//! let sealed = message.seal(&alice, bob.get_public_key()).expect("Failed to seal message");
+//!
+//! let opened =
+//!     Message::open(&bob, alice.get_public_key(), &sealed).expect("Failed to open message");
+//! assert_eq!(opened.text(), "Here are the photos");
+//! let mut decrypted = Vec::new();
+//! message::decrypt_attachment(
+//!     &bob,
+//!     &opened.attachments()[0],
+//!     &encrypted_photo[..],
+//!     &mut decrypted,
+//! )
+//! .expect("Failed to decrypt attachment");
+//! assert_eq!(decrypted, photo);
+//! ```
+use crate::core;
+use crate::envelope::{self, EnvelopeError};
+use crate::server::{E2ee, E2eeError};
+use crate::stream::{StreamDecryptor, StreamEncryptor, StreamError};
+use rsa::{
+    pkcs8::spki,
+    sha2::{Digest, Sha256},
+    RsaPublicKey,
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+};
+use thiserror::Error;
+
+/// The version of the canonical encoding of messages.
+pub const VERSION: u8 = 1;
+
+/// Separates the signatures of messages from other signatures of the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee message v1\0";
+
+pub type MessageResult<T> = std::result::Result<T, MessageError>;
+
+/// An error returned when sealing or opening a message or its attachments.
+#[derive(Error, Debug)]
+pub enum MessageError {
+    #[error("E2ee error: {0}")]
+    E2ee(#[from] E2eeError),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Stream error: {0}")]
+    Stream(#[from] StreamError),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("The {0} is too long")]
+    TooLong(&'static str),
+
+    #[error("Malformed message")]
+    Malformed,
+
+    #[error("Unsupported message version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid sender signature")]
+    InvalidSignature,
+
+    #[error("The attachment is not the one of the message")]
+    AttachmentMismatch,
+}
+
+/// An attachment of a [`Message`], whose content is encrypted separately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    name: String,
+    content_type: String,
+    size: u64,
+    digest: [u8; 32],
+}
+
+impl Attachment {
+    /// Returns the file name of the attachment.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the media type of the attachment, e.g. `image/jpeg`.
+    pub fn content_type(&self) -> &str {
+        &self.content_type
+    }
+
+    /// Returns the size of the plaintext of the attachment, in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the SHA-256 digest of the encrypted stream of the attachment.
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+}
+
+/// A chat message: a text, attachments and metadata.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    text: String,
+    metadata: BTreeMap<String, String>,
+    attachments: Vec<Attachment>,
+}
+
+impl Message {
+    /// Creates a message with `text`, without attachments or metadata.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Sets the metadata `key` to `value`.
+    pub fn with_metadata(
+        mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    /// Returns the text of the message.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Returns the metadata of the message, sorted by key.
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Returns the attachments of the message, in the order they were attached.
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+
+    /// Encrypts the content read from `reader` for `recipient` into `writer`, as a stream, and adds
+    /// it to the message as an attachment named `name`. Returns `writer`, flushed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MessageError::TooLong` if `name` or `content_type` is longer than
+    /// 65535 bytes, or an error if reading, encryption or writing fails.
+    pub fn attach<R: Read, W: Write>(
+        &mut self,
+        recipient: &RsaPublicKey,
+        name: &str,
+        content_type: &str,
+        mut reader: R,
+        writer: W,
+    ) -> MessageResult<W> {
+        for (field, value) in
+            [("attachment name", name), ("content type", content_type)]
+        {
+            if value.len() > usize::from(u16::MAX) {
+                return Err(MessageError::TooLong(field));
+            }
+        }
+        let mut encryptor =
+            StreamEncryptor::new(recipient, HashingWriter::new(writer))?;
+        let size = io::copy(&mut reader, &mut encryptor)?;
+        let HashingWriter { inner, hasher } = encryptor.finish()?;
+        self.attachments.push(Attachment {
+            name: name.to_string(),
+            content_type: content_type.to_string(),
+            size,
+            digest: hasher.finalize().into(),
+        });
+        Ok(inner)
+    }
+
+    /// Returns the canonical encoding of the message, the same for equal messages.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MessageError::TooLong` if a field or a count exceeds its length
+    /// prefix.
+    pub fn to_bytes(&self) -> MessageResult<Vec<u8>> {
+        let mut bytes = vec![VERSION];
+        push_u32_field(&mut bytes, "text", self.text.as_bytes())?;
+        push_count(&mut bytes, "metadata", self.metadata.len())?;
+        for (key, value) in &self.metadata {
+            push_u16_field(&mut bytes, "metadata key", key.as_bytes())?;
+            push_u32_field(&mut bytes, "metadata value", value.as_bytes())?;
+        }
+        push_count(&mut bytes, "attachment list", self.attachments.len())?;
+        for attachment in &self.attachments {
+            push_u16_field(
+                &mut bytes,
+                "attachment name",
+                attachment.name.as_bytes(),
+            )?;
+            push_u16_field(
+                &mut bytes,
+                "content type",
+                attachment.content_type.as_bytes(),
+            )?;
+            bytes.extend_from_slice(&attachment.size.to_be_bytes());
+            bytes.extend_from_slice(&attachment.digest);
+        }
+        Ok(bytes)
+    }
+
+    /// Decodes the canonical encoding of a message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MessageError::UnsupportedVersion` if the encoding has another
+    /// version, or `MessageError::Malformed` if it is truncated, has trailing data, is not
+    /// canonical or holds invalid UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> MessageResult<Self> {
+        let (&version, rest) = bytes.split_first().ok_or(MessageError::Malformed)?;
+        if version != VERSION {
+            return Err(MessageError::UnsupportedVersion(version));
+        }
+        let mut reader = FieldReader { rest };
+        let text = reader.string(4)?;
+        let mut metadata = BTreeMap::new();
+        for _ in 0..reader.integer(2)? {
+            let key = reader.string(2)?;
+            // Keys are sorted and unique in the canonical encoding.
+            if metadata
+                .last_key_value()
+                .is_some_and(|(last, _)| *last >= key)
+            {
+                return Err(MessageError::Malformed);
+            }
+            metadata.insert(key, reader.string(4)?);
+        }
+        let mut attachments = Vec::new();
+        for _ in 0..reader.integer(2)? {
+            attachments.push(Attachment {
+                name: reader.string(2)?,
+                content_type: reader.string(2)?,
+                size: reader.integer(8)?,
+                digest: reader.bytes(32)?.try_into().expect("32 bytes"),
+            });
+        }
+        if !reader.rest.is_empty() {
+            return Err(MessageError::Malformed);
+        }
+        Ok(Self {
+            text,
+            metadata,
+            attachments,
+        })
+    }
+
+    /// Returns the SHA-256 digest of the canonical encoding of the message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message cannot be encoded (see
+    /// [`Message::to_bytes`]).
+    pub fn digest(&self) -> MessageResult<[u8; 32]> {
+        Ok(Sha256::digest(self.to_bytes()?).into())
+    }
+
+    /// Signs the message with the key of `sender`, and encrypts it with the signature into an
+    /// envelope for `recipient`. The signature also covers the key ID of the recipient.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message cannot be encoded, or if signing or
+    /// encryption fails.
+    pub fn seal(
+        &self,
+        sender: &E2ee,
+        recipient: &RsaPublicKey,
+    ) -> MessageResult<Vec<u8>> {
+        let bytes = self.to_bytes()?;
+        let signature =
+            sender.sign(&signed_data(&core::key_id(recipient)?, &bytes))?;
+        let mut plaintext = Vec::with_capacity(2 + signature.len() + bytes.len());
+        push_u16_field(&mut plaintext, "signature", &signature)?;
+        plaintext.extend_from_slice(&bytes);
+        Ok(envelope::seal(recipient, &plaintext)?)
+    }
+
+    /// Decrypts a message sealed with [`Message::seal`] with the key of `recipient`, and checks its
+    /// signature against the key of `sender`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the envelope cannot be decrypted, if the message is
+    /// malformed, or `MessageError::InvalidSignature` if the signature does not match the sender
+    /// key, the message and the recipient.
+    pub fn open(
+        recipient: &E2ee,
+        sender: &RsaPublicKey,
+        sealed: &[u8],
+    ) -> MessageResult<Self> {
+        let plaintext = recipient.decrypt_envelope(sealed)?;
+        let mut reader = FieldReader { rest: &plaintext };
+        let len = reader.integer(2)? as usize;
+        let signature = reader.bytes(len)?;
+        let bytes = reader.rest;
+        let signed = signed_data(&core::key_id(recipient.get_public_key())?, bytes);
+        core::verify(sender, &signed, signature)
+            .map_err(|_| MessageError::InvalidSignature)?;
+        Self::from_bytes(bytes)
+    }
+}
+
+/// Decrypts the encrypted stream of `attachment`, read from `reader`, with the key of `recipient`
+/// into `writer`, and returns the size of the plaintext.
+///
+/// The plaintext is written as it is decrypted, and the digest of the stream is only checked at
+/// its end: discard the output if this function fails.
+///
+/// # Errors
+///
+/// This function returns `MessageError::AttachmentMismatch` if the stream is not the one
+/// attached to the message, or an error if decryption or I/O fails.
+pub fn decrypt_attachment<R: Read, W: Write>(
+    recipient: &E2ee,
+    attachment: &Attachment,
+    reader: R,
+    mut writer: W,
+) -> MessageResult<u64> {
+    let private_key = recipient.get_private_key();
+    let mut decryptor =
+        StreamDecryptor::new(private_key, HashingReader::new(reader))?;
+    let size = io::copy(&mut decryptor, &mut writer)?;
+    writer.flush()?;
+    let digest: [u8; 32] = decryptor.into_inner().hasher.finalize().into();
+    if digest != attachment.digest || size != attachment.size {
+        return Err(MessageError::AttachmentMismatch);
+    }
+    Ok(size)
+}
+
+/// Returns the data signed by the sender: the context, the recipient key ID and the message.
+fn signed_data(recipient_key_id: &str, message: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, recipient_key_id.as_bytes(), message].concat()
+}
+
+fn push_count(
+    bytes: &mut Vec<u8>,
+    field: &'static str,
+    count: usize,
+) -> MessageResult<()> {
+    let count = u16::try_from(count).map_err(|_| MessageError::TooLong(field))?;
+    bytes.extend_from_slice(&count.to_be_bytes());
+    Ok(())
+}
+
+fn push_u16_field(
+    bytes: &mut Vec<u8>,
+    field: &'static str,
+    value: &[u8],
+) -> MessageResult<()> {
+    push_count(bytes, field, value.len())?;
+    bytes.extend_from_slice(value);
+    Ok(())
+}
+
+fn push_u32_field(
+    bytes: &mut Vec<u8>,
+    field: &'static str,
+    value: &[u8],
+) -> MessageResult<()> {
+    let len =
+        u32::try_from(value.len()).map_err(|_| MessageError::TooLong(field))?;
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(value);
+    Ok(())
+}
+
+/// Reads the fields of a canonical encoding.
+struct FieldReader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    fn bytes(&mut self, len: usize) -> MessageResult<&'a [u8]> {
+        if self.rest.len() < len {
+            return Err(MessageError::Malformed);
+        }
+        let (bytes, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(bytes)
+    }
+
+    /// Reads a big-endian integer of `len` bytes.
+    fn integer(&mut self, len: usize) -> MessageResult<u64> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    /// Reads a UTF-8 string prefixed with its length, of `len_len` bytes.
+    fn string(&mut self, len_len: usize) -> MessageResult<String> {
+        let len = self.integer(len_len)? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec())
+            .map_err(|_| MessageError::Malformed)
+    }
+}
+
+/// Hashes everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> HashingReader<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_canonical_encoding() {
+        let message = Message::new("Hello")
+            .with_metadata("b", "2")
+            .with_metadata("a", "1");
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(
+            bytes,
+            Message::new("Hello")
+                .with_metadata("a", "1")
+                .with_metadata("b", "2")
+                .to_bytes()
+                .unwrap()
+        );
+        assert_eq!(Message::from_bytes(&bytes).unwrap(), message);
+        assert_eq!(
+            message.digest().unwrap(),
+            <[u8; 32]>::from(Sha256::digest(&bytes))
+        );
+
+        // Metadata out of order is not canonical.
+        let mut unsorted = bytes.clone();
+        let a = unsorted.iter().position(|&byte| byte == b'a').unwrap();
+        let b = unsorted.iter().position(|&byte| byte == b'b').unwrap();
+        unsorted.swap(a, b);
+        assert!(matches!(
+            Message::from_bytes(&unsorted),
+            Err(MessageError::Malformed)
+        ));
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(Message::from_bytes(&trailing).is_err());
+        assert!(Message::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(matches!(
+            Message::from_bytes(&[2]),
+            Err(MessageError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn test_sealed_messages_and_attachments() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let eve = E2ee::new(KeySize::Bit1024).unwrap();
+
+        let mut message = Message::new("Two files");
+        let first = message
+            .attach(
+                bob.get_public_key(),
+                "a.txt",
+                "text/plain",
+                &b"aaa"[..],
+                Vec::new(),
+            )
+            .unwrap();
+        let second = message
+            .attach(
+                bob.get_public_key(),
+                "b.txt",
+                "text/plain",
+                &b"bbb"[..],
+                Vec::new(),
+            )
+            .unwrap();
+        let sealed = message.seal(&alice, bob.get_public_key()).unwrap();
+
+        let opened = Message::open(&bob, alice.get_public_key(), &sealed).unwrap();
+        assert_eq!(opened, message);
+        let mut decrypted = Vec::new();
+        let size = decrypt_attachment(
+            &bob,
+            &opened.attachments()[0],
+            &first[..],
+            &mut decrypted,
+        )
+        .unwrap();
+        assert_eq!((size, &decrypted[..]), (3, &b"aaa"[..]));
+        // A stream swapped for another one encrypted for the recipient is detected.
+        assert!(matches!(
+            decrypt_attachment(
+                &bob,
+                &opened.attachments()[0],
+                &second[..],
+                io::sink()
+            ),
+            Err(MessageError::AttachmentMismatch)
+        ));
+
+        assert!(matches!(
+            Message::open(&bob, eve.get_public_key(), &sealed),
+            Err(MessageError::InvalidSignature)
+        ));
+        // The signature is bound to the recipient.
+        let plaintext = bob.decrypt_envelope(&sealed).unwrap();
+        let forwarded = envelope::seal(eve.get_public_key(), &plaintext).unwrap();
+        assert!(matches!(
+            Message::open(&eve, alice.get_public_key(), &forwarded),
+            Err(MessageError::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,171 @@
+//! Operation metrics hooks.
+//!
+//! Implement [`MetricsSink`] to feed encryption and decryption counters and durations into
+//! Prometheus, StatsD or any other metrics backend, then attach it with `E2ee::with_metrics` or
+//! `PublicE2ee::with_metrics`. By default every instance uses [`NoopMetrics`].
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The operations reported to a [`MetricsSink`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    /// Encryption of a message with a public key.
+    Encrypt,
+    /// Decryption of a ciphertext with a private key.
+    Decrypt,
+    /// Signature of a message with a private key.
+    Sign,
+    /// Verification of a signature with a public key.
+    Verify,
+}
+
+impl Operation {
+    /// Returns a short, lowercase name suitable for metric names or labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Encrypt => "encrypt",
+            Operation::Decrypt => "decrypt",
+            Operation::Sign => "sign",
+            Operation::Verify => "verify",
+        }
+    }
+}
+
+/// A receiver for operation counters and duration histograms.
+///
+/// Implementations must be cheap and must not block, as they are called inline on every
+/// encryption and decryption.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::metrics::{MetricsSink, Operation};
+/// use std::sync::atomic::{AtomicU64, Ordering};
+/// use std::time::Duration;
+///
+/// #[derive(Default)]
+/// struct FailureCounter(AtomicU64);
+///
+/// impl MetricsSink for FailureCounter {
+///     fn increment(&self, _operation: Operation, success: bool) {
+///         if !success {
+///             self.0.fetch_add(1, Ordering::Relaxed);
+///         }
+///     }
+///
+///     fn observe_duration(&self, _operation: Operation, _duration: Duration) {}
+/// }
+/// ```
+pub trait MetricsSink: Send + Sync {
+    /// Increments the success or failure counter of `operation`.
+    fn increment(&self, operation: Operation, success: bool);
+
+    /// Records how long `operation` took in a duration histogram.
+    fn observe_duration(&self, operation: Operation, duration: Duration);
+
+    /// Counts a lookup in the decryption cache of an `E2ee` instance (see the `cache` feature), to
+    /// track its hit rate. Does nothing by default.
+    fn cache_lookup(&self, _hit: bool) {}
+}
+
+/// A [`MetricsSink`] that discards every measurement.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl MetricsSink for NoopMetrics {
+    fn increment(&self, _operation: Operation, _success: bool) {}
+
+    fn observe_duration(&self, _operation: Operation, _duration: Duration) {}
+}
+
+/// A shared handle to the sink attached to an `E2ee` or `PublicE2ee` instance.
+#[derive(Clone)]
+pub(crate) struct SharedMetrics(Arc<dyn MetricsSink>);
+
+impl SharedMetrics {
+    pub(crate) fn new(sink: Arc<dyn MetricsSink>) -> Self {
+        Self(sink)
+    }
+
+    /// Runs `f`, then reports its duration and outcome as `operation`.
+    pub(crate) fn measure<T, E>(
+        &self,
+        operation: Operation,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let result = f();
+        self.0.observe_duration(operation, start.elapsed());
+        self.0.increment(operation, result.is_ok());
+        result
+    }
+
+    /// Reports a lookup in a decryption cache.
+    #[cfg(feature = "cache")]
+    pub(crate) fn cache_lookup(&self, hit: bool) {
+        self.0.cache_lookup(hit);
+    }
+}
+
+impl Default for SharedMetrics {
+    fn default() -> Self {
+        Self(Arc::new(NoopMetrics))
+    }
+}
+
+impl fmt::Debug for SharedMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsSink")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{E2ee, KeySize};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct RecordingMetrics {
+        counters: Mutex<Vec<(Operation, bool)>>,
+        durations: Mutex<Vec<Operation>>,
+    }
+
+    impl MetricsSink for RecordingMetrics {
+        fn increment(&self, operation: Operation, success: bool) {
+            self.counters.lock().unwrap().push((operation, success));
+        }
+
+        fn observe_duration(&self, operation: Operation, _duration: Duration) {
+            self.durations.lock().unwrap().push(operation);
+        }
+    }
+
+    #[test]
+    fn test_metrics_record_success_and_failure() {
+        let metrics = Arc::new(RecordingMetrics::default());
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_metrics(metrics.clone());
+
+        let encrypted = e2ee.encrypt("Hello metrics!").unwrap();
+        e2ee.decrypt(&encrypted).unwrap();
+        assert!(e2ee.decrypt("invalid_base64_string").is_err());
+
+        assert_eq!(
+            *metrics.counters.lock().unwrap(),
+            vec![
+                (Operation::Encrypt, true),
+                (Operation::Decrypt, true),
+                (Operation::Decrypt, false),
+            ]
+        );
+        assert_eq!(
+            *metrics.durations.lock().unwrap(),
+            vec![Operation::Encrypt, Operation::Decrypt, Operation::Decrypt]
+        );
+    }
+}
@@ -0,0 +1,374 @@
+//! Per-message encryption for message queues.
+//!
+//! This module is enabled by the `mq` feature. It turns values into encrypted message payloads for
+//! Kafka (`rdkafka`), AMQP (`lapin`) or any other broker that carries opaque bytes:
+//!
+//! - [`EncryptingSerializer`] serializes a value with a [`Format`] and encrypts it into an envelope
+//!   (see the `envelope` module) for the consumers' public key;
+//! - [`DecryptingDeserializer`] decrypts and deserializes payloads with one of several private
+//!   keys.
+//!
+//! Each payload starts with the 16-digit hex ID of the key it was encrypted for (see
+//! `core::key_id`), followed by the envelope. To rotate keys, add the new key to the consumers'
+//! `DecryptingDeserializer` first, then switch producers to it with
+//! [`EncryptingSerializer::rotate`]; messages still in flight remain readable as long as the
+//! consumers keep the old key.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::mq::{DecryptingDeserializer, EncryptingSerializer, Json};
+//! use e2ee::server::{E2ee, KeySize};
+//! use std::collections::HashMap;
+//!
+//! let consumer_key = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let serializer = EncryptingSerializer::new(consumer_key.get_public_key().clone(), Json)
+//!     .expect("Failed to create serializer");
+//! let mut deserializer = DecryptingDeserializer::new(Json);
+//! deserializer.add_key(consumer_key).expect("Failed to add key");
+//!
+//! let event = HashMap::from([("order", 42)]);
+//! let payload = serializer.serialize(&event).expect("Failed to encrypt event");
+//! let decrypted: HashMap<String, u32> =
+//!     deserializer.deserialize(&payload).expect("Failed to decrypt event");
+//! assert_eq!(decrypted["order"], 42);
+//! ```
+use crate::core::{self, KEY_ID_LEN};
+use crate::envelope::{self, EnvelopeError};
+use crate::server::{E2ee, E2eeError};
+use rsa::RsaPublicKey;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, sync::RwLock};
+use thiserror::Error;
+
+/// A boxed error returned by a [`Format`].
+pub type FormatError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An error returned when encrypting or decrypting a message payload.
+#[derive(Error, Debug)]
+pub enum MqError {
+    #[error("SPKI error: {0}")]
+    Spki(#[from] rsa::pkcs8::spki::Error),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Decryption error: {0}")]
+    Decryption(#[from] E2eeError),
+
+    #[error("Serialization error: {0}")]
+    Format(FormatError),
+
+    #[error("Malformed message payload")]
+    Malformed,
+
+    #[error("No key with ID {0} to decrypt the message")]
+    UnknownKey(String),
+}
+
+/// A serde data format, such as JSON, used to encode values before encryption.
+///
+/// Implement this trait to use another format, for example with `bincode` or `rmp-serde`.
+pub trait Format {
+    /// Serializes `value` into bytes.
+    ///
+    /// # Errors
+    ///
+    /// Implementations return an error if `value` cannot be serialized.
+    fn serialize<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+    ) -> Result<Vec<u8>, FormatError>;
+
+    /// Deserializes a value from `bytes`.
+    ///
+    /// # Errors
+    ///
+    /// Implementations return an error if `bytes` is not a valid encoding of `T`.
+    fn deserialize<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T, FormatError>;
+}
+
+/// The JSON format, using `serde_json`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Format for Json {
+    fn serialize<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+    ) -> Result<Vec<u8>, FormatError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn deserialize<T: DeserializeOwned>(
+        &self,
+        bytes: &[u8],
+    ) -> Result<T, FormatError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[derive(Debug)]
+struct RecipientKey {
+    public_key: RsaPublicKey,
+    key_id: String,
+}
+
+impl RecipientKey {
+    fn new(public_key: RsaPublicKey) -> Result<Self, MqError> {
+        let key_id = core::key_id(&public_key)?;
+        Ok(Self { public_key, key_id })
+    }
+}
+
+/// Serializes values and encrypts them for the consumers' public key.
+///
+/// The serializer can be shared between producer threads; [`EncryptingSerializer::rotate`] switches
+/// the key of every producer at once.
+#[derive(Debug)]
+pub struct EncryptingSerializer<F> {
+    key: RwLock<RecipientKey>,
+    format: F,
+}
+
+impl<F: Format> EncryptingSerializer<F> {
+    /// Creates a serializer encrypting values encoded with `format` for `public_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the ID of the key cannot be computed.
+    pub fn new(public_key: RsaPublicKey, format: F) -> Result<Self, MqError> {
+        Ok(Self {
+            key: RwLock::new(RecipientKey::new(public_key)?),
+            format,
+        })
+    }
+
+    /// Encrypts the following messages for `public_key` instead.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the ID of the key cannot be computed.
+    pub fn rotate(&self, public_key: RsaPublicKey) -> Result<(), MqError> {
+        let key = RecipientKey::new(public_key)?;
+        *self.key.write().unwrap_or_else(|err| err.into_inner()) = key;
+        Ok(())
+    }
+
+    /// Returns the ID of the key messages are currently encrypted for.
+    pub fn key_id(&self) -> String {
+        self.key
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .key_id
+            .clone()
+    }
+
+    /// Serializes and encrypts `value` into a message payload.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `value` cannot be serialized or encrypted.
+    pub fn serialize<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+    ) -> Result<Vec<u8>, MqError> {
+        self.serialize_with_label(value, None)
+    }
+
+    /// Serializes and encrypts `value` like [`serialize`](Self::serialize), binding the envelope to
+    /// `label` if set (see `envelope::seal_with_label`).
+    pub(crate) fn serialize_with_label<T: Serialize + ?Sized>(
+        &self,
+        value: &T,
+        label: Option<&str>,
+    ) -> Result<Vec<u8>, MqError> {
+        let plaintext = self.format.serialize(value).map_err(MqError::Format)?;
+        let key = self.key.read().unwrap_or_else(|err| err.into_inner());
+        let envelope = match label {
+            Some(label) => {
+                envelope::seal_with_label(&key.public_key, label, &plaintext)?
+            }
+            None => envelope::seal(&key.public_key, &plaintext)?,
+        };
+        let mut payload = Vec::with_capacity(KEY_ID_LEN + envelope.len());
+        payload.extend_from_slice(key.key_id.as_bytes());
+        payload.extend_from_slice(&envelope);
+        Ok(payload)
+    }
+}
+
+/// Decrypts and deserializes message payloads with one of several private keys.
+#[derive(Debug)]
+pub struct DecryptingDeserializer<F> {
+    keys: HashMap<String, E2ee>,
+    format: F,
+}
+
+impl<F: Format> DecryptingDeserializer<F> {
+    /// Creates a deserializer decoding values with `format`, without any key.
+    pub fn new(format: F) -> Self {
+        Self {
+            keys: HashMap::new(),
+            format,
+        }
+    }
+
+    /// Adds a key to decrypt messages with, and returns its ID.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the ID of the key cannot be computed.
+    pub fn add_key(&mut self, e2ee: E2ee) -> Result<String, MqError> {
+        let key_id = core::key_id(e2ee.get_public_key())?;
+        self.keys.insert(key_id.clone(), e2ee);
+        Ok(key_id)
+    }
+
+    /// Removes the key with ID `key_id`, once no message encrypted for it remains.
+    pub fn remove_key(&mut self, key_id: &str) -> Option<E2ee> {
+        self.keys.remove(key_id)
+    }
+
+    /// Returns the ID of the key a message payload was encrypted for.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `MqError::Malformed` if the payload does not start with a key ID.
+    pub fn key_id_of<'a>(&self, payload: &'a [u8]) -> Result<&'a str, MqError> {
+        split(payload).map(|(key_id, _)| key_id)
+    }
+
+    /// Decrypts and deserializes a message payload.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the payload is malformed, if its key is unknown, or if it
+    /// cannot be decrypted or deserialized.
+    pub fn deserialize<T: DeserializeOwned>(
+        &self,
+        payload: &[u8],
+    ) -> Result<T, MqError> {
+        self.deserialize_with_label(payload, None)
+    }
+
+    /// Decrypts and deserializes a payload like [`deserialize`](Self::deserialize), whose envelope
+    /// is bound to `label` if set.
+    pub(crate) fn deserialize_with_label<T: DeserializeOwned>(
+        &self,
+        payload: &[u8],
+        label: Option<&str>,
+    ) -> Result<T, MqError> {
+        let (key_id, envelope) = split(payload)?;
+        let e2ee = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| MqError::UnknownKey(key_id.to_string()))?;
+        let plaintext = match label {
+            Some(label) => {
+                envelope::open_with_label(e2ee.get_private_key(), label, envelope)?
+            }
+            None => e2ee.decrypt_envelope(envelope)?,
+        };
+        self.format.deserialize(&plaintext).map_err(MqError::Format)
+    }
+}
+
+fn split(payload: &[u8]) -> Result<(&str, &[u8]), MqError> {
+    if payload.len() < KEY_ID_LEN {
+        return Err(MqError::Malformed);
+    }
+    let (key_id, envelope) = payload.split_at(KEY_ID_LEN);
+    let key_id = std::str::from_utf8(key_id).map_err(|_| MqError::Malformed)?;
+    if !key_id.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(MqError::Malformed);
+    }
+    Ok((key_id, envelope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+    use serde_json::{json, Value};
+
+    /// A format wrapping JSON in a marker, standing in for binary formats.
+    struct Marked;
+
+    impl Format for Marked {
+        fn serialize<T: Serialize + ?Sized>(
+            &self,
+            value: &T,
+        ) -> Result<Vec<u8>, FormatError> {
+            let mut bytes = b"marked:".to_vec();
+            bytes.extend(serde_json::to_vec(value)?);
+            Ok(bytes)
+        }
+
+        fn deserialize<T: DeserializeOwned>(
+            &self,
+            bytes: &[u8],
+        ) -> Result<T, FormatError> {
+            let json = bytes.strip_prefix(b"marked:").ok_or("missing marker")?;
+            Ok(serde_json::from_slice(json)?)
+        }
+    }
+
+    #[test]
+    fn test_rotation_keeps_old_messages_readable() {
+        let old = E2ee::new(KeySize::Bit1024).unwrap();
+        let new = E2ee::new(KeySize::Bit1024).unwrap();
+        let serializer =
+            EncryptingSerializer::new(old.get_public_key().clone(), Marked).unwrap();
+        let before = serializer.serialize(&json!({"seq": 1})).unwrap();
+
+        let mut deserializer = DecryptingDeserializer::new(Marked);
+        let old_id = deserializer.add_key(old).unwrap();
+        let new_public_key = new.get_public_key().clone();
+        let new_id = deserializer.add_key(new).unwrap();
+        serializer.rotate(new_public_key).unwrap();
+        assert_eq!(serializer.key_id(), new_id);
+        let after = serializer.serialize(&json!({"seq": 2})).unwrap();
+
+        assert_eq!(deserializer.key_id_of(&before).unwrap(), old_id);
+        assert_eq!(deserializer.key_id_of(&after).unwrap(), new_id);
+        let decrypted: Value = deserializer.deserialize(&before).unwrap();
+        assert_eq!(decrypted, json!({"seq": 1}));
+        let decrypted: Value = deserializer.deserialize(&after).unwrap();
+        assert_eq!(decrypted, json!({"seq": 2}));
+
+        deserializer.remove_key(&old_id);
+        assert!(matches!(
+            deserializer.deserialize::<Value>(&before),
+            Err(MqError::UnknownKey(id)) if id == old_id
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_rejects_malformed_payloads() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let serializer =
+            EncryptingSerializer::new(e2ee.get_public_key().clone(), Json).unwrap();
+        let mut deserializer = DecryptingDeserializer::new(Json);
+        deserializer.add_key(e2ee).unwrap();
+
+        assert!(matches!(
+            deserializer.deserialize::<Value>(b"short"),
+            Err(MqError::Malformed)
+        ));
+        let mut payload = serializer.serialize("message").unwrap();
+        *payload.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            deserializer.deserialize::<Value>(&payload),
+            Err(MqError::Decryption(E2eeError::DecryptionFailed))
+        ));
+        let payload = serializer.serialize("message").unwrap();
+        assert!(matches!(
+            deserializer.deserialize::<u32>(&payload),
+            Err(MqError::Format(_))
+        ));
+    }
+}
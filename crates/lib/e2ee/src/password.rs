@@ -0,0 +1,459 @@
+//! Password-based key derivation with Argon2id.
+//!
+//! This module is enabled by the `password` feature. [`PasswordKdf`] turns a password or a
+//! passphrase into a 32-byte key with Argon2id (RFC 9106). Its cost parameters are either the
+//! defaults recommended by OWASP (19 MiB of memory, 2 iterations, 1 lane) or calibrated on the
+//! current machine with [`PasswordKdf::tune_for`].
+//!
+//! Every derivation draws a random salt and returns it with the parameters in a
+//! [`PasswordHeader`], to be stored next to what the key protects. The key is derived again from
+//! the header alone, so data protected under older parameters stays readable after they are
+//! raised, and [`PasswordKdf::needs_upgrade`] tells when to re-protect it. The binary layout of a
+//! header is:
+//!
+//! ```text
+//! version (1 byte) | memory in KiB (4 bytes, big endian) | iterations (4 bytes, big endian) |
+//! parallelism (4 bytes, big endian) | salt (16 bytes)
+//! ```
+//!
+//! Its text form is a PHC string, e.g. `$argon2id$v=19$m=19456,t=2,p=1$<salt in base64>`.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::password::{PasswordHeader, PasswordKdf};
+//! use std::time::Duration;
+//!
+//! let kdf = PasswordKdf::default()
+//!     .tune_for(Duration::from_millis(100))
+//!     .expect("Failed to calibrate");
+//! let derived = kdf.derive(b"correct horse battery staple").expect("Failed to derive key");
+//! let stored = derived.header.to_bytes();
+//!
+//! // Later, possibly with other parameters.
+//! let header = PasswordHeader::from_bytes(&stored).expect("Invalid header");
+//! let key = header
+//!     .derive_key(b"correct horse battery staple")
+//!     .expect("Failed to derive key");
+//! assert_eq!(*key, *derived.key);
+//! assert!(!kdf.needs_upgrade(&header));
+//! ```
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine};
+use rsa::rand_core::{OsRng, RngCore};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the header layout.
+pub const HEADER_VERSION: u8 = 1;
+
+/// The length of a salt, in bytes.
+pub const SALT_LEN: usize = 16;
+
+/// The length of an encoded [`PasswordHeader`], in bytes.
+pub const HEADER_LEN: usize = 1 + 3 * 4 + SALT_LEN;
+
+/// The largest memory cost accepted from a header, 4 GiB, so that a forged header cannot exhaust
+/// the memory of the host.
+pub const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+
+/// The number of iterations never lowered by calibration.
+const MIN_TUNED_ITERATIONS: u32 = Params::DEFAULT_T_COST;
+
+pub type PasswordResult<T> = std::result::Result<T, PasswordError>;
+
+/// An error returned when deriving keys from passwords.
+#[derive(Error, Debug)]
+pub enum PasswordError {
+    #[error("Argon2 error: {0}")]
+    Argon2(#[from] argon2::Error),
+
+    #[error("Invalid parameters: {0}")]
+    InvalidParams(String),
+
+    #[error("Invalid header: {0}")]
+    InvalidHeader(String),
+
+    #[error("Unsupported header version {0}")]
+    UnsupportedVersion(u8),
+}
+
+/// The cost parameters of Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PasswordParams {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Default for PasswordParams {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordParams {
+    /// Creates parameters using `memory_kib` KiB of memory, `iterations` passes over it and
+    /// `parallelism` lanes.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PasswordError::InvalidParams` if Argon2 rejects the parameters, e.g.
+    /// less than 8 KiB of memory per lane, or if the memory exceeds [`MAX_MEMORY_KIB`].
+    pub fn new(
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> PasswordResult<Self> {
+        let params = Self {
+            memory_kib,
+            iterations,
+            parallelism,
+        };
+        params.argon2_params()?;
+        Ok(params)
+    }
+
+    /// Returns the memory cost, in KiB.
+    pub fn memory_kib(&self) -> u32 {
+        self.memory_kib
+    }
+
+    /// Returns the number of iterations.
+    pub fn iterations(&self) -> u32 {
+        self.iterations
+    }
+
+    /// Returns the number of lanes.
+    pub fn parallelism(&self) -> u32 {
+        self.parallelism
+    }
+
+    /// Returns whether any cost of these parameters is lower than that of `other`.
+    pub fn is_weaker_than(&self, other: &Self) -> bool {
+        self.memory_kib < other.memory_kib
+            || self.iterations < other.iterations
+            || self.parallelism < other.parallelism
+    }
+
+    fn argon2_params(&self) -> PasswordResult<Params> {
+        if self.memory_kib > MAX_MEMORY_KIB {
+            return Err(PasswordError::InvalidParams(format!(
+                "{} KiB of memory exceeds the maximum of {MAX_MEMORY_KIB} KiB",
+                self.memory_kib
+            )));
+        }
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|err| PasswordError::InvalidParams(err.to_string()))
+    }
+
+    fn hash(
+        &self,
+        password: &[u8],
+        salt: &[u8; SALT_LEN],
+    ) -> PasswordResult<Zeroizing<[u8; 32]>> {
+        let argon2 =
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, self.argon2_params()?);
+        let mut key = Zeroizing::new([0; 32]);
+        argon2.hash_password_into(password, salt, key.as_mut())?;
+        Ok(key)
+    }
+}
+
+/// The parameters and the salt of a derivation, stored with the data protected by the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordHeader {
+    params: PasswordParams,
+    salt: [u8; SALT_LEN],
+}
+
+impl PasswordHeader {
+    /// Returns the parameters of the derivation.
+    pub fn params(&self) -> &PasswordParams {
+        &self.params
+    }
+
+    /// Returns the salt of the derivation.
+    pub fn salt(&self) -> &[u8; SALT_LEN] {
+        &self.salt
+    }
+
+    /// Derives the key of `password` again with the parameters and the salt of this header.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if Argon2 fails, e.g. when the memory cannot be allocated.
+    pub fn derive_key(
+        &self,
+        password: &[u8],
+    ) -> PasswordResult<Zeroizing<[u8; 32]>> {
+        self.params.hash(password, &self.salt)
+    }
+
+    /// Encodes the header into its binary layout of [`HEADER_LEN`] bytes.
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        bytes[0] = HEADER_VERSION;
+        bytes[1..5].copy_from_slice(&self.params.memory_kib.to_be_bytes());
+        bytes[5..9].copy_from_slice(&self.params.iterations.to_be_bytes());
+        bytes[9..13].copy_from_slice(&self.params.parallelism.to_be_bytes());
+        bytes[13..].copy_from_slice(&self.salt);
+        bytes
+    }
+
+    /// Decodes a header from its binary layout. Data stored after a header starts at
+    /// [`HEADER_LEN`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PasswordError::UnsupportedVersion` if the header has another
+    /// version, or `PasswordError::InvalidHeader` if it is truncated or its parameters are
+    /// invalid.
+    pub fn from_bytes(bytes: &[u8]) -> PasswordResult<Self> {
+        let Some(bytes) = bytes.get(..HEADER_LEN) else {
+            return Err(PasswordError::InvalidHeader(format!(
+                "{} bytes instead of {HEADER_LEN}",
+                bytes.len()
+            )));
+        };
+        if bytes[0] != HEADER_VERSION {
+            return Err(PasswordError::UnsupportedVersion(bytes[0]));
+        }
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+        let params = PasswordParams::new(read_u32(1), read_u32(5), read_u32(9))
+            .map_err(|err| PasswordError::InvalidHeader(err.to_string()))?;
+        Ok(Self {
+            params,
+            salt: bytes[13..].try_into().unwrap(),
+        })
+    }
+}
+
+impl fmt::Display for PasswordHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "$argon2id$v=19$m={},t={},p={}${}",
+            self.params.memory_kib,
+            self.params.iterations,
+            self.params.parallelism,
+            general_purpose::STANDARD_NO_PAD.encode(self.salt)
+        )
+    }
+}
+
+impl FromStr for PasswordHeader {
+    type Err = PasswordError;
+
+    /// Parses a header from its PHC string, as written by `Display`.
+    fn from_str(s: &str) -> PasswordResult<Self> {
+        let invalid = || PasswordError::InvalidHeader(s.to_string());
+        let rest = s.strip_prefix("$argon2id$v=19$").ok_or_else(invalid)?;
+        let (costs, salt) = rest.split_once('$').ok_or_else(invalid)?;
+        let mut values = [None; 3];
+        for cost in costs.split(',') {
+            let (name, value) = cost.split_once('=').ok_or_else(invalid)?;
+            let index = ["m", "t", "p"]
+                .iter()
+                .position(|expected| *expected == name)
+                .ok_or_else(invalid)?;
+            values[index] = Some(value.parse().map_err(|_| invalid())?);
+        }
+        let [Some(memory_kib), Some(iterations), Some(parallelism)] = values else {
+            return Err(invalid());
+        };
+        let salt = general_purpose::STANDARD_NO_PAD
+            .decode(salt)
+            .ok()
+            .and_then(|salt| salt.try_into().ok())
+            .ok_or_else(invalid)?;
+        Ok(Self {
+            params: PasswordParams::new(memory_kib, iterations, parallelism)
+                .map_err(|err| PasswordError::InvalidHeader(err.to_string()))?,
+            salt,
+        })
+    }
+}
+
+/// A key derived from a password, with the header needed to derive it again.
+pub struct DerivedKey {
+    pub header: PasswordHeader,
+    pub key: Zeroizing<[u8; 32]>,
+}
+
+impl fmt::Debug for DerivedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DerivedKey")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Derives keys from passwords with Argon2id and the parameters it holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PasswordKdf {
+    params: PasswordParams,
+}
+
+impl PasswordKdf {
+    /// Creates a KDF deriving keys with `params`.
+    pub fn new(params: PasswordParams) -> Self {
+        Self { params }
+    }
+
+    /// Returns the parameters of new derivations.
+    pub fn params(&self) -> &PasswordParams {
+        &self.params
+    }
+
+    /// Calibrates the number of iterations so that a derivation takes about `target` on this
+    /// machine, keeping the memory cost and the parallelism. The number of iterations is never
+    /// lowered below the current one, nor below the default of 2.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if Argon2 fails while measuring.
+    pub fn tune_for(self, target: Duration) -> PasswordResult<Self> {
+        // A first derivation warms up the allocator, which would otherwise skew the measure.
+        let probe = PasswordParams {
+            iterations: 1,
+            ..self.params
+        };
+        probe.hash(b"", &[0; SALT_LEN])?;
+        let start = Instant::now();
+        probe.hash(b"", &[0; SALT_LEN])?;
+        let per_iteration = start.elapsed().max(Duration::from_micros(1));
+        let iterations = (target.as_secs_f64() / per_iteration.as_secs_f64()).ceil();
+        let iterations = (iterations.min(u32::MAX as f64) as u32)
+            .max(self.params.iterations)
+            .max(MIN_TUNED_ITERATIONS);
+        Ok(Self {
+            params: PasswordParams {
+                iterations,
+                ..self.params
+            },
+        })
+    }
+
+    /// Derives a key from `password` with a new random salt.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if Argon2 fails, e.g. when the memory cannot be allocated.
+    pub fn derive(&self, password: &[u8]) -> PasswordResult<DerivedKey> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let header = PasswordHeader {
+            params: self.params,
+            salt,
+        };
+        Ok(DerivedKey {
+            key: header.derive_key(password)?,
+            header,
+        })
+    }
+
+    /// Returns whether `header` was derived with parameters weaker than those of this KDF, in
+    /// which case the data it protects should be protected again with a new derivation.
+    pub fn needs_upgrade(&self, header: &PasswordHeader) -> bool {
+        header.params.is_weaker_than(&self.params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_kdf() -> PasswordKdf {
+        PasswordKdf::new(PasswordParams::new(64, 1, 1).unwrap())
+    }
+
+    #[test]
+    fn test_keys_are_derived_again_from_the_header() {
+        let kdf = fast_kdf();
+        let derived = kdf.derive(b"password").unwrap();
+        assert_eq!(
+            *derived.header.derive_key(b"password").unwrap(),
+            *derived.key
+        );
+        assert_ne!(
+            *derived.header.derive_key(b"Password").unwrap(),
+            *derived.key
+        );
+        let other = kdf.derive(b"password").unwrap();
+        assert_ne!(other.header.salt(), derived.header.salt());
+        assert_ne!(*other.key, *derived.key);
+        assert!(!format!("{derived:?}").contains("key:"));
+    }
+
+    #[test]
+    fn test_header_encodings() {
+        let header = fast_kdf().derive(b"password").unwrap().header;
+        let bytes = header.to_bytes();
+        assert_eq!(PasswordHeader::from_bytes(&bytes).unwrap(), header);
+        let mut with_data = bytes.to_vec();
+        with_data.extend_from_slice(b"ciphertext");
+        assert_eq!(PasswordHeader::from_bytes(&with_data).unwrap(), header);
+        assert!(matches!(
+            PasswordHeader::from_bytes(&bytes[..HEADER_LEN - 1]),
+            Err(PasswordError::InvalidHeader(_))
+        ));
+        let mut tampered = bytes;
+        tampered[0] = 2;
+        assert!(matches!(
+            PasswordHeader::from_bytes(&tampered),
+            Err(PasswordError::UnsupportedVersion(2))
+        ));
+        let mut forged = bytes;
+        forged[1..5].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            PasswordHeader::from_bytes(&forged),
+            Err(PasswordError::InvalidHeader(_))
+        ));
+
+        let phc = header.to_string();
+        assert!(phc.starts_with("$argon2id$v=19$m=64,t=1,p=1$"));
+        assert_eq!(phc.parse::<PasswordHeader>().unwrap(), header);
+        for invalid in [
+            "$argon2i$v=19$m=64,t=1,p=1$AAAAAAAAAAAAAAAAAAAAAA",
+            "$argon2id$v=19$m=64,t=1$AAAAAAAAAAAAAAAAAAAAAA",
+            "$argon2id$v=19$m=64,t=1,p=1$AAAA",
+        ] {
+            assert!(invalid.parse::<PasswordHeader>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_tuning_and_upgrades() {
+        let kdf = fast_kdf();
+        let tuned = kdf.tune_for(Duration::from_millis(20)).unwrap();
+        assert_eq!(tuned.params().memory_kib(), 64);
+        assert!(tuned.params().iterations() >= MIN_TUNED_ITERATIONS);
+        assert!(!tuned
+            .tune_for(Duration::ZERO)
+            .unwrap()
+            .params()
+            .is_weaker_than(tuned.params()));
+
+        let old = kdf.derive(b"password").unwrap().header;
+        assert!(tuned.needs_upgrade(&old));
+        assert!(!kdf.needs_upgrade(&old));
+        let new = tuned.derive(b"password").unwrap().header;
+        assert!(!tuned.needs_upgrade(&new));
+        assert!(matches!(
+            PasswordParams::new(4, 1, 1),
+            Err(PasswordError::InvalidParams(_))
+        ));
+    }
+}
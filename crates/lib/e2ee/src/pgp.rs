@@ -0,0 +1,592 @@
+//! OpenPGP message output.
+//!
+//! This module is enabled by the `pgp` feature. It imports OpenPGP RSA public keys (for example
+//! exported with `gpg --armor --export`) and encrypts data into OpenPGP messages
+//! ([RFC 4880](https://www.rfc-editor.org/rfc/rfc4880)) that recipients can decrypt with GnuPG or
+//! any other OpenPGP implementation, giving an escape hatch into an established ecosystem.
+//!
+//! Messages are hybrid-encrypted: a random AES-256 session key is encrypted to the recipient's
+//! RSA key (public-key encrypted session key packet), and the data is wrapped in a literal data
+//! packet inside a symmetrically encrypted and integrity protected data packet (AES-256 in CFB
+//! mode with a SHA-1 modification detection code), the format every OpenPGP implementation
+//! understands.
+//!
+//! Only what is needed for encryption is implemented: v4 RSA keys, no signatures, compression or
+//! decryption.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::pgp::{self, PgpPublicKey};
+//!
+//! let armored_key = include_str!("../files/pgp/recipient.asc");
+//! let recipient = PgpPublicKey::from_armored(armored_key).expect("Failed to import PGP key");
+//! let message = pgp::encrypt_message_armored(&recipient, b"Secret message")
+//!     .expect("Failed to encrypt message");
+//! assert!(message.starts_with("-----BEGIN PGP MESSAGE-----"));
+//! // Decrypt with: gpg --decrypt message.asc
+//! ```
+use crate::core;
+use aes::Aes256;
+use base64::{engine::general_purpose, Engine};
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    BigUint, Pkcs1v15Encrypt, RsaPublicKey,
+};
+use sha1::{Digest, Sha1};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub type PgpResult<T> = std::result::Result<T, PgpError>;
+
+/// An error returned when importing an OpenPGP key or encrypting a message.
+#[derive(Error, Debug)]
+pub enum PgpError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("Invalid ASCII armor: {0}")]
+    Armor(String),
+
+    #[error("Invalid OpenPGP packet: {0}")]
+    Packet(String),
+
+    #[error("Unsupported OpenPGP key: {0}")]
+    UnsupportedKey(String),
+
+    #[error("The OpenPGP key has no RSA key usable for encryption")]
+    NoEncryptionKey,
+}
+
+const TAG_PKESK: u8 = 1;
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_LITERAL_DATA: u8 = 11;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+const TAG_SEIPD: u8 = 18;
+const TAG_MDC: u8 = 19;
+
+const ALGO_RSA: u8 = 1;
+const ALGO_RSA_ENCRYPT_ONLY: u8 = 2;
+const ALGO_RSA_SIGN_ONLY: u8 = 3;
+const SYM_ALGO_AES256: u8 = 9;
+
+const SUBPACKET_KEY_FLAGS: u8 = 27;
+const KEY_FLAGS_ENCRYPT: u8 = 0x04 | 0x08;
+
+/// The RSA encryption key of an OpenPGP certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgpPublicKey {
+    public_key: RsaPublicKey,
+    fingerprint: [u8; 20],
+}
+
+impl PgpPublicKey {
+    /// Imports an ASCII-armored OpenPGP public key block, as produced by
+    /// `gpg --armor --export`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the armor or the packets are malformed, or if the key has
+    /// no RSA key usable for encryption.
+    pub fn from_armored(armored: &str) -> PgpResult<Self> {
+        Self::from_bytes(&dearmor(armored, "PGP PUBLIC KEY BLOCK")?)
+    }
+
+    /// Imports a binary OpenPGP public key, as produced by `gpg --export`.
+    ///
+    /// The encryption key is the first RSA subkey whose binding signature allows encryption, or
+    /// the primary key if it does. Keys without key flags are considered usable for encryption
+    /// unless their algorithm is RSA sign-only. The key goes through the same validation as keys
+    /// imported with `PublicE2ee::new`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the packets are malformed, or if the key has no RSA key
+    /// usable for encryption.
+    pub fn from_bytes(bytes: &[u8]) -> PgpResult<Self> {
+        let mut keys: Vec<KeyCandidate> = Vec::new();
+        for packet in Packets(bytes) {
+            let (tag, body) = packet?;
+            match tag {
+                TAG_PUBLIC_KEY | TAG_PUBLIC_SUBKEY => {
+                    if tag == TAG_PUBLIC_KEY && !keys.is_empty() {
+                        // Only the first certificate of a keyring is considered.
+                        break;
+                    }
+                    keys.push(KeyCandidate::parse(tag == TAG_PUBLIC_SUBKEY, body)?);
+                }
+                TAG_SIGNATURE => {
+                    if let (Some(key), Some(flags)) =
+                        (keys.last_mut(), key_flags(body))
+                    {
+                        key.flags = Some(key.flags.unwrap_or(0) | flags);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let usable = |key: &&KeyCandidate| {
+            key.rsa.is_some()
+                && key
+                    .flags
+                    .map_or(key.algorithm != ALGO_RSA_SIGN_ONLY, |flags| {
+                        flags & KEY_FLAGS_ENCRYPT != 0
+                    })
+        };
+        let key = keys
+            .iter()
+            .filter(|key| key.is_subkey)
+            .find(usable)
+            .or_else(|| keys.iter().filter(|key| !key.is_subkey).find(usable))
+            .ok_or(PgpError::NoEncryptionKey)?;
+        let (n, e) = key.rsa.clone().ok_or(PgpError::NoEncryptionKey)?;
+        core::validate_public_key(&n, &e).map_err(PgpError::UnsupportedKey)?;
+        Ok(Self {
+            public_key: RsaPublicKey::new(n, e)?,
+            fingerprint: key.fingerprint,
+        })
+    }
+
+    /// Returns the v4 fingerprint of the encryption key as uppercase hex, as shown by
+    /// `gpg --with-subkey-fingerprints`.
+    pub fn fingerprint(&self) -> String {
+        hex_upper(&self.fingerprint)
+    }
+
+    /// Returns the 64-bit key ID of the encryption key as uppercase hex.
+    pub fn key_id(&self) -> String {
+        hex_upper(&self.fingerprint[12..])
+    }
+
+    /// Returns the RSA public key used for encryption.
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+}
+
+struct KeyCandidate {
+    is_subkey: bool,
+    algorithm: u8,
+    rsa: Option<(BigUint, BigUint)>,
+    fingerprint: [u8; 20],
+    flags: Option<u8>,
+}
+
+impl KeyCandidate {
+    fn parse(is_subkey: bool, body: &[u8]) -> PgpResult<Self> {
+        match body.first() {
+            Some(4) => {}
+            Some(version) => {
+                return Err(PgpError::UnsupportedKey(format!(
+                    "version {version} keys are not supported"
+                )))
+            }
+            None => return Err(PgpError::Packet("empty key packet".into())),
+        }
+        let algorithm = *body
+            .get(5)
+            .ok_or_else(|| PgpError::Packet("truncated key packet".into()))?;
+        let rsa = match algorithm {
+            ALGO_RSA | ALGO_RSA_ENCRYPT_ONLY | ALGO_RSA_SIGN_ONLY => {
+                let mut rest = &body[6..];
+                let n = read_mpi(&mut rest)?;
+                let e = read_mpi(&mut rest)?;
+                Some((n, e))
+            }
+            _ => None,
+        };
+
+        let mut hasher = Sha1::new();
+        hasher.update([0x99]);
+        hasher.update((body.len() as u16).to_be_bytes());
+        hasher.update(body);
+        Ok(Self {
+            is_subkey,
+            algorithm,
+            rsa,
+            fingerprint: hasher.finalize().into(),
+            flags: None,
+        })
+    }
+}
+
+/// Returns the key flags of a v4 signature packet, if it has any in its hashed area.
+fn key_flags(body: &[u8]) -> Option<u8> {
+    if body.first() != Some(&4) {
+        return None;
+    }
+    let hashed_len = u16::from_be_bytes([*body.get(4)?, *body.get(5)?]) as usize;
+    let mut subpackets = body.get(6..6 + hashed_len)?;
+    while !subpackets.is_empty() {
+        let (len, header) = match subpackets[0] {
+            first @ 0..=191 => (first as usize, 1),
+            first @ 192..=254 => (
+                ((first as usize - 192) << 8) + *subpackets.get(1)? as usize + 192,
+                2,
+            ),
+            255 => (
+                u32::from_be_bytes(subpackets.get(1..5)?.try_into().ok()?) as usize,
+                5,
+            ),
+        };
+        let subpacket = subpackets.get(header..header + len)?;
+        if subpacket.first().map(|kind| kind & 0x7f) == Some(SUBPACKET_KEY_FLAGS) {
+            return subpacket.get(1).copied();
+        }
+        subpackets = &subpackets[header + len..];
+    }
+    None
+}
+
+/// Encrypts `plaintext` to `recipient` and returns a binary OpenPGP message.
+///
+/// # Errors
+///
+/// This function returns an error if the session key cannot be encrypted to the recipient's key.
+pub fn encrypt_message(
+    recipient: &PgpPublicKey,
+    plaintext: &[u8],
+) -> PgpResult<Vec<u8>> {
+    let mut rng = OsRng;
+    let mut session_key = [0u8; 32];
+    rng.fill_bytes(&mut session_key);
+
+    // Public-key encrypted session key packet (version 3).
+    let checksum = session_key
+        .iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+    let mut key_material = vec![SYM_ALGO_AES256];
+    key_material.extend_from_slice(&session_key);
+    key_material.extend_from_slice(&checksum.to_be_bytes());
+    let encrypted_key =
+        recipient
+            .public_key
+            .encrypt(&mut rng, Pkcs1v15Encrypt, &key_material)?;
+    let mut pkesk = vec![3];
+    pkesk.extend_from_slice(&recipient.fingerprint[12..]);
+    pkesk.push(ALGO_RSA);
+    write_mpi(&mut pkesk, &encrypted_key);
+
+    // Literal data packet, binary, without file name.
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as u32)
+        .unwrap_or_default();
+    let mut literal = vec![b'b', 0];
+    literal.extend_from_slice(&created.to_be_bytes());
+    literal.extend_from_slice(plaintext);
+
+    // Symmetrically encrypted integrity protected data packet (version 1): a random block
+    // prefix with its last two bytes repeated, the data and the modification detection code,
+    // encrypted with AES-256 in CFB mode with a zero IV.
+    let mut prefix = [0u8; 18];
+    rng.fill_bytes(&mut prefix[..16]);
+    prefix.copy_within(14..16, 16);
+    let mut data = prefix.to_vec();
+    write_packet(&mut data, TAG_LITERAL_DATA, &literal);
+    data.extend_from_slice(&[0xc0 | TAG_MDC, 20]);
+    let mdc = Sha1::digest(&data);
+    data.extend_from_slice(&mdc);
+    cfb_mode::Encryptor::<Aes256>::new(&session_key.into(), &[0u8; 16].into())
+        .encrypt(&mut data);
+    let mut seipd = vec![1];
+    seipd.extend_from_slice(&data);
+
+    let mut message = Vec::new();
+    write_packet(&mut message, TAG_PKESK, &pkesk);
+    write_packet(&mut message, TAG_SEIPD, &seipd);
+    Ok(message)
+}
+
+/// Encrypts `plaintext` to `recipient` and returns an ASCII-armored OpenPGP message.
+///
+/// # Errors
+///
+/// This function returns an error if the session key cannot be encrypted to the recipient's key.
+pub fn encrypt_message_armored(
+    recipient: &PgpPublicKey,
+    plaintext: &[u8],
+) -> PgpResult<String> {
+    Ok(armor(
+        "PGP MESSAGE",
+        &encrypt_message(recipient, plaintext)?,
+    ))
+}
+
+/// An iterator over the `(tag, body)` pairs of a sequence of OpenPGP packets.
+struct Packets<'a>(&'a [u8]);
+
+impl<'a> Iterator for Packets<'a> {
+    type Item = PgpResult<(u8, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&first, rest) = self.0.split_first()?;
+        let result =
+            parse_packet_header(first, rest).and_then(|(tag, header, len)| {
+                let body = rest
+                    .get(header..header + len)
+                    .ok_or_else(|| PgpError::Packet("truncated packet".into()))?;
+                self.0 = &rest[header + len..];
+                Ok((tag, body))
+            });
+        if result.is_err() {
+            self.0 = &[];
+        }
+        Some(result)
+    }
+}
+
+/// Parses a packet header and returns the tag, the remaining header length and the body length.
+fn parse_packet_header(first: u8, rest: &[u8]) -> PgpResult<(u8, usize, usize)> {
+    let byte = |i: usize| {
+        rest.get(i)
+            .map(|&byte| byte as usize)
+            .ok_or_else(|| PgpError::Packet("truncated packet header".into()))
+    };
+    if first & 0x80 == 0 {
+        return Err(PgpError::Packet("invalid packet tag".into()));
+    }
+    if first & 0x40 == 0 {
+        // Old format.
+        let tag = (first >> 2) & 0x0f;
+        return match first & 0x03 {
+            0 => Ok((tag, 1, byte(0)?)),
+            1 => Ok((tag, 2, byte(0)? << 8 | byte(1)?)),
+            2 => Ok((
+                tag,
+                4,
+                byte(0)? << 24 | byte(1)? << 16 | byte(2)? << 8 | byte(3)?,
+            )),
+            _ => Ok((tag, 0, rest.len())),
+        };
+    }
+    let tag = first & 0x3f;
+    match byte(0)? {
+        len @ 0..=191 => Ok((tag, 1, len)),
+        first @ 192..=223 => Ok((tag, 2, ((first - 192) << 8) + byte(1)? + 192)),
+        255 => Ok((
+            tag,
+            5,
+            byte(1)? << 24 | byte(2)? << 16 | byte(3)? << 8 | byte(4)?,
+        )),
+        _ => Err(PgpError::Packet(
+            "partial body lengths are not supported".into(),
+        )),
+    }
+}
+
+fn write_packet(out: &mut Vec<u8>, tag: u8, body: &[u8]) {
+    out.push(0xc0 | tag);
+    match body.len() {
+        len @ 0..=191 => out.push(len as u8),
+        len @ 192..=8383 => {
+            let len = len - 192;
+            out.extend_from_slice(&[(len >> 8) as u8 + 192, len as u8]);
+        }
+        len => {
+            out.push(255);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(body);
+}
+
+fn read_mpi(input: &mut &[u8]) -> PgpResult<BigUint> {
+    let truncated = || PgpError::Packet("truncated MPI".into());
+    let bits = u16::from_be_bytes(
+        input.get(..2).ok_or_else(truncated)?.try_into().unwrap(),
+    );
+    let len = (bits as usize).div_ceil(8);
+    let value = input.get(2..2 + len).ok_or_else(truncated)?;
+    *input = &input[2 + len..];
+    Ok(BigUint::from_bytes_be(value))
+}
+
+fn write_mpi(out: &mut Vec<u8>, value: &[u8]) {
+    let start = value
+        .iter()
+        .position(|&byte| byte != 0)
+        .unwrap_or(value.len());
+    let value = &value[start..];
+    let bits = value.first().map_or(0, |&first| {
+        (value.len() - 1) * 8 + (8 - first.leading_zeros() as usize)
+    });
+    out.extend_from_slice(&(bits as u16).to_be_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Computes the CRC-24 checksum of ASCII armor.
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xb7_04ce;
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x100_0000 != 0 {
+                crc ^= 0x186_4cfb;
+            }
+        }
+    }
+    crc & 0xff_ffff
+}
+
+fn armor(kind: &str, data: &[u8]) -> String {
+    let encoded = general_purpose::STANDARD.encode(data);
+    let mut armored = format!("-----BEGIN {kind}-----\n\n");
+    for line in encoded.as_bytes().chunks(64) {
+        armored.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        armored.push('\n');
+    }
+    armored.push('=');
+    armored.push_str(
+        &general_purpose::STANDARD.encode(&crc24(data).to_be_bytes()[1..]),
+    );
+    armored.push_str(&format!("\n-----END {kind}-----\n"));
+    armored
+}
+
+fn dearmor(armored: &str, kind: &str) -> PgpResult<Vec<u8>> {
+    let begin = format!("-----BEGIN {kind}-----");
+    let end = format!("-----END {kind}-----");
+    let mut lines = armored
+        .lines()
+        .map(str::trim)
+        .skip_while(|line| *line != begin)
+        .skip(1);
+    // Skip the armor headers, which end with an empty line.
+    for line in lines.by_ref() {
+        if line.is_empty() {
+            break;
+        }
+    }
+
+    let mut body = String::new();
+    let mut checksum = None;
+    let mut terminated = false;
+    for line in lines {
+        if line == end {
+            terminated = true;
+            break;
+        } else if let Some(crc) = line.strip_prefix('=') {
+            checksum = Some(crc.to_string());
+        } else {
+            body.push_str(line);
+        }
+    }
+    if !terminated {
+        return Err(PgpError::Armor(format!("missing {begin} or {end} line")));
+    }
+
+    let data = general_purpose::STANDARD
+        .decode(&body)
+        .map_err(|err| PgpError::Armor(err.to_string()))?;
+    if let Some(checksum) = checksum {
+        let expected = general_purpose::STANDARD
+            .decode(checksum)
+            .map_err(|err| PgpError::Armor(err.to_string()))?;
+        if expected != crc24(&data).to_be_bytes()[1..] {
+            return Err(PgpError::Armor("checksum mismatch".into()));
+        }
+    }
+    Ok(data)
+}
+
+fn hex_upper(bytes: &[u8]) -> String {
+    core::hex(bytes).to_ascii_uppercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfb_mode::cipher::AsyncStreamCipher;
+    use rsa::{traits::PublicKeyParts, RsaPrivateKey};
+
+    const RECIPIENT: &str = include_str!("../files/pgp/recipient.asc");
+
+    /// Builds a certificate made of a single v4 RSA primary key.
+    fn certificate(public_key: &RsaPublicKey) -> Vec<u8> {
+        let mut body = vec![4, 0, 0, 0, 0, ALGO_RSA];
+        write_mpi(&mut body, &public_key.n().to_bytes_be());
+        write_mpi(&mut body, &public_key.e().to_bytes_be());
+        let mut packet = Vec::new();
+        write_packet(&mut packet, TAG_PUBLIC_KEY, &body);
+        packet
+    }
+
+    /// Decrypts a message produced by `encrypt_message`, checking its integrity.
+    fn decrypt_message(private_key: &RsaPrivateKey, message: &[u8]) -> Vec<u8> {
+        let packets: Vec<_> = Packets(message).map(Result::unwrap).collect();
+        assert_eq!(packets.len(), 2);
+        let (TAG_PKESK, pkesk) = packets[0] else {
+            panic!("expected a PKESK packet");
+        };
+        let (TAG_SEIPD, seipd) = packets[1] else {
+            panic!("expected a SEIPD packet");
+        };
+
+        let mut mpi = &pkesk[10..];
+        let encrypted_key = read_mpi(&mut mpi).unwrap().to_bytes_be();
+        let key_material = private_key
+            .decrypt(Pkcs1v15Encrypt, &encrypted_key)
+            .unwrap();
+        assert_eq!(key_material[0], SYM_ALGO_AES256);
+        let session_key: [u8; 32] = key_material[1..33].try_into().unwrap();
+
+        assert_eq!(seipd[0], 1);
+        let mut data = seipd[1..].to_vec();
+        cfb_mode::Decryptor::<Aes256>::new(&session_key.into(), &[0u8; 16].into())
+            .decrypt(&mut data);
+        assert_eq!(data[14..16], data[16..18]);
+        let (content, mdc) = data.split_at(data.len() - 20);
+        assert_eq!(Sha1::digest(content).as_slice(), mdc);
+
+        let (tag, literal) = Packets(&content[18..]).next().unwrap().unwrap();
+        assert_eq!(tag, TAG_LITERAL_DATA);
+        literal[6..].to_vec()
+    }
+
+    #[test]
+    fn test_import_gpg_key_selects_encryption_subkey() {
+        // gpg --list-keys --with-subkey-fingerprints recipient@example.com
+        let recipient = PgpPublicKey::from_armored(RECIPIENT).unwrap();
+        assert_eq!(
+            recipient.fingerprint(),
+            "2955715772B70FB5B9479CDD11557B8B03A4F5AE"
+        );
+        assert_eq!(recipient.key_id(), "11557B8B03A4F5AE");
+    }
+
+    #[test]
+    fn test_dearmor_rejects_corruption() {
+        let corrupted = RECIPIENT.replacen("mQENB", "mQENC", 1);
+        assert!(matches!(
+            PgpPublicKey::from_armored(&corrupted),
+            Err(PgpError::Armor(_))
+        ));
+        assert!(matches!(
+            PgpPublicKey::from_armored("not a key"),
+            Err(PgpError::Armor(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypt_message_roundtrip() {
+        let private_key = core::generate_private_key(2048).unwrap();
+        let recipient =
+            PgpPublicKey::from_bytes(&certificate(&private_key.to_public_key()))
+                .unwrap();
+
+        for plaintext in [&b""[..], b"Hello, OpenPGP!", &[0x42; 10_000]] {
+            let message = encrypt_message(&recipient, plaintext).unwrap();
+            assert_eq!(decrypt_message(&private_key, &message), plaintext);
+        }
+
+        let armored =
+            encrypt_message_armored(&recipient, b"Hello, OpenPGP!").unwrap();
+        let message = dearmor(&armored, "PGP MESSAGE").unwrap();
+        assert_eq!(decrypt_message(&private_key, &message), b"Hello, OpenPGP!");
+    }
+}
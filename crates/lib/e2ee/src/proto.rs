@@ -0,0 +1,376 @@
+//! Protobuf messages for envelopes and key bundles, matching `proto/e2ee.proto`.
+//!
+//! This module is enabled by the `proto` feature. gRPC services and other protobuf-based systems
+//! can carry E2EE payloads in typed fields rather than opaque `bytes`: [`Envelope`] holds the
+//! fields of a binary envelope (see the `envelope` module) and converts losslessly to and from it,
+//! and [`KeyBundle`] publishes a public key with its key ID. The messages are declared with
+//! `prost` derives rather than generated by `tonic-build`, so that building does not require
+//! `protoc`; other languages generate their types from `proto/e2ee.proto`.
+//!
+//! The header of a binary envelope is authenticated as associated data and rebuilt from the
+//! fields, so tampering with any field of an [`Envelope`] fails authentication when it is opened.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::envelope;
+//! use e2ee::proto::{Envelope, KeyBundle};
+//! use prost::Message;
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let bundle = KeyBundle::new(&private_key.to_public_key(), None).expect("Failed to encode key");
+//!
+//! // The sender encrypts for the published key.
+//! let public_key = bundle.to_public_key().expect("Invalid key bundle");
+//! let sealed = envelope::seal(&public_key, b"Hello, protobuf!").expect("Failed to seal");
+//! let message = Envelope::from_binary(&sealed).expect("Malformed envelope").encode_to_vec();
+//!
+//! // The recipient decodes the message and opens the envelope.
+//! let decoded = Envelope::decode(&message[..]).expect("Invalid message");
+//! let binary = decoded.to_binary().expect("Malformed envelope");
+//! assert_eq!(envelope::open(&private_key, &binary).unwrap(), b"Hello, protobuf!");
+//! ```
+use crate::core;
+use crate::envelope::{self, EnvelopeError};
+use rsa::{
+    pkcs8::{DecodePublicKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub type ProtoResult<T> = std::result::Result<T, ProtoError>;
+
+/// An error returned when converting protobuf messages.
+#[derive(Error, Debug)]
+pub enum ProtoError {
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Missing field {0}")]
+    MissingField(&'static str),
+
+    #[error("Unknown AEAD {0}")]
+    UnknownAead(i32),
+
+    #[error("Invalid version {0}")]
+    InvalidVersion(u32),
+
+    #[error("Invalid public key: {0}")]
+    InvalidKey(String),
+
+    #[error("Key ID {actual} does not match the key ID {expected} of the bundle")]
+    KeyIdMismatch { expected: String, actual: String },
+}
+
+/// The cipher encrypting the payload of an envelope with the data key.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum Aead {
+    Aes256Gcm = 0,
+    ChaCha20Poly1305 = 1,
+}
+
+impl From<envelope::Aead> for Aead {
+    fn from(aead: envelope::Aead) -> Self {
+        match aead {
+            envelope::Aead::Aes256Gcm => Aead::Aes256Gcm,
+            envelope::Aead::ChaCha20Poly1305 => Aead::ChaCha20Poly1305,
+        }
+    }
+}
+
+impl From<Aead> for envelope::Aead {
+    fn from(aead: Aead) -> Self {
+        match aead {
+            Aead::Aes256Gcm => envelope::Aead::Aes256Gcm,
+            Aead::ChaCha20Poly1305 => envelope::Aead::ChaCha20Poly1305,
+        }
+    }
+}
+
+/// A key the data key of an envelope is wrapped for.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Recipient {
+    /// The binary key ID, empty in envelopes of version 1.
+    #[prost(bytes = "vec", tag = "1")]
+    pub key_id: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub wrapped_key: Vec<u8>,
+}
+
+/// A hybrid RSA-OAEP envelope, field by field.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Envelope {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    #[prost(enumeration = "Aead", tag = "2")]
+    pub aead: i32,
+    #[prost(message, optional, tag = "3")]
+    pub recipient: Option<Recipient>,
+    /// The escrow keys or the other recipients.
+    #[prost(message, repeated, tag = "4")]
+    pub additional_recipients: Vec<Recipient>,
+    /// The expiry time, in seconds since the Unix epoch.
+    #[prost(uint64, optional, tag = "5")]
+    pub expires_at: Option<u64>,
+    #[prost(bytes = "vec", tag = "6")]
+    pub nonce: Vec<u8>,
+    /// The encrypted payload, followed by its authentication tag.
+    #[prost(bytes = "vec", tag = "7")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Converts a binary envelope, as returned by `envelope::seal`, into its message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `envelope` is malformed (see `Envelope::parse` in the
+    /// `envelope` module).
+    pub fn from_binary(envelope: &[u8]) -> ProtoResult<Self> {
+        let parsed = envelope::Envelope::parse(envelope)?;
+        let mut recipients =
+            parsed.recipients().into_iter().map(|recipient| Recipient {
+                key_id: recipient.raw_key_id().unwrap_or_default().to_vec(),
+                wrapped_key: recipient.wrapped_key().to_vec(),
+            });
+        Ok(Self {
+            version: parsed.version().into(),
+            aead: Aead::from(parsed.aead()).into(),
+            recipient: recipients.next(),
+            additional_recipients: recipients.collect(),
+            expires_at: parsed.expires_at().map(|expires_at| {
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs())
+            }),
+            nonce: parsed.nonce().to_vec(),
+            ciphertext: parsed.ciphertext().to_vec(),
+        })
+    }
+
+    /// Converts the message back into a binary envelope, to be opened with `envelope::open`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ProtoError::MissingField` if the message has no recipient,
+    /// `ProtoError::UnknownAead` or `ProtoError::InvalidVersion` if these fields are out of range,
+    /// or `ProtoError::Envelope` if the fields do not form a valid envelope of their version.
+    pub fn to_binary(&self) -> ProtoResult<Vec<u8>> {
+        let version = u8::try_from(self.version)
+            .map_err(|_| ProtoError::InvalidVersion(self.version))?;
+        let aead = Aead::try_from(self.aead)
+            .map_err(|_| ProtoError::UnknownAead(self.aead))?;
+        let recipient = self
+            .recipient
+            .as_ref()
+            .ok_or(ProtoError::MissingField("recipient"))?;
+        let recipients: Vec<_> = std::iter::once(recipient)
+            .chain(&self.additional_recipients)
+            .map(|recipient| {
+                let key_id =
+                    (!recipient.key_id.is_empty()).then_some(&recipient.key_id[..]);
+                (key_id, &recipient.wrapped_key[..])
+            })
+            .collect();
+        Ok(envelope::encode_fields(
+            version,
+            aead.into(),
+            &recipients,
+            self.expires_at,
+            &self.nonce,
+            &self.ciphertext,
+        )?)
+    }
+}
+
+/// A public key to encrypt for, as published by its owner.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct KeyBundle {
+    /// The hex key ID of the key, see `core::key_id`.
+    #[prost(string, tag = "1")]
+    pub key_id: String,
+    /// The SPKI DER encoding of the RSA public key.
+    #[prost(bytes = "vec", tag = "2")]
+    pub public_key: Vec<u8>,
+    /// The time after which the key must no longer be used, in seconds since the Unix epoch.
+    #[prost(uint64, optional, tag = "3")]
+    pub expires_at: Option<u64>,
+}
+
+impl KeyBundle {
+    /// Creates the bundle of `public_key`, valid until `expires_at` if given.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ProtoError::InvalidKey` if the key cannot be DER-encoded.
+    pub fn new(
+        public_key: &RsaPublicKey,
+        expires_at: Option<SystemTime>,
+    ) -> ProtoResult<Self> {
+        let invalid =
+            |err: rsa::pkcs8::spki::Error| ProtoError::InvalidKey(err.to_string());
+        Ok(Self {
+            key_id: core::key_id(public_key).map_err(invalid)?,
+            public_key: public_key.to_public_key_der().map_err(invalid)?.into_vec(),
+            expires_at: expires_at.map(|expires_at| {
+                expires_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_secs())
+            }),
+        })
+    }
+
+    /// Returns whether the bundle has expired at `now`. An expiry beyond the range of
+    /// `SystemTime` is later than any `now`, so it never expires.
+    pub fn is_expired_at(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|seconds| {
+            UNIX_EPOCH
+                .checked_add(Duration::from_secs(seconds))
+                .is_some_and(|expires_at| now >= expires_at)
+        })
+    }
+
+    /// Decodes and validates the public key of the bundle, and checks that it matches the key ID.
+    /// The expiry is left to the caller, see [`KeyBundle::is_expired_at`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ProtoError::InvalidKey` if the key cannot be decoded or fails the
+    /// checks applied to foreign keys (see `PublicE2ee::from_public_key`), or
+    /// `ProtoError::KeyIdMismatch` if its key ID is not that of the bundle.
+    pub fn to_public_key(&self) -> ProtoResult<RsaPublicKey> {
+        let public_key = RsaPublicKey::from_public_key_der(&self.public_key)
+            .map_err(|err| ProtoError::InvalidKey(err.to_string()))?;
+        core::validate_public_key(public_key.n(), public_key.e())
+            .map_err(ProtoError::InvalidKey)?;
+        let key_id = core::key_id(&public_key)
+            .map_err(|err| ProtoError::InvalidKey(err.to_string()))?;
+        if key_id != self.key_id {
+            return Err(ProtoError::KeyIdMismatch {
+                expected: self.key_id.clone(),
+                actual: key_id,
+            });
+        }
+        Ok(public_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prost::Message;
+    use rsa::rand_core::OsRng;
+
+    #[test]
+    fn test_envelope_roundtrip_for_every_version() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let escrow_key = core::generate_private_key(1024).unwrap().to_public_key();
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        let mut sealed = vec![
+            envelope::seal_with_aead(
+                &public_key,
+                envelope::Aead::ChaCha20Poly1305,
+                b"payload",
+            )
+            .unwrap(),
+            envelope::seal_with_options(
+                &mut OsRng,
+                &public_key,
+                std::slice::from_ref(&escrow_key),
+                None,
+                envelope::Aead::Aes256Gcm,
+                b"payload",
+            )
+            .unwrap(),
+            envelope::seal_with_expiry(&public_key, expires_at, b"payload").unwrap(),
+            envelope::seal_for_recipients(
+                &[public_key.clone(), escrow_key],
+                b"payload",
+            )
+            .unwrap(),
+        ];
+        // An envelope of version 1, without a key ID.
+        let mut legacy = sealed[0].clone();
+        legacy.drain(1..1 + envelope::KEY_ID_LEN);
+        legacy[0] = envelope::VERSION_WITHOUT_KEY_ID;
+        sealed.push(legacy);
+
+        for sealed in sealed {
+            let message = Envelope::from_binary(&sealed).unwrap();
+            let decoded = Envelope::decode(&message.encode_to_vec()[..]).unwrap();
+            assert_eq!(decoded, message);
+            assert_eq!(decoded.to_binary().unwrap(), sealed);
+        }
+    }
+
+    #[test]
+    fn test_invalid_envelopes_are_rejected() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let sealed =
+            envelope::seal(&private_key.to_public_key(), b"payload").unwrap();
+        let message = Envelope::from_binary(&sealed).unwrap();
+
+        let mut tampered = message.clone();
+        tampered.aead = Aead::ChaCha20Poly1305.into();
+        assert!(matches!(
+            envelope::open(&private_key, &tampered.to_binary().unwrap()),
+            Err(EnvelopeError::Authentication)
+        ));
+        tampered.aead = 7;
+        assert!(matches!(
+            tampered.to_binary(),
+            Err(ProtoError::UnknownAead(7))
+        ));
+        let mut tampered = message.clone();
+        tampered.recipient = None;
+        assert!(matches!(
+            tampered.to_binary(),
+            Err(ProtoError::MissingField("recipient"))
+        ));
+        let mut tampered = message.clone();
+        tampered.expires_at = Some(0);
+        assert!(matches!(
+            tampered.to_binary(),
+            Err(ProtoError::Envelope(EnvelopeError::Malformed))
+        ));
+        let mut tampered = message;
+        tampered.nonce.pop();
+        assert!(tampered.to_binary().is_err());
+    }
+
+    #[test]
+    fn test_key_bundles() {
+        let public_key = core::generate_private_key(1024).unwrap().to_public_key();
+        let bundle = KeyBundle::new(&public_key, Some(UNIX_EPOCH)).unwrap();
+        let decoded = KeyBundle::decode(&bundle.encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded.to_public_key().unwrap(), public_key);
+        assert!(decoded.is_expired_at(SystemTime::now()));
+        assert!(!KeyBundle::new(&public_key, None)
+            .unwrap()
+            .is_expired_at(SystemTime::now()));
+        let unbounded = KeyBundle {
+            expires_at: Some(u64::MAX),
+            ..decoded.clone()
+        };
+        assert!(!unbounded.is_expired_at(SystemTime::now()));
+
+        let mut forged = decoded.clone();
+        forged.key_id = "0000000000000000".into();
+        assert!(matches!(
+            forged.to_public_key(),
+            Err(ProtoError::KeyIdMismatch { .. })
+        ));
+        forged.public_key.truncate(10);
+        assert!(matches!(
+            forged.to_public_key(),
+            Err(ProtoError::InvalidKey(_))
+        ));
+    }
+}
@@ -0,0 +1,501 @@
+//! Proxy re-encryption: a proxy turns ciphertexts for Alice into ciphertexts for Bob.
+//!
+//! This module is enabled by the `experimental` feature.
+//!
+//! # Warning
+//!
+//! This mode is experimental: its format may change without notice, and it has not been reviewed.
+//! A proxy colluding with Bob recovers Alice's secret key from the token, so only delegate to
+//! recipients trusted with everything Alice can decrypt. Bob cannot check that the proxy
+//! re-encrypted honestly, and ciphertexts are not authenticated: like with any public key
+//! encryption, anyone can encrypt for Alice. Sign the plaintext (e.g. with the `sealed_sender`
+//! module) when Bob needs to know who wrote it.
+//!
+//! # Design
+//!
+//! RSA has no algebraic structure allowing re-encryption, so this module uses its own key pairs,
+//! [`PreKeyPair`], in the Ristretto group of Curve25519. The scheme is a single-hop, unidirectional
+//! key encapsulation in the style of Umbral (without threshold): [`encrypt`] encapsulates an
+//! AES-256-GCM data key for Alice's public key in a capsule, and Alice derives a
+//! [`ReEncryptionToken`] from her secret key and Bob's public key alone, with
+//! [`PreKeyPair::re_encryption_token`]. The proxy holding the token transforms the capsule with
+//! [`re_encrypt`], without learning the data key or the plaintext, and Bob opens the result with
+//! [`PreKeyPair::decrypt`]. The payload itself is never touched by the proxy. The binary layouts
+//! are:
+//!
+//! ```text
+//! version 1 (1 byte) | capsule (96 bytes) | nonce (12 bytes) | AES-256-GCM ciphertext and tag
+//! version 2 (1 byte) | capsule (96 bytes) | re-encrypted capsule (96 bytes) | nonce (12 bytes) |
+//!     AES-256-GCM ciphertext and tag
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::proxy_reencryption::{self, PreKeyPair};
+//!
+//! let alice = PreKeyPair::generate();
+//! let bob = PreKeyPair::generate();
+//! let stored = proxy_reencryption::encrypt(&alice.public_key(), b"Shared document")
+//!     .expect("Failed to encrypt");
+//!
+//! // Alice hands a token to the proxy, which never sees the plaintext.
+//! let token = alice.re_encryption_token(&bob.public_key());
+//! let for_bob = proxy_reencryption::re_encrypt(&token, &stored).expect("Failed to re-encrypt");
+//!
+//! assert_eq!(bob.decrypt(&for_bob).unwrap(), b"Shared document");
+//! assert_eq!(alice.decrypt(&stored).unwrap(), b"Shared document");
+//! ```
+use crate::kdf::Kdf;
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use curve25519_dalek::{
+    constants::RISTRETTO_BASEPOINT_POINT, ristretto::CompressedRistretto,
+    traits::Identity, RistrettoPoint, Scalar,
+};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    sha2::{Digest, Sha512},
+};
+use std::fmt;
+use thiserror::Error;
+use zeroize::{Zeroize, Zeroizing};
+
+/// The version of ciphertexts produced by [`encrypt`].
+pub const VERSION: u8 = 1;
+
+/// The version of ciphertexts produced by [`re_encrypt`].
+pub const VERSION_RE_ENCRYPTED: u8 = 2;
+
+const POINT_LEN: usize = 32;
+const CAPSULE_LEN: usize = 3 * POINT_LEN;
+const NONCE_LEN: usize = 12;
+const KDF_SALT: &[u8] = b"e2ee proxy re-encryption v1";
+
+pub type PreResult<T> = std::result::Result<T, PreError>;
+
+/// An error returned when encrypting, re-encrypting or decrypting.
+#[derive(Error, Debug)]
+pub enum PreError {
+    #[error("Malformed ciphertext, key or token")]
+    Malformed,
+
+    #[error("Unsupported ciphertext version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The ciphertext was already re-encrypted")]
+    AlreadyReEncrypted,
+
+    #[error("Invalid capsule: the ciphertext was tampered with")]
+    InvalidCapsule,
+
+    #[error("Decryption failed: wrong key, or the ciphertext was tampered with")]
+    Decryption,
+}
+
+/// A public key ciphertexts are encrypted and re-encrypted for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrePublicKey(RistrettoPoint);
+
+impl PrePublicKey {
+    /// Returns the compressed encoding of the key.
+    pub fn to_bytes(&self) -> [u8; POINT_LEN] {
+        self.0.compress().to_bytes()
+    }
+
+    /// Decodes a key encoded with [`PrePublicKey::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PreError::Malformed` if `bytes` is not a valid key.
+    pub fn from_bytes(bytes: &[u8]) -> PreResult<Self> {
+        let point = decode_point(bytes)?;
+        if point == RistrettoPoint::identity() {
+            return Err(PreError::Malformed);
+        }
+        Ok(Self(point))
+    }
+}
+
+/// A key pair decrypting ciphertexts encrypted or re-encrypted for its public key.
+#[derive(Clone)]
+pub struct PreKeyPair {
+    secret: Scalar,
+    public: PrePublicKey,
+}
+
+impl fmt::Debug for PreKeyPair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PreKeyPair")
+            .field("public", &self.public)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for PreKeyPair {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+impl PreKeyPair {
+    /// Generates a random key pair.
+    pub fn generate() -> Self {
+        Self::from_secret(random_scalar())
+    }
+
+    /// Restores a key pair from the secret key returned by [`PreKeyPair::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PreError::Malformed` if `bytes` is not a valid secret key.
+    pub fn from_bytes(bytes: &[u8]) -> PreResult<Self> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| PreError::Malformed)?;
+        let secret = Option::<Scalar>::from(Scalar::from_canonical_bytes(bytes))
+            .filter(|secret| *secret != Scalar::ZERO)
+            .ok_or(PreError::Malformed)?;
+        Ok(Self::from_secret(secret))
+    }
+
+    /// Returns the secret key, zeroed when dropped.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.secret.to_bytes())
+    }
+
+    /// Returns the public key.
+    pub fn public_key(&self) -> PrePublicKey {
+        self.public
+    }
+
+    /// Returns a token letting a proxy re-encrypt the ciphertexts for this key pair into
+    /// ciphertexts for `recipient` (see the warning of the module documentation).
+    pub fn re_encryption_token(
+        &self,
+        recipient: &PrePublicKey,
+    ) -> ReEncryptionToken {
+        let ephemeral_secret = Zeroizing::new(random_scalar());
+        let ephemeral = RISTRETTO_BASEPOINT_POINT * *ephemeral_secret;
+        let shared = recipient.0 * *ephemeral_secret;
+        let d =
+            hash_to_scalar(b"re-encryption", &[&ephemeral, &recipient.0, &shared]);
+        ReEncryptionToken {
+            key: self.secret * d.invert(),
+            ephemeral,
+        }
+    }
+
+    /// Decrypts a ciphertext encrypted for this key pair with [`encrypt`], or re-encrypted for it
+    /// with [`re_encrypt`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PreError::Decryption` if the ciphertext is not for this key pair or
+    /// was tampered with, and `PreError::Malformed` or `PreError::InvalidCapsule` if it is
+    /// malformed.
+    pub fn decrypt(&self, ciphertext: &[u8]) -> PreResult<Vec<u8>> {
+        let parsed = Ciphertext::parse(ciphertext)?;
+        parsed.capsule.verify()?;
+        let shared = match &parsed.re_encrypted {
+            None => (parsed.capsule.e + parsed.capsule.v) * self.secret,
+            Some(re_encrypted) => {
+                let shared = re_encrypted.ephemeral * self.secret;
+                let d = hash_to_scalar(
+                    b"re-encryption",
+                    &[&re_encrypted.ephemeral, &self.public.0, &shared],
+                );
+                (re_encrypted.e + re_encrypted.v) * d
+            }
+        };
+        Aes256Gcm::new(data_key(&shared, parsed.capsule_bytes).as_ref().into())
+            .decrypt(
+                Nonce::from_slice(parsed.nonce),
+                Payload {
+                    msg: parsed.payload,
+                    aad: parsed.capsule_bytes,
+                },
+            )
+            .map_err(|_| PreError::Decryption)
+    }
+
+    fn from_secret(secret: Scalar) -> Self {
+        Self {
+            secret,
+            public: PrePublicKey(RISTRETTO_BASEPOINT_POINT * secret),
+        }
+    }
+}
+
+/// A token letting a proxy re-encrypt ciphertexts from one key pair to another, created with
+/// [`PreKeyPair::re_encryption_token`].
+#[derive(Clone)]
+pub struct ReEncryptionToken {
+    key: Scalar,
+    ephemeral: RistrettoPoint,
+}
+
+impl fmt::Debug for ReEncryptionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReEncryptionToken").finish_non_exhaustive()
+    }
+}
+
+impl Drop for ReEncryptionToken {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+impl ReEncryptionToken {
+    /// Encodes the token, to be sent to the proxy over a confidential channel.
+    pub fn to_bytes(&self) -> Zeroizing<[u8; 64]> {
+        let mut bytes = Zeroizing::new([0; 64]);
+        bytes[..32].copy_from_slice(self.key.as_bytes());
+        bytes[32..].copy_from_slice(self.ephemeral.compress().as_bytes());
+        bytes
+    }
+
+    /// Decodes a token encoded with [`ReEncryptionToken::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `PreError::Malformed` if `bytes` is not a valid token.
+    pub fn from_bytes(bytes: &[u8]) -> PreResult<Self> {
+        if bytes.len() != 64 {
+            return Err(PreError::Malformed);
+        }
+        let key = Option::<Scalar>::from(Scalar::from_canonical_bytes(
+            bytes[..32].try_into().expect("32 bytes"),
+        ))
+        .ok_or(PreError::Malformed)?;
+        Ok(Self {
+            key,
+            ephemeral: decode_point(&bytes[32..])?,
+        })
+    }
+}
+
+/// Encrypts `plaintext` for `recipient`.
+///
+/// # Errors
+///
+/// This function returns an error if encryption fails.
+pub fn encrypt(recipient: &PrePublicKey, plaintext: &[u8]) -> PreResult<Vec<u8>> {
+    let r = Zeroizing::new(random_scalar());
+    let u = Zeroizing::new(random_scalar());
+    let e = RISTRETTO_BASEPOINT_POINT * *r;
+    let v = RISTRETTO_BASEPOINT_POINT * *u;
+    let s = *u + *r * hash_to_scalar(b"capsule", &[&e, &v]);
+    let shared = recipient.0 * (*r + *u);
+
+    let mut ciphertext = vec![VERSION];
+    ciphertext.extend_from_slice(e.compress().as_bytes());
+    ciphertext.extend_from_slice(v.compress().as_bytes());
+    ciphertext.extend_from_slice(s.as_bytes());
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let capsule_bytes = &ciphertext[1..];
+    let payload = Aes256Gcm::new(data_key(&shared, capsule_bytes).as_ref().into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: capsule_bytes,
+            },
+        )
+        .map_err(|_| PreError::Decryption)?;
+    ciphertext.extend_from_slice(&nonce);
+    ciphertext.extend_from_slice(&payload);
+    Ok(ciphertext)
+}
+
+/// Re-encrypts a ciphertext produced by [`encrypt`] for the recipient of `token`. Only the capsule
+/// is transformed: the proxy learns neither the data key nor the plaintext.
+///
+/// # Errors
+///
+/// This function returns `PreError::InvalidCapsule` if the capsule was tampered with,
+/// `PreError::AlreadyReEncrypted` if the ciphertext was already re-encrypted, and
+/// `PreError::Malformed` if it is malformed.
+pub fn re_encrypt(
+    token: &ReEncryptionToken,
+    ciphertext: &[u8],
+) -> PreResult<Vec<u8>> {
+    let parsed = Ciphertext::parse(ciphertext)?;
+    if parsed.re_encrypted.is_some() {
+        return Err(PreError::AlreadyReEncrypted);
+    }
+    parsed.capsule.verify()?;
+
+    let mut re_encrypted = vec![VERSION_RE_ENCRYPTED];
+    re_encrypted.extend_from_slice(parsed.capsule_bytes);
+    re_encrypted
+        .extend_from_slice((parsed.capsule.e * token.key).compress().as_bytes());
+    re_encrypted
+        .extend_from_slice((parsed.capsule.v * token.key).compress().as_bytes());
+    re_encrypted.extend_from_slice(token.ephemeral.compress().as_bytes());
+    re_encrypted.extend_from_slice(parsed.nonce);
+    re_encrypted.extend_from_slice(parsed.payload);
+    Ok(re_encrypted)
+}
+
+/// The encapsulation of a data key: `E = rG`, `V = uG` and `s = u + r·H(E, V)`.
+struct Capsule {
+    e: RistrettoPoint,
+    v: RistrettoPoint,
+    s: Scalar,
+}
+
+impl Capsule {
+    /// Checks that `sG = V + H(E, V)·E`, so that a proxy does not transform a forged capsule.
+    fn verify(&self) -> PreResult<()> {
+        let h = hash_to_scalar(b"capsule", &[&self.e, &self.v]);
+        if RISTRETTO_BASEPOINT_POINT * self.s == self.v + self.e * h {
+            Ok(())
+        } else {
+            Err(PreError::InvalidCapsule)
+        }
+    }
+}
+
+/// A capsule transformed by [`re_encrypt`]: `E' = rk·E`, `V' = rk·V` and the ephemeral public
+/// key of the token.
+struct ReEncryptedCapsule {
+    e: RistrettoPoint,
+    v: RistrettoPoint,
+    ephemeral: RistrettoPoint,
+}
+
+struct Ciphertext<'a> {
+    capsule: Capsule,
+    capsule_bytes: &'a [u8],
+    re_encrypted: Option<ReEncryptedCapsule>,
+    nonce: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl<'a> Ciphertext<'a> {
+    fn parse(ciphertext: &'a [u8]) -> PreResult<Self> {
+        let (&version, rest) =
+            ciphertext.split_first().ok_or(PreError::Malformed)?;
+        let re_encrypted_len = match version {
+            VERSION => 0,
+            VERSION_RE_ENCRYPTED => CAPSULE_LEN,
+            _ => return Err(PreError::UnsupportedVersion(version)),
+        };
+        if rest.len() < CAPSULE_LEN + re_encrypted_len + NONCE_LEN {
+            return Err(PreError::Malformed);
+        }
+        let (capsule_bytes, rest) = rest.split_at(CAPSULE_LEN);
+        let (re_encrypted_bytes, rest) = rest.split_at(re_encrypted_len);
+        let (nonce, payload) = rest.split_at(NONCE_LEN);
+        let capsule = Capsule {
+            e: decode_point(&capsule_bytes[..32])?,
+            v: decode_point(&capsule_bytes[32..64])?,
+            s: Option::from(Scalar::from_canonical_bytes(
+                capsule_bytes[64..].try_into().expect("32 bytes"),
+            ))
+            .ok_or(PreError::Malformed)?,
+        };
+        let re_encrypted = if re_encrypted_len == 0 {
+            None
+        } else {
+            Some(ReEncryptedCapsule {
+                e: decode_point(&re_encrypted_bytes[..32])?,
+                v: decode_point(&re_encrypted_bytes[32..64])?,
+                ephemeral: decode_point(&re_encrypted_bytes[64..])?,
+            })
+        };
+        Ok(Self {
+            capsule,
+            capsule_bytes,
+            re_encrypted,
+            nonce,
+            payload,
+        })
+    }
+}
+
+fn decode_point(bytes: &[u8]) -> PreResult<RistrettoPoint> {
+    CompressedRistretto::from_slice(bytes)
+        .ok()
+        .and_then(|point| point.decompress())
+        .ok_or(PreError::Malformed)
+}
+
+fn random_scalar() -> Scalar {
+    let mut bytes = Zeroizing::new([0u8; 64]);
+    OsRng.fill_bytes(bytes.as_mut());
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+/// Hashes `points` to a scalar, separated by `label` from the other uses of the hash.
+fn hash_to_scalar(label: &[u8], points: &[&RistrettoPoint]) -> Scalar {
+    let mut hasher = Sha512::new();
+    hasher.update(KDF_SALT);
+    hasher.update(label);
+    for point in points {
+        hasher.update(point.compress().as_bytes());
+    }
+    Scalar::from_bytes_mod_order_wide(&hasher.finalize().into())
+}
+
+/// Derives the data key from the shared point, bound to the capsule.
+fn data_key(shared: &RistrettoPoint, capsule_bytes: &[u8]) -> Zeroizing<[u8; 32]> {
+    Kdf::extract(Some(KDF_SALT), shared.compress().as_bytes())
+        .derive_key("data key", capsule_bytes)
+        .expect("the label has no NUL byte")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_re_encryption_reaches_only_the_delegatee() {
+        let alice = PreKeyPair::generate();
+        let bob = PreKeyPair::generate();
+        let carol = PreKeyPair::generate();
+        let stored = encrypt(&alice.public_key(), b"Shared document").unwrap();
+        assert!(matches!(bob.decrypt(&stored), Err(PreError::Decryption)));
+
+        let token = alice.re_encryption_token(&bob.public_key());
+        let token =
+            ReEncryptionToken::from_bytes(token.to_bytes().as_ref()).unwrap();
+        let for_bob = re_encrypt(&token, &stored).unwrap();
+        assert_eq!(bob.decrypt(&for_bob).unwrap(), b"Shared document");
+        assert!(matches!(carol.decrypt(&for_bob), Err(PreError::Decryption)));
+        assert!(matches!(
+            re_encrypt(&token, &for_bob),
+            Err(PreError::AlreadyReEncrypted)
+        ));
+
+        let restored = PreKeyPair::from_bytes(alice.to_bytes().as_ref()).unwrap();
+        assert_eq!(restored.public_key(), alice.public_key());
+        assert_eq!(restored.decrypt(&stored).unwrap(), b"Shared document");
+    }
+
+    #[test]
+    fn test_tampered_capsules_are_rejected() {
+        let alice = PreKeyPair::generate();
+        let bob = PreKeyPair::generate();
+        let mut stored = encrypt(&alice.public_key(), b"Shared document").unwrap();
+        // Swap E and V: both still decode, but the capsule no longer verifies.
+        let (e, v) = stored[1..65].split_at_mut(32);
+        e.swap_with_slice(v);
+        let token = alice.re_encryption_token(&bob.public_key());
+        assert!(matches!(
+            re_encrypt(&token, &stored),
+            Err(PreError::InvalidCapsule)
+        ));
+        assert!(matches!(
+            alice.decrypt(&stored),
+            Err(PreError::InvalidCapsule)
+        ));
+        assert!(matches!(
+            alice.decrypt(&[3]),
+            Err(PreError::UnsupportedVersion(3))
+        ));
+    }
+}
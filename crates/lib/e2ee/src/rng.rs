@@ -0,0 +1,159 @@
+//! Injection of the random number generator.
+//!
+//! By default, every random value drawn by key generation and encryption (primes, OAEP seeds,
+//! data keys, nonces and blinding factors) comes from the operating system through `OsRng`. Some
+//! certified environments must use a specific generator instead, e.g. a hardware RNG or a DRBG
+//! seeded from an HSM. Any `CryptoRng + RngCore` can be supplied:
+//!
+//! - per call, to the generic `core::generate_private_key_with_rng`, `core::encrypt_with_rng` and
+//!   `envelope::seal_with_rng`;
+//! - once, to `E2ee::new_with_rng`, `E2ee::with_rng`, `PublicE2ee::with_rng` and
+//!   `KeyGenerator::with_rng`, which use it for all their later operations.
+//!
+//! A generator kept by an instance is shared with its clones and behind a mutex, locked for each
+//! draw only, so that the instance stays `Send + Sync`. The other modules (e.g. `stream` and
+//! `session`) still draw from `OsRng`.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::server::{E2ee, KeySize};
+//! use rsa::rand_core::{CryptoRng, Error, OsRng, RngCore};
+//!
+//! /// A hardware generator, stubbed here with the operating system generator.
+//! struct HardwareRng;
+//!
+//! impl RngCore for HardwareRng {
+//!     fn next_u32(&mut self) -> u32 {
+//!         OsRng.next_u32()
+//!     }
+//!
+//!     fn next_u64(&mut self) -> u64 {
+//!         OsRng.next_u64()
+//!     }
+//!
+//!     fn fill_bytes(&mut self, dest: &mut [u8]) {
+//!         OsRng.fill_bytes(dest)
+//!     }
+//!
+//!     fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+//!         OsRng.try_fill_bytes(dest)
+//!     }
+//! }
+//!
+//! impl CryptoRng for HardwareRng {}
+//!
+//! let e2ee = E2ee::new_with_rng(KeySize::Bit2048, HardwareRng)
+//!     .expect("Failed to create E2ee instance");
+//! let encrypted = e2ee.encrypt("Hello, world!").expect("Failed to encrypt message");
+//! assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello, world!");
+//! ```
+use rsa::rand_core::{CryptoRng, CryptoRngCore, Error, OsRng, RngCore};
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// A generator shared between the clones of an instance, `OsRng` unless one was injected.
+#[derive(Clone, Default)]
+pub(crate) struct SharedRng(Option<Arc<Mutex<dyn CryptoRngCore + Send>>>);
+
+impl SharedRng {
+    pub(crate) fn new(rng: impl CryptoRngCore + Send + 'static) -> Self {
+        Self(Some(Arc::new(Mutex::new(rng))))
+    }
+
+    /// Runs `f` with the generator, locked for the duration of the call.
+    fn with<T>(&self, f: impl FnOnce(&mut dyn RngCore) -> T) -> T {
+        match &self.0 {
+            Some(rng) => f(rng
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_rngcore()),
+            None => f(&mut OsRng),
+        }
+    }
+}
+
+impl RngCore for SharedRng {
+    fn next_u32(&mut self) -> u32 {
+        self.with(|rng| rng.next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.with(|rng| rng.next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.with(|rng| rng.fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.with(|rng| rng.try_fill_bytes(dest))
+    }
+}
+
+impl CryptoRng for SharedRng {}
+
+impl fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "CustomRng"
+        } else {
+            "OsRng"
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A generator counting the bytes drawn from it, delegating to `OsRng`.
+    #[derive(Clone, Default)]
+    pub(crate) struct CountingRng(pub(crate) Arc<AtomicUsize>);
+
+    impl CountingRng {
+        pub(crate) fn drawn(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl RngCore for CountingRng {
+        fn next_u32(&mut self) -> u32 {
+            self.0.fetch_add(4, Ordering::Relaxed);
+            OsRng.next_u32()
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            self.0.fetch_add(8, Ordering::Relaxed);
+            OsRng.next_u64()
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            self.0.fetch_add(dest.len(), Ordering::Relaxed);
+            OsRng.fill_bytes(dest)
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+            self.0.fetch_add(dest.len(), Ordering::Relaxed);
+            OsRng.try_fill_bytes(dest)
+        }
+    }
+
+    impl CryptoRng for CountingRng {}
+
+    #[test]
+    fn test_shared_rng_draws_from_the_injected_generator() {
+        let counting = CountingRng::default();
+        let mut shared = SharedRng::new(counting.clone());
+        let mut clone = shared.clone();
+        let mut bytes = [0u8; 16];
+        shared.fill_bytes(&mut bytes);
+        clone.next_u64();
+        assert_eq!(counting.drawn(), 24);
+        assert_eq!(format!("{shared:?}"), "CustomRng");
+        assert_eq!(format!("{:?}", SharedRng::default()), "OsRng");
+    }
+}
@@ -0,0 +1,220 @@
+//! Sealed-sender messages, whose sender is only known to the recipient.
+//!
+//! A signed message usually reveals its sender to whoever relays it. [`seal`] signs the message
+//! with the sender's key, then encrypts the sender's public key, the signature and the message
+//! together into an envelope (see the `envelope` module) for the recipient. Relays only see the key
+//! ID of the recipient; [`open`] decrypts the envelope, then checks the signature against the
+//! enclosed sender key and returns it with the message.
+//!
+//! The enclosed sender key proves that its holder wrote the message, not that the holder is who
+//! they claim to be: look [`SealedMessage::sender_key_id`] up in a directory of known keys before
+//! trusting it. The signature also covers the key ID of the recipient, so a recipient cannot
+//! re-seal a message to a third party as if the sender had sent it to them.
+//!
+//! The plaintext of the envelope is laid out as:
+//!
+//! ```text
+//! version (1 byte) | sender key length (2 bytes, big endian) | sender key (SPKI DER) |
+//! signature length (2 bytes, big endian) | signature | message
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::{core, sealed_sender};
+//!
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let server = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//!
+//! let sealed = sealed_sender::seal(&alice, server.get_public_key(), b"Hello, server!")
+//!     .expect("Failed to seal message");
+//! let opened = sealed_sender::open(&server, &sealed).expect("Failed to open message");
+//! assert_eq!(opened.message, b"Hello, server!");
+//! assert_eq!(opened.sender_key_id, core::key_id(alice.get_public_key()).unwrap());
+//! ```
+use crate::core;
+use crate::envelope;
+use crate::server::{E2ee, E2eeError};
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use thiserror::Error;
+
+/// The version of the layout of sealed-sender messages.
+pub const VERSION: u8 = 1;
+
+/// Separates the signatures of sealed-sender messages from other signatures of the same key.
+const SIGNATURE_CONTEXT: &[u8] = b"e2ee sealed sender v1\0";
+
+pub type SealedSenderResult<T> = std::result::Result<T, SealedSenderError>;
+
+/// An error returned when sealing or opening a sealed-sender message.
+#[derive(Error, Debug)]
+pub enum SealedSenderError {
+    #[error("E2ee error: {0}")]
+    E2ee(#[from] E2eeError),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("Malformed sealed-sender message")]
+    Malformed,
+
+    #[error("Unsupported sealed-sender version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid sender key: {0}")]
+    InvalidSenderKey(String),
+
+    #[error("Invalid sender signature")]
+    InvalidSignature,
+}
+
+/// A message opened with [`open`], whose signature was checked against its sender key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SealedMessage {
+    /// The public key of the sender.
+    pub sender: RsaPublicKey,
+    /// The key ID of the sender, see `core::key_id`.
+    pub sender_key_id: String,
+    /// The message.
+    pub message: Vec<u8>,
+}
+
+/// Signs `message` with the key of `sender` and encrypts it, with the sender's public key and the
+/// signature, into an envelope for `recipient`.
+///
+/// # Errors
+///
+/// This function returns an error if signing or encryption fails.
+pub fn seal(
+    sender: &E2ee,
+    recipient: &RsaPublicKey,
+    message: &[u8],
+) -> SealedSenderResult<Vec<u8>> {
+    let sender_key = sender.get_public_key().to_public_key_der()?;
+    let signature = sender.sign(&signed_data(&core::key_id(recipient)?, message))?;
+
+    let mut plaintext = vec![VERSION];
+    for field in [sender_key.as_bytes(), &signature] {
+        let len =
+            u16::try_from(field.len()).map_err(|_| SealedSenderError::Malformed)?;
+        plaintext.extend_from_slice(&len.to_be_bytes());
+        plaintext.extend_from_slice(field);
+    }
+    plaintext.extend_from_slice(message);
+    Ok(envelope::seal(recipient, &plaintext).map_err(E2eeError::from)?)
+}
+
+/// Decrypts a sealed-sender message with the key of `recipient`, then checks the signature of its
+/// sender.
+///
+/// # Errors
+///
+/// This function returns an error if the envelope cannot be decrypted (see
+/// `E2ee::decrypt_envelope`), if its content is malformed, if the sender key is invalid, or if
+/// the signature does not match the sender key, the message and the recipient.
+pub fn open(recipient: &E2ee, sealed: &[u8]) -> SealedSenderResult<SealedMessage> {
+    let plaintext = recipient.decrypt_envelope(sealed)?;
+    let (&version, rest) = plaintext
+        .split_first()
+        .ok_or(SealedSenderError::Malformed)?;
+    if version != VERSION {
+        return Err(SealedSenderError::UnsupportedVersion(version));
+    }
+    let (sender_key, rest) = split_field(rest)?;
+    let (signature, message) = split_field(rest)?;
+
+    let sender = RsaPublicKey::from_public_key_der(sender_key)?;
+    core::validate_public_key(sender.n(), sender.e())
+        .map_err(SealedSenderError::InvalidSenderKey)?;
+    let signed = signed_data(&core::key_id(recipient.get_public_key())?, message);
+    core::verify(&sender, &signed, signature)
+        .map_err(|_| SealedSenderError::InvalidSignature)?;
+    Ok(SealedMessage {
+        sender_key_id: core::key_id(&sender)?,
+        sender,
+        message: message.to_vec(),
+    })
+}
+
+/// Returns the data signed by the sender: the context, the recipient key ID and the message.
+fn signed_data(recipient_key_id: &str, message: &[u8]) -> Vec<u8> {
+    [SIGNATURE_CONTEXT, recipient_key_id.as_bytes(), message].concat()
+}
+
+/// Splits a field prefixed with its length (2 bytes, big endian) from the rest of `data`.
+fn split_field(data: &[u8]) -> SealedSenderResult<(&[u8], &[u8])> {
+    if data.len() < 2 {
+        return Err(SealedSenderError::Malformed);
+    }
+    let (len, rest) = data.split_at(2);
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    if rest.len() < len {
+        return Err(SealedSenderError::Malformed);
+    }
+    Ok(rest.split_at(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_sender_is_hidden_and_authenticated() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let server = E2ee::new(KeySize::Bit1024).unwrap();
+        let eve = E2ee::new(KeySize::Bit1024).unwrap();
+
+        let sealed = seal(&alice, server.get_public_key(), b"Hello").unwrap();
+        let parsed = Envelope::parse(&sealed).unwrap();
+        assert_eq!(
+            parsed.key_id().unwrap(),
+            core::key_id(server.get_public_key()).unwrap()
+        );
+        let alice_der = alice.get_public_key().to_public_key_der().unwrap();
+        assert!(!sealed
+            .windows(alice_der.as_bytes().len())
+            .any(|window| window == alice_der.as_bytes()));
+
+        let opened = open(&server, &sealed).unwrap();
+        assert_eq!(opened.message, b"Hello");
+        assert_eq!(&opened.sender, alice.get_public_key());
+        assert_eq!(
+            opened.sender_key_id,
+            core::key_id(alice.get_public_key()).unwrap()
+        );
+        assert!(matches!(
+            open(&eve, &sealed),
+            Err(SealedSenderError::E2ee(E2eeError::DecryptionFailed))
+        ));
+    }
+
+    #[test]
+    fn test_forwarded_messages_are_rejected() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let carol = E2ee::new(KeySize::Bit1024).unwrap();
+
+        // Bob re-seals the content Alice sent him, unchanged, for Carol.
+        let sealed = seal(&alice, bob.get_public_key(), b"For Bob only").unwrap();
+        let content = bob.decrypt_envelope(&sealed).unwrap();
+        let forwarded = envelope::seal(carol.get_public_key(), &content).unwrap();
+        assert!(matches!(
+            open(&carol, &forwarded),
+            Err(SealedSenderError::InvalidSignature)
+        ));
+
+        let truncated =
+            envelope::seal(bob.get_public_key(), &content[..10]).unwrap();
+        assert!(matches!(
+            open(&bob, &truncated),
+            Err(SealedSenderError::Malformed)
+        ));
+    }
+}
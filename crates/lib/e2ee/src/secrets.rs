@@ -0,0 +1,412 @@
+//! One-time "burn after reading" secrets.
+//!
+//! [`share`] encrypts a secret with AES-256-GCM under a fresh random key, and stores the ciphertext
+//! in a [`SecretStore`] under a random ID. The returned [`SharedSecret`] holds the ID and the key,
+//! both URL-safe, and [`SharedSecret::link`] joins them into a link such as
+//! `https://secrets.example.com/s/<ID>#<key>`: browsers never send the fragment to the server, so
+//! the server holding the store cannot read the secrets it serves.
+//!
+//! [`reveal`] takes the ciphertext out of the store before decrypting it, so a secret is revealed
+//! at most once: a second reveal, even a concurrent one, fails with `SecretError::NotFound`. A
+//! reveal with a wrong key burns the secret too. Each secret also expires after the time to live
+//! given to [`share`], which is authenticated with the ciphertext.
+//!
+//! The store is pluggable: [`MemorySecretStore`] keeps ciphertexts in memory, and
+//! [`DirectorySecretStore`] keeps one file per secret. Other stores (e.g. Redis `GETDEL`) only need
+//! an atomic [`SecretStore::take`].
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::secrets::{self, MemorySecretStore, SecretError};
+//! use std::time::Duration;
+//!
+//! let store = MemorySecretStore::new();
+//! let shared = secrets::share(&store, b"hunter2", Duration::from_secs(3600))
+//!     .expect("Failed to share secret");
+//! let link = shared.link("https://secrets.example.com/s");
+//!
+//! let (id, key) = secrets::parse_link(&link).expect("Malformed link");
+//! assert_eq!(secrets::reveal(&store, id, key).unwrap(), b"hunter2");
+//! assert!(matches!(
+//!     secrets::reveal(&store, id, key),
+//!     Err(SecretError::NotFound)
+//! ));
+//! ```
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose, Engine};
+use rsa::rand_core::{OsRng, RngCore};
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// The version of the format of stored ciphertexts.
+pub const VERSION: u8 = 1;
+
+const ID_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = 1 + 8;
+
+pub type SecretResult<T> = std::result::Result<T, SecretError>;
+
+/// An error returned when sharing or revealing a secret.
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("Store error: {0}")]
+    Store(#[from] io::Error),
+
+    #[error("Secret not found: it was already revealed, or never existed")]
+    NotFound,
+
+    #[error("Malformed secret ID, key or link")]
+    Malformed,
+
+    #[error("Unsupported secret version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid secret key")]
+    Invalid,
+
+    #[error("Secret expired at {expires_at:?}")]
+    Expired { expires_at: SystemTime },
+
+    #[error("The time to live is too long")]
+    InvalidExpiry,
+}
+
+/// A store of encrypted secrets, keyed by their ID.
+///
+/// It is implemented by [`MemorySecretStore`] and [`DirectorySecretStore`].
+pub trait SecretStore: Send + Sync {
+    /// Stores `ciphertext` under `id`, which is fresh and URL-safe.
+    fn insert(&self, id: &str, ciphertext: Vec<u8>) -> io::Result<()>;
+
+    /// Removes the ciphertext stored under `id` and returns it, or returns `None` if there is none.
+    /// Of several concurrent calls with the same `id`, at most one may return the ciphertext.
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// A [`SecretStore`] keeping ciphertexts in memory, for single-process services and tests.
+#[derive(Debug, Default)]
+pub struct MemorySecretStore {
+    secrets: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemorySecretStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of secrets that were not revealed yet, expired ones included.
+    pub fn len(&self) -> usize {
+        self.secrets.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Returns `true` if every secret was revealed.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SecretStore for MemorySecretStore {
+    fn insert(&self, id: &str, ciphertext: Vec<u8>) -> io::Result<()> {
+        self.secrets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id.to_string(), ciphertext);
+        Ok(())
+    }
+
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .secrets
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id))
+    }
+}
+
+/// A [`SecretStore`] keeping each ciphertext in a file named after its ID, shared by the processes
+/// of a machine (e.g. successive runs of `e2ee-cli share`).
+///
+/// A secret is taken by renaming its file to a unique name before reading and deleting it, which
+/// only one of several concurrent renames can do.
+#[derive(Debug, Clone)]
+pub struct DirectorySecretStore {
+    directory: PathBuf,
+}
+
+impl DirectorySecretStore {
+    /// Creates a store in `directory`, which is created if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the directory cannot be created.
+    pub fn new(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self { directory })
+    }
+}
+
+impl SecretStore for DirectorySecretStore {
+    fn insert(&self, id: &str, ciphertext: Vec<u8>) -> io::Result<()> {
+        fs::write(self.directory.join(id), ciphertext)
+    }
+
+    fn take(&self, id: &str) -> io::Result<Option<Vec<u8>>> {
+        let taken = self.directory.join(format!(".{id}.{}", OsRng.next_u64()));
+        match fs::rename(self.directory.join(id), &taken) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        let ciphertext = fs::read(&taken);
+        fs::remove_file(&taken)?;
+        ciphertext.map(Some)
+    }
+}
+
+/// A secret shared with [`share`]: the ID of its ciphertext in the store, and the key decrypting
+/// it, both encoded as URL-safe base64 without padding.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SharedSecret {
+    /// The ID of the ciphertext in the store.
+    pub id: String,
+    /// The key decrypting the ciphertext, to be sent in the fragment of a link.
+    pub key: String,
+}
+
+impl fmt::Debug for SharedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedSecret")
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SharedSecret {
+    /// Returns the link `<base_url>/<ID>#<key>`, to be parsed back with [`parse_link`].
+    pub fn link(&self, base_url: &str) -> String {
+        format!(
+            "{}/{}#{}",
+            base_url.trim_end_matches('/'),
+            self.id,
+            self.key
+        )
+    }
+}
+
+/// Returns the ID and the key of a link produced by [`SharedSecret::link`]: the last path segment
+/// and the fragment. A bare `<ID>#<key>` is accepted too.
+///
+/// # Errors
+///
+/// This function returns `SecretError::Malformed` if the link has no fragment.
+pub fn parse_link(link: &str) -> SecretResult<(&str, &str)> {
+    let (path, key) = link.split_once('#').ok_or(SecretError::Malformed)?;
+    let id = path.rsplit('/').next().unwrap_or(path);
+    Ok((id, key))
+}
+
+/// Encrypts `secret` under a fresh random key, stores the ciphertext in `store` and returns its ID
+/// and key. The secret can be revealed once with [`reveal`], until `ttl` has elapsed.
+///
+/// # Errors
+///
+/// This function returns `SecretError::InvalidExpiry` if `ttl` reaches beyond the range of
+/// `SystemTime`, or an error if the ciphertext cannot be stored.
+pub fn share(
+    store: &dyn SecretStore,
+    secret: &[u8],
+    ttl: Duration,
+) -> SecretResult<SharedSecret> {
+    let mut id = [0u8; ID_LEN];
+    let mut key = [0u8; KEY_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut id);
+    OsRng.fill_bytes(&mut key);
+    OsRng.fill_bytes(&mut nonce);
+    let id = general_purpose::URL_SAFE_NO_PAD.encode(id);
+
+    let expires_at = SystemTime::now()
+        .checked_add(ttl)
+        .ok_or(SecretError::InvalidExpiry)?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs());
+    let mut ciphertext = vec![VERSION];
+    ciphertext.extend_from_slice(&expires_at.to_be_bytes());
+    let encrypted = Aes256Gcm::new(&key.into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: secret,
+                aad: &aad(&id, &ciphertext),
+            },
+        )
+        .map_err(|_| SecretError::Invalid)?;
+    ciphertext.extend_from_slice(&nonce);
+    ciphertext.extend_from_slice(&encrypted);
+
+    store.insert(&id, ciphertext)?;
+    Ok(SharedSecret {
+        id,
+        key: general_purpose::URL_SAFE_NO_PAD.encode(key),
+    })
+}
+
+/// Takes the ciphertext stored under `id` out of `store` and decrypts it with `key`.
+///
+/// The ciphertext is removed before decryption: whatever the outcome, the secret cannot be revealed
+/// again.
+///
+/// # Errors
+///
+/// This function returns `SecretError::NotFound` if the secret was already revealed or never
+/// existed, `SecretError::Expired` if it expired, `SecretError::Invalid` if `key` is wrong or the
+/// ciphertext was tampered with, and `SecretError::Malformed` if `id` or `key` is malformed.
+pub fn reveal(
+    store: &dyn SecretStore,
+    id: &str,
+    key: &str,
+) -> SecretResult<Vec<u8>> {
+    // The ID may come from an untrusted link and name a file: only accept IDs `share` produces.
+    let valid_id = general_purpose::URL_SAFE_NO_PAD
+        .decode(id)
+        .is_ok_and(|id| id.len() == ID_LEN);
+    let key: [u8; KEY_LEN] = general_purpose::URL_SAFE_NO_PAD
+        .decode(key)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or(SecretError::Malformed)?;
+    if !valid_id {
+        return Err(SecretError::Malformed);
+    }
+
+    let ciphertext = store.take(id)?.ok_or(SecretError::NotFound)?;
+    if ciphertext.len() < HEADER_LEN + NONCE_LEN {
+        return Err(SecretError::Malformed);
+    }
+    if ciphertext[0] != VERSION {
+        return Err(SecretError::UnsupportedVersion(ciphertext[0]));
+    }
+    let (header, rest) = ciphertext.split_at(HEADER_LEN);
+    let (nonce, encrypted) = rest.split_at(NONCE_LEN);
+    let secret = Aes256Gcm::new(&key.into())
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: encrypted,
+                aad: &aad(id, header),
+            },
+        )
+        .map_err(|_| SecretError::Invalid)?;
+
+    let seconds = u64::from_be_bytes(header[1..].try_into().expect("8 bytes"));
+    // An expiry beyond the range of `SystemTime` is later than any time, so it never passes.
+    if let Some(expires_at) = UNIX_EPOCH.checked_add(Duration::from_secs(seconds)) {
+        if SystemTime::now() >= expires_at {
+            return Err(SecretError::Expired { expires_at });
+        }
+    }
+    Ok(secret)
+}
+
+/// Binds the ciphertext to its ID, so that it cannot be served under another one.
+fn aad(id: &str, header: &[u8]) -> Vec<u8> {
+    [id.as_bytes(), header].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{sync::Arc, thread};
+
+    #[test]
+    fn test_secrets_are_revealed_once() {
+        let store = MemorySecretStore::new();
+        let shared = share(&store, b"hunter2", Duration::from_secs(60)).unwrap();
+        assert!(!format!("{shared:?}").contains(&shared.key));
+        let link = shared.link("https://example.com/s/");
+        assert_eq!(
+            link,
+            format!("https://example.com/s/{}#{}", shared.id, shared.key)
+        );
+        assert_eq!(
+            parse_link(&link).unwrap(),
+            (shared.id.as_str(), shared.key.as_str())
+        );
+
+        let (id, key) = parse_link(&link).unwrap();
+        assert_eq!(reveal(&store, id, key).unwrap(), b"hunter2");
+        assert!(matches!(
+            reveal(&store, id, key),
+            Err(SecretError::NotFound)
+        ));
+        assert!(store.is_empty());
+
+        // A wrong key burns the secret as well.
+        let shared = share(&store, b"hunter2", Duration::from_secs(60)).unwrap();
+        let other = share(&store, b"other", Duration::from_secs(60)).unwrap();
+        assert!(matches!(
+            reveal(&store, &shared.id, &other.key),
+            Err(SecretError::Invalid)
+        ));
+        assert!(matches!(
+            reveal(&store, &shared.id, &shared.key),
+            Err(SecretError::NotFound)
+        ));
+
+        let expired = share(&store, b"gone", Duration::ZERO).unwrap();
+        assert!(matches!(
+            reveal(&store, &expired.id, &expired.key),
+            Err(SecretError::Expired { .. })
+        ));
+        assert!(matches!(
+            share(&store, b"forever", Duration::MAX),
+            Err(SecretError::InvalidExpiry)
+        ));
+
+        assert!(matches!(
+            parse_link("no-fragment"),
+            Err(SecretError::Malformed)
+        ));
+        assert!(matches!(
+            reveal(&store, "../../etc/passwd", &other.key),
+            Err(SecretError::Malformed)
+        ));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_directory_store_is_single_use_across_threads() {
+        let directory = std::env::temp_dir()
+            .join(format!("e2ee-secrets-test-{}", OsRng.next_u64()));
+        let store = Arc::new(DirectorySecretStore::new(&directory).unwrap());
+        let shared = share(&*store, b"hunter2", Duration::from_secs(60)).unwrap();
+
+        let revealed = (0..8)
+            .map(|_| {
+                let store = store.clone();
+                let shared = shared.clone();
+                thread::spawn(move || reveal(&*store, &shared.id, &shared.key))
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .filter_map(|handle| handle.join().unwrap().ok())
+            .collect::<Vec<_>>();
+        assert_eq!(revealed, [b"hunter2".to_vec()]);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 0);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
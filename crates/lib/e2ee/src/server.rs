@@ -1,14 +1,39 @@
-use base64::{engine::general_purpose, Engine};
+use crate::audit::{AuditEvent, AuditLogger, AuditOperation, SharedAuditLogger};
+#[cfg(feature = "cache")]
+use crate::cache::DecryptionCache;
+use crate::core::{self, OaepParams};
+use crate::envelope::{self, Aead, EnvelopeError, EnvelopeFormat};
+use crate::interop::{self, Diagnosis, InteropConfig};
+#[cfg(feature = "io")]
+use crate::io;
+use crate::keygen::{CancellationToken, KeyGenerator, KeygenProgress};
+use crate::mac::Mac;
+use crate::metrics::{MetricsSink, Operation, SharedMetrics};
+use crate::rng::SharedRng;
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+use health::{HealthCheck, HealthCheckKind, HealthReport, MIN_HEALTHY_MODULUS_BITS};
 use rsa::{
-    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey},
-    rand_core::OsRng,
-    sha2::Sha256,
-    Oaep, RsaPrivateKey, RsaPublicKey,
+    rand_core::{CryptoRngCore, RngCore},
+    sha2::{Digest, Sha256},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
 };
+use std::{
+    borrow::Borrow,
+    num::NonZeroUsize,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use zeroize::Zeroizing;
 mod error;
+pub mod guard;
+pub mod health;
+#[cfg(feature = "axum")]
+pub mod layer;
+pub mod legacy;
 use clap::ValueEnum;
-use error::{E2eeError, E2eeResult};
-use std::{fs::File, io::Write};
+pub use error::{E2eeError, E2eeResult};
 
 /// A struct representing the End-to-End Encryption (E2EE) system on the server side.
 ///
@@ -21,12 +46,23 @@ use std::{fs::File, io::Write};
 /// The server side E2EE system requires both a private key and a public key to function correctly.
 /// The private key is used for decryption, while the public key is used for encryption.
 ///
+/// `E2ee` is `Send + Sync`: every operation takes `&self` and draws randomness from the operating
+/// system on each call (or from the generator injected with [`E2ee::with_rng`]), so a single
+/// instance can be shared by all the threads of a web server, e.g. through [`E2ee::into_shared`].
+///
+/// Clones share the hooks (metrics, audit logger, decryption cache, random number generator) of
+/// the original. Two instances
+/// are equal when they hold the same key pair, compared by public key, regardless of their
+/// configuration: comparing them never inspects the private key.
+///
 /// The `E2ee` struct includes the following fields:
 ///
 /// - `private_key`: The RSA private key used for decrypting messages.
 /// - `public_key`: The RSA public key used for encrypting messages.
-/// - `private_key_pem`: The PEM-encoded private key as a string.
-/// - `public_key_pem`: The PEM-encoded public key as a string.
+/// - `public_key_pem`: The PEM-encoded public key as a string (requires the `io` feature).
+///
+/// The PEM-encoded private key is not stored: [`E2ee::get_private_key_pem`] encodes it on demand
+/// into a [`PrivateKeyPem`], zeroed when dropped and page-locked with the `secure-mem` feature.
 ///
 /// # Examples
 ///
@@ -52,14 +88,82 @@ use std::{fs::File, io::Write};
 /// # Errors
 ///
 /// The struct's methods may return errors if key generation fails, or if encryption/decryption operations fail.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct E2ee {
     private_key: RsaPrivateKey,
     public_key: RsaPublicKey,
-    private_key_pem: String,
+    #[cfg(feature = "io")]
     public_key_pem: String,
+    metrics: SharedMetrics,
+    audit: SharedAuditLogger,
+    detailed_errors: bool,
+    blinding: bool,
+    expiry_check: bool,
+    config: InteropConfig,
+    escrow_keys: Vec<RsaPublicKey>,
+    aead: Aead,
+    envelope_format: EnvelopeFormat,
+    rng: SharedRng,
+    envelope_mac: Option<Mac>,
+    #[cfg(feature = "cache")]
+    cache: Option<Arc<DecryptionCache>>,
+}
+
+impl PartialEq for E2ee {
+    fn eq(&self, other: &Self) -> bool {
+        self.public_key == other.public_key
+    }
+}
+
+impl Eq for E2ee {}
+
+/// A PEM-encoded private key returned by [`E2ee::get_private_key_pem`], zeroed when dropped.
+///
+/// With the `secure-mem` feature, the key is kept in a `secure_mem::LockedString`, in page-locked
+/// memory excluded from swap and core dumps.
+#[cfg(feature = "io")]
+pub struct PrivateKeyPem(
+    #[cfg(not(feature = "secure-mem"))] Zeroizing<String>,
+    #[cfg(feature = "secure-mem")] crate::secure_mem::LockedString,
+);
+
+#[cfg(feature = "io")]
+impl PrivateKeyPem {
+    #[cfg(not(feature = "secure-mem"))]
+    fn new(pem: Zeroizing<String>) -> E2eeResult<Self> {
+        Ok(Self(pem))
+    }
+
+    #[cfg(feature = "secure-mem")]
+    fn new(pem: Zeroizing<String>) -> E2eeResult<Self> {
+        crate::secure_mem::LockedString::new(&pem)
+            .map(Self)
+            .map_err(E2eeError::SecureMemory)
+    }
+}
+
+#[cfg(feature = "io")]
+impl std::ops::Deref for PrivateKeyPem {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(feature = "io")]
+impl std::fmt::Debug for PrivateKeyPem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PrivateKeyPem(..)")
+    }
 }
 
+// `E2ee` is shared across threads, see its documentation.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<E2ee>();
+};
+
 /// Represents the key sizes available for RSA key generation.
 ///
 /// The sizes are in bits and correspond to common RSA key lengths.
@@ -84,74 +188,991 @@ impl KeySize {
             KeySize::Bit4096 => 4096,
         }
     }
-}
+}
+
+impl E2ee {
+    /// Creates a new `E2ee` instance with the specified key size.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_size` - The size of the RSA keys to generate. This should be one of the `KeySize` variants.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if key generation fails.
+    pub fn new(key_size: KeySize) -> E2eeResult<Self> {
+        let private_key = core::generate_private_key(key_size.as_usize())?;
+        Self::from_private_key(private_key)
+    }
+
+    /// Creates a new `E2ee` instance with the specified key size, generating the key with `rng`
+    /// and keeping it for every later operation (see `with_rng` and the `rng` module).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use rsa::rand_core::OsRng;
+    ///
+    /// // Any `CryptoRng + RngCore`, e.g. a hardware generator.
+    /// let e2ee = E2ee::new_with_rng(KeySize::Bit2048, OsRng)
+    ///     .expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if key generation fails.
+    pub fn new_with_rng(
+        key_size: KeySize,
+        rng: impl CryptoRngCore + Send + 'static,
+    ) -> E2eeResult<Self> {
+        let mut rng = SharedRng::new(rng);
+        let private_key =
+            core::generate_private_key_with_rng(&mut rng, key_size.as_usize())?;
+        let mut e2ee = Self::from_private_key(private_key)?;
+        e2ee.rng = rng;
+        Ok(e2ee)
+    }
+
+    /// Creates a new `E2ee` instance with the specified key size, searching for the primes of the
+    /// key on `threads` threads (see `keygen::KeyGenerator`).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let threads = std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN);
+    /// let e2ee = E2ee::new_parallel(KeySize::Bit2048, threads)
+    ///     .expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if key generation fails.
+    pub fn new_parallel(
+        key_size: KeySize,
+        threads: NonZeroUsize,
+    ) -> E2eeResult<Self> {
+        let private_key = KeyGenerator::new(key_size.as_usize())
+            .with_threads(threads)
+            .generate()?;
+        Self::from_private_key(private_key)
+    }
+
+    /// Creates a new `E2ee` instance with the specified key size, reporting the progress of the
+    /// key generation to `progress` and aborting it when `cancellation` is cancelled.
+    ///
+    /// Use `keygen::KeyGenerator` directly to also search on multiple threads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::keygen::CancellationToken;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let cancellation = CancellationToken::new();
+    /// let e2ee = E2ee::new_with_progress(
+    ///     KeySize::Bit2048,
+    ///     |progress| println!("{:.0}%", progress.fraction() * 100.0),
+    ///     cancellation,
+    /// )
+    /// .expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::Keygen(KeygenError::Cancelled)` if the generation was
+    /// cancelled, or an error if key generation fails.
+    pub fn new_with_progress(
+        key_size: KeySize,
+        progress: impl Fn(KeygenProgress) + Send + Sync + 'static,
+        cancellation: CancellationToken,
+    ) -> E2eeResult<Self> {
+        let private_key = KeyGenerator::new(key_size.as_usize())
+            .with_progress(progress)
+            .with_cancellation(cancellation)
+            .generate()?;
+        Self::from_private_key(private_key)
+    }
+
+    /// Creates a new `E2ee` instance with the specified key size, giving up on the key generation
+    /// if it takes longer than `timeout`.
+    ///
+    /// Use `keygen::KeyGenerator::with_retries` to start the generation over instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::time::Duration;
+    ///
+    /// let e2ee = E2ee::new_with_timeout(KeySize::Bit2048, Duration::from_secs(30))
+    ///     .expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::Keygen(KeygenError::TimedOut { .. })` if the generation
+    /// timed out, or an error if key generation fails.
+    pub fn new_with_timeout(
+        key_size: KeySize,
+        timeout: Duration,
+    ) -> E2eeResult<Self> {
+        let private_key = KeyGenerator::new(key_size.as_usize())
+            .with_timeout(timeout)
+            .generate()?;
+        Self::from_private_key(private_key)
+    }
+
+    /// Creates a new `E2ee` instance from an existing RSA private key.
+    ///
+    /// The public key is derived from the private key, and the CRT parameters of the private key
+    /// are precomputed if they are missing.
+    ///
+    /// # Arguments
+    ///
+    /// * `private_key` - The RSA private key used for decryption.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::{core, server::E2ee};
+    ///
+    /// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let e2ee = E2ee::from_private_key(private_key).expect("Failed to create E2ee instance");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the keys cannot be PEM-encoded (with the `io` feature).
+    pub fn from_private_key(mut private_key: RsaPrivateKey) -> E2eeResult<Self> {
+        private_key.precompute()?;
+        let public_key = RsaPublicKey::from(&private_key);
+        #[cfg(feature = "io")]
+        let public_key_pem = io::encode_public_key_pem(&public_key)?;
+        Ok(Self {
+            private_key,
+            public_key,
+            #[cfg(feature = "io")]
+            public_key_pem,
+            metrics: SharedMetrics::default(),
+            audit: SharedAuditLogger::default(),
+            detailed_errors: false,
+            blinding: true,
+            expiry_check: true,
+            config: InteropConfig::default(),
+            escrow_keys: Vec::new(),
+            aead: Aead::default(),
+            envelope_format: EnvelopeFormat::default(),
+            rng: SharedRng::default(),
+            envelope_mac: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+        })
+    }
+
+    /// Creates a new `E2ee` instance from PEM-encoded private and public keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `private_key_pem` - The PEM-encoded private key as a string.
+    /// * `public_key_pem` - The PEM-encoded public key as a string.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::E2ee;
+    ///
+    /// let private_key_pem = include_str!("../files/private.pem");
+    /// let public_key_pem = include_str!("../files/public.pem");
+    /// let e2ee = E2ee::new_from_pem(private_key_pem.to_string(), public_key_pem.to_string())
+    ///     .expect("Failed to create E2ee instance from PEM");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::InvalidPublicKey` if the public key is too weak (see
+    /// `core::validate_public_key`) or does not match the private key, or an error if decoding the
+    /// PEM keys fails. `private_key_pem` is zeroed before returning.
+    #[cfg(feature = "io")]
+    pub fn new_from_pem(
+        private_key_pem: String,
+        public_key_pem: String,
+    ) -> E2eeResult<Self> {
+        use rsa::pkcs8::DecodePublicKey;
+        let public_key = RsaPublicKey::from_public_key_pem(&public_key_pem)?;
+        let private_key_pem = Zeroizing::new(private_key_pem);
+        let mut private_key = io::decode_private_key_pem(&private_key_pem)?;
+        core::validate_public_key(public_key.n(), public_key.e())
+            .map_err(E2eeError::InvalidPublicKey)?;
+        if RsaPublicKey::from(&private_key) != public_key {
+            return Err(E2eeError::InvalidPublicKey(
+                "the public key does not match the private key".to_string(),
+            ));
+        }
+        private_key.precompute()?;
+        Ok(Self {
+            private_key,
+            public_key,
+            public_key_pem,
+            metrics: SharedMetrics::default(),
+            audit: SharedAuditLogger::default(),
+            detailed_errors: false,
+            blinding: true,
+            expiry_check: true,
+            config: InteropConfig::default(),
+            escrow_keys: Vec::new(),
+            aead: Aead::default(),
+            envelope_format: EnvelopeFormat::default(),
+            rng: SharedRng::default(),
+            envelope_mac: None,
+            #[cfg(feature = "cache")]
+            cache: None,
+        })
+    }
+
+    /// Attaches a metrics sink that receives counters and durations for every encryption and
+    /// decryption performed by this instance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::metrics::NoopMetrics;
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::sync::Arc;
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_metrics(Arc::new(NoopMetrics));
+    /// ```
+    pub fn with_metrics(mut self, sink: Arc<dyn MetricsSink>) -> Self {
+        self.metrics = SharedMetrics::new(sink);
+        self
+    }
+
+    /// Attaches an audit logger that is invoked on every decryption and signature with the key
+    /// fingerprint, the caller-supplied context and a timestamp.
+    ///
+    /// Audit logging is fail-closed: if the logger returns an error, decryption fails with
+    /// `E2eeError::AuditLog` and the plaintext is not returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::audit::{AuditEvent, AuditLogger};
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::sync::Arc;
+    ///
+    /// struct StderrAuditLogger;
+    ///
+    /// impl AuditLogger for StderrAuditLogger {
+    ///     fn log(&self, event: &AuditEvent<'_>) -> std::io::Result<()> {
+    ///         eprintln!("{} with key {}", event.operation.as_str(), event.key_fingerprint);
+    ///         Ok(())
+    ///     }
+    /// }
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_audit_logger(Arc::new(StderrAuditLogger));
+    /// ```
+    pub fn with_audit_logger(mut self, logger: Arc<dyn AuditLogger>) -> Self {
+        self.audit = SharedAuditLogger::new(logger);
+        self
+    }
+
+    /// Caches the results of RSA decryptions in `cache` (see the `cache` module). The cache can be
+    /// shared with other instances using the same key, and inspected with `DecryptionCache::stats`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::cache::DecryptionCache;
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::{num::NonZeroUsize, sync::Arc, time::Duration};
+    ///
+    /// let cache = DecryptionCache::new(NonZeroUsize::new(1024).unwrap(), Duration::from_secs(60));
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_decryption_cache(Arc::new(cache));
+    /// ```
+    #[cfg(feature = "cache")]
+    pub fn with_decryption_cache(mut self, cache: Arc<DecryptionCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Returns the decryption cache attached with `with_decryption_cache`, if any.
+    #[cfg(feature = "cache")]
+    pub fn decryption_cache(&self) -> Option<&DecryptionCache> {
+        self.cache.as_deref()
+    }
+
+    /// Enables detailed decryption errors.
+    ///
+    /// By default, every decryption failure (invalid base64, invalid padding, invalid UTF-8) is
+    /// reported as the same `E2eeError::DecryptionFailed`, so that a service forwarding errors to
+    /// its clients cannot be used as a decryption oracle. Detailed errors report the underlying
+    /// cause instead and should only be enabled for debugging.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, E2eeError, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .detailed_errors();
+    /// let result = e2ee.decrypt("not base64!");
+    /// assert!(matches!(result, Err(E2eeError::Decoding(_))));
+    /// ```
+    pub fn detailed_errors(mut self) -> Self {
+        self.detailed_errors = true;
+        self
+    }
+
+    /// Enables or disables RSA blinding of decryptions and signatures, which is enabled by default.
+    ///
+    /// Blinding multiplies the ciphertext by a random factor before the private key operation, so
+    /// that its timing does not leak information about the key (see `core::decrypt_blinded_with`).
+    /// Disabling it saves a modular exponentiation with the public exponent and a modular inverse
+    /// per operation; only do so when timing cannot be observed by an attacker.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_blinding(false);
+    /// ```
+    pub fn with_blinding(mut self, enabled: bool) -> Self {
+        self.blinding = enabled;
+        self
+    }
+
+    /// Draws the OAEP seeds, envelope data keys and nonces, and blinding factors from `rng`
+    /// instead of `OsRng` (see the `rng` module). Clones of this instance share it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use rsa::rand_core::OsRng;
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_rng(OsRng);
+    /// ```
+    pub fn with_rng(mut self, rng: impl CryptoRngCore + Send + 'static) -> Self {
+        self.rng = SharedRng::new(rng);
+        self
+    }
+
+    /// Authenticates the ciphertexts of `encrypt` with `mac` (see `mac::Mac::seal`), and requires
+    /// the ciphertexts of `decrypt` and `decrypt_into` to be authenticated with it, so that
+    /// tampering is detected before the private key is used. The sender must use the same key,
+    /// e.g. with `PublicE2ee::with_envelope_mac`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::mac::Mac;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_envelope_mac(Mac::new(&[7; 32]));
+    /// let encrypted = e2ee.encrypt("Hello, world!").expect("Failed to encrypt message");
+    /// assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello, world!");
+    /// ```
+    pub fn with_envelope_mac(mut self, mac: Mac) -> Self {
+        self.envelope_mac = Some(mac);
+        self
+    }
+
+    /// Enables or disables the rejection of expired envelopes by `decrypt_envelope`, which is
+    /// enabled by default. Disable it to recover envelopes sealed with `encrypt_with_expiry` after
+    /// they expired, e.g. from an archive.
+    pub fn with_expiry_check(mut self, enabled: bool) -> Self {
+        self.expiry_check = enabled;
+        self
+    }
+
+    /// Performs a throwaway encryption and decryption, so that the first decryption of a
+    /// latency-sensitive service does not pay for lazy initialization (e.g. of the random number
+    /// generator). This also checks that the public key matches the private key.
+    ///
+    /// The operation is not reported to the metrics sink, the audit logger or the decryption
+    /// cache.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// e2ee.warm_up().expect("Failed to warm up");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::DecryptionFailed` if the public key does not match the
+    /// private key, or an error if encryption fails.
+    pub fn warm_up(&self) -> E2eeResult<()> {
+        let probe = [0x5a; 32];
+        let ciphertext = core::encrypt_with_rng(
+            &mut self.rng.clone(),
+            &self.public_key,
+            OaepParams::SHA256,
+            &probe,
+        )?;
+        match self.private_decrypt(OaepParams::SHA256, &ciphertext) {
+            Ok(decrypted) if decrypted == probe => Ok(()),
+            _ => Err(E2eeError::DecryptionFailed),
+        }
+    }
+
+    /// Checks that the instance can serve requests, for the readiness probe of a service: the key
+    /// pair is consistent, the key is at least `MIN_HEALTHY_MODULUS_BITS` long, and a random
+    /// canary survives a round trip through `encrypt`/`decrypt` (with the OAEP parameters and
+    /// blinding of the instance) and through an envelope (with its AEAD and format).
+    ///
+    /// Every check runs, even after a failure. The canaries bypass the metrics sink, the audit
+    /// logger and the decryption cache, so that frequent probes do not show up as traffic.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let report = e2ee.health_check();
+    /// assert!(report.is_healthy());
+    /// print!("{report}");
+    ///
+    /// let weak = E2ee::new(KeySize::Bit1024).expect("Failed to create E2ee instance");
+    /// assert!(!weak.health_check().is_healthy());
+    /// ```
+    pub fn health_check(&self) -> HealthReport {
+        let mut canary = [0u8; 32];
+        self.rng.clone().fill_bytes(&mut canary);
+        let checks = vec![
+            timed(HealthCheckKind::KeyPair, || {
+                self.private_key.validate().map_err(|err| err.to_string())?;
+                if self.private_key.to_public_key() != self.public_key {
+                    return Err(
+                        "the private key does not match the public key".into()
+                    );
+                }
+                Ok(())
+            }),
+            timed(HealthCheckKind::KeySize, || {
+                let bits = self.public_key.n().bits();
+                if bits < MIN_HEALTHY_MODULUS_BITS {
+                    return Err(format!(
+                        "modulus is {} bits, at least {} bits are required",
+                        bits, MIN_HEALTHY_MODULUS_BITS
+                    ));
+                }
+                Ok(())
+            }),
+            timed(HealthCheckKind::RoundTrip, || {
+                let ciphertext = core::encrypt_with_rng(
+                    &mut self.rng.clone(),
+                    &self.public_key,
+                    self.config.oaep,
+                    &canary,
+                )
+                .map_err(|err| format!("encryption failed: {}", err))?;
+                match self.private_decrypt(self.config.oaep, &ciphertext) {
+                    Ok(decrypted) if decrypted == canary => Ok(()),
+                    Ok(_) => Err("the canary decrypted to another value".into()),
+                    Err(err) => Err(format!("decryption failed: {}", err)),
+                }
+            }),
+            timed(HealthCheckKind::EnvelopeRoundTrip, || {
+                let envelope = envelope::seal_with_options(
+                    &mut self.rng.clone(),
+                    &self.public_key,
+                    &[],
+                    None,
+                    self.aead,
+                    &canary,
+                )
+                .and_then(|envelope| self.envelope_format.encode(envelope))
+                .map_err(|err| format!("sealing failed: {}", err))?;
+                let opened =
+                    envelope::open_with(&envelope, &self.public_key, None, |key| {
+                        Ok(self.private_decrypt(OaepParams::SHA256, key)?)
+                    });
+                match opened {
+                    Ok(opened) if opened == canary => Ok(()),
+                    Ok(_) => Err("the canary opened to another value".into()),
+                    Err(err) => Err(format!("opening failed: {}", err)),
+                }
+            }),
+        ];
+        HealthReport {
+            fingerprint: self.get_fingerprint().ok(),
+            key_bits: self.public_key.n().bits(),
+            checks,
+        }
+    }
+
+    /// Sets the OAEP parameters and ciphertext encoding used by `encrypt` and `decrypt`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::core::{OaepHash, OaepParams};
+    /// use e2ee::interop::{Encoding, InteropConfig};
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_interop_config(InteropConfig {
+    ///         oaep: OaepParams::new(OaepHash::Sha512),
+    ///         encoding: Encoding::Base64UrlNoPad,
+    ///     });
+    /// ```
+    pub fn with_interop_config(mut self, config: InteropConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds an escrow key, for which the data key of every envelope produced by
+    /// `encrypt_envelope` is also wrapped, so that the organization holding the escrow private key
+    /// can recover the payloads if this instance's key is lost (see `envelope::seal_with_escrow`).
+    /// `envelope::Envelope::recipients` lists the escrow keys of an envelope.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::core;
+    /// use e2ee::envelope;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let escrow_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_escrow_key(escrow_key.to_public_key());
+    /// let sealed = e2ee.encrypt_envelope(b"Hello, recovery!").expect("Failed to encrypt");
+    /// assert_eq!(e2ee.decrypt_envelope(&sealed).unwrap(), b"Hello, recovery!");
+    /// assert_eq!(envelope::open(&escrow_key, &sealed).unwrap(), b"Hello, recovery!");
+    /// ```
+    pub fn with_escrow_key(mut self, escrow_key: RsaPublicKey) -> Self {
+        self.escrow_keys.push(escrow_key);
+        self
+    }
+
+    /// Sets the cipher encrypting the payloads of `encrypt_envelope` and `encrypt_with_expiry`,
+    /// AES-256-GCM by default (see `envelope::seal_with_aead`). `decrypt_envelope` supports both.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::envelope::Aead;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_aead(Aead::ChaCha20Poly1305);
+    /// let sealed = e2ee.encrypt_envelope(b"Hello, ARM!").expect("Failed to encrypt");
+    /// assert_eq!(e2ee.decrypt_envelope(&sealed).unwrap(), b"Hello, ARM!");
+    /// ```
+    pub fn with_aead(mut self, aead: Aead) -> Self {
+        self.aead = aead;
+        self
+    }
+
+    /// Sets the encoding of the envelopes produced by `encrypt_envelope` and `encrypt_with_expiry`,
+    /// binary by default. `decrypt_envelope` accepts both encodings.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::envelope::EnvelopeFormat;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .with_envelope_format(EnvelopeFormat::Json);
+    /// let sealed = e2ee.encrypt_envelope(b"Hello, JSON!").expect("Failed to encrypt");
+    /// assert!(sealed.starts_with(b"{"));
+    /// assert_eq!(e2ee.decrypt_envelope(&sealed).unwrap(), b"Hello, JSON!");
+    /// ```
+    pub fn with_envelope_format(mut self, format: EnvelopeFormat) -> Self {
+        self.envelope_format = format;
+        self
+    }
+
+    /// Switches to `InteropConfig::WEBCRYPTO`, so that ciphertexts produced in browsers with
+    /// `crypto.subtle.encrypt({ name: "RSA-OAEP" }, key, data)` and encoded with `btoa` can be
+    /// decrypted.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .webcrypto_compatible();
+    /// let encrypted = e2ee.encrypt("Secret message").expect("Failed to encrypt message");
+    /// assert!(encrypted.ends_with('='));
+    /// ```
+    pub fn webcrypto_compatible(self) -> Self {
+        self.with_interop_config(InteropConfig::WEBCRYPTO)
+    }
+
+    /// Explains why a ciphertext produced by another implementation fails to decrypt.
+    ///
+    /// `ciphertext` is the data as received, either text (base64, hex) or raw bytes. The
+    /// troubleshooter tries every supported encoding and OAEP hash combination, as well as PKCS#1
+    /// v1.5 padding, and reports likely mismatches such as Java's SHA-1 MGF1 default, missing or
+    /// extra leading zero bytes, or UTF-16 plaintexts. The plaintext is never returned.
+    ///
+    /// This is a debugging aid: as it reveals which paddings decrypt a ciphertext, it must never
+    /// be exposed to untrusted input.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::interop::InteropConfig;
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let sender = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let encrypted = e2ee
+    ///     .encrypt("Secret message")
+    ///     .expect("Failed to encrypt message");
+    ///
+    /// let diagnosis = e2ee.diagnose_ciphertext(encrypted.as_bytes());
+    /// assert_eq!(diagnosis.config, Some(InteropConfig::DEFAULT));
+    /// for finding in &diagnosis.findings {
+    ///     println!("{finding}");
+    /// }
+    ///
+    /// let encrypted = sender
+    ///     .encrypt("Secret message")
+    ///     .expect("Failed to encrypt message");
+    /// assert_eq!(e2ee.diagnose_ciphertext(encrypted.as_bytes()).config, None);
+    /// ```
+    pub fn diagnose_ciphertext(&self, ciphertext: &[u8]) -> Diagnosis {
+        interop::diagnose(&self.private_key, ciphertext)
+    }
+
+    /// Wraps the instance into an `Arc`, to share it between threads, e.g. as the state of a web
+    /// server.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    /// use std::thread;
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048)
+    ///     .expect("Failed to create E2ee instance")
+    ///     .into_shared();
+    /// let encrypted = e2ee.encrypt("Hello, world!").expect("Failed to encrypt message");
+    ///
+    /// let worker = {
+    ///     let e2ee = e2ee.clone();
+    ///     thread::spawn(move || e2ee.decrypt(&encrypted))
+    /// };
+    /// assert_eq!(worker.join().unwrap().unwrap(), "Hello, world!");
+    /// ```
+    pub fn into_shared(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+
+    /// Retrieves the SHA-256 fingerprint of the public key as a lowercase hex string.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let fingerprint = e2ee.get_fingerprint().expect("Failed to compute fingerprint");
+    /// assert_eq!(fingerprint.len(), 64);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the public key cannot be DER-encoded.
+    pub fn get_fingerprint(&self) -> E2eeResult<String> {
+        Ok(core::fingerprint(&self.public_key)?)
+    }
+
+    /// Retrieves the public key in its original `RsaPublicKey` format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let public_key = e2ee.get_public_key();
+    /// // Now you can use the public key for encryption
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// This function returns a reference to the `RsaPublicKey` contained in the `E2ee` struct.
+    pub fn get_public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    /// Retrieves the public key in its original `RsaPrivateKey` format.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let public_key = e2ee.get_private_key();
+    /// // Now you can use the public key for encryption
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// This function returns a reference to the `RsaPrivateKey` contained in the `E2ee` struct.
+    pub fn get_private_key(&self) -> &RsaPrivateKey {
+        &self.private_key
+    }
+
+    /// Retrieves the PEM-encoded private key.
+    ///
+    /// The key is encoded on each call rather than kept in memory as a string: drop the result as
+    /// soon as it is no longer needed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee,KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let private_key_pem = e2ee.get_private_key_pem();
+    /// println!("Private Key PEM: {}", &*private_key_pem.expect("Failed to encode private key"));
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// This function returns the PEM-encoded private key, zeroed when dropped and kept in
+    /// page-locked memory with the `secure-mem` feature.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be encoded, or
+    /// `E2eeError::SecureMemory` if it cannot be copied into locked memory (with the
+    /// `secure-mem` feature).
+    #[cfg(feature = "io")]
+    pub fn get_private_key_pem(&self) -> E2eeResult<PrivateKeyPem> {
+        PrivateKeyPem::new(io::encode_private_key_pem(&self.private_key)?)
+    }
+
+    /// Retrieves the PEM-encoded public key.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee,KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let public_key_pem = e2ee.get_public_key_pem();
+    /// println!("Public Key PEM: {}", public_key_pem);
+    /// ```
+    ///
+    /// # Returns
+    ///
+    /// This function returns a string slice containing the PEM-encoded public key.
+    #[cfg(feature = "io")]
+    pub fn get_public_key_pem(&self) -> &str {
+        &self.public_key_pem
+    }
+
+    /// Encrypts a message for the public key of this instance: only this instance can decrypt
+    /// it (self-encryption), e.g. to store data at rest. To answer a client, encrypt for its key
+    /// with `encrypt_for` instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `message` - The plaintext message to encrypt.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let message = "Hello, world!";
+    /// let encrypted = e2ee.encrypt(message).expect("Failed to encrypt message");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    pub fn encrypt(&self, message: &str) -> E2eeResult<String> {
+        self.encrypt_raw(message.as_bytes())
+    }
 
-impl E2ee {
-    /// Creates a new `E2ee` instance with the specified key size.
+    /// Wraps a symmetric key (e.g. the key of an external AEAD pipeline) under the public key of
+    /// this instance with RSA-OAEP, to be unwrapped with `unwrap_key`. The wrapped key is encoded
+    /// like the output of `encrypt`.
     ///
-    /// # Arguments
+    /// # Examples
     ///
-    /// * `key_size` - The size of the RSA keys to generate. This should be one of the `KeySize` variants.
+    /// ```
+    /// use e2ee::server::{E2ee, KeySize};
+    ///
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let data_key = [0x42; 32];
+    /// let wrapped = e2ee.wrap_key(&data_key).expect("Failed to wrap key");
+    /// let unwrapped = e2ee.unwrap_key(&wrapped).expect("Failed to unwrap key");
+    /// assert_eq!(unwrapped.as_slice(), data_key);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key is too long for the RSA key or encryption fails.
+    pub fn wrap_key(&self, key: &[u8]) -> E2eeResult<String> {
+        self.encrypt_raw(key)
+    }
+
+    /// Unwraps a symmetric key wrapped with `wrap_key` or `PublicE2ee::wrap_key`. The key is
+    /// zeroized when dropped.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::DecryptionFailed` if decryption fails, whatever the cause,
+    /// unless detailed errors were enabled with `detailed_errors`.
+    pub fn unwrap_key(&self, wrapped: &str) -> E2eeResult<Zeroizing<Vec<u8>>> {
+        self.audited(AuditOperation::Decrypt, None, || {
+            Ok(Zeroizing::new(self.decrypt_raw(wrapped, self.config)?))
+        })
+    }
+
+    /// Encrypts `plaintext` for the public key of this instance, and encodes it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(
+                oaep_hash = self.config.oaep.hash.as_str(),
+                mgf1_hash = self.config.oaep.mgf1_hash.as_str(),
+                encoding = ?self.config.encoding,
+                mac = self.envelope_mac.is_some(),
+            ),
+            err(level = "warn")
+        )
+    )]
+    fn encrypt_raw(&self, plaintext: &[u8]) -> E2eeResult<String> {
+        self.metrics.measure(Operation::Encrypt, || {
+            let encrypted_data = core::encrypt_with_rng(
+                &mut self.rng.clone(),
+                &self.public_key,
+                self.config.oaep,
+                plaintext,
+            )?;
+            let encrypted_data = match &self.envelope_mac {
+                Some(mac) => mac.seal(b"", &encrypted_data)?,
+                None => encrypted_data,
+            };
+            Ok(self.config.encoding.encode(&encrypted_data))
+        })
+    }
+
+    /// Encrypts a message for `recipient`, e.g. the public key sent by a client, with the OAEP
+    /// parameters and encoding of this instance. `recipient` is an `RsaPublicKey` or a
+    /// `PublicE2ee`; the MAC set with `with_envelope_mac` is not applied, as the recipient does
+    /// not share it.
     ///
     /// # Examples
     ///
     /// ```
+    /// use e2ee::client::ClientKeyPair;
     /// use e2ee::server::{E2ee, KeySize};
     ///
-    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let server = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let client = ClientKeyPair::generate(KeySize::Bit2048).expect("Failed to generate key pair");
+    ///
+    /// let response = server
+    ///     .encrypt_for(client.public_key(), "Hello, client!")
+    ///     .expect("Failed to encrypt message");
+    /// assert_eq!(client.decrypt(&response).unwrap(), "Hello, client!");
     /// ```
     ///
     /// # Errors
     ///
-    /// This function returns an error if key generation fails.
-    pub fn new(key_size: KeySize) -> E2eeResult<Self> {
-        let bits = key_size.as_usize();
-        let (private_key, public_key, private_key_pem, public_key_pem) =
-            generate_rsa_keypair(bits)?;
-        Ok(Self {
-            private_key,
-            public_key,
-            private_key_pem,
-            public_key_pem,
+    /// This function returns `E2eeError::InvalidRecipientKey` if `recipient` is too weak to
+    /// encrypt for (see `core::validate_public_key`), or an error if encryption fails.
+    pub fn encrypt_for(
+        &self,
+        recipient: &impl Borrow<RsaPublicKey>,
+        message: &str,
+    ) -> E2eeResult<String> {
+        let recipient = recipient.borrow();
+        core::validate_public_key(recipient.n(), recipient.e())
+            .map_err(E2eeError::InvalidRecipientKey)?;
+        self.metrics.measure(Operation::Encrypt, || {
+            let encrypted_data = core::encrypt_with_rng(
+                &mut self.rng.clone(),
+                recipient,
+                self.config.oaep,
+                message.as_bytes(),
+            )?;
+            Ok(self.config.encoding.encode(&encrypted_data))
         })
     }
 
-    /// Creates a new `E2ee` instance from PEM-encoded private and public keys.
+    /// Decrypts a ciphertext using the private key.
     ///
     /// # Arguments
     ///
-    /// * `private_key_pem` - The PEM-encoded private key as a string.
-    /// * `public_key_pem` - The PEM-encoded public key as a string.
+    /// * `ciphertext` - The base64-encoded encrypted message to decrypt.
     ///
     /// # Examples
     ///
     /// ```
-    /// use e2ee::server::E2ee;
+    /// use e2ee::server::{E2ee, KeySize};
     ///
-    /// let private_key_pem = include_str!("../files/private.pem");
-    /// let public_key_pem = include_str!("../files/public.pem");
-    /// let e2ee = E2ee::new_from_pem(private_key_pem.to_string(), public_key_pem.to_string())
-    ///     .expect("Failed to create E2ee instance from PEM");
+    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let message = "Hello, world!";
+    /// let encrypted = e2ee.encrypt(message).expect("Failed to encrypt message");
+    /// let decrypted = e2ee.decrypt(&encrypted).expect("Failed to decrypt message");
+    /// assert_eq!(message, decrypted);
     /// ```
     ///
     /// # Errors
     ///
-    /// This function returns an error if decoding the PEM keys fails.
-    pub fn new_from_pem(
-        private_key_pem: String,
-        public_key_pem: String,
-    ) -> E2eeResult<Self> {
-        let public_key = RsaPublicKey::from_public_key_pem(&public_key_pem)?;
-        let private_key = RsaPrivateKey::from_pkcs8_pem(&private_key_pem)?;
-        Ok(Self {
-            private_key,
-            public_key,
-            private_key_pem,
-            public_key_pem,
-        })
+    /// This function returns `E2eeError::DecryptionFailed` if decryption fails, whatever the cause,
+    /// unless detailed errors were enabled with `detailed_errors`.
+    pub fn decrypt(&self, ciphertext: &str) -> E2eeResult<String> {
+        self.decrypt_audited(ciphertext, None)
     }
 
-    /// Retrieves the public key in its original `RsaPublicKey` format.
+    /// Decrypts a ciphertext like `decrypt`, but writes the plaintext into `out` and returns its
+    /// length, for embedded and FFI callers reusing a pre-allocated buffer. The plaintext does not
+    /// need to be UTF-8.
+    ///
+    /// The plaintext of RSA-OAEP is at most the size of the key in bytes, minus 66 with SHA-256:
+    /// a buffer of `get_public_key().size()` bytes is always large enough. The RSA computation
+    /// itself still uses temporary heap buffers.
     ///
     /// # Examples
     ///
@@ -159,18 +1180,58 @@ impl E2ee {
     /// use e2ee::server::{E2ee, KeySize};
     ///
     /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let public_key = e2ee.get_public_key();
-    /// // Now you can use the public key for encryption
+    /// let encrypted = e2ee.encrypt("Hello, world!").expect("Failed to encrypt message");
+    ///
+    /// let mut buffer = [0; 256];
+    /// let len = e2ee.decrypt_into(&encrypted, &mut buffer).expect("Failed to decrypt message");
+    /// assert_eq!(&buffer[..len], b"Hello, world!");
     /// ```
     ///
-    /// # Returns
+    /// # Errors
     ///
-    /// This function returns a reference to the `RsaPublicKey` contained in the `E2ee` struct.
-    pub fn get_public_key(&self) -> &RsaPublicKey {
-        &self.public_key
+    /// This function returns `E2eeError::BufferTooSmall` with the length of the plaintext if it
+    /// does not fit in `out`, which is then left untouched. Otherwise, it returns the errors of
+    /// `decrypt`.
+    pub fn decrypt_into(
+        &self,
+        ciphertext: &str,
+        out: &mut [u8],
+    ) -> E2eeResult<usize> {
+        let plaintext = self.audited(AuditOperation::Decrypt, None, || {
+            self.decrypt_raw(ciphertext, self.config)
+        })?;
+        let out =
+            out.get_mut(..plaintext.len())
+                .ok_or(E2eeError::BufferTooSmall {
+                    needed: plaintext.len(),
+                })?;
+        out.copy_from_slice(&plaintext);
+        Ok(plaintext.len())
     }
 
-    /// Retrieves the public key in its original `RsaPrivateKey` format.
+    /// Decrypts a ciphertext using the private key, passing `context` to the audit logger.
+    ///
+    /// The context identifies who or what requested the decryption (e.g. a user or request ID) so
+    /// that audit records can be correlated. Without an audit logger, this behaves like `decrypt`.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` - The base64-encoded encrypted message to decrypt.
+    /// * `context` - The caller-supplied context recorded in the audit event.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if decryption or audit logging fails.
+    pub fn decrypt_with_context(
+        &self,
+        ciphertext: &str,
+        context: &str,
+    ) -> E2eeResult<String> {
+        self.decrypt_audited(ciphertext, Some(context))
+    }
+
+    /// Encrypts `message` like `encrypt`, but returns the raw RSA-OAEP ciphertext, without the
+    /// base64 encoding or the UTF-8 restriction of `encrypt`, for callers handling binary data.
     ///
     /// # Examples
     ///
@@ -178,109 +1239,389 @@ impl E2ee {
     /// use e2ee::server::{E2ee, KeySize};
     ///
     /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let public_key = e2ee.get_private_key();
-    /// // Now you can use the public key for encryption
+    /// let encrypted = e2ee.encrypt_bytes_ref([0xde, 0xad]).expect("Failed to encrypt");
+    /// let decrypted = e2ee.decrypt_bytes_ref(&encrypted).expect("Failed to decrypt");
+    /// assert_eq!(&decrypted[..], [0xde, 0xad]);
     /// ```
     ///
-    /// # Returns
+    /// # Errors
     ///
-    /// This function returns a reference to the `RsaPrivateKey` contained in the `E2ee` struct.
-    pub fn get_private_key(&self) -> &RsaPrivateKey {
-        &self.private_key
+    /// This function returns an error if encryption fails.
+    #[cfg(feature = "bytes")]
+    pub fn encrypt_bytes_ref(&self, message: impl AsRef<[u8]>) -> E2eeResult<Bytes> {
+        self.metrics.measure(Operation::Encrypt, || {
+            let encrypted_data = core::encrypt_with_rng(
+                &mut self.rng.clone(),
+                &self.public_key,
+                self.config.oaep,
+                message.as_ref(),
+            )?;
+            Ok(Bytes::from(encrypted_data))
+        })
     }
 
-    /// Retrieves the PEM-encoded private key.
+    /// Decrypts a raw RSA-OAEP ciphertext produced by `encrypt_bytes_ref`, and returns the
+    /// plaintext without requiring it to be UTF-8.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::DecryptionFailed` if decryption fails, whatever the cause,
+    /// unless detailed errors were enabled with `detailed_errors`.
+    #[cfg(feature = "bytes")]
+    pub fn decrypt_bytes_ref(
+        &self,
+        ciphertext: impl AsRef<[u8]>,
+    ) -> E2eeResult<Bytes> {
+        self.audited(AuditOperation::Decrypt, None, || {
+            Ok(Bytes::from(
+                self.rsa_decrypt(self.config.oaep, ciphertext.as_ref())?,
+            ))
+        })
+    }
+
+    /// Encrypts `payload` of any size into an envelope (see the `envelope` module).
     ///
     /// # Examples
     ///
     /// ```
-    /// use e2ee::server::{E2ee,KeySize};
+    /// use e2ee::server::{E2ee, KeySize};
     ///
     /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let private_key_pem = e2ee.get_private_key_pem();
-    /// println!("Private Key PEM: {}", private_key_pem);
+    /// let payload = vec![0x42; 10_000];
+    /// let envelope = e2ee.encrypt_envelope(&payload).expect("Failed to encrypt payload");
+    /// let decrypted = e2ee.decrypt_envelope(&envelope).expect("Failed to decrypt envelope");
+    /// assert_eq!(payload, decrypted);
     /// ```
     ///
-    /// # Returns
+    /// # Errors
     ///
-    /// This function returns a string slice containing the PEM-encoded private key.
-    pub fn get_private_key_pem(&self) -> &str {
-        &self.private_key_pem
+    /// This function returns an error if encryption fails.
+    pub fn encrypt_envelope(&self, payload: &[u8]) -> E2eeResult<Vec<u8>> {
+        self.metrics.measure(Operation::Encrypt, || {
+            Ok(self.envelope_format.encode(envelope::seal_with_options(
+                &mut self.rng.clone(),
+                &self.public_key,
+                &self.escrow_keys,
+                None,
+                self.aead,
+                payload,
+            )?)?)
+        })
     }
 
-    /// Retrieves the PEM-encoded public key.
+    /// Encrypts `payload` of any size into an envelope that `decrypt_envelope` rejects once `ttl`
+    /// has elapsed (see `envelope::seal_with_expiry`), e.g. for one-time links and short-lived
+    /// secrets.
     ///
     /// # Examples
     ///
     /// ```
-    /// use e2ee::server::{E2ee,KeySize};
+    /// use e2ee::envelope::EnvelopeError;
+    /// use e2ee::server::{E2ee, E2eeError, KeySize};
+    /// use std::time::Duration;
     ///
     /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let public_key_pem = e2ee.get_public_key_pem();
-    /// println!("Public Key PEM: {}", public_key_pem);
+    /// let envelope = e2ee
+    ///     .encrypt_with_expiry(b"one-time secret", Duration::from_secs(600))
+    ///     .expect("Failed to encrypt payload");
+    /// assert_eq!(e2ee.decrypt_envelope(&envelope).unwrap(), b"one-time secret");
+    ///
+    /// let expired = e2ee
+    ///     .encrypt_with_expiry(b"one-time secret", Duration::ZERO)
+    ///     .expect("Failed to encrypt payload");
+    /// assert!(matches!(
+    ///     e2ee.decrypt_envelope(&expired),
+    ///     Err(E2eeError::Envelope(EnvelopeError::Expired { .. }))
+    /// ));
     /// ```
     ///
-    /// # Returns
+    /// # Errors
     ///
-    /// This function returns a string slice containing the PEM-encoded public key.
-    pub fn get_public_key_pem(&self) -> &str {
-        &self.public_key_pem
+    /// This function returns `E2eeError::InvalidExpiry` if `ttl` reaches beyond the range of
+    /// `SystemTime`, or an error if encryption fails.
+    pub fn encrypt_with_expiry(
+        &self,
+        payload: &[u8],
+        ttl: Duration,
+    ) -> E2eeResult<Vec<u8>> {
+        let expires_at = SystemTime::now()
+            .checked_add(ttl)
+            .ok_or(E2eeError::InvalidExpiry)?;
+        self.metrics.measure(Operation::Encrypt, || {
+            Ok(self.envelope_format.encode(envelope::seal_with_options(
+                &mut self.rng.clone(),
+                &self.public_key,
+                &self.escrow_keys,
+                Some(expires_at),
+                self.aead,
+                payload,
+            )?)?)
+        })
     }
 
-    /// Encrypts a message using the public key.
+    /// Decrypts an envelope produced by `encrypt_envelope`, `encrypt_with_expiry` or
+    /// `PublicE2ee::encrypt_envelope`.
     ///
-    /// # Arguments
+    /// # Errors
     ///
-    /// * `message` - The plaintext message to encrypt.
+    /// This function returns `E2eeError::Envelope` with `EnvelopeError::Expired` if the envelope has
+    /// expired, unless expiry checks were disabled with `with_expiry_check`. Otherwise, it returns
+    /// `E2eeError::DecryptionFailed` if decryption fails, whatever the cause, unless detailed
+    /// errors were enabled with `detailed_errors`.
+    pub fn decrypt_envelope(&self, envelope: &[u8]) -> E2eeResult<Vec<u8>> {
+        self.audited(AuditOperation::Decrypt, None, || {
+            Ok(envelope::open_with(
+                envelope,
+                &self.public_key,
+                self.expiry_check.then(SystemTime::now),
+                |wrapped_key| Ok(self.rsa_decrypt(OaepParams::SHA256, wrapped_key)?),
+            )?)
+        })
+    }
+
+    /// Rewraps an envelope sealed for this instance for `new_public_key`, e.g. when rotating keys
+    /// over large stored blobs: only the data key is decrypted, and the payload is not (see
+    /// `envelope::rewrap`).
     ///
     /// # Examples
     ///
     /// ```
     /// use e2ee::server::{E2ee, KeySize};
     ///
-    /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let message = "Hello, world!";
-    /// let encrypted = e2ee.encrypt(message).expect("Failed to encrypt message");
+    /// let old = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let new = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+    /// let envelope = old.encrypt_envelope(&[0x42; 10_000]).expect("Failed to encrypt payload");
+    ///
+    /// let rewrapped = old
+    ///     .rewrap(&envelope, new.get_public_key())
+    ///     .expect("Failed to rewrap envelope");
+    /// assert_eq!(new.decrypt_envelope(&rewrapped).unwrap(), [0x42; 10_000]);
     /// ```
     ///
     /// # Errors
     ///
-    /// This function returns an error if encryption fails.
-    pub fn encrypt(&self, message: &str) -> E2eeResult<String> {
-        let mut rng = OsRng;
-        let padding = Oaep::new::<Sha256>();
-        let encrypted_data =
-            self.public_key
-                .encrypt(&mut rng, padding, message.as_bytes())?;
-        Ok(general_purpose::STANDARD_NO_PAD.encode(encrypted_data))
+    /// This function returns `E2eeError::DecryptionFailed` if the data key cannot be unwrapped or
+    /// the payload was tampered with, whatever the cause, unless detailed errors were enabled with
+    /// `detailed_errors`.
+    pub fn rewrap(
+        &self,
+        envelope: &[u8],
+        new_public_key: &RsaPublicKey,
+    ) -> E2eeResult<Vec<u8>> {
+        self.audited(AuditOperation::Decrypt, None, || {
+            Ok(envelope::rewrap_with(
+                &mut self.rng.clone(),
+                envelope,
+                &self.public_key,
+                new_public_key,
+                |wrapped_key| Ok(self.rsa_decrypt(OaepParams::SHA256, wrapped_key)?),
+            )?)
+        })
     }
 
-    /// Decrypts a ciphertext using the private key.
-    ///
-    /// # Arguments
-    ///
-    /// * `ciphertext` - The base64-encoded encrypted message to decrypt.
+    /// Signs `message` with RSASSA-PKCS1-v1_5 (SHA-256), see `core::sign`. Signatures can be
+    /// checked with `PublicE2ee::verify` or `openssl dgst -sha256 -verify`.
     ///
     /// # Examples
     ///
     /// ```
+    /// use e2ee::client::PublicE2ee;
     /// use e2ee::server::{E2ee, KeySize};
     ///
     /// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
-    /// let message = "Hello, world!";
-    /// let encrypted = e2ee.encrypt(message).expect("Failed to encrypt message");
-    /// let decrypted = e2ee.decrypt(&encrypted).expect("Failed to decrypt message");
-    /// assert_eq!(message, decrypted);
+    /// let signature = e2ee.sign(b"release-1.0.tar.gz").expect("Failed to sign message");
+    /// let public = PublicE2ee::from_public_key(e2ee.get_public_key().clone())
+    ///     .expect("Failed to create PublicE2ee instance");
+    /// assert!(public.verify(b"release-1.0.tar.gz", &signature).is_ok());
     /// ```
     ///
     /// # Errors
     ///
-    /// This function returns an error if decryption fails.
-    pub fn decrypt(&self, ciphertext: &str) -> E2eeResult<String> {
-        let padding = Oaep::new::<Sha256>();
-        let encrypted_data = general_purpose::STANDARD_NO_PAD.decode(ciphertext)?;
-        let decrypted_data = self.private_key.decrypt(padding, &encrypted_data)?;
-        Ok(String::from_utf8(decrypted_data)?)
+    /// This function returns an error if signing or audit logging fails.
+    pub fn sign(&self, message: &[u8]) -> E2eeResult<Vec<u8>> {
+        self.sign_audited(&Sha256::digest(message).into(), None)
+    }
+
+    /// Signs a message given its SHA-256 `digest`, like `sign` signs the message, for messages
+    /// hashed incrementally (e.g. large files, see the `signing` module).
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing or audit logging fails.
+    pub fn sign_digest(
+        &self,
+        digest: &[u8; 32],
+        context: Option<&str>,
+    ) -> E2eeResult<Vec<u8>> {
+        self.sign_audited(digest, context)
+    }
+
+    /// Signs `message` like `sign`, passing `context` to the audit logger.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing or audit logging fails.
+    pub fn sign_with_context(
+        &self,
+        message: &[u8],
+        context: &str,
+    ) -> E2eeResult<Vec<u8>> {
+        self.sign_audited(&Sha256::digest(message).into(), Some(context))
+    }
+
+    /// Decrypts `ciphertext` with `config` instead of the configuration of this instance,
+    /// reporting the underlying cause of failures.
+    pub(crate) fn decrypt_detailed(
+        &self,
+        ciphertext: &str,
+        config: InteropConfig,
+    ) -> E2eeResult<String> {
+        Ok(String::from_utf8(self.decrypt_raw(ciphertext, config)?)?)
+    }
+
+    /// Decodes and decrypts `ciphertext`, without checking that the plaintext is UTF-8.
+    ///
+    /// With the `tracing` feature, the OAEP parameters and encoding attempted are recorded, along
+    /// with the detailed error, before `audited` hides it.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            level = "debug",
+            skip_all,
+            fields(
+                oaep_hash = config.oaep.hash.as_str(),
+                mgf1_hash = config.oaep.mgf1_hash.as_str(),
+                encoding = ?config.encoding,
+                mac = self.envelope_mac.is_some(),
+            ),
+            err(level = "warn")
+        )
+    )]
+    fn decrypt_raw(
+        &self,
+        ciphertext: &str,
+        config: InteropConfig,
+    ) -> E2eeResult<Vec<u8>> {
+        match config.encoding.decode(ciphertext) {
+            Ok(encrypted_data) => match &self.envelope_mac {
+                Some(mac) => Ok(self.rsa_decrypt(
+                    config.oaep,
+                    mac.open(&encrypted_data)?.ciphertext,
+                )?),
+                None => Ok(self.rsa_decrypt(config.oaep, &encrypted_data)?),
+            },
+            Err(err) => {
+                // Run a dummy decryption so that malformed input is not distinguishable from a
+                // padding failure by its timing.
+                let dummy = vec![0; self.private_key.size()];
+                let _ = self.private_decrypt(config.oaep, &dummy);
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Decrypts an RSA-OAEP `ciphertext`, through the decryption cache if one is attached.
+    fn rsa_decrypt(
+        &self,
+        oaep: OaepParams,
+        ciphertext: &[u8],
+    ) -> rsa::Result<Vec<u8>> {
+        let decrypt = || self.private_decrypt(oaep, ciphertext);
+        #[cfg(feature = "cache")]
+        if let Some(cache) = &self.cache {
+            return cache.get_or_try_insert(
+                oaep,
+                ciphertext,
+                &self.metrics,
+                decrypt,
+            );
+        }
+        decrypt()
+    }
+
+    /// Decrypts an RSA-OAEP `ciphertext` with the private key, blinded unless disabled.
+    fn private_decrypt(
+        &self,
+        oaep: OaepParams,
+        ciphertext: &[u8],
+    ) -> rsa::Result<Vec<u8>> {
+        if self.blinding {
+            core::decrypt_blinded_with_rng(
+                &mut self.rng.clone(),
+                &self.private_key,
+                oaep,
+                ciphertext,
+            )
+        } else {
+            core::decrypt_with(&self.private_key, oaep, ciphertext)
+        }
+    }
+
+    fn decrypt_audited(
+        &self,
+        ciphertext: &str,
+        context: Option<&str>,
+    ) -> E2eeResult<String> {
+        self.audited(AuditOperation::Decrypt, context, || {
+            self.decrypt_detailed(ciphertext, self.config)
+        })
+    }
+
+    fn sign_audited(
+        &self,
+        digest: &[u8; 32],
+        context: Option<&str>,
+    ) -> E2eeResult<Vec<u8>> {
+        self.audited(AuditOperation::Sign, context, || {
+            if self.blinding {
+                Ok(core::sign_digest_blinded_with_rng(
+                    &mut self.rng.clone(),
+                    &self.private_key,
+                    digest,
+                )?)
+            } else {
+                Ok(core::sign_digest(&self.private_key, digest)?)
+            }
+        })
+    }
+
+    /// Runs the private key `operation` `f`, reporting it to the metrics sink and the audit logger.
+    /// The cause of decryption failures is hidden unless detailed errors are enabled.
+    fn audited<T>(
+        &self,
+        operation: AuditOperation,
+        context: Option<&str>,
+        f: impl FnOnce() -> E2eeResult<T>,
+    ) -> E2eeResult<T> {
+        let metrics_operation = match operation {
+            AuditOperation::Decrypt => Operation::Decrypt,
+            AuditOperation::Sign => Operation::Sign,
+        };
+        let result = self.metrics.measure(metrics_operation, || {
+            let result = f();
+            if self.detailed_errors || operation != AuditOperation::Decrypt {
+                result
+            } else {
+                // The expiry of an envelope is public: reporting it reveals nothing.
+                result.map_err(|err| match err {
+                    E2eeError::Envelope(EnvelopeError::Expired { .. }) => err,
+                    _ => E2eeError::DecryptionFailed,
+                })
+            }
+        });
+        if let Some(logger) = self.audit.get() {
+            let key_fingerprint = self.get_fingerprint()?;
+            logger
+                .log(&AuditEvent {
+                    operation,
+                    key_fingerprint: &key_fingerprint,
+                    context,
+                    timestamp: SystemTime::now(),
+                    success: result.is_ok(),
+                })
+                .map_err(E2eeError::AuditLog)?;
+        }
+        result
     }
 
     /// Saves the PEM-encoded private and public keys to files.
@@ -311,58 +1652,42 @@ impl E2ee {
     /// # Errors
     ///
     /// This function returns an error if writing to the files fails.
+    #[cfg(feature = "io")]
     pub fn save_keys_to_files(
         &self,
         private_key_file_path: &str,
         public_key_file_path: &str,
     ) -> E2eeResult<()> {
-        let mut private_key_file =
-            File::create(private_key_file_path).map_err(|_| {
-                E2eeError::FileWriteError("Failed to create private key file".into())
-            })?;
-        let mut public_key_file =
-            File::create(public_key_file_path).map_err(|_| {
-                E2eeError::FileWriteError("Failed to create public key file".into())
-            })?;
-
-        private_key_file
-            .write_all(self.private_key_pem.as_bytes())
-            .map_err(|_| {
-                E2eeError::FileWriteError(
-                    "Failed to write private key to file".into(),
-                )
-            })?;
-        public_key_file
-            .write_all(self.public_key_pem.as_bytes())
-            .map_err(|_| {
-                E2eeError::FileWriteError(
-                    "Failed to write public key to file".into(),
-                )
-            })?;
+        let private_key_pem = io::encode_private_key_pem(&self.private_key)?;
+        io::write_pem_file(private_key_file_path, &private_key_pem).map_err(
+            |_| E2eeError::FileWriteError("Failed to write private key file".into()),
+        )?;
+        io::write_pem_file(public_key_file_path, &self.public_key_pem).map_err(
+            |_| E2eeError::FileWriteError("Failed to write public key file".into()),
+        )?;
 
         Ok(())
     }
 }
 
-fn generate_rsa_keypair(
-    bits: usize,
-) -> Result<(RsaPrivateKey, RsaPublicKey, String, String), E2eeError> {
-    let mut rng = OsRng;
-    let private_key = RsaPrivateKey::new(&mut rng, bits)?;
-    let public_key = RsaPublicKey::from(&private_key);
-    let private_key_pem = private_key
-        .to_pkcs8_pem(rsa::pkcs8::LineEnding::default())
-        .map_err(E2eeError::Pkcs8)?
-        .to_string();
-    let public_key_pem = public_key
-        .to_public_key_pem(rsa::pkcs8::LineEnding::default())
-        .map_err(E2eeError::Spki)?;
-    Ok((private_key, public_key, private_key_pem, public_key_pem))
+/// Runs the health check `kind`, measuring how long it takes.
+fn timed(
+    kind: HealthCheckKind,
+    check: impl FnOnce() -> Result<(), String>,
+) -> HealthCheck {
+    let start = Instant::now();
+    let failure = check().err();
+    HealthCheck {
+        kind,
+        failure,
+        duration: start.elapsed(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use base64::{engine::general_purpose, Engine};
 
     /// Tests encryption and decryption using a 2048-bit RSA key.
     ///
@@ -413,11 +1738,33 @@ mod tests {
         assert_eq!(message, decrypted);
     }
 
+    /// Tests encrypting for an external recipient key.
+    ///
+    /// The recipient decrypts the message whether it was passed as a `PublicE2ee` or an
+    /// `RsaPublicKey`, and the sender cannot decrypt it.
+    #[test]
+    fn test_encrypt_for_recipient() {
+        let server = E2ee::new(KeySize::Bit1024).unwrap();
+        let client = E2ee::new(KeySize::Bit1024).unwrap();
+        let public = crate::client::PublicE2ee::from_public_key(
+            client.get_public_key().clone(),
+        )
+        .unwrap();
+
+        let encrypted = server.encrypt_for(&public, "Hello, client!").unwrap();
+        assert_eq!(client.decrypt(&encrypted).unwrap(), "Hello, client!");
+        assert!(server.decrypt(&encrypted).is_err());
+
+        let encrypted = server.encrypt_for(client.get_public_key(), "Hi!").unwrap();
+        assert_eq!(client.decrypt(&encrypted).unwrap(), "Hi!");
+    }
+
     /// Tests saving and loading keys from files.
     ///
     /// This test verifies that PEM-encoded keys can be correctly saved to files and then loaded back,
     /// ensuring that the saved keys match the original ones. It also checks that the file operations succeed.
     #[test]
+    #[cfg(feature = "io")]
     fn test_save_load_keys() {
         const FILES_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/files/");
         let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
@@ -443,8 +1790,8 @@ mod tests {
 
         // Ensure the loaded keys match the original keys
         assert_eq!(
-            e2ee.get_private_key_pem(),
-            loaded_e2ee.get_private_key_pem()
+            &*e2ee.get_private_key_pem().unwrap(),
+            &*loaded_e2ee.get_private_key_pem().unwrap()
         );
         assert_eq!(e2ee.get_public_key_pem(), loaded_e2ee.get_public_key_pem());
 
@@ -455,6 +1802,272 @@ mod tests {
             .expect("Failed to delete public key file");
     }
 
+    #[test]
+    #[cfg(feature = "io")]
+    fn test_new_from_pem_rejects_mismatched_public_keys() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let other = E2ee::new(KeySize::Bit1024).unwrap();
+        let result = E2ee::new_from_pem(
+            e2ee.get_private_key_pem().unwrap().to_string(),
+            other.get_public_key_pem().to_string(),
+        );
+        assert!(matches!(result, Err(E2eeError::InvalidPublicKey(_))));
+        assert!(E2ee::new_from_pem(
+            e2ee.get_private_key_pem().unwrap().to_string(),
+            e2ee.get_public_key_pem().to_string(),
+        )
+        .is_ok());
+    }
+
+    /// Tests that decryption failures are reported uniformly by default.
+    ///
+    /// Invalid base64, an invalid padding and invalid UTF-8 must all produce the same
+    /// `DecryptionFailed` error, while `detailed_errors` reveals the underlying cause.
+    #[test]
+    fn test_decrypt_errors_are_uniform() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let not_utf8 = general_purpose::STANDARD_NO_PAD
+            .encode(core::encrypt(e2ee.get_public_key(), &[0xff, 0xfe]).unwrap());
+        let bad_padding = general_purpose::STANDARD_NO_PAD
+            .encode(vec![1u8; e2ee.private_key.size()]);
+
+        let errors: Vec<String> = ["invalid_base64_string", &bad_padding, &not_utf8]
+            .iter()
+            .map(|ciphertext| {
+                let err = e2ee.decrypt(ciphertext).unwrap_err();
+                assert!(matches!(err, E2eeError::DecryptionFailed));
+                err.to_string()
+            })
+            .collect();
+        assert!(errors.windows(2).all(|pair| pair[0] == pair[1]));
+
+        let e2ee = e2ee.detailed_errors();
+        assert!(matches!(
+            e2ee.decrypt("invalid_base64_string"),
+            Err(E2eeError::Decoding(_))
+        ));
+        assert!(matches!(e2ee.decrypt(&bad_padding), Err(E2eeError::Rsa(_))));
+        assert!(matches!(
+            e2ee.decrypt(&not_utf8),
+            Err(E2eeError::Encoding(_))
+        ));
+    }
+
+    /// Tests that blinded and unblinded operations produce the same results, and that `warm_up`
+    /// detects a public key that does not match the private key.
+    #[test]
+    fn test_blinding_and_warm_up() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        e2ee.warm_up().unwrap();
+        let encrypted = e2ee.encrypt("Hello blinding!").unwrap();
+        let signature = e2ee.sign(b"Hello blinding!").unwrap();
+
+        let e2ee = e2ee.with_blinding(false);
+        e2ee.warm_up().unwrap();
+        assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello blinding!");
+        assert_eq!(e2ee.sign(b"Hello blinding!").unwrap(), signature);
+
+        let mut mismatched = E2ee::new(KeySize::Bit1024).unwrap();
+        mismatched.public_key = e2ee.public_key.clone();
+        assert!(matches!(
+            mismatched.warm_up(),
+            Err(E2eeError::DecryptionFailed)
+        ));
+    }
+
+    /// Tests that `health_check` runs every check and reports the failing ones.
+    #[test]
+    fn test_health_check() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let report = e2ee.health_check();
+        assert!(report.is_healthy());
+        assert_eq!(report.key_bits, 2048);
+        assert_eq!(report.fingerprint, e2ee.get_fingerprint().ok());
+        assert_eq!(
+            report.to_string(),
+            "key_pair: ok\nkey_size: ok\nround_trip: ok\nenvelope_round_trip: ok\n"
+        );
+
+        let mut mismatched = E2ee::new(KeySize::Bit1024).unwrap();
+        mismatched.public_key = e2ee.public_key.clone();
+        let failures: Vec<_> = mismatched
+            .health_check()
+            .failures()
+            .map(|check| check.kind)
+            .collect();
+        assert_eq!(
+            failures,
+            [
+                HealthCheckKind::KeyPair,
+                HealthCheckKind::RoundTrip,
+                HealthCheckKind::EnvelopeRoundTrip
+            ]
+        );
+    }
+
+    /// Tests that expired envelopes are rejected with a distinct error, even without detailed
+    /// errors, unless expiry checks are disabled.
+    #[test]
+    fn test_expired_envelopes_are_rejected() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let fresh = e2ee
+            .encrypt_with_expiry(b"short-lived", Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(e2ee.decrypt_envelope(&fresh).unwrap(), b"short-lived");
+        let expired = e2ee
+            .encrypt_with_expiry(b"short-lived", Duration::ZERO)
+            .unwrap();
+        assert!(matches!(
+            e2ee.decrypt_envelope(&expired),
+            Err(E2eeError::Envelope(
+                crate::envelope::EnvelopeError::Expired { .. }
+            ))
+        ));
+        let e2ee = e2ee.with_expiry_check(false);
+        assert_eq!(e2ee.decrypt_envelope(&expired).unwrap(), b"short-lived");
+
+        // A time to live beyond the range of `SystemTime` is rejected.
+        assert!(matches!(
+            e2ee.encrypt_with_expiry(b"short-lived", Duration::MAX),
+            Err(E2eeError::InvalidExpiry)
+        ));
+        let public = crate::client::PublicE2ee::from_public_key(
+            e2ee.get_public_key().clone(),
+        )
+        .unwrap();
+        assert!(matches!(
+            public.encrypt_with_expiry(b"short-lived", Duration::MAX),
+            Err(crate::client::PublicE2eeError::InvalidExpiry)
+        ));
+    }
+
+    /// Tests that binary messages round-trip through the `Bytes` API, including with a
+    /// `PublicE2ee`, and that the plaintext need not be UTF-8.
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_bytes_roundtrip() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let public = crate::client::PublicE2ee::from_public_key(
+            e2ee.get_public_key().clone(),
+        )
+        .unwrap();
+        let message = bytes::Bytes::from_static(&[0xff, 0x00, 0xfe]);
+        for encrypted in [
+            e2ee.encrypt_bytes_ref(&message).unwrap(),
+            public.encrypt_bytes_ref(&message).unwrap(),
+        ] {
+            assert_eq!(e2ee.decrypt_bytes_ref(&encrypted).unwrap(), message);
+        }
+        assert!(matches!(
+            e2ee.decrypt_bytes_ref(b"garbage"),
+            Err(E2eeError::DecryptionFailed)
+        ));
+    }
+
+    /// Tests that `decrypt_into` fills the caller's buffer, and reports the needed length when it
+    /// is too small.
+    #[test]
+    fn test_decrypt_into() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let encrypted = e2ee.encrypt("Hello, world!").unwrap();
+        let mut buffer = [0xaa; 13];
+        assert_eq!(e2ee.decrypt_into(&encrypted, &mut buffer).unwrap(), 13);
+        assert_eq!(&buffer, b"Hello, world!");
+
+        let mut small = [0xaa; 12];
+        assert!(matches!(
+            e2ee.decrypt_into(&encrypted, &mut small),
+            Err(E2eeError::BufferTooSmall { needed: 13 })
+        ));
+        assert_eq!(small, [0xaa; 12]);
+        assert!(matches!(
+            e2ee.decrypt_into("invalid", &mut buffer),
+            Err(E2eeError::DecryptionFailed)
+        ));
+    }
+
+    /// Tests that key generation, encryption, blinding and envelopes draw from an injected RNG.
+    #[test]
+    fn test_injected_rng_is_used() {
+        let rng = crate::rng::tests::CountingRng::default();
+        let e2ee = E2ee::new_with_rng(KeySize::Bit1024, rng.clone()).unwrap();
+        let mut drawn = rng.drawn();
+        assert!(drawn > 0);
+
+        let encrypted = e2ee.encrypt("Hello, world!").unwrap();
+        assert!(rng.drawn() > drawn);
+        drawn = rng.drawn();
+        assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello, world!");
+        assert!(rng.drawn() > drawn);
+        drawn = rng.drawn();
+        let envelope = e2ee.clone().encrypt_envelope(b"payload").unwrap();
+        assert!(rng.drawn() > drawn);
+        assert_eq!(e2ee.decrypt_envelope(&envelope).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn test_envelope_mac_detects_tampering() {
+        let mac = Mac::new(&[7; 32]);
+        let e2ee = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .with_envelope_mac(mac.clone())
+            .detailed_errors();
+        let client = crate::client::PublicE2ee::from_public_key(
+            e2ee.get_public_key().clone(),
+        )
+        .unwrap()
+        .with_envelope_mac(mac);
+        let encrypted = client.encrypt("Hello, world!").unwrap();
+        assert_eq!(e2ee.decrypt(&encrypted).unwrap(), "Hello, world!");
+
+        let mut tampered =
+            general_purpose::STANDARD_NO_PAD.decode(&encrypted).unwrap();
+        tampered[10] ^= 1;
+        assert!(matches!(
+            e2ee.decrypt(&general_purpose::STANDARD_NO_PAD.encode(&tampered)),
+            Err(E2eeError::Mac(crate::mac::MacError::InvalidTag))
+        ));
+        let unauthenticated = E2ee::from_private_key(e2ee.get_private_key().clone())
+            .unwrap()
+            .encrypt("Hello, world!")
+            .unwrap();
+        assert!(e2ee.decrypt(&unauthenticated).is_err());
+    }
+
+    /// Tests that a shared instance serves concurrent encryptions and decryptions.
+    #[test]
+    fn test_shared_across_threads() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap().into_shared();
+        let workers: Vec<_> = (0..4)
+            .map(|worker| {
+                let e2ee = Arc::clone(&e2ee);
+                std::thread::spawn(move || {
+                    for i in 0..8 {
+                        let message = format!("worker {worker}, message {i}");
+                        let encrypted = e2ee.encrypt(&message).unwrap();
+                        assert_eq!(e2ee.decrypt(&encrypted).unwrap(), message);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    /// Tests that clones decrypt each other's ciphertexts and compare equal by key pair only.
+    #[test]
+    fn test_clone_and_eq() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let clone = e2ee.clone().detailed_errors();
+        assert_eq!(e2ee, clone);
+        assert_eq!(
+            clone.decrypt(&e2ee.encrypt("Hello").unwrap()).unwrap(),
+            "Hello"
+        );
+        assert_ne!(e2ee, E2ee::new(KeySize::Bit1024).unwrap());
+    }
+
     /// Tests decryption with invalid base64-encoded ciphertext.
     ///
     /// This test ensures that attempting to decrypt a ciphertext that is not valid base64
@@ -466,4 +2079,49 @@ mod tests {
         let result = e2ee.decrypt(invalid_ciphertext);
         assert!(result.is_err());
     }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_records_attempted_parameters() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Clone, Default)]
+        struct Captured(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Captured {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let message = "Do not log this plaintext";
+        tracing::subscriber::with_default(subscriber, || {
+            let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+            let encrypted = e2ee.encrypt(message).unwrap();
+            assert_eq!(e2ee.decrypt(&encrypted).unwrap(), message);
+            assert!(e2ee.decrypt("not base64!").is_err());
+            let envelope = e2ee.encrypt_envelope(message.as_bytes()).unwrap();
+            e2ee.decrypt_envelope(&envelope).unwrap();
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        assert!(output.contains("oaep_hash=\"SHA-256\""));
+        assert!(output.contains("encoding=Base64NoPad"));
+        assert!(output.contains("Decoding error"));
+        assert!(output.contains("aead=\"AES-256-GCM\""));
+        assert!(!output.contains(message));
+    }
 }
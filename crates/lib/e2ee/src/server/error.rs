@@ -18,6 +18,45 @@ pub enum E2eeError {
     #[error("Decoding error: {0}")]
     Decoding(#[from] base64::DecodeError),
 
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] crate::envelope::EnvelopeError),
+
+    #[error("MAC error: {0}")]
+    Mac(#[from] crate::mac::MacError),
+
+    #[error("Key generation error: {0}")]
+    Keygen(#[from] crate::keygen::KeygenError),
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+
+    #[error("The output buffer is too small, {needed} bytes are needed")]
+    BufferTooSmall { needed: usize },
+
     #[error("File write error: {0}")]
     FileWriteError(String),
+
+    #[error("Audit log error: {0}")]
+    AuditLog(std::io::Error),
+
+    #[error("Secure memory error: {0}")]
+    SecureMemory(std::io::Error),
+
+    #[error("Too many failed decryptions, retry after {retry_after:?}")]
+    LockedOut { retry_after: std::time::Duration },
+
+    #[error("The ciphertext was already decrypted")]
+    Replayed,
+
+    #[error("Guard store error: {0}")]
+    GuardStore(std::io::Error),
+
+    #[error("Invalid recipient key: {0}")]
+    InvalidRecipientKey(String),
+
+    #[error("Invalid public key: {0}")]
+    InvalidPublicKey(String),
+
+    #[error("The time to live is too long")]
+    InvalidExpiry,
 }
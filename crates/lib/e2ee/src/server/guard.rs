@@ -0,0 +1,372 @@
+use super::{E2ee, E2eeError, E2eeResult};
+use rsa::sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "redis")]
+pub mod redis;
+
+/// The longest lockout or replay window applied: longer ones, e.g. `Duration::MAX`, which
+/// `Instant` cannot represent, are clamped to it and thus permanent in practice.
+const MAX_DURATION: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// Returns `now + duration`, with `duration` clamped to [`MAX_DURATION`].
+fn deadline(now: Instant, duration: Duration) -> Instant {
+    // A century is within the range of `Instant` on every platform.
+    now + duration.min(MAX_DURATION)
+}
+
+/// The throttling policy applied by a [`DecryptGuard`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuardPolicy {
+    /// The number of failed decryptions a source may cause within `window` before it is locked out.
+    pub max_failures: u32,
+    /// The period over which failures are counted.
+    pub window: Duration,
+    /// How long a source stays locked out once it reached `max_failures`, at most a century.
+    pub lockout: Duration,
+}
+
+impl Default for GuardPolicy {
+    /// Five failures per minute, followed by a five-minute lockout.
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(300),
+        }
+    }
+}
+
+/// The state of a [`DecryptGuard`]: failure counts and lockouts per source, and the ciphertexts
+/// decrypted recently.
+///
+/// [`MemoryGuardStore`] keeps it in the process, and `redis::RedisGuardStore` (with the `redis`
+/// feature) in Redis, shared by every instance of a service.
+pub trait GuardStore<K>: Send + Sync {
+    /// Returns how long `source` remains locked out, or `None` if it may decrypt.
+    fn retry_after(&self, source: &K) -> io::Result<Option<Duration>>;
+
+    /// Counts a failed decryption by `source`, locking it out for `policy.lockout` once it
+    /// caused `policy.max_failures` within `policy.window`.
+    fn record_failure(&self, source: &K, policy: &GuardPolicy) -> io::Result<()>;
+
+    /// Records that the ciphertext with the SHA-256 `digest` was decrypted, and returns `false`
+    /// if it already was within the last `window`.
+    ///
+    /// Implementations must check and record atomically, so that concurrent decryptions of the
+    /// same ciphertext are not both accepted.
+    fn record_decryption(
+        &self,
+        digest: &[u8; 32],
+        window: Duration,
+    ) -> io::Result<bool>;
+}
+
+#[derive(Debug)]
+struct FailureState {
+    failures: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl FailureState {
+    fn is_stale(&self, now: Instant, policy: &GuardPolicy) -> bool {
+        self.locked_until.is_none_or(|until| until <= now)
+            && now.duration_since(self.window_start) >= policy.window
+    }
+}
+
+/// A [`GuardStore`] keeping the state of a single process in memory.
+#[derive(Debug)]
+pub struct MemoryGuardStore<K> {
+    sources: Mutex<HashMap<K, FailureState>>,
+    decrypted: Mutex<HashMap<[u8; 32], Instant>>,
+}
+
+impl<K> MemoryGuardStore<K> {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self {
+            sources: Mutex::new(HashMap::new()),
+            decrypted: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K> Default for MemoryGuardStore<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send> GuardStore<K> for MemoryGuardStore<K> {
+    fn retry_after(&self, source: &K) -> io::Result<Option<Duration>> {
+        let now = Instant::now();
+        let sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(sources
+            .get(source)
+            .and_then(|state| state.locked_until)
+            .filter(|&until| until > now)
+            .map(|until| until - now))
+    }
+
+    fn record_failure(&self, source: &K, policy: &GuardPolicy) -> io::Result<()> {
+        let now = Instant::now();
+        let mut sources = self.sources.lock().unwrap_or_else(|e| e.into_inner());
+        sources.retain(|_, state| !state.is_stale(now, policy));
+
+        let state = sources.entry(source.clone()).or_insert(FailureState {
+            failures: 0,
+            window_start: now,
+            locked_until: None,
+        });
+        if now.duration_since(state.window_start) >= policy.window {
+            state.failures = 0;
+            state.window_start = now;
+        }
+        state.failures += 1;
+        if state.failures >= policy.max_failures {
+            state.failures = 0;
+            state.window_start = now;
+            state.locked_until = Some(deadline(now, policy.lockout));
+        }
+        Ok(())
+    }
+
+    fn record_decryption(
+        &self,
+        digest: &[u8; 32],
+        window: Duration,
+    ) -> io::Result<bool> {
+        let now = Instant::now();
+        let mut decrypted = self.decrypted.lock().unwrap_or_else(|e| e.into_inner());
+        decrypted.retain(|_, &mut expires| expires > now);
+        if decrypted.contains_key(digest) {
+            return Ok(false);
+        }
+        decrypted.insert(*digest, deadline(now, window));
+        Ok(true)
+    }
+}
+
+/// A wrapper around [`E2ee`] that throttles repeated decryption failures per source.
+///
+/// Services exposing a decryption endpoint can be probed with crafted ciphertexts (e.g. padding
+/// oracle attacks). `DecryptGuard` counts failed decryptions per source, identified by any
+/// hashable key such as a peer ID or IP address, and rejects every request from a source that
+/// exceeded the [`GuardPolicy`] with `E2eeError::LockedOut` until its lockout expires.
+///
+/// Successful decryptions do not reset the failure count, so that an attacker cannot interleave
+/// valid ciphertexts with probes to stay below the limit. With [`DecryptGuard::with_replay_window`],
+/// the guard also rejects ciphertexts it already decrypted within the window with
+/// `E2eeError::Replayed`.
+///
+/// The state lives in a [`GuardStore`], in memory by default. Services running several instances
+/// share it with [`DecryptGuard::with_store`], e.g. over Redis with the `redis` feature, so that
+/// lockouts and replay windows apply across instances. If the store fails, the guard fails closed
+/// with `E2eeError::GuardStore`.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::server::guard::{DecryptGuard, GuardPolicy};
+/// use e2ee::server::{E2ee, KeySize};
+///
+/// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+/// let encrypted = e2ee.encrypt("Secret message").expect("Failed to encrypt message");
+///
+/// let guard = DecryptGuard::new(e2ee, GuardPolicy::default());
+/// let decrypted = guard
+///     .decrypt(&"peer-1".to_string(), &encrypted)
+///     .expect("Failed to decrypt message");
+/// assert_eq!(decrypted, "Secret message");
+/// ```
+pub struct DecryptGuard<K = String> {
+    e2ee: E2ee,
+    policy: GuardPolicy,
+    store: Box<dyn GuardStore<K>>,
+    replay_window: Option<Duration>,
+}
+
+impl<K> fmt::Debug for DecryptGuard<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptGuard")
+            .field("e2ee", &self.e2ee)
+            .field("policy", &self.policy)
+            .field("replay_window", &self.replay_window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + 'static> DecryptGuard<K> {
+    /// Creates a new guard around `e2ee` applying `policy`, with its state in a
+    /// [`MemoryGuardStore`].
+    pub fn new(e2ee: E2ee, policy: GuardPolicy) -> Self {
+        Self {
+            e2ee,
+            policy,
+            store: Box::new(MemoryGuardStore::new()),
+            replay_window: None,
+        }
+    }
+}
+
+impl<K> DecryptGuard<K> {
+    /// Keeps the state of the guard in `store`, e.g. one shared by every instance of a service.
+    pub fn with_store(mut self, store: impl GuardStore<K> + 'static) -> Self {
+        self.store = Box::new(store);
+        self
+    }
+
+    /// Rejects the ciphertexts already decrypted within the last `window` with
+    /// `E2eeError::Replayed`.
+    ///
+    /// The window should cover the lifetime of the ciphertexts, e.g. the expiry of envelopes
+    /// (see `E2ee::encrypt_with_expiry`), since replays are no longer detected after it. It is
+    /// clamped to a century.
+    pub fn with_replay_window(mut self, window: Duration) -> Self {
+        self.replay_window = Some(window);
+        self
+    }
+
+    /// Returns the wrapped `E2ee` instance.
+    pub fn inner(&self) -> &E2ee {
+        &self.e2ee
+    }
+
+    /// Decrypts `ciphertext` on behalf of `source`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::LockedOut` without attempting decryption if `source` is
+    /// locked out, `E2eeError::Replayed` if `ciphertext` was already decrypted within the replay
+    /// window, `E2eeError::GuardStore` if the store fails, and otherwise any error returned by
+    /// `E2ee::decrypt`.
+    pub fn decrypt(&self, source: &K, ciphertext: &str) -> E2eeResult<String> {
+        if let Some(retry_after) = self.retry_after(source)? {
+            return Err(E2eeError::LockedOut { retry_after });
+        }
+        let result = self.e2ee.decrypt(ciphertext);
+        if result.is_err() {
+            self.store
+                .record_failure(source, &self.policy)
+                .map_err(E2eeError::GuardStore)?;
+        } else if let Some(window) = self.replay_window {
+            let digest = Sha256::digest(ciphertext.as_bytes()).into();
+            if !self
+                .store
+                .record_decryption(&digest, window)
+                .map_err(E2eeError::GuardStore)?
+            {
+                return Err(E2eeError::Replayed);
+            }
+        }
+        result
+    }
+
+    /// Returns how long `source` remains locked out, or `None` if it may decrypt.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `E2eeError::GuardStore` if the store fails.
+    pub fn retry_after(&self, source: &K) -> E2eeResult<Option<Duration>> {
+        self.store
+            .retry_after(source)
+            .map_err(E2eeError::GuardStore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    fn guard(policy: GuardPolicy) -> DecryptGuard<&'static str> {
+        DecryptGuard::new(E2ee::new(KeySize::Bit1024).unwrap(), policy)
+    }
+
+    #[test]
+    fn test_guard_locks_out_after_max_failures() {
+        let guard = guard(GuardPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(60),
+        });
+        let encrypted = guard.inner().encrypt("Hello guard!").unwrap();
+
+        for _ in 0..3 {
+            let result = guard.decrypt(&"attacker", "invalid_base64_string");
+            assert!(!matches!(result, Err(E2eeError::LockedOut { .. })));
+        }
+
+        // Even a valid ciphertext is rejected while the source is locked out.
+        let result = guard.decrypt(&"attacker", &encrypted);
+        assert!(matches!(result, Err(E2eeError::LockedOut { .. })));
+        assert!(guard.retry_after(&"attacker").unwrap().is_some());
+
+        // Other sources are not affected.
+        assert_eq!(guard.decrypt(&"peer", &encrypted).unwrap(), "Hello guard!");
+    }
+
+    #[test]
+    fn test_guard_lockout_expires() {
+        let guard = guard(GuardPolicy {
+            max_failures: 1,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_millis(50),
+        });
+        let encrypted = guard.inner().encrypt("Hello guard!").unwrap();
+
+        assert!(guard.decrypt(&"peer", "invalid_base64_string").is_err());
+        assert!(guard.retry_after(&"peer").unwrap().is_some());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(guard.retry_after(&"peer").unwrap(), None);
+        assert_eq!(guard.decrypt(&"peer", &encrypted).unwrap(), "Hello guard!");
+    }
+
+    #[test]
+    fn test_guard_rejects_replays() {
+        let guard = guard(GuardPolicy::default())
+            .with_replay_window(Duration::from_millis(50));
+        let encrypted = guard.inner().encrypt("Hello guard!").unwrap();
+
+        assert_eq!(guard.decrypt(&"peer", &encrypted).unwrap(), "Hello guard!");
+        assert!(matches!(
+            guard.decrypt(&"other", &encrypted),
+            Err(E2eeError::Replayed)
+        ));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(guard.decrypt(&"peer", &encrypted).unwrap(), "Hello guard!");
+    }
+
+    #[test]
+    fn test_guard_clamps_unbounded_durations() {
+        let guard = guard(GuardPolicy {
+            max_failures: 1,
+            window: Duration::MAX,
+            lockout: Duration::MAX,
+        })
+        .with_replay_window(Duration::MAX);
+        let encrypted = guard.inner().encrypt("Hello guard!").unwrap();
+
+        assert_eq!(guard.decrypt(&"peer", &encrypted).unwrap(), "Hello guard!");
+        assert!(matches!(
+            guard.decrypt(&"peer", &encrypted),
+            Err(E2eeError::Replayed)
+        ));
+        assert!(guard.decrypt(&"attacker", "invalid_base64_string").is_err());
+        assert!(
+            guard.retry_after(&"attacker").unwrap().unwrap()
+                > Duration::from_secs(1 << 31)
+        );
+    }
+}
@@ -0,0 +1,308 @@
+//! A [`GuardStore`] over Redis (enabled by the `redis` feature), sharing lockouts and replay
+//! windows between the instances of a horizontally scaled service.
+//!
+//! [`RedisGuardStore`] keeps, under a configurable prefix (`e2ee:guard:` by default):
+//!
+//! - `failures:<source>`: the failures of a source in the current window, expiring with it,
+//! - `lockout:<source>`: the lockout of a source, expiring with it,
+//! - `replay:<digest>`: the hex SHA-256 of a decrypted ciphertext, expiring with the replay
+//!   window.
+//!
+//! Failures are counted and lockouts set by a Lua script, and ciphertexts recorded with
+//! `SET NX`, so that concurrent instances do not race. Expiries are left to Redis, which needs no
+//! clock agreement between instances.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use e2ee::server::guard::{redis::RedisGuardStore, DecryptGuard, GuardPolicy};
+//! use e2ee::server::{E2ee, KeySize};
+//! use std::time::Duration;
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let store = RedisGuardStore::open("redis://127.0.0.1/").expect("Invalid Redis URL");
+//! let guard = DecryptGuard::new(e2ee, GuardPolicy::default())
+//!     .with_store(store)
+//!     .with_replay_window(Duration::from_secs(600));
+//!
+//! let encrypted = guard.inner().encrypt("Secret message").expect("Failed to encrypt message");
+//! let decrypted = guard
+//!     .decrypt(&"203.0.113.7".to_string(), &encrypted)
+//!     .expect("Failed to decrypt message");
+//! ```
+use super::{GuardPolicy, GuardStore, MAX_DURATION};
+use crate::core;
+use ::redis::{Client, Connection, RedisError, Script};
+use std::{fmt, io, sync::Mutex, time::Duration};
+
+/// The default prefix of the keys of a [`RedisGuardStore`].
+pub const DEFAULT_PREFIX: &str = "e2ee:guard:";
+
+/// Counts a failure in `KEYS[1]`, expiring after `ARGV[1]` milliseconds, and once it reaches
+/// `ARGV[2]` failures, clears it and sets the lockout `KEYS[2]` for `ARGV[3]` milliseconds.
+const RECORD_FAILURE: &str = r"
+local failures = redis.call('INCR', KEYS[1])
+if failures == 1 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[1])
+end
+if failures >= tonumber(ARGV[2]) then
+    redis.call('DEL', KEYS[1])
+    redis.call('SET', KEYS[2], '1', 'PX', ARGV[3])
+end
+return failures
+";
+
+/// A [`GuardStore`] keeping the state of a `DecryptGuard` in Redis.
+///
+/// Sources are identified in keys by their `Display` form. The store keeps one connection,
+/// opened on first use and reopened after an error.
+pub struct RedisGuardStore {
+    client: Client,
+    connection: Mutex<Option<Connection>>,
+    prefix: String,
+    record_failure: Script,
+}
+
+impl fmt::Debug for RedisGuardStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisGuardStore")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RedisGuardStore {
+    /// Creates a store over the Redis server at `url`, e.g. `redis://127.0.0.1/`. The connection
+    /// is opened on first use.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidInput` if `url` is not a valid Redis URL.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let client = Client::open(url)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(Self::from_client(client))
+    }
+
+    /// Creates a store over the server of `client`.
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            client,
+            connection: Mutex::new(None),
+            prefix: DEFAULT_PREFIX.to_string(),
+            record_failure: Script::new(RECORD_FAILURE),
+        }
+    }
+
+    /// Sets the prefix of the keys of the store, e.g. to keep the guards of several services
+    /// apart on one server.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Runs `query` on the connection, opening it if needed and dropping it on error.
+    fn with_connection<T>(
+        &self,
+        query: impl FnOnce(&mut Connection) -> Result<T, RedisError>,
+    ) -> io::Result<T> {
+        let mut connection =
+            self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let result = match connection.as_mut() {
+            Some(connection) => query(connection),
+            None => self
+                .client
+                .get_connection()
+                .and_then(|new| query(connection.insert(new))),
+        };
+        result.map_err(|err| {
+            *connection = None;
+            io::Error::other(err)
+        })
+    }
+}
+
+impl<K: fmt::Display> GuardStore<K> for RedisGuardStore {
+    fn retry_after(&self, source: &K) -> io::Result<Option<Duration>> {
+        let key = format!("{}lockout:{source}", self.prefix);
+        let ttl: i64 = self.with_connection(|connection| {
+            ::redis::cmd("PTTL").arg(&key).query(connection)
+        })?;
+        // PTTL is negative for missing keys and keys without expiry.
+        Ok(u64::try_from(ttl)
+            .ok()
+            .filter(|&ttl| ttl > 0)
+            .map(Duration::from_millis))
+    }
+
+    fn record_failure(&self, source: &K, policy: &GuardPolicy) -> io::Result<()> {
+        let failures = format!("{}failures:{source}", self.prefix);
+        let lockout = format!("{}lockout:{source}", self.prefix);
+        self.with_connection(|connection| {
+            self.record_failure
+                .key(&failures)
+                .key(&lockout)
+                .arg(millis(policy.window))
+                .arg(policy.max_failures.max(1))
+                .arg(millis(policy.lockout))
+                .invoke::<i64>(connection)
+        })?;
+        Ok(())
+    }
+
+    fn record_decryption(
+        &self,
+        digest: &[u8; 32],
+        window: Duration,
+    ) -> io::Result<bool> {
+        let key = format!("{}replay:{}", self.prefix, core::hex(digest));
+        let set: Option<String> = self.with_connection(|connection| {
+            ::redis::cmd("SET")
+                .arg(&key)
+                .arg(1)
+                .arg("NX")
+                .arg("PX")
+                .arg(millis(window))
+                .query(connection)
+        })?;
+        Ok(set.is_some())
+    }
+}
+
+/// Returns `duration` in milliseconds, at least one since Redis rejects zero expiries, and
+/// clamped like the in-memory store since Redis rejects expiries past the range of its clock.
+fn millis(duration: Duration) -> u64 {
+    // A century in milliseconds fits in a `u64`.
+    (duration.min(MAX_DURATION).as_millis() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{guard::DecryptGuard, E2ee, E2eeError, KeySize};
+    use rsa::rand_core::{OsRng, RngCore};
+    use std::thread;
+
+    /// Returns a store with a prefix of its own on the Redis server at `REDIS_URL`, and a
+    /// connection to check and clean up its keys, or `None` to skip the test when `REDIS_URL` is
+    /// not set.
+    fn redis_store() -> Option<(RedisGuardStore, Connection, String)> {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            eprintln!("Skipping test: REDIS_URL is not set");
+            return None;
+        };
+        let prefix = format!("e2ee:test:{:016x}:", OsRng.next_u64());
+        let store = RedisGuardStore::open(&url).unwrap().with_prefix(&prefix);
+        let connection = Client::open(url).unwrap().get_connection().unwrap();
+        Some((store, connection, prefix))
+    }
+
+    fn pttl(connection: &mut Connection, key: &str) -> i64 {
+        ::redis::cmd("PTTL").arg(key).query(connection).unwrap()
+    }
+
+    fn clean_up(connection: &mut Connection, prefix: &str) {
+        let keys: Vec<String> = ::redis::cmd("KEYS")
+            .arg(format!("{prefix}*"))
+            .query(connection)
+            .unwrap();
+        if !keys.is_empty() {
+            ::redis::cmd("DEL").arg(keys).exec(connection).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_redis_store_locks_out_sources() {
+        let Some((store, mut connection, prefix)) = redis_store() else {
+            return;
+        };
+        let source = "203.0.113.7".to_string();
+        let policy = GuardPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_secs(10),
+        };
+        let failures = format!("{prefix}failures:{source}");
+        let lockout = format!("{prefix}lockout:{source}");
+
+        for _ in 0..2 {
+            store.record_failure(&source, &policy).unwrap();
+            assert_eq!(store.retry_after(&source).unwrap(), None);
+        }
+        // The window starts at the first failure and is not extended by the next ones.
+        assert!((1..=60_000).contains(&pttl(&mut connection, &failures)));
+        store.record_failure(&source, &policy).unwrap();
+        let retry_after = store.retry_after(&source).unwrap().unwrap();
+        assert!(retry_after > Duration::from_secs(9));
+        assert!(retry_after <= Duration::from_secs(10));
+        // The failures are cleared with the lockout, which expires on its own.
+        assert_eq!(pttl(&mut connection, &failures), -2);
+        assert!((1..=10_000).contains(&pttl(&mut connection, &lockout)));
+
+        // Unbounded durations are clamped to what Redis accepts.
+        let other = "198.51.100.1".to_string();
+        let unbounded = GuardPolicy {
+            max_failures: 0,
+            window: Duration::MAX,
+            lockout: Duration::MAX,
+        };
+        store.record_failure(&other, &unbounded).unwrap();
+        let retry_after = store.retry_after(&other).unwrap().unwrap();
+        assert!(retry_after > MAX_DURATION - Duration::from_secs(60));
+        clean_up(&mut connection, &prefix);
+    }
+
+    #[test]
+    fn test_redis_store_rejects_replays_within_the_window() {
+        let Some((store, mut connection, prefix)) = redis_store() else {
+            return;
+        };
+        let record = |digest: &[u8; 32], window| {
+            GuardStore::<String>::record_decryption(&store, digest, window).unwrap()
+        };
+        let window = Duration::from_secs(60);
+        assert!(record(&[1; 32], window));
+        assert!(!record(&[1; 32], window));
+        assert!(record(&[2; 32], window));
+        let replay = format!("{prefix}replay:{}", "01".repeat(32));
+        assert!((1..=60_000).contains(&pttl(&mut connection, &replay)));
+
+        // A ciphertext is accepted again once its window expired, and a zero window is kept
+        // for a millisecond rather than rejected.
+        assert!(record(&[3; 32], Duration::ZERO));
+        thread::sleep(Duration::from_millis(20));
+        assert!(record(&[3; 32], Duration::ZERO));
+
+        // Concurrent decryptions of the same ciphertext are accepted once.
+        let accepted = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| scope.spawn(|| record(&[4; 32], window)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .filter(|&accepted| accepted)
+                .count()
+        });
+        assert_eq!(accepted, 1);
+        clean_up(&mut connection, &prefix);
+    }
+
+    #[test]
+    fn test_guard_fails_closed_without_redis() {
+        // Nothing listens on port 1.
+        let store = RedisGuardStore::open("redis://127.0.0.1:1/").unwrap();
+        let guard = DecryptGuard::new(
+            E2ee::new(KeySize::Bit1024).unwrap(),
+            GuardPolicy::default(),
+        )
+        .with_store(store);
+        let encrypted = guard.inner().encrypt("Hello guard!").unwrap();
+
+        assert!(matches!(
+            guard.decrypt(&"peer".to_string(), &encrypted),
+            Err(E2eeError::GuardStore(_))
+        ));
+        assert!(RedisGuardStore::open("not a url").is_err());
+    }
+}
@@ -0,0 +1,95 @@
+//! The report of [`E2ee::health_check`](super::E2ee::health_check), for the readiness probes of
+//! services.
+use std::{fmt, time::Duration};
+
+/// The smallest modulus, in bits, that passes the key size check.
+pub const MIN_HEALTHY_MODULUS_BITS: usize = 2048;
+
+/// A check run by `E2ee::health_check`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthCheckKind {
+    /// The private key is consistent, and matches the public key of the instance.
+    KeyPair,
+    /// The modulus is at least [`MIN_HEALTHY_MODULUS_BITS`] long.
+    KeySize,
+    /// A canary encrypted with the OAEP parameters of the instance decrypts back to itself.
+    RoundTrip,
+    /// A canary envelope sealed with the AEAD and format of the instance opens back to itself.
+    EnvelopeRoundTrip,
+}
+
+impl HealthCheckKind {
+    /// Returns the name of the check, in snake case.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthCheckKind::KeyPair => "key_pair",
+            HealthCheckKind::KeySize => "key_size",
+            HealthCheckKind::RoundTrip => "round_trip",
+            HealthCheckKind::EnvelopeRoundTrip => "envelope_round_trip",
+        }
+    }
+}
+
+impl fmt::Display for HealthCheckKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The outcome of one check of a [`HealthReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthCheck {
+    /// The check.
+    pub kind: HealthCheckKind,
+    /// Why the check failed, or `None` if it passed.
+    pub failure: Option<String>,
+    /// How long the check took.
+    pub duration: Duration,
+}
+
+impl HealthCheck {
+    /// Returns whether the check passed.
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// The outcome of `E2ee::health_check`.
+///
+/// Its `Display` implementation writes one `name: ok` or `name: failed (reason)` line per check,
+/// to be returned as the body of a readiness endpoint. Failures never include key material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    /// The fingerprint of the public key, or `None` if it cannot be computed.
+    pub fingerprint: Option<String>,
+    /// The size of the modulus, in bits.
+    pub key_bits: usize,
+    /// The checks, in the order they ran.
+    pub checks: Vec<HealthCheck>,
+}
+
+impl HealthReport {
+    /// Returns whether every check passed.
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(HealthCheck::passed)
+    }
+
+    /// Returns the checks that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &HealthCheck> {
+        self.checks.iter().filter(|check| !check.passed())
+    }
+}
+
+impl fmt::Display for HealthReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.failure {
+                None => writeln!(f, "{}: ok", check.kind)?,
+                Some(failure) => {
+                    writeln!(f, "{}: failed ({})", check.kind, failure)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
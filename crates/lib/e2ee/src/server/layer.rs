@@ -0,0 +1,350 @@
+//! A [`tower`] layer decrypting envelope request bodies and encrypting responses, for `axum` and
+//! other `tower`-based web frameworks (enabled by the `axum` feature).
+use super::E2ee;
+use crate::envelope::{self, CLIENT_KEY_HEADER, ORIGINAL_CONTENT_TYPE_HEADER};
+use axum::{
+    body::{self, Body},
+    http::{header, HeaderMap, HeaderValue, Request, Response, StatusCode},
+};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// The default largest request or response body accepted by [`EnvelopeLayer`]: 16 MiB.
+pub const DEFAULT_BODY_LIMIT: usize = 16 * 1024 * 1024;
+
+/// A layer that decrypts request bodies sent as envelopes, and optionally encrypts responses for
+/// the client key supplied in the request.
+///
+/// Requests with the `application/vnd.e2ee.envelope` content type are decrypted with the server
+/// `E2ee` before reaching the inner service, which sees the original body and content type.
+/// Envelopes that fail to decrypt are rejected with `400 Bad Request`, without detail. Other
+/// requests pass through unchanged.
+///
+/// With [`EnvelopeLayer::encrypt_responses`], responses to requests carrying an
+/// `e2ee-client-key` header are encrypted for that key, as expected by the `e2ee-http-client`
+/// middleware.
+///
+/// # Examples
+///
+/// ```
+/// use axum::{routing::post, Router};
+/// use e2ee::server::layer::EnvelopeLayer;
+/// use e2ee::server::{E2ee, KeySize};
+///
+/// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+/// let app: Router = Router::new()
+///     .route("/messages", post(|body: String| async move { body }))
+///     .layer(EnvelopeLayer::new(e2ee).encrypt_responses());
+/// ```
+#[derive(Debug, Clone)]
+pub struct EnvelopeLayer {
+    e2ee: Arc<E2ee>,
+    encrypt_responses: bool,
+    body_limit: usize,
+}
+
+impl EnvelopeLayer {
+    /// Creates a layer decrypting request bodies with `e2ee`.
+    pub fn new(e2ee: E2ee) -> Self {
+        Self::from_shared(Arc::new(e2ee))
+    }
+
+    /// Creates a layer decrypting request bodies with an `E2ee` instance shared with the rest of
+    /// the application.
+    pub fn from_shared(e2ee: Arc<E2ee>) -> Self {
+        Self {
+            e2ee,
+            encrypt_responses: false,
+            body_limit: DEFAULT_BODY_LIMIT,
+        }
+    }
+
+    /// Encrypts responses for the public key in the `e2ee-client-key` header of the request, when
+    /// there is one. Requests with an invalid client key are rejected with `400 Bad Request`.
+    pub fn encrypt_responses(mut self) -> Self {
+        self.encrypt_responses = true;
+        self
+    }
+
+    /// Sets the largest request or response body, in bytes, that the layer buffers. Larger bodies
+    /// are rejected with `413 Payload Too Large`.
+    pub fn with_body_limit(mut self, limit: usize) -> Self {
+        self.body_limit = limit;
+        self
+    }
+}
+
+impl<S> Layer<S> for EnvelopeLayer {
+    type Service = EnvelopeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        EnvelopeService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service produced by [`EnvelopeLayer`].
+#[derive(Debug, Clone)]
+pub struct EnvelopeService<S> {
+    inner: S,
+    layer: EnvelopeLayer,
+}
+
+impl<S> Service<Request<Body>> for EnvelopeService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response<Body>;
+    type Error = S::Error;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Response<Body>, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // Use the service that was driven to readiness, leaving a fresh clone in its place.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+        Box::pin(async move {
+            let client_key = match layer.client_key(request.headers()) {
+                Ok(client_key) => client_key,
+                Err(status) => return Ok(status_response(status)),
+            };
+            let request = match layer.decrypt_request(request).await {
+                Ok(request) => request,
+                Err(status) => return Ok(status_response(status)),
+            };
+            let response = inner.call(request).await?;
+            match client_key {
+                Some(client_key) => Ok(layer
+                    .encrypt_response(response, &client_key)
+                    .await
+                    .unwrap_or_else(status_response)),
+                None => Ok(response),
+            }
+        })
+    }
+}
+
+impl EnvelopeLayer {
+    fn client_key(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<Option<rsa::RsaPublicKey>, StatusCode> {
+        if !self.encrypt_responses {
+            return Ok(None);
+        }
+        headers
+            .get(CLIENT_KEY_HEADER)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| envelope::decode_client_key(value).ok())
+                    .ok_or(StatusCode::BAD_REQUEST)
+            })
+            .transpose()
+    }
+
+    async fn decrypt_request(
+        &self,
+        request: Request<Body>,
+    ) -> Result<Request<Body>, StatusCode> {
+        if !is_envelope(request.headers()) {
+            return Ok(request);
+        }
+        let (mut parts, body) = request.into_parts();
+        let encrypted = body::to_bytes(body, self.body_limit)
+            .await
+            .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+        let payload = self
+            .e2ee
+            .decrypt_envelope(&encrypted)
+            .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        parts.headers.remove(header::CONTENT_TYPE);
+        parts.headers.remove(header::CONTENT_LENGTH);
+        if let Some(content_type) =
+            parts.headers.remove(ORIGINAL_CONTENT_TYPE_HEADER)
+        {
+            parts.headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(payload.len()));
+        Ok(Request::from_parts(parts, Body::from(payload)))
+    }
+
+    async fn encrypt_response(
+        &self,
+        response: Response<Body>,
+        client_key: &rsa::RsaPublicKey,
+    ) -> Result<Response<Body>, StatusCode> {
+        let (mut parts, body) = response.into_parts();
+        let payload = body::to_bytes(body, self.body_limit)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let encrypted = envelope::seal(client_key, &payload)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        parts.headers.remove(header::CONTENT_LENGTH);
+        if let Some(content_type) = parts.headers.remove(header::CONTENT_TYPE) {
+            parts
+                .headers
+                .insert(ORIGINAL_CONTENT_TYPE_HEADER, content_type);
+        }
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(envelope::CONTENT_TYPE),
+        );
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(encrypted.len()));
+        Ok(Response::from_parts(parts, Body::from(encrypted)))
+    }
+}
+
+/// Returns whether `headers` declare an envelope body.
+fn is_envelope(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| {
+            media_type
+                .trim()
+                .eq_ignore_ascii_case(envelope::CONTENT_TYPE)
+        })
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    fn app(e2ee: Arc<E2ee>) -> Router {
+        Router::new()
+            .route(
+                "/echo",
+                post(|headers: HeaderMap, body: String| async move {
+                    let content_type = headers
+                        .get(header::CONTENT_TYPE)
+                        .map_or("none".to_string(), |value| {
+                            value.to_str().unwrap().to_string()
+                        });
+                    format!("{content_type}:{body}")
+                }),
+            )
+            .layer(EnvelopeLayer::from_shared(e2ee).encrypt_responses())
+    }
+
+    fn envelope_request(
+        e2ee: &E2ee,
+        body: &str,
+        client_key: Option<&str>,
+    ) -> Request<Body> {
+        let mut builder = Request::post("/echo")
+            .header(header::CONTENT_TYPE, envelope::CONTENT_TYPE)
+            .header(ORIGINAL_CONTENT_TYPE_HEADER, "text/plain");
+        if let Some(client_key) = client_key {
+            builder = builder.header(CLIENT_KEY_HEADER, client_key);
+        }
+        builder
+            .body(Body::from(e2ee.encrypt_envelope(body.as_bytes()).unwrap()))
+            .unwrap()
+    }
+
+    async fn body_bytes(response: Response<Body>) -> Vec<u8> {
+        body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap()
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_layer_decrypts_requests() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let response = app(Arc::clone(&e2ee))
+            .oneshot(envelope_request(&e2ee, "Hello, layer!", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_bytes(response).await, b"text/plain:Hello, layer!");
+
+        // Plain requests pass through unchanged.
+        let response = app(e2ee)
+            .oneshot(Request::post("/echo").body(Body::from("plain")).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(body_bytes(response).await, b"none:plain");
+    }
+
+    #[tokio::test]
+    async fn test_layer_encrypts_responses_for_client_key() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let client = E2ee::new(KeySize::Bit2048).unwrap();
+        let client_key =
+            envelope::encode_client_key(client.get_public_key()).unwrap();
+        let response = app(Arc::clone(&e2ee))
+            .oneshot(envelope_request(&e2ee, "Hello, layer!", Some(&client_key)))
+            .await
+            .unwrap();
+        assert!(is_envelope(response.headers()));
+        assert!(response.headers()[ORIGINAL_CONTENT_TYPE_HEADER]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let encrypted = body_bytes(response).await;
+        assert_eq!(
+            client.decrypt_envelope(&encrypted).unwrap(),
+            b"text/plain:Hello, layer!"
+        );
+
+        let response = app(Arc::clone(&e2ee))
+            .oneshot(envelope_request(&e2ee, "Hello, layer!", Some("invalid")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_layer_rejects_invalid_envelopes() {
+        let e2ee = Arc::new(E2ee::new(KeySize::Bit2048).unwrap());
+        let other = E2ee::new(KeySize::Bit2048).unwrap();
+        let response = app(Arc::clone(&e2ee))
+            .oneshot(envelope_request(&other, "Hello, layer!", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let app = Router::new()
+            .route("/echo", post(|body: String| async move { body }))
+            .layer(
+                EnvelopeLayer::from_shared(Arc::clone(&e2ee)).with_body_limit(16),
+            );
+        let response = app
+            .oneshot(envelope_request(&e2ee, "Hello, layer!", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -0,0 +1,226 @@
+//! Decryption of the ciphertexts of earlier versions of this crate alongside envelopes, to upgrade
+//! stored data lazily.
+use super::{E2ee, E2eeError, E2eeResult};
+use crate::audit::AuditOperation;
+use crate::envelope::Envelope;
+use crate::interop::{Encoding, InteropConfig};
+
+/// The format of a ciphertext recognized by [`detect_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CiphertextFormat {
+    /// The bare base64 RSA-OAEP output of `E2ee::encrypt`, as stored by earlier versions of this
+    /// crate.
+    Legacy,
+    /// A binary envelope, from `E2ee::encrypt_envelope`.
+    Envelope,
+    /// A JSON envelope, from `E2ee::encrypt_envelope` with `EnvelopeFormat::Json`.
+    JsonEnvelope,
+}
+
+/// Recognizes the format of `ciphertext` without decrypting it, or returns `None` if it is neither
+/// an envelope nor base64 text.
+///
+/// The first byte of a binary envelope is its version, which is never a base64 character, so the
+/// formats cannot be mistaken for one another.
+pub fn detect_format(ciphertext: &[u8]) -> Option<CiphertextFormat> {
+    #[cfg(feature = "io")]
+    if crate::envelope::json::is_json(ciphertext) {
+        return Some(CiphertextFormat::JsonEnvelope);
+    }
+    if Envelope::parse(ciphertext).is_ok() {
+        return Some(CiphertextFormat::Envelope);
+    }
+    let text = std::str::from_utf8(ciphertext).ok()?;
+    [
+        Encoding::Base64NoPad,
+        Encoding::Base64,
+        Encoding::Base64UrlNoPad,
+    ]
+    .iter()
+    .any(|encoding| encoding.decode(text.trim()).is_ok())
+    .then_some(CiphertextFormat::Legacy)
+}
+
+/// A plaintext decrypted by a [`LegacyDecryptor`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decrypted {
+    /// The format the ciphertext was in.
+    pub format: CiphertextFormat,
+    /// The OAEP parameters and encoding of a legacy ciphertext, or `None` for an envelope.
+    pub config: Option<InteropConfig>,
+    /// The plaintext.
+    pub plaintext: Vec<u8>,
+}
+
+impl Decrypted {
+    /// Returns whether the ciphertext was in the legacy format, and should be upgraded.
+    pub fn is_legacy(&self) -> bool {
+        self.format == CiphertextFormat::Legacy
+    }
+}
+
+/// A wrapper around [`E2ee`] decrypting both envelopes and the bare base64 ciphertexts of
+/// `E2ee::encrypt`, the format earlier versions of this crate stored, so that existing data stays
+/// readable after switching to envelopes.
+///
+/// Legacy ciphertexts are decrypted with the `InteropConfig` of the instance, then with the
+/// configurations added with [`LegacyDecryptor::with_legacy_config`], e.g. when the default
+/// changed between versions. [`LegacyDecryptor::upgrade`] rewrites legacy ciphertexts as
+/// envelopes, to migrate records as they are read rather than all at once.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::server::legacy::{CiphertextFormat, LegacyDecryptor};
+/// use e2ee::server::{E2ee, KeySize};
+///
+/// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+/// let legacy = e2ee.encrypt("Stored in 2023").expect("Failed to encrypt message");
+/// let envelope = e2ee.encrypt_envelope(b"Stored today").expect("Failed to encrypt payload");
+///
+/// let decryptor = LegacyDecryptor::new(e2ee);
+/// let decrypted = decryptor.decrypt(legacy.as_bytes()).expect("Failed to decrypt");
+/// assert_eq!(decrypted.format, CiphertextFormat::Legacy);
+/// assert_eq!(decrypted.plaintext, b"Stored in 2023");
+/// assert_eq!(decryptor.decrypt(&envelope).unwrap().plaintext, b"Stored today");
+///
+/// let upgraded = decryptor
+///     .upgrade(legacy.as_bytes())
+///     .expect("Failed to upgrade")
+///     .expect("Legacy ciphertexts are rewritten");
+/// assert_eq!(decryptor.inner().decrypt_envelope(&upgraded).unwrap(), b"Stored in 2023");
+/// assert_eq!(decryptor.upgrade(&envelope).unwrap(), None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LegacyDecryptor {
+    e2ee: E2ee,
+    legacy_configs: Vec<InteropConfig>,
+}
+
+impl LegacyDecryptor {
+    /// Creates a new decryptor around `e2ee`, decrypting legacy ciphertexts with its
+    /// `InteropConfig`.
+    pub fn new(e2ee: E2ee) -> Self {
+        Self {
+            e2ee,
+            legacy_configs: Vec::new(),
+        }
+    }
+
+    /// Also tries `config` on legacy ciphertexts that fail to decrypt with the configurations
+    /// before it.
+    pub fn with_legacy_config(mut self, config: InteropConfig) -> Self {
+        if config != self.e2ee.config && !self.legacy_configs.contains(&config) {
+            self.legacy_configs.push(config);
+        }
+        self
+    }
+
+    /// Returns the wrapped `E2ee` instance.
+    pub fn inner(&self) -> &E2ee {
+        &self.e2ee
+    }
+
+    /// Decrypts an envelope or a legacy ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of `E2ee::decrypt_envelope` for envelopes. For anything
+    /// else, it returns `E2eeError::DecryptionFailed` if no configuration decrypts it, unless
+    /// detailed errors were enabled with `E2ee::detailed_errors`, in which case the error of the
+    /// last configuration tried is returned.
+    pub fn decrypt(&self, ciphertext: &[u8]) -> E2eeResult<Decrypted> {
+        match detect_format(ciphertext) {
+            Some(
+                format @ (CiphertextFormat::Envelope
+                | CiphertextFormat::JsonEnvelope),
+            ) => Ok(Decrypted {
+                format,
+                config: None,
+                plaintext: self.e2ee.decrypt_envelope(ciphertext)?,
+            }),
+            _ => self.decrypt_legacy(ciphertext),
+        }
+    }
+
+    /// Returns `ciphertext` rewritten as an envelope (see `E2ee::encrypt_envelope`) if it is a
+    /// legacy ciphertext, or `None` if it is already an envelope and needs no rewrite.
+    ///
+    /// Envelopes are only parsed, not decrypted.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`LegacyDecryptor::decrypt`] for legacy ciphertexts, or
+    /// an error if encryption fails.
+    pub fn upgrade(&self, ciphertext: &[u8]) -> E2eeResult<Option<Vec<u8>>> {
+        match detect_format(ciphertext) {
+            Some(CiphertextFormat::Envelope | CiphertextFormat::JsonEnvelope) => {
+                Ok(None)
+            }
+            _ => {
+                let decrypted = self.decrypt_legacy(ciphertext)?;
+                Ok(Some(self.e2ee.encrypt_envelope(&decrypted.plaintext)?))
+            }
+        }
+    }
+
+    /// Decrypts a legacy ciphertext with the configuration of the instance, then with the legacy
+    /// configurations, as a single audited decryption.
+    fn decrypt_legacy(&self, ciphertext: &[u8]) -> E2eeResult<Decrypted> {
+        self.e2ee.audited(AuditOperation::Decrypt, None, || {
+            let text = std::str::from_utf8(ciphertext)
+                .map_err(|_| E2eeError::DecryptionFailed)?
+                .trim();
+            let mut result = Err(E2eeError::DecryptionFailed);
+            for config in
+                std::iter::once(&self.e2ee.config).chain(&self.legacy_configs)
+            {
+                result = self.e2ee.decrypt_raw(text, *config).map(|plaintext| {
+                    Decrypted {
+                        format: CiphertextFormat::Legacy,
+                        config: Some(*config),
+                        plaintext,
+                    }
+                });
+                if result.is_ok() {
+                    break;
+                }
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    #[test]
+    fn test_decrypts_legacy_configs_and_envelopes() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let java = e2ee.clone().with_interop_config(InteropConfig::JAVA);
+        let legacy = java.encrypt("Hello, Java!").unwrap();
+        assert_eq!(
+            detect_format(legacy.as_bytes()),
+            Some(CiphertextFormat::Legacy)
+        );
+        assert_eq!(detect_format(&[0xff, 0x00]), None);
+
+        let decryptor = LegacyDecryptor::new(e2ee.clone());
+        assert!(decryptor.decrypt(legacy.as_bytes()).is_err());
+
+        let decryptor = decryptor.with_legacy_config(InteropConfig::JAVA);
+        let decrypted = decryptor.decrypt(legacy.as_bytes()).unwrap();
+        assert!(decrypted.is_legacy());
+        assert_eq!(decrypted.config, Some(InteropConfig::JAVA));
+        assert_eq!(decrypted.plaintext, b"Hello, Java!");
+
+        let upgraded = decryptor.upgrade(legacy.as_bytes()).unwrap().unwrap();
+        assert_eq!(detect_format(&upgraded), Some(CiphertextFormat::Envelope));
+        let decrypted = decryptor.decrypt(&upgraded).unwrap();
+        assert_eq!(decrypted.format, CiphertextFormat::Envelope);
+        assert_eq!(decrypted.config, None);
+        assert_eq!(decrypted.plaintext, b"Hello, Java!");
+    }
+}
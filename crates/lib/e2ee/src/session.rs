@@ -0,0 +1,831 @@
+//! Authenticated sessions with forward secrecy, using the Double Ratchet algorithm.
+//!
+//! This module is enabled by the `session` feature. Two parties holding long-term RSA identity
+//! keys establish a [`Session`] with a two-message [`Handshake`]: each side sends an ephemeral
+//! X25519 public key signed with its identity key (see `E2ee::sign`), and verifies the key of the
+//! other side against the identity it expects. The shared secret of the ephemeral keys seeds a
+//! [Double Ratchet](https://signal.org/docs/specifications/doubleratchet/):
+//!
+//! - every message is encrypted with a fresh AES-256-GCM key from a symmetric chain, so that
+//!   compromising the session state does not expose earlier messages;
+//! - each time the conversation changes direction, the sender mixes a new X25519 exchange into
+//!   the chains, so that the session heals after a compromise;
+//! - messages may arrive out of order, or not at all: keys of skipped messages are kept (up to
+//!   [`MAX_SKIP`] per chain) until their messages arrive.
+//!
+//! Handshake and session messages are opaque byte strings, to be carried by any transport (e.g.
+//! WebSocket frames, see the `chat_client` example).
+//!
+//! A session can be saved with [`Session::serialize`], e.g. when a mobile app is suspended, and
+//! resumed with [`Session::deserialize`] without a new handshake. The state is encrypted and
+//! authenticated with AES-256-GCM under a 32-byte storage key, which the app keeps in its
+//! platform keystore. Save the session after every call to `encrypt` or `decrypt`, and never
+//! resume an older state: it would reuse message keys that were already used.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::session::Handshake;
+//!
+//! let alice = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let bob = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//!
+//! let (handshake, hello) = Handshake::initiate(&alice).expect("Failed to sign handshake");
+//! let (mut bob_session, reply) = Handshake::respond(&bob, alice.get_public_key(), &hello)
+//!     .expect("Failed to accept handshake");
+//! let mut alice_session = handshake
+//!     .complete(bob.get_public_key(), &reply)
+//!     .expect("Failed to complete handshake");
+//!
+//! let message = alice_session.encrypt(b"Hello, Bob!").expect("Failed to encrypt");
+//! assert_eq!(bob_session.decrypt(&message).expect("Failed to decrypt"), b"Hello, Bob!");
+//! let message = bob_session.encrypt(b"Hello, Alice!").expect("Failed to encrypt");
+//! assert_eq!(alice_session.decrypt(&message).expect("Failed to decrypt"), b"Hello, Alice!");
+//! ```
+use crate::core;
+use crate::kdf::Kdf;
+use crate::mac::Mac;
+use crate::server::{E2ee, E2eeError};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    sha2::{Digest, Sha256},
+    RsaPublicKey,
+};
+use std::{collections::HashMap, fmt};
+use thiserror::Error;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// The version of the handshake and session message formats.
+pub const VERSION: u8 = 1;
+
+/// The largest number of message keys skipped in a single chain, and kept for late messages.
+pub const MAX_SKIP: u32 = 1000;
+
+const INITIATOR_CONTEXT: &[u8] = b"e2ee-session-v1 initiator";
+const RESPONDER_CONTEXT: &[u8] = b"e2ee-session-v1 responder";
+const HEADER_LEN: usize = 1 + 32 + 4 + 4;
+const STATE_CONTEXT: &[u8] = b"e2ee-session-v1 state";
+const STATE_NONCE_LEN: usize = 12;
+
+/// A 32-byte X25519 public key, or symmetric key.
+type Key = [u8; 32];
+
+/// An error returned when establishing a session or exchanging messages.
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("Signature error: {0}")]
+    Signature(#[from] E2eeError),
+
+    #[error("The handshake is not signed by the expected identity")]
+    InvalidSignature,
+
+    #[error("Malformed session message")]
+    Malformed,
+
+    #[error("Unsupported session message version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("The peer sent a weak ephemeral key")]
+    WeakKey,
+
+    #[error("Too many skipped messages")]
+    TooManySkipped,
+
+    #[error("Decryption failed")]
+    DecryptionFailed,
+}
+
+/// A signed ephemeral key, exchanged to establish a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeMessage {
+    ephemeral: Key,
+    signature: Vec<u8>,
+}
+
+impl HandshakeMessage {
+    /// Encodes the message as `version | ephemeral key (32) | u16 BE signature length | signature`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(35 + self.signature.len());
+        bytes.push(VERSION);
+        bytes.extend_from_slice(&self.ephemeral);
+        bytes.extend_from_slice(&(self.signature.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    /// Decodes a message encoded with [`HandshakeMessage::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message is malformed or of another version.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SessionError> {
+        let (&version, rest) = bytes.split_first().ok_or(SessionError::Malformed)?;
+        if version != VERSION {
+            return Err(SessionError::UnsupportedVersion(version));
+        }
+        if rest.len() < 34 {
+            return Err(SessionError::Malformed);
+        }
+        let (ephemeral, rest) = rest.split_at(32);
+        let (len, signature) = rest.split_at(2);
+        if usize::from(u16::from_be_bytes([len[0], len[1]])) != signature.len() {
+            return Err(SessionError::Malformed);
+        }
+        Ok(Self {
+            ephemeral: ephemeral.try_into().expect("32 bytes were split off"),
+            signature: signature.to_vec(),
+        })
+    }
+}
+
+/// The initiating side of a handshake, waiting for the reply of the responder.
+pub struct Handshake {
+    ephemeral: StaticSecret,
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handshake").finish_non_exhaustive()
+    }
+}
+
+impl Handshake {
+    /// Starts a handshake as `identity`, returning the message to send to the responder.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if signing fails.
+    pub fn initiate(
+        identity: &E2ee,
+    ) -> Result<(Self, HandshakeMessage), SessionError> {
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&ephemeral).to_bytes();
+        let signature = identity.sign(&[INITIATOR_CONTEXT, &public].concat())?;
+        let hello = HandshakeMessage {
+            ephemeral: public,
+            signature,
+        };
+        Ok((Self { ephemeral }, hello))
+    }
+
+    /// Accepts the handshake message `hello` of the initiator `peer` as `identity`, returning the
+    /// session and the reply to send back.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `SessionError::InvalidSignature` if `hello` was not signed by `peer`,
+    /// or an error if the key exchange or signing fails.
+    pub fn respond(
+        identity: &E2ee,
+        peer: &RsaPublicKey,
+        hello: &HandshakeMessage,
+    ) -> Result<(Session, HandshakeMessage), SessionError> {
+        let message = [INITIATOR_CONTEXT, &hello.ephemeral].concat();
+        core::verify(peer, &message, &hello.signature)
+            .map_err(|_| SessionError::InvalidSignature)?;
+
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&ephemeral).to_bytes();
+        let signature = identity
+            .sign(&[RESPONDER_CONTEXT, &public, &hello.ephemeral].concat())?;
+        let (root_key, chain_key, associated_data) = derive_session_keys(
+            &ephemeral,
+            &PublicKey::from(hello.ephemeral),
+            &hello.ephemeral,
+            &public,
+        )?;
+        let session = Session {
+            root_key,
+            sending: Some(Chain::new(chain_key)),
+            receiving: None,
+            own_ratchet_key: ephemeral,
+            peer_ratchet_key: None,
+            previous_sending_len: 0,
+            skipped: HashMap::new(),
+            associated_data,
+        };
+        let reply = HandshakeMessage {
+            ephemeral: public,
+            signature,
+        };
+        Ok((session, reply))
+    }
+
+    /// Completes the handshake with the `reply` of the responder `peer`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `SessionError::InvalidSignature` if `reply` was not signed by `peer`
+    /// for this handshake, or an error if the key exchange fails.
+    pub fn complete(
+        self,
+        peer: &RsaPublicKey,
+        reply: &HandshakeMessage,
+    ) -> Result<Session, SessionError> {
+        let own = PublicKey::from(&self.ephemeral).to_bytes();
+        let message = [RESPONDER_CONTEXT, &reply.ephemeral, &own].concat();
+        core::verify(peer, &message, &reply.signature)
+            .map_err(|_| SessionError::InvalidSignature)?;
+
+        let peer_ratchet_key = PublicKey::from(reply.ephemeral);
+        let (root_key, chain_key, associated_data) = derive_session_keys(
+            &self.ephemeral,
+            &peer_ratchet_key,
+            &own,
+            &reply.ephemeral,
+        )?;
+        // The responder starts sending on the handshake chain; the initiator ratchets right away.
+        let own_ratchet_key = StaticSecret::random_from_rng(OsRng);
+        let (root_key, sending_key) =
+            kdf_root(&root_key, &dh(&own_ratchet_key, &peer_ratchet_key)?);
+        Ok(Session {
+            root_key,
+            sending: Some(Chain::new(sending_key)),
+            receiving: Some(Chain::new(chain_key)),
+            own_ratchet_key,
+            peer_ratchet_key: Some(peer_ratchet_key),
+            previous_sending_len: 0,
+            skipped: HashMap::new(),
+            associated_data,
+        })
+    }
+}
+
+/// A symmetric key chain and the number of message keys taken from it.
+#[derive(Clone)]
+struct Chain {
+    key: Key,
+    len: u32,
+}
+
+impl Chain {
+    fn new(key: Key) -> Self {
+        Self { key, len: 0 }
+    }
+
+    /// Advances the chain, returning the next message key.
+    fn next(&mut self) -> Key {
+        let message_key = hmac(&self.key, &[0x01]);
+        self.key = hmac(&self.key, &[0x02]);
+        self.len += 1;
+        message_key
+    }
+}
+
+/// An established session, encrypting and decrypting messages with the Double Ratchet.
+#[derive(Clone)]
+pub struct Session {
+    root_key: Key,
+    sending: Option<Chain>,
+    receiving: Option<Chain>,
+    own_ratchet_key: StaticSecret,
+    peer_ratchet_key: Option<PublicKey>,
+    previous_sending_len: u32,
+    skipped: HashMap<(Key, u32), Key>,
+    associated_data: Key,
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("sent", &self.sending.as_ref().map_or(0, |chain| chain.len))
+            .field(
+                "received",
+                &self.receiving.as_ref().map_or(0, |chain| chain.len),
+            )
+            .field("skipped", &self.skipped.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Session {
+    /// Encrypts `plaintext` into a session message.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let chain = self
+            .sending
+            .as_mut()
+            .expect("every session has a sending chain");
+        let header = encode_header(
+            PublicKey::from(&self.own_ratchet_key).as_bytes(),
+            self.previous_sending_len,
+            chain.len,
+        );
+        let message_key = chain.next();
+        let ciphertext =
+            seal(&message_key, &header, &self.associated_data, plaintext)?;
+        Ok([header.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a session message. The session is left unchanged if decryption fails.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `SessionError::DecryptionFailed` if the message was not encrypted in
+    /// this session, was tampered with or was already decrypted, or an error if it is malformed
+    /// or skips too many messages.
+    pub fn decrypt(&mut self, message: &[u8]) -> Result<Vec<u8>, SessionError> {
+        let mut next = self.clone();
+        let plaintext = next.decrypt_in_place(message)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    /// Encrypts the state of the session under `storage_key`, to be resumed with
+    /// [`Session::deserialize`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # use e2ee::server::{E2ee, KeySize};
+    /// # use e2ee::session::{Handshake, Session};
+    /// # let alice = E2ee::new(KeySize::Bit2048).unwrap();
+    /// # let bob = E2ee::new(KeySize::Bit2048).unwrap();
+    /// # let (handshake, hello) = Handshake::initiate(&alice).unwrap();
+    /// # let (mut bob_session, reply) =
+    /// #     Handshake::respond(&bob, alice.get_public_key(), &hello).unwrap();
+    /// # let mut alice_session = handshake.complete(bob.get_public_key(), &reply).unwrap();
+    /// let storage_key = [7; 32]; // Kept in the platform keystore.
+    /// let saved = alice_session.serialize(&storage_key).expect("Failed to save session");
+    ///
+    /// let mut resumed = Session::deserialize(&storage_key, &saved).expect("Failed to resume");
+    /// let message = resumed.encrypt(b"Still there?").expect("Failed to encrypt");
+    /// assert_eq!(bob_session.decrypt(&message).unwrap(), b"Still there?");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if encryption fails.
+    pub fn serialize(
+        &self,
+        storage_key: &[u8; 32],
+    ) -> Result<Vec<u8>, SessionError> {
+        let mut state = Vec::new();
+        state.extend_from_slice(&self.root_key);
+        for chain in [&self.sending, &self.receiving] {
+            encode_chain(&mut state, chain.as_ref());
+        }
+        state.extend_from_slice(self.own_ratchet_key.as_bytes());
+        match &self.peer_ratchet_key {
+            Some(key) => {
+                state.push(1);
+                state.extend_from_slice(key.as_bytes());
+            }
+            None => state.push(0),
+        }
+        state.extend_from_slice(&self.previous_sending_len.to_be_bytes());
+        state.extend_from_slice(&(self.skipped.len() as u32).to_be_bytes());
+        for ((ratchet_key, index), message_key) in &self.skipped {
+            state.extend_from_slice(ratchet_key);
+            state.extend_from_slice(&index.to_be_bytes());
+            state.extend_from_slice(message_key);
+        }
+        state.extend_from_slice(&self.associated_data);
+
+        let mut nonce = [0; STATE_NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = Aes256Gcm::new(storage_key.into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &state,
+                    aad: &[STATE_CONTEXT, &[VERSION]].concat(),
+                },
+            )
+            .map_err(|_| SessionError::DecryptionFailed)?;
+        Ok([&[VERSION], nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Resumes a session saved with [`Session::serialize`] under `storage_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `SessionError::DecryptionFailed` if the state was not saved under
+    /// `storage_key` or was tampered with, or an error if it is malformed.
+    pub fn deserialize(
+        storage_key: &[u8; 32],
+        saved: &[u8],
+    ) -> Result<Self, SessionError> {
+        let (&version, rest) = saved.split_first().ok_or(SessionError::Malformed)?;
+        if version != VERSION {
+            return Err(SessionError::UnsupportedVersion(version));
+        }
+        if rest.len() < STATE_NONCE_LEN {
+            return Err(SessionError::Malformed);
+        }
+        let (nonce, ciphertext) = rest.split_at(STATE_NONCE_LEN);
+        let state = Aes256Gcm::new(storage_key.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[STATE_CONTEXT, &[VERSION]].concat(),
+                },
+            )
+            .map_err(|_| SessionError::DecryptionFailed)?;
+
+        let mut reader = StateReader(&state);
+        let root_key = reader.key()?;
+        let sending = reader.chain()?;
+        let receiving = reader.chain()?;
+        let own_ratchet_key = StaticSecret::from(reader.key()?);
+        let peer_ratchet_key = match reader.take(1)?[0] {
+            0 => None,
+            1 => Some(PublicKey::from(reader.key()?)),
+            _ => return Err(SessionError::Malformed),
+        };
+        let previous_sending_len = reader.u32()?;
+        let skipped_len = reader.u32()?;
+        let mut skipped = HashMap::new();
+        for _ in 0..skipped_len {
+            let ratchet_key = reader.key()?;
+            let index = reader.u32()?;
+            skipped.insert((ratchet_key, index), reader.key()?);
+        }
+        let associated_data = reader.key()?;
+        if !reader.0.is_empty() || sending.is_none() {
+            return Err(SessionError::Malformed);
+        }
+        Ok(Self {
+            root_key,
+            sending,
+            receiving,
+            own_ratchet_key,
+            peer_ratchet_key,
+            previous_sending_len,
+            skipped,
+            associated_data,
+        })
+    }
+
+    fn decrypt_in_place(&mut self, message: &[u8]) -> Result<Vec<u8>, SessionError> {
+        if message.len() < HEADER_LEN {
+            return Err(SessionError::Malformed);
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LEN);
+        if header[0] != VERSION {
+            return Err(SessionError::UnsupportedVersion(header[0]));
+        }
+        let ratchet_key: Key = header[1..33].try_into().expect("32 bytes");
+        let previous_len =
+            u32::from_be_bytes(header[33..37].try_into().expect("4 bytes"));
+        let index = u32::from_be_bytes(header[37..41].try_into().expect("4 bytes"));
+
+        let message_key = match self.skipped.remove(&(ratchet_key, index)) {
+            Some(message_key) => message_key,
+            None => {
+                let ratchet_key = PublicKey::from(ratchet_key);
+                if self.peer_ratchet_key != Some(ratchet_key) {
+                    self.skip(previous_len)?;
+                    self.ratchet(ratchet_key)?;
+                }
+                let chain = self
+                    .receiving
+                    .as_ref()
+                    .ok_or(SessionError::DecryptionFailed)?;
+                if index < chain.len {
+                    return Err(SessionError::DecryptionFailed);
+                }
+                self.skip(index)?;
+                self.receiving
+                    .as_mut()
+                    .expect("the receiving chain exists")
+                    .next()
+            }
+        };
+        open(&message_key, header, &self.associated_data, ciphertext)
+    }
+
+    /// Stores the keys of the messages of the receiving chain up to `until`.
+    fn skip(&mut self, until: u32) -> Result<(), SessionError> {
+        let (Some(chain), Some(peer_ratchet_key)) =
+            (self.receiving.as_mut(), self.peer_ratchet_key)
+        else {
+            return Ok(());
+        };
+        if until.saturating_sub(chain.len) > MAX_SKIP {
+            return Err(SessionError::TooManySkipped);
+        }
+        while chain.len < until {
+            let index = chain.len;
+            self.skipped
+                .insert((peer_ratchet_key.to_bytes(), index), chain.next());
+        }
+        Ok(())
+    }
+
+    /// Performs a Diffie-Hellman ratchet step on receiving a new ratchet key from the peer.
+    fn ratchet(&mut self, peer_ratchet_key: PublicKey) -> Result<(), SessionError> {
+        self.previous_sending_len =
+            self.sending.as_ref().map_or(0, |chain| chain.len);
+        self.peer_ratchet_key = Some(peer_ratchet_key);
+        let (root_key, receiving_key) = kdf_root(
+            &self.root_key,
+            &dh(&self.own_ratchet_key, &peer_ratchet_key)?,
+        );
+        self.own_ratchet_key = StaticSecret::random_from_rng(OsRng);
+        let (root_key, sending_key) =
+            kdf_root(&root_key, &dh(&self.own_ratchet_key, &peer_ratchet_key)?);
+        self.root_key = root_key;
+        self.receiving = Some(Chain::new(receiving_key));
+        self.sending = Some(Chain::new(sending_key));
+        Ok(())
+    }
+}
+
+fn encode_chain(state: &mut Vec<u8>, chain: Option<&Chain>) {
+    match chain {
+        Some(chain) => {
+            state.push(1);
+            state.extend_from_slice(&chain.key);
+            state.extend_from_slice(&chain.len.to_be_bytes());
+        }
+        None => state.push(0),
+    }
+}
+
+/// Reads the fields of a decrypted session state.
+struct StateReader<'a>(&'a [u8]);
+
+impl StateReader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], SessionError> {
+        if self.0.len() < len {
+            return Err(SessionError::Malformed);
+        }
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(field)
+    }
+
+    fn key(&mut self) -> Result<Key, SessionError> {
+        Ok(self.take(32)?.try_into().expect("32 bytes"))
+    }
+
+    fn u32(&mut self) -> Result<u32, SessionError> {
+        Ok(u32::from_be_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    fn chain(&mut self) -> Result<Option<Chain>, SessionError> {
+        match self.take(1)?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(Chain {
+                key: self.key()?,
+                len: self.u32()?,
+            })),
+            _ => Err(SessionError::Malformed),
+        }
+    }
+}
+
+/// Derives the root key, the first chain key of the responder and the associated data of a
+/// session from the handshake keys.
+fn derive_session_keys(
+    own: &StaticSecret,
+    peer: &PublicKey,
+    initiator_key: &Key,
+    responder_key: &Key,
+) -> Result<(Key, Key, Key), SessionError> {
+    let associated_data: Key = Sha256::new()
+        .chain_update(initiator_key)
+        .chain_update(responder_key)
+        .finalize()
+        .into();
+    let kdf = Kdf::extract(Some(&associated_data), &dh(own, peer)?);
+    let mut root_key = [0; 32];
+    let mut chain_key = [0; 32];
+    kdf.expand(b"e2ee-session root", &mut root_key)
+        .expect("32 bytes is a valid HKDF output length");
+    kdf.expand(b"e2ee-session responder chain", &mut chain_key)
+        .expect("32 bytes is a valid HKDF output length");
+    Ok((root_key, chain_key, associated_data))
+}
+
+fn dh(own: &StaticSecret, peer: &PublicKey) -> Result<Key, SessionError> {
+    let shared = own.diffie_hellman(peer);
+    if !shared.was_contributory() {
+        return Err(SessionError::WeakKey);
+    }
+    Ok(shared.to_bytes())
+}
+
+fn kdf_root(root_key: &Key, dh_output: &Key) -> (Key, Key) {
+    let mut output = [0; 64];
+    Kdf::extract(Some(root_key), dh_output)
+        .expand(b"e2ee-session ratchet", &mut output)
+        .expect("64 bytes is a valid HKDF output length");
+    let (root_key, chain_key) = output.split_at(32);
+    (
+        root_key.try_into().expect("32 bytes"),
+        chain_key.try_into().expect("32 bytes"),
+    )
+}
+
+fn hmac(key: &Key, input: &[u8]) -> Key {
+    Mac::new(key).tag(input)
+}
+
+fn encode_header(ratchet_key: &Key, previous_len: u32, index: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.push(VERSION);
+    header.extend_from_slice(ratchet_key);
+    header.extend_from_slice(&previous_len.to_be_bytes());
+    header.extend_from_slice(&index.to_be_bytes());
+    header
+}
+
+/// Derives the AES-256-GCM key and nonce of a message key. Each message key is used once.
+fn message_cipher(message_key: &Key) -> (Aes256Gcm, [u8; 12]) {
+    let mut output = [0; 44];
+    Kdf::extract(None, message_key)
+        .expand(b"e2ee-session message", &mut output)
+        .expect("44 bytes is a valid HKDF output length");
+    let cipher = Aes256Gcm::new_from_slice(&output[..32]).expect("32-byte key");
+    (cipher, output[32..].try_into().expect("12 bytes"))
+}
+
+fn seal(
+    message_key: &Key,
+    header: &[u8],
+    associated_data: &Key,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, SessionError> {
+    let (cipher, nonce) = message_cipher(message_key);
+    let aad = [associated_data.as_slice(), header].concat();
+    cipher
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| SessionError::DecryptionFailed)
+}
+
+fn open(
+    message_key: &Key,
+    header: &[u8],
+    associated_data: &Key,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, SessionError> {
+    let (cipher, nonce) = message_cipher(message_key);
+    let aad = [associated_data.as_slice(), header].concat();
+    cipher
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &aad,
+            },
+        )
+        .map_err(|_| SessionError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    fn establish() -> (Session, Session) {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let (handshake, hello) = Handshake::initiate(&alice).unwrap();
+        let hello = HandshakeMessage::from_bytes(&hello.to_bytes()).unwrap();
+        let (bob_session, reply) =
+            Handshake::respond(&bob, alice.get_public_key(), &hello).unwrap();
+        let alice_session =
+            handshake.complete(bob.get_public_key(), &reply).unwrap();
+        (alice_session, bob_session)
+    }
+
+    #[test]
+    fn test_conversation_in_both_directions() {
+        let (mut alice, mut bob) = establish();
+        // The responder may speak first.
+        let message = bob.encrypt(b"hi").unwrap();
+        assert_eq!(alice.decrypt(&message).unwrap(), b"hi");
+        for round in 0..3u8 {
+            let message = alice.encrypt(&[round; 3]).unwrap();
+            assert_eq!(bob.decrypt(&message).unwrap(), [round; 3]);
+            let message = bob.encrypt(&[round; 5]).unwrap();
+            assert_eq!(alice.decrypt(&message).unwrap(), [round; 5]);
+        }
+    }
+
+    #[test]
+    fn test_out_of_order_and_lost_messages() {
+        let (mut alice, mut bob) = establish();
+        let early = bob.encrypt(b"early").unwrap();
+        let first = alice.encrypt(b"1").unwrap();
+        let _lost = alice.encrypt(b"2").unwrap();
+        let third = alice.encrypt(b"3").unwrap();
+        assert_eq!(bob.decrypt(&third).unwrap(), b"3");
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+        // Late messages of earlier chains remain readable, once.
+        assert_eq!(alice.decrypt(&early).unwrap(), b"early");
+        assert_eq!(bob.decrypt(&first).unwrap(), b"1");
+        assert!(matches!(
+            bob.decrypt(&first),
+            Err(SessionError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            bob.decrypt(&third),
+            Err(SessionError::DecryptionFailed)
+        ));
+    }
+
+    #[test]
+    fn test_tampered_message_leaves_session_unchanged() {
+        let (mut alice, mut bob) = establish();
+        let message = alice.encrypt(b"Hello, Bob!").unwrap();
+        let mut tampered = message.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            bob.decrypt(&tampered),
+            Err(SessionError::DecryptionFailed)
+        ));
+        // A forged ratchet key must not advance the ratchet either.
+        let mut forged = message.clone();
+        forged[1..33].copy_from_slice(&[9; 32]);
+        assert!(bob.decrypt(&forged).is_err());
+        assert_eq!(bob.decrypt(&message).unwrap(), b"Hello, Bob!");
+
+        let mut far = alice.encrypt(b"far").unwrap();
+        far[37..41].copy_from_slice(&(MAX_SKIP + 2).to_be_bytes());
+        assert!(matches!(
+            bob.decrypt(&far),
+            Err(SessionError::TooManySkipped)
+        ));
+    }
+
+    #[test]
+    fn test_serialized_session_resumes() {
+        let (mut alice, mut bob) = establish();
+        let storage_key = [3; 32];
+        let _lost = bob.encrypt(b"lost").unwrap();
+        let late = bob.encrypt(b"late").unwrap();
+        let reply = bob.encrypt(b"reply").unwrap();
+        assert_eq!(alice.decrypt(&reply).unwrap(), b"reply");
+
+        // Skipped message keys survive a restart.
+        let saved = alice.serialize(&storage_key).unwrap();
+        let mut alice = Session::deserialize(&storage_key, &saved).unwrap();
+        assert_eq!(alice.decrypt(&late).unwrap(), b"late");
+        let message = alice.encrypt(b"back").unwrap();
+        assert_eq!(bob.decrypt(&message).unwrap(), b"back");
+
+        let saved = bob.serialize(&storage_key).unwrap();
+        let mut bob = Session::deserialize(&storage_key, &saved).unwrap();
+        let message = bob.encrypt(b"again").unwrap();
+        assert_eq!(alice.decrypt(&message).unwrap(), b"again");
+
+        assert!(matches!(
+            Session::deserialize(&[4; 32], &saved),
+            Err(SessionError::DecryptionFailed)
+        ));
+        let mut tampered = saved.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            Session::deserialize(&storage_key, &tampered),
+            Err(SessionError::DecryptionFailed)
+        ));
+        assert!(matches!(
+            Session::deserialize(&storage_key, &saved[..5]),
+            Err(SessionError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_handshake_rejects_wrong_identity() {
+        let alice = E2ee::new(KeySize::Bit1024).unwrap();
+        let bob = E2ee::new(KeySize::Bit1024).unwrap();
+        let mallory = E2ee::new(KeySize::Bit1024).unwrap();
+
+        let (_, hello) = Handshake::initiate(&mallory).unwrap();
+        assert!(matches!(
+            Handshake::respond(&bob, alice.get_public_key(), &hello),
+            Err(SessionError::InvalidSignature)
+        ));
+
+        // A reply signed for another handshake is rejected.
+        let (handshake, _) = Handshake::initiate(&alice).unwrap();
+        let (_, other_hello) = Handshake::initiate(&alice).unwrap();
+        let (_, reply) =
+            Handshake::respond(&bob, alice.get_public_key(), &other_hello).unwrap();
+        assert!(matches!(
+            handshake.complete(bob.get_public_key(), &reply),
+            Err(SessionError::InvalidSignature)
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+//! Detached signatures of files and signed manifests of directory trees.
+//!
+//! This module is enabled by the default `io` feature. It authenticates release artifacts with the
+//! same keys used for encryption:
+//!
+//! - [`sign_file`] writes the RSASSA-PKCS1-v1_5 (SHA-256) signature of a file next to it, with the
+//!   `.sig` extension, and [`verify_file`] checks it. The signature is raw, so it can also be
+//!   checked with `openssl dgst -sha256 -verify public.pem -signature file.sig file`.
+//! - [`sign_manifest`] hashes every file of a directory tree into a [`MANIFEST_FILE_NAME`] file in
+//!   the format of `sha256sum`, and signs it. [`verify_manifest`] checks the signature, then the
+//!   hash of every file, and rejects missing, modified and unlisted files.
+//!
+//! Files are hashed incrementally, so they do not need to fit in memory.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::client::PublicE2ee;
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::signing;
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let public = PublicE2ee::from_public_key(e2ee.get_public_key().clone())
+//!     .expect("Failed to create PublicE2ee instance");
+//!
+//! let release = std::env::temp_dir().join("e2ee-signing-example");
+//! std::fs::create_dir_all(release.join("bin")).unwrap();
+//! std::fs::write(release.join("bin/app"), b"binary").unwrap();
+//!
+//! let signature = signing::sign_file(&e2ee, release.join("bin/app")).expect("Failed to sign");
+//! signing::verify_file(&public, release.join("bin/app"), &signature).expect("Invalid signature");
+//!
+//! signing::sign_manifest(&e2ee, &release).expect("Failed to sign manifest");
+//! signing::verify_manifest(&public, &release).expect("Invalid manifest");
+//! # std::fs::remove_dir_all(&release).unwrap();
+//! ```
+use crate::client::PublicE2ee;
+use crate::core;
+use crate::server::{E2ee, E2eeError};
+use rsa::sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// The extension of detached signature files.
+pub const SIGNATURE_EXTENSION: &str = "sig";
+
+/// The name of the manifest written at the root of a signed directory tree. Its signature is
+/// written next to it, with the [`SIGNATURE_EXTENSION`].
+pub const MANIFEST_FILE_NAME: &str = "SHA256SUMS";
+
+pub type SigningResult<T> = std::result::Result<T, SigningError>;
+
+/// An error returned when signing or verifying files.
+#[derive(Error, Debug)]
+pub enum SigningError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Signing failed: {0}")]
+    Sign(#[from] E2eeError),
+
+    #[error("Invalid signature")]
+    InvalidSignature,
+
+    #[error("Malformed manifest at line {0}")]
+    MalformedManifest(usize),
+
+    #[error("Unsupported path {0}: paths must be UTF-8 without line breaks")]
+    UnsupportedPath(PathBuf),
+
+    #[error("File {0} does not match the manifest")]
+    Modified(PathBuf),
+
+    #[error("File {0} is listed in the manifest but missing")]
+    Missing(PathBuf),
+
+    #[error("File {0} is not listed in the manifest")]
+    Unlisted(PathBuf),
+}
+
+/// Computes the SHA-256 digest of the file at `path`, reading it incrementally.
+///
+/// # Errors
+///
+/// This function returns an error if the file cannot be read.
+pub fn hash_file(path: impl AsRef<Path>) -> io::Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize().into()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
+
+/// Returns the path of the detached signature of the file at `path`: `path` with `.sig` appended.
+pub fn signature_path(path: impl AsRef<Path>) -> PathBuf {
+    let mut signature_path = path.as_ref().as_os_str().to_owned();
+    signature_path.push(".");
+    signature_path.push(SIGNATURE_EXTENSION);
+    signature_path.into()
+}
+
+/// Signs the file at `path` and writes the signature to [`signature_path`], whose path is returned.
+/// The path of the file is passed to the audit logger as context.
+///
+/// # Errors
+///
+/// This function returns an error if the file cannot be read, if signing fails, or if the
+/// signature cannot be written.
+pub fn sign_file(e2ee: &E2ee, path: impl AsRef<Path>) -> SigningResult<PathBuf> {
+    let path = path.as_ref();
+    let digest = hash_file(path)?;
+    let signature = e2ee.sign_digest(&digest, Some(&path.display().to_string()))?;
+    let signature_path = signature_path(path);
+    fs::write(&signature_path, signature)?;
+    Ok(signature_path)
+}
+
+/// Verifies the detached signature stored at `signature_path` of the file at `path`.
+///
+/// # Errors
+///
+/// This function returns `SigningError::InvalidSignature` if the signature does not match, or an
+/// error if either file cannot be read.
+pub fn verify_file(
+    public: &PublicE2ee,
+    path: impl AsRef<Path>,
+    signature_path: impl AsRef<Path>,
+) -> SigningResult<()> {
+    let signature = fs::read(signature_path)?;
+    public
+        .verify_digest(&hash_file(path)?, &signature)
+        .map_err(|_| SigningError::InvalidSignature)
+}
+
+/// Hashes every file under `directory` into a manifest, in the format of `sha256sum`: one line
+/// per file with its lowercase hex digest, two spaces and its path relative to `directory` with
+/// `/` separators, sorted by path. The manifest and the files derived from it, such as its
+/// signature or its time-stamp token, are not listed.
+///
+/// # Errors
+///
+/// This function returns an error if the tree cannot be read, or `SigningError::UnsupportedPath`
+/// if a path is not UTF-8 or contains a line break.
+pub fn build_manifest(directory: impl AsRef<Path>) -> SigningResult<String> {
+    let mut manifest = String::new();
+    for (relative, path) in list_files(directory.as_ref())? {
+        let digest = hash_file(path)?;
+        manifest.push_str(&core::hex(&digest));
+        manifest.push_str("  ");
+        manifest.push_str(&relative);
+        manifest.push('\n');
+    }
+    Ok(manifest)
+}
+
+/// Writes the manifest of `directory` (see [`build_manifest`]) to [`MANIFEST_FILE_NAME`] at its
+/// root, signs it like [`sign_file`], and returns the path of the manifest.
+///
+/// # Errors
+///
+/// This function returns an error if the tree cannot be read, if signing fails, or if the
+/// manifest or its signature cannot be written.
+pub fn sign_manifest(
+    e2ee: &E2ee,
+    directory: impl AsRef<Path>,
+) -> SigningResult<PathBuf> {
+    let directory = directory.as_ref();
+    let manifest = build_manifest(directory)?;
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+    fs::write(&manifest_path, manifest)?;
+    sign_file(e2ee, &manifest_path)?;
+    Ok(manifest_path)
+}
+
+/// Verifies the signed manifest of `directory`, then the digest of every file under it, and
+/// returns the number of files checked.
+///
+/// # Errors
+///
+/// This function returns `SigningError::InvalidSignature` if the manifest signature does not
+/// match, `SigningError::Modified`, `SigningError::Missing` or `SigningError::Unlisted` for the
+/// first file that differs from the manifest, or an error if the tree cannot be read.
+pub fn verify_manifest(
+    public: &PublicE2ee,
+    directory: impl AsRef<Path>,
+) -> SigningResult<usize> {
+    let directory = directory.as_ref();
+    let manifest_path = directory.join(MANIFEST_FILE_NAME);
+    let manifest = fs::read(&manifest_path)?;
+    let signature = fs::read(signature_path(&manifest_path))?;
+    public
+        .verify_digest(&Sha256::digest(&manifest).into(), &signature)
+        .map_err(|_| SigningError::InvalidSignature)?;
+
+    let manifest = String::from_utf8(manifest)
+        .map_err(|_| SigningError::MalformedManifest(1))?;
+    let mut expected = BTreeMap::new();
+    for (index, line) in manifest.lines().enumerate() {
+        let (digest, relative) = line
+            .split_once("  ")
+            .filter(|(digest, _)| digest.len() == 64)
+            .ok_or(SigningError::MalformedManifest(index + 1))?;
+        expected.insert(relative.to_string(), digest.to_string());
+    }
+    let files = list_files(directory)?;
+    for relative in expected.keys() {
+        if !files.contains_key(relative) {
+            return Err(SigningError::Missing(directory.join(relative)));
+        }
+    }
+    for (relative, path) in &files {
+        let digest = expected
+            .get(relative)
+            .ok_or_else(|| SigningError::Unlisted(path.clone()))?;
+        if core::hex(&hash_file(path)?) != *digest {
+            return Err(SigningError::Modified(path.clone()));
+        }
+    }
+    Ok(files.len())
+}
+
+/// Lists the files under `directory` by their path relative to it, with `/` separators, except
+/// the manifest at the root and the files derived from it, such as its signature.
+fn list_files(directory: &Path) -> SigningResult<BTreeMap<String, PathBuf>> {
+    let derived_prefix = format!("{MANIFEST_FILE_NAME}.");
+    let mut files = BTreeMap::new();
+    let mut pending = vec![(String::new(), directory.to_path_buf())];
+    while let Some((prefix, current)) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry
+                .file_name()
+                .into_string()
+                .ok()
+                .filter(|name| !name.contains(['\n', '\r']))
+                .ok_or_else(|| SigningError::UnsupportedPath(path.clone()))?;
+            let relative = format!("{prefix}{name}");
+            if fs::metadata(&path)?.is_dir() {
+                pending.push((format!("{relative}/"), path));
+            } else if prefix.is_empty()
+                && (name == MANIFEST_FILE_NAME || name.starts_with(&derived_prefix))
+            {
+                continue;
+            } else {
+                files.insert(relative, path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-signing-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_sign_and_verify_file() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let public =
+            PublicE2ee::from_public_key(e2ee.get_public_key().clone()).unwrap();
+        let dir = temp_dir("file");
+        let path = dir.join("release.tar.gz");
+        fs::write(&path, vec![0x42; 200_000]).unwrap();
+
+        let signature = sign_file(&e2ee, &path).unwrap();
+        assert_eq!(signature, dir.join("release.tar.gz.sig"));
+        verify_file(&public, &path, &signature).unwrap();
+        // The signature is that of the whole content, as produced by `openssl dgst -sha256`.
+        public
+            .verify(&fs::read(&path).unwrap(), &fs::read(&signature).unwrap())
+            .unwrap();
+
+        fs::write(&path, b"tampered").unwrap();
+        assert!(matches!(
+            verify_file(&public, &path, &signature),
+            Err(SigningError::InvalidSignature)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_sign_and_verify_manifest() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        let public =
+            PublicE2ee::from_public_key(e2ee.get_public_key().clone()).unwrap();
+        let dir = temp_dir("manifest");
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("bin/app"), b"binary").unwrap();
+        fs::write(dir.join("README"), b"readme").unwrap();
+
+        let manifest_path = sign_manifest(&e2ee, &dir).unwrap();
+        let manifest = fs::read_to_string(&manifest_path).unwrap();
+        assert_eq!(
+            manifest,
+            format!(
+                "{}  README\n{}  bin/app\n",
+                core::hex(&Sha256::digest(b"readme")),
+                core::hex(&Sha256::digest(b"binary"))
+            )
+        );
+        assert_eq!(verify_manifest(&public, &dir).unwrap(), 2);
+
+        fs::write(dir.join("bin/app"), b"patched").unwrap();
+        assert!(matches!(
+            verify_manifest(&public, &dir),
+            Err(SigningError::Modified(path)) if path.ends_with("bin/app")
+        ));
+        fs::write(dir.join("bin/app"), b"binary").unwrap();
+
+        fs::write(dir.join("extra"), b"extra").unwrap();
+        assert!(matches!(
+            verify_manifest(&public, &dir),
+            Err(SigningError::Unlisted(_))
+        ));
+        fs::remove_file(dir.join("extra")).unwrap();
+
+        fs::remove_file(dir.join("README")).unwrap();
+        assert!(matches!(
+            verify_manifest(&public, &dir),
+            Err(SigningError::Missing(_))
+        ));
+
+        fs::write(&manifest_path, manifest.replace("README", "readme")).unwrap();
+        assert!(matches!(
+            verify_manifest(&public, &dir),
+            Err(SigningError::InvalidSignature)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
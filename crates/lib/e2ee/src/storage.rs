@@ -0,0 +1,397 @@
+//! Pluggable storage of the state an app persists: keystores, saved sessions, pins and replay
+//! state.
+//!
+//! A [`StorageBackend`] keeps byte values under string keys, and applies a [`Batch`] of writes
+//! atomically. [`MemoryStorage`] keeps them in memory, for tests and ephemeral state, and
+//! [`FileStorage`] in a single file rewritten atomically. With the `sqlite` feature,
+//! `sqlite::SqliteStorage` keeps them sealed in an SQLite database. Hosts bring their own
+//! backend, e.g. over the Keychain on mobile, by implementing the trait, or through the
+//! `E2eeStorageBackend` callbacks of the FFI.
+//!
+//! Other backends store values as given: only store state that is already encrypted, such as a keystore
+//! (see `keystore::Keystore::create_in`) or a session saved with `Session::serialize`. The
+//! prefixes [`KEYSTORES`], [`SESSIONS`], [`PINS`] and [`REPLAY`] keep the kinds of state apart
+//! when they share a backend.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::storage::{Batch, MemoryStorage, StorageBackend, SESSIONS};
+//!
+//! let storage = MemoryStorage::new();
+//! storage
+//!     .apply(
+//!         Batch::new()
+//!             .put(format!("{SESSIONS}alice"), b"saved session".to_vec())
+//!             .put(format!("{SESSIONS}bob"), b"saved session".to_vec()),
+//!     )
+//!     .expect("Failed to write");
+//! storage.delete(&format!("{SESSIONS}bob")).expect("Failed to delete");
+//!
+//! assert_eq!(storage.list(SESSIONS).unwrap(), vec![format!("{SESSIONS}alice")]);
+//! assert_eq!(
+//!     storage.get(&format!("{SESSIONS}alice")).unwrap().as_deref(),
+//!     Some(&b"saved session"[..])
+//! );
+//! ```
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// The version of the layout of [`FileStorage`] files.
+pub const VERSION: u8 = 1;
+
+/// The prefix of the keys of keystores.
+pub const KEYSTORES: &str = "keystores/";
+
+/// The prefix of the keys of saved sessions, e.g. by peer.
+pub const SESSIONS: &str = "sessions/";
+
+/// The prefix of the keys of the pinned keys of peers.
+pub const PINS: &str = "pins/";
+
+/// The prefix of the keys of replay protection state, e.g. the last counters seen.
+pub const REPLAY: &str = "replay/";
+
+/// A store of byte values under string keys.
+///
+/// Keys are 1 to 65535 bytes long. It is implemented by [`MemoryStorage`] and [`FileStorage`].
+pub trait StorageBackend: Send + Sync {
+    /// Returns the value stored under `key`, or `None` if there is none.
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Returns the keys starting with `prefix`, sorted.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Applies the operations of `batch` in order, all of them or none.
+    fn apply(&self, batch: Batch) -> io::Result<()>;
+
+    /// Stores `value` under `key`, replacing the previous value.
+    fn put(&self, key: &str, value: &[u8]) -> io::Result<()> {
+        self.apply(Batch::new().put(key, value.to_vec()))
+    }
+
+    /// Removes the value stored under `key`, if any.
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.apply(Batch::new().delete(key))
+    }
+}
+
+/// A write of a [`Batch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    /// Stores `value` under `key`.
+    Put { key: String, value: Vec<u8> },
+    /// Removes the value stored under `key`, if any.
+    Delete { key: String },
+}
+
+impl Operation {
+    /// Returns the key written by the operation.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Put { key, .. } | Self::Delete { key } => key,
+        }
+    }
+}
+
+/// Writes applied atomically by [`StorageBackend::apply`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    operations: Vec<Operation>,
+}
+
+impl Batch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the storage of `value` under `key`.
+    pub fn put(mut self, key: impl Into<String>, value: Vec<u8>) -> Self {
+        self.operations.push(Operation::Put {
+            key: key.into(),
+            value,
+        });
+        self
+    }
+
+    /// Adds the removal of the value stored under `key`.
+    pub fn delete(mut self, key: impl Into<String>) -> Self {
+        self.operations.push(Operation::Delete { key: key.into() });
+        self
+    }
+
+    /// Returns the operations of the batch, in order.
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Returns whether the batch has no operation.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Applies the batch to `entries`, after checking all of its keys.
+    fn apply_to(self, entries: &mut BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+        self.operations
+            .iter()
+            .try_for_each(|operation| check_key(operation.key()))?;
+        for operation in self.operations {
+            match operation {
+                Operation::Put { key, value } => {
+                    entries.insert(key, value);
+                }
+                Operation::Delete { key } => {
+                    entries.remove(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for Batch {
+    type Item = Operation;
+    type IntoIter = std::vec::IntoIter<Operation>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.operations.into_iter()
+    }
+}
+
+fn check_key(key: &str) -> io::Result<()> {
+    if key.is_empty() || u16::try_from(key.len()).is_err() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Storage keys must be 1 to 65535 bytes long",
+        ));
+    }
+    Ok(())
+}
+
+fn list_prefix(entries: &BTreeMap<String, Vec<u8>>, prefix: &str) -> Vec<String> {
+    entries
+        .range(prefix.to_string()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(prefix))
+        .cloned()
+        .collect()
+}
+
+/// A [`StorageBackend`] keeping values in memory, for tests and state that does not outlive the
+/// process.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .cloned())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(list_prefix(
+            &self.entries.lock().unwrap_or_else(|e| e.into_inner()),
+            prefix,
+        ))
+    }
+
+    fn apply(&self, batch: Batch) -> io::Result<()> {
+        batch.apply_to(&mut self.entries.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+/// A [`StorageBackend`] keeping all values in a single file, loaded when opened.
+///
+/// Every batch rewrites the file through a temporary file renamed over it, so that a crash never
+/// leaves a half-applied batch. The file is owned by a single `FileStorage` at a time: changes
+/// made by other processes are not seen, and are overwritten. Its layout is:
+///
+/// ```text
+/// version (1 byte) | entry count (4 bytes) | (key length (2 bytes) | key | value length (4 bytes) | value)...
+/// ```
+///
+/// with integers in big endian.
+pub struct FileStorage {
+    path: PathBuf,
+    entries: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl fmt::Debug for FileStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileStorage")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileStorage {
+    /// Opens the storage file at `path`, or an empty storage if it does not exist yet, in which
+    /// case the file is created by the first batch.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the file cannot be read, or of kind `InvalidData` if it
+    /// is malformed.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let entries = match fs::read(&path) {
+            Ok(data) => decode(&data).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Malformed storage file")
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Returns the path of the storage file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `entries` to the file, through a temporary file.
+    fn save(&self, entries: &BTreeMap<String, Vec<u8>>) -> io::Result<()> {
+        let mut data = vec![VERSION];
+        data.extend((entries.len() as u32).to_be_bytes());
+        for (key, value) in entries {
+            let value_len = u32::try_from(value.len()).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Storage value too large",
+                )
+            })?;
+            data.extend((key.len() as u16).to_be_bytes());
+            data.extend_from_slice(key.as_bytes());
+            data.extend(value_len.to_be_bytes());
+            data.extend_from_slice(value);
+        }
+
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let temporary = self.path.with_file_name(name);
+        let result = fs::File::create(&temporary).and_then(|mut file| {
+            file.write_all(&data)?;
+            file.sync_all()?;
+            fs::rename(&temporary, &self.path)
+        });
+        if result.is_err() {
+            let _ = fs::remove_file(&temporary);
+        }
+        result
+    }
+}
+
+impl StorageBackend for FileStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self
+            .entries
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(key)
+            .cloned())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(list_prefix(
+            &self.entries.lock().unwrap_or_else(|e| e.into_inner()),
+            prefix,
+        ))
+    }
+
+    fn apply(&self, batch: Batch) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut updated = entries.clone();
+        batch.apply_to(&mut updated)?;
+        self.save(&updated)?;
+        *entries = updated;
+        Ok(())
+    }
+}
+
+/// Decodes the entries of a storage file, or returns `None` if it is malformed.
+fn decode(mut data: &[u8]) -> Option<BTreeMap<String, Vec<u8>>> {
+    fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if data.len() < len {
+            return None;
+        }
+        let (taken, rest) = data.split_at(len);
+        *data = rest;
+        Some(taken)
+    }
+    if take(&mut data, 1)? != [VERSION] {
+        return None;
+    }
+    let count = u32::from_be_bytes(take(&mut data, 4)?.try_into().ok()?);
+    let mut entries = BTreeMap::new();
+    for _ in 0..count {
+        let key_len = u16::from_be_bytes(take(&mut data, 2)?.try_into().ok()?);
+        let key = std::str::from_utf8(take(&mut data, key_len.into())?).ok()?;
+        let value_len = u32::from_be_bytes(take(&mut data, 4)?.try_into().ok()?);
+        let value = take(&mut data, value_len as usize)?;
+        entries.insert(key.to_string(), value.to_vec());
+    }
+    data.is_empty().then_some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_storage_persists_batches() {
+        let path = std::env::temp_dir()
+            .join(format!("e2ee-test-storage-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let storage = FileStorage::open(&path).unwrap();
+        storage
+            .apply(
+                Batch::new()
+                    .put("pins/alice", b"fingerprint".to_vec())
+                    .put("pins/bob", b"fingerprint".to_vec())
+                    .put("replay/alice", vec![0; 8])
+                    .delete("pins/bob"),
+            )
+            .unwrap();
+        assert!(storage
+            .apply(Batch::new().put("pins/carol", vec![]).put("", vec![]))
+            .is_err());
+
+        let storage = FileStorage::open(&path).unwrap();
+        assert_eq!(storage.list(PINS).unwrap(), ["pins/alice"]);
+        assert_eq!(storage.list("").unwrap().len(), 2);
+        assert_eq!(storage.get("replay/alice").unwrap(), Some(vec![0; 8]));
+        assert_eq!(storage.get("pins/bob").unwrap(), None);
+
+        fs::write(&path, [VERSION, 0, 0, 0, 1]).unwrap();
+        assert_eq!(
+            FileStorage::open(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+//! A storage backend over an SQLite database, with values sealed by the app (enabled by the
+//! `sqlite` feature).
+//!
+//! [`SqliteStorage`] keeps every value in a row of the `e2ee_storage` table, sealed with
+//! AES-256-GCM under a storage key held by the app (e.g. in the Keychain or the Android Keystore),
+//! with the name of the row as associated data, so that values cannot be read, altered or moved
+//! to another row without the key. The names themselves are stored in clear, to be listed by
+//! prefix: do not put secrets in them.
+//!
+//! Batches are applied in a transaction. The database is opened in WAL mode with a busy timeout,
+//! so that several processes, or several `SqliteStorage` instances, can use it at once.
+//!
+//! A sealed value is:
+//!
+//! ```text
+//! version (1 byte) | nonce (12 bytes) | AES-256-GCM ciphertext and tag
+//! ```
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::storage::{sqlite::SqliteStorage, StorageBackend};
+//!
+//! let storage_key = [7; 32];
+//! let storage = SqliteStorage::open_in_memory(&storage_key).expect("Failed to open database");
+//! storage.put("pins/alice", b"fingerprint").expect("Failed to write");
+//! assert_eq!(
+//!     storage.get("pins/alice").unwrap().as_deref(),
+//!     Some(&b"fingerprint"[..])
+//! );
+//! ```
+use super::{check_key, Batch, Operation, StorageBackend};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use rsa::rand_core::{OsRng, RngCore};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::{
+    fmt, io,
+    path::Path,
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+use zeroize::Zeroizing;
+
+/// The version of the format of sealed values.
+pub const VERSION: u8 = 1;
+
+/// The prefix of the associated data of sealed values, followed by the name of their row.
+const CONTEXT: &[u8] = b"e2ee sqlite storage v1:";
+
+/// The name of the row sealed when the database is created, to detect a wrong storage key.
+const CHECK_NAME: &str = "check";
+
+const NONCE_LEN: usize = 12;
+
+/// How long to wait for other connections to release the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS e2ee_storage (
+        key TEXT PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS e2ee_storage_meta (
+        name TEXT PRIMARY KEY NOT NULL,
+        value BLOB NOT NULL
+    ) WITHOUT ROWID;
+";
+
+/// A [`StorageBackend`] keeping values sealed under a storage key in an SQLite database.
+pub struct SqliteStorage {
+    connection: Mutex<Connection>,
+    cipher: Aes256Gcm,
+}
+
+impl fmt::Debug for SqliteStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStorage").finish_non_exhaustive()
+    }
+}
+
+impl SqliteStorage {
+    /// Opens the database at `path`, creating it if needed, with the values sealed under
+    /// `storage_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidData` if the database was created with
+    /// another storage key, or an error if it cannot be opened or initialized.
+    pub fn open(path: impl AsRef<Path>, storage_key: &[u8; 32]) -> io::Result<Self> {
+        let connection = Connection::open(path).map_err(sqlite_error)?;
+        connection
+            .pragma_update(None, "journal_mode", "WAL")
+            .map_err(sqlite_error)?;
+        Self::init(connection, storage_key)
+    }
+
+    /// Opens a database in memory, dropped with the instance, e.g. for tests.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the database cannot be initialized.
+    pub fn open_in_memory(storage_key: &[u8; 32]) -> io::Result<Self> {
+        Self::init(
+            Connection::open_in_memory().map_err(sqlite_error)?,
+            storage_key,
+        )
+    }
+
+    /// Creates the tables if needed, and checks `storage_key` against the database.
+    fn init(connection: Connection, storage_key: &[u8; 32]) -> io::Result<Self> {
+        connection
+            .busy_timeout(BUSY_TIMEOUT)
+            .map_err(sqlite_error)?;
+        let storage = Self {
+            connection: Mutex::new(connection),
+            cipher: Aes256Gcm::new(storage_key.into()),
+        };
+        {
+            let mut connection = storage.lock();
+            let transaction = connection
+                .transaction_with_behavior(TransactionBehavior::Immediate)
+                .map_err(sqlite_error)?;
+            transaction.execute_batch(SCHEMA).map_err(sqlite_error)?;
+            let check: Option<Vec<u8>> = transaction
+                .query_row(
+                    "SELECT value FROM e2ee_storage_meta WHERE name = ?1",
+                    params![CHECK_NAME],
+                    |row| row.get(0),
+                )
+                .optional()
+                .map_err(sqlite_error)?;
+            match check {
+                Some(sealed) => {
+                    storage.open_value(CHECK_NAME, &sealed).map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Wrong storage key",
+                        )
+                    })?;
+                }
+                None => {
+                    transaction
+                        .execute(
+                            "INSERT INTO e2ee_storage_meta (name, value) VALUES (?1, ?2)",
+                            params![CHECK_NAME, storage.seal(CHECK_NAME, &[])?],
+                        )
+                        .map_err(sqlite_error)?;
+                }
+            }
+            transaction.commit().map_err(sqlite_error)?;
+        }
+        Ok(storage)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn seal(&self, key: &str, value: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: value,
+                    aad: &associated_data(key),
+                },
+            )
+            .map_err(|_| io::Error::other("Failed to seal storage value"))?;
+        Ok([&[VERSION], nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open_value(&self, key: &str, sealed: &[u8]) -> io::Result<Vec<u8>> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("The value of {key:?} was tampered with or moved"),
+            )
+        };
+        let (&version, rest) = sealed.split_first().ok_or_else(invalid)?;
+        if version != VERSION || rest.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &associated_data(key),
+                },
+            )
+            .map_err(|_| invalid())
+    }
+}
+
+impl StorageBackend for SqliteStorage {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let sealed: Option<Vec<u8>> = self
+            .lock()
+            .query_row(
+                "SELECT value FROM e2ee_storage WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        sealed
+            .map(|sealed| self.open_value(key, &sealed))
+            .transpose()
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let connection = self.lock();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT key FROM e2ee_storage
+                 WHERE substr(key, 1, length(?1)) = ?1 ORDER BY key",
+            )
+            .map_err(sqlite_error)?;
+        let keys = statement
+            .query_map(params![prefix], |row| row.get(0))
+            .map_err(sqlite_error)?;
+        keys.collect::<rusqlite::Result<_>>().map_err(sqlite_error)
+    }
+
+    fn apply(&self, batch: Batch) -> io::Result<()> {
+        // Values are sealed before the transaction, to hold the write lock briefly.
+        let mut writes = Vec::new();
+        for operation in batch {
+            check_key(operation.key())?;
+            writes.push(match operation {
+                Operation::Put { key, value } => {
+                    let value = Zeroizing::new(value);
+                    let sealed = self.seal(&key, &value)?;
+                    (key, Some(sealed))
+                }
+                Operation::Delete { key } => (key, None),
+            });
+        }
+        let mut connection = self.lock();
+        let transaction = connection
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .map_err(sqlite_error)?;
+        for (key, sealed) in &writes {
+            match sealed {
+                Some(sealed) => transaction.execute(
+                    "INSERT OR REPLACE INTO e2ee_storage (key, value) VALUES (?1, ?2)",
+                    params![key, sealed],
+                ),
+                None => transaction
+                    .execute("DELETE FROM e2ee_storage WHERE key = ?1", params![key]),
+            }
+            .map_err(sqlite_error)?;
+        }
+        transaction.commit().map_err(sqlite_error)
+    }
+}
+
+fn associated_data(key: &str) -> Vec<u8> {
+    [CONTEXT, key.as_bytes()].concat()
+}
+
+fn sqlite_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_storage_seals_values() {
+        let path = std::env::temp_dir()
+            .join(format!("e2ee-test-sqlite-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = SqliteStorage::open(&path, &[1; 32]).unwrap();
+        storage
+            .apply(
+                Batch::new()
+                    .put("sessions/alice", b"alice".to_vec())
+                    .put("sessions/bob", b"bob".to_vec())
+                    .put("pins/alice", b"pin".to_vec()),
+            )
+            .unwrap();
+        storage.delete("sessions/bob").unwrap();
+        assert!(storage
+            .apply(Batch::new().put("sessions/carol", vec![]).put("", vec![]))
+            .is_err());
+        assert_eq!(storage.list("sessions/").unwrap(), ["sessions/alice"]);
+
+        // A second instance sees the writes of the first.
+        let other = SqliteStorage::open(&path, &[1; 32]).unwrap();
+        assert_eq!(other.get("pins/alice").unwrap().unwrap(), b"pin");
+        assert_eq!(
+            SqliteStorage::open(&path, &[2; 32]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // Values cannot be moved between rows.
+        storage
+            .lock()
+            .execute(
+                "UPDATE e2ee_storage SET value =
+                 (SELECT value FROM e2ee_storage WHERE key = 'sessions/alice')
+                 WHERE key = 'pins/alice'",
+                [],
+            )
+            .unwrap();
+        assert_eq!(
+            storage.get("pins/alice").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        drop((storage, other));
+        for suffix in ["", "-wal", "-shm"] {
+            let mut name = path.clone().into_os_string();
+            name.push(suffix);
+            let _ = std::fs::remove_file(name);
+        }
+    }
+}
@@ -0,0 +1,1292 @@
+//! Chunked encryption of streams and large files.
+//!
+//! Envelopes (see the `envelope` module) encrypt a payload in one piece, so it must fit in memory.
+//! A stream is encrypted in chunks instead, with the STREAM construction of Hoang, Reyhanitabar,
+//! Rogaway and Vizár: every chunk is sealed with an AEAD (AES-256-GCM by default) under a nonce made of a random prefix,
+//! the index of the chunk and a flag marking the last chunk. Besides tampering with a chunk,
+//! decryption then detects chunks that were reordered, duplicated or dropped, and streams that
+//! were truncated, even at a chunk boundary.
+//!
+//! The layout of an encrypted stream is:
+//!
+//! ```text
+//! version (1 byte) | key ID (8 bytes) | wrapped key length (2 bytes, big endian) | wrapped key |
+//! chunk size (4 bytes, big endian) | nonce prefix (7 bytes) | chunk... | last chunk
+//! ```
+//!
+//! where the data key is wrapped with RSA-OAEP (SHA-256) as in envelopes, every chunk but the last
+//! holds exactly `chunk size` bytes of plaintext followed by its 16-byte tag, and the last holds
+//! up to `chunk size` bytes (none for an empty stream). The nonce of chunk `i` is:
+//!
+//! ```text
+//! nonce prefix (7 bytes) | i (4 bytes, big endian) | 1 for the last chunk, 0 otherwise (1 byte)
+//! ```
+//!
+//! The header is authenticated as associated data of every chunk. As in envelopes, the chunks are
+//! sealed with ChaCha20-Poly1305 instead when the `envelope::CHACHA20_POLY1305_FLAG` bit of the
+//! version byte is set, see [`StreamEncryptor::new_with_aead`].
+//!
+//! [`StreamEncryptor`] and [`StreamDecryptor`] wrap a writer and a reader, and only hold one chunk
+//! in memory, as does [`PushDecryptor`], which is fed the stream piece by piece instead;
+//! [`encrypt_file`] and [`decrypt_file`] apply them to files, and their `_with_progress`
+//! variants report how much of the input file was read. With the `mmap` feature,
+//! `decrypt_file_mmap` decrypts memory-mapped files of several gigabytes without buffering them.
+//!
+//! An interrupted transfer of a large stream can be resumed from a [`StreamCheckpoint`] taken
+//! with [`StreamEncryptor::checkpoint`] or [`StreamDecryptor::checkpoint`], rather than restarted
+//! from byte zero. Chunks are authenticated by their index alone, with no hash running over the
+//! stream, so a checkpoint only holds the header, the data key and the index of the next chunk.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::stream::{StreamDecryptor, StreamEncryptor};
+//! use std::io::{Read, Write};
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//!
+//! let mut encryptor = StreamEncryptor::new(&private_key.to_public_key(), Vec::new())
+//!     .expect("Failed to create encryptor");
+//! encryptor.write_all(b"Hello, stream!").expect("Failed to encrypt");
+//! let encrypted = encryptor.finish().expect("Failed to encrypt");
+//!
+//! let mut decryptor =
+//!     StreamDecryptor::new(&private_key, &encrypted[..]).expect("Failed to decrypt header");
+//! let mut decrypted = Vec::new();
+//! decryptor.read_to_end(&mut decrypted).expect("Failed to decrypt");
+//! assert_eq!(decrypted, b"Hello, stream!");
+//! ```
+use crate::core::{self, OaepParams};
+use crate::envelope::{self, Aead, AeadCipher, EnvelopeError, KEY_ID_LEN};
+use aes_gcm::aead::Payload;
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    RsaPrivateKey, RsaPublicKey,
+};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+/// The version of the layout of encrypted streams.
+pub const VERSION: u8 = 1;
+
+/// The version of the encoding of checkpoints.
+const CHECKPOINT_VERSION: u8 = 1;
+
+/// The default size of the plaintext of a chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 << 10;
+
+/// The largest size of the plaintext of a chunk.
+pub const MAX_CHUNK_SIZE: usize = 16 << 20;
+
+const DATA_KEY_LEN: usize = 32;
+const NONCE_PREFIX_LEN: usize = 7;
+const TAG_LEN: usize = 16;
+
+pub type StreamResult<T> = std::result::Result<T, StreamError>;
+
+/// An error returned when encrypting or decrypting a stream.
+#[derive(Error, Debug)]
+pub enum StreamError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Malformed stream header")]
+    Malformed,
+
+    #[error("Unsupported stream version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Invalid chunk size {0}")]
+    InvalidChunkSize(usize),
+
+    #[error("Chunk {0} failed authentication")]
+    Authentication(u64),
+
+    #[error("The stream is truncated after chunk {0}")]
+    Truncated(u64),
+
+    #[error("The stream has too many chunks")]
+    TooManyChunks,
+
+    #[error("Invalid stream checkpoint")]
+    InvalidCheckpoint,
+}
+
+impl From<StreamError> for io::Error {
+    fn from(err: StreamError) -> Self {
+        match err {
+            StreamError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Seals and opens the chunks of a stream, in order.
+struct ChunkCipher {
+    data_key: Zeroizing<[u8; DATA_KEY_LEN]>,
+    cipher: AeadCipher,
+    header: Vec<u8>,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    chunk_size: usize,
+    index: u32,
+    done: bool,
+}
+
+impl ChunkCipher {
+    /// Returns a checkpoint resuming the stream at chunk `index`, once the header is complete.
+    fn checkpoint(&self, index: u32) -> StreamCheckpoint {
+        StreamCheckpoint {
+            data_key: self.data_key.clone(),
+            header: self.header.clone(),
+            index,
+        }
+    }
+
+    fn nonce(&self, last: bool) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_LEN..11].copy_from_slice(&self.index.to_be_bytes());
+        nonce[11] = last.into();
+        nonce
+    }
+
+    /// Moves on to the next chunk, refusing to reuse the nonce of the last index.
+    fn advance(&mut self, last: bool) -> StreamResult<()> {
+        if last {
+            self.done = true;
+        } else {
+            self.index = self
+                .index
+                .checked_add(1)
+                .ok_or(StreamError::TooManyChunks)?;
+        }
+        Ok(())
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> StreamResult<Vec<u8>> {
+        let payload = Payload {
+            msg: chunk,
+            aad: &self.header,
+        };
+        let sealed = self
+            .cipher
+            .encrypt(&self.nonce(last), payload)
+            .map_err(|_| StreamError::Authentication(self.index.into()))?;
+        self.advance(last)?;
+        Ok(sealed)
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> StreamResult<Vec<u8>> {
+        let open = |nonce: [u8; 12]| {
+            self.cipher.decrypt(
+                &nonce,
+                Payload {
+                    msg: chunk,
+                    aad: &self.header,
+                },
+            )
+        };
+        match open(self.nonce(last)) {
+            Ok(plaintext) => {
+                self.advance(last)?;
+                Ok(plaintext)
+            }
+            // The end of the stream was reached on a chunk sealed as an intermediate one.
+            Err(_) if last && open(self.nonce(false)).is_ok() => {
+                Err(StreamError::Truncated(self.index.into()))
+            }
+            Err(_) => Err(StreamError::Authentication(self.index.into())),
+        }
+    }
+}
+
+/// Encrypts everything written to it into an encrypted stream, written to an inner writer.
+///
+/// The stream must be completed with [`StreamEncryptor::finish`]: dropping the encryptor without
+/// calling it leaves a stream that decrypts with `StreamError::Truncated`.
+pub struct StreamEncryptor<W: Write> {
+    writer: W,
+    cipher: ChunkCipher,
+    buffer: Vec<u8>,
+    header_written: bool,
+}
+
+impl<W: Write> StreamEncryptor<W> {
+    /// Creates an encryptor for `public_key`, writing the encrypted stream to `writer` in chunks of
+    /// [`DEFAULT_CHUNK_SIZE`] bytes.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the data key cannot be encrypted with `public_key`, or if
+    /// `public_key` cannot be DER-encoded to compute its ID.
+    pub fn new(public_key: &RsaPublicKey, writer: W) -> StreamResult<Self> {
+        Self::new_with_aead(public_key, Aead::default(), writer)
+    }
+
+    /// Creates an encryptor like [`StreamEncryptor::new`], sealing the chunks with `aead` rather
+    /// than AES-256-GCM.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::core;
+    /// use e2ee::envelope::Aead;
+    /// use e2ee::stream::{StreamDecryptor, StreamEncryptor};
+    /// use std::io::{Read, Write};
+    ///
+    /// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let mut encryptor = StreamEncryptor::new_with_aead(
+    ///     &private_key.to_public_key(),
+    ///     Aead::ChaCha20Poly1305,
+    ///     Vec::new(),
+    /// )
+    /// .expect("Failed to create encryptor");
+    /// encryptor.write_all(b"Hello, ARM!").expect("Failed to encrypt");
+    /// let encrypted = encryptor.finish().expect("Failed to encrypt");
+    ///
+    /// let mut decrypted = Vec::new();
+    /// StreamDecryptor::new(&private_key, &encrypted[..])
+    ///     .expect("Failed to decrypt header")
+    ///     .read_to_end(&mut decrypted)
+    ///     .expect("Failed to decrypt");
+    /// assert_eq!(decrypted, b"Hello, ARM!");
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`StreamEncryptor::new`].
+    pub fn new_with_aead(
+        public_key: &RsaPublicKey,
+        aead: Aead,
+        writer: W,
+    ) -> StreamResult<Self> {
+        let mut data_key = Zeroizing::new([0u8; DATA_KEY_LEN]);
+        OsRng.fill_bytes(data_key.as_mut());
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+        OsRng.fill_bytes(&mut nonce_prefix);
+
+        let wrapped_key =
+            core::encrypt_with(public_key, OaepParams::SHA256, data_key.as_ref())
+                .map_err(EnvelopeError::from)?;
+        let mut header = vec![aead.mark_version(VERSION)];
+        header.extend_from_slice(&envelope::key_id(public_key)?);
+        header.extend_from_slice(&(wrapped_key.len() as u16).to_be_bytes());
+        header.extend_from_slice(&wrapped_key);
+        Ok(Self {
+            writer,
+            cipher: ChunkCipher {
+                cipher: aead.cipher(data_key.as_ref()).expect(
+                    "the data key has the length of the key of every cipher",
+                ),
+                data_key,
+                header,
+                nonce_prefix,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                index: 0,
+                done: false,
+            },
+            buffer: Vec::with_capacity(DEFAULT_CHUNK_SIZE),
+            header_written: false,
+        })
+    }
+
+    /// Sets the size of the plaintext of each chunk, between 1 byte and [`MAX_CHUNK_SIZE`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::InvalidChunkSize` if `chunk_size` is out of bounds, or
+    /// if data or the header was already written.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> StreamResult<Self> {
+        if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size)
+            || !self.buffer.is_empty()
+            || self.header_written
+        {
+            return Err(StreamError::InvalidChunkSize(chunk_size));
+        }
+        self.cipher.chunk_size = chunk_size;
+        self.buffer = Vec::with_capacity(chunk_size);
+        Ok(self)
+    }
+
+    /// Resumes a stream from `checkpoint`, taken with [`StreamEncryptor::checkpoint`], writing the
+    /// chunks that follow it to `writer`.
+    ///
+    /// `writer` must continue the encrypted stream at `checkpoint.ciphertext_offset()`, e.g. a file
+    /// truncated to that length, and the plaintext written to the encryptor must continue at
+    /// `checkpoint.plaintext_offset()`.
+    ///
+    /// Resuming seals the chunks that follow the checkpoint again, under the same nonces: the
+    /// plaintext must be the same as in the interrupted transfer, or the confidentiality of both
+    /// versions of those chunks is lost. Never resume a checkpoint to encrypt other data.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use e2ee::core;
+    /// use e2ee::stream::{StreamCheckpoint, StreamDecryptor, StreamEncryptor};
+    /// use std::io::{Read, Write};
+    ///
+    /// let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+    /// let data = vec![0x42; 100_000];
+    ///
+    /// let mut encryptor = StreamEncryptor::new(&private_key.to_public_key(), Vec::new())
+    ///     .expect("Failed to create encryptor");
+    /// encryptor.write_all(&data[..70_000]).expect("Failed to encrypt");
+    /// let token = encryptor.checkpoint().expect("Failed to checkpoint").to_bytes();
+    /// let mut sent = std::mem::take(encryptor.get_mut());
+    /// drop(encryptor); // The transfer is interrupted.
+    ///
+    /// let checkpoint = StreamCheckpoint::from_bytes(&token).expect("Invalid checkpoint");
+    /// sent.truncate(checkpoint.ciphertext_offset() as usize);
+    /// let mut encryptor =
+    ///     StreamEncryptor::resume(&checkpoint, sent).expect("Failed to resume");
+    /// encryptor
+    ///     .write_all(&data[checkpoint.plaintext_offset() as usize..])
+    ///     .expect("Failed to encrypt");
+    /// let encrypted = encryptor.finish().expect("Failed to encrypt");
+    ///
+    /// let mut decrypted = Vec::new();
+    /// StreamDecryptor::new(&private_key, &encrypted[..])
+    ///     .expect("Failed to decrypt header")
+    ///     .read_to_end(&mut decrypted)
+    ///     .expect("Failed to decrypt");
+    /// assert_eq!(decrypted, data);
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::InvalidCheckpoint` if the checkpoint is malformed.
+    pub fn resume(checkpoint: &StreamCheckpoint, writer: W) -> StreamResult<Self> {
+        let cipher = checkpoint.cipher()?;
+        Ok(Self {
+            writer,
+            buffer: Vec::with_capacity(cipher.chunk_size),
+            cipher,
+            header_written: true,
+        })
+    }
+
+    /// Returns a checkpoint of the chunks encrypted so far, to resume the stream with
+    /// [`StreamEncryptor::resume`] if the transfer is interrupted.
+    ///
+    /// The header is written if it was not yet, and the inner writer is flushed. The plaintext
+    /// buffered since the last sealed chunk is not covered by the checkpoint, and must be written
+    /// again after resuming (see `StreamCheckpoint::plaintext_offset`). The checkpoint holds the
+    /// data key of the stream: store it as securely as the plaintext.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the header cannot be written or the writer flushed.
+    pub fn checkpoint(&mut self) -> StreamResult<StreamCheckpoint> {
+        self.write_header()?;
+        self.writer.flush()?;
+        Ok(self.cipher.checkpoint(self.cipher.index))
+    }
+
+    /// Writes the header, unless it was already written.
+    fn write_header(&mut self) -> StreamResult<()> {
+        if !self.header_written {
+            self.cipher
+                .header
+                .extend_from_slice(&(self.cipher.chunk_size as u32).to_be_bytes());
+            self.cipher
+                .header
+                .extend_from_slice(&self.cipher.nonce_prefix);
+            self.writer.write_all(&self.cipher.header)?;
+            self.header_written = true;
+        }
+        Ok(())
+    }
+
+    /// Encrypts the buffered chunk, writing the header first if needed.
+    fn write_chunk(&mut self, last: bool) -> StreamResult<()> {
+        self.write_header()?;
+        let sealed = self.cipher.seal(&self.buffer, last)?;
+        self.writer.write_all(&sealed)?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Returns a mutable reference to the inner writer, e.g. to take the encrypted data written to a
+    /// buffer so far.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Encrypts the last chunk and returns the inner writer, flushed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the stream cannot be written.
+    pub fn finish(mut self) -> StreamResult<W> {
+        self.write_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full chunk is only sealed once more data follows, as it may be the last one.
+        if self.buffer.len() == self.cipher.chunk_size && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let len = buf.len().min(self.cipher.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts an encrypted stream read from an inner reader.
+///
+/// Reads fail with an `io::Error` of kind `InvalidData` wrapping a [`StreamError`] if a chunk was
+/// tampered with, reordered or dropped, or if the stream was truncated. The plaintext of a chunk is
+/// only returned once the chunk is authenticated, but earlier chunks may already have been
+/// returned: discard the output of a failed decryption, as [`decrypt_file`] does.
+pub struct StreamDecryptor<R: Read> {
+    reader: R,
+    cipher: ChunkCipher,
+    chunk: Vec<u8>,
+    lookahead: Option<u8>,
+    plaintext: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> StreamDecryptor<R> {
+    /// Reads the header of the stream from `reader` and decrypts its data key with `private_key`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::UnsupportedVersion` or `StreamError::Malformed` if the
+    /// header is invalid, `EnvelopeError::NotARecipient` if the stream is encrypted for another
+    /// key, or an error if the data key cannot be decrypted.
+    pub fn new(private_key: &RsaPrivateKey, mut reader: R) -> StreamResult<Self> {
+        let cipher = read_stream_header(private_key, &mut reader)?;
+        Ok(Self {
+            reader,
+            chunk: Vec::with_capacity(cipher.chunk_size + TAG_LEN),
+            cipher,
+            lookahead: None,
+            plaintext: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Resumes decrypting a stream from `checkpoint`, taken with [`StreamDecryptor::checkpoint`],
+    /// without the private key.
+    ///
+    /// `reader` must continue the encrypted stream at `checkpoint.ciphertext_offset()`, and the
+    /// plaintext read from the decryptor continues at `checkpoint.plaintext_offset()`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::InvalidCheckpoint` if the checkpoint is malformed.
+    pub fn resume(checkpoint: &StreamCheckpoint, reader: R) -> StreamResult<Self> {
+        let cipher = checkpoint.cipher()?;
+        Ok(Self {
+            reader,
+            chunk: Vec::with_capacity(cipher.chunk_size + TAG_LEN),
+            cipher,
+            lookahead: None,
+            plaintext: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Returns a checkpoint of the chunks whose plaintext was entirely read, to resume with
+    /// [`StreamDecryptor::resume`] if the transfer is interrupted.
+    ///
+    /// The checkpoint holds the data key of the stream: store it as securely as the plaintext.
+    pub fn checkpoint(&self) -> StreamCheckpoint {
+        // The chunk being read is decrypted again after resuming.
+        let reading = !self.cipher.done && self.position < self.plaintext.len();
+        self.cipher
+            .checkpoint(self.cipher.index - u32::from(reading))
+    }
+
+    /// Returns the inner reader, e.g. to read what follows the stream once it is fully decrypted.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads and decrypts the next chunk.
+    fn read_chunk(&mut self) -> StreamResult<()> {
+        let full_len = self.cipher.chunk_size + TAG_LEN;
+        self.chunk.clear();
+        self.chunk.extend(self.lookahead.take());
+        (&mut self.reader)
+            .take((full_len - self.chunk.len()) as u64)
+            .read_to_end(&mut self.chunk)?;
+        // A full chunk is the last one if nothing follows it.
+        let last = self.chunk.len() < full_len || {
+            let mut byte = [0];
+            let read = read_retrying(&mut self.reader, &mut byte)?;
+            self.lookahead = (read == 1).then_some(byte[0]);
+            read == 0
+        };
+        if self.chunk.is_empty() {
+            return Err(StreamError::Truncated(u64::from(self.cipher.index)));
+        }
+        self.plaintext = self.cipher.open(&self.chunk, last)?;
+        self.position = 0;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for StreamDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.plaintext.len() {
+            if self.cipher.done || buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let len = buf.len().min(self.plaintext.len() - self.position);
+        buf[..len].copy_from_slice(&self.plaintext[self.position..][..len]);
+        self.position += len;
+        Ok(len)
+    }
+}
+
+/// The state of a stream at a chunk boundary, to resume an interrupted encryption or decryption
+/// without starting over (see [`StreamEncryptor::checkpoint`] and [`StreamDecryptor::checkpoint`]).
+///
+/// It is encoded by [`StreamCheckpoint::to_bytes`] as:
+///
+/// ```text
+/// version (1 byte) | data key (32 bytes) | chunk index (4 bytes, big endian) | stream header
+/// ```
+///
+/// A checkpoint holds the data key of the stream, and decrypts it without the private key: keep
+/// it as secret as the plaintext, e.g. encrypted with `E2ee::wrap_key`, and delete it once the
+/// transfer completes.
+#[derive(Clone)]
+pub struct StreamCheckpoint {
+    data_key: Zeroizing<[u8; DATA_KEY_LEN]>,
+    header: Vec<u8>,
+    index: u32,
+}
+
+impl fmt::Debug for StreamCheckpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamCheckpoint")
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl StreamCheckpoint {
+    /// Returns the index of the chunk the stream resumes at.
+    pub fn chunk_index(&self) -> u32 {
+        self.index
+    }
+
+    /// Returns the offset in the plaintext that the stream resumes at.
+    pub fn plaintext_offset(&self) -> u64 {
+        u64::from(self.index) * self.chunk_size() as u64
+    }
+
+    /// Returns the offset in the encrypted stream, header included, that the stream resumes at.
+    pub fn ciphertext_offset(&self) -> u64 {
+        self.header.len() as u64
+            + u64::from(self.index) * (self.chunk_size() + TAG_LEN) as u64
+    }
+
+    /// Encodes the checkpoint, to be stored while the transfer is interrupted.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut encoded = Zeroizing::new(vec![CHECKPOINT_VERSION]);
+        encoded.extend_from_slice(self.data_key.as_ref());
+        encoded.extend(self.index.to_be_bytes());
+        encoded.extend_from_slice(&self.header);
+        encoded
+    }
+
+    /// Decodes a checkpoint encoded with [`StreamCheckpoint::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::InvalidCheckpoint` if `encoded` is malformed or of an
+    /// unknown version.
+    pub fn from_bytes(encoded: &[u8]) -> StreamResult<Self> {
+        let fixed_len = 1 + DATA_KEY_LEN + 4;
+        if encoded.len() < fixed_len || encoded[0] != CHECKPOINT_VERSION {
+            return Err(StreamError::InvalidCheckpoint);
+        }
+        let checkpoint = Self {
+            data_key: Zeroizing::new(
+                encoded[1..1 + DATA_KEY_LEN].try_into().expect("32 bytes"),
+            ),
+            index: u32::from_be_bytes(
+                encoded[1 + DATA_KEY_LEN..fixed_len]
+                    .try_into()
+                    .expect("4 bytes"),
+            ),
+            header: encoded[fixed_len..].to_vec(),
+        };
+        checkpoint.cipher()?;
+        Ok(checkpoint)
+    }
+
+    /// Returns the chunk size recorded in the header, which ends with it and the nonce prefix.
+    fn chunk_size(&self) -> usize {
+        let end = self.header.len() - NONCE_PREFIX_LEN;
+        u32::from_be_bytes(self.header[end - 4..end].try_into().expect("4 bytes"))
+            as usize
+    }
+
+    /// Returns the cipher of the chunks that follow the checkpoint, checking the header.
+    fn cipher(&self) -> StreamResult<ChunkCipher> {
+        let fixed_len = 1 + KEY_ID_LEN + 2;
+        let wrapped_key_len = self
+            .header
+            .get(1 + KEY_ID_LEN..fixed_len)
+            .map(|len| usize::from(u16::from_be_bytes([len[0], len[1]])));
+        if wrapped_key_len.map(|len| fixed_len + len + 4 + NONCE_PREFIX_LEN)
+            != Some(self.header.len())
+        {
+            return Err(StreamError::InvalidCheckpoint);
+        }
+        let (version, aead) = Aead::split_version(self.header[0]);
+        let chunk_size = self.chunk_size();
+        if version != VERSION || !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+            return Err(StreamError::InvalidCheckpoint);
+        }
+        Ok(ChunkCipher {
+            cipher: aead
+                .cipher(self.data_key.as_ref())
+                .ok_or(StreamError::InvalidCheckpoint)?,
+            data_key: self.data_key.clone(),
+            header: self.header.clone(),
+            nonce_prefix: self.header[self.header.len() - NONCE_PREFIX_LEN..]
+                .try_into()
+                .expect("7 bytes"),
+            chunk_size,
+            index: self.index,
+            done: false,
+        })
+    }
+}
+
+/// Decrypts an encrypted stream fed to it piece by piece, for callers running their own I/O loop
+/// rather than handing over a reader (see [`StreamDecryptor`]).
+///
+/// Only the pieces not decrypted yet are buffered, at most a chunk. As with [`StreamDecryptor`],
+/// discard the output of a failed decryption.
+pub struct PushDecryptor {
+    private_key: RsaPrivateKey,
+    cipher: Option<ChunkCipher>,
+    pending: Vec<u8>,
+}
+
+impl fmt::Debug for PushDecryptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushDecryptor").finish_non_exhaustive()
+    }
+}
+
+impl PushDecryptor {
+    /// Creates a decryptor for streams encrypted for `private_key`.
+    pub fn new(private_key: &RsaPrivateKey) -> Self {
+        Self {
+            private_key: private_key.clone(),
+            cipher: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Feeds the next piece of the stream, and returns the plaintext of the chunks it completes.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`StreamDecryptor::new`] once the header is complete,
+    /// or `StreamError::Authentication` if a chunk was tampered with.
+    pub fn update(&mut self, data: &[u8]) -> StreamResult<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+        let mut plaintext = Vec::new();
+        if self.cipher.is_none() {
+            let fixed_len = 1 + KEY_ID_LEN + 2;
+            let Some(len) = self.pending.get(1 + KEY_ID_LEN..fixed_len) else {
+                return Ok(plaintext);
+            };
+            let header_len = fixed_len
+                + usize::from(u16::from_be_bytes([len[0], len[1]]))
+                + 4
+                + NONCE_PREFIX_LEN;
+            if self.pending.len() < header_len {
+                return Ok(plaintext);
+            }
+            let cipher = read_stream_header(
+                &self.private_key,
+                &mut &self.pending[..header_len],
+            )?;
+            self.pending.drain(..header_len);
+            self.cipher = Some(cipher);
+        }
+        let cipher = self.cipher.as_mut().expect("the header was read");
+        let full_len = cipher.chunk_size + TAG_LEN;
+        // A full chunk is only opened once more data follows, as it may be the last one.
+        let mut start = 0;
+        while self.pending.len() - start > full_len {
+            plaintext
+                .extend(cipher.open(&self.pending[start..start + full_len], false)?);
+            start += full_len;
+        }
+        self.pending.drain(..start);
+        Ok(plaintext)
+    }
+
+    /// Decrypts the last chunk of the stream, once all of it was fed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `StreamError::Malformed` if the header is incomplete,
+    /// `StreamError::Truncated` if the stream is, or `StreamError::Authentication` if the last
+    /// chunk was tampered with.
+    pub fn finish(mut self) -> StreamResult<Vec<u8>> {
+        let cipher = self.cipher.as_mut().ok_or(StreamError::Malformed)?;
+        if self.pending.is_empty() {
+            return Err(StreamError::Truncated(u64::from(cipher.index)));
+        }
+        cipher.open(&self.pending, true)
+    }
+}
+
+/// Reads the header of a stream from `reader`, decrypts its data key with `private_key` and
+/// returns the cipher of its chunks.
+fn read_stream_header(
+    private_key: &RsaPrivateKey,
+    reader: &mut impl Read,
+) -> StreamResult<ChunkCipher> {
+    let mut header = vec![0; 1 + KEY_ID_LEN + 2];
+    read_header(reader, &mut header)?;
+    let (version, aead) = Aead::split_version(header[0]);
+    if version != VERSION {
+        return Err(StreamError::UnsupportedVersion(header[0]));
+    }
+    if header[1..1 + KEY_ID_LEN] != envelope::key_id(&private_key.to_public_key())? {
+        return Err(EnvelopeError::NotARecipient.into());
+    }
+    let wrapped_key_len =
+        u16::from_be_bytes([header[1 + KEY_ID_LEN], header[2 + KEY_ID_LEN]])
+            as usize;
+    let wrapped_key_start = header.len();
+    header.resize(wrapped_key_start + wrapped_key_len + 4, 0);
+    read_header(reader, &mut header[wrapped_key_start..])?;
+    let chunk_size =
+        u32::from_be_bytes(header[header.len() - 4..].try_into().expect("4 bytes"))
+            as usize;
+    if !(1..=MAX_CHUNK_SIZE).contains(&chunk_size) {
+        return Err(StreamError::InvalidChunkSize(chunk_size));
+    }
+    let mut nonce_prefix = [0u8; NONCE_PREFIX_LEN];
+    read_header(reader, &mut nonce_prefix)?;
+    header.extend_from_slice(&nonce_prefix);
+
+    let data_key = Zeroizing::new(
+        core::decrypt_with(
+            private_key,
+            OaepParams::SHA256,
+            &header[wrapped_key_start..wrapped_key_start + wrapped_key_len],
+        )
+        .map_err(EnvelopeError::from)?,
+    );
+    let cipher = aead.cipher(&data_key).ok_or(StreamError::Malformed)?;
+    Ok(ChunkCipher {
+        data_key: Zeroizing::new(
+            data_key
+                .as_slice()
+                .try_into()
+                .map_err(|_| StreamError::Malformed)?,
+        ),
+        cipher,
+        header,
+        nonce_prefix,
+        chunk_size,
+        index: 0,
+        done: false,
+    })
+}
+
+fn read_header(reader: &mut impl Read, buf: &mut [u8]) -> StreamResult<()> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => StreamError::Malformed,
+        _ => err.into(),
+    })
+}
+
+fn read_retrying(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    loop {
+        match reader.read(buf) {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Encrypts the file at `input` for `public_key` into the file at `output`, in chunks of
+/// [`DEFAULT_CHUNK_SIZE`] bytes.
+///
+/// # Errors
+///
+/// This function returns an error if a file cannot be read or written, or if encryption fails.
+pub fn encrypt_file(
+    public_key: &RsaPublicKey,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> StreamResult<()> {
+    encrypt_file_with_progress(public_key, input, output, |_, _| {})
+}
+
+/// Encrypts a file like [`encrypt_file`], calling `progress` with the number of bytes of `input`
+/// read so far and its length after every read.
+///
+/// # Errors
+///
+/// This function returns the errors of [`encrypt_file`].
+pub fn encrypt_file_with_progress(
+    public_key: &RsaPublicKey,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    progress: impl FnMut(u64, u64),
+) -> StreamResult<()> {
+    let mut reader = ProgressReader::new(File::open(input)?, progress)?;
+    let writer = BufWriter::new(File::create(output)?);
+    let mut encryptor = StreamEncryptor::new(public_key, writer)?;
+    io::copy(&mut reader, &mut encryptor)?;
+    encryptor
+        .finish()?
+        .into_inner()
+        .map_err(|err| err.into_error())?;
+    Ok(())
+}
+
+/// Decrypts the encrypted file at `input` with `private_key` into the file at `output`.
+///
+/// The plaintext is written to a temporary file next to `output`, which is only renamed to
+/// `output` once the whole stream is authenticated.
+///
+/// # Errors
+///
+/// This function returns an error if a file cannot be read or written, or any error of
+/// [`StreamDecryptor`].
+pub fn decrypt_file(
+    private_key: &RsaPrivateKey,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> StreamResult<()> {
+    decrypt_file_with_progress(private_key, input, output, |_, _| {})
+}
+
+/// Decrypts a file like [`decrypt_file`], calling `progress` with the number of bytes of `input`
+/// read so far and its length after every read.
+///
+/// # Errors
+///
+/// This function returns the errors of [`decrypt_file`].
+pub fn decrypt_file_with_progress(
+    private_key: &RsaPrivateKey,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    progress: impl FnMut(u64, u64),
+) -> StreamResult<()> {
+    write_authenticated(output.as_ref(), |partial| {
+        let reader = ProgressReader::new(File::open(input)?, progress)?;
+        let mut decryptor = StreamDecryptor::new(private_key, reader)?;
+        let mut writer = BufWriter::new(File::create(partial)?);
+        io::copy(&mut decryptor, &mut writer).map_err(unwrap_io_error)?;
+        Ok(writer.into_inner().map_err(|err| err.into_error())?)
+    })
+}
+
+/// Decrypts the encrypted file at `input` with `private_key` into the file at `output`, like
+/// [`decrypt_file`], and returns the length of the plaintext.
+///
+/// The encrypted file is memory-mapped and decrypted chunk by chunk into an output file allocated
+/// to the length of the plaintext up front, so that a single chunk is held in memory and running
+/// out of disk space fails before any decryption. This is meant for archives of several gigabytes
+/// on hosts with little memory: the mapped pages are part of the page cache, which the system
+/// reclaims as needed.
+///
+/// The encrypted file must not be modified while it is decrypted.
+///
+/// # Errors
+///
+/// This function returns an error if a file cannot be mapped, read or written, or any error of
+/// [`StreamDecryptor`].
+#[cfg(feature = "mmap")]
+pub fn decrypt_file_mmap(
+    private_key: &RsaPrivateKey,
+    input: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> StreamResult<u64> {
+    let file = File::open(input)?;
+    // SAFETY: the mapping is read-only, and the caller does not modify the file meanwhile. A file
+    // modified anyway can at worst make chunks fail authentication.
+    let mmap = unsafe { memmap2::Mmap::map(&file)? };
+    #[cfg(unix)]
+    mmap.advise(memmap2::Advice::Sequential)?;
+
+    let mut body = &mmap[..];
+    let mut cipher = read_stream_header(private_key, &mut body)?;
+    let chunk_len = cipher.chunk_size + TAG_LEN;
+    let chunk_count = body.len().div_ceil(chunk_len);
+    if chunk_count == 0 {
+        return Err(StreamError::Truncated(0));
+    }
+    let plaintext_len = body.len().saturating_sub(chunk_count * TAG_LEN) as u64;
+
+    write_authenticated(output.as_ref(), |partial| {
+        let mut writer = File::create(partial)?;
+        writer.set_len(plaintext_len)?;
+        for (index, chunk) in body.chunks(chunk_len).enumerate() {
+            writer.write_all(&cipher.open(chunk, index + 1 == chunk_count)?)?;
+        }
+        Ok(writer)
+    })?;
+    Ok(plaintext_len)
+}
+
+/// Writes `output` through `write`, which writes a temporary file next to it and returns it. The
+/// file is synced and renamed to `output` on success, and removed on failure.
+fn write_authenticated(
+    output: &Path,
+    write: impl FnOnce(&Path) -> StreamResult<File>,
+) -> StreamResult<()> {
+    let partial = partial_path(output);
+    let result = write(&partial).and_then(|file| {
+        file.sync_all()?;
+        Ok(fs::rename(&partial, output)?)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&partial);
+    }
+    result
+}
+
+/// Reads a file through a buffer, reporting the bytes read so far and the length of the file.
+struct ProgressReader<F> {
+    inner: BufReader<File>,
+    read: u64,
+    len: u64,
+    progress: F,
+}
+
+impl<F: FnMut(u64, u64)> ProgressReader<F> {
+    fn new(file: File, progress: F) -> io::Result<Self> {
+        Ok(Self {
+            len: file.metadata()?.len(),
+            inner: BufReader::new(file),
+            read: 0,
+            progress,
+        })
+    }
+}
+
+impl<F: FnMut(u64, u64)> Read for ProgressReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        (self.progress)(self.read, self.len);
+        Ok(read)
+    }
+}
+
+/// Returns the path of the temporary file written while decrypting to `output`.
+fn partial_path(output: &Path) -> PathBuf {
+    let mut name = output.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    output.with_file_name(name)
+}
+
+/// Recovers the `StreamError` wrapped into an `io::Error` by [`StreamDecryptor`].
+fn unwrap_io_error(err: io::Error) -> StreamError {
+    if !err.get_ref().is_some_and(|inner| inner.is::<StreamError>()) {
+        return StreamError::Io(err);
+    }
+    match err
+        .into_inner()
+        .map(|inner| inner.downcast::<StreamError>())
+    {
+        Some(Ok(inner)) => *inner,
+        _ => unreachable!("the inner error is a StreamError"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(
+        public_key: &RsaPublicKey,
+        chunk_size: usize,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(public_key, Vec::new())
+            .unwrap()
+            .with_chunk_size(chunk_size)
+            .unwrap();
+        encryptor.write_all(data).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt(
+        private_key: &RsaPrivateKey,
+        encrypted: &[u8],
+    ) -> StreamResult<Vec<u8>> {
+        let mut decryptor = StreamDecryptor::new(private_key, encrypted)?;
+        let mut decrypted = Vec::new();
+        decryptor
+            .read_to_end(&mut decrypted)
+            .map_err(unwrap_io_error)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let public_key = private_key.to_public_key();
+        for len in [0, 1, 15, 16, 17, 32, 100] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let encrypted = encrypt(&public_key, 16, &data);
+            assert_eq!(decrypt(&private_key, &encrypted).unwrap(), data);
+        }
+
+        let other_key = core::generate_private_key(1024).unwrap();
+        let encrypted = encrypt(&public_key, 16, b"Hello");
+        assert!(matches!(
+            decrypt(&other_key, &encrypted),
+            Err(StreamError::Envelope(EnvelopeError::NotARecipient))
+        ));
+    }
+
+    #[test]
+    fn test_chacha20_poly1305_stream_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let mut encryptor = StreamEncryptor::new_with_aead(
+            &private_key.to_public_key(),
+            Aead::ChaCha20Poly1305,
+            Vec::new(),
+        )
+        .unwrap()
+        .with_chunk_size(16)
+        .unwrap();
+        encryptor.write_all(&data).unwrap();
+        let mut encrypted = encryptor.finish().unwrap();
+        assert_eq!(encrypted[0], VERSION | envelope::CHACHA20_POLY1305_FLAG);
+        assert_eq!(decrypt(&private_key, &encrypted).unwrap(), data);
+
+        encrypted[0] = VERSION;
+        assert!(matches!(
+            decrypt(&private_key, &encrypted),
+            Err(StreamError::Authentication(0))
+        ));
+    }
+
+    #[test]
+    fn test_stream_detects_reordering_and_truncation() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let data: Vec<u8> = (0..64).collect();
+        let encrypted = encrypt(&private_key.to_public_key(), 16, &data);
+        let chunk_len = 16 + TAG_LEN;
+        let header_len = encrypted.len() - 4 * chunk_len;
+        let chunks: Vec<&[u8]> = encrypted[header_len..].chunks(chunk_len).collect();
+        let reassemble = |order: &[usize]| {
+            let mut stream = encrypted[..header_len].to_vec();
+            for &index in order {
+                stream.extend_from_slice(chunks[index]);
+            }
+            stream
+        };
+
+        assert_eq!(
+            decrypt(&private_key, &reassemble(&[0, 1, 2, 3])).unwrap(),
+            data
+        );
+        assert!(matches!(
+            decrypt(&private_key, &reassemble(&[1, 0, 2, 3])),
+            Err(StreamError::Authentication(0))
+        ));
+        assert!(matches!(
+            decrypt(&private_key, &reassemble(&[0, 0, 1, 2, 3])),
+            Err(StreamError::Authentication(1))
+        ));
+        assert!(matches!(
+            decrypt(&private_key, &reassemble(&[0, 1, 2])),
+            Err(StreamError::Truncated(2))
+        ));
+        assert!(matches!(
+            decrypt(&private_key, &reassemble(&[])),
+            Err(StreamError::Truncated(0))
+        ));
+        assert!(matches!(
+            decrypt(&private_key, &reassemble(&[0, 1, 3])),
+            Err(StreamError::Authentication(2))
+        ));
+
+        let mut tampered = encrypted.clone();
+        tampered[header_len + chunk_len + 3] ^= 1;
+        assert!(matches!(
+            decrypt(&private_key, &tampered),
+            Err(StreamError::Authentication(1))
+        ));
+        let mut tampered = encrypted.clone();
+        // The chunk size is part of the authenticated header.
+        tampered[header_len - NONCE_PREFIX_LEN - 1] = 32;
+        assert!(decrypt(&private_key, &tampered).is_err());
+    }
+
+    #[test]
+    fn test_resume_from_checkpoints() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let mut encryptor =
+            StreamEncryptor::new(&private_key.to_public_key(), Vec::new())
+                .unwrap()
+                .with_chunk_size(16)
+                .unwrap();
+        encryptor.write_all(&data[..40]).unwrap();
+        let checkpoint = encryptor.checkpoint().unwrap();
+        assert_eq!(checkpoint.chunk_index(), 2);
+        assert_eq!(checkpoint.plaintext_offset(), 32);
+        let mut sent = encryptor.get_mut().clone();
+        assert_eq!(sent.len() as u64, checkpoint.ciphertext_offset());
+        assert!(encryptor.with_chunk_size(32).is_err());
+
+        sent.extend_from_slice(b"garbage of an interrupted chunk");
+        sent.truncate(checkpoint.ciphertext_offset() as usize);
+        let checkpoint =
+            StreamCheckpoint::from_bytes(&checkpoint.to_bytes()).unwrap();
+        let mut encryptor = StreamEncryptor::resume(&checkpoint, sent).unwrap();
+        encryptor.write_all(&data[32..]).unwrap();
+        let encrypted = encryptor.finish().unwrap();
+        assert_eq!(decrypt(&private_key, &encrypted).unwrap(), data);
+
+        let mut decryptor =
+            StreamDecryptor::new(&private_key, &encrypted[..]).unwrap();
+        let mut decrypted = vec![0; 40];
+        decryptor.read_exact(&mut decrypted).unwrap();
+        let checkpoint = decryptor.checkpoint();
+        assert_eq!(checkpoint.plaintext_offset(), 32);
+        decrypted.truncate(32);
+        let offset = checkpoint.ciphertext_offset() as usize;
+        StreamDecryptor::resume(&checkpoint, &encrypted[offset..])
+            .unwrap()
+            .read_to_end(&mut decrypted)
+            .unwrap();
+        assert_eq!(decrypted, data);
+
+        let mut token = checkpoint.to_bytes();
+        token.pop();
+        assert!(matches!(
+            StreamCheckpoint::from_bytes(&token),
+            Err(StreamError::InvalidCheckpoint)
+        ));
+    }
+
+    #[test]
+    fn test_push_decryptor() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let data: Vec<u8> = (0..100).collect();
+        let encrypted = encrypt(&private_key.to_public_key(), 16, &data);
+        for piece_len in [1, 7, 32, encrypted.len()] {
+            let mut decryptor = PushDecryptor::new(&private_key);
+            let mut decrypted = Vec::new();
+            for piece in encrypted.chunks(piece_len) {
+                decrypted.extend(decryptor.update(piece).unwrap());
+            }
+            decrypted.extend(decryptor.finish().unwrap());
+            assert_eq!(decrypted, data);
+        }
+
+        let mut decryptor = PushDecryptor::new(&private_key);
+        decryptor
+            .update(&encrypted[..encrypted.len() - 20])
+            .unwrap();
+        assert!(matches!(decryptor.finish(), Err(StreamError::Truncated(5))));
+        let mut decryptor = PushDecryptor::new(&private_key);
+        decryptor.update(&encrypted[..10]).unwrap();
+        assert!(matches!(decryptor.finish(), Err(StreamError::Malformed)));
+    }
+
+    #[test]
+    fn test_file_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("e2ee-stream-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("plain"), &data).unwrap();
+
+        encrypt_file(
+            &private_key.to_public_key(),
+            dir.join("plain"),
+            dir.join("enc"),
+        )
+        .unwrap();
+        decrypt_file(&private_key, dir.join("enc"), dir.join("dec")).unwrap();
+        assert_eq!(fs::read(dir.join("dec")).unwrap(), data);
+
+        let mut encrypted = fs::read(dir.join("enc")).unwrap();
+        encrypted.truncate(encrypted.len() - 1);
+        fs::write(dir.join("enc"), &encrypted).unwrap();
+        assert!(matches!(
+            decrypt_file(&private_key, dir.join("enc"), dir.join("bad")),
+            Err(StreamError::Authentication(3))
+        ));
+        assert!(!dir.join("bad").exists());
+        assert!(!dir.join("bad.part").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_mmap_file_roundtrip() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-stream-mmap-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("plain"), &data).unwrap();
+        encrypt_file(
+            &private_key.to_public_key(),
+            dir.join("plain"),
+            dir.join("enc"),
+        )
+        .unwrap();
+
+        let len = decrypt_file_mmap(&private_key, dir.join("enc"), dir.join("dec"))
+            .unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(fs::read(dir.join("dec")).unwrap(), data);
+
+        // Drop the last chunk, at a chunk boundary.
+        let encrypted = fs::read(dir.join("enc")).unwrap();
+        let last_chunk_len = data.len() % DEFAULT_CHUNK_SIZE + TAG_LEN;
+        fs::write(
+            dir.join("enc"),
+            &encrypted[..encrypted.len() - last_chunk_len],
+        )
+        .unwrap();
+        assert!(matches!(
+            decrypt_file_mmap(&private_key, dir.join("enc"), dir.join("bad")),
+            Err(StreamError::Truncated(2))
+        ));
+        assert!(!dir.join("bad").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Property-based testing utilities for code built on this crate.
+//!
+//! This module is enabled by the `test-utils` feature, typically as a dev-dependency:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! e2ee = { version = "0.1", features = ["test-utils"] }
+//! ```
+//!
+//! It provides [`proptest`] strategies for messages of arbitrary sizes and contents, and
+//! [`assert_roundtrip`] to check that any [`Encryptor`] / [`Decryptor`] pair, such as an
+//! application wrapper around `E2ee`, returns every message unchanged. [`MockE2ee`] stands in for
+//! `E2ee` in application tests that should not pay for RSA key generation.
+use crate::server::E2eeError;
+use crate::traits::{Decryptor, Encryptor};
+use base64::{engine::general_purpose, Engine};
+use proptest::{
+    collection::vec,
+    prelude::*,
+    test_runner::{Config, TestCaseError, TestRunner},
+};
+use std::fmt::Debug;
+
+/// The longest message, in bytes, generated by [`assert_roundtrip`].
+///
+/// This is the RSA-OAEP (SHA-256) capacity of the smallest supported key size, 1024 bits.
+pub const MAX_MESSAGE_LEN: usize = 62;
+
+/// Generates printable ASCII strings of up to `max_len` bytes.
+pub fn payloads(max_len: usize) -> impl Strategy<Value = String> {
+    vec(0x20u8..0x7f, 0..=max_len)
+        .prop_map(|bytes| bytes.into_iter().map(char::from).collect())
+}
+
+/// Generates strings of arbitrary Unicode scalar values of up to `max_len` bytes.
+pub fn unicode_strings(max_len: usize) -> impl Strategy<Value = String> {
+    vec(any::<char>(), 0..=max_len).prop_map(move |chars| {
+        let mut message = String::new();
+        for c in chars {
+            if message.len() + c.len_utf8() > max_len {
+                break;
+            }
+            message.push(c);
+        }
+        message
+    })
+}
+
+/// Generates binary blobs of up to `max_len` bytes.
+pub fn binary_blobs(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=max_len)
+}
+
+/// Generates messages of up to `max_len` bytes: ASCII payloads, Unicode strings, and binary
+/// blobs encoded as base64, the way binary data travels through the string API.
+pub fn messages(max_len: usize) -> impl Strategy<Value = String> {
+    prop_oneof![
+        payloads(max_len),
+        unicode_strings(max_len),
+        binary_blobs(max_len / 4 * 3)
+            .prop_map(|blob| general_purpose::STANDARD.encode(blob)),
+    ]
+}
+
+/// Asserts that `decryptor` recovers every message encrypted by `encryptor`.
+///
+/// Runs 32 cases drawn from [`messages`]`(`[`MAX_MESSAGE_LEN`]`)`. Use [`assert_roundtrip_with`]
+/// to choose the number of cases or the messages.
+///
+/// # Panics
+///
+/// Panics with the smallest failing message if encryption or decryption fails, or if the
+/// decrypted message differs from the original.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::server::{E2ee, KeySize};
+/// use e2ee::test_utils::assert_roundtrip;
+///
+/// let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+/// assert_roundtrip(&e2ee, &e2ee);
+/// ```
+pub fn assert_roundtrip<E, D>(encryptor: &E, decryptor: &D)
+where
+    E: Encryptor,
+    E::Error: Debug,
+    D: Decryptor,
+    D::Error: Debug,
+{
+    assert_roundtrip_with(
+        encryptor,
+        decryptor,
+        Config::with_cases(32),
+        messages(MAX_MESSAGE_LEN),
+    );
+}
+
+/// Asserts that `decryptor` recovers every message drawn from `strategy` and encrypted by
+/// `encryptor`, running the number of cases set in `config`.
+///
+/// # Panics
+///
+/// Panics with the smallest failing message if encryption or decryption fails, or if the
+/// decrypted message differs from the original.
+pub fn assert_roundtrip_with<E, D, S>(
+    encryptor: &E,
+    decryptor: &D,
+    config: Config,
+    strategy: S,
+) where
+    E: Encryptor,
+    E::Error: Debug,
+    D: Decryptor,
+    D::Error: Debug,
+    S: Strategy<Value = String>,
+{
+    let mut runner = TestRunner::new(config);
+    let result = runner.run(&strategy, |message| {
+        let ciphertext = encryptor.encrypt(&message).map_err(|err| {
+            TestCaseError::fail(format!("encryption failed: {err:?}"))
+        })?;
+        let decrypted = decryptor.decrypt(&ciphertext).map_err(|err| {
+            TestCaseError::fail(format!("decryption failed: {err:?}"))
+        })?;
+        prop_assert_eq!(decrypted, message);
+        Ok(())
+    });
+    if let Err(err) = result {
+        panic!("Round trip failed: {err}");
+    }
+}
+
+/// A fast, deterministic stand-in for `E2ee` in tests.
+///
+/// `MockE2ee` implements [`Encryptor`] and [`Decryptor`] with a reversible, *insecure* encoding:
+/// the ciphertext is `mock:` followed by the base64-encoded message. It needs no key, and its
+/// output is stable, so it can be used in snapshot tests. Ciphertexts without the prefix or with
+/// invalid base64 fail to decrypt with `E2eeError::DecryptionFailed`, like `E2ee::decrypt`.
+///
+/// # Examples
+///
+/// ```
+/// use e2ee::test_utils::MockE2ee;
+/// use e2ee::traits::{Decryptor, Encryptor};
+///
+/// let mock = MockE2ee::new();
+/// let encrypted = mock.encrypt("Secret message").unwrap();
+/// assert_eq!(encrypted, "mock:U2VjcmV0IG1lc3NhZ2U=");
+/// assert_eq!(mock.decrypt(&encrypted).unwrap(), "Secret message");
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MockE2ee;
+
+impl MockE2ee {
+    /// The prefix of every ciphertext produced by `MockE2ee`.
+    pub const PREFIX: &'static str = "mock:";
+
+    /// Creates a new `MockE2ee`.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Encryptor for MockE2ee {
+    type Error = E2eeError;
+
+    fn encrypt(&self, message: &str) -> Result<String, E2eeError> {
+        Ok(format!(
+            "{}{}",
+            Self::PREFIX,
+            general_purpose::STANDARD.encode(message)
+        ))
+    }
+}
+
+impl Decryptor for MockE2ee {
+    type Error = E2eeError;
+
+    fn decrypt(&self, ciphertext: &str) -> Result<String, E2eeError> {
+        let encoded = ciphertext
+            .strip_prefix(Self::PREFIX)
+            .ok_or(E2eeError::DecryptionFailed)?;
+        let decoded = general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|_| E2eeError::DecryptionFailed)?;
+        String::from_utf8(decoded).map_err(|_| E2eeError::DecryptionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{E2ee, KeySize};
+
+    struct Identity;
+
+    impl Encryptor for Identity {
+        type Error = ();
+
+        fn encrypt(&self, message: &str) -> Result<String, ()> {
+            Ok(message.to_string())
+        }
+    }
+
+    struct Truncating;
+
+    impl Decryptor for Truncating {
+        type Error = ();
+
+        fn decrypt(&self, ciphertext: &str) -> Result<String, ()> {
+            Ok(ciphertext.chars().skip(1).collect())
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_strategies_respect_max_len(
+            payload in payloads(16),
+            unicode in unicode_strings(16),
+            blob in binary_blobs(16),
+            message in messages(16),
+        ) {
+            prop_assert!(payload.len() <= 16);
+            prop_assert!(unicode.len() <= 16);
+            prop_assert!(blob.len() <= 16);
+            prop_assert!(message.len() <= 16);
+        }
+    }
+
+    #[test]
+    fn test_assert_roundtrip_e2ee() {
+        let e2ee = E2ee::new(KeySize::Bit1024).unwrap();
+        assert_roundtrip(&e2ee, &e2ee);
+    }
+
+    #[test]
+    fn test_mock_e2ee() {
+        let mock = MockE2ee::new();
+        assert_roundtrip(&mock, &mock);
+        assert_eq!(mock.encrypt("").unwrap(), MockE2ee::PREFIX);
+        for invalid in ["U2VjcmV0", "mock:not base64!", "mock:/w=="] {
+            assert!(matches!(
+                mock.decrypt(invalid),
+                Err(E2eeError::DecryptionFailed)
+            ));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Round trip failed")]
+    fn test_assert_roundtrip_detects_corruption() {
+        assert_roundtrip(&Identity, &Truncating);
+    }
+}
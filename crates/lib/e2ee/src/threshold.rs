@@ -0,0 +1,574 @@
+//! Threshold decryption: t-of-n sharing of an RSA private key.
+//!
+//! [`split`] splits an RSA private key into `n` [`KeyShare`]s, so that any `t` of them can
+//! decrypt together, and fewer cannot. Each shareholder computes a [`PartialDecryption`] of a
+//! ciphertext with its share alone, and whoever collects `t` of them combines them into the
+//! plaintext with [`ThresholdKey::combine`]. The private key is never reconstructed: the shares
+//! only ever meet as partial decryptions of one ciphertext, which reveal nothing about other
+//! ciphertexts.
+//!
+//! The scheme is Shoup's threshold RSA ("Practical Threshold Signatures", Eurocrypt 2000) applied
+//! to RSA-OAEP decryption, with a trusted dealer: the machine calling [`split`] holds the whole
+//! key once, and should destroy it after handing out the shares. Partial decryptions carry no
+//! proof of correctness; a wrong one makes [`ThresholdKey::combine`] fail, without telling which.
+//!
+//! Ciphertexts are those of `core::encrypt_with` and `E2ee::encrypt` (after base64 decoding), and
+//! envelopes are opened with [`KeyShare::decrypt_envelope_share`] and
+//! [`ThresholdKey::open_envelope`].
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::threshold;
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let (key, shares) = threshold::split(&private_key, 2, 3).expect("Failed to split key");
+//! drop(private_key);
+//!
+//! let ciphertext = core::encrypt(key.public_key(), b"launch code").expect("Failed to encrypt");
+//! // Any two shareholders decrypt their share, on their own machines.
+//! let partials = [&shares[0], &shares[2]]
+//!     .map(|share| share.decrypt_share(&ciphertext).expect("Failed to decrypt share"));
+//! let plaintext = key.combine(&ciphertext, &partials).expect("Failed to combine");
+//! assert_eq!(plaintext, b"launch code");
+//! ```
+use crate::core::{self, OaepParams};
+use crate::envelope::{self, Envelope, EnvelopeError};
+use num_bigint_dig::{BigInt, ExtendedGcd, ModInverse, Sign};
+use rsa::{
+    rand_core::{OsRng, RngCore},
+    traits::{PrivateKeyParts, PublicKeyParts},
+    BigUint, RsaPrivateKey, RsaPublicKey,
+};
+use std::{fmt, iter, time::SystemTime};
+use thiserror::Error;
+
+/// The version of the encoding of key shares and partial decryptions.
+const VERSION: u8 = 1;
+
+pub type ThresholdResult<T> = std::result::Result<T, ThresholdError>;
+
+/// An error returned when splitting keys or combining partial decryptions.
+#[derive(Error, Debug)]
+pub enum ThresholdError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("Envelope error: {0}")]
+    Envelope(#[from] EnvelopeError),
+
+    #[error("Invalid threshold of {threshold} out of {shares} shares")]
+    InvalidThreshold { threshold: u8, shares: u8 },
+
+    #[error("Unsupported key: {0}")]
+    UnsupportedKey(&'static str),
+
+    #[error("Invalid share index {0}")]
+    InvalidIndex(u8),
+
+    #[error("{found} partial decryptions of distinct shares, {needed} needed")]
+    NotEnoughShares { found: usize, needed: u8 },
+
+    #[error(
+        "The partial decryptions do not combine into a decryption of the ciphertext"
+    )]
+    InvalidPartialDecryption,
+
+    #[error("Malformed key share or partial decryption")]
+    Malformed,
+}
+
+/// Splits `private_key` into `shares` key shares, any `threshold` of which can decrypt together.
+///
+/// Shares are numbered from 1; the share with index `i` is at position `i - 1` in the returned
+/// vector. The returned [`ThresholdKey`] holds the public parameters needed to combine partial
+/// decryptions.
+///
+/// # Errors
+///
+/// This function returns `ThresholdError::InvalidThreshold` unless `1 <= threshold <= shares`, or
+/// `ThresholdError::UnsupportedKey` if the key has more than two primes or if its public exponent
+/// has a factor no larger than `shares`.
+pub fn split(
+    private_key: &RsaPrivateKey,
+    threshold: u8,
+    shares: u8,
+) -> ThresholdResult<(ThresholdKey, Vec<KeyShare>)> {
+    let key = ThresholdKey::new(private_key.to_public_key(), threshold, shares)?;
+    let [p, q] = private_key.primes() else {
+        return Err(ThresholdError::UnsupportedKey(
+            "only two-prime keys can be split",
+        ));
+    };
+    // Partial decryptions are computed among the squares modulo n, a group of order
+    // (p - 1)(q - 1) / 4, in which the private exponent is the inverse of e modulo that order.
+    let order = ((p - 1u32) * (q - 1u32)) >> 2;
+    let exponent = private_key
+        .e()
+        .mod_inverse(&order)
+        .and_then(|exponent| exponent.to_biguint())
+        .ok_or(ThresholdError::UnsupportedKey(
+            "the public exponent is not invertible",
+        ))?;
+    // A random polynomial of degree threshold - 1 whose value at 0 is the private exponent.
+    let coefficients: Vec<BigUint> = iter::once(exponent)
+        .chain((1..threshold).map(|_| random_below(&order)))
+        .collect();
+    let shares = (1..=shares)
+        .map(|index| {
+            let secret = coefficients
+                .iter()
+                .rev()
+                .fold(BigUint::default(), |acc, c| (acc * index + c) % &order);
+            KeyShare {
+                key: key.clone(),
+                index,
+                secret,
+            }
+        })
+        .collect();
+    Ok((key, shares))
+}
+
+/// The public parameters of a shared key: its public key, the threshold and the number of shares.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdKey {
+    public_key: RsaPublicKey,
+    threshold: u8,
+    shares: u8,
+}
+
+impl ThresholdKey {
+    /// Creates the parameters of `public_key` split into `shares` shares, `threshold` of which are
+    /// needed to decrypt, e.g. to combine partial decryptions without holding a share.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`split`] for these parameters.
+    pub fn new(
+        public_key: RsaPublicKey,
+        threshold: u8,
+        shares: u8,
+    ) -> ThresholdResult<Self> {
+        if threshold == 0 || threshold > shares {
+            return Err(ThresholdError::InvalidThreshold { threshold, shares });
+        }
+        // Combining requires e to be coprime with 4 * shares!^2.
+        let e = public_key.e();
+        if (2..=u32::from(shares)).any(|factor| (e % factor) == BigUint::default()) {
+            return Err(ThresholdError::UnsupportedKey(
+                "the public exponent has a factor no larger than the number of shares",
+            ));
+        }
+        Ok(Self {
+            public_key,
+            threshold,
+            shares,
+        })
+    }
+
+    /// Returns the public key, to encrypt for the shareholders.
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    /// Returns the number of shares needed to decrypt.
+    pub fn threshold(&self) -> u8 {
+        self.threshold
+    }
+
+    /// Returns the total number of shares.
+    pub fn shares(&self) -> u8 {
+        self.shares
+    }
+
+    /// Combines partial decryptions of an RSA-OAEP (SHA-256) `ciphertext` into its plaintext.
+    ///
+    /// Only the first partial decryption of each share is used, and only `threshold` of them.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ThresholdError::NotEnoughShares` if `partials` come from fewer than
+    /// `threshold` distinct shares, `ThresholdError::InvalidPartialDecryption` if one of them is
+    /// wrong (e.g. computed for another ciphertext), or `ThresholdError::Rsa` if the plaintext is
+    /// not correctly padded.
+    pub fn combine(
+        &self,
+        ciphertext: &[u8],
+        partials: &[PartialDecryption],
+    ) -> ThresholdResult<Vec<u8>> {
+        self.combine_with(OaepParams::SHA256, ciphertext, partials)
+    }
+
+    /// Combines partial decryptions like [`ThresholdKey::combine`], for a `ciphertext` encrypted
+    /// with the OAEP `params`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`ThresholdKey::combine`].
+    pub fn combine_with(
+        &self,
+        params: OaepParams,
+        ciphertext: &[u8],
+        partials: &[PartialDecryption],
+    ) -> ThresholdResult<Vec<u8>> {
+        let n = self.public_key.n();
+        let c = ciphertext_integer(&self.public_key, ciphertext)?;
+        let mut selected: Vec<&PartialDecryption> = Vec::new();
+        for partial in partials {
+            if partial.index == 0 || partial.index > self.shares {
+                return Err(ThresholdError::InvalidIndex(partial.index));
+            }
+            if selected.len() < usize::from(self.threshold)
+                && selected.iter().all(|other| other.index != partial.index)
+            {
+                selected.push(partial);
+            }
+        }
+        if selected.len() < usize::from(self.threshold) {
+            return Err(ThresholdError::NotEnoughShares {
+                found: selected.len(),
+                needed: self.threshold,
+            });
+        }
+
+        // Each partial decryption is c^(2 delta s_i), with delta = shares!. Raised to twice its
+        // Lagrange coefficient at 0 scaled by delta, an integer, their product is c^(4 delta^2 d).
+        let delta = BigInt::from_biguint(Sign::Plus, factorial(self.shares));
+        let mut combined = BigUint::from(1u32);
+        for partial in &selected {
+            let mut numerator = delta.clone();
+            let mut denominator = BigInt::from(1);
+            for other in selected.iter().filter(|other| other.index != partial.index)
+            {
+                numerator *= BigInt::from(other.index);
+                denominator *=
+                    BigInt::from(i16::from(other.index) - i16::from(partial.index));
+            }
+            let coefficient = numerator / denominator * 2u32;
+            combined = combined * pow_signed(&partial.value, &coefficient, n)? % n;
+        }
+        // With a 4 delta^2 + b e = 1, c^d = (c^(4 delta^2 d))^a c^b.
+        let (_, a, b) = (&delta * &delta * 4u32).extended_gcd(self.public_key.e());
+        let decrypted = pow_signed(&combined, &a, n)? * pow_signed(&c, &b, n)? % n;
+        if decrypted.modpow(self.public_key.e(), n) != c {
+            return Err(ThresholdError::InvalidPartialDecryption);
+        }
+        Ok(core::oaep_decode(
+            params,
+            self.public_key.size(),
+            &decrypted.to_bytes_be(),
+        )?)
+    }
+
+    /// Opens an envelope (see the `envelope` module) with partial decryptions of its wrapped data
+    /// key, computed with [`KeyShare::decrypt_envelope_share`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns the errors of [`ThresholdKey::combine`], or
+    /// `ThresholdError::Envelope` if the envelope is malformed, expired or was tampered with.
+    pub fn open_envelope(
+        &self,
+        envelope: &[u8],
+        partials: &[PartialDecryption],
+    ) -> ThresholdResult<Vec<u8>> {
+        let data_key = self.combine(
+            Envelope::parse(envelope)?.wrapped_key_for(&self.public_key)?,
+            partials,
+        )?;
+        Ok(envelope::open_with(
+            envelope,
+            &self.public_key,
+            Some(SystemTime::now()),
+            |_| Ok(data_key),
+        )?)
+    }
+}
+
+/// The share of a private key held by one shareholder.
+///
+/// The `Debug` implementation does not print the secret share.
+#[derive(Clone, PartialEq, Eq)]
+pub struct KeyShare {
+    key: ThresholdKey,
+    index: u8,
+    secret: BigUint,
+}
+
+impl fmt::Debug for KeyShare {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyShare")
+            .field("index", &self.index)
+            .field("threshold", &self.key.threshold)
+            .field("shares", &self.key.shares)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KeyShare {
+    /// Returns the index of the share, from 1 to the number of shares.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Returns the public parameters of the shared key.
+    pub fn threshold_key(&self) -> &ThresholdKey {
+        &self.key
+    }
+
+    /// Computes the partial decryption of an RSA-OAEP `ciphertext` with this share.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ThresholdError::Rsa` if the ciphertext is not smaller than the
+    /// modulus.
+    pub fn decrypt_share(
+        &self,
+        ciphertext: &[u8],
+    ) -> ThresholdResult<PartialDecryption> {
+        let c = ciphertext_integer(&self.key.public_key, ciphertext)?;
+        let exponent = factorial(self.key.shares) * 2u32 * &self.secret;
+        Ok(PartialDecryption {
+            index: self.index,
+            value: c.modpow(&exponent, self.key.public_key.n()),
+        })
+    }
+
+    /// Computes the partial decryption of the data key wrapped in `envelope` with this share.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ThresholdError::Envelope` if the envelope is malformed.
+    pub fn decrypt_envelope_share(
+        &self,
+        envelope: &[u8],
+    ) -> ThresholdResult<PartialDecryption> {
+        self.decrypt_share(
+            Envelope::parse(envelope)?.wrapped_key_for(&self.key.public_key)?,
+        )
+    }
+
+    /// Encodes the share, to hand it to its shareholder. The encoding contains the secret share:
+    /// store and transmit it like a private key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes =
+            vec![VERSION, self.key.threshold, self.key.shares, self.index];
+        for field in [
+            self.key.public_key.n(),
+            self.key.public_key.e(),
+            &self.secret,
+        ] {
+            write_field(&mut bytes, &field.to_bytes_be());
+        }
+        bytes
+    }
+
+    /// Decodes a share encoded with [`KeyShare::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ThresholdError::Malformed` if `bytes` is not a valid encoding.
+    pub fn from_bytes(mut bytes: &[u8]) -> ThresholdResult<Self> {
+        let [VERSION, threshold, shares, index] = *take(&mut bytes, 4)? else {
+            return Err(ThresholdError::Malformed);
+        };
+        let n = BigUint::from_bytes_be(read_field(&mut bytes)?);
+        let e = BigUint::from_bytes_be(read_field(&mut bytes)?);
+        let secret = BigUint::from_bytes_be(read_field(&mut bytes)?);
+        if !bytes.is_empty() || index == 0 || index > shares {
+            return Err(ThresholdError::Malformed);
+        }
+        let public_key =
+            RsaPublicKey::new(n, e).map_err(|_| ThresholdError::Malformed)?;
+        Ok(Self {
+            key: ThresholdKey::new(public_key, threshold, shares)?,
+            index,
+            secret,
+        })
+    }
+}
+
+/// The partial decryption of a ciphertext computed with one [`KeyShare`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialDecryption {
+    index: u8,
+    value: BigUint,
+}
+
+impl PartialDecryption {
+    /// Returns the index of the share that computed this partial decryption.
+    pub fn index(&self) -> u8 {
+        self.index
+    }
+
+    /// Encodes the partial decryption, to send it to whoever combines them.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION, self.index];
+        write_field(&mut bytes, &self.value.to_bytes_be());
+        bytes
+    }
+
+    /// Decodes a partial decryption encoded with [`PartialDecryption::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// This function returns `ThresholdError::Malformed` if `bytes` is not a valid encoding.
+    pub fn from_bytes(mut bytes: &[u8]) -> ThresholdResult<Self> {
+        let [VERSION, index] = *take(&mut bytes, 2)? else {
+            return Err(ThresholdError::Malformed);
+        };
+        let value = BigUint::from_bytes_be(read_field(&mut bytes)?);
+        if !bytes.is_empty() {
+            return Err(ThresholdError::Malformed);
+        }
+        Ok(Self { index, value })
+    }
+}
+
+fn ciphertext_integer(
+    public_key: &RsaPublicKey,
+    ciphertext: &[u8],
+) -> ThresholdResult<BigUint> {
+    let c = BigUint::from_bytes_be(ciphertext);
+    if ciphertext.len() != public_key.size() || c >= *public_key.n() {
+        return Err(rsa::errors::Error::Decryption.into());
+    }
+    Ok(c)
+}
+
+fn factorial(n: u8) -> BigUint {
+    (2..=u32::from(n)).fold(BigUint::from(1u32), |acc, k| acc * k)
+}
+
+/// Computes `base^exponent mod n` for a possibly negative `exponent`.
+fn pow_signed(
+    base: &BigUint,
+    exponent: &BigInt,
+    n: &BigUint,
+) -> ThresholdResult<BigUint> {
+    let (base, exponent) = match exponent.sign() {
+        Sign::Minus => (
+            base.mod_inverse(n).and_then(|inverse| inverse.to_biguint()),
+            (-exponent).to_biguint(),
+        ),
+        _ => (Some(base.clone()), exponent.to_biguint()),
+    };
+    match (base, exponent) {
+        (Some(base), Some(exponent)) => Ok(base.modpow(&exponent, n)),
+        // Only a value sharing a factor with n has no inverse.
+        _ => Err(ThresholdError::InvalidPartialDecryption),
+    }
+}
+
+/// Returns a uniformly random integer below `bound`, up to a bias of 2^-64.
+fn random_below(bound: &BigUint) -> BigUint {
+    let mut bytes = vec![0; bound.bits().div_ceil(8) + 8];
+    OsRng.fill_bytes(&mut bytes);
+    BigUint::from_bytes_be(&bytes) % bound
+}
+
+fn write_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    let len = u16::try_from(field.len()).expect("RSA integers fit in 64 KiB");
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(field);
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> ThresholdResult<&'a [u8]> {
+    if bytes.len() < len {
+        return Err(ThresholdError::Malformed);
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_field<'a>(bytes: &mut &'a [u8]) -> ThresholdResult<&'a [u8]> {
+    let len = take(bytes, 2)?;
+    take(bytes, usize::from(u16::from_be_bytes([len[0], len[1]])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::OaepHash;
+
+    #[test]
+    fn test_any_threshold_of_shares_decrypts() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        let (key, shares) = split(&private_key, 3, 5).unwrap();
+        let ciphertext = core::encrypt(key.public_key(), b"launch code").unwrap();
+        let partial =
+            |index: usize| shares[index].decrypt_share(&ciphertext).unwrap();
+        for subset in [[0, 1, 2], [0, 2, 4], [4, 3, 1]] {
+            let partials = subset.map(partial);
+            assert_eq!(key.combine(&ciphertext, &partials).unwrap(), b"launch code");
+        }
+
+        assert!(matches!(
+            key.combine(&ciphertext, &[partial(0), partial(0), partial(1)]),
+            Err(ThresholdError::NotEnoughShares {
+                found: 2,
+                needed: 3
+            })
+        ));
+        let other = core::encrypt(key.public_key(), b"other").unwrap();
+        let wrong = shares[2].decrypt_share(&other).unwrap();
+        assert!(matches!(
+            key.combine(&ciphertext, &[partial(0), partial(1), wrong]),
+            Err(ThresholdError::InvalidPartialDecryption)
+        ));
+
+        let share = KeyShare::from_bytes(&shares[4].to_bytes()).unwrap();
+        assert_eq!(share, shares[4]);
+        assert_eq!(share.threshold_key(), &key);
+        let decoded = PartialDecryption::from_bytes(&partial(4).to_bytes()).unwrap();
+        assert_eq!(decoded, partial(4));
+        assert!(!format!("{share:?}").contains(&share.secret.to_string()));
+        assert!(matches!(
+            KeyShare::from_bytes(&shares[4].to_bytes()[1..]),
+            Err(ThresholdError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_threshold_envelopes_and_oaep_params() {
+        let private_key = core::generate_private_key(1024).unwrap();
+        assert!(matches!(
+            split(&private_key, 3, 2),
+            Err(ThresholdError::InvalidThreshold {
+                threshold: 3,
+                shares: 2
+            })
+        ));
+        let (key, shares) = split(&private_key, 2, 2).unwrap();
+
+        let payload = vec![0x42; 10_000];
+        let sealed = envelope::seal(key.public_key(), &payload).unwrap();
+        let partials: Vec<_> = shares
+            .iter()
+            .map(|share| share.decrypt_envelope_share(&sealed).unwrap())
+            .collect();
+        assert_eq!(key.open_envelope(&sealed, &partials).unwrap(), payload);
+
+        let params = OaepParams {
+            hash: OaepHash::Sha256,
+            mgf1_hash: OaepHash::Sha1,
+        };
+        let ciphertext =
+            core::encrypt_with(key.public_key(), params, b"java").unwrap();
+        let partials: Vec<_> = shares
+            .iter()
+            .map(|share| share.decrypt_share(&ciphertext).unwrap())
+            .collect();
+        assert_eq!(
+            key.combine_with(params, &ciphertext, &partials).unwrap(),
+            b"java"
+        );
+        assert!(matches!(
+            key.combine(&ciphertext, &partials),
+            Err(ThresholdError::Rsa(_))
+        ));
+    }
+}
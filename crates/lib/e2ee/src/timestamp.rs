@@ -0,0 +1,859 @@
+//! RFC 3161 timestamping of signatures.
+//!
+//! This module is enabled by the `timestamp` feature. A time-stamp token, issued and signed by a
+//! time-stamping authority (TSA), proves that a signature existed at a given time: a signature
+//! timestamped before its key was compromised or expired can still be trusted afterwards.
+//!
+//! A [`Timestamper`] sends the SHA-256 hash of a signature to a [`TimestampAuthority`], usually an
+//! [`HttpTimestampAuthority`], and checks that the returned [`TimestampToken`] covers the signature
+//! and answers the request. When the public key of the TSA is configured with
+//! [`Timestamper::with_tsa_key`], the signature of the token is checked as well; otherwise the
+//! token must be checked later, e.g. against the certificate chain of the TSA with OpenSSL.
+//!
+//! [`timestamp_signature`] stores the token of a detached signature file (see the `signing`
+//! module) next to it, with the `.tst` extension, so that it can also be checked with
+//! `openssl ts -verify -token_in -in file.sig.tst -data file.sig -CAfile tsa.pem`.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::signing;
+//! use e2ee::timestamp::{self, HttpTimestampAuthority, Timestamper};
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let timestamper = Timestamper::new(HttpTimestampAuthority::new("https://freetsa.org/tsr"));
+//!
+//! let signature = signing::sign_file(&e2ee, "release.tar.gz").expect("Failed to sign");
+//! let token_path =
+//!     timestamp::timestamp_signature(&timestamper, &signature).expect("Failed to timestamp");
+//! let token = timestamp::verify_signature_timestamp(&signature, &token_path, None)
+//!     .expect("Invalid timestamp");
+//! println!("Signed before {:?}", token.time());
+//! ```
+use crate::cms::{
+    set_elements, Attribute, ContentInfo, SignedData, SignerInfo, ID_SIGNED_DATA,
+    RSA_ENCRYPTION,
+};
+use der::{
+    asn1::{BitString, OctetString, Uint},
+    Any, DateTime, Decode, Encode, Sequence, Tag, Tagged,
+};
+use rsa::{
+    pkcs8::spki::AlgorithmIdentifierOwned,
+    rand_core::{OsRng, RngCore},
+    sha2::{Digest, Sha256, Sha384, Sha512},
+    Pkcs1v15Sign, RsaPublicKey,
+};
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use thiserror::Error;
+
+pub use der::asn1::ObjectIdentifier;
+
+/// The extension of time-stamp token files, appended to the path of the signature they cover.
+pub const TOKEN_EXTENSION: &str = "tst";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_RESPONSE_LEN: u64 = 1 << 20;
+
+const ID_CT_TST_INFO: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.16.1.4");
+const ID_CONTENT_TYPE: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.3");
+const ID_MESSAGE_DIGEST: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.2.840.113549.1.9.4");
+
+pub type TimestampResult<T> = std::result::Result<T, TimestampError>;
+
+/// An error returned when requesting or verifying time-stamp tokens.
+#[derive(Error, Debug)]
+pub enum TimestampError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Failed to reach the time-stamping authority: {0}")]
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+
+    #[error("Malformed time-stamp message: {0}")]
+    Asn1(#[from] der::Error),
+
+    #[error("Malformed time-stamp token: {0}")]
+    Malformed(&'static str),
+
+    #[error("The time-stamping authority rejected the request with status {status}: {message}")]
+    Rejected { status: u8, message: String },
+
+    #[error("The time-stamp token does not answer the request")]
+    NonceMismatch,
+
+    #[error(
+        "The time-stamp token was issued under policy {0}, not the requested one"
+    )]
+    PolicyMismatch(String),
+
+    #[error("The time-stamp token does not cover the data")]
+    ImprintMismatch,
+
+    #[error("Unsupported algorithm {0}")]
+    UnsupportedAlgorithm(String),
+
+    #[error("Invalid time-stamp token signature")]
+    InvalidSignature,
+}
+
+/// A time-stamping authority, answering DER-encoded RFC 3161 `TimeStampReq` messages with
+/// DER-encoded `TimeStampResp` messages.
+///
+/// It is implemented by [`HttpTimestampAuthority`], and by closures for other transports.
+pub trait TimestampAuthority: Send + Sync {
+    /// Sends the time-stamp `request` to the authority and returns its response.
+    fn request(&self, request: &[u8]) -> TimestampResult<Vec<u8>>;
+}
+
+impl<F> TimestampAuthority for F
+where
+    F: Fn(&[u8]) -> TimestampResult<Vec<u8>> + Send + Sync,
+{
+    fn request(&self, request: &[u8]) -> TimestampResult<Vec<u8>> {
+        self(request)
+    }
+}
+
+/// A time-stamping authority reached over HTTP, as described in RFC 3161 section 3.4.
+#[derive(Debug, Clone)]
+pub struct HttpTimestampAuthority {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl HttpTimestampAuthority {
+    /// Creates an authority posting requests to `url`, with a timeout of 30 seconds.
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            agent: ureq::AgentBuilder::new().timeout(DEFAULT_TIMEOUT).build(),
+        }
+    }
+
+    /// Sets the timeout of each request, from connection to the end of the response.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        self
+    }
+}
+
+impl TimestampAuthority for HttpTimestampAuthority {
+    fn request(&self, request: &[u8]) -> TimestampResult<Vec<u8>> {
+        let response = self
+            .agent
+            .post(&self.url)
+            .set("Content-Type", "application/timestamp-query")
+            .set("Accept", "application/timestamp-reply")
+            .send_bytes(request)
+            .map_err(|err| TimestampError::Transport(Box::new(err)))?;
+        let mut reply = Vec::new();
+        response
+            .into_reader()
+            .take(MAX_RESPONSE_LEN)
+            .read_to_end(&mut reply)?;
+        Ok(reply)
+    }
+}
+
+/// Requests time-stamp tokens from a [`TimestampAuthority`].
+pub struct Timestamper {
+    authority: Box<dyn TimestampAuthority>,
+    policy: Option<ObjectIdentifier>,
+    tsa_key: Option<RsaPublicKey>,
+}
+
+impl fmt::Debug for Timestamper {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Timestamper")
+            .field("policy", &self.policy)
+            .field("tsa_key", &self.tsa_key.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Timestamper {
+    /// Creates a timestamper requesting tokens from `authority`, under its default policy.
+    pub fn new(authority: impl TimestampAuthority + 'static) -> Self {
+        Self {
+            authority: Box::new(authority),
+            policy: None,
+            tsa_key: None,
+        }
+    }
+
+    /// Requests tokens issued under `policy`, an object identifier such as `1.2.3.4.1`.
+    pub fn with_policy(mut self, policy: ObjectIdentifier) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Checks that tokens are signed with the RSA key `tsa_key` of the authority.
+    pub fn with_tsa_key(mut self, tsa_key: RsaPublicKey) -> Self {
+        self.tsa_key = Some(tsa_key);
+        self
+    }
+
+    /// Requests a token covering the SHA-256 hash of `data`, typically a signature, and checks it.
+    /// The certificate of the authority is requested in the token, for verifiers relying on its
+    /// certificate chain.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `TimestampError::Transport` if the authority cannot be reached,
+    /// `TimestampError::Rejected` if it rejects the request, or an error if the token does not
+    /// answer the request, does not cover `data`, or is not signed with the configured key.
+    pub fn timestamp(&self, data: &[u8]) -> TimestampResult<TimestampToken> {
+        let mut nonce = [0; 8];
+        OsRng.fill_bytes(&mut nonce);
+        let request = TimeStampReq {
+            version: 1,
+            message_imprint: MessageImprint::new(HashAlgorithm::Sha256, data)?,
+            req_policy: self.policy,
+            nonce: Some(Uint::new(&nonce)?),
+            cert_req: true,
+        };
+        let response = self.authority.request(&request.to_der()?)?;
+        let token = TimestampToken::from_response(&response)?;
+        if token.info.nonce != request.nonce {
+            return Err(TimestampError::NonceMismatch);
+        }
+        if self
+            .policy
+            .is_some_and(|policy| policy != token.info.policy)
+        {
+            return Err(TimestampError::PolicyMismatch(token.policy()));
+        }
+        token.verify(data, self.tsa_key.as_ref())?;
+        Ok(token)
+    }
+}
+
+/// An RFC 3161 time-stamp token: a CMS `SignedData` structure, signed by a time-stamping authority,
+/// carrying the hash of the timestamped data and the time at which it was timestamped.
+#[derive(Debug, Clone)]
+pub struct TimestampToken {
+    der: Vec<u8>,
+    signed_data: SignedData,
+    tst_info: Vec<u8>,
+    info: TstInfo,
+    time: SystemTime,
+}
+
+impl TimestampToken {
+    /// Decodes a DER-encoded token, as written by [`timestamp_signature`] or
+    /// `openssl ts -reply -token_out`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if `der` is not a time-stamp token.
+    pub fn from_der(der: &[u8]) -> TimestampResult<Self> {
+        Self::from_content_info(ContentInfo::from_der(der)?)
+    }
+
+    /// Decodes the token of a DER-encoded `TimeStampResp` message, as returned by an authority.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `TimestampError::Rejected` if the authority did not grant the request,
+    /// or an error if `response` is malformed.
+    pub fn from_response(response: &[u8]) -> TimestampResult<Self> {
+        let response = TimeStampResp::from_der(response)?;
+        // 0 is granted, and 1 granted with modifications.
+        if response.status.status > 1 {
+            return Err(TimestampError::Rejected {
+                status: response.status.status,
+                message: response
+                    .status
+                    .status_string
+                    .unwrap_or_default()
+                    .join("; "),
+            });
+        }
+        let token = response.time_stamp_token.ok_or(TimestampError::Malformed(
+            "the response grants the request without a token",
+        ))?;
+        Self::from_content_info(token)
+    }
+
+    fn from_content_info(content_info: ContentInfo) -> TimestampResult<Self> {
+        if content_info.content_type != ID_SIGNED_DATA {
+            return Err(TimestampError::Malformed("the token is not signed data"));
+        }
+        let signed_data: SignedData = content_info.content.decode_as()?;
+        let encapsulated = &signed_data.encap_content_info;
+        if encapsulated.econtent_type != ID_CT_TST_INFO {
+            return Err(TimestampError::Malformed("the token carries no TSTInfo"));
+        }
+        let tst_info = encapsulated
+            .econtent
+            .as_ref()
+            .ok_or(TimestampError::Malformed("the token carries no TSTInfo"))?
+            .as_bytes()
+            .to_vec();
+        let info = TstInfo::from_der(&tst_info)?;
+        let time = generalized_time(&info.gen_time)?;
+        Ok(Self {
+            der: content_info.to_der()?,
+            signed_data,
+            tst_info,
+            info,
+            time,
+        })
+    }
+
+    /// Returns the DER encoding of the token.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Returns the time at which the data was timestamped.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Returns the accuracy of [`TimestampToken::time`], if the authority stated it.
+    pub fn accuracy(&self) -> Option<Duration> {
+        self.info.accuracy.as_ref().map(|accuracy| {
+            Duration::from_secs(accuracy.seconds.unwrap_or_default().into())
+                + Duration::from_millis(accuracy.millis.unwrap_or_default().into())
+                + Duration::from_micros(accuracy.micros.unwrap_or_default().into())
+        })
+    }
+
+    /// Returns the serial number of the token, unique for the authority, as big-endian bytes.
+    pub fn serial_number(&self) -> &[u8] {
+        self.info.serial_number.as_bytes()
+    }
+
+    /// Returns the object identifier of the policy the token was issued under.
+    pub fn policy(&self) -> String {
+        self.info.policy.to_string()
+    }
+
+    /// Checks that the token covers `data` and, if `tsa_key` is given, that it is signed with it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns `TimestampError::ImprintMismatch` if the token covers other data,
+    /// `TimestampError::InvalidSignature` if it is not signed with `tsa_key`, or
+    /// `TimestampError::UnsupportedAlgorithm` if it uses a hash other than SHA-2.
+    pub fn verify(
+        &self,
+        data: &[u8],
+        tsa_key: Option<&RsaPublicKey>,
+    ) -> TimestampResult<()> {
+        let imprint = &self.info.message_imprint;
+        let hash = HashAlgorithm::from_oid(&imprint.hash_algorithm.oid)?;
+        if imprint.hashed_message.as_bytes() != hash.digest(data) {
+            return Err(TimestampError::ImprintMismatch);
+        }
+        match tsa_key {
+            Some(tsa_key) => self.verify_signer(tsa_key),
+            None => Ok(()),
+        }
+    }
+
+    fn verify_signer(&self, tsa_key: &RsaPublicKey) -> TimestampResult<()> {
+        let signers = set_elements(&self.signed_data.signer_infos)?;
+        let [signer] = signers.as_slice() else {
+            return Err(TimestampError::Malformed("the token must have one signer"));
+        };
+        let signer: SignerInfo = signer.decode_as()?;
+        let hash = HashAlgorithm::from_oid(&signer.digest_algorithm.oid)?;
+        let signature_oid = signer.signature_algorithm.oid;
+        if signature_oid != RSA_ENCRYPTION
+            && signature_oid != hash.rsa_signature_oid()
+        {
+            return Err(TimestampError::UnsupportedAlgorithm(
+                signature_oid.to_string(),
+            ));
+        }
+        let attributes =
+            signer
+                .signed_attrs
+                .as_deref()
+                .ok_or(TimestampError::Malformed(
+                    "the token has no signed attributes",
+                ))?;
+        let content_type: ObjectIdentifier =
+            signed_attribute(attributes, ID_CONTENT_TYPE)?.decode_as()?;
+        let message_digest: OctetString =
+            signed_attribute(attributes, ID_MESSAGE_DIGEST)?.decode_as()?;
+        if content_type != ID_CT_TST_INFO
+            || message_digest.as_bytes() != hash.digest(&self.tst_info)
+        {
+            return Err(TimestampError::InvalidSignature);
+        }
+        // The signature covers the attributes encoded as a SET, in their original order.
+        let mut signed_attributes = Vec::new();
+        for attribute in attributes {
+            attribute.encode_to_vec(&mut signed_attributes)?;
+        }
+        let signed_attributes = Any::new(Tag::Set, signed_attributes)?.to_der()?;
+        tsa_key
+            .verify(
+                hash.signature_scheme(),
+                &hash.digest(&signed_attributes),
+                signer.signature.as_bytes(),
+            )
+            .map_err(|_| TimestampError::InvalidSignature)
+    }
+}
+
+/// Returns the path of the time-stamp token of the signature at `signature_path`: the path with
+/// `.tst` appended.
+pub fn token_path(signature_path: impl AsRef<Path>) -> PathBuf {
+    let mut token_path = signature_path.as_ref().as_os_str().to_owned();
+    token_path.push(".");
+    token_path.push(TOKEN_EXTENSION);
+    token_path.into()
+}
+
+/// Timestamps the signature stored at `signature_path`, and writes the token to [`token_path`],
+/// whose path is returned.
+///
+/// # Errors
+///
+/// This function returns an error if the signature cannot be read, if timestamping fails (see
+/// [`Timestamper::timestamp`]), or if the token cannot be written.
+pub fn timestamp_signature(
+    timestamper: &Timestamper,
+    signature_path: impl AsRef<Path>,
+) -> TimestampResult<PathBuf> {
+    let signature_path = signature_path.as_ref();
+    let token = timestamper.timestamp(&fs::read(signature_path)?)?;
+    let token_path = token_path(signature_path);
+    fs::write(&token_path, token.as_der())?;
+    Ok(token_path)
+}
+
+/// Verifies the time-stamp token stored at `token_path` of the signature stored at
+/// `signature_path` (see [`TimestampToken::verify`]), and returns it.
+///
+/// # Errors
+///
+/// This function returns an error if either file cannot be read, or if the token is invalid.
+pub fn verify_signature_timestamp(
+    signature_path: impl AsRef<Path>,
+    token_path: impl AsRef<Path>,
+    tsa_key: Option<&RsaPublicKey>,
+) -> TimestampResult<TimestampToken> {
+    let token = TimestampToken::from_der(&fs::read(token_path)?)?;
+    token.verify(&fs::read(signature_path)?, tsa_key)?;
+    Ok(token)
+}
+
+/// Returns the first value of the attribute `oid` among the signed `attributes`.
+fn signed_attribute(
+    attributes: &[Any],
+    oid: ObjectIdentifier,
+) -> TimestampResult<Any> {
+    for attribute in attributes {
+        let attribute: Attribute = attribute.decode_as()?;
+        if attribute.oid == oid {
+            return set_elements(&attribute.values)?.into_iter().next().ok_or(
+                TimestampError::Malformed("a signed attribute has no value"),
+            );
+        }
+    }
+    Err(TimestampError::Malformed("a signed attribute is missing"))
+}
+
+/// Parses a `GeneralizedTime` in UTC, with optional fractional seconds as allowed by RFC 3161.
+fn generalized_time(value: &Any) -> TimestampResult<SystemTime> {
+    const MALFORMED: TimestampError =
+        TimestampError::Malformed("the time is not a UTC GeneralizedTime");
+    let text = std::str::from_utf8(value.value())
+        .ok()
+        .filter(|_| value.tag() == Tag::GeneralizedTime)
+        .and_then(|text| text.strip_suffix('Z'))
+        .ok_or(MALFORMED)?;
+    let (seconds, fraction) = text.split_once('.').unwrap_or((text, ""));
+    if seconds.len() != 14
+        || !seconds
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(MALFORMED);
+    }
+    let field = |start: usize, end: usize| seconds[start..end].parse::<u8>();
+    let year = seconds[..4].parse::<u16>().map_err(|_| MALFORMED)?;
+    let (month, day, hour, minute, second) = (|| {
+        Ok::<_, std::num::ParseIntError>((
+            field(4, 6)?,
+            field(6, 8)?,
+            field(8, 10)?,
+            field(10, 12)?,
+            field(12, 14)?,
+        ))
+    })()
+    .map_err(|_| MALFORMED)?;
+    let nanos: String = fraction
+        .chars()
+        .chain(std::iter::repeat('0'))
+        .take(9)
+        .collect();
+    let nanos = nanos.parse::<u64>().map_err(|_| MALFORMED)?;
+    Ok(
+        DateTime::new(year, month, day, hour, minute, second)?.to_system_time()
+            + Duration::from_nanos(nanos),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum HashAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// The algorithms, with the object identifiers of their digests and RSA signatures.
+    const ALL: [(Self, ObjectIdentifier, ObjectIdentifier); 3] = [
+        (
+            Self::Sha256,
+            ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.1"),
+            ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.11"),
+        ),
+        (
+            Self::Sha384,
+            ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.2"),
+            ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.12"),
+        ),
+        (
+            Self::Sha512,
+            ObjectIdentifier::new_unwrap("2.16.840.1.101.3.4.2.3"),
+            ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.13"),
+        ),
+    ];
+
+    fn from_oid(oid: &ObjectIdentifier) -> TimestampResult<Self> {
+        Self::ALL
+            .iter()
+            .find(|(_, digest_oid, _)| digest_oid == oid)
+            .map(|(hash, _, _)| *hash)
+            .ok_or_else(|| TimestampError::UnsupportedAlgorithm(oid.to_string()))
+    }
+
+    fn oid(self) -> ObjectIdentifier {
+        Self::ALL[self as usize].1
+    }
+
+    fn rsa_signature_oid(self) -> ObjectIdentifier {
+        Self::ALL[self as usize].2
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+            Self::Sha384 => Sha384::digest(data).to_vec(),
+            Self::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+
+    fn signature_scheme(self) -> Pkcs1v15Sign {
+        match self {
+            Self::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+            Self::Sha384 => Pkcs1v15Sign::new::<Sha384>(),
+            Self::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+        }
+    }
+}
+
+// The RFC 3161 messages, from its appendix C. Extensions of requests are never sent.
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct MessageImprint {
+    hash_algorithm: AlgorithmIdentifierOwned,
+    hashed_message: OctetString,
+}
+
+impl MessageImprint {
+    fn new(hash: HashAlgorithm, data: &[u8]) -> TimestampResult<Self> {
+        Ok(Self {
+            hash_algorithm: AlgorithmIdentifierOwned {
+                oid: hash.oid(),
+                parameters: Some(Any::null()),
+            },
+            hashed_message: OctetString::new(hash.digest(data))?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct TimeStampReq {
+    version: u8,
+    message_imprint: MessageImprint,
+    req_policy: Option<ObjectIdentifier>,
+    nonce: Option<Uint>,
+    #[asn1(default = "Default::default")]
+    cert_req: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct PkiStatusInfo {
+    status: u8,
+    status_string: Option<Vec<String>>,
+    fail_info: Option<BitString>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct TimeStampResp {
+    status: PkiStatusInfo,
+    time_stamp_token: Option<ContentInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct Accuracy {
+    seconds: Option<u32>,
+    #[asn1(context_specific = "0", tag_mode = "IMPLICIT", optional = "true")]
+    millis: Option<u16>,
+    #[asn1(context_specific = "1", tag_mode = "IMPLICIT", optional = "true")]
+    micros: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Sequence)]
+struct TstInfo {
+    version: u8,
+    policy: ObjectIdentifier,
+    message_imprint: MessageImprint,
+    serial_number: Uint,
+    gen_time: Any,
+    accuracy: Option<Accuracy>,
+    #[asn1(default = "Default::default")]
+    ordering: bool,
+    nonce: Option<Uint>,
+    #[asn1(context_specific = "0", tag_mode = "EXPLICIT", optional = "true")]
+    tsa: Option<Any>,
+    #[asn1(
+        context_specific = "1",
+        tag_mode = "IMPLICIT",
+        constructed = "true",
+        optional = "true"
+    )]
+    extensions: Option<Vec<Any>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cms::EncapsulatedContentInfo;
+    use crate::server::{E2ee, KeySize};
+    use der::TagNumber;
+    use rsa::RsaPrivateKey;
+    use std::sync::{Arc, Mutex};
+
+    const POLICY: &str = "1.3.6.1.4.1.99999.1";
+
+    fn sha256() -> AlgorithmIdentifierOwned {
+        AlgorithmIdentifierOwned {
+            oid: HashAlgorithm::Sha256.oid(),
+            parameters: Some(Any::null()),
+        }
+    }
+
+    fn set(elements: &[Any]) -> Any {
+        let mut encoded = Vec::new();
+        for element in elements {
+            element.encode_to_vec(&mut encoded).unwrap();
+        }
+        Any::new(Tag::Set, encoded).unwrap()
+    }
+
+    fn attribute(oid: ObjectIdentifier, value: impl Encode) -> Any {
+        let value = Any::from_der(&value.to_der().unwrap()).unwrap();
+        Any::encode_from(&Attribute {
+            oid,
+            values: set(&[value]),
+        })
+        .unwrap()
+    }
+
+    /// Answers `request` like a TSA signing with `key`, at 2026-10-16 12:00:00.25 UTC.
+    fn grant(key: &RsaPrivateKey, request: &[u8]) -> Vec<u8> {
+        let request = TimeStampReq::from_der(request).unwrap();
+        assert!(request.cert_req);
+        let info = TstInfo {
+            version: 1,
+            policy: request
+                .req_policy
+                .unwrap_or(ObjectIdentifier::new_unwrap(POLICY)),
+            message_imprint: request.message_imprint,
+            serial_number: Uint::new(&[0x2a]).unwrap(),
+            gen_time: Any::new(Tag::GeneralizedTime, &b"20261016120000.25Z"[..])
+                .unwrap(),
+            accuracy: Some(Accuracy {
+                seconds: Some(1),
+                millis: None,
+                micros: Some(500),
+            }),
+            ordering: false,
+            nonce: request.nonce,
+            tsa: None,
+            extensions: None,
+        };
+        let tst_info = info.to_der().unwrap();
+        let attributes = vec![
+            attribute(ID_CONTENT_TYPE, ID_CT_TST_INFO),
+            attribute(
+                ID_MESSAGE_DIGEST,
+                OctetString::new(Sha256::digest(&tst_info).to_vec()).unwrap(),
+            ),
+        ];
+        let signature = key
+            .sign(
+                Pkcs1v15Sign::new::<Sha256>(),
+                &Sha256::digest(set(&attributes).to_der().unwrap()),
+            )
+            .unwrap();
+        let signer = SignerInfo {
+            version: 3,
+            // A subject key identifier.
+            sid: Any::new(
+                Tag::ContextSpecific {
+                    constructed: false,
+                    number: TagNumber::N0,
+                },
+                vec![7; 20],
+            )
+            .unwrap(),
+            digest_algorithm: sha256(),
+            signed_attrs: Some(attributes),
+            signature_algorithm: AlgorithmIdentifierOwned {
+                oid: HashAlgorithm::Sha256.rsa_signature_oid(),
+                parameters: Some(Any::null()),
+            },
+            signature: OctetString::new(signature).unwrap(),
+            unsigned_attrs: None,
+        };
+        let signed_data = SignedData {
+            version: 3,
+            digest_algorithms: set(&[Any::encode_from(&sha256()).unwrap()]),
+            encap_content_info: EncapsulatedContentInfo {
+                econtent_type: ID_CT_TST_INFO,
+                econtent: Some(OctetString::new(tst_info).unwrap()),
+            },
+            certificates: None,
+            crls: None,
+            signer_infos: set(&[Any::encode_from(&signer).unwrap()]),
+        };
+        TimeStampResp {
+            status: PkiStatusInfo {
+                status: 0,
+                status_string: None,
+                fail_info: None,
+            },
+            time_stamp_token: Some(ContentInfo {
+                content_type: ID_SIGNED_DATA,
+                content: Any::encode_from(&signed_data).unwrap(),
+            }),
+        }
+        .to_der()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_timestamp_signature_file() {
+        let tsa = E2ee::new(KeySize::Bit1024).unwrap();
+        let tsa_key = tsa.get_public_key().clone();
+        let timestamper = Timestamper::new(move |request: &[u8]| {
+            Ok(grant(tsa.get_private_key(), request))
+        })
+        .with_tsa_key(tsa_key.clone());
+
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-timestamp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let signature_path = dir.join("release.tar.gz.sig");
+        fs::write(&signature_path, b"signature").unwrap();
+
+        let path = timestamp_signature(&timestamper, &signature_path).unwrap();
+        assert_eq!(path, dir.join("release.tar.gz.sig.tst"));
+        let token =
+            verify_signature_timestamp(&signature_path, &path, Some(&tsa_key))
+                .unwrap();
+        assert_eq!(
+            token.time(),
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_792_152_000_250)
+        );
+        assert_eq!(token.accuracy(), Some(Duration::from_micros(1_000_500)));
+        assert_eq!(token.serial_number(), [0x2a]);
+        assert_eq!(token.policy(), POLICY);
+
+        let other_key = E2ee::new(KeySize::Bit1024).unwrap();
+        assert!(matches!(
+            token.verify(b"signature", Some(other_key.get_public_key())),
+            Err(TimestampError::InvalidSignature)
+        ));
+        fs::write(&signature_path, b"forged signature").unwrap();
+        assert!(matches!(
+            verify_signature_timestamp(&signature_path, &path, None),
+            Err(TimestampError::ImprintMismatch)
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rejected_and_replayed_responses() {
+        let rejected = PkiStatusInfo {
+            status: 2,
+            status_string: Some(vec!["unsupported policy".to_string()]),
+            fail_info: None,
+        };
+        let timestamper = Timestamper::new(move |_: &[u8]| {
+            Ok(TimeStampResp {
+                status: rejected.clone(),
+                time_stamp_token: None,
+            }
+            .to_der()?)
+        });
+        assert!(matches!(
+            timestamper.timestamp(b"signature"),
+            Err(TimestampError::Rejected { status: 2, message })
+                if message == "unsupported policy"
+        ));
+
+        // An authority replaying its first response to every request.
+        let key = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .get_private_key()
+            .clone();
+        let first = Arc::new(Mutex::new(None));
+        let timestamper = Timestamper::new(move |request: &[u8]| {
+            let mut first = first.lock().unwrap();
+            Ok(first.get_or_insert_with(|| grant(&key, request)).clone())
+        });
+        let token = timestamper.timestamp(b"signature").unwrap();
+        assert_eq!(
+            TimestampToken::from_der(token.as_der()).unwrap().time(),
+            token.time()
+        );
+        assert!(matches!(
+            timestamper.timestamp(b"signature"),
+            Err(TimestampError::NonceMismatch)
+        ));
+
+        let key = E2ee::new(KeySize::Bit1024)
+            .unwrap()
+            .get_private_key()
+            .clone();
+        let timestamper =
+            Timestamper::new(move |request: &[u8]| Ok(grant(&key, request)))
+                .with_policy(ObjectIdentifier::new_unwrap("1.3.6.1.4.1.99999.2"));
+        assert_eq!(
+            timestamper.timestamp(b"signature").unwrap().policy(),
+            "1.3.6.1.4.1.99999.2"
+        );
+    }
+}
@@ -0,0 +1,411 @@
+//! Encrypted tokens carrying small claim sets.
+//!
+//! A token is a compact, URL-safe string that only the holder of the private key can issue and
+//! read, suited to encrypted session state and magic links. It has four dot-separated parts:
+//!
+//! ```text
+//! v1.<key ID>.<payload>.<signature>
+//! ```
+//!
+//! - `v1` is the token format version;
+//! - the key ID is the first 16 hex digits of the fingerprint of the key (see [`key_id`]), so
+//!   that a server holding several keys can pick the right one;
+//! - the payload is the RSA-OAEP (SHA-256) encryption of a JSON object holding the claims, the
+//!   expiry time and the key ID;
+//! - the signature is the RSASSA-PKCS1-v1_5 (SHA-256) signature of the first three parts, joined
+//!   by dots, with the same private key.
+//!
+//! The payload and the signature are encoded as URL-safe base64 without padding. The signature is
+//! checked before decrypting, so that tokens built with only the public key are rejected.
+//!
+//! The payload is limited by the RSA-OAEP capacity of the key: about 135 bytes of JSON claims with
+//! a 2048-bit key and 390 bytes with a 4096-bit key.
+//!
+//! This module is enabled by the default `io` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::token::{self, Claims};
+//! use serde_json::json;
+//! use std::time::Duration;
+//!
+//! let e2ee = E2ee::new(KeySize::Bit2048).expect("Failed to create E2ee instance");
+//! let mut claims = Claims::new();
+//! claims.insert("sub".into(), json!("user-42"));
+//!
+//! let encrypted = token::encrypt_token(&e2ee, &claims, Duration::from_secs(900))
+//!     .expect("Failed to encrypt token");
+//! let decrypted = token::decrypt_token(&e2ee, &encrypted).expect("Invalid token");
+//! assert_eq!(decrypted.claims, claims);
+//! ```
+use crate::core::{self, OaepParams};
+use crate::server::E2ee;
+use base64::{engine::general_purpose, Engine};
+use rsa::{traits::PublicKeyParts, RsaPublicKey};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+pub type TokenResult<T> = std::result::Result<T, TokenError>;
+
+/// The claims carried by a token.
+pub type Claims = serde_json::Map<String, Value>;
+
+/// The version prefix of tokens produced by this module.
+pub const VERSION: &str = "v1";
+
+/// An error returned when encrypting or validating a token.
+#[derive(Error, Debug)]
+pub enum TokenError {
+    #[error("RSA error: {0}")]
+    Rsa(#[from] rsa::errors::Error),
+
+    #[error("SPKI error: {0}")]
+    Spki(#[from] rsa::pkcs8::spki::Error),
+
+    #[error(
+        "The claims take {len} bytes, more than the {max} bytes the key can encrypt"
+    )]
+    TooLarge { len: usize, max: usize },
+
+    #[error("Malformed token")]
+    Malformed,
+
+    #[error("Unsupported token version {0:?}")]
+    UnsupportedVersion(String),
+
+    #[error("Token was encrypted with key {actual}, expected key {expected}")]
+    UnknownKey { expected: String, actual: String },
+
+    #[error("Invalid token")]
+    Invalid,
+
+    #[error("Token expired at {expires_at:?}")]
+    Expired { expires_at: SystemTime },
+
+    #[error("The time to live is too long")]
+    InvalidExpiry,
+}
+
+/// A decrypted and validated token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    /// The ID of the key the token was encrypted with.
+    pub key_id: String,
+    /// The time after which the token is no longer accepted.
+    pub expires_at: SystemTime,
+    /// The claims of the token.
+    pub claims: Claims,
+}
+
+/// Returns the key ID embedded in tokens issued with the key pair of `public_key`: the first 16 hex digits of
+/// its fingerprint (see `core::key_id`).
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn key_id(public_key: &RsaPublicKey) -> TokenResult<String> {
+    Ok(core::key_id(public_key)?)
+}
+
+/// Returns the key ID of a token without decrypting it, to select the key to decrypt it with.
+///
+/// # Errors
+///
+/// This function returns an error if the token is malformed or has an unsupported version.
+pub fn peek_key_id(token: &str) -> TokenResult<&str> {
+    split(token).map(|(key_id, _, _)| key_id)
+}
+
+/// Encrypts `claims` into a token for the key of `e2ee`, signed with its private key and valid
+/// for `ttl` from now.
+///
+/// # Errors
+///
+/// This function returns `TokenError::InvalidExpiry` if `ttl` reaches beyond the range of
+/// `SystemTime`, `TokenError::TooLarge` if the claims do not fit in the RSA-OAEP capacity of the
+/// key, or an error if encryption or signing fails.
+pub fn encrypt_token(
+    e2ee: &E2ee,
+    claims: &Claims,
+    ttl: Duration,
+) -> TokenResult<String> {
+    let public_key = e2ee.get_public_key();
+    let key_id = key_id(public_key)?;
+    let expires_at = SystemTime::now()
+        .checked_add(ttl)
+        .ok_or(TokenError::InvalidExpiry)?;
+    let payload = json!({
+        "kid": key_id,
+        "exp": unix_time(expires_at),
+        "claims": claims,
+    })
+    .to_string();
+
+    let max = OaepParams::SHA256.max_message_len(public_key.size());
+    if payload.len() > max {
+        return Err(TokenError::TooLarge {
+            len: payload.len(),
+            max,
+        });
+    }
+    let encrypted =
+        core::encrypt_with(public_key, OaepParams::SHA256, payload.as_bytes())?;
+    let signed = format!(
+        "{VERSION}.{key_id}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(encrypted)
+    );
+    let signature = core::sign_blinded(e2ee.get_private_key(), signed.as_bytes())?;
+    Ok(format!(
+        "{signed}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// Decrypts and validates a token encrypted for the key of `e2ee`.
+///
+/// # Errors
+///
+/// This function returns an error if the token is malformed, was issued with another key, is not
+/// signed by the key of `e2ee`, cannot be decrypted, or has expired.
+pub fn decrypt_token(e2ee: &E2ee, token: &str) -> TokenResult<Token> {
+    decrypt_token_at(e2ee, token, SystemTime::now())
+}
+
+/// Decrypts and validates a token as of `now`.
+///
+/// # Errors
+///
+/// This function returns an error if the token is malformed, was issued with another key, is not
+/// signed by the key of `e2ee`, cannot be decrypted, or expired before `now`.
+pub fn decrypt_token_at(
+    e2ee: &E2ee,
+    token: &str,
+    now: SystemTime,
+) -> TokenResult<Token> {
+    let (token_key_id, payload, signature) = split(token)?;
+    let expected = key_id(e2ee.get_public_key())?;
+    if token_key_id != expected {
+        return Err(TokenError::UnknownKey {
+            expected,
+            actual: token_key_id.to_string(),
+        });
+    }
+
+    let encrypted = general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| TokenError::Malformed)?;
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    let signed = &token[..token.len() - signature_len(token)];
+    core::verify(e2ee.get_public_key(), signed.as_bytes(), &signature)
+        .map_err(|_| TokenError::Invalid)?;
+    // Every decryption or parsing failure is reported as `Invalid` so that the token does not act
+    // as a padding oracle.
+    let decrypted =
+        core::decrypt_with(e2ee.get_private_key(), OaepParams::SHA256, &encrypted)
+            .map_err(|_| TokenError::Invalid)?;
+    let mut payload = match serde_json::from_slice(&decrypted) {
+        Ok(Value::Object(payload)) => payload,
+        _ => return Err(TokenError::Invalid),
+    };
+    if payload.get("kid").and_then(Value::as_str) != Some(token_key_id) {
+        return Err(TokenError::Invalid);
+    }
+    let expires_at = payload
+        .get("exp")
+        .and_then(Value::as_u64)
+        .and_then(|exp| UNIX_EPOCH.checked_add(Duration::from_secs(exp)))
+        .ok_or(TokenError::Invalid)?;
+    let claims = match payload.remove("claims") {
+        Some(Value::Object(claims)) => claims,
+        _ => return Err(TokenError::Invalid),
+    };
+
+    if now >= expires_at {
+        return Err(TokenError::Expired { expires_at });
+    }
+    Ok(Token {
+        key_id: expected,
+        expires_at,
+        claims,
+    })
+}
+
+fn split(token: &str) -> TokenResult<(&str, &str, &str)> {
+    let mut parts = token.split('.');
+    match (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) {
+        (Some(VERSION), Some(key_id), Some(payload), Some(signature), None) => {
+            Ok((key_id, payload, signature))
+        }
+        (Some(version), Some(_), Some(_), Some(_), None) => {
+            Err(TokenError::UnsupportedVersion(version.to_string()))
+        }
+        _ => Err(TokenError::Malformed),
+    }
+}
+
+/// Returns the length of the signature part of `token`, with its leading dot.
+fn signature_len(token: &str) -> usize {
+    token
+        .rsplit('.')
+        .next()
+        .map_or(0, |signature| signature.len() + 1)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+
+    fn claims() -> Claims {
+        let mut claims = Claims::new();
+        claims.insert("sub".into(), json!("user-42"));
+        claims.insert("scope".into(), json!(["read", "write"]));
+        claims
+    }
+
+    #[test]
+    fn test_token_roundtrip() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let token =
+            encrypt_token(&e2ee, &claims(), Duration::from_secs(60)).unwrap();
+        assert!(token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)));
+        assert_eq!(
+            peek_key_id(&token).unwrap(),
+            key_id(e2ee.get_public_key()).unwrap()
+        );
+
+        let decrypted = decrypt_token(&e2ee, &token).unwrap();
+        assert_eq!(decrypted.claims, claims());
+        assert_eq!(decrypted.key_id, peek_key_id(&token).unwrap());
+        assert!(decrypted.expires_at > SystemTime::now());
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let token =
+            encrypt_token(&e2ee, &claims(), Duration::from_secs(60)).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(120);
+        assert!(matches!(
+            decrypt_token_at(&e2ee, &token, later),
+            Err(TokenError::Expired { .. })
+        ));
+    }
+
+    #[test]
+    fn test_token_rejects_other_key_and_tampering() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let other = E2ee::new(KeySize::Bit2048).unwrap();
+        let token =
+            encrypt_token(&other, &claims(), Duration::from_secs(60)).unwrap();
+        assert!(matches!(
+            decrypt_token(&e2ee, &token),
+            Err(TokenError::UnknownKey { .. })
+        ));
+
+        // Re-labelling the token with the right key ID does not make it valid.
+        let own_key_id = key_id(e2ee.get_public_key()).unwrap();
+        let relabelled = format!(
+            "{VERSION}.{own_key_id}.{}",
+            token.splitn(3, '.').nth(2).unwrap()
+        );
+        assert!(matches!(
+            decrypt_token(&e2ee, &relabelled),
+            Err(TokenError::Invalid)
+        ));
+
+        assert!(matches!(
+            decrypt_token(&e2ee, "v2.abc.def.ghi"),
+            Err(TokenError::UnsupportedVersion(_))
+        ));
+        assert!(matches!(
+            decrypt_token(&e2ee, "not a token"),
+            Err(TokenError::Malformed)
+        ));
+    }
+
+    #[test]
+    fn test_token_too_large() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let mut claims = Claims::new();
+        claims.insert("data".into(), json!("x".repeat(200)));
+        assert!(matches!(
+            encrypt_token(&e2ee, &claims, Duration::from_secs(60)),
+            Err(TokenError::TooLarge { .. })
+        ));
+    }
+
+    /// Builds a token with `payload`, signed with the private key of `e2ee` if `signed`, or
+    /// with a bogus signature as anyone holding only the public key could.
+    fn build(e2ee: &E2ee, payload: &Value, signed: bool) -> String {
+        let public_key = e2ee.get_public_key();
+        let encrypted = core::encrypt_with(
+            public_key,
+            OaepParams::SHA256,
+            payload.to_string().as_bytes(),
+        )
+        .unwrap();
+        let unsigned = format!(
+            "{VERSION}.{}.{}",
+            key_id(public_key).unwrap(),
+            general_purpose::URL_SAFE_NO_PAD.encode(encrypted)
+        );
+        let signature = if signed {
+            core::sign(e2ee.get_private_key(), unsigned.as_bytes()).unwrap()
+        } else {
+            vec![0; 256]
+        };
+        format!(
+            "{unsigned}.{}",
+            general_purpose::URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    #[test]
+    fn test_token_rejects_forgeries_and_invalid_expiries() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        let kid = key_id(e2ee.get_public_key()).unwrap();
+        let exp = unix_time(SystemTime::now() + Duration::from_secs(60));
+        let payload = json!({ "kid": kid, "exp": exp, "claims": { "admin": true } });
+        assert!(decrypt_token(&e2ee, &build(&e2ee, &payload, true)).is_ok());
+        assert!(matches!(
+            decrypt_token(&e2ee, &build(&e2ee, &payload, false)),
+            Err(TokenError::Invalid)
+        ));
+
+        // An expiry beyond the range of `SystemTime` is rejected, not a panic.
+        let payload = json!({ "kid": kid, "exp": u64::MAX, "claims": {} });
+        assert!(matches!(
+            decrypt_token(&e2ee, &build(&e2ee, &payload, true)),
+            Err(TokenError::Invalid)
+        ));
+    }
+
+    #[test]
+    fn test_token_rejects_ttl_beyond_system_time() {
+        let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+        assert!(matches!(
+            encrypt_token(&e2ee, &claims(), Duration::MAX),
+            Err(TokenError::InvalidExpiry)
+        ));
+    }
+}
@@ -0,0 +1,56 @@
+//! Traits abstracting over encryption and decryption.
+//!
+//! [`Encryptor`] and [`Decryptor`] let application code accept any implementation, such as
+//! `E2ee`, `PublicE2ee` or a test double, instead of a concrete type.
+use crate::client::{PublicE2ee, PublicE2eeError};
+use crate::server::{E2ee, E2eeError};
+
+/// A type that encrypts messages into base64-encoded ciphertexts.
+pub trait Encryptor {
+    /// The error returned when encryption fails.
+    type Error;
+
+    /// Encrypts `message` and returns the base64-encoded ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// Implementations return an error if the message cannot be encrypted.
+    fn encrypt(&self, message: &str) -> Result<String, Self::Error>;
+}
+
+/// A type that decrypts ciphertexts produced by an [`Encryptor`].
+pub trait Decryptor {
+    /// The error returned when decryption fails.
+    type Error;
+
+    /// Decrypts the base64-encoded `ciphertext` and returns the plaintext message.
+    ///
+    /// # Errors
+    ///
+    /// Implementations return an error if the ciphertext cannot be decrypted.
+    fn decrypt(&self, ciphertext: &str) -> Result<String, Self::Error>;
+}
+
+impl Encryptor for E2ee {
+    type Error = E2eeError;
+
+    fn encrypt(&self, message: &str) -> Result<String, E2eeError> {
+        E2ee::encrypt(self, message)
+    }
+}
+
+impl Decryptor for E2ee {
+    type Error = E2eeError;
+
+    fn decrypt(&self, ciphertext: &str) -> Result<String, E2eeError> {
+        E2ee::decrypt(self, ciphertext)
+    }
+}
+
+impl Encryptor for PublicE2ee {
+    type Error = PublicE2eeError;
+
+    fn encrypt(&self, message: &str) -> Result<String, PublicE2eeError> {
+        PublicE2ee::encrypt(self, message)
+    }
+}
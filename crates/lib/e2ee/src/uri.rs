@@ -0,0 +1,353 @@
+//! `e2ee:` URIs for keys and ciphertexts.
+//!
+//! Public keys, short encrypted messages and identity bundles (see the `identity` module) are
+//! shared as links, deep links into mobile apps or QR codes with the following URIs, whose
+//! payloads are in unpadded base64url:
+//!
+//! ```text
+//! e2ee:pk:<SPKI DER of the public key>
+//! e2ee:msg:v1:<envelope>
+//! e2ee:identity:<exported identity bundle>
+//! ```
+//!
+//! A QR code holds at most [`QR_CAPACITY`] bytes, and fewer are easier to scan. [`split`] cuts a
+//! URI into parts of bounded length, to be shown as a sequence of QR codes, and [`join`]
+//! reassembles them in any order:
+//!
+//! ```text
+//! e2ee:part:<ID>:<index>/<count>:<slice of the URI>
+//! ```
+//!
+//! where the ID, 8 hex digits of the SHA-256 of the URI, keeps parts of different URIs apart and
+//! is checked against the reassembled URI.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::core;
+//! use e2ee::envelope;
+//! use e2ee::uri::{self, Uri};
+//!
+//! let private_key = core::generate_private_key(2048).expect("Failed to generate key");
+//! let public_key = private_key.to_public_key();
+//! let link = uri::encode_public_key(&public_key).expect("Failed to encode key");
+//! assert!(link.starts_with("e2ee:pk:"));
+//!
+//! let sealed = envelope::seal(&public_key, b"Hi!").expect("Failed to seal");
+//! let parts = uri::split(&uri::encode_message(&sealed), 200).expect("Failed to split");
+//! let joined = uri::join(parts.iter().rev()).expect("Failed to join");
+//! match uri::decode(&joined).expect("Invalid URI") {
+//!     Uri::Message(envelope) => {
+//!         assert_eq!(envelope::open(&private_key, &envelope).unwrap(), b"Hi!")
+//!     }
+//!     _ => unreachable!(),
+//! }
+//! ```
+use crate::core;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rsa::{
+    pkcs8::{spki, DecodePublicKey, EncodePublicKey},
+    sha2::{Digest, Sha256},
+    traits::PublicKeyParts,
+    RsaPublicKey,
+};
+use thiserror::Error;
+
+/// The prefix of public key URIs.
+pub const PUBLIC_KEY_PREFIX: &str = "e2ee:pk:";
+
+/// The prefix of message URIs, with the version of their layout.
+pub const MESSAGE_PREFIX: &str = "e2ee:msg:v1:";
+
+/// The prefix of identity bundle URIs.
+pub const IDENTITY_PREFIX: &str = "e2ee:identity:";
+
+/// The prefix of the parts returned by [`split`].
+pub const PART_PREFIX: &str = "e2ee:part:";
+
+/// The bytes a QR code of version 40 holds in byte mode, with the lowest error correction level.
+pub const QR_CAPACITY: usize = 2953;
+
+/// The length of the ID of split URIs, in hex digits.
+const PART_ID_LEN: usize = 8;
+
+/// The most parts [`split`] cuts a URI into.
+const MAX_PARTS: usize = 999;
+
+pub type UriResult<T> = std::result::Result<T, UriError>;
+
+/// An error returned when encoding, decoding, splitting or joining URIs.
+#[derive(Error, Debug)]
+pub enum UriError {
+    #[error("SPKI error: {0}")]
+    Spki(#[from] spki::Error),
+
+    #[error("Invalid public key: {0}")]
+    InvalidKey(String),
+
+    #[error("Unsupported URI: {0}")]
+    Unsupported(String),
+
+    #[error("Invalid base64url payload")]
+    InvalidPayload,
+
+    #[error("Parts of at most {0} bytes cannot hold the URI")]
+    PartTooShort(usize),
+
+    #[error("Malformed URI part")]
+    MalformedPart,
+
+    #[error("The parts belong to different URIs")]
+    MixedParts,
+
+    #[error("Missing URI part {0}")]
+    MissingPart(usize),
+
+    #[error("Duplicate URI part {0}")]
+    DuplicatePart(usize),
+
+    #[error("The joined URI does not match the ID of its parts")]
+    Corrupted,
+}
+
+/// A decoded `e2ee:` URI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Uri {
+    /// A validated public key.
+    PublicKey(RsaPublicKey),
+    /// An envelope, to be opened with `envelope::open` or `E2ee::decrypt_envelope`.
+    Message(Vec<u8>),
+    /// An exported identity bundle, to be imported with `identity::import_bundle`.
+    Identity(String),
+}
+
+/// Encodes `public_key` as an `e2ee:pk:` URI.
+///
+/// # Errors
+///
+/// This function returns an error if the key cannot be DER-encoded.
+pub fn encode_public_key(public_key: &RsaPublicKey) -> UriResult<String> {
+    let der = public_key.to_public_key_der()?;
+    Ok(format!(
+        "{PUBLIC_KEY_PREFIX}{}",
+        URL_SAFE_NO_PAD.encode(der.as_bytes())
+    ))
+}
+
+/// Encodes an envelope (see `envelope::seal`) as an `e2ee:msg:v1:` URI.
+pub fn encode_message(envelope: &[u8]) -> String {
+    format!("{MESSAGE_PREFIX}{}", URL_SAFE_NO_PAD.encode(envelope))
+}
+
+/// Encodes an exported identity bundle as an `e2ee:identity:` URI.
+pub fn encode_identity(exported: &str) -> String {
+    format!("{IDENTITY_PREFIX}{}", URL_SAFE_NO_PAD.encode(exported))
+}
+
+/// Decodes an `e2ee:` URI. Public keys are validated like foreign keys (see
+/// `PublicE2ee::from_public_key`); envelopes and identity bundles are returned as is.
+///
+/// # Errors
+///
+/// This function returns `UriError::Unsupported` if `uri` is not one of the URIs of this module
+/// (parts must be joined first), `UriError::InvalidPayload` if its payload is not base64url, or
+/// `UriError::InvalidKey` if the public key is malformed or weak.
+pub fn decode(uri: &str) -> UriResult<Uri> {
+    if let Some(payload) = uri.strip_prefix(PUBLIC_KEY_PREFIX) {
+        let public_key =
+            RsaPublicKey::from_public_key_der(&decode_payload(payload)?)
+                .map_err(|err| UriError::InvalidKey(err.to_string()))?;
+        core::validate_public_key(public_key.n(), public_key.e())
+            .map_err(UriError::InvalidKey)?;
+        Ok(Uri::PublicKey(public_key))
+    } else if let Some(payload) = uri.strip_prefix(MESSAGE_PREFIX) {
+        Ok(Uri::Message(decode_payload(payload)?))
+    } else if let Some(payload) = uri.strip_prefix(IDENTITY_PREFIX) {
+        String::from_utf8(decode_payload(payload)?)
+            .map(Uri::Identity)
+            .map_err(|_| UriError::InvalidPayload)
+    } else {
+        let scheme = uri.split(':').take(2).collect::<Vec<_>>().join(":");
+        Err(UriError::Unsupported(scheme))
+    }
+}
+
+/// Cuts `uri` into parts of at most `max_len` bytes each, headers included. A URI that already
+/// fits is returned as a single part, unchanged.
+///
+/// # Errors
+///
+/// This function returns `UriError::PartTooShort` if `max_len` leaves no room for the URI next to
+/// the headers of its parts, or if it would take more than 999 parts.
+pub fn split(uri: &str, max_len: usize) -> UriResult<Vec<String>> {
+    if uri.len() <= max_len {
+        return Ok(vec![uri.to_string()]);
+    }
+    let id = part_id(uri);
+    // The count of parts sets the width of the headers: try 9 parts at most, then 99, then 999.
+    for width in 1..=3 {
+        let header_len = PART_PREFIX.len() + PART_ID_LEN + 2 * width + 3;
+        let Some(room) = max_len.checked_sub(header_len).filter(|&room| room >= 4)
+        else {
+            break;
+        };
+        // Fill parts by bytes, cutting only on character boundaries.
+        let mut slices = vec![String::new()];
+        for c in uri.chars() {
+            if slices
+                .last()
+                .is_some_and(|slice| slice.len() + c.len_utf8() > room)
+            {
+                slices.push(String::new());
+            }
+            slices.last_mut().expect("non-empty").push(c);
+        }
+        let count = slices.len();
+        if count < 10_usize.pow(width as u32) {
+            return Ok(slices
+                .iter()
+                .enumerate()
+                .map(|(index, slice)| {
+                    format!("{PART_PREFIX}{id}:{}/{count}:{slice}", index + 1)
+                })
+                .collect());
+        }
+    }
+    Err(UriError::PartTooShort(max_len))
+}
+
+/// Reassembles the parts returned by [`split`], in any order. A single URI that is not a part is
+/// returned unchanged.
+///
+/// # Errors
+///
+/// This function returns `UriError::MixedParts` if the parts come from different URIs,
+/// `UriError::MissingPart` or `UriError::DuplicatePart` if a part is missing or repeated,
+/// `UriError::MalformedPart` if a part is malformed, or `UriError::Corrupted` if the joined URI
+/// does not match the ID of the parts.
+pub fn join<I, S>(parts: I) -> UriResult<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let parts: Vec<S> = parts.into_iter().collect();
+    if let [single] = &parts[..] {
+        if !single.as_ref().starts_with(PART_PREFIX) {
+            return Ok(single.as_ref().to_string());
+        }
+    }
+    let mut id = None;
+    let mut slices: Vec<Option<&str>> = Vec::new();
+    for part in &parts {
+        let (part_id, index, count, slice) = parse_part(part.as_ref())?;
+        if *id.get_or_insert(part_id) != part_id {
+            return Err(UriError::MixedParts);
+        }
+        if slices.is_empty() {
+            slices = vec![None; count];
+        } else if slices.len() != count {
+            return Err(UriError::MixedParts);
+        }
+        if slices[index - 1].replace(slice).is_some() {
+            return Err(UriError::DuplicatePart(index));
+        }
+    }
+    let mut uri = String::new();
+    for (index, slice) in slices.iter().enumerate() {
+        uri.push_str(slice.ok_or(UriError::MissingPart(index + 1))?);
+    }
+    if id != Some(part_id(&uri).as_str()) {
+        return Err(UriError::Corrupted);
+    }
+    Ok(uri)
+}
+
+fn decode_payload(payload: &str) -> UriResult<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| UriError::InvalidPayload)
+}
+
+/// Returns the ID of the parts of `uri`.
+fn part_id(uri: &str) -> String {
+    core::hex(&Sha256::digest(uri.as_bytes())[..PART_ID_LEN / 2])
+}
+
+/// Returns the ID, the index (from 1), the count and the slice of a part.
+fn parse_part(part: &str) -> UriResult<(&str, usize, usize, &str)> {
+    let mut fields = part
+        .strip_prefix(PART_PREFIX)
+        .ok_or(UriError::MalformedPart)?
+        .splitn(3, ':');
+    let (Some(id), Some(position), Some(slice)) =
+        (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(UriError::MalformedPart);
+    };
+    let (index, count) = position
+        .split_once('/')
+        .and_then(|(index, count)| Some((index.parse().ok()?, count.parse().ok()?)))
+        .filter(|&(index, count): &(usize, usize)| {
+            (1..=count).contains(&index) && count <= MAX_PARTS
+        })
+        .ok_or(UriError::MalformedPart)?;
+    if id.len() != PART_ID_LEN {
+        return Err(UriError::MalformedPart);
+    }
+    Ok((id, index, count, slice))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_key_and_identity_uris() {
+        let public_key = core::generate_private_key(1024).unwrap().to_public_key();
+        let uri = encode_public_key(&public_key).unwrap();
+        assert_eq!(decode(&uri).unwrap(), Uri::PublicKey(public_key));
+        assert_eq!(
+            decode(&encode_identity("{}")).unwrap(),
+            Uri::Identity("{}".into())
+        );
+
+        assert!(matches!(
+            decode("e2ee:msg:v2:AAAA"),
+            Err(UriError::Unsupported(scheme)) if scheme == "e2ee:msg"
+        ));
+        assert!(matches!(
+            decode("e2ee:pk:not base64"),
+            Err(UriError::InvalidPayload)
+        ));
+        assert!(matches!(
+            decode(&format!("{PUBLIC_KEY_PREFIX}AAAA")),
+            Err(UriError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let uri = encode_message(&[7; 1000]);
+        assert_eq!(split(&uri, uri.len()).unwrap(), vec![uri.clone()]);
+        let parts = split(&uri, 100).unwrap();
+        assert!(parts.len() > 10);
+        assert!(parts.iter().all(|part| part.len() <= 100));
+        let mut shuffled = parts.clone();
+        shuffled.rotate_left(3);
+        assert_eq!(join(&shuffled).unwrap(), uri);
+        assert_eq!(join([&uri]).unwrap(), uri);
+
+        assert!(matches!(join(&parts[1..]), Err(UriError::MissingPart(1))));
+        let mut duplicated = parts.clone();
+        duplicated[1] = parts[0].clone();
+        assert!(matches!(join(&duplicated), Err(UriError::DuplicatePart(1))));
+        let other = split(&encode_message(&[8; 1000]), 100).unwrap();
+        let mut mixed = parts.clone();
+        mixed[0] = other[0].clone();
+        assert!(matches!(join(&mixed), Err(UriError::MixedParts)));
+        assert!(matches!(
+            split(&uri, PART_PREFIX.len() + 10),
+            Err(UriError::PartTooShort(_))
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+//! Known-answer test vectors for checking interoperability with other implementations.
+//!
+//! The vectors use a fixed 2048-bit key ([`PRIVATE_KEY_PEM`], [`PUBLIC_KEY_PEM`]). Each file in
+//! `files/vectors/` groups the vectors of one [`InteropConfig`] (OAEP hashes and ciphertext
+//! encoding):
+//!
+//! - `oaep_sha256.json`: the default configuration of this crate, with ciphertexts produced by
+//!   OpenSSL, WebCrypto and this crate;
+//! - `webcrypto.json`: `InteropConfig::WEBCRYPTO`, with ciphertexts produced by WebCrypto;
+//! - `java_mgf1_sha1.json`: `InteropConfig::JAVA`, with ciphertexts produced by Java's default
+//!   `RSA/ECB/OAEPWithSHA-256AndMGF1Padding`;
+//! - `java_mgf1_sha256.json`: `InteropConfig::JAVA_MGF1_SHA256`, with ciphertexts produced by the
+//!   same transformation initialized with `MGF1ParameterSpec.SHA256`;
+//! - `dotnet.json`: `InteropConfig::DOTNET`, with ciphertexts produced by OpenSSL using the
+//!   parameters of .NET's `RSAEncryptionPadding.OaepSHA256`.
+//!
+//! The key is also provided as JWK (`private.jwk`, `public.jwk`), as exported by WebCrypto.
+//!
+//! OAEP is randomized, so vectors cannot be checked by re-encrypting the plaintext. Instead:
+//!
+//! - to check a decryptor written in another language, decrypt the bundled vectors (see
+//!   [`interop_vectors`]) with [`PRIVATE_KEY_PEM`] and compare the plaintexts;
+//! - to check an encryptor written in another language, encrypt plaintexts with
+//!   [`PUBLIC_KEY_PEM`] and pass the resulting [`Vector`]s to [`run_vectors`].
+//!
+//! This module is enabled by the default `io` feature.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::vectors;
+//!
+//! let checked = vectors::run_interop_vectors().expect("Interop vector failed");
+//! assert!(checked > 0);
+//! ```
+use crate::core::{OaepHash, OaepParams};
+use crate::interop::{Encoding, InteropConfig};
+use crate::server::{E2ee, E2eeError};
+use thiserror::Error;
+
+/// The PKCS#8 PEM-encoded private key of the test vectors.
+pub const PRIVATE_KEY_PEM: &str = include_str!("../files/vectors/private.pem");
+
+/// The SPKI PEM-encoded public key of the test vectors.
+pub const PUBLIC_KEY_PEM: &str = include_str!("../files/vectors/public.pem");
+
+const VECTOR_FILES: [&str; 5] = [
+    include_str!("../files/vectors/oaep_sha256.json"),
+    include_str!("../files/vectors/webcrypto.json"),
+    include_str!("../files/vectors/java_mgf1_sha1.json"),
+    include_str!("../files/vectors/java_mgf1_sha256.json"),
+    include_str!("../files/vectors/dotnet.json"),
+];
+
+/// A single known-answer test vector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vector {
+    /// A unique name identifying the vector in error reports.
+    pub name: String,
+    /// The implementation that produced the ciphertext.
+    pub source: String,
+    /// The expected plaintext.
+    pub plaintext: String,
+    /// The encoded ciphertext.
+    pub ciphertext: String,
+    /// The OAEP parameters and encoding of the ciphertext.
+    pub config: InteropConfig,
+}
+
+/// An error reported when checking test vectors.
+#[derive(Error, Debug)]
+pub enum VectorError {
+    #[error("Failed to load the vector key: {0}")]
+    Key(#[source] E2eeError),
+
+    #[error("Vector {name} failed to decrypt: {source}")]
+    Decryption {
+        name: String,
+        #[source]
+        source: E2eeError,
+    },
+
+    #[error("Vector {name} decrypted to {actual:?} instead of {expected:?}")]
+    Mismatch {
+        name: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Returns the bundled vectors of every configuration.
+pub fn interop_vectors() -> Vec<Vector> {
+    VECTOR_FILES
+        .iter()
+        .flat_map(|file| parse_vectors(file))
+        .collect()
+}
+
+fn parse_vectors(file: &str) -> Vec<Vector> {
+    let data: serde_json::Value =
+        serde_json::from_str(file).expect("bundled vectors are valid JSON");
+    let hash = |key: &str| match data[key].as_str() {
+        Some("SHA-1") => OaepHash::Sha1,
+        Some("SHA-256") => OaepHash::Sha256,
+        Some("SHA-384") => OaepHash::Sha384,
+        Some("SHA-512") => OaepHash::Sha512,
+        other => panic!("unsupported hash {other:?} in bundled vectors"),
+    };
+    let config = InteropConfig {
+        oaep: OaepParams {
+            hash: hash("hash"),
+            mgf1_hash: hash("mgf1_hash"),
+        },
+        encoding: match data["encoding"].as_str() {
+            Some("base64-nopad") => Encoding::Base64NoPad,
+            Some("base64") => Encoding::Base64,
+            Some("base64url-nopad") => Encoding::Base64UrlNoPad,
+            other => panic!("unsupported encoding {other:?} in bundled vectors"),
+        },
+    };
+    data["vectors"]
+        .as_array()
+        .expect("bundled vectors contain a list of vectors")
+        .iter()
+        .map(|vector| {
+            let field = |key: &str| {
+                vector[key]
+                    .as_str()
+                    .expect("bundled vector fields are strings")
+                    .to_string()
+            };
+            Vector {
+                name: field("name"),
+                source: field("source"),
+                plaintext: field("plaintext"),
+                ciphertext: field("ciphertext"),
+                config,
+            }
+        })
+        .collect()
+}
+
+/// Creates an `E2ee` instance holding the vector key, with detailed errors enabled.
+///
+/// # Errors
+///
+/// This function returns an error if the vector key cannot be decoded.
+pub fn vector_e2ee() -> Result<E2ee, VectorError> {
+    E2ee::new_from_pem(PRIVATE_KEY_PEM.to_string(), PUBLIC_KEY_PEM.to_string())
+        .map(E2ee::detailed_errors)
+        .map_err(VectorError::Key)
+}
+
+/// Decrypts every vector with the private key of `e2ee`, using the configuration of the vector,
+/// and compares the result with its expected plaintext.
+///
+/// # Errors
+///
+/// This function returns an error for the first vector that fails to decrypt or decrypts to an
+/// unexpected plaintext.
+pub fn run_vectors(e2ee: &E2ee, vectors: &[Vector]) -> Result<(), VectorError> {
+    for vector in vectors {
+        let actual = e2ee
+            .decrypt_detailed(&vector.ciphertext, vector.config)
+            .map_err(|source| VectorError::Decryption {
+                name: vector.name.clone(),
+                source,
+            })?;
+        if actual != vector.plaintext {
+            return Err(VectorError::Mismatch {
+                name: vector.name.clone(),
+                expected: vector.plaintext.clone(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Checks the bundled vectors against this crate and returns the number of vectors checked.
+///
+/// # Errors
+///
+/// This function returns an error for the first vector that does not decrypt to its expected
+/// plaintext.
+pub fn run_interop_vectors() -> Result<usize, VectorError> {
+    let vectors = interop_vectors();
+    run_vectors(&vector_e2ee()?, &vectors)?;
+    Ok(vectors.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interop_vectors_cover_all_sources() {
+        let vectors = interop_vectors();
+        for source in ["OpenSSL", "WebCrypto", "Java", "e2ee"] {
+            assert!(vectors.iter().any(|v| v.source.starts_with(source)));
+        }
+        assert!(vectors
+            .iter()
+            .any(|v| v.config == InteropConfig::WEBCRYPTO
+                && v.ciphertext.ends_with('=')));
+        assert_eq!(run_interop_vectors().unwrap(), vectors.len());
+    }
+
+    #[test]
+    fn test_run_vectors_reports_mismatch() {
+        let mut vectors = interop_vectors();
+        vectors[0].plaintext.push('!');
+        let result = run_vectors(&vector_e2ee().unwrap(), &vectors);
+        assert!(matches!(result, Err(VectorError::Mismatch { .. })));
+    }
+}
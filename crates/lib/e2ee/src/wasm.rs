@@ -0,0 +1,371 @@
+//! WebAssembly bindings for browsers, built with [`wasm-bindgen`](https://docs.rs/wasm-bindgen).
+//!
+//! This module is enabled by the `wasm` feature, and exports to JavaScript:
+//!
+//! - `E2ee` and `PublicE2ee`, holding a key pair and the public key of a recipient;
+//! - `ClientKeyPair`, generated in the browser so that the server can answer with messages only
+//!   the browser decrypts (see `client::ClientKeyPair`);
+//! - `StreamEncryptor` and `StreamDecryptor`, encrypting and decrypting streams (see the `stream`
+//!   module) piece by piece;
+//! - `PublicE2ee.encryptStream()` and `E2ee.decryptStream()`, returning `TransformStream`s to
+//!   pipe a `ReadableStream` through, e.g. `File.stream()` to encrypt an upload client-side.
+//!
+//! Data is exchanged as `Uint8Array` chunks rather than strings, so that large files are neither
+//! base64-encoded nor held in memory as a whole: only the chunk being processed is.
+//!
+//! As with `stream::PushDecryptor`, the output of `E2ee.decryptStream()` is only authenticated as a
+//! whole once the stream closes without error: if it errors, e.g. because the input was truncated,
+//! discard what was written.
+//!
+//! # Examples
+//!
+//! ```js
+//! import init, { PublicE2ee } from "./e2ee.js";
+//!
+//! await init();
+//! const recipient = PublicE2ee.fromPem(publicKeyPem);
+//! const body = file.stream().pipeThrough(recipient.encryptStream());
+//! await fetch("/upload", { method: "POST", body, duplex: "half" });
+//! ```
+use crate::client::{ClientKeyPair, PublicE2ee};
+use crate::server::{E2ee, KeySize};
+use crate::stream::{PushDecryptor, StreamEncryptor};
+use js_sys::Uint8Array;
+use std::{cell::RefCell, io::Write, rc::Rc};
+use wasm_bindgen::prelude::*;
+use web_sys::{TransformStream, TransformStreamDefaultController, Transformer};
+
+fn key_size_from_js(key_size: u32) -> Result<KeySize, JsError> {
+    match key_size {
+        1024 => Ok(KeySize::Bit1024),
+        2048 => Ok(KeySize::Bit2048),
+        3072 => Ok(KeySize::Bit3072),
+        4096 => Ok(KeySize::Bit4096),
+        _ => Err(JsError::new("Unsupported key size")),
+    }
+}
+
+/// An RSA key pair, exported to JavaScript as `E2ee`.
+#[wasm_bindgen(js_name = E2ee)]
+pub struct WasmE2ee {
+    pub(crate) inner: E2ee,
+}
+
+#[wasm_bindgen(js_class = E2ee)]
+impl WasmE2ee {
+    /// Generates a key pair of `key_size` bits (1024, 2048, 3072 or 4096).
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key size is not supported.
+    #[wasm_bindgen(constructor)]
+    pub fn new(key_size: u32) -> Result<WasmE2ee, JsError> {
+        Ok(Self {
+            inner: E2ee::new(key_size_from_js(key_size)?)?,
+        })
+    }
+
+    /// Loads a key pair from its PEM-encoded private and public keys.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the keys cannot be parsed or do not match.
+    #[wasm_bindgen(js_name = fromPem)]
+    pub fn from_pem(
+        private_key_pem: String,
+        public_key_pem: String,
+    ) -> Result<WasmE2ee, JsError> {
+        Ok(Self {
+            inner: E2ee::new_from_pem(private_key_pem, public_key_pem)?,
+        })
+    }
+
+    /// The PEM-encoded public key, to hand to senders.
+    #[wasm_bindgen(getter, js_name = publicKeyPem)]
+    pub fn public_key_pem(&self) -> String {
+        self.inner.get_public_key_pem().to_string()
+    }
+
+    /// Returns a `TransformStream` decrypting the `Uint8Array` chunks of a stream encrypted for
+    /// this key pair.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the `TransformStream` cannot be created.
+    #[wasm_bindgen(js_name = decryptStream)]
+    pub fn decrypt_stream(&self) -> Result<TransformStream, JsValue> {
+        transform_stream(
+            WasmStreamDecryptor::new(self),
+            WasmStreamDecryptor::update,
+            WasmStreamDecryptor::finish,
+        )
+    }
+}
+
+/// The public key of a recipient, exported to JavaScript as `PublicE2ee`.
+#[wasm_bindgen(js_name = PublicE2ee)]
+pub struct WasmPublicE2ee {
+    inner: PublicE2ee,
+}
+
+#[wasm_bindgen(js_class = PublicE2ee)]
+impl WasmPublicE2ee {
+    /// Loads a PEM-encoded public key.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key cannot be parsed or is too weak.
+    #[wasm_bindgen(js_name = fromPem)]
+    pub fn from_pem(public_key_pem: String) -> Result<WasmPublicE2ee, JsError> {
+        Ok(Self {
+            inner: PublicE2ee::new(public_key_pem)?,
+        })
+    }
+
+    /// Encrypts a message for this public key, as a base64 string.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the message is too long for the key.
+    pub fn encrypt(&self, message: &str) -> Result<String, JsError> {
+        Ok(self.inner.encrypt(message)?)
+    }
+
+    /// Encrypts a binary payload of any size for this public key into an envelope.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the data key cannot be wrapped.
+    #[wasm_bindgen(js_name = encryptBytes)]
+    pub fn encrypt_bytes(&self, payload: &[u8]) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.encrypt_envelope(payload)?)
+    }
+
+    /// Returns a `TransformStream` encrypting `Uint8Array` chunks for this public key.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the data key cannot be wrapped, or if the
+    /// `TransformStream` cannot be created.
+    #[wasm_bindgen(js_name = encryptStream)]
+    pub fn encrypt_stream(&self) -> Result<TransformStream, JsValue> {
+        transform_stream(
+            WasmStreamEncryptor::new(self)?,
+            WasmStreamEncryptor::update,
+            WasmStreamEncryptor::finish,
+        )
+    }
+}
+
+/// A key pair generated in the browser, exported to JavaScript as `ClientKeyPair`.
+#[wasm_bindgen(js_name = ClientKeyPair)]
+pub struct WasmClientKeyPair {
+    inner: ClientKeyPair,
+}
+
+#[wasm_bindgen(js_class = ClientKeyPair)]
+impl WasmClientKeyPair {
+    /// Generates a key pair of `key_size` bits (1024, 2048, 3072 or 4096).
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key size is not supported.
+    #[wasm_bindgen(constructor)]
+    pub fn new(key_size: u32) -> Result<WasmClientKeyPair, JsError> {
+        Ok(Self {
+            inner: ClientKeyPair::generate(key_size_from_js(key_size)?)?,
+        })
+    }
+
+    /// The PEM-encoded public key, to send to the server.
+    #[wasm_bindgen(getter, js_name = publicKeyPem)]
+    pub fn public_key_pem(&self) -> String {
+        self.inner.public_key_pem().to_string()
+    }
+
+    /// Decrypts a response encrypted by the server with `PublicE2ee::encrypt`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the response was not encrypted for this key pair.
+    pub fn decrypt(&self, ciphertext: &str) -> Result<String, JsError> {
+        Ok(self.inner.decrypt(ciphertext)?)
+    }
+
+    /// Decrypts an envelope sealed by the server with `PublicE2ee::encrypt_envelope`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the envelope was not sealed for this key pair.
+    #[wasm_bindgen(js_name = decryptBytes)]
+    pub fn decrypt_bytes(&self, envelope: &[u8]) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.decrypt_envelope(envelope)?)
+    }
+}
+
+/// Encrypts a stream piece by piece, exported to JavaScript as `StreamEncryptor`.
+#[wasm_bindgen(js_name = StreamEncryptor)]
+pub struct WasmStreamEncryptor {
+    inner: Option<StreamEncryptor<Vec<u8>>>,
+}
+
+#[wasm_bindgen(js_class = StreamEncryptor)]
+impl WasmStreamEncryptor {
+    /// Starts a stream encrypted for `recipient`.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the data key cannot be wrapped.
+    #[wasm_bindgen(constructor)]
+    pub fn new(recipient: &WasmPublicE2ee) -> Result<WasmStreamEncryptor, JsError> {
+        Ok(Self {
+            inner: Some(StreamEncryptor::new(
+                recipient.inner.get_public_key(),
+                Vec::new(),
+            )?),
+        })
+    }
+
+    /// Encrypts the next piece of the stream, and returns the encrypted output ready so far,
+    /// possibly empty.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the stream is finished.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        let encryptor = self.inner.as_mut().ok_or_else(finished)?;
+        encryptor.write_all(data)?;
+        Ok(std::mem::take(encryptor.get_mut()))
+    }
+
+    /// Encrypts the last chunk of the stream, and returns the rest of the encrypted output.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the stream is already finished.
+    pub fn finish(&mut self) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.take().ok_or_else(finished)?.finish()?)
+    }
+}
+
+/// Decrypts a stream piece by piece, exported to JavaScript as `StreamDecryptor`.
+#[wasm_bindgen(js_name = StreamDecryptor)]
+pub struct WasmStreamDecryptor {
+    inner: Option<PushDecryptor>,
+}
+
+#[wasm_bindgen(js_class = StreamDecryptor)]
+impl WasmStreamDecryptor {
+    /// Starts decrypting a stream encrypted for `recipient`.
+    #[wasm_bindgen(constructor)]
+    pub fn new(recipient: &WasmE2ee) -> WasmStreamDecryptor {
+        Self {
+            inner: Some(PushDecryptor::new(recipient.inner.get_private_key())),
+        }
+    }
+
+    /// Decrypts the next piece of the stream, and returns the plaintext of the chunks it
+    /// completes, possibly empty.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if a chunk was tampered with, or if the stream is finished.
+    pub fn update(&mut self, data: &[u8]) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.as_mut().ok_or_else(finished)?.update(data)?)
+    }
+
+    /// Decrypts the last chunk of the stream, and returns the rest of the plaintext.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the stream is truncated or was tampered with, or if it
+    /// is already finished.
+    pub fn finish(&mut self) -> Result<Vec<u8>, JsError> {
+        Ok(self.inner.take().ok_or_else(finished)?.finish()?)
+    }
+}
+
+fn finished() -> JsError {
+    JsError::new("The stream is already finished")
+}
+
+/// Wraps the `update` and `finish` steps of `state` into a `TransformStream` of `Uint8Array`
+/// chunks, erroring the stream when a step fails.
+fn transform_stream<T: 'static>(
+    state: T,
+    update: fn(&mut T, &[u8]) -> Result<Vec<u8>, JsError>,
+    finish: fn(&mut T) -> Result<Vec<u8>, JsError>,
+) -> Result<TransformStream, JsValue> {
+    fn enqueue(
+        controller: &TransformStreamDefaultController,
+        output: &[u8],
+    ) -> Result<(), JsValue> {
+        if output.is_empty() {
+            return Ok(());
+        }
+        controller.enqueue_with_chunk(&Uint8Array::from(output))
+    }
+
+    let state = Rc::new(RefCell::new(state));
+    let transformer = Transformer::new();
+
+    let transform_state = Rc::clone(&state);
+    let transform = Closure::<
+        dyn FnMut(JsValue, TransformStreamDefaultController) -> Result<(), JsValue>,
+    >::new(move |chunk: JsValue, controller| {
+        let chunk: Uint8Array = chunk
+            .dyn_into()
+            .map_err(|_| JsError::new("Chunks must be Uint8Arrays"))?;
+        let output = update(&mut transform_state.borrow_mut(), &chunk.to_vec())?;
+        enqueue(&controller, &output)
+    });
+    transformer.set_transform(&transform.into_js_value().unchecked_into());
+
+    let flush = Closure::<
+        dyn FnMut(TransformStreamDefaultController) -> Result<(), JsValue>,
+    >::new(move |controller| {
+        let output = finish(&mut state.borrow_mut())?;
+        enqueue(&controller, &output)
+    });
+    transformer.set_flush(&flush.into_js_value().unchecked_into());
+
+    TransformStream::new_with_transformer(&transformer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_key_pair_roundtrip() {
+        let key_pair = WasmClientKeyPair::new(1024).unwrap();
+        let server_side =
+            WasmPublicE2ee::from_pem(key_pair.public_key_pem()).unwrap();
+        let response = server_side.encrypt("Hello, browser!").unwrap();
+        assert_eq!(key_pair.decrypt(&response).unwrap(), "Hello, browser!");
+        let envelope = server_side.encrypt_bytes(&[0, 1, 2]).unwrap();
+        assert_eq!(key_pair.decrypt_bytes(&envelope).unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn test_stream_roundtrip() {
+        let e2ee = WasmE2ee::new(1024).unwrap();
+        let recipient = WasmPublicE2ee::from_pem(e2ee.public_key_pem()).unwrap();
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+        let mut encryptor = WasmStreamEncryptor::new(&recipient).unwrap();
+        let mut encrypted = Vec::new();
+        for piece in data.chunks(10_000) {
+            encrypted.extend(encryptor.update(piece).unwrap());
+        }
+        encrypted.extend(encryptor.finish().unwrap());
+
+        let mut decryptor = WasmStreamDecryptor::new(&e2ee);
+        let mut decrypted = Vec::new();
+        for piece in encrypted.chunks(3_000) {
+            decrypted.extend(decryptor.update(piece).unwrap());
+        }
+        decrypted.extend(decryptor.finish().unwrap());
+        assert_eq!(decrypted, data);
+    }
+}
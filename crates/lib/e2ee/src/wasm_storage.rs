@@ -0,0 +1,486 @@
+//! Persistence of key pairs in the IndexedDB database of a browser.
+//!
+//! This module is enabled by the `wasm-storage` feature, and exports `KeyStorage` to JavaScript,
+//! so that web apps keep the key pairs they generate (see the `wasm` module) across sessions
+//! without each inventing their own storage. Private keys are never stored in the clear, but
+//! wrapped either:
+//!
+//! - with a non-extractable AES-GCM key generated by WebCrypto and kept in the same database
+//!   (`save`), so that scripts can use but never read it, nor copy it to another origin or device;
+//! - or under a password, with a key derived with Argon2id (`saveWithPassword`), so that the key
+//!   pair cannot be loaded without the user.
+//!
+//! The database is versioned: opening it with a newer version of this module runs the migrations
+//! of every version in between. Each record also carries the version of its layout, and records
+//! written by a newer version are rejected rather than misread. Keys wrapped under a password are
+//! laid out as:
+//!
+//! ```text
+//! version (1 byte) | password header (29 bytes) | nonce (12 bytes) | ciphertext
+//! ```
+//!
+//! where the version and the header are authenticated as associated data, and the plaintext is
+//! the PKCS#8 DER encoding of the private key.
+//!
+//! # Examples
+//!
+//! ```js
+//! import init, { E2ee, KeyStorage } from "./e2ee.js";
+//!
+//! await init();
+//! const storage = await KeyStorage.open("my-app");
+//! let e2ee = await storage.load("default").catch(() => null);
+//! if (!e2ee) {
+//!   e2ee = new E2ee(2048);
+//!   await storage.save("default", e2ee);
+//! }
+//! ```
+use crate::password::{PasswordError, PasswordHeader, PasswordKdf, HEADER_LEN};
+use crate::server::{E2ee, E2eeError};
+use crate::wasm::WasmE2ee;
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use js_sys::{Array, Object, Promise, Reflect, Uint8Array};
+use rsa::{
+    pkcs8::{self, DecodePrivateKey, EncodePrivateKey},
+    rand_core::{OsRng, RngCore},
+    RsaPrivateKey,
+};
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use web_sys::{
+    AesGcmParams, AesKeyGenParams, Crypto, CryptoKey, IdbDatabase, IdbFactory,
+    IdbObjectStore, IdbRequest, IdbTransactionMode, IdbVersionChangeEvent,
+};
+use zeroize::Zeroizing;
+
+/// The version of the database, increased with each migration.
+pub const DB_VERSION: u32 = 1;
+
+/// The version of the layout of records and of keys wrapped under a password.
+pub const VERSION: u8 = 1;
+
+const NONCE_LEN: usize = 12;
+
+/// The object store of wrapped key pairs, by ID.
+const KEYS: &str = "keys";
+
+/// The object store holding the non-extractable wrapping key.
+const DEVICE_KEYS: &str = "device-keys";
+
+const DEVICE_KEY_ID: &str = "default";
+
+pub type StorageResult<T> = std::result::Result<T, StorageError>;
+
+/// An error returned when wrapping or unwrapping a stored key pair.
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("Password error: {0}")]
+    Password(#[from] PasswordError),
+
+    #[error("PKCS#8 error: {0}")]
+    Pkcs8(#[from] pkcs8::Error),
+
+    #[error("E2ee error: {0}")]
+    E2ee(#[from] E2eeError),
+
+    #[error("Wrong password, or the stored key was tampered with")]
+    WrongPassword,
+
+    #[error("Malformed stored key")]
+    Malformed,
+
+    #[error("Unsupported stored key version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("No key pair is stored under {0:?}")]
+    NotFound(String),
+
+    #[error("The key pair stored under {0:?} is protected by a password")]
+    PasswordRequired(String),
+}
+
+/// Wraps `private_key` under `password`, with a key derived by `kdf`.
+///
+/// # Errors
+///
+/// This function returns an error if the key derivation or the encoding of the key fails.
+pub fn wrap_with_password(
+    private_key: &RsaPrivateKey,
+    password: &[u8],
+    kdf: &PasswordKdf,
+) -> StorageResult<Vec<u8>> {
+    let der = private_key.to_pkcs8_der()?;
+    let derived = kdf.derive(password)?;
+    let mut wrapped = vec![VERSION];
+    wrapped.extend_from_slice(&derived.header.to_bytes());
+    let mut nonce = [0; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = Aes256Gcm::new(derived.key.as_ref().into())
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: der.as_bytes(),
+                aad: &wrapped,
+            },
+        )
+        .map_err(|_| StorageError::Malformed)?;
+    wrapped.extend_from_slice(&nonce);
+    wrapped.extend_from_slice(&ciphertext);
+    Ok(wrapped)
+}
+
+/// Unwraps a private key wrapped by [`wrap_with_password`].
+///
+/// # Errors
+///
+/// This function returns `StorageError::WrongPassword` if `password` is wrong or the key was
+/// tampered with, or an error if it is malformed.
+pub fn unwrap_with_password(
+    wrapped: &[u8],
+    password: &[u8],
+) -> StorageResult<RsaPrivateKey> {
+    let (&version, rest) = wrapped.split_first().ok_or(StorageError::Malformed)?;
+    if version != VERSION {
+        return Err(StorageError::UnsupportedVersion(version));
+    }
+    if rest.len() < HEADER_LEN + NONCE_LEN {
+        return Err(StorageError::Malformed);
+    }
+    let (header, rest) = rest.split_at(HEADER_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key = PasswordHeader::from_bytes(header)?.derive_key(password)?;
+    let der = Zeroizing::new(
+        Aes256Gcm::new(key.as_ref().into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &wrapped[..1 + HEADER_LEN],
+                },
+            )
+            .map_err(|_| StorageError::WrongPassword)?,
+    );
+    Ok(RsaPrivateKey::from_pkcs8_der(&der)?)
+}
+
+/// How the private key of a record is wrapped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protection {
+    Device,
+    Password,
+}
+
+impl Protection {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protection::Device => "device",
+            Protection::Password => "password",
+        }
+    }
+}
+
+/// Key pairs stored in an IndexedDB database, exported to JavaScript as `KeyStorage`.
+#[wasm_bindgen]
+pub struct KeyStorage {
+    db: IdbDatabase,
+}
+
+#[wasm_bindgen]
+impl KeyStorage {
+    /// Opens the database `name`, creating it or migrating it to [`DB_VERSION`] if needed.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if IndexedDB is not available or the database is newer
+    /// than this module.
+    pub async fn open(name: String) -> Result<KeyStorage, JsValue> {
+        let factory: IdbFactory =
+            Reflect::get(&js_sys::global(), &"indexedDB".into())?.dyn_into()?;
+        let request = factory.open_with_u32(&name, DB_VERSION)?;
+        let upgrading = request.clone();
+        let on_upgrade_needed = Closure::once_into_js(
+            move |event: IdbVersionChangeEvent| -> Result<(), JsValue> {
+                let db: IdbDatabase = upgrading.result()?.dyn_into()?;
+                migrate(&db, event.old_version() as u32)
+            },
+        );
+        request.set_onupgradeneeded(Some(on_upgrade_needed.unchecked_ref()));
+        Ok(Self {
+            db: complete(&request).await?.dyn_into()?,
+        })
+    }
+
+    /// Stores the key pair of `e2ee` under `id`, wrapped with the non-extractable key of this
+    /// database, replacing any key pair stored under `id`.
+    ///
+    /// Returns a promise resolving once the key pair is stored.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the private key cannot be encoded.
+    pub fn save(&self, id: String, e2ee: &WasmE2ee) -> Result<Promise, JsError> {
+        let der = private_key_der(&e2ee.inner)?;
+        let db = self.db.clone();
+        Ok(future_to_promise(async move {
+            let key = device_key(&db).await?;
+            let mut nonce = [0; NONCE_LEN];
+            OsRng.fill_bytes(&mut nonce);
+            let params = AesGcmParams::new("AES-GCM", &Uint8Array::from(&nonce[..]));
+            let ciphertext = JsFuture::from(
+                subtle()?.encrypt_with_object_and_u8_array(&params, &key, &der)?,
+            )
+            .await?;
+            let mut wrapped = nonce.to_vec();
+            wrapped.extend(Uint8Array::new(&ciphertext).to_vec());
+            put_record(&db, &id, Protection::Device, &wrapped).await
+        }))
+    }
+
+    /// Stores the key pair of `e2ee` under `id`, wrapped under `password`, replacing any key pair
+    /// stored under `id`.
+    ///
+    /// Returns a promise resolving once the key pair is stored.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the key derivation fails.
+    #[wasm_bindgen(js_name = saveWithPassword)]
+    pub fn save_with_password(
+        &self,
+        id: String,
+        e2ee: &WasmE2ee,
+        password: String,
+    ) -> Result<Promise, JsError> {
+        let wrapped = wrap_with_password(
+            e2ee.inner.get_private_key(),
+            password.as_bytes(),
+            &PasswordKdf::default(),
+        )?;
+        let db = self.db.clone();
+        Ok(future_to_promise(async move {
+            put_record(&db, &id, Protection::Password, &wrapped).await
+        }))
+    }
+
+    /// Loads the key pair stored under `id`, with `password` if it was stored with
+    /// `saveWithPassword`.
+    ///
+    /// Returns a promise resolving to an `E2ee`, and rejecting if no key pair is stored under
+    /// `id`, or if `password` is missing or wrong.
+    pub fn load(&self, id: String, password: Option<String>) -> Promise {
+        let db = self.db.clone();
+        future_to_promise(async move {
+            let store = object_store(&db, KEYS, IdbTransactionMode::Readonly)?;
+            let record = complete(&store.get(&id.as_str().into())?).await?;
+            if record.is_undefined() {
+                return Err(JsError::from(StorageError::NotFound(id)).into());
+            }
+            let version = Reflect::get(&record, &"version".into())?
+                .as_f64()
+                .ok_or_else(|| JsError::from(StorageError::Malformed))?;
+            if version != f64::from(VERSION) {
+                return Err(JsError::from(StorageError::UnsupportedVersion(
+                    version as u8,
+                ))
+                .into());
+            }
+            let wrapped = Reflect::get(&record, &"wrapped".into())?
+                .dyn_into::<Uint8Array>()?
+                .to_vec();
+            let protection =
+                Reflect::get(&record, &"protection".into())?.as_string();
+            let private_key = match (protection.as_deref(), password) {
+                (Some("device"), _) => unwrap_with_device_key(&db, &wrapped).await?,
+                (Some("password"), Some(password)) => {
+                    unwrap_with_password(&wrapped, password.as_bytes())
+                        .map_err(JsError::from)?
+                }
+                (Some("password"), None) => {
+                    return Err(
+                        JsError::from(StorageError::PasswordRequired(id)).into()
+                    )
+                }
+                _ => return Err(JsError::from(StorageError::Malformed).into()),
+            };
+            let inner = E2ee::from_private_key(private_key)
+                .map_err(|error| JsError::from(StorageError::from(error)))?;
+            Ok(WasmE2ee { inner }.into())
+        })
+    }
+
+    /// Removes the key pair stored under `id`, if any.
+    ///
+    /// Returns a promise resolving once it is removed.
+    pub fn remove(&self, id: String) -> Promise {
+        let db = self.db.clone();
+        future_to_promise(async move {
+            let store = object_store(&db, KEYS, IdbTransactionMode::Readwrite)?;
+            complete(&store.delete(&id.as_str().into())?).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Returns a promise resolving to the IDs of the stored key pairs.
+    pub fn ids(&self) -> Promise {
+        let db = self.db.clone();
+        future_to_promise(async move {
+            let store = object_store(&db, KEYS, IdbTransactionMode::Readonly)?;
+            complete(&store.get_all_keys()?).await
+        })
+    }
+}
+
+/// Brings the database from `old_version` (0 if it was just created) to [`DB_VERSION`], one
+/// version at a time.
+fn migrate(db: &IdbDatabase, old_version: u32) -> Result<(), JsValue> {
+    if old_version < 1 {
+        db.create_object_store(KEYS)?;
+        db.create_object_store(DEVICE_KEYS)?;
+    }
+    Ok(())
+}
+
+fn private_key_der(e2ee: &E2ee) -> Result<Zeroizing<Vec<u8>>, JsError> {
+    let der = e2ee
+        .get_private_key()
+        .to_pkcs8_der()
+        .map_err(StorageError::from)?;
+    Ok(Zeroizing::new(der.as_bytes().to_vec()))
+}
+
+/// Resolves with the result of `request`, or rejects with its error.
+async fn complete(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise = Promise::new(&mut |resolve, reject| {
+        let succeeded = request.clone();
+        request.set_onsuccess(Some(
+            Closure::once_into_js(move || {
+                resolve.call1(&JsValue::UNDEFINED, &succeeded.result()?)
+            })
+            .unchecked_ref(),
+        ));
+        let failed = request.clone();
+        request.set_onerror(Some(
+            Closure::once_into_js(move || {
+                let error =
+                    failed.error()?.map_or(JsValue::UNDEFINED, JsValue::from);
+                reject.call1(&JsValue::UNDEFINED, &error)
+            })
+            .unchecked_ref(),
+        ));
+    });
+    JsFuture::from(promise).await
+}
+
+fn object_store(
+    db: &IdbDatabase,
+    name: &str,
+    mode: IdbTransactionMode,
+) -> Result<IdbObjectStore, JsValue> {
+    db.transaction_with_str_and_mode(name, mode)?
+        .object_store(name)
+}
+
+fn subtle() -> Result<web_sys::SubtleCrypto, JsValue> {
+    let crypto: Crypto =
+        Reflect::get(&js_sys::global(), &"crypto".into())?.dyn_into()?;
+    Ok(crypto.subtle())
+}
+
+/// Returns the non-extractable wrapping key of `db`, generating it on first use.
+async fn device_key(db: &IdbDatabase) -> Result<CryptoKey, JsValue> {
+    let store = object_store(db, DEVICE_KEYS, IdbTransactionMode::Readonly)?;
+    let key = complete(&store.get(&DEVICE_KEY_ID.into())?).await?;
+    if !key.is_undefined() {
+        return key.dyn_into();
+    }
+
+    let usages = Array::of2(&"encrypt".into(), &"decrypt".into());
+    let key = JsFuture::from(subtle()?.generate_key_with_object(
+        &AesKeyGenParams::new("AES-GCM", 256),
+        false,
+        &usages,
+    )?)
+    .await?;
+    // Another tab may have generated one meanwhile: `add` keeps the first, which is then used.
+    let store = object_store(db, DEVICE_KEYS, IdbTransactionMode::Readwrite)?;
+    match complete(&store.add_with_key(&key, &DEVICE_KEY_ID.into())?).await {
+        Ok(_) => key.dyn_into(),
+        Err(_) => {
+            let store = object_store(db, DEVICE_KEYS, IdbTransactionMode::Readonly)?;
+            complete(&store.get(&DEVICE_KEY_ID.into())?)
+                .await?
+                .dyn_into()
+        }
+    }
+}
+
+async fn unwrap_with_device_key(
+    db: &IdbDatabase,
+    wrapped: &[u8],
+) -> Result<RsaPrivateKey, JsValue> {
+    if wrapped.len() < NONCE_LEN {
+        return Err(JsError::from(StorageError::Malformed).into());
+    }
+    let (nonce, ciphertext) = wrapped.split_at(NONCE_LEN);
+    let key = device_key(db).await?;
+    let params = AesGcmParams::new("AES-GCM", &Uint8Array::from(nonce));
+    let der = JsFuture::from(
+        subtle()?.decrypt_with_object_and_u8_array(&params, &key, ciphertext)?,
+    )
+    .await
+    .map_err(|_| JsError::from(StorageError::Malformed))?;
+    let der = Zeroizing::new(Uint8Array::new(&der).to_vec());
+    RsaPrivateKey::from_pkcs8_der(&der)
+        .map_err(|error| JsError::from(StorageError::from(error)).into())
+}
+
+async fn put_record(
+    db: &IdbDatabase,
+    id: &str,
+    protection: Protection,
+    wrapped: &[u8],
+) -> Result<JsValue, JsValue> {
+    let record = Object::new();
+    Reflect::set(&record, &"version".into(), &VERSION.into())?;
+    Reflect::set(&record, &"protection".into(), &protection.as_str().into())?;
+    Reflect::set(&record, &"wrapped".into(), &Uint8Array::from(wrapped))?;
+    let store = object_store(db, KEYS, IdbTransactionMode::Readwrite)?;
+    complete(&store.put_with_key(&record, &id.into())?).await?;
+    Ok(JsValue::UNDEFINED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core;
+    use crate::password::PasswordParams;
+
+    #[test]
+    fn test_password_wrapping() {
+        let kdf = PasswordKdf::new(PasswordParams::new(64, 1, 1).unwrap());
+        let private_key = core::generate_private_key(1024).unwrap();
+        let mut wrapped = wrap_with_password(&private_key, b"secret", &kdf).unwrap();
+        assert_eq!(
+            unwrap_with_password(&wrapped, b"secret").unwrap(),
+            private_key
+        );
+        assert!(matches!(
+            unwrap_with_password(&wrapped, b"wrong"),
+            Err(StorageError::WrongPassword)
+        ));
+        assert!(matches!(
+            unwrap_with_password(&wrapped[..20], b"secret"),
+            Err(StorageError::Malformed)
+        ));
+
+        wrapped[0] = VERSION + 1;
+        assert!(matches!(
+            unwrap_with_password(&wrapped, b"secret"),
+            Err(StorageError::UnsupportedVersion(2))
+        ));
+    }
+}
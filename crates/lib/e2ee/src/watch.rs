@@ -0,0 +1,305 @@
+//! Hot reloading of the key pair of a long-running server when its key files change.
+//!
+//! This module is enabled by the `watch` feature. [`WatchedKeys`] loads an `E2ee` instance from a
+//! private and a public key file, watches their directories with
+//! [`notify`](https://docs.rs/notify), and swaps in a new instance when the files are written,
+//! replaced or renamed over, e.g. by a key rotation job. [`WatchedKeys::current`] returns the
+//! instance to use for each request: requests in flight keep the instance they started with.
+//!
+//! A pair that fails to load, e.g. a private key rotated before its public key, leaves the current
+//! instance in place until both files match: write the new files next to the old ones and rename
+//! them over to avoid reading half-written keys. Instances are built with
+//! [`WatchedKeys::with_setup`], so that metrics, audit loggers and other settings survive reloads.
+//!
+//! # Examples
+//!
+//! ```
+//! use e2ee::server::{E2ee, KeySize};
+//! use e2ee::watch::WatchedKeys;
+//!
+//! # let dir = std::env::temp_dir().join(format!("e2ee-doc-watch-{}", std::process::id()));
+//! # std::fs::create_dir_all(&dir).unwrap();
+//! # let e2ee = E2ee::new(KeySize::Bit2048).unwrap();
+//! # std::fs::write(dir.join("private.pem"), e2ee.get_private_key_pem().unwrap().as_bytes()).unwrap();
+//! # std::fs::write(dir.join("public.pem"), e2ee.get_public_key_pem()).unwrap();
+//! let keys = WatchedKeys::open(dir.join("private.pem"), dir.join("public.pem"))
+//!     .expect("Failed to load keys")
+//!     .with_on_reload(|result| match result {
+//!         Ok(e2ee) => println!("Reloaded key {}", e2ee::core::key_id(e2ee.get_public_key()).unwrap()),
+//!         Err(err) => eprintln!("Kept the current key: {err}"),
+//!     });
+//!
+//! // In each request handler:
+//! let e2ee = keys.current();
+//! let encrypted = e2ee.encrypt("Secret message").expect("Failed to encrypt message");
+//! # std::fs::remove_dir_all(&dir).unwrap();
+//! ```
+use crate::server::{E2ee, E2eeError};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+};
+use thiserror::Error;
+
+pub type WatchResult<T> = std::result::Result<T, WatchError>;
+
+/// An error returned when watching or loading key files.
+#[derive(Error, Debug)]
+pub enum WatchError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Watch error: {0}")]
+    Notify(#[from] notify::Error),
+
+    #[error("Key error: {0}")]
+    E2ee(#[from] E2eeError),
+}
+
+type Setup = Box<dyn Fn(E2ee) -> E2ee + Send + Sync>;
+type OnReload = Box<dyn Fn(Result<&E2ee, &WatchError>) + Send + Sync>;
+
+/// The state shared with the watcher thread.
+struct Shared {
+    private_key_path: PathBuf,
+    public_key_path: PathBuf,
+    current: RwLock<Arc<E2ee>>,
+    setup: Mutex<Option<Setup>>,
+    on_reload: Mutex<Option<OnReload>>,
+}
+
+/// An `E2ee` instance reloaded from its key files whenever they change.
+pub struct WatchedKeys {
+    shared: Arc<Shared>,
+    _watcher: RecommendedWatcher,
+}
+
+impl fmt::Debug for WatchedKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WatchedKeys")
+            .field("private_key_path", &self.shared.private_key_path)
+            .field("public_key_path", &self.shared.public_key_path)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WatchedKeys {
+    /// Loads the key pair from `private_key_path` and `public_key_path`, and starts watching
+    /// them.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if the files cannot be read, the keys cannot be decoded or
+    /// do not match, or their directories cannot be watched.
+    pub fn open(
+        private_key_path: impl AsRef<Path>,
+        public_key_path: impl AsRef<Path>,
+    ) -> WatchResult<Self> {
+        let private_key_path = absolute(private_key_path.as_ref())?;
+        let public_key_path = absolute(public_key_path.as_ref())?;
+        let e2ee = load(&private_key_path, &public_key_path)?;
+        let shared = Arc::new(Shared {
+            private_key_path,
+            public_key_path,
+            current: RwLock::new(Arc::new(e2ee)),
+            setup: Mutex::new(None),
+            on_reload: Mutex::new(None),
+        });
+
+        let handler = Arc::clone(&shared);
+        let mut watcher =
+            notify::recommended_watcher(move |event| handler.handle(event))?;
+        // Directories are watched rather than the files, which rotations replace.
+        for path in [&shared.private_key_path, &shared.public_key_path] {
+            if let Some(dir) = path.parent() {
+                watcher.watch(dir, RecursiveMode::NonRecursive)?;
+            }
+        }
+        Ok(Self {
+            shared,
+            _watcher: watcher,
+        })
+    }
+
+    /// Configures every instance loaded from the key files with `setup`, e.g. to attach metrics
+    /// or an audit logger, starting with the current one.
+    pub fn with_setup(
+        self,
+        setup: impl Fn(E2ee) -> E2ee + Send + Sync + 'static,
+    ) -> Self {
+        let current = setup(E2ee::clone(&self.current()));
+        *self
+            .shared
+            .current
+            .write()
+            .unwrap_or_else(|e| e.into_inner()) = Arc::new(current);
+        *self.shared.setup.lock().unwrap_or_else(|e| e.into_inner()) =
+            Some(Box::new(setup));
+        self
+    }
+
+    /// Calls `on_reload` after every reload triggered by a change of the key files, with the new
+    /// instance or the error that kept the current one in place.
+    pub fn with_on_reload(
+        self,
+        on_reload: impl Fn(Result<&E2ee, &WatchError>) + Send + Sync + 'static,
+    ) -> Self {
+        *self
+            .shared
+            .on_reload
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(Box::new(on_reload));
+        self
+    }
+
+    /// Returns the current instance.
+    pub fn current(&self) -> Arc<E2ee> {
+        self.shared.current()
+    }
+
+    /// Reloads the key files now, and returns the current instance.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error, and keeps the current instance, if the files cannot be read,
+    /// or the keys cannot be decoded or do not match.
+    pub fn reload(&self) -> WatchResult<Arc<E2ee>> {
+        self.shared.reload()
+    }
+}
+
+impl Shared {
+    fn current(&self) -> Arc<E2ee> {
+        Arc::clone(&self.current.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    fn reload(&self) -> WatchResult<Arc<E2ee>> {
+        let e2ee = load(&self.private_key_path, &self.public_key_path)?;
+        let current = self.current();
+        // Writing a file raises several events: only swap once per key pair.
+        if e2ee == *current {
+            return Ok(current);
+        }
+        let e2ee = match &*self.setup.lock().unwrap_or_else(|e| e.into_inner()) {
+            Some(setup) => setup(e2ee),
+            None => e2ee,
+        };
+        let e2ee = Arc::new(e2ee);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::clone(&e2ee);
+        Ok(e2ee)
+    }
+
+    fn handle(&self, event: notify::Result<Event>) {
+        let result = match event {
+            Ok(event) => {
+                let written = matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_)
+                );
+                let ours = event.paths.iter().any(|path| {
+                    *path == self.private_key_path || *path == self.public_key_path
+                });
+                if !(written && ours) {
+                    return;
+                }
+                self.reload()
+            }
+            Err(err) => Err(err.into()),
+        };
+        if let Some(on_reload) =
+            &*self.on_reload.lock().unwrap_or_else(|e| e.into_inner())
+        {
+            on_reload(result.as_deref())
+        }
+    }
+}
+
+fn load(private_key_path: &Path, public_key_path: &Path) -> WatchResult<E2ee> {
+    let private_key_pem = fs::read_to_string(private_key_path)?;
+    let public_key_pem = fs::read_to_string(public_key_path)?;
+    // `new_from_pem` rejects a public key that does not match the private key.
+    Ok(E2ee::new_from_pem(private_key_pem, public_key_pem)?)
+}
+
+/// Returns `path` in the canonical directory it is in, to compare it with the paths of events.
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "A key path has no file name")
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(dir.canonicalize()?.join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::KeySize;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    fn write_keys(dir: &Path, e2ee: &E2ee) {
+        for (name, pem) in [
+            (
+                "private.pem",
+                e2ee.get_private_key_pem().unwrap().to_string(),
+            ),
+            ("public.pem", e2ee.get_public_key_pem().to_string()),
+        ] {
+            let temp = dir.join(format!("{name}.tmp"));
+            fs::write(&temp, pem).unwrap();
+            fs::rename(temp, dir.join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_watched_keys_reload_rotated_files() {
+        let dir = std::env::temp_dir()
+            .join(format!("e2ee-test-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let old = E2ee::new(KeySize::Bit1024).unwrap();
+        write_keys(&dir, &old);
+
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&reloads);
+        let keys =
+            WatchedKeys::open(dir.join("private.pem"), dir.join("public.pem"))
+                .unwrap()
+                .with_setup(|e2ee| e2ee.with_expiry_check(false))
+                .with_on_reload(move |_| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                });
+        let in_flight = keys.current();
+        assert_eq!(*in_flight, old);
+
+        let new = E2ee::new(KeySize::Bit1024).unwrap();
+        write_keys(&dir, &new);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while *keys.current() != new && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(*keys.current(), new);
+        assert!(reloads.load(Ordering::SeqCst) > 0);
+        // Requests in flight keep the previous keys.
+        let encrypted = old.encrypt("Hello watch!").unwrap();
+        assert_eq!(in_flight.decrypt(&encrypted).unwrap(), "Hello watch!");
+
+        // A mismatched pair keeps the current keys.
+        let other = E2ee::new(KeySize::Bit1024).unwrap();
+        fs::write(dir.join("public.pem"), other.get_public_key_pem()).unwrap();
+        assert!(matches!(
+            keys.reload(),
+            Err(WatchError::E2ee(E2eeError::InvalidPublicKey(_)))
+        ));
+        assert_eq!(*keys.current(), new);
+        drop(keys);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}